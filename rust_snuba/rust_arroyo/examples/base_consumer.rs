@@ -1,26 +1,29 @@
 extern crate rust_arroyo;
 
-use rust_arroyo::backends::kafka::config::KafkaConfig;
+use rust_arroyo::backends::kafka::config::{InitialOffset, KafkaConfig};
 use rust_arroyo::backends::kafka::KafkaConsumer;
 use rust_arroyo::backends::AssignmentCallbacks;
 use rust_arroyo::backends::Consumer;
-use rust_arroyo::types::{Partition, Topic};
+use rust_arroyo::types::{Partition, Position, Topic};
 use std::collections::HashMap;
 
 struct EmptyCallbacks {}
 impl AssignmentCallbacks for EmptyCallbacks {
     fn on_assign(&mut self, _: HashMap<Partition, u64>) {}
-    fn on_revoke(&mut self, _: Vec<Partition>) {}
+    fn on_revoke(&mut self, _: Vec<Partition>) -> HashMap<Partition, Position> {
+        HashMap::new()
+    }
 }
 
 fn main() {
     let config = KafkaConfig::new_consumer_config(
         vec!["localhost:9092".to_string()],
         "my_group".to_string(),
-        "latest".to_string(),
+        InitialOffset::Latest,
         false,
         None,
-    );
+    )
+    .unwrap();
     let mut consumer = KafkaConsumer::new(config);
     let topic = Topic {
         name: "test_static".to_string(),
@@ -31,11 +34,8 @@ fn main() {
     for _ in 0..20 {
         println!("Polling");
         let res = consumer.poll(None);
-        match res.unwrap() {
-            Some(x) => {
-                println!("MSG {}", x)
-            }
-            None => {}
+        if let Some(x) = res.unwrap() {
+            println!("MSG {}", x)
         }
     }
 }
@@ -1,21 +1,21 @@
 extern crate rust_arroyo;
 
-use rust_arroyo::backends::kafka::config::KafkaConfig;
+use rust_arroyo::backends::kafka::config::{InitialOffset, KafkaConfig};
 use rust_arroyo::backends::kafka::types::KafkaPayload;
 use rust_arroyo::backends::kafka::KafkaConsumer;
 use rust_arroyo::processing::strategies::{
-    CommitRequest, MessageRejected, ProcessingStrategy, ProcessingStrategyFactory,
+    CommitRequest, InvalidMessage, ProcessingStrategy, ProcessingStrategyFactory, SubmitError,
 };
 use rust_arroyo::processing::StreamProcessor;
-use rust_arroyo::types::{Message, Partition, Topic};
+use rust_arroyo::types::{Message, Partition, Position, Topic};
 use std::collections::HashMap;
 use std::time::Duration;
 
 struct TestStrategy {
-    partitions: HashMap<Partition, u64>,
+    partitions: HashMap<Partition, Position>,
 }
 impl ProcessingStrategy<KafkaPayload> for TestStrategy {
-    fn poll(&mut self) -> Option<CommitRequest> {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
         println!("POLL");
         if !self.partitions.is_empty() {
             // TODO: Actually make commit work. It does not seem
@@ -24,18 +24,17 @@ impl ProcessingStrategy<KafkaPayload> for TestStrategy {
                 positions: self.partitions.clone(),
             });
             self.partitions.clear();
-            ret
+            Ok(ret)
         } else {
-            None
+            Ok(None)
         }
     }
 
-    fn submit(&mut self, message: Message<KafkaPayload>) -> Result<(), MessageRejected> {
+    fn submit(&mut self, message: Message<KafkaPayload>) -> Result<(), SubmitError<KafkaPayload>> {
         println!("SUBMIT {}", message);
-        self.partitions.insert(
-            message.partition,
-            message.offset,
-        );
+        for (partition, position) in message.committable() {
+            self.partitions.insert(partition, position);
+        }
         Ok(())
     }
 
@@ -61,10 +60,11 @@ fn main() {
     let config = KafkaConfig::new_consumer_config(
         vec!["localhost:9092".to_string()],
         "my_group".to_string(),
-        "latest".to_string(),
+        InitialOffset::Latest,
         false,
         None,
-    );
+    )
+    .unwrap();
     let consumer = Box::new(KafkaConsumer::new(config));
     let topic = Topic {
         name: "test_static".to_string(),
@@ -4,18 +4,18 @@
 // and then produces it to topic test_out.
 extern crate rust_arroyo;
 
-use rdkafka::message::ToBytes;
-use rust_arroyo::backends::kafka::config::KafkaConfig;
+use rust_arroyo::backends::kafka::config::{InitialOffset, KafkaConfig};
 use rust_arroyo::backends::kafka::producer::KafkaProducer;
 use rust_arroyo::backends::kafka::types::KafkaPayload;
 use rust_arroyo::backends::kafka::KafkaConsumer;
 use rust_arroyo::processing::strategies::produce::Produce;
 use rust_arroyo::processing::strategies::transform::Transform;
 use rust_arroyo::processing::strategies::{
-    CommitRequest, MessageRejected, ProcessingStrategy, ProcessingStrategyFactory, InvalidMessage,
+    CommitRequest, InvalidMessage, ProcessingStrategy, ProcessingStrategyFactory, SubmitError,
 };
 use rust_arroyo::processing::StreamProcessor;
 use rust_arroyo::types::{Message, Topic, TopicOrPartition};
+use std::sync::Arc;
 use std::time::Duration;
 
 fn reverse_string(value: KafkaPayload) -> Result<KafkaPayload, InvalidMessage> {
@@ -26,17 +26,17 @@ fn reverse_string(value: KafkaPayload) -> Result<KafkaPayload, InvalidMessage> {
     println!("transforming value: {:?} -> {:?}", str_payload, &result_str);
 
     let result = KafkaPayload {
-        payload: Some(result_str.to_bytes().to_vec()),
+        payload: Some(result_str.as_bytes().into()),
         ..value
     };
     Ok(result)
 }
 struct Noop {}
 impl ProcessingStrategy<KafkaPayload> for Noop {
-    fn poll(&mut self) -> Option<CommitRequest> {
-        None
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        Ok(None)
     }
-    fn submit(&mut self, _message: Message<KafkaPayload>) -> Result<(), MessageRejected> {
+    fn submit(&mut self, _message: Message<KafkaPayload>) -> Result<(), SubmitError<KafkaPayload>> {
         Ok(())
     }
     fn close(&mut self) {}
@@ -46,8 +46,6 @@ impl ProcessingStrategy<KafkaPayload> for Noop {
     }
 }
 
-
-
 #[tokio::main]
 async fn main() {
     struct ReverseStringAndProduceStrategyFactory {
@@ -58,10 +56,10 @@ async fn main() {
         fn create(&self) -> Box<dyn ProcessingStrategy<KafkaPayload>> {
             let producer = KafkaProducer::new(self.config.clone());
             let topic = TopicOrPartition::Topic(self.topic.clone());
-            let reverse_string_and_produce_strategy = Transform {
-                function: reverse_string,
-                next_step: Box::new(Produce::new(producer, Box::new(Noop {}), topic ))
-            };
+            let reverse_string_and_produce_strategy = Transform::new(
+                reverse_string,
+                Box::new(Produce::new(Arc::new(producer), Box::new(Noop {}), topic)),
+            );
             Box::new(reverse_string_and_produce_strategy)
         }
     }
@@ -69,21 +67,25 @@ async fn main() {
     let config = KafkaConfig::new_consumer_config(
         vec!["0.0.0.0:9092".to_string()],
         "my_group".to_string(),
-        "latest".to_string(),
+        InitialOffset::Latest,
         false,
         None,
-    );
-
+    )
+    .unwrap();
 
     let consumer = Box::new(KafkaConsumer::new(config.clone()));
-    let mut processor = StreamProcessor::new(consumer,
+    let mut processor = StreamProcessor::new(
+        consumer,
         Box::new(ReverseStringAndProduceStrategyFactory {
-                            config: config.clone(),
-                            topic: Topic { name: "test_out".to_string() }
-                         }));
+            config: config.clone(),
+            topic: Topic {
+                name: "test_out".to_string(),
+            },
+        }),
+    );
     processor.subscribe(Topic {
         name: "test_in".to_string(),
     });
     println!("running processor. transforming from test_in to test_out");
-    processor.run();
+    processor.run().unwrap();
 }
@@ -1,9 +1,229 @@
+use crate::backends::kafka::oauth::OAuthTokenProvider;
 use rdkafka::config::ClientConfig as RdKafkaConfig;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
 
-#[derive(Debug, Clone)]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("enable.auto.commit is not supported, offsets are committed by the strategies")]
+    AutoCommitEnabled,
+
+    #[error("auto.commit.interval.ms is not supported, offsets are committed by the strategies")]
+    AutoCommitInterval,
+
+    #[error("Invalid {0}: {1}")]
+    InvalidValue(&'static str, String),
+
+    #[error("{0} requires security.protocol to be one of {1}")]
+    IncompatibleSecurityProtocol(&'static str, &'static str),
+
+    #[error("{0} is required by the {1} SASL mechanism")]
+    MissingCredentials(&'static str, &'static str),
+}
+
+// Settings whose values are not shown by ``Debug``.
+fn is_secret(key: &str) -> bool {
+    key.contains("password") || key.contains("secret") || key.ends_with(".pem")
+}
+
+/// Where a consumer starts reading a partition that has no committed offset,
+/// or whose committed offset is out of range. This is ``auto.offset.reset``.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitialOffset {
+    Earliest,
+    Latest,
+    /// Fail instead of skipping or replaying messages.
+    Error,
+}
+
+impl InitialOffset {
+    fn as_str(&self) -> &'static str {
+        match self {
+            InitialOffset::Earliest => "earliest",
+            InitialOffset::Latest => "latest",
+            InitialOffset::Error => "error",
+        }
+    }
+}
+
+impl FromStr for InitialOffset {
+    type Err = ConfigError;
+
+    /// Accepts the values, and aliases, librdkafka accepts.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "earliest" | "smallest" | "beginning" => Ok(InitialOffset::Earliest),
+            "latest" | "largest" | "end" => Ok(InitialOffset::Latest),
+            "error" => Ok(InitialOffset::Error),
+            other => Err(ConfigError::InvalidValue(
+                "auto.offset.reset",
+                other.to_string(),
+            )),
+        }
+    }
+}
+
+/// The partition assignor used by a consumer group.
+///
+/// With ``CooperativeSticky``, rebalances are incremental: consumers keep the
+/// partitions that do not move and the assignment callbacks are only told
+/// about the partitions that are added or removed. All the members of a group
+/// have to use the same kind of assignor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssignmentStrategy {
+    Range,
+    RoundRobin,
+    CooperativeSticky,
+}
+
+impl AssignmentStrategy {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AssignmentStrategy::Range => "range",
+            AssignmentStrategy::RoundRobin => "roundrobin",
+            AssignmentStrategy::CooperativeSticky => "cooperative-sticky",
+        }
+    }
+}
+
+/// How clients talk to the brokers, this is ``security.protocol``.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecurityProtocol {
+    Plaintext,
+    Ssl,
+    SaslPlaintext,
+    SaslSsl,
+}
+
+impl SecurityProtocol {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SecurityProtocol::Plaintext => "plaintext",
+            SecurityProtocol::Ssl => "ssl",
+            SecurityProtocol::SaslPlaintext => "sasl_plaintext",
+            SecurityProtocol::SaslSsl => "sasl_ssl",
+        }
+    }
+
+    fn is_sasl(&self) -> bool {
+        matches!(
+            self,
+            SecurityProtocol::SaslPlaintext | SecurityProtocol::SaslSsl
+        )
+    }
+
+    fn is_ssl(&self) -> bool {
+        matches!(self, SecurityProtocol::Ssl | SecurityProtocol::SaslSsl)
+    }
+}
+
+impl FromStr for SecurityProtocol {
+    type Err = ConfigError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "plaintext" => Ok(SecurityProtocol::Plaintext),
+            "ssl" => Ok(SecurityProtocol::Ssl),
+            "sasl_plaintext" => Ok(SecurityProtocol::SaslPlaintext),
+            "sasl_ssl" => Ok(SecurityProtocol::SaslSsl),
+            _ => Err(ConfigError::InvalidValue(
+                "security.protocol",
+                value.to_string(),
+            )),
+        }
+    }
+}
+
+/// How clients authenticate with a ``Sasl*`` security protocol, this is
+/// ``sasl.mechanism``.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaslMechanism {
+    Plain,
+    ScramSha256,
+    ScramSha512,
+    Gssapi,
+    /// Set by ``KafkaConfig::with_oauth_token_provider``.
+    OAuthBearer,
+}
+
+impl SaslMechanism {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SaslMechanism::Plain => "PLAIN",
+            SaslMechanism::ScramSha256 => "SCRAM-SHA-256",
+            SaslMechanism::ScramSha512 => "SCRAM-SHA-512",
+            SaslMechanism::Gssapi => "GSSAPI",
+            SaslMechanism::OAuthBearer => "OAUTHBEARER",
+        }
+    }
+
+    fn requires_credentials(&self) -> bool {
+        !matches!(self, SaslMechanism::Gssapi | SaslMechanism::OAuthBearer)
+    }
+}
+
+impl FromStr for SaslMechanism {
+    type Err = ConfigError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_uppercase().as_str() {
+            "PLAIN" => Ok(SaslMechanism::Plain),
+            "SCRAM-SHA-256" => Ok(SaslMechanism::ScramSha256),
+            "SCRAM-SHA-512" => Ok(SaslMechanism::ScramSha512),
+            "GSSAPI" => Ok(SaslMechanism::Gssapi),
+            "OAUTHBEARER" => Ok(SaslMechanism::OAuthBearer),
+            _ => Err(ConfigError::InvalidValue(
+                "sasl.mechanism",
+                value.to_string(),
+            )),
+        }
+    }
+}
+
+/// The paths of the files used to set up TLS connections. Paths left to
+/// ``None`` use the librdkafka defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SslConfig {
+    /// The CA certificate used to verify the brokers.
+    pub ca_location: Option<String>,
+    /// The client certificate, for brokers that authenticate clients with
+    /// TLS.
+    pub certificate_location: Option<String>,
+    pub key_location: Option<String>,
+    pub key_password: Option<String>,
+}
+
+/// The settings of a Kafka client. Values that look like credentials,
+/// such as ``sasl.password``, are redacted from the ``Debug`` output.
+#[derive(Clone)]
 pub struct KafkaConfig {
     config_map: HashMap<String, String>,
+    pub(crate) oauth_token_provider: Option<Arc<dyn OAuthTokenProvider>>,
+}
+
+impl fmt::Debug for KafkaConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let config_map: BTreeMap<&str, &str> = self
+            .config_map
+            .iter()
+            .map(|(key, value)| {
+                let value = if is_secret(key) {
+                    "[redacted]"
+                } else {
+                    value.as_str()
+                };
+                (key.as_str(), value)
+            })
+            .collect();
+        f.debug_struct("KafkaConfig")
+            .field("config_map", &config_map)
+            .field("oauth_token_provider", &self.oauth_token_provider.is_some())
+            .finish()
+    }
 }
 
 impl KafkaConfig {
@@ -13,27 +233,96 @@ impl KafkaConfig {
     ) -> Self {
         let mut config_map = HashMap::new();
         config_map.insert("bootstrap.servers".to_string(), bootstrap_servers.join(","));
-        let config = Self { config_map };
+        let config = Self {
+            config_map,
+            oauth_token_provider: None,
+        };
         apply_override_params(config, override_params)
     }
 
+    /// Builds a consumer configuration. Fails if ``override_params`` sets
+    /// an invalid ``auto.offset.reset`` or configures auto commit: the
+    /// offsets have to be committed by the strategies once the messages
+    /// were processed, or messages could be lost on a crash.
     pub fn new_consumer_config(
         bootstrap_servers: Vec<String>,
         group_id: String,
-        auto_offset_reset: String,
+        auto_offset_reset: InitialOffset,
         _strict_offset_reset: bool, // TODO: Implement this
         override_params: Option<HashMap<String, String>>,
-    ) -> Self {
+    ) -> Result<Self, ConfigError> {
         let mut config = KafkaConfig::new_config(bootstrap_servers, None);
         config.config_map.insert("group.id".to_string(), group_id);
         config
             .config_map
             .insert("enable.auto.commit".to_string(), "false".to_string());
-        config
+        config.config_map.insert(
+            "auto.offset.reset".to_string(),
+            auto_offset_reset.as_str().to_string(),
+        );
+
+        let config = apply_override_params(config, override_params);
+        config.validate_consumer_config()?;
+        Ok(config)
+    }
+
+    fn validate_consumer_config(&self) -> Result<(), ConfigError> {
+        self.validate()?;
+        if let Some(value) = self.config_map.get("auto.offset.reset") {
+            value.parse::<InitialOffset>()?;
+        }
+        if self
             .config_map
-            .insert("auto.offset.reset".to_string(), auto_offset_reset);
+            .get("enable.auto.commit")
+            .is_some_and(|value| value != "false")
+        {
+            return Err(ConfigError::AutoCommitEnabled);
+        }
+        // It has no effect without auto commit, setting it is a mistake.
+        if self.config_map.contains_key("auto.commit.interval.ms") {
+            return Err(ConfigError::AutoCommitInterval);
+        }
+        Ok(())
+    }
 
-        apply_override_params(config, override_params)
+    /// Checks that the security settings are consistent, whether they were
+    /// set with the ``with_*`` methods or as override parameters.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let protocol = match self.config_map.get("security.protocol") {
+            Some(value) => value.parse()?,
+            None => SecurityProtocol::Plaintext,
+        };
+
+        if let Some(value) = self.config_map.get("sasl.mechanism") {
+            let mechanism: SaslMechanism = value.parse()?;
+            if !protocol.is_sasl() {
+                return Err(ConfigError::IncompatibleSecurityProtocol(
+                    "sasl.mechanism",
+                    "sasl_plaintext, sasl_ssl",
+                ));
+            }
+            if mechanism.requires_credentials() {
+                for key in ["sasl.username", "sasl.password"] {
+                    if !self.config_map.contains_key(key) {
+                        return Err(ConfigError::MissingCredentials(key, mechanism.as_str()));
+                    }
+                }
+            }
+        }
+
+        for key in [
+            "ssl.ca.location",
+            "ssl.certificate.location",
+            "ssl.key.location",
+        ] {
+            if self.config_map.contains_key(key) && !protocol.is_ssl() {
+                return Err(ConfigError::IncompatibleSecurityProtocol(
+                    key,
+                    "ssl, sasl_ssl",
+                ));
+            }
+        }
+        Ok(())
     }
 
     pub fn new_producer_config(
@@ -44,6 +333,101 @@ impl KafkaConfig {
 
         apply_override_params(config, override_params)
     }
+
+    /// Sets the partition assignor of a consumer configuration.
+    pub fn with_assignment_strategy(mut self, strategy: AssignmentStrategy) -> Self {
+        self.config_map.insert(
+            "partition.assignment.strategy".to_string(),
+            strategy.as_str().to_string(),
+        );
+        self
+    }
+
+    pub fn with_security_protocol(mut self, protocol: SecurityProtocol) -> Self {
+        self.config_map.insert(
+            "security.protocol".to_string(),
+            protocol.as_str().to_string(),
+        );
+        self
+    }
+
+    /// Sets the SASL mechanism and the credentials used with it. The
+    /// security protocol has to be one of the ``Sasl*`` protocols.
+    pub fn with_sasl(
+        mut self,
+        mechanism: SaslMechanism,
+        username: String,
+        password: String,
+    ) -> Self {
+        self.config_map
+            .insert("sasl.mechanism".to_string(), mechanism.as_str().to_string());
+        self.config_map
+            .insert("sasl.username".to_string(), username);
+        self.config_map
+            .insert("sasl.password".to_string(), password);
+        self
+    }
+
+    /// Authenticates with the ``OAUTHBEARER`` SASL mechanism using the
+    /// tokens of ``provider``. Consumers refresh the token before it
+    /// expires. The security protocol has to be one of the ``Sasl*``
+    /// protocols.
+    pub fn with_oauth_token_provider(mut self, provider: Arc<dyn OAuthTokenProvider>) -> Self {
+        self.config_map.insert(
+            "sasl.mechanism".to_string(),
+            SaslMechanism::OAuthBearer.as_str().to_string(),
+        );
+        self.oauth_token_provider = Some(provider);
+        self
+    }
+
+    /// Sets the files used for TLS. The security protocol has to be
+    /// ``Ssl`` or ``SaslSsl``.
+    pub fn with_ssl(mut self, ssl: SslConfig) -> Self {
+        for (key, value) in [
+            ("ssl.ca.location", ssl.ca_location),
+            ("ssl.certificate.location", ssl.certificate_location),
+            ("ssl.key.location", ssl.key_location),
+            ("ssl.key.password", ssl.key_password),
+        ] {
+            if let Some(value) = value {
+                self.config_map.insert(key.to_string(), value);
+            }
+        }
+        self
+    }
+
+    /// Makes librdkafka emit its statistics every ``interval``. They are
+    /// reported as ``arroyo.kafka.*`` gauges, tagged by broker and by
+    /// partition.
+    pub fn with_statistics_interval(mut self, interval: Duration) -> Self {
+        self.config_map.insert(
+            "statistics.interval.ms".to_string(),
+            interval.as_millis().to_string(),
+        );
+        self
+    }
+
+    /// Makes the consumer a static member of its group. A static member that
+    /// restarts with the same ``group_instance_id`` before ``session_timeout``
+    /// expires gets its previous assignment back without a rebalance, so the
+    /// timeout should cover the time it takes to restart the consumer. The
+    /// broker default is kept if ``session_timeout`` is ``None``.
+    pub fn with_static_membership(
+        mut self,
+        group_instance_id: String,
+        session_timeout: Option<Duration>,
+    ) -> Self {
+        self.config_map
+            .insert("group.instance.id".to_string(), group_instance_id);
+        if let Some(timeout) = session_timeout {
+            self.config_map.insert(
+                "session.timeout.ms".to_string(),
+                timeout.as_millis().to_string(),
+            );
+        }
+        self
+    }
 }
 
 impl From<KafkaConfig> for RdKafkaConfig {
@@ -60,35 +444,41 @@ fn apply_override_params(
     mut config: KafkaConfig,
     override_params: Option<HashMap<String, String>>,
 ) -> KafkaConfig {
-    match override_params {
-        Some(params) => {
-            for (param, value) in params {
-                config.config_map.insert(param, value);
-            }
+    if let Some(params) = override_params {
+        for (param, value) in params {
+            config.config_map.insert(param, value);
         }
-        None => {}
     }
     config
 }
 
 #[cfg(test)]
 mod tests {
-    use super::KafkaConfig;
+    use super::{
+        AssignmentStrategy, ConfigError, InitialOffset, KafkaConfig, SaslMechanism,
+        SecurityProtocol, SslConfig,
+    };
+    use crate::backends::kafka::oauth::{OAuthToken, OAuthTokenProvider};
     use rdkafka::config::ClientConfig as RdKafkaConfig;
     use std::collections::HashMap;
+    use std::error::Error;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::time::SystemTime;
 
     #[test]
     fn test_build_consumer_configuration() {
         let config = KafkaConfig::new_consumer_config(
             vec!["localhost:9092".to_string()],
             "my-group".to_string(),
-            "error".to_string(),
+            InitialOffset::Error,
             false,
             Some(HashMap::from([(
                 "queued.max.messages.kbytes".to_string(),
                 "1000000".to_string(),
             )])),
-        );
+        )
+        .unwrap();
 
         let rdkafka_config: RdKafkaConfig = config.into();
         assert_eq!(
@@ -96,4 +486,165 @@ mod tests {
             Some("1000000")
         );
     }
+
+    #[test]
+    fn test_assignment_strategy() {
+        let config = KafkaConfig::new_consumer_config(
+            vec!["localhost:9092".to_string()],
+            "my-group".to_string(),
+            InitialOffset::Error,
+            false,
+            None,
+        )
+        .unwrap()
+        .with_assignment_strategy(AssignmentStrategy::CooperativeSticky);
+
+        let rdkafka_config: RdKafkaConfig = config.into();
+        assert_eq!(
+            rdkafka_config.get("partition.assignment.strategy"),
+            Some("cooperative-sticky")
+        );
+    }
+
+    #[test]
+    fn test_static_membership() {
+        let config = KafkaConfig::new_consumer_config(
+            vec!["localhost:9092".to_string()],
+            "my-group".to_string(),
+            InitialOffset::Error,
+            false,
+            None,
+        )
+        .unwrap()
+        .with_static_membership("consumer-0".to_string(), Some(Duration::from_secs(60)));
+
+        let rdkafka_config: RdKafkaConfig = config.into();
+        assert_eq!(rdkafka_config.get("group.instance.id"), Some("consumer-0"));
+        assert_eq!(rdkafka_config.get("session.timeout.ms"), Some("60000"));
+    }
+
+    #[test]
+    fn test_invalid_consumer_configuration() {
+        let build = |key: &str, value: &str| {
+            KafkaConfig::new_consumer_config(
+                vec!["localhost:9092".to_string()],
+                "my-group".to_string(),
+                InitialOffset::Earliest,
+                false,
+                Some(HashMap::from([(key.to_string(), value.to_string())])),
+            )
+        };
+
+        assert!(build("auto.offset.reset", "smallest").is_ok());
+        assert_eq!(
+            build("auto.offset.reset", "oldest").unwrap_err(),
+            ConfigError::InvalidValue("auto.offset.reset", "oldest".to_string())
+        );
+        assert_eq!(
+            build("enable.auto.commit", "true").unwrap_err(),
+            ConfigError::AutoCommitEnabled
+        );
+        assert_eq!(
+            build("enable.auto.commit", "1").unwrap_err(),
+            ConfigError::AutoCommitEnabled
+        );
+        assert!(build("enable.auto.commit", "false").is_ok());
+        assert_eq!(
+            build("auto.commit.interval.ms", "5000").unwrap_err(),
+            ConfigError::AutoCommitInterval
+        );
+    }
+
+    #[test]
+    fn test_security_configuration() {
+        let config = KafkaConfig::new_config(vec!["localhost:9092".to_string()], None)
+            .with_security_protocol(SecurityProtocol::SaslSsl)
+            .with_sasl(
+                SaslMechanism::ScramSha512,
+                "snuba".to_string(),
+                "hunter2".to_string(),
+            )
+            .with_ssl(SslConfig {
+                ca_location: Some("/etc/kafka/ca.pem".to_string()),
+                ..Default::default()
+            });
+        assert_eq!(config.validate(), Ok(()));
+
+        let debug = format!("{:?}", config);
+        assert!(debug.contains("\"sasl.username\": \"snuba\""));
+        assert!(debug.contains("\"sasl.password\": \"[redacted]\""));
+        assert!(!debug.contains("hunter2"));
+
+        let rdkafka_config: RdKafkaConfig = config.into();
+        assert_eq!(rdkafka_config.get("security.protocol"), Some("sasl_ssl"));
+        assert_eq!(rdkafka_config.get("sasl.mechanism"), Some("SCRAM-SHA-512"));
+        assert_eq!(
+            rdkafka_config.get("ssl.ca.location"),
+            Some("/etc/kafka/ca.pem")
+        );
+    }
+
+    #[test]
+    fn test_invalid_security_configuration() {
+        let build = |params: &[(&str, &str)]| {
+            KafkaConfig::new_consumer_config(
+                vec!["localhost:9092".to_string()],
+                "my-group".to_string(),
+                InitialOffset::Earliest,
+                false,
+                Some(
+                    params
+                        .iter()
+                        .map(|(key, value)| (key.to_string(), value.to_string()))
+                        .collect(),
+                ),
+            )
+        };
+
+        assert!(build(&[("security.protocol", "SASL_PLAINTEXT")]).is_ok());
+        assert_eq!(
+            build(&[("security.protocol", "tls")]).unwrap_err(),
+            ConfigError::InvalidValue("security.protocol", "tls".to_string())
+        );
+        assert_eq!(
+            build(&[("sasl.mechanism", "PLAIN")]).unwrap_err(),
+            ConfigError::IncompatibleSecurityProtocol("sasl.mechanism", "sasl_plaintext, sasl_ssl")
+        );
+        assert_eq!(
+            build(&[
+                ("security.protocol", "sasl_ssl"),
+                ("sasl.mechanism", "PLAIN"),
+                ("sasl.username", "snuba"),
+            ])
+            .unwrap_err(),
+            ConfigError::MissingCredentials("sasl.password", "PLAIN")
+        );
+        assert_eq!(
+            build(&[("ssl.ca.location", "/etc/kafka/ca.pem")]).unwrap_err(),
+            ConfigError::IncompatibleSecurityProtocol("ssl.ca.location", "ssl, sasl_ssl")
+        );
+    }
+
+    #[test]
+    fn test_oauth_token_provider() {
+        struct StaticToken;
+        impl OAuthTokenProvider for StaticToken {
+            fn generate_token(&self) -> Result<OAuthToken, Box<dyn Error + Send + Sync>> {
+                Ok(OAuthToken {
+                    token: "token".to_string(),
+                    principal_name: "snuba".to_string(),
+                    expires_at: SystemTime::now() + Duration::from_secs(3600),
+                })
+            }
+        }
+
+        let config = KafkaConfig::new_config(vec!["localhost:9092".to_string()], None)
+            .with_security_protocol(SecurityProtocol::SaslSsl)
+            .with_oauth_token_provider(Arc::new(StaticToken));
+        assert_eq!(config.validate(), Ok(()));
+        assert!(format!("{:?}", config).contains("oauth_token_provider: true"));
+
+        let rdkafka_config: RdKafkaConfig = config.into();
+        assert_eq!(rdkafka_config.get("sasl.mechanism"), Some("OAUTHBEARER"));
+    }
 }
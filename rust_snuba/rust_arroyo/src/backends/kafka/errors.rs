@@ -1,16 +1,80 @@
 use rdkafka::error::{KafkaError, RDKafkaErrorCode};
 
-use crate::backends::ConsumerError;
+use crate::backends::{ConsumerError, ProducerError};
 
 impl From<KafkaError> for ConsumerError {
     fn from(err: KafkaError) -> Self {
-        match err {
-            KafkaError::OffsetFetch(RDKafkaErrorCode::OffsetOutOfRange) => {
-                ConsumerError::OffsetOutOfRange {
-                    source: Box::new(err),
-                }
-            }
-            other => ConsumerError::BrokerError(Box::new(other)),
+        if let KafkaError::PartitionEOF(_) = err {
+            return ConsumerError::EndOfPartition;
         }
+        let source = Box::new(err.clone());
+        match err.rdkafka_error_code() {
+            Some(RDKafkaErrorCode::PartitionEOF) => ConsumerError::EndOfPartition,
+            Some(RDKafkaErrorCode::OffsetOutOfRange) => ConsumerError::OffsetOutOfRange { source },
+            Some(
+                RDKafkaErrorCode::BrokerTransportFailure
+                | RDKafkaErrorCode::AllBrokersDown
+                | RDKafkaErrorCode::Resolve
+                | RDKafkaErrorCode::NetworkException
+                | RDKafkaErrorCode::RequestTimedOut
+                | RDKafkaErrorCode::OperationTimedOut,
+            ) => ConsumerError::BrokerTransportFailure { source },
+            Some(
+                RDKafkaErrorCode::Authentication
+                | RDKafkaErrorCode::SSL
+                | RDKafkaErrorCode::SaslAuthenticationFailed
+                | RDKafkaErrorCode::TopicAuthorizationFailed
+                | RDKafkaErrorCode::GroupAuthorizationFailed
+                | RDKafkaErrorCode::ClusterAuthorizationFailed,
+            ) => ConsumerError::AuthFailure { source },
+            Some(RDKafkaErrorCode::Fatal) => ConsumerError::Fatal { source },
+            _ => ConsumerError::BrokerError(source),
+        }
+    }
+}
+
+impl From<KafkaError> for ProducerError {
+    fn from(err: KafkaError) -> Self {
+        ProducerError::BrokerError(Box::new(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backends::ConsumerError;
+    use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+
+    #[test]
+    fn test_consumer_error() {
+        let error = |err: KafkaError| ConsumerError::from(err);
+
+        assert!(matches!(
+            error(KafkaError::PartitionEOF(0)),
+            ConsumerError::EndOfPartition
+        ));
+        assert!(matches!(
+            error(KafkaError::OffsetFetch(RDKafkaErrorCode::OffsetOutOfRange)),
+            ConsumerError::OffsetOutOfRange { .. }
+        ));
+        assert!(matches!(
+            error(KafkaError::MessageConsumption(
+                RDKafkaErrorCode::AllBrokersDown
+            )),
+            ConsumerError::BrokerTransportFailure { .. }
+        ));
+        assert!(matches!(
+            error(KafkaError::Global(
+                RDKafkaErrorCode::TopicAuthorizationFailed
+            )),
+            ConsumerError::AuthFailure { .. }
+        ));
+        assert!(matches!(
+            error(KafkaError::Global(RDKafkaErrorCode::Fatal)),
+            ConsumerError::Fatal { .. }
+        ));
+        assert!(matches!(
+            error(KafkaError::Canceled),
+            ConsumerError::BrokerError(_)
+        ));
     }
 }
@@ -1,28 +1,41 @@
 use super::kafka::config::KafkaConfig;
+use super::kafka::oauth::OAuthRefresher;
 use super::AssignmentCallbacks;
 use super::Consumer as ArroyoConsumer;
 use super::ConsumerError;
-use crate::backends::kafka::types::KafkaPayload;
-use crate::types::{BrokerMessage, Partition, Topic};
+use crate::backends::kafka::statistics::StatisticsRecorder;
+use crate::backends::kafka::types::{Headers, KafkaPayload};
+use crate::types::{BrokerMessage, Partition, Position, Topic};
 use chrono::{DateTime, NaiveDateTime, Utc};
-use rdkafka::client::ClientContext;
+use rdkafka::client::{ClientContext, NativeClient};
 use rdkafka::config::{ClientConfig, RDKafkaLogLevel};
 use rdkafka::consumer::base_consumer::BaseConsumer;
 use rdkafka::consumer::{CommitMode, Consumer, ConsumerContext, Rebalance};
-use rdkafka::error::KafkaResult;
-use rdkafka::message::{BorrowedHeaders, BorrowedMessage, Message};
+use rdkafka::error::{KafkaError, KafkaResult, RDKafkaErrorCode};
+use rdkafka::message::{BorrowedMessage, Message};
+use rdkafka::statistics::Statistics;
 use rdkafka::topic_partition_list::{Offset, TopicPartitionList};
+use rdkafka::types::RDKafkaRespErr;
+use rdkafka_sys as rdsys;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::ffi::{CStr, CString};
 use std::mem;
+use std::ptr;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 pub mod config;
 mod errors;
+pub mod oauth;
 pub mod producer;
+mod statistics;
+pub mod stream;
+pub mod trace_context;
 pub mod types;
 
+const SEEK_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Eq, Hash, PartialEq)]
 enum KafkaConsumerState {
     NotSubscribed,
@@ -48,7 +61,7 @@ impl KafkaConsumerState {
     }
 }
 
-fn create_kafka_message(msg: BorrowedMessage) -> BrokerMessage<KafkaPayload> {
+pub(crate) fn create_kafka_message(msg: &BorrowedMessage) -> BrokerMessage<KafkaPayload> {
     let topic = Topic {
         name: msg.topic().to_string(),
     };
@@ -60,15 +73,16 @@ fn create_kafka_message(msg: BorrowedMessage) -> BrokerMessage<KafkaPayload> {
 
     BrokerMessage::new(
         KafkaPayload {
-            key: msg.key().map(|k| k.to_vec()),
-            headers: msg.headers().map(BorrowedHeaders::detach),
-            payload: msg.payload().map(|p| p.to_vec()),
+            key: msg.key().map(Arc::from),
+            headers: msg.headers().map(Headers::from),
+            payload: msg.payload().map(Arc::from),
         },
-
         partition,
         msg.offset() as u64,
-
-        DateTime::from_utc(NaiveDateTime::from_timestamp_millis(time_millis).unwrap_or(NaiveDateTime::MIN), Utc),
+        DateTime::from_utc(
+            NaiveDateTime::from_timestamp_millis(time_millis).unwrap_or(NaiveDateTime::MIN),
+            Utc,
+        ),
     )
 }
 
@@ -79,11 +93,115 @@ pub struct CustomContext {
     // I am sure there has to be a better way to do this.
     callbacks: Mutex<Box<dyn AssignmentCallbacks>>,
     consumer_offsets: Arc<Mutex<HashMap<Partition, u64>>>,
+    // librdkafka resumes partitions when they are reassigned, so they are
+    // dropped from here on every rebalance.
+    paused: Arc<Mutex<HashSet<Partition>>>,
+    // The offsets staged for revoked partitions are dropped so that they are
+    // not committed over those of the next owner.
+    staged_offsets: Arc<Mutex<HashMap<Partition, Position>>>,
+    // The positions returned by on_revoke, committed by rebalance before the
+    // partitions are released.
+    revoke_offsets: Mutex<HashMap<Partition, Position>>,
+    // The positions of the asynchronous commits the broker did not
+    // acknowledge yet, and those committed since ``take_committed`` was last
+    // called which ``commit_offsets`` did not return.
+    async_commits: Arc<Mutex<HashMap<Partition, Position>>>,
+    committed: Arc<Mutex<HashMap<Partition, Position>>>,
+    statistics: StatisticsRecorder,
+}
+
+// Keeps the furthest position of each partition.
+fn merge_positions(
+    into: &mut HashMap<Partition, Position>,
+    positions: impl IntoIterator<Item = (Partition, Position)>,
+) {
+    for (partition, position) in positions {
+        let entry = into.entry(partition).or_insert(position);
+        *entry = entry.max(position);
+    }
+}
+
+impl CustomContext {
+    fn commit_revoke_offsets(&self, native_client: &NativeClient) {
+        let positions = mem::take(&mut *self.revoke_offsets.lock().unwrap());
+        if positions.is_empty() {
+            return;
+        }
+
+        let mut topic_map = HashMap::new();
+        for (partition, position) in positions.iter() {
+            topic_map.insert(
+                (partition.topic.name.clone(), partition.index as i32),
+                Offset::from_raw(position.offset as i64),
+            );
+        }
+        let partitions = TopicPartitionList::from_topic_map(&topic_map).unwrap();
+        let err = unsafe { rdsys::rd_kafka_commit(native_client.ptr(), partitions.ptr(), 0) };
+        if err != RDKafkaRespErr::RD_KAFKA_RESP_ERR_NO_ERROR {
+            log::error!(
+                "Failed to commit offsets on revoke: {}",
+                rdkafka::error::RDKafkaErrorCode::from(err)
+            );
+            return;
+        }
+        merge_positions(&mut self.committed.lock().unwrap(), positions);
+    }
 }
 
-impl ClientContext for CustomContext {}
+impl ClientContext for CustomContext {
+    fn stats(&self, statistics: Statistics) {
+        self.statistics.record(&statistics);
+    }
+}
 
 impl ConsumerContext for CustomContext {
+    // Same as the default implementation, except that the offsets returned by
+    // on_revoke are committed synchronously before the partitions are
+    // unassigned. This can't happen in pre_rebalance which has no access to
+    // the consumer.
+    fn rebalance(
+        &self,
+        native_client: &NativeClient,
+        err: RDKafkaRespErr,
+        tpl: &mut TopicPartitionList,
+    ) {
+        let rebalance = match err {
+            RDKafkaRespErr::RD_KAFKA_RESP_ERR__ASSIGN_PARTITIONS => Rebalance::Assign(tpl),
+            RDKafkaRespErr::RD_KAFKA_RESP_ERR__REVOKE_PARTITIONS => Rebalance::Revoke(tpl),
+            _ => {
+                let error = unsafe { CStr::from_ptr(rdsys::rd_kafka_err2str(err)) };
+                let error = error.to_string_lossy().into_owned();
+                log::error!("Error rebalancing: {}", error);
+                Rebalance::Error(error)
+            }
+        };
+
+        self.pre_rebalance(&rebalance);
+        self.commit_revoke_offsets(native_client);
+
+        let protocol =
+            unsafe { CStr::from_ptr(rdsys::rd_kafka_rebalance_protocol(native_client.ptr())) };
+        let cooperative = protocol.to_bytes() == b"COOPERATIVE";
+        unsafe {
+            match (err, cooperative) {
+                (RDKafkaRespErr::RD_KAFKA_RESP_ERR__ASSIGN_PARTITIONS, true) => {
+                    rdsys::rd_kafka_incremental_assign(native_client.ptr(), tpl.ptr());
+                }
+                (RDKafkaRespErr::RD_KAFKA_RESP_ERR__ASSIGN_PARTITIONS, false) => {
+                    rdsys::rd_kafka_assign(native_client.ptr(), tpl.ptr());
+                }
+                (_, true) => {
+                    rdsys::rd_kafka_incremental_unassign(native_client.ptr(), tpl.ptr());
+                }
+                (_, false) => {
+                    rdsys::rd_kafka_assign(native_client.ptr(), ptr::null());
+                }
+            }
+        }
+
+        self.post_rebalance(&rebalance);
+    }
+
     fn pre_rebalance(&self, rebalance: &Rebalance) {
         if let Rebalance::Revoke(list) = rebalance {
             let mut partitions: Vec<Partition> = Vec::new();
@@ -99,11 +217,19 @@ impl ConsumerContext for CustomContext {
             }
 
             let mut offsets = self.consumer_offsets.lock().unwrap();
+            let mut paused = self.paused.lock().unwrap();
+            let mut staged_offsets = self.staged_offsets.lock().unwrap();
             for partition in partitions.iter() {
                 offsets.remove(partition);
+                paused.remove(partition);
+                staged_offsets.remove(partition);
             }
+            drop(offsets);
+            drop(paused);
+            drop(staged_offsets);
 
-            self.callbacks.lock().unwrap().on_revoke(partitions);
+            let revoke_offsets = self.callbacks.lock().unwrap().on_revoke(partitions);
+            *self.revoke_offsets.lock().unwrap() = revoke_offsets;
         }
     }
 
@@ -125,42 +251,180 @@ impl ConsumerContext for CustomContext {
                 );
             }
             let mut offsets = self.consumer_offsets.lock().unwrap();
+            let mut paused = self.paused.lock().unwrap();
             for (partition, offset) in map.clone() {
+                paused.remove(&partition);
                 offsets.insert(partition, offset);
             }
+            drop(offsets);
+            drop(paused);
             self.callbacks.lock().unwrap().on_assign(map);
         }
     }
 
-    fn commit_callback(&self, _: KafkaResult<()>, _offsets: &TopicPartitionList) {}
+    fn commit_callback(&self, result: KafkaResult<()>, offsets: &TopicPartitionList) {
+        // Asynchronous commits are only reported here, synchronous ones are
+        // too but they were already returned by ``commit_offsets``.
+        match result {
+            Ok(()) => {}
+            Err(KafkaError::ConsumerCommit(RDKafkaErrorCode::NoOffset)) => return,
+            Err(err) => {
+                log::error!("Failed to commit offsets: {}", err);
+                return;
+            }
+        }
+        let mut async_commits = self.async_commits.lock().unwrap();
+        let mut acknowledged = HashMap::new();
+        for element in offsets.elements() {
+            let partition = Partition {
+                topic: Topic {
+                    name: element.topic().to_string(),
+                },
+                index: element.partition() as u16,
+            };
+            // A later commit of the partition is still waiting for the broker.
+            let Some(position) = async_commits.get(&partition) else {
+                continue;
+            };
+            if element.offset() == Offset::Offset(position.offset as i64) {
+                let position = async_commits.remove(&partition).unwrap();
+                acknowledged.insert(partition, position);
+            }
+        }
+        drop(async_commits);
+        merge_positions(&mut self.committed.lock().unwrap(), acknowledged);
+    }
 }
 
-pub struct KafkaConsumer {
+/// The rdkafka consumer a ``KafkaConsumer`` is built on.
+pub trait ConsumerBackend: Consumer<CustomContext> + Sized {
+    fn create(config: &ClientConfig, context: CustomContext) -> KafkaResult<Self>;
+
+    fn poll_message(&self, timeout: Duration) -> Option<KafkaResult<BorrowedMessage<'_>>>;
+
+    /// How ``commit_offsets`` commits, a synchronous commit waits for the
+    /// broker to acknowledge the offsets.
+    fn commit_mode() -> CommitMode {
+        CommitMode::Sync
+    }
+}
+
+impl ConsumerBackend for BaseConsumer<CustomContext> {
+    fn create(config: &ClientConfig, context: CustomContext) -> KafkaResult<Self> {
+        config.create_with_context(context)
+    }
+
+    fn poll_message(&self, timeout: Duration) -> Option<KafkaResult<BorrowedMessage<'_>>> {
+        self.poll(timeout)
+    }
+}
+
+pub struct KafkaConsumer<C: ConsumerBackend = BaseConsumer<CustomContext>> {
     // TODO: This has to be an option as of now because rdkafka requires
     // callbacks during the instantiation. While the streaming processor
     // can only pass the callbacks during the subscribe call.
     // So we need to build the kafka consumer upon subscribe and not
     // in the constructor.
-    pub consumer: Option<BaseConsumer<CustomContext>>,
+    pub consumer: Option<C>,
     config: KafkaConfig,
     state: KafkaConsumerState,
     offsets: Arc<Mutex<HashMap<Partition, u64>>>,
-    staged_offsets: HashMap<Partition, u64>,
+    paused: Arc<Mutex<HashSet<Partition>>>,
+    staged_offsets: Arc<Mutex<HashMap<Partition, Position>>>,
+    async_commits: Arc<Mutex<HashMap<Partition, Position>>>,
+    committed: Arc<Mutex<HashMap<Partition, Position>>>,
+    commit_mode: CommitMode,
+    oauth_refresher: Option<OAuthRefresher>,
 }
 
 impl KafkaConsumer {
     pub fn new(config: KafkaConfig) -> Self {
+        Self::build(config)
+    }
+}
+
+impl<C: ConsumerBackend> KafkaConsumer<C> {
+    fn build(config: KafkaConfig) -> Self {
         Self {
             consumer: None,
             config,
             state: KafkaConsumerState::NotSubscribed,
             offsets: Arc::new(Mutex::new(HashMap::new())),
-            staged_offsets: HashMap::new(),
+            paused: Arc::new(Mutex::new(HashSet::new())),
+            staged_offsets: Arc::new(Mutex::new(HashMap::new())),
+            async_commits: Arc::new(Mutex::new(HashMap::new())),
+            committed: Arc::new(Mutex::new(HashMap::new())),
+            commit_mode: C::commit_mode(),
+            oauth_refresher: None,
+        }
+    }
+
+    /// Overrides how ``commit_offsets`` commits, see
+    /// ``ConsumerBackend::commit_mode``. An asynchronous commit returns
+    /// before the broker acknowledged the offsets, they are returned by
+    /// ``take_committed`` once it did. Its failures are only logged. The offsets returned by ``on_revoke`` and those committed on
+    /// shutdown are always committed synchronously.
+    pub fn with_commit_mode(mut self, mode: CommitMode) -> Self {
+        self.commit_mode = mode;
+        self
+    }
+
+    fn commit_staged_offsets(
+        &mut self,
+        mode: CommitMode,
+    ) -> Result<HashMap<Partition, Position>, ConsumerError> {
+        self.state.assert_consuming_state()?;
+
+        // Offsets staged after their partition was revoked, for a retried
+        // commit, belong to the next owner now.
+        let assigned = self.offsets.lock().unwrap();
+        let mut staged_offsets = self.staged_offsets.lock().unwrap();
+        staged_offsets.retain(|partition, _| {
+            let retain = assigned.contains_key(partition);
+            if !retain {
+                log::warn!(
+                    "Not committing the offset of revoked partition {}",
+                    partition
+                );
+            }
+            retain
+        });
+        drop(assigned);
+        if staged_offsets.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let mut topic_map = HashMap::new();
+        for (partition, position) in staged_offsets.iter() {
+            topic_map.insert(
+                (partition.topic.name.clone(), partition.index as i32),
+                Offset::from_raw(position.offset as i64),
+            );
+        }
+
+        let consumer = self.consumer.as_mut().unwrap();
+        let partitions = TopicPartitionList::from_topic_map(&topic_map).unwrap();
+        // The offsets stay staged if the commit fails.
+        consumer.commit(&partitions, mode)?;
+
+        let positions = mem::take(&mut *staged_offsets);
+        if matches!(mode, CommitMode::Async) {
+            merge_positions(&mut self.async_commits.lock().unwrap(), positions);
+            return Ok(HashMap::new());
+        }
+        Ok(positions)
+    }
+
+    fn refresh_oauth_token(&mut self) {
+        if let (Some(refresher), Some(consumer)) =
+            (self.oauth_refresher.as_mut(), self.consumer.as_ref())
+        {
+            refresher.maybe_refresh(consumer.client().native_client());
         }
     }
 }
 
-impl<'a> ArroyoConsumer<'a, KafkaPayload> for KafkaConsumer {
+impl<'a, C: ConsumerBackend> ArroyoConsumer<'a, KafkaPayload> for KafkaConsumer<C> {
     fn subscribe(
         &mut self,
         topics: &[Topic],
@@ -169,17 +433,27 @@ impl<'a> ArroyoConsumer<'a, KafkaPayload> for KafkaConsumer {
         let context = CustomContext {
             callbacks: Mutex::new(callbacks),
             consumer_offsets: self.offsets.clone(),
+            paused: self.paused.clone(),
+            staged_offsets: self.staged_offsets.clone(),
+            revoke_offsets: Mutex::new(HashMap::new()),
+            async_commits: self.async_commits.clone(),
+            committed: self.committed.clone(),
+            statistics: StatisticsRecorder::default(),
         };
 
         let mut config_obj: ClientConfig = self.config.clone().into();
 
-        let consumer: BaseConsumer<CustomContext> = config_obj
-            .set_log_level(RDKafkaLogLevel::Warning)
-            .create_with_context(context)?;
+        let consumer = C::create(config_obj.set_log_level(RDKafkaLogLevel::Warning), context)?;
         let topic_str: Vec<&str> = topics.iter().map(|t| t.name.as_ref()).collect();
         consumer.subscribe(&topic_str)?;
         self.consumer = Some(consumer);
         self.state = KafkaConsumerState::Consuming;
+        self.oauth_refresher = self
+            .config
+            .oauth_token_provider
+            .clone()
+            .map(OAuthRefresher::new);
+        self.refresh_oauth_token();
         Ok(())
     }
 
@@ -196,15 +470,16 @@ impl<'a> ArroyoConsumer<'a, KafkaPayload> for KafkaConsumer {
         timeout: Option<Duration>,
     ) -> Result<Option<BrokerMessage<KafkaPayload>>, ConsumerError> {
         self.state.assert_consuming_state()?;
+        self.refresh_oauth_token();
 
         let duration = timeout.unwrap_or(Duration::ZERO);
         let consumer = self.consumer.as_mut().unwrap();
-        let res = consumer.poll(duration);
+        let res = consumer.poll_message(duration);
         match res {
             None => Ok(None),
             Some(res) => {
                 let msg = res?;
-                Ok(Some(create_kafka_message(msg)))
+                Ok(Some(create_kafka_message(&msg)))
             }
         }
     }
@@ -213,7 +488,7 @@ impl<'a> ArroyoConsumer<'a, KafkaPayload> for KafkaConsumer {
         self.state.assert_consuming_state()?;
 
         let mut topic_map = HashMap::new();
-        for partition in partitions {
+        for partition in partitions.iter().cloned() {
             let offset = *self
                 .offsets
                 .lock()
@@ -229,6 +504,7 @@ impl<'a> ArroyoConsumer<'a, KafkaPayload> for KafkaConsumer {
         let consumer = self.consumer.as_ref().unwrap();
         let topic_partition_list = TopicPartitionList::from_topic_map(&topic_map).unwrap();
         consumer.pause(&topic_partition_list)?;
+        self.paused.lock().unwrap().extend(partitions);
 
         Ok(())
     }
@@ -237,8 +513,8 @@ impl<'a> ArroyoConsumer<'a, KafkaPayload> for KafkaConsumer {
         self.state.assert_consuming_state()?;
 
         let mut topic_partition_list = TopicPartitionList::new();
-        for partition in partitions {
-            if !self.offsets.lock().unwrap().contains_key(&partition) {
+        for partition in partitions.iter() {
+            if !self.offsets.lock().unwrap().contains_key(partition) {
                 return Err(ConsumerError::UnassignedPartition);
             }
             topic_partition_list.add_partition(&partition.topic.name, partition.index as i32);
@@ -246,13 +522,17 @@ impl<'a> ArroyoConsumer<'a, KafkaPayload> for KafkaConsumer {
 
         let consumer = self.consumer.as_mut().unwrap();
         consumer.resume(&topic_partition_list)?;
+        let mut paused = self.paused.lock().unwrap();
+        for partition in partitions.iter() {
+            paused.remove(partition);
+        }
 
         Ok(())
     }
 
     fn paused(&self) -> Result<HashSet<Partition>, ConsumerError> {
-        //TODO: Implement this
-        Ok(HashSet::new())
+        self.state.assert_consuming_state()?;
+        Ok(self.paused.lock().unwrap().clone())
     }
 
     fn tell(&self) -> Result<HashMap<Partition, u64>, ConsumerError> {
@@ -260,41 +540,117 @@ impl<'a> ArroyoConsumer<'a, KafkaPayload> for KafkaConsumer {
         Ok(self.offsets.lock().unwrap().clone())
     }
 
-    fn seek(&self, _: HashMap<Partition, u64>) -> Result<(), ConsumerError> {
-        //TODO: Implement this
-        Ok(())
+    fn high_watermarks(&self) -> Result<HashMap<Partition, u64>, ConsumerError> {
+        self.state.assert_consuming_state()?;
+        let consumer = self.consumer.as_ref().unwrap();
+        let mut watermarks = HashMap::new();
+        for partition in self.offsets.lock().unwrap().keys() {
+            let topic = CString::new(partition.topic.name.as_str()).unwrap();
+            let mut low = -1;
+            let mut high = -1;
+            // Unlike ``fetch_watermarks`` this reads the watermarks cached
+            // from the last fetch response instead of querying the broker.
+            let err = unsafe {
+                rdsys::rd_kafka_get_watermark_offsets(
+                    consumer.client().native_ptr(),
+                    topic.as_ptr(),
+                    partition.index as i32,
+                    &mut low,
+                    &mut high,
+                )
+            };
+            if err == RDKafkaRespErr::RD_KAFKA_RESP_ERR_NO_ERROR && high >= 0 {
+                watermarks.insert(partition.clone(), high as u64);
+            }
+        }
+        Ok(watermarks)
     }
 
-    fn stage_offsets(
-        &mut self,
-        offsets: HashMap<Partition, u64>,
-    ) -> Result<(), ConsumerError> {
+    fn seek(&mut self, offsets: HashMap<Partition, u64>) -> Result<(), ConsumerError> {
+        self.state.assert_consuming_state()?;
+        let mut current = self.offsets.lock().unwrap();
+        if offsets
+            .keys()
+            .any(|partition| !current.contains_key(partition))
+        {
+            return Err(ConsumerError::UnassignedPartition);
+        }
+
+        let consumer = self.consumer.as_ref().unwrap();
         for (partition, offset) in offsets {
-            self.staged_offsets.insert(partition, offset);
+            consumer.seek(
+                &partition.topic.name,
+                partition.index as i32,
+                Offset::from_raw(offset as i64),
+                SEEK_TIMEOUT,
+            )?;
+            current.insert(partition, offset);
         }
         Ok(())
     }
 
-    fn commit_offsets(&mut self) -> Result<HashMap<Partition, u64>, ConsumerError> {
+    fn seek_to_timestamp(
+        &mut self,
+        partitions: HashSet<Partition>,
+        timestamp: DateTime<Utc>,
+    ) -> Result<HashMap<Partition, u64>, ConsumerError> {
         self.state.assert_consuming_state()?;
 
-        let mut topic_map = HashMap::new();
-        for (partition, offset) in self.staged_offsets.iter() {
-            topic_map.insert(
-                (partition.topic.name.clone(), partition.index as i32),
-                Offset::from_raw(*offset as i64),
+        let mut topic_partition_list = TopicPartitionList::new();
+        for partition in partitions.iter() {
+            topic_partition_list.add_partition_offset(
+                &partition.topic.name,
+                partition.index as i32,
+                Offset::Offset(timestamp.timestamp_millis()),
+            )?;
+        }
+
+        let consumer = self.consumer.as_ref().unwrap();
+        let found = consumer.offsets_for_times(topic_partition_list, SEEK_TIMEOUT)?;
+        let mut offsets = HashMap::new();
+        for element in found.elements() {
+            let offset = match element.offset() {
+                Offset::Offset(offset) => offset,
+                // No message after the timestamp
+                _ => {
+                    consumer
+                        .fetch_watermarks(element.topic(), element.partition(), SEEK_TIMEOUT)?
+                        .1
+                }
+            };
+            offsets.insert(
+                Partition {
+                    topic: Topic {
+                        name: element.topic().to_string(),
+                    },
+                    index: element.partition() as u16,
+                },
+                offset as u64,
             );
         }
 
-        let consumer = self.consumer.as_mut().unwrap();
-        let partitions = TopicPartitionList::from_topic_map(&topic_map).unwrap();
-        consumer.commit(&partitions, CommitMode::Sync).unwrap();
+        self.seek(offsets.clone())?;
+        Ok(offsets)
+    }
+
+    fn stage_offsets(
+        &mut self,
+        offsets: HashMap<Partition, Position>,
+    ) -> Result<(), ConsumerError> {
+        self.staged_offsets.lock().unwrap().extend(offsets);
+        Ok(())
+    }
+
+    fn commit_offsets(&mut self) -> Result<HashMap<Partition, Position>, ConsumerError> {
+        self.commit_staged_offsets(self.commit_mode)
+    }
 
-        // Clear staged offsets
-        let cleared_map = HashMap::new();
-        let prev_offsets = mem::replace(&mut self.staged_offsets, cleared_map);
+    fn commit_offsets_sync(&mut self) -> Result<HashMap<Partition, Position>, ConsumerError> {
+        self.commit_staged_offsets(CommitMode::Sync)
+    }
 
-        Ok(prev_offsets)
+    fn take_committed(&mut self) -> HashMap<Partition, Position> {
+        mem::take(&mut *self.committed.lock().unwrap())
     }
 
     fn close(&mut self) {
@@ -309,22 +665,27 @@ impl<'a> ArroyoConsumer<'a, KafkaPayload> for KafkaConsumer {
 
 #[cfg(test)]
 mod tests {
-    use super::{AssignmentCallbacks, KafkaConsumer};
-    use crate::backends::kafka::config::KafkaConfig;
+    use super::{AssignmentCallbacks, CustomContext, KafkaConsumer, StatisticsRecorder};
+    use crate::backends::kafka::config::{InitialOffset, KafkaConfig};
     use crate::backends::Consumer;
-    use crate::types::{Partition, Topic};
+    use crate::types::{Partition, Position, Topic};
     use chrono::Utc;
     use rdkafka::admin::{AdminClient, AdminOptions, NewTopic, TopicReplication};
     use rdkafka::client::DefaultClientContext;
     use rdkafka::config::ClientConfig;
-    use std::collections::HashMap;
+    use rdkafka::consumer::{ConsumerContext, Rebalance};
+    use rdkafka::topic_partition_list::{Offset, TopicPartitionList};
+    use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, Mutex};
     use std::thread::sleep;
     use std::time::Duration;
 
     struct EmptyCallbacks {}
     impl AssignmentCallbacks for EmptyCallbacks {
         fn on_assign(&mut self, _: HashMap<Partition, u64>) {}
-        fn on_revoke(&mut self, _: Vec<Partition>) {}
+        fn on_revoke(&mut self, _: Vec<Partition>) -> HashMap<Partition, Position> {
+            HashMap::new()
+        }
     }
 
     fn get_admin_client() -> AdminClient<DefaultClientContext> {
@@ -362,10 +723,11 @@ mod tests {
         let configuration = KafkaConfig::new_consumer_config(
             vec!["localhost:9092".to_string()],
             "my-group".to_string(),
-            "latest".to_string(),
+            InitialOffset::Latest,
             false,
             None,
-        );
+        )
+        .unwrap();
         let mut consumer = KafkaConsumer::new(configuration);
         let topic = Topic {
             name: "test".to_string(),
@@ -380,10 +742,11 @@ mod tests {
         let configuration = KafkaConfig::new_consumer_config(
             vec!["localhost:9092".to_string()],
             "my-group-1".to_string(),
-            "latest".to_string(),
+            InitialOffset::Latest,
             false,
             None,
-        );
+        )
+        .unwrap();
         let mut consumer = KafkaConsumer::new(configuration);
         let topic = Topic {
             name: "test".to_string(),
@@ -411,10 +774,11 @@ mod tests {
         let configuration = KafkaConfig::new_consumer_config(
             vec!["localhost:9092".to_string()],
             "my-group-2".to_string(),
-            "latest".to_string(),
+            InitialOffset::Latest,
             false,
             None,
-        );
+        )
+        .unwrap();
 
         let mut consumer = KafkaConsumer::new(configuration);
         let topic = Topic {
@@ -422,11 +786,13 @@ mod tests {
         };
 
         let my_callbacks: Box<dyn AssignmentCallbacks> = Box::new(EmptyCallbacks {});
-        consumer.subscribe(&[topic.clone()], my_callbacks).unwrap();
+        consumer
+            .subscribe(std::slice::from_ref(&topic), my_callbacks)
+            .unwrap();
 
         let positions = HashMap::from([(
             Partition { topic, index: 0 },
-            100,
+            Position::new(100, Utc::now()),
         )]);
 
         consumer.stage_offsets(positions.clone()).unwrap();
@@ -448,6 +814,91 @@ mod tests {
         delete_topic("test").await;
     }
 
+    #[test]
+    fn test_revoke_drops_staged_offsets() {
+        let topic = Topic {
+            name: "test".to_string(),
+        };
+        let partition = |index| Partition {
+            topic: topic.clone(),
+            index,
+        };
+        let timestamp = Utc::now();
+        let context = CustomContext {
+            callbacks: Mutex::new(Box::new(EmptyCallbacks {})),
+            consumer_offsets: Arc::new(Mutex::new(HashMap::from([
+                (partition(0), 10),
+                (partition(1), 20),
+            ]))),
+            paused: Arc::new(Mutex::new(HashSet::new())),
+            staged_offsets: Arc::new(Mutex::new(HashMap::from([
+                (partition(0), Position::new(15, timestamp)),
+                (partition(1), Position::new(25, timestamp)),
+            ]))),
+            revoke_offsets: Mutex::new(HashMap::new()),
+            async_commits: Arc::new(Mutex::new(HashMap::new())),
+            committed: Arc::new(Mutex::new(HashMap::new())),
+            statistics: StatisticsRecorder::default(),
+        };
+
+        let mut revoked = TopicPartitionList::new();
+        revoked.add_partition("test", 1);
+        context.pre_rebalance(&Rebalance::Revoke(&revoked));
+
+        assert_eq!(
+            *context.staged_offsets.lock().unwrap(),
+            HashMap::from([(partition(0), Position::new(15, timestamp))])
+        );
+        assert_eq!(
+            *context.consumer_offsets.lock().unwrap(),
+            HashMap::from([(partition(0), 10)])
+        );
+    }
+
+    #[test]
+    fn test_acknowledged_async_commits() {
+        let topic = Topic {
+            name: "test".to_string(),
+        };
+        let partition = |index| Partition {
+            topic: topic.clone(),
+            index,
+        };
+        let timestamp = Utc::now();
+        let context = CustomContext {
+            callbacks: Mutex::new(Box::new(EmptyCallbacks {})),
+            consumer_offsets: Arc::new(Mutex::new(HashMap::new())),
+            paused: Arc::new(Mutex::new(HashSet::new())),
+            staged_offsets: Arc::new(Mutex::new(HashMap::new())),
+            revoke_offsets: Mutex::new(HashMap::new()),
+            async_commits: Arc::new(Mutex::new(HashMap::from([
+                (partition(0), Position::new(15, timestamp)),
+                (partition(1), Position::new(25, timestamp)),
+            ]))),
+            committed: Arc::new(Mutex::new(HashMap::new())),
+            statistics: StatisticsRecorder::default(),
+        };
+
+        // The commit of partition 1 was superseded by one still in flight.
+        let mut offsets = TopicPartitionList::new();
+        offsets
+            .add_partition_offset("test", 0, Offset::Offset(15))
+            .unwrap();
+        offsets
+            .add_partition_offset("test", 1, Offset::Offset(20))
+            .unwrap();
+        context.commit_callback(Ok(()), &offsets);
+
+        assert_eq!(
+            *context.committed.lock().unwrap(),
+            HashMap::from([(partition(0), Position::new(15, timestamp))])
+        );
+        assert_eq!(
+            *context.async_commits.lock().unwrap(),
+            HashMap::from([(partition(1), Position::new(25, timestamp))])
+        );
+    }
+
     #[test]
     fn test_pause() {}
 
@@ -0,0 +1,182 @@
+use crate::utils::metrics;
+use rdkafka::client::NativeClient;
+use rdkafka::types::RDKafkaRespErr;
+use rdkafka_sys as rdsys;
+use std::error::Error;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::ptr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+// Tokens are refreshed once this fraction of their lifetime has elapsed.
+const REFRESH_AT: f64 = 0.8;
+// Delay before trying again after a failed refresh.
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// A token for the ``OAUTHBEARER`` SASL mechanism.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OAuthToken {
+    pub token: String,
+    pub principal_name: String,
+    pub expires_at: SystemTime,
+}
+
+/// Fetches short lived tokens, for example from the metadata server of a
+/// cloud provider. It is called from the thread that polls the consumer, so
+/// it should not take long.
+pub trait OAuthTokenProvider: Send + Sync {
+    fn generate_token(&self) -> Result<OAuthToken, Box<dyn Error + Send + Sync>>;
+}
+
+/// Hands a new token from the provider to librdkafka whenever the previous
+/// one is about to expire.
+///
+/// librdkafka 1.9 only calls back into the application for a token through
+/// a configuration callback the Rust client does not expose, so the token is
+/// refreshed from ``poll`` instead.
+pub(crate) struct OAuthRefresher {
+    provider: Arc<dyn OAuthTokenProvider>,
+    next_refresh: Option<Instant>,
+}
+
+impl OAuthRefresher {
+    pub(crate) fn new(provider: Arc<dyn OAuthTokenProvider>) -> Self {
+        Self {
+            provider,
+            next_refresh: None,
+        }
+    }
+
+    /// Returns a new token if it is time to refresh, and schedules the next
+    /// refresh.
+    fn take_token(
+        &mut self,
+        now: Instant,
+    ) -> Option<Result<OAuthToken, Box<dyn Error + Send + Sync>>> {
+        if self
+            .next_refresh
+            .is_some_and(|next_refresh| now < next_refresh)
+        {
+            return None;
+        }
+        let token = self.provider.generate_token();
+        let delay = match &token {
+            Ok(token) => {
+                let lifetime = token
+                    .expires_at
+                    .duration_since(SystemTime::now())
+                    .unwrap_or(Duration::ZERO);
+                lifetime.mul_f64(REFRESH_AT)
+            }
+            Err(_) => RETRY_DELAY,
+        };
+        self.next_refresh = Some(now + delay);
+        Some(token)
+    }
+
+    pub(crate) fn maybe_refresh(&mut self, client: &NativeClient) {
+        let token = match self.take_token(Instant::now()) {
+            None => return,
+            Some(Ok(token)) => token,
+            Some(Err(error)) => {
+                log::error!("Failed to refresh the OAuth token: {}", error);
+                metrics::increment("arroyo.consumer.oauth_refresh.failure", None, None, None);
+                let error = CString::new(error.to_string()).unwrap_or_default();
+                unsafe {
+                    rdsys::rd_kafka_oauthbearer_set_token_failure(client.ptr(), error.as_ptr())
+                };
+                return;
+            }
+        };
+
+        if let Err(error) = set_token(client, &token) {
+            log::error!("Failed to set the OAuth token: {}", error);
+            metrics::increment("arroyo.consumer.oauth_refresh.failure", None, None, None);
+            return;
+        }
+        metrics::increment("arroyo.consumer.oauth_refresh.success", None, None, None);
+    }
+}
+
+fn set_token(client: &NativeClient, token: &OAuthToken) -> Result<(), String> {
+    let value = CString::new(token.token.as_str()).map_err(|e| e.to_string())?;
+    let principal_name = CString::new(token.principal_name.as_str()).map_err(|e| e.to_string())?;
+    let lifetime_ms = token
+        .expires_at
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|e| e.to_string())?
+        .as_millis() as i64;
+
+    let mut errstr = [0 as c_char; 512];
+    let err = unsafe {
+        rdsys::rd_kafka_oauthbearer_set_token(
+            client.ptr(),
+            value.as_ptr(),
+            lifetime_ms,
+            principal_name.as_ptr(),
+            ptr::null_mut(),
+            0,
+            errstr.as_mut_ptr(),
+            errstr.len(),
+        )
+    };
+    if err != RDKafkaRespErr::RD_KAFKA_RESP_ERR_NO_ERROR {
+        let error = unsafe { CStr::from_ptr(errstr.as_ptr()) };
+        return Err(error.to_string_lossy().into_owned());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OAuthRefresher, OAuthToken, OAuthTokenProvider, RETRY_DELAY};
+    use std::error::Error;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, SystemTime};
+
+    #[derive(Default)]
+    struct TestProvider {
+        calls: AtomicUsize,
+        fail: AtomicBool,
+    }
+
+    impl OAuthTokenProvider for TestProvider {
+        fn generate_token(&self) -> Result<OAuthToken, Box<dyn Error + Send + Sync>> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            if self.fail.load(Ordering::Relaxed) {
+                return Err("metadata server unavailable".into());
+            }
+            Ok(OAuthToken {
+                token: "token".to_string(),
+                principal_name: "snuba".to_string(),
+                expires_at: SystemTime::now() + Duration::from_secs(100),
+            })
+        }
+    }
+
+    #[test]
+    fn test_refresh_schedule() {
+        let provider = Arc::new(TestProvider::default());
+        let mut refresher = OAuthRefresher::new(provider.clone());
+        let start = Instant::now();
+
+        assert!(refresher.take_token(start).unwrap().is_ok());
+        assert!(refresher
+            .take_token(start + Duration::from_secs(60))
+            .is_none());
+        assert_eq!(provider.calls.load(Ordering::Relaxed), 1);
+
+        // Refreshed before the token expires
+        provider.fail.store(true, Ordering::Relaxed);
+        let now = start + Duration::from_secs(81);
+        assert!(refresher.take_token(now).unwrap().is_err());
+
+        // Failures are retried sooner
+        assert!(refresher.take_token(now + RETRY_DELAY / 2).is_none());
+        provider.fail.store(false, Ordering::Relaxed);
+        assert!(refresher.take_token(now + RETRY_DELAY).unwrap().is_ok());
+        assert_eq!(provider.calls.load(Ordering::Relaxed), 3);
+    }
+}
@@ -1,22 +1,96 @@
 use crate::backends::kafka::config::KafkaConfig;
-use crate::backends::kafka::types::KafkaPayload;
+use crate::backends::kafka::create_kafka_message;
+use crate::backends::kafka::statistics::StatisticsRecorder;
+use crate::backends::kafka::trace_context::TraceContext;
+use crate::backends::kafka::types::{Headers, KafkaPayload};
 use crate::backends::Producer as ArroyoProducer;
-use crate::types::TopicOrPartition;
+use crate::backends::{ProduceFuture, ProducerError};
+use crate::types::{BrokerMessage, TopicOrPartition};
+use futures::channel::oneshot;
+use futures::{future, FutureExt};
+use log::error;
+use rdkafka::client::ClientContext;
 use rdkafka::config::ClientConfig;
-use rdkafka::producer::{BaseProducer, BaseRecord, Producer};
+use rdkafka::producer::{BaseRecord, DeliveryResult, Producer, ProducerContext, ThreadedProducer};
+use rdkafka::statistics::Statistics;
+use std::sync::Arc;
 use std::time::Duration;
 
+const FLUSH_TIMEOUT: Duration = Duration::from_millis(5000);
+
+/// Receives the outcome of every message produced by a ``KafkaProducer``,
+/// either the message as it was written to the broker or the error that
+/// prevented its delivery. It is called from the producer's polling thread.
+pub type DeliveryCallback =
+    Arc<dyn Fn(Result<BrokerMessage<KafkaPayload>, ProducerError>) + Send + Sync>;
+
+type DeliverySender = oneshot::Sender<Result<BrokerMessage<KafkaPayload>, ProducerError>>;
+
+pub struct DeliveryContext {
+    callback: Option<DeliveryCallback>,
+    statistics: StatisticsRecorder,
+}
+
+impl ClientContext for DeliveryContext {
+    fn stats(&self, statistics: Statistics) {
+        self.statistics.record(&statistics);
+    }
+}
+
+impl ProducerContext for DeliveryContext {
+    // Messages produced with ``produce_async`` carry the sender that
+    // resolves their future.
+    type DeliveryOpaque = Box<Option<DeliverySender>>;
+
+    fn delivery(&self, delivery_result: &DeliveryResult<'_>, sender: Self::DeliveryOpaque) {
+        let result = match delivery_result {
+            Ok(message) => Ok(create_kafka_message(message)),
+            Err((err, _)) => Err(ProducerError::from(err.clone())),
+        };
+        match (*sender, &self.callback) {
+            (Some(sender), _) => {
+                // The future may have been dropped already
+                let _ = sender.send(result);
+            }
+            (None, Some(callback)) => callback(result),
+            (None, None) => {
+                if let Err(err) = result {
+                    error!("Failed to deliver message: {}", err);
+                }
+            }
+        }
+    }
+}
+
+/// Produces to Kafka. Messages are delivered asynchronously by a background
+/// thread, ``produce`` only returns an error if the message could not be
+/// queued. Delivery failures are logged unless a ``DeliveryCallback`` is
+/// provided. The outcome of messages produced with ``produce_async`` is only
+/// reported through the returned future.
 pub struct KafkaProducer {
-    producer: Option<BaseProducer>,
+    producer: Option<ThreadedProducer<DeliveryContext>>,
 }
 
 impl KafkaProducer {
     pub fn new(config: KafkaConfig) -> Self {
+        Self::build(config, None)
+    }
+
+    pub fn new_with_delivery_callback(config: KafkaConfig, callback: DeliveryCallback) -> Self {
+        Self::build(config, Some(callback))
+    }
+
+    fn build(config: KafkaConfig, callback: Option<DeliveryCallback>) -> Self {
         let config_obj: ClientConfig = config.into();
-        let base_producer: BaseProducer<_> = config_obj.create().unwrap();
+        let threaded_producer: ThreadedProducer<_> = config_obj
+            .create_with_context(DeliveryContext {
+                callback,
+                statistics: StatisticsRecorder::default(),
+            })
+            .unwrap();
 
         Self {
-            producer: Some(base_producer),
+            producer: Some(threaded_producer),
         }
     }
 }
@@ -27,25 +101,44 @@ impl KafkaProducer {
         producer.poll(Duration::ZERO);
     }
 
+    /// Wait for all the queued messages to be delivered, for at most five
+    /// seconds. Delivery callbacks are called before this returns.
     pub fn flush(&self) {
         let producer = self.producer.as_ref().unwrap();
-        producer.flush(Duration::from_millis(5000));
+        producer.flush(FLUSH_TIMEOUT);
     }
 }
 
-impl ArroyoProducer<KafkaPayload> for KafkaProducer {
-    fn produce(&self, destination: &TopicOrPartition, payload: &KafkaPayload) {
+impl KafkaProducer {
+    fn send(
+        &self,
+        destination: &TopicOrPartition,
+        payload: &KafkaPayload,
+        sender: Option<DeliverySender>,
+    ) -> Result<(), ProducerError> {
         let topic = match destination {
             TopicOrPartition::Topic(topic) => topic.name.as_ref(),
             TopicOrPartition::Partition(partition) => partition.topic.name.as_ref(),
         };
 
-        // TODO: Fix the KafkaPayload type to avoid all this cloning
-        let payload_copy = payload.clone();
-        let msg_key = payload_copy.key.unwrap_or_default();
-        let msg_payload = payload_copy.payload.unwrap_or_default();
+        let msg_key = payload.key.as_deref().unwrap_or_default();
+        let msg_payload = payload.payload.as_deref().unwrap_or_default();
+        // Messages produced while processing a traced message continue its
+        // trace, unless they carry a trace context of their own.
+        let mut headers = payload.headers.clone();
+        if let Some(context) = TraceContext::current() {
+            let headers = headers.get_or_insert_with(Headers::new);
+            if !TraceContext::is_in_headers(headers) {
+                context.inject(headers);
+            }
+        }
 
-        let mut base_record = BaseRecord::to(topic).payload(&msg_payload).key(&msg_key);
+        let mut base_record = BaseRecord::with_opaque_to(topic, Box::new(sender))
+            .payload(msg_payload)
+            .key(msg_key);
+        if let Some(headers) = headers {
+            base_record = base_record.headers(headers.into());
+        }
 
         let partition = match destination {
             TopicOrPartition::Topic(_) => None,
@@ -56,12 +149,46 @@ impl ArroyoProducer<KafkaPayload> for KafkaProducer {
             base_record = base_record.partition(index as i32)
         }
 
-        let producer = self.producer.as_ref().expect("Not closed");
+        let producer = self
+            .producer
+            .as_ref()
+            .ok_or(ProducerError::ProducerClosed)?;
+
+        producer
+            .send(base_record)
+            .map_err(|(error, _)| ProducerError::from(error))
+    }
+}
+
+impl ArroyoProducer<KafkaPayload> for KafkaProducer {
+    fn produce(
+        &self,
+        destination: &TopicOrPartition,
+        payload: &KafkaPayload,
+    ) -> Result<(), ProducerError> {
+        self.send(destination, payload, None)
+    }
 
-        producer.send(base_record).expect("Something went wrong");
+    fn produce_async(
+        &self,
+        destination: &TopicOrPartition,
+        payload: &KafkaPayload,
+    ) -> ProduceFuture<KafkaPayload> {
+        let (sender, receiver) = oneshot::channel();
+        if let Err(error) = self.send(destination, payload, Some(sender)) {
+            return Box::pin(future::ready(Err(error)));
+        }
+        // The sender is only dropped without a result if the producer was
+        // torn down before the message was delivered.
+        Box::pin(receiver.map(|result| result.unwrap_or(Err(ProducerError::ProducerClosed))))
     }
+
+    /// Flush the queued messages and close the producer. Any further call
+    /// to ``produce`` fails with ``ProducerClosed``.
     fn close(&mut self) {
-        self.producer = None;
+        if let Some(producer) = self.producer.take() {
+            producer.flush(FLUSH_TIMEOUT);
+        }
     }
 }
 
@@ -70,25 +197,103 @@ mod tests {
     use super::KafkaProducer;
     use crate::backends::kafka::config::KafkaConfig;
     use crate::backends::kafka::types::KafkaPayload;
-    use crate::backends::Producer;
+    use crate::backends::{Producer, ProducerError};
     use crate::types::{Topic, TopicOrPartition};
+    use futures::FutureExt;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
     #[test]
     fn test_producer() {
         let topic = Topic {
             name: "test".to_string(),
         };
         let destination = TopicOrPartition::Topic(topic);
-        let configuration =
-            KafkaConfig::new_producer_config(vec!["localhost:9092".to_string()], None);
+        let configuration = KafkaConfig::new_producer_config(
+            vec!["localhost:9092".to_string()],
+            Some(HashMap::from([(
+                "message.timeout.ms".to_string(),
+                "1000".to_string(),
+            )])),
+        );
 
         let mut producer = KafkaProducer::new(configuration);
 
         let payload = KafkaPayload {
             key: None,
             headers: None,
-            payload: Some("asdf".as_bytes().to_vec()),
+            payload: Some("asdf".as_bytes().into()),
         };
-        producer.produce(&destination, &payload);
+        producer.produce(&destination, &payload).unwrap();
         producer.close();
+        assert!(producer.produce(&destination, &payload).is_err());
+    }
+
+    #[test]
+    fn test_delivery_callback() {
+        let topic = Topic {
+            name: "test".to_string(),
+        };
+        let destination = TopicOrPartition::Topic(topic);
+        // Fail fast when no broker is running
+        let configuration = KafkaConfig::new_producer_config(
+            vec!["localhost:9092".to_string()],
+            Some(HashMap::from([(
+                "message.timeout.ms".to_string(),
+                "1000".to_string(),
+            )])),
+        );
+
+        let deliveries = Arc::new(Mutex::new(0));
+        let counter = deliveries.clone();
+        let mut producer = KafkaProducer::new_with_delivery_callback(
+            configuration,
+            Arc::new(move |_result| *counter.lock().unwrap() += 1),
+        );
+
+        let payload = KafkaPayload {
+            key: None,
+            headers: None,
+            payload: Some("asdf".as_bytes().into()),
+        };
+        producer.produce(&destination, &payload).unwrap();
+        producer.close();
+
+        // The outcome of the message is reported whether or not it could be
+        // delivered.
+        assert_eq!(*deliveries.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_produce_async() {
+        let topic = Topic {
+            name: "test".to_string(),
+        };
+        let destination = TopicOrPartition::Topic(topic);
+        let configuration = KafkaConfig::new_producer_config(
+            vec!["localhost:9092".to_string()],
+            Some(HashMap::from([(
+                "message.timeout.ms".to_string(),
+                "1000".to_string(),
+            )])),
+        );
+
+        let mut producer = KafkaProducer::new(configuration);
+        let payload = KafkaPayload {
+            key: None,
+            headers: None,
+            payload: Some("asdf".as_bytes().into()),
+        };
+        let delivery = producer.produce_async(&destination, &payload);
+        producer.close();
+
+        // The delivery was reported before close returned
+        assert!(delivery.now_or_never().is_some());
+
+        let delivery = producer.produce_async(&destination, &payload);
+        assert!(matches!(
+            delivery.now_or_never(),
+            Some(Err(ProducerError::ProducerClosed))
+        ));
     }
 }
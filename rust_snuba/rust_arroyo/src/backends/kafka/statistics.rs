@@ -0,0 +1,342 @@
+use crate::utils::metrics;
+use rdkafka::statistics::{Statistics, Window};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// A metric taken from the statistics librdkafka emits every
+/// ``statistics.interval.ms``.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Gauge {
+    pub key: &'static str,
+    pub value: u64,
+    pub tags: Vec<(&'static str, String)>,
+    /// The value is a total since the client started, it is reported as a
+    /// counter of the increase since the previous statistics.
+    pub total: bool,
+}
+
+fn gauge(key: &'static str, value: i64, tags: &[(&'static str, String)]) -> Gauge {
+    Gauge {
+        key,
+        value: value.max(0) as u64,
+        tags: tags.to_vec(),
+        total: false,
+    }
+}
+
+fn total(key: &'static str, value: u64, tags: &[(&'static str, String)]) -> Gauge {
+    Gauge {
+        key,
+        value,
+        tags: tags.to_vec(),
+        total: true,
+    }
+}
+
+// Windows are in microseconds, they are reported in milliseconds like the
+// other timings. Empty windows, of brokers that were not used, are skipped.
+fn window_gauges(
+    gauges: &mut Vec<Gauge>,
+    keys: [&'static str; 2],
+    window: &Option<Window>,
+    tags: &[(&'static str, String)],
+) {
+    if let Some(window) = window.as_ref().filter(|window| window.cnt > 0) {
+        gauges.push(gauge(keys[0], window.avg / 1000, tags));
+        gauges.push(gauge(keys[1], window.p99 / 1000, tags));
+    }
+}
+
+/// Picks the statistics that help diagnosing throughput issues: the latency
+/// of the brokers, the bytes exchanged with them and the size of the
+/// internal queues. Every metric is tagged with the type and the name of the
+/// client, so that the producers of a consumer are told apart. Brokers are
+/// tagged by id, the bootstrap brokers which have none are skipped, and
+/// partitions by topic and index.
+pub(crate) fn gauges(statistics: &Statistics) -> Vec<Gauge> {
+    let client = [
+        ("client", statistics.client_type.clone()),
+        ("client_name", statistics.name.clone()),
+    ];
+    let mut gauges = vec![
+        gauge("arroyo.kafka.replyq", statistics.replyq, &client),
+        gauge(
+            "arroyo.kafka.producer.queue.messages",
+            statistics.msg_cnt as i64,
+            &client,
+        ),
+        gauge(
+            "arroyo.kafka.producer.queue.bytes",
+            statistics.msg_size as i64,
+            &client,
+        ),
+    ];
+
+    let mut brokers: Vec<_> = statistics
+        .brokers
+        .values()
+        .filter(|broker| broker.nodeid >= 0)
+        .collect();
+    brokers.sort_by_key(|broker| broker.nodeid);
+    for broker in brokers {
+        let tags = [
+            client[0].clone(),
+            client[1].clone(),
+            ("broker", broker.nodeid.to_string()),
+        ];
+        gauges.push(total("arroyo.kafka.broker.rx_bytes", broker.rxbytes, &tags));
+        gauges.push(total("arroyo.kafka.broker.tx_bytes", broker.txbytes, &tags));
+        gauges.push(gauge(
+            "arroyo.kafka.broker.outbuf.messages",
+            broker.outbuf_msg_cnt,
+            &tags,
+        ));
+        gauges.push(gauge(
+            "arroyo.kafka.broker.waitresp.messages",
+            broker.waitresp_msg_cnt,
+            &tags,
+        ));
+        // The round trip time includes the fetch requests of consumers.
+        window_gauges(
+            &mut gauges,
+            ["arroyo.kafka.broker.rtt.avg", "arroyo.kafka.broker.rtt.p99"],
+            &broker.rtt,
+            &tags,
+        );
+        window_gauges(
+            &mut gauges,
+            [
+                "arroyo.kafka.broker.int_latency.avg",
+                "arroyo.kafka.broker.int_latency.p99",
+            ],
+            &broker.int_latency,
+            &tags,
+        );
+    }
+
+    let mut topics: Vec<_> = statistics.topics.values().collect();
+    topics.sort_by(|a, b| a.topic.cmp(&b.topic));
+    for topic in topics {
+        let mut partitions: Vec<_> = topic
+            .partitions
+            .values()
+            // -1 is the partition of the messages whose partition is not
+            // known yet.
+            .filter(|partition| partition.partition >= 0)
+            .collect();
+        partitions.sort_by_key(|partition| partition.partition);
+        for partition in partitions {
+            let tags = [
+                client[0].clone(),
+                client[1].clone(),
+                ("topic", topic.topic.clone()),
+                ("partition", partition.partition.to_string()),
+            ];
+            gauges.push(gauge(
+                "arroyo.kafka.partition.fetchq.messages",
+                partition.fetchq_cnt,
+                &tags,
+            ));
+            gauges.push(gauge(
+                "arroyo.kafka.partition.fetchq.bytes",
+                partition.fetchq_size as i64,
+                &tags,
+            ));
+            gauges.push(gauge(
+                "arroyo.kafka.partition.msgq.messages",
+                partition.msgq_cnt,
+                &tags,
+            ));
+            gauges.push(total(
+                "arroyo.kafka.partition.rx_bytes",
+                partition.rxbytes,
+                &tags,
+            ));
+        }
+    }
+    gauges
+}
+
+type TotalKey = (&'static str, Vec<(&'static str, String)>);
+
+/// Reports the statistics of a client to the metrics backend. It remembers
+/// the totals of the previous statistics of the client to report their
+/// increase.
+#[derive(Default)]
+pub(crate) struct StatisticsRecorder {
+    totals: Mutex<HashMap<TotalKey, u64>>,
+}
+
+impl StatisticsRecorder {
+    pub fn record(&self, statistics: &Statistics) {
+        for (gauge, increase) in self.increases(gauges(statistics)) {
+            let tags: HashMap<&str, &str> = gauge
+                .tags
+                .iter()
+                .map(|(key, value)| (*key, value.as_str()))
+                .collect();
+            match increase {
+                Some(increase) => {
+                    metrics::increment(gauge.key, Some(increase as i64), Some(tags), None)
+                }
+                None => metrics::gauge(gauge.key, gauge.value, Some(tags), None),
+            }
+        }
+    }
+
+    // Pairs each total with its increase since the previous statistics, the
+    // whole of it the first time. A total that went down was reset with its
+    // connection, it increased by its new value.
+    fn increases(&self, gauges: Vec<Gauge>) -> Vec<(Gauge, Option<u64>)> {
+        let mut totals = self.totals.lock().unwrap();
+        gauges
+            .into_iter()
+            .map(|gauge| {
+                if !gauge.total {
+                    return (gauge, None);
+                }
+                let key = (gauge.key, gauge.tags.clone());
+                let previous = totals.insert(key, gauge.value).unwrap_or(0);
+                let increase = match gauge.value.checked_sub(previous) {
+                    Some(increase) => increase,
+                    None => gauge.value,
+                };
+                (gauge, Some(increase))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{gauges, Gauge, StatisticsRecorder};
+    use rdkafka::statistics::{Broker, Partition, Statistics, Topic, Window};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_gauges() {
+        let statistics = Statistics {
+            name: "rdkafka#consumer-1".to_string(),
+            client_type: "consumer".to_string(),
+            replyq: 3,
+            brokers: HashMap::from([
+                (
+                    "localhost:9092/1".to_string(),
+                    Broker {
+                        nodeid: 1,
+                        rxbytes: 2048,
+                        txbytes: 512,
+                        rtt: Some(Window {
+                            cnt: 10,
+                            avg: 1500,
+                            p99: 12_000,
+                            ..Default::default()
+                        }),
+                        int_latency: Some(Window::default()),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "localhost:9092/bootstrap".to_string(),
+                    Broker {
+                        nodeid: -1,
+                        ..Default::default()
+                    },
+                ),
+            ]),
+            topics: HashMap::from([(
+                "events".to_string(),
+                Topic {
+                    topic: "events".to_string(),
+                    partitions: HashMap::from([
+                        (
+                            0,
+                            Partition {
+                                partition: 0,
+                                fetchq_cnt: 100,
+                                fetchq_size: 4096,
+                                ..Default::default()
+                            },
+                        ),
+                        (
+                            -1,
+                            Partition {
+                                partition: -1,
+                                ..Default::default()
+                            },
+                        ),
+                    ]),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+
+        let gauges = gauges(&statistics);
+        let get = |key: &str| gauges.iter().find(|gauge| gauge.key == key);
+        assert_eq!(get("arroyo.kafka.replyq").unwrap().value, 3);
+        assert_eq!(
+            get("arroyo.kafka.broker.rtt.avg"),
+            Some(&Gauge {
+                key: "arroyo.kafka.broker.rtt.avg",
+                value: 1,
+                tags: vec![
+                    ("client", "consumer".to_string()),
+                    ("client_name", "rdkafka#consumer-1".to_string()),
+                    ("broker", "1".to_string())
+                ],
+                total: false,
+            })
+        );
+        assert_eq!(get("arroyo.kafka.broker.rtt.p99").unwrap().value, 12);
+        assert_eq!(get("arroyo.kafka.broker.rx_bytes").unwrap().value, 2048);
+        // Empty windows and the bootstrap broker are skipped
+        assert_eq!(get("arroyo.kafka.broker.int_latency.avg"), None);
+        assert_eq!(
+            gauges
+                .iter()
+                .filter(|gauge| gauge.key == "arroyo.kafka.broker.tx_bytes")
+                .count(),
+            1
+        );
+
+        let fetchq = get("arroyo.kafka.partition.fetchq.messages").unwrap();
+        assert_eq!(fetchq.value, 100);
+        assert_eq!(
+            fetchq.tags,
+            vec![
+                ("client", "consumer".to_string()),
+                ("client_name", "rdkafka#consumer-1".to_string()),
+                ("topic", "events".to_string()),
+                ("partition", "0".to_string()),
+            ]
+        );
+        assert_eq!(
+            get("arroyo.kafka.partition.fetchq.bytes").unwrap().value,
+            4096
+        );
+    }
+
+    #[test]
+    fn test_increases() {
+        let recorder = StatisticsRecorder::default();
+        let statistics = |rxbytes| {
+            let tags = [("client_name", "rdkafka#producer-1".to_string())];
+            vec![
+                super::gauge("arroyo.kafka.replyq", 3, &tags),
+                super::total("arroyo.kafka.broker.rx_bytes", rxbytes, &tags),
+            ]
+        };
+        let increases = |rxbytes| -> Vec<Option<u64>> {
+            recorder
+                .increases(statistics(rxbytes))
+                .into_iter()
+                .map(|(_, increase)| increase)
+                .collect()
+        };
+        assert_eq!(increases(100), vec![None, Some(100)]);
+        assert_eq!(increases(150), vec![None, Some(50)]);
+        // The connection was reset.
+        assert_eq!(increases(20), vec![None, Some(20)]);
+    }
+}
@@ -0,0 +1,112 @@
+use crate::backends::kafka::config::KafkaConfig;
+use crate::backends::kafka::types::KafkaPayload;
+use crate::backends::kafka::{create_kafka_message, ConsumerBackend, CustomContext, KafkaConsumer};
+use crate::backends::ConsumerError;
+use crate::types::BrokerMessage;
+use futures::FutureExt;
+use lazy_static::lazy_static;
+use rdkafka::config::{ClientConfig, FromClientConfigAndContext};
+use rdkafka::consumer::{CommitMode, StreamConsumer};
+use rdkafka::error::KafkaResult;
+use rdkafka::message::BorrowedMessage;
+use std::time::Duration;
+use tokio::runtime::{Builder, Handle, Runtime};
+
+lazy_static! {
+    // Used by consumers created outside of a Tokio runtime.
+    static ref RUNTIME: Runtime = Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("arroyo-kafka-stream")
+        .enable_all()
+        .build()
+        .unwrap();
+}
+
+/// A Kafka consumer built on rdkafka's ``StreamConsumer``, which can be
+/// awaited from a Tokio runtime with ``recv``. It can also be driven by a
+/// ``StreamProcessor`` like ``KafkaConsumer``.
+///
+/// Offsets are committed asynchronously, ``commit_offsets`` does not wait
+/// for the broker and commit failures are only logged. The offsets returned
+/// by ``on_revoke`` are still committed synchronously.
+pub type KafkaStreamConsumer = KafkaConsumer<StreamConsumer<CustomContext>>;
+
+impl ConsumerBackend for StreamConsumer<CustomContext> {
+    fn create(config: &ClientConfig, context: CustomContext) -> KafkaResult<Self> {
+        // The consumer spawns a task that wakes it up periodically, it
+        // goes to the current runtime if there is one.
+        let _guard = Handle::try_current().is_err().then(|| RUNTIME.enter());
+        StreamConsumer::from_config_and_context(config, context)
+    }
+
+    fn poll_message(&self, timeout: Duration) -> Option<KafkaResult<BorrowedMessage<'_>>> {
+        // Blocking is not allowed on a runtime thread.
+        if timeout.is_zero() || Handle::try_current().is_ok() {
+            return self.recv().now_or_never();
+        }
+        RUNTIME
+            .block_on(tokio::time::timeout(timeout, self.recv()))
+            .ok()
+    }
+
+    fn commit_mode() -> CommitMode {
+        CommitMode::Async
+    }
+}
+
+impl KafkaStreamConsumer {
+    pub fn new_stream(config: KafkaConfig) -> Self {
+        Self::build(config)
+    }
+
+    /// Waits for the next message. Rebalance callbacks are triggered while
+    /// waiting.
+    pub async fn recv(&mut self) -> Result<BrokerMessage<KafkaPayload>, ConsumerError> {
+        self.state.assert_consuming_state()?;
+        self.refresh_oauth_token();
+        let consumer = self.consumer.as_ref().unwrap();
+        let message = consumer.recv().await?;
+        Ok(create_kafka_message(&message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KafkaStreamConsumer;
+    use crate::backends::kafka::config::{InitialOffset, KafkaConfig};
+    use crate::backends::{AssignmentCallbacks, Consumer};
+    use crate::types::{Partition, Position, Topic};
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    struct EmptyCallbacks {}
+    impl AssignmentCallbacks for EmptyCallbacks {
+        fn on_assign(&mut self, _: HashMap<Partition, u64>) {}
+        fn on_revoke(&mut self, _: Vec<Partition>) -> HashMap<Partition, Position> {
+            HashMap::new()
+        }
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let configuration = KafkaConfig::new_consumer_config(
+            vec!["localhost:9092".to_string()],
+            "my-group".to_string(),
+            InitialOffset::Latest,
+            false,
+            None,
+        )
+        .unwrap();
+        let mut consumer = KafkaStreamConsumer::new_stream(configuration);
+        assert!(consumer.poll(None).is_err()); // Not subscribed yet
+
+        let topic = Topic {
+            name: "test".to_string(),
+        };
+        consumer
+            .subscribe(&[topic], Box::new(EmptyCallbacks {}))
+            .unwrap();
+        assert!(consumer.poll(Some(Duration::ZERO)).is_ok());
+        consumer.close();
+    }
+}
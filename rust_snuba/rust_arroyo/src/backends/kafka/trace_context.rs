@@ -0,0 +1,195 @@
+use crate::backends::kafka::types::Headers;
+use rand::Rng;
+use std::cell::RefCell;
+
+pub const SENTRY_TRACE_HEADER: &str = "sentry-trace";
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+thread_local! {
+    static CURRENT: RefCell<Option<TraceContext>> = const { RefCell::new(None) };
+}
+
+/// The position of a message in a distributed trace, as carried by the
+/// ``sentry-trace`` and W3C ``traceparent`` headers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceContext {
+    /// 32 lowercase hex characters.
+    pub trace_id: String,
+    /// 16 lowercase hex characters.
+    pub span_id: String,
+    pub sampled: Option<bool>,
+}
+
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len && value.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..bytes)
+        .map(|_| format!("{:02x}", rng.gen::<u8>()))
+        .collect()
+}
+
+impl TraceContext {
+    /// Parses a ``{trace_id}-{span_id}[-{sampled}]`` header.
+    pub fn parse_sentry_trace(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let trace_id = parts.next()?;
+        let span_id = parts.next()?;
+        let sampled = match parts.next() {
+            None => None,
+            Some("1") => Some(true),
+            Some("0") => Some(false),
+            Some(_) => return None,
+        };
+        if parts.next().is_some() || !is_hex(trace_id, 32) || !is_hex(span_id, 16) {
+            return None;
+        }
+        Some(Self {
+            trace_id: trace_id.to_ascii_lowercase(),
+            span_id: span_id.to_ascii_lowercase(),
+            sampled,
+        })
+    }
+
+    /// Parses a ``{version}-{trace_id}-{parent_id}-{flags}`` header.
+    pub fn parse_traceparent(value: &str) -> Option<Self> {
+        let parts: Vec<&str> = value.trim().split('-').collect();
+        let [version, trace_id, span_id, flags] = parts.as_slice() else {
+            return None;
+        };
+        if !is_hex(version, 2) || !is_hex(trace_id, 32) || !is_hex(span_id, 16) {
+            return None;
+        }
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        Some(Self {
+            trace_id: trace_id.to_ascii_lowercase(),
+            span_id: span_id.to_ascii_lowercase(),
+            sampled: Some(flags & 1 == 1),
+        })
+    }
+
+    /// Reads the trace context from the headers of a message. The
+    /// ``sentry-trace`` header wins if both are present.
+    pub fn from_headers(headers: &Headers) -> Option<Self> {
+        headers
+            .get_str(SENTRY_TRACE_HEADER)
+            .and_then(Self::parse_sentry_trace)
+            .or_else(|| {
+                headers
+                    .get_str(TRACEPARENT_HEADER)
+                    .and_then(Self::parse_traceparent)
+            })
+    }
+
+    /// Returns whether the headers already carry a trace context.
+    pub fn is_in_headers(headers: &Headers) -> bool {
+        headers.contains_key(SENTRY_TRACE_HEADER) || headers.contains_key(TRACEPARENT_HEADER)
+    }
+
+    /// A new span of the same trace.
+    pub fn new_child(&self) -> Self {
+        Self {
+            trace_id: self.trace_id.clone(),
+            span_id: random_hex(8),
+            sampled: self.sampled,
+        }
+    }
+
+    pub fn sentry_trace(&self) -> String {
+        match self.sampled {
+            None => format!("{}-{}", self.trace_id, self.span_id),
+            Some(sampled) => format!("{}-{}-{}", self.trace_id, self.span_id, sampled as u8),
+        }
+    }
+
+    pub fn traceparent(&self) -> String {
+        let flags = if self.sampled == Some(true) {
+            "01"
+        } else {
+            "00"
+        };
+        format!("00-{}-{}-{}", self.trace_id, self.span_id, flags)
+    }
+
+    /// Sets both trace headers.
+    pub fn inject(&self, headers: &mut Headers) {
+        headers.insert(SENTRY_TRACE_HEADER, self.sentry_trace());
+        headers.insert(TRACEPARENT_HEADER, self.traceparent());
+    }
+
+    /// The trace context of the message being processed on this thread, if
+    /// any. The Kafka producer adds it to the messages it produces.
+    pub fn current() -> Option<Self> {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+
+    /// Replaces the trace context of this thread and returns the previous
+    /// one.
+    pub fn set_current(context: Option<Self>) -> Option<Self> {
+        CURRENT.with(|current| current.replace(context))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TraceContext;
+    use crate::backends::kafka::types::Headers;
+
+    const TRACE_ID: &str = "771a43a4192642f0b136d5159a501700";
+    const SPAN_ID: &str = "b7ad6b7169203331";
+
+    #[test]
+    fn test_parse() {
+        let expected = TraceContext {
+            trace_id: TRACE_ID.to_string(),
+            span_id: SPAN_ID.to_string(),
+            sampled: Some(true),
+        };
+        assert_eq!(
+            TraceContext::parse_sentry_trace(&format!("{}-{}-1", TRACE_ID, SPAN_ID)),
+            Some(expected.clone())
+        );
+        assert_eq!(
+            TraceContext::parse_traceparent(&format!("00-{}-{}-01", TRACE_ID, SPAN_ID)),
+            Some(expected.clone())
+        );
+        assert_eq!(
+            TraceContext::parse_sentry_trace(&expected.sentry_trace()),
+            Some(expected.clone())
+        );
+        assert_eq!(
+            TraceContext::parse_traceparent(&expected.traceparent()),
+            Some(expected)
+        );
+
+        assert_eq!(
+            TraceContext::parse_sentry_trace(&format!("{}-{}", TRACE_ID, SPAN_ID))
+                .unwrap()
+                .sampled,
+            None
+        );
+        assert!(TraceContext::parse_sentry_trace("abc-def").is_none());
+        assert!(TraceContext::parse_traceparent(TRACE_ID).is_none());
+    }
+
+    #[test]
+    fn test_headers() {
+        let mut headers = Headers::new();
+        headers.insert("other", "value");
+        headers.insert("traceparent", format!("00-{}-{}-00", TRACE_ID, SPAN_ID));
+        assert!(TraceContext::is_in_headers(&headers));
+        let context = TraceContext::from_headers(&headers).unwrap();
+        assert_eq!(context.sampled, Some(false));
+
+        let child = context.new_child();
+        assert_eq!(child.trace_id, TRACE_ID);
+        assert_ne!(child.span_id, SPAN_ID);
+
+        let mut headers = Headers::new();
+        child.inject(&mut headers);
+        assert_eq!(TraceContext::from_headers(&headers), Some(child));
+        assert!(!TraceContext::is_in_headers(&Headers::new()));
+    }
+}
@@ -1,8 +1,136 @@
-use rdkafka::message::OwnedHeaders;
+use rdkafka::message::{BorrowedHeaders, Headers as _, OwnedHeaders};
+use std::sync::Arc;
 
-#[derive(Clone)]
+/// The headers of a Kafka message, in the order they were produced. Kafka
+/// allows several headers with the same key.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Headers {
+    headers: Vec<(String, Vec<u8>)>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the value of the first header with this key.
+    pub fn get(&self, key: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Returns the value of the first header with this key, if it is valid
+    /// UTF-8.
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key)
+            .and_then(|value| std::str::from_utf8(value).ok())
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Sets the value of the header, replacing all the headers that had
+    /// this key.
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<Vec<u8>>) {
+        let key = key.into();
+        self.remove(&key);
+        self.headers.push((key, value.into()));
+    }
+
+    /// Removes all the headers with this key.
+    pub fn remove(&mut self, key: &str) {
+        self.headers.retain(|(k, _)| k != key);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.headers
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_slice()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+}
+
+impl From<&BorrowedHeaders> for Headers {
+    fn from(borrowed: &BorrowedHeaders) -> Self {
+        let headers = (0..borrowed.count())
+            .filter_map(|index| borrowed.get(index))
+            .map(|(key, value)| (key.to_string(), value.to_vec()))
+            .collect();
+        Self { headers }
+    }
+}
+
+impl From<Headers> for OwnedHeaders {
+    fn from(headers: Headers) -> Self {
+        headers.headers.iter().fold(
+            OwnedHeaders::new_with_capacity(headers.len()),
+            |owned, (key, value)| owned.add(key, value),
+        )
+    }
+}
+
+/// The key and the payload are reference counted, cloning a payload to
+/// buffer or batch it does not copy them.
+#[derive(Clone, Debug)]
 pub struct KafkaPayload {
-    pub key: Option<Vec<u8>>,
-    pub headers: Option<OwnedHeaders>,
-    pub payload: Option<Vec<u8>>,
+    pub key: Option<Arc<[u8]>>,
+    pub headers: Option<Headers>,
+    pub payload: Option<Arc<[u8]>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Headers, KafkaPayload};
+    use rdkafka::message::{Headers as _, OwnedHeaders};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_headers() {
+        let mut headers = Headers::new();
+        assert!(headers.is_empty());
+
+        headers.insert("key", "value");
+        headers.insert("binary", vec![0xff, 0x00]);
+        assert_eq!(headers.get("key"), Some(&b"value"[..]));
+        assert_eq!(headers.get_str("key"), Some("value"));
+        assert_eq!(headers.get_str("binary"), None);
+        assert!(!headers.contains_key("missing"));
+
+        headers.insert("key", "other");
+        assert_eq!(
+            headers.iter().collect::<Vec<_>>(),
+            vec![("binary", &[0xff, 0x00][..]), ("key", &b"other"[..])]
+        );
+
+        let owned: OwnedHeaders = headers.clone().into();
+        assert_eq!(owned.count(), 2);
+        assert_eq!(Headers::from(owned.as_borrowed()), headers);
+
+        headers.remove("binary");
+        assert_eq!(headers.len(), 1);
+    }
+
+    #[test]
+    fn test_clone_shares_payload() {
+        let payload = KafkaPayload {
+            key: Some(b"key".as_slice().into()),
+            headers: None,
+            payload: Some(vec![0; 4096].into()),
+        };
+        let clone = payload.clone();
+        assert!(Arc::ptr_eq(
+            payload.payload.as_ref().unwrap(),
+            clone.payload.as_ref().unwrap()
+        ));
+    }
 }
@@ -1,13 +1,14 @@
 use crate::backends::storages::{ConsumeError, MessageStorage, TopicDoesNotExist, TopicExists};
 use crate::types::{BrokerMessage, Partition, Topic};
 use crate::utils::clock::Clock;
-use chrono::DateTime;
-use std::collections::{HashMap, HashSet};
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use thiserror::Error;
 use uuid::Uuid;
 
 pub struct LocalBroker<TPayload: Clone> {
-    storage: Box<dyn MessageStorage<TPayload>>,
+    storage: Box<dyn MessageStorage<TPayload> + Send>,
     clock: Box<dyn Clock>,
     offsets: HashMap<String, HashMap<Partition, u64>>,
     subscriptions: HashMap<String, HashMap<Uuid, Vec<Topic>>>,
@@ -24,6 +25,9 @@ pub enum BrokerError {
 
     #[error("Topci does not exist")]
     TopicDoesNotExist,
+
+    #[error("Invalid topic pattern")]
+    InvalidTopicPattern,
 }
 
 impl From<TopicDoesNotExist> for BrokerError {
@@ -33,7 +37,7 @@ impl From<TopicDoesNotExist> for BrokerError {
 }
 
 impl<TPayload: Clone> LocalBroker<TPayload> {
-    pub fn new(storage: Box<dyn MessageStorage<TPayload>>, clock: Box<dyn Clock>) -> Self {
+    pub fn new(storage: Box<dyn MessageStorage<TPayload> + Send>, clock: Box<dyn Clock>) -> Self {
         Self {
             storage,
             clock,
@@ -46,7 +50,7 @@ impl<TPayload: Clone> LocalBroker<TPayload> {
         self.storage.create_topic(topic, partitions)
     }
 
-    pub fn get_topic_partition_count(self, topic: &Topic) -> Result<u16, TopicDoesNotExist> {
+    pub fn get_topic_partition_count(&self, topic: &Topic) -> Result<u16, TopicDoesNotExist> {
         self.storage.get_partition_count(topic)
     }
 
@@ -60,12 +64,34 @@ impl<TPayload: Clone> LocalBroker<TPayload> {
             .produce(partition, payload, DateTime::from(time))
     }
 
+    /// Replaces the patterns in ``topics`` with the existing topics they
+    /// match. Topics are returned sorted by name.
+    pub fn resolve_topics(&self, topics: &[Topic]) -> Result<Vec<Topic>, BrokerError> {
+        let mut resolved = BTreeSet::new();
+        for topic in topics {
+            if !topic.is_pattern() {
+                resolved.insert(topic.clone());
+                continue;
+            }
+            let pattern = Regex::new(&topic.name).map_err(|_| BrokerError::InvalidTopicPattern)?;
+            for existing in self.storage.list_topics() {
+                if pattern.is_match(&existing.name) {
+                    resolved.insert(existing.clone());
+                }
+            }
+        }
+        Ok(resolved.into_iter().collect())
+    }
+
+    /// Subscribes a consumer to ``topics``, which can be patterns, and
+    /// returns its assignment.
     pub fn subscribe(
         &mut self,
         consumer_id: Uuid,
         consumer_group: String,
         topics: Vec<Topic>,
     ) -> Result<HashMap<Partition, u64>, BrokerError> {
+        let topics = self.resolve_topics(&topics)?;
         // Handle rebalancing request which is not supported
         let group_subscriptions = self.subscriptions.get(&consumer_group);
         if let Some(group_s) = group_subscriptions {
@@ -79,7 +105,7 @@ impl<TPayload: Clone> LocalBroker<TPayload> {
                 if non_matches.next().is_some() {
                     return Err(BrokerError::RebalanceNotSupported);
                 }
-            } else {
+            } else if !group_s.is_empty() {
                 return Err(BrokerError::RebalanceNotSupported);
             }
         }
@@ -145,8 +171,35 @@ impl<TPayload: Clone> LocalBroker<TPayload> {
         self.storage.consume(partition, offset)
     }
 
+    /// Returns the offset the next message produced to the partition will
+    /// have.
+    pub fn high_watermark(&self, partition: &Partition) -> Result<u64, ConsumeError> {
+        self.storage.get_high_watermark(partition)
+    }
+
+    /// Returns the offset of the first message of the partition produced at
+    /// or after ``timestamp``, or the offset following the last message if
+    /// there is none.
+    pub fn offset_for_timestamp(
+        &self,
+        partition: &Partition,
+        timestamp: DateTime<Utc>,
+    ) -> Result<u64, ConsumeError> {
+        let mut offset = 0;
+        while let Some(message) = self.storage.consume(partition, offset)? {
+            if message.timestamp >= timestamp {
+                break;
+            }
+            offset += 1;
+        }
+        Ok(offset)
+    }
+
     pub fn commit(&mut self, consumer_group: &str, offsets: HashMap<Partition, u64>) {
-        self.offsets.insert(consumer_group.to_string(), offsets);
+        self.offsets
+            .entry(consumer_group.to_string())
+            .or_default()
+            .extend(offsets);
     }
 }
 
@@ -154,9 +207,9 @@ impl<TPayload: Clone> LocalBroker<TPayload> {
 mod tests {
     use super::LocalBroker;
     use crate::backends::storages::memory::MemoryMessageStorage;
-    use crate::types::{Partition, Topic};
+    use crate::types::{InnerMessage, Message, Partition, Position, Topic};
     use crate::utils::clock::SystemClock;
-    use std::collections::HashMap;
+    use std::collections::{BTreeMap, HashMap};
     use uuid::Uuid;
 
     #[test]
@@ -202,8 +255,15 @@ mod tests {
 
         let message = broker.consume(&partition, 0).unwrap().unwrap();
         assert_eq!(message.offset, 0);
-        assert_eq!(message.committable(), BTreeMap::from([(partition.clone(), 1)]));
         assert_eq!(message.payload, "message".to_string());
+        let message = Message {
+            inner_message: InnerMessage::BrokerMessage(message),
+        };
+        let timestamp = message.timestamp().unwrap();
+        assert_eq!(
+            message.committable(),
+            BTreeMap::from([(partition, Position::new(1, timestamp))])
+        );
     }
 
     fn build_broker() -> LocalBroker<String> {
@@ -1,10 +1,15 @@
 pub mod broker;
 
-use super::{AssignmentCallbacks, Consumer, ConsumerError};
-use crate::types::{BrokerMessage, Partition, Topic};
+use super::{AssignmentCallbacks, Consumer, ConsumerError, ProduceFuture, Producer, ProducerError};
+use crate::backends::storages::ConsumeError;
+use crate::types::{BrokerMessage, Partition, Position, Topic, TopicOrPartition};
 use broker::LocalBroker;
+use chrono::{DateTime, Utc};
+use futures::future;
+use rand::Rng;
 use std::collections::HashSet;
 use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use uuid::Uuid;
 
@@ -18,32 +23,44 @@ enum Callback {
 
 struct SubscriptionState {
     topics: Vec<Topic>,
+    // What the patterns in ``topics`` resolved to when the consumer last
+    // joined the group.
+    matched_topics: Vec<Topic>,
     callbacks: Option<Box<dyn AssignmentCallbacks>>,
     offsets: HashMap<Partition, u64>,
-    staged_positions: HashMap<Partition, u64>,
+    staged_positions: HashMap<Partition, Position>,
     last_eof_at: HashMap<Partition, u64>,
 }
 
-pub struct LocalConsumer<'a, TPayload: Clone> {
+fn offsets(positions: &HashMap<Partition, Position>) -> HashMap<Partition, u64> {
+    positions
+        .iter()
+        .map(|(partition, position)| (partition.clone(), position.offset))
+        .collect()
+}
+
+pub struct LocalConsumer<TPayload: Clone> {
     id: Uuid,
     group: String,
-    broker: &'a mut LocalBroker<TPayload>,
+    broker: Arc<Mutex<LocalBroker<TPayload>>>,
     pending_callback: VecDeque<Callback>,
     paused: HashSet<Partition>,
     // The offset that a the last ``EndOfPartition`` exception that was
     // raised at. To maintain consistency with the Confluent consumer, this
     // is only sent once per (partition, offset) pair.
     subscription_state: SubscriptionState,
+    // The positions returned by ``on_revoke``, until ``take_committed``.
+    revoke_committed: HashMap<Partition, Position>,
     enable_end_of_partition: bool,
     commit_offset_calls: u32,
     close_calls: u32,
     closed: bool,
 }
 
-impl<'a, TPayload: Clone> LocalConsumer<'a, TPayload> {
+impl<TPayload: Clone> LocalConsumer<TPayload> {
     pub fn new(
         id: Uuid,
-        broker: &'a mut LocalBroker<TPayload>,
+        broker: Arc<Mutex<LocalBroker<TPayload>>>,
         group: String,
         enable_end_of_partition: bool,
     ) -> Self {
@@ -55,11 +72,13 @@ impl<'a, TPayload: Clone> LocalConsumer<'a, TPayload> {
             paused: HashSet::new(),
             subscription_state: SubscriptionState {
                 topics: Vec::new(),
+                matched_topics: Vec::new(),
                 callbacks: None,
                 offsets: HashMap::new(),
                 staged_positions: HashMap::new(),
                 last_eof_at: HashMap::new(),
             },
+            revoke_committed: HashMap::new(),
             enable_end_of_partition,
             commit_offset_calls: 0,
             close_calls: 0,
@@ -68,7 +87,47 @@ impl<'a, TPayload: Clone> LocalConsumer<'a, TPayload> {
     }
 }
 
-impl<'a, TPayload: Clone> Consumer<'a, TPayload> for LocalConsumer<'a, TPayload> {
+impl<TPayload: Clone> LocalConsumer<TPayload> {
+    // Patterns are resolved again on every poll. When the topics they match
+    // change, the consumer leaves and joins the group again, which is how
+    // the rebalance triggered by the metadata refresh of librdkafka looks
+    // like to the callbacks.
+    fn refresh_pattern_subscription(&mut self) {
+        if !self.subscription_state.topics.iter().any(Topic::is_pattern) {
+            return;
+        }
+        let mut broker = self.broker.lock().unwrap();
+        let matched = broker
+            .resolve_topics(&self.subscription_state.topics)
+            .unwrap();
+        if matched == self.subscription_state.matched_topics {
+            return;
+        }
+
+        let revoked = broker.unsubscribe(self.id, self.group.clone()).unwrap();
+        if let Some(callbacks) = self.subscription_state.callbacks.as_mut() {
+            let positions = callbacks.on_revoke(revoked.clone());
+            broker.commit(&self.group, offsets(&positions));
+            self.revoke_committed.extend(positions);
+        }
+        for partition in revoked.iter() {
+            self.paused.remove(partition);
+        }
+        self.subscription_state.offsets = HashMap::new();
+
+        let assigned = broker
+            .subscribe(
+                self.id,
+                self.group.clone(),
+                self.subscription_state.topics.clone(),
+            )
+            .unwrap();
+        self.pending_callback.push_back(Callback::Assign(assigned));
+        self.subscription_state.matched_topics = matched;
+    }
+}
+
+impl<'a, TPayload: Clone> Consumer<'a, TPayload> for LocalConsumer<TPayload> {
     fn subscribe(
         &mut self,
         topics: &[Topic],
@@ -77,10 +136,12 @@ impl<'a, TPayload: Clone> Consumer<'a, TPayload> for LocalConsumer<'a, TPayload>
         if self.closed {
             return Err(ConsumerError::ConsumerClosed);
         }
-        let offsets = self
-            .broker
+        let mut broker = self.broker.lock().unwrap();
+        let offsets = broker
             .subscribe(self.id, self.group.clone(), topics.to_vec())
             .unwrap();
+        self.subscription_state.matched_topics = broker.resolve_topics(topics).unwrap();
+        drop(broker);
         self.subscription_state.topics = topics.to_vec();
         self.subscription_state.callbacks = Some(callbacks);
 
@@ -98,12 +159,15 @@ impl<'a, TPayload: Clone> Consumer<'a, TPayload> for LocalConsumer<'a, TPayload>
 
         let partitions = self
             .broker
+            .lock()
+            .unwrap()
             .unsubscribe(self.id, self.group.clone())
             .unwrap();
         self.pending_callback
             .push_back(Callback::Revoke(partitions));
 
         self.subscription_state.topics.clear();
+        self.subscription_state.matched_topics.clear();
         self.subscription_state.staged_positions.clear();
         self.subscription_state.last_eof_at.clear();
         Ok(())
@@ -117,6 +181,7 @@ impl<'a, TPayload: Clone> Consumer<'a, TPayload> for LocalConsumer<'a, TPayload>
             return Err(ConsumerError::ConsumerClosed);
         }
 
+        self.refresh_pattern_subscription();
         while !self.pending_callback.is_empty() {
             let callback = self.pending_callback.pop_front().unwrap();
             match callback {
@@ -127,15 +192,27 @@ impl<'a, TPayload: Clone> Consumer<'a, TPayload> for LocalConsumer<'a, TPayload>
                             callbacks.on_assign(offsets.clone());
                         }
                     }
+                    // Partitions are resumed when they are reassigned
+                    for partition in offsets.keys() {
+                        self.paused.remove(partition);
+                    }
                     self.subscription_state.offsets = offsets;
                 }
                 Callback::Revoke(partitions) => {
                     match self.subscription_state.callbacks.as_mut() {
                         None => {}
                         Some(callbacks) => {
-                            callbacks.on_revoke(partitions.clone());
+                            let positions = callbacks.on_revoke(partitions.clone());
+                            self.broker
+                                .lock()
+                                .unwrap()
+                                .commit(&self.group, offsets(&positions));
+                            self.revoke_committed.extend(positions);
                         }
                     }
+                    for partition in partitions.iter() {
+                        self.paused.remove(partition);
+                    }
                     self.subscription_state.offsets = HashMap::new();
                 }
             }
@@ -150,7 +227,12 @@ impl<'a, TPayload: Clone> Consumer<'a, TPayload> for LocalConsumer<'a, TPayload>
             }
 
             let offset = self.subscription_state.offsets[partition];
-            let message = self.broker.consume(partition, offset).unwrap();
+            let message = self
+                .broker
+                .lock()
+                .unwrap()
+                .consume(partition, offset)
+                .unwrap();
             match message {
                 Some(msg) => {
                     new_offset = Some((partition.clone(), msg.offset + 1));
@@ -226,16 +308,62 @@ impl<'a, TPayload: Clone> Consumer<'a, TPayload> for LocalConsumer<'a, TPayload>
         Ok(self.subscription_state.offsets.clone())
     }
 
-    fn seek(&self, _: HashMap<Partition, u64>) -> Result<(), ConsumerError> {
+    fn high_watermarks(&self) -> Result<HashMap<Partition, u64>, ConsumerError> {
+        if self.closed {
+            return Err(ConsumerError::ConsumerClosed);
+        }
+        let broker = self.broker.lock().unwrap();
+        let mut watermarks = HashMap::new();
+        for partition in self.subscription_state.offsets.keys() {
+            let watermark = broker
+                .high_watermark(partition)
+                .map_err(|e| ConsumerError::BrokerError(Box::new(e)))?;
+            watermarks.insert(partition.clone(), watermark);
+        }
+        Ok(watermarks)
+    }
+
+    fn seek(&mut self, offsets: HashMap<Partition, u64>) -> Result<(), ConsumerError> {
+        if self.closed {
+            return Err(ConsumerError::ConsumerClosed);
+        }
+        if offsets
+            .keys()
+            .any(|partition| !self.subscription_state.offsets.contains_key(partition))
+        {
+            return Err(ConsumerError::UnassignedPartition);
+        }
+
+        self.subscription_state.offsets.extend(offsets);
+        Ok(())
+    }
+
+    fn seek_to_timestamp(
+        &mut self,
+        partitions: HashSet<Partition>,
+        timestamp: DateTime<Utc>,
+    ) -> Result<HashMap<Partition, u64>, ConsumerError> {
         if self.closed {
             return Err(ConsumerError::ConsumerClosed);
         }
-        unimplemented!("Seek is not implemented");
+
+        let broker = self.broker.lock().unwrap();
+        let mut offsets = HashMap::new();
+        for partition in partitions {
+            let offset = broker
+                .offset_for_timestamp(&partition, timestamp)
+                .map_err(|e| ConsumerError::BrokerError(Box::new(e)))?;
+            offsets.insert(partition, offset);
+        }
+        drop(broker);
+
+        self.seek(offsets.clone())?;
+        Ok(offsets)
     }
 
     fn stage_offsets(
         &mut self,
-        offsets: HashMap<Partition, u64>,
+        offsets: HashMap<Partition, Position>,
     ) -> Result<(), ConsumerError> {
         if self.closed {
             return Err(ConsumerError::ConsumerClosed);
@@ -256,32 +384,42 @@ impl<'a, TPayload: Clone> Consumer<'a, TPayload> for LocalConsumer<'a, TPayload>
         Ok(())
     }
 
-    fn commit_offsets(&mut self) -> Result<HashMap<Partition, u64>, ConsumerError> {
+    fn commit_offsets(&mut self) -> Result<HashMap<Partition, Position>, ConsumerError> {
         if self.closed {
             return Err(ConsumerError::ConsumerClosed);
         }
         let positions = self.subscription_state.staged_positions.clone();
 
-        let offsets = positions
-            .iter()
-            .map(|(part, offset)| (part.clone(), offset.clone()))
-            .collect();
-        self.broker.commit(&self.group, offsets);
+        self.broker
+            .lock()
+            .unwrap()
+            .commit(&self.group, offsets(&positions));
         self.subscription_state.staged_positions.clear();
         self.commit_offset_calls += 1;
 
         Ok(positions)
     }
 
+    fn take_committed(&mut self) -> HashMap<Partition, Position> {
+        std::mem::take(&mut self.revoke_committed)
+    }
+
     fn close(&mut self) {
         let partitions = self
             .broker
+            .lock()
+            .unwrap()
             .unsubscribe(self.id, self.group.clone())
             .unwrap();
         match self.subscription_state.callbacks.as_mut() {
             None => {}
             Some(c) => {
-                c.on_revoke(partitions);
+                let positions = c.on_revoke(partitions);
+                self.broker
+                    .lock()
+                    .unwrap()
+                    .commit(&self.group, offsets(&positions));
+                self.revoke_committed.extend(positions);
             }
         }
         self.closed = true;
@@ -293,26 +431,101 @@ impl<'a, TPayload: Clone> Consumer<'a, TPayload> for LocalConsumer<'a, TPayload>
     }
 }
 
+/// Produces to the topics of a ``LocalBroker``, which can be shared with
+/// ``LocalConsumer`` instances to exercise a whole pipeline without Kafka.
+pub struct LocalProducer<TPayload: Clone> {
+    broker: Arc<Mutex<LocalBroker<TPayload>>>,
+    closed: bool,
+}
+
+impl<TPayload: Clone> LocalProducer<TPayload> {
+    pub fn new(broker: Arc<Mutex<LocalBroker<TPayload>>>) -> Self {
+        Self {
+            broker,
+            closed: false,
+        }
+    }
+}
+
+impl<TPayload: Clone> LocalProducer<TPayload> {
+    fn produce_to_broker(
+        &self,
+        destination: &TopicOrPartition,
+        payload: &TPayload,
+    ) -> Result<BrokerMessage<TPayload>, ProducerError> {
+        if self.closed {
+            return Err(ProducerError::ProducerClosed);
+        }
+
+        let mut broker = self.broker.lock().unwrap();
+        let partition = match destination {
+            TopicOrPartition::Topic(topic) => {
+                let partition_count = broker.get_topic_partition_count(topic).map_err(|_| {
+                    ProducerError::BrokerError(Box::new(ConsumeError::TopicDoesNotExist))
+                })?;
+                Partition {
+                    topic: topic.clone(),
+                    index: rand::thread_rng().gen_range(0..partition_count),
+                }
+            }
+            TopicOrPartition::Partition(partition) => partition.clone(),
+        };
+        let broker_error = |error: ConsumeError| ProducerError::BrokerError(Box::new(error));
+        let offset = broker
+            .produce(&partition, payload.clone())
+            .map_err(broker_error)?;
+        let message = broker.consume(&partition, offset).map_err(broker_error)?;
+        Ok(message.unwrap())
+    }
+}
+
+impl<TPayload: Clone + Send + Sync + 'static> Producer<TPayload> for LocalProducer<TPayload> {
+    fn produce(
+        &self,
+        destination: &TopicOrPartition,
+        payload: &TPayload,
+    ) -> Result<(), ProducerError> {
+        self.produce_to_broker(destination, payload)?;
+        Ok(())
+    }
+
+    fn produce_async(
+        &self,
+        destination: &TopicOrPartition,
+        payload: &TPayload,
+    ) -> ProduceFuture<TPayload> {
+        Box::pin(future::ready(self.produce_to_broker(destination, payload)))
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{AssignmentCallbacks, LocalConsumer};
+    use super::{AssignmentCallbacks, LocalConsumer, LocalProducer};
     use crate::backends::local::broker::LocalBroker;
     use crate::backends::storages::memory::MemoryMessageStorage;
-    use crate::backends::Consumer;
-    use crate::types::{Partition, Topic};
-    use crate::utils::clock::SystemClock;
-    use chrono::Utc;
+    use crate::backends::{Consumer, Producer};
+    use crate::types::{Partition, Position, Topic, TopicOrPartition};
+    use crate::utils::clock::{Clock, SystemClock, TestingClock};
+    use chrono::{DateTime, Utc};
+    use futures::FutureExt;
     use std::collections::{HashMap, HashSet};
-    use std::time::Duration;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
     use uuid::Uuid;
 
     struct EmptyCallbacks {}
     impl AssignmentCallbacks for EmptyCallbacks {
         fn on_assign(&mut self, _: HashMap<Partition, u64>) {}
-        fn on_revoke(&mut self, _: Vec<Partition>) {}
+        fn on_revoke(&mut self, _: Vec<Partition>) -> HashMap<Partition, Position> {
+            HashMap::new()
+        }
     }
 
-    fn build_broker() -> LocalBroker<String> {
+    fn build_broker() -> Arc<Mutex<LocalBroker<String>>> {
         let storage: MemoryMessageStorage<String> = Default::default();
         let clock = SystemClock {};
         let mut broker = LocalBroker::new(Box::new(storage), Box::new(clock));
@@ -326,12 +539,12 @@ mod tests {
 
         let _ = broker.create_topic(topic1, 2);
         let _ = broker.create_topic(topic2, 1);
-        broker
+        Arc::new(Mutex::new(broker))
     }
 
     #[test]
     fn test_consumer_subscription() {
-        let mut broker = build_broker();
+        let broker = build_broker();
 
         let topic1 = Topic {
             name: "test1".to_string(),
@@ -342,7 +555,7 @@ mod tests {
 
         let my_callbacks: Box<dyn AssignmentCallbacks> = Box::new(EmptyCallbacks {});
         let mut consumer =
-            LocalConsumer::new(Uuid::nil(), &mut broker, "test_group".to_string(), true);
+            LocalConsumer::new(Uuid::nil(), broker.clone(), "test_group".to_string(), true);
         assert!(consumer.subscription_state.topics.is_empty());
 
         let res = consumer.subscribe(&[topic1.clone(), topic2.clone()], my_callbacks);
@@ -385,7 +598,7 @@ mod tests {
 
     #[test]
     fn test_subscription_callback() {
-        let mut broker = build_broker();
+        let broker = build_broker();
 
         let topic1 = Topic {
             name: "test1".to_string(),
@@ -430,7 +643,7 @@ mod tests {
                     ])
                 )
             }
-            fn on_revoke(&mut self, partitions: Vec<Partition>) {
+            fn on_revoke(&mut self, partitions: Vec<Partition>) -> HashMap<Partition, Position> {
                 let topic1 = Topic {
                     name: "test1".to_string(),
                 };
@@ -454,13 +667,14 @@ mod tests {
                         },
                     ]
                 );
+                HashMap::new()
             }
         }
 
         let my_callbacks: Box<dyn AssignmentCallbacks> = Box::new(TheseCallbacks {});
 
         let mut consumer =
-            LocalConsumer::new(Uuid::nil(), &mut broker, "test_group".to_string(), true);
+            LocalConsumer::new(Uuid::nil(), broker.clone(), "test_group".to_string(), true);
 
         let _ = consumer.subscribe(&[topic1, topic2], my_callbacks);
         let _ = consumer.poll(Some(Duration::from_millis(100)));
@@ -471,7 +685,7 @@ mod tests {
 
     #[test]
     fn test_consume() {
-        let mut broker = build_broker();
+        let broker = build_broker();
 
         let topic2 = Topic {
             name: "test2".to_string(),
@@ -480,8 +694,14 @@ mod tests {
             topic: topic2.clone(),
             index: 0,
         };
-        let _ = broker.produce(&partition, "message1".to_string());
-        let _ = broker.produce(&partition, "message2".to_string());
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "message1".to_string());
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "message2".to_string());
 
         struct TheseCallbacks {}
         impl AssignmentCallbacks for TheseCallbacks {
@@ -500,12 +720,14 @@ mod tests {
                     ),])
                 );
             }
-            fn on_revoke(&mut self, _: Vec<Partition>) {}
+            fn on_revoke(&mut self, _: Vec<Partition>) -> HashMap<Partition, Position> {
+                HashMap::new()
+            }
         }
 
         let my_callbacks: Box<dyn AssignmentCallbacks> = Box::new(TheseCallbacks {});
         let mut consumer =
-            LocalConsumer::new(Uuid::nil(), &mut broker, "test_group".to_string(), true);
+            LocalConsumer::new(Uuid::nil(), broker.clone(), "test_group".to_string(), true);
 
         let _ = consumer.subscribe(&[topic2], my_callbacks);
 
@@ -529,7 +751,7 @@ mod tests {
 
     #[test]
     fn test_paused() {
-        let mut broker = build_broker();
+        let broker = build_broker();
         let topic2 = Topic {
             name: "test2".to_string(),
         };
@@ -539,7 +761,7 @@ mod tests {
         };
         let my_callbacks: Box<dyn AssignmentCallbacks> = Box::new(EmptyCallbacks {});
         let mut consumer =
-            LocalConsumer::new(Uuid::nil(), &mut broker, "test_group".to_string(), false);
+            LocalConsumer::new(Uuid::nil(), broker.clone(), "test_group".to_string(), false);
         let _ = consumer.subscribe(&[topic2], my_callbacks);
 
         assert_eq!(consumer.poll(None).unwrap(), None);
@@ -553,28 +775,115 @@ mod tests {
         assert_eq!(consumer.poll(None).unwrap(), None);
     }
 
+    #[test]
+    fn test_seek_to_timestamp() {
+        let storage: MemoryMessageStorage<String> = Default::default();
+        let start = SystemTime::UNIX_EPOCH;
+        let clock = TestingClock::new(start);
+        let mut broker = LocalBroker::new(Box::new(storage), Box::new(clock.clone()));
+        let topic = Topic {
+            name: "test".to_string(),
+        };
+        let _ = broker.create_topic(topic.clone(), 1);
+        let partition = Partition {
+            topic: topic.clone(),
+            index: 0,
+        };
+        for payload in ["message1", "message2", "message3"] {
+            broker.produce(&partition, payload.to_string()).unwrap();
+            clock.sleep(Duration::from_secs(10));
+        }
+
+        let mut consumer = LocalConsumer::new(
+            Uuid::nil(),
+            Arc::new(Mutex::new(broker)),
+            "test_group".to_string(),
+            false,
+        );
+        let _ = consumer.subscribe(std::slice::from_ref(&topic), Box::new(EmptyCallbacks {}));
+        assert_eq!(consumer.poll(None).unwrap().unwrap().offset, 0);
+
+        let offsets = consumer
+            .seek_to_timestamp(
+                HashSet::from([partition.clone()]),
+                DateTime::from(start + Duration::from_secs(5)),
+            )
+            .unwrap();
+        assert_eq!(offsets, HashMap::from([(partition.clone(), 1)]));
+        assert_eq!(consumer.poll(None).unwrap().unwrap().offset, 1);
+        assert_eq!(
+            consumer.high_watermarks().unwrap(),
+            HashMap::from([(partition.clone(), 3)])
+        );
+
+        // Past the last message
+        let offsets = consumer
+            .seek_to_timestamp(
+                HashSet::from([partition.clone()]),
+                DateTime::from(start + Duration::from_secs(3600)),
+            )
+            .unwrap();
+        assert_eq!(offsets, HashMap::from([(partition, 3)]));
+        assert_eq!(consumer.poll(None).unwrap(), None);
+
+        let unassigned = Partition { topic, index: 1 };
+        assert!(consumer.seek(HashMap::from([(unassigned, 0)])).is_err());
+    }
+
+    #[test]
+    fn test_pattern_subscription() {
+        let broker = build_broker();
+        let mut consumer =
+            LocalConsumer::new(Uuid::nil(), broker.clone(), "test_group".to_string(), false);
+        let pattern = Topic {
+            name: "^test[0-9]$".to_string(),
+        };
+        let _ = consumer.subscribe(&[pattern], Box::new(EmptyCallbacks {}));
+        let _ = consumer.poll(None);
+        assert_eq!(consumer.tell().unwrap().len(), 3);
+
+        // A topic matching the pattern is picked up by the next poll
+        let topic3 = Topic {
+            name: "test3".to_string(),
+        };
+        let _ = broker.lock().unwrap().create_topic(topic3.clone(), 1);
+        let _ = broker.lock().unwrap().create_topic(
+            Topic {
+                name: "other".to_string(),
+            },
+            1,
+        );
+        let _ = consumer.poll(None);
+        let offsets = consumer.tell().unwrap();
+        assert_eq!(offsets.len(), 4);
+        assert!(offsets.contains_key(&Partition {
+            topic: topic3,
+            index: 0
+        }));
+    }
+
     #[test]
     fn test_commit() {
-        let mut broker = build_broker();
+        let broker = build_broker();
         let my_callbacks: Box<dyn AssignmentCallbacks> = Box::new(EmptyCallbacks {});
         let mut consumer =
-            LocalConsumer::new(Uuid::nil(), &mut broker, "test_group".to_string(), false);
+            LocalConsumer::new(Uuid::nil(), broker.clone(), "test_group".to_string(), false);
         let topic2 = Topic {
             name: "test2".to_string(),
         };
-        let _ = consumer.subscribe(&[topic2.clone()], my_callbacks);
+        let _ = consumer.subscribe(std::slice::from_ref(&topic2), my_callbacks);
         let _ = consumer.poll(None);
         let positions = HashMap::from([(
             Partition {
                 topic: topic2.clone(),
                 index: 0,
             },
-            100,
+            Position::new(100, Utc::now()),
         )]);
         let stage_result = consumer.stage_offsets(positions.clone());
         assert!(stage_result.is_ok());
 
-        let offsets = consumer.commit_offsetes();
+        let offsets = consumer.commit_offsets();
         assert!(offsets.is_ok());
         assert_eq!(offsets.unwrap(), positions);
 
@@ -584,10 +893,46 @@ mod tests {
                 topic: topic2,
                 index: 1,
             },
-            100
+            Position::new(100, Utc::now()),
         )]);
 
         let stage_result = consumer.stage_offsets(invalid_positions);
         assert!(stage_result.is_err());
     }
+
+    #[test]
+    fn test_producer() {
+        let broker = build_broker();
+        let topic2 = Topic {
+            name: "test2".to_string(),
+        };
+        let mut producer = LocalProducer::new(broker.clone());
+        producer
+            .produce(
+                &TopicOrPartition::Topic(topic2.clone()),
+                &"message1".to_string(),
+            )
+            .unwrap();
+
+        let mut consumer = LocalConsumer::new(Uuid::nil(), broker, "test_group".to_string(), false);
+        let _ = consumer.subscribe(std::slice::from_ref(&topic2), Box::new(EmptyCallbacks {}));
+        let message = consumer.poll(None).unwrap().unwrap();
+        assert_eq!(message.payload, "message1".to_string());
+
+        let produced = producer
+            .produce_async(
+                &TopicOrPartition::Topic(topic2.clone()),
+                &"message2".to_string(),
+            )
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(produced.offset, 1);
+        assert_eq!(produced.payload, "message2".to_string());
+
+        producer.close();
+        assert!(producer
+            .produce(&TopicOrPartition::Topic(topic2), &"message3".to_string())
+            .is_err());
+    }
 }
@@ -1,5 +1,8 @@
-use super::types::{BrokerMessage, Partition, Topic, TopicOrPartition};
+use super::types::{BrokerMessage, Partition, Position, Topic, TopicOrPartition};
+use chrono::{DateTime, Utc};
 use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
 use std::time::Duration;
 use thiserror::Error;
 
@@ -28,15 +31,69 @@ pub enum ConsumerError {
     #[error("Offset out of range")]
     OffsetOutOfRange { source: Box<dyn std::error::Error> },
 
+    /// The brokers can not be reached. This is transient, the client
+    /// reconnects on its own.
+    #[error("Broker transport failure: {source}")]
+    BrokerTransportFailure { source: Box<dyn std::error::Error> },
+
+    /// The credentials were rejected, or they do not allow consuming the
+    /// topic. This does not go away without a change of configuration.
+    #[error("Authentication failure: {source}")]
+    AuthFailure { source: Box<dyn std::error::Error> },
+
+    /// The client can not be used anymore.
+    #[error("Fatal error: {source}")]
+    Fatal { source: Box<dyn std::error::Error> },
+
     #[error(transparent)]
     BrokerError(#[from] Box<dyn std::error::Error>),
 }
 
+impl ConsumerError {
+    /// Whether the operation that failed can be retried. The client recovers
+    /// on its own from transport failures, and from the errors of the broker
+    /// that are not classified.
+    pub fn is_retriable(&self) -> bool {
+        matches!(
+            self,
+            ConsumerError::EndOfPartition
+                | ConsumerError::BrokerTransportFailure { .. }
+                | ConsumerError::BrokerError(_)
+        )
+    }
+
+    /// The name of the variant, to tag metrics with.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ConsumerError::EndOfPartition => "end_of_partition",
+            ConsumerError::NotSubscribed => "not_subscribed",
+            ConsumerError::ConsumerErrored => "consumer_errored",
+            ConsumerError::ConsumerClosed => "consumer_closed",
+            ConsumerError::UnassignedPartition => "unassigned_partition",
+            ConsumerError::OffsetOutOfRange { .. } => "offset_out_of_range",
+            ConsumerError::BrokerTransportFailure { .. } => "broker_transport_failure",
+            ConsumerError::AuthFailure { .. } => "auth_failure",
+            ConsumerError::Fatal { .. } => "fatal",
+            ConsumerError::BrokerError(_) => "broker_error",
+        }
+    }
+}
+
 /// This is basically an observer pattern to receive the callbacks from
 /// the consumer when partitions are assigned/revoked.
+///
+/// Both callbacks only mention the partitions that changed. With an eager
+/// assignor every rebalance revokes the whole assignment before assigning
+/// the new one, with a cooperative one the partitions that do not move are
+/// never part of either call.
 pub trait AssignmentCallbacks: Send + Sync {
     fn on_assign(&mut self, partitions: HashMap<Partition, u64>);
-    fn on_revoke(&mut self, partitions: Vec<Partition>);
+
+    /// Called before the partitions are released. The returned positions are
+    /// committed synchronously by the consumer before the revocation
+    /// completes, so that work finished while winding down is not replayed
+    /// by the next owner of the partitions.
+    fn on_revoke(&mut self, partitions: Vec<Partition>) -> HashMap<Partition, Position>;
 }
 
 /// This abstract class provides an interface for consuming messages from a
@@ -122,6 +179,11 @@ pub trait Consumer<'a, TPayload: Clone> {
     /// Return the working offsets for all currently assigned positions.
     fn tell(&self) -> Result<HashMap<Partition, u64>, ConsumerError>;
 
+    /// Return the high watermarks of all currently assigned partitions, as
+    /// last known by the consumer: this does not query the broker.
+    /// Partitions whose high watermark is not known yet are omitted.
+    fn high_watermarks(&self) -> Result<HashMap<Partition, u64>, ConsumerError>;
+
     /// Update the working offsets for the provided partitions.
     ///
     /// When using this method, it is possible to set a partition to an
@@ -136,28 +198,104 @@ pub trait Consumer<'a, TPayload: Clone> {
     ///
     /// If any provided partitions are not in the assignment set, an
     /// exception will be raised and no offsets will be modified.
-    fn seek(&self, offsets: HashMap<Partition, u64>) -> Result<(), ConsumerError>;
+    fn seek(&mut self, offsets: HashMap<Partition, u64>) -> Result<(), ConsumerError>;
+
+    /// Move the working offsets of the provided partitions to the first
+    /// message with a timestamp greater than or equal to ``timestamp``, or to
+    /// the end of the partition if there is no such message. Returns the new
+    /// working offsets.
+    ///
+    /// If any provided partitions are not in the assignment set, an
+    /// exception will be raised and no offsets will be modified.
+    fn seek_to_timestamp(
+        &mut self,
+        partitions: HashSet<Partition>,
+        timestamp: DateTime<Utc>,
+    ) -> Result<HashMap<Partition, u64>, ConsumerError>;
 
     /// Stage offsets to be committed. If an offset has already been staged
     /// for a given partition, that offset is overwritten (even if the offset
-    /// moves in reverse.)
+    /// moves in reverse.) The offsets staged for a partition are dropped
+    /// when it is revoked.
     fn stage_offsets(
         &mut self,
-        positions: HashMap<Partition, u64>,
+        positions: HashMap<Partition, Position>,
     ) -> Result<(), ConsumerError>;
 
     /// Commit staged offsets. The return value of this method is a mapping
-    /// of streams with their committed offsets as values.
-    fn commit_offsets(&mut self) -> Result<HashMap<Partition, u64>, ConsumerError>;
+    /// of streams with their committed positions as values. A consumer that
+    /// commits asynchronously returns nothing, the positions are returned by
+    /// ``take_committed`` once the broker acknowledged them.
+    fn commit_offsets(&mut self) -> Result<HashMap<Partition, Position>, ConsumerError>;
+
+    /// Commit staged offsets and wait for the broker to acknowledge them,
+    /// even if ``commit_offsets`` does not. This is how the offsets are
+    /// committed on shutdown.
+    fn commit_offsets_sync(&mut self) -> Result<HashMap<Partition, Position>, ConsumerError> {
+        self.commit_offsets()
+    }
+
+    /// Returns the positions committed since the last call which
+    /// ``commit_offsets`` did not return: those of asynchronous commits the
+    /// broker acknowledged, and those returned by ``on_revoke``.
+    fn take_committed(&mut self) -> HashMap<Partition, Position> {
+        HashMap::new()
+    }
 
     fn close(&mut self);
 
     fn closed(&self) -> bool;
 }
 
-pub trait Producer<TPayload> {
+#[non_exhaustive]
+#[derive(Error, Debug)]
+pub enum ProducerError {
+    #[error("The producer is closed")]
+    ProducerClosed,
+
+    #[error(transparent)]
+    BrokerError(#[from] Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl ProducerError {
+    /// Errors of the broker, such as a full queue, can be retried.
+    pub fn is_retriable(&self) -> bool {
+        matches!(self, ProducerError::BrokerError(_))
+    }
+
+    /// The name of the variant, to tag metrics with.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProducerError::ProducerClosed => "producer_closed",
+            ProducerError::BrokerError(_) => "broker_error",
+        }
+    }
+}
+
+/// Resolves once the broker has acknowledged a produced message, to the
+/// message as it was written, or to the error that prevented its delivery.
+pub type ProduceFuture<TPayload> =
+    Pin<Box<dyn Future<Output = Result<BrokerMessage<TPayload>, ProducerError>> + Send + Sync>>;
+
+pub trait Producer<TPayload: Clone>: Send + Sync {
     /// Produce to a topic or partition.
-    fn produce(&self, destination: &TopicOrPartition, payload: &TPayload);
+    ///
+    /// An error is returned if the message could not be handed over to the
+    /// broker client.
+    fn produce(
+        &self,
+        destination: &TopicOrPartition,
+        payload: &TPayload,
+    ) -> Result<(), ProducerError>;
+
+    /// Produce to a topic or partition and return a future that resolves
+    /// once the delivery of the message was confirmed. This never blocks,
+    /// any error is reported through the future.
+    fn produce_async(
+        &self,
+        destination: &TopicOrPartition,
+        payload: &TPayload,
+    ) -> ProduceFuture<TPayload>;
 
     fn close(&mut self);
 }
@@ -28,7 +28,10 @@ impl<TPayload: Clone> TopicContent<TPayload> {
         }
     }
 
-    fn get_messages(&self, partition: &Partition) -> Result<&Vec<BrokerMessage<TPayload>>, ConsumeError> {
+    fn get_messages(
+        &self,
+        partition: &Partition,
+    ) -> Result<&Vec<BrokerMessage<TPayload>>, ConsumeError> {
         if !self.partition_meta.contains(partition) {
             return Err(ConsumeError::PartitionDoesNotExist);
         }
@@ -127,6 +130,15 @@ impl<TPayload: Clone> MessageStorage<TPayload> for MemoryMessageStorage<TPayload
         }
     }
 
+    fn get_high_watermark(&self, partition: &Partition) -> Result<u64, ConsumeError> {
+        let messages = self
+            .topics
+            .get(&partition.topic)
+            .ok_or(ConsumeError::TopicDoesNotExist)?
+            .get_messages(partition)?;
+        Ok(u64::try_from(messages.len()).unwrap())
+    }
+
     fn produce(
         &mut self,
         partition: &Partition,
@@ -142,7 +154,7 @@ impl<TPayload: Clone> MessageStorage<TPayload> for MemoryMessageStorage<TPayload
             payload,
             partition.clone(),
             u64::try_from(offset).unwrap(),
-             timestamp,
+            timestamp,
         ));
         Ok(u64::try_from(offset).unwrap())
     }
@@ -153,7 +165,7 @@ mod tests {
     use super::MemoryMessageStorage;
     use super::TopicContent;
     use crate::backends::storages::MessageStorage;
-    use crate::types::{Message, Partition, Topic};
+    use crate::types::{BrokerMessage, Partition, Topic};
     use chrono::Utc;
 
     #[test]
@@ -209,7 +221,7 @@ mod tests {
             },
             index: 0,
         };
-        let res = topic.add_message(Message::new(p, 10, "payload".to_string(), now));
+        let res = topic.add_message(BrokerMessage::new("payload".to_string(), p, 10, now));
 
         let p0 = Partition {
             topic: Topic {
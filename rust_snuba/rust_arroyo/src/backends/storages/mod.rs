@@ -1,6 +1,7 @@
 pub mod memory;
 use super::super::types::{BrokerMessage, Partition, Topic};
 use chrono::{DateTime, Utc};
+use thiserror::Error;
 
 #[derive(Debug, Clone)]
 pub struct TopicExists;
@@ -14,10 +15,13 @@ pub struct PartitionDoesNotExist;
 #[derive(Debug, Clone)]
 pub struct OffsetOutOfRange;
 
-#[derive(Debug)]
+#[derive(Debug, Error)]
 pub enum ConsumeError {
+    #[error("Topic does not exist")]
     TopicDoesNotExist,
+    #[error("Partition does not exist")]
     PartitionDoesNotExist,
+    #[error("Offset out of range")]
     OffsetOutOfRange,
 }
 
@@ -62,6 +66,13 @@ pub trait MessageStorage<TPayload: Clone> {
         offset: u64,
     ) -> Result<Option<BrokerMessage<TPayload>>, ConsumeError>;
 
+    // Get the offset the next message produced to the partition will have.
+    //
+    // If the topic does not exist, a ``TopicDoesNotExist`` exception will
+    // be raised. If the topic exists but the partition does not, a
+    // ``PartitionDoesNotExist`` exception will be raised.
+    fn get_high_watermark(&self, partition: &Partition) -> Result<u64, ConsumeError>;
+
     // Produce a single message to the provided partition.
     //
     // If the topic does not exist, a ``TopicDoesNotExist`` exception will
@@ -3,7 +3,7 @@ extern crate rust_arroyo;
 use crate::rust_arroyo::backends::Producer;
 use clap::{App, Arg};
 use log::debug;
-use rust_arroyo::backends::kafka::config::KafkaConfig;
+use rust_arroyo::backends::kafka::config::{ConfigError, KafkaConfig};
 use rust_arroyo::backends::kafka::producer::KafkaProducer;
 use rust_arroyo::backends::kafka::types::KafkaPayload;
 use rust_arroyo::backends::kafka::KafkaConsumer;
@@ -65,7 +65,7 @@ impl ProcessingStrategyFactory<KafkaPayload> for StrategyFactory {
     }
 }
 
-fn main() {
+fn main() -> Result<(), ConfigError> {
     let matches = App::new("consumer example")
         .version(option_env!("CARGO_PKG_VERSION").unwrap_or(""))
         .about("Simple command line consumer")
@@ -136,10 +136,10 @@ fn main() {
     let config = KafkaConfig::new_consumer_config(
         vec![brokers.to_string()],
         group_id.to_string(),
-        offset_reset.to_string(),
+        offset_reset.parse()?,
         false,
         None,
-    );
+    )?;
     let consumer = KafkaConsumer::new(config);
     let topic = Topic {
         name: source_topic.to_string(),
@@ -154,4 +154,5 @@ fn main() {
 
     stream_processor.subscribe(topic);
     stream_processor.run().unwrap();
+    Ok(())
 }
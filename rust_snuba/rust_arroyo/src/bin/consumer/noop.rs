@@ -2,7 +2,7 @@ extern crate rust_arroyo;
 
 use clap::{App, Arg};
 use log::info;
-use rust_arroyo::backends::kafka::config::KafkaConfig;
+use rust_arroyo::backends::kafka::config::{ConfigError, KafkaConfig};
 use rust_arroyo::backends::kafka::types::KafkaPayload;
 use rust_arroyo::backends::kafka::KafkaConsumer;
 use rust_arroyo::backends::AssignmentCallbacks;
@@ -28,7 +28,7 @@ impl ProcessingStrategyFactory<KafkaPayload> for StrategyFactory {
     }
 }
 
-fn main() {
+fn main() -> Result<(), ConfigError> {
     let matches = App::new("noop consumer")
         .version(option_env!("CARGO_PKG_VERSION").unwrap_or(""))
         .about("Simple noop consumer")
@@ -91,10 +91,10 @@ fn main() {
     let config = KafkaConfig::new_consumer_config(
         vec![brokers.to_string()],
         group_id.to_string(),
-        offset_reset.to_string(),
+        offset_reset.parse()?,
         false,
         None,
-    );
+    )?;
     let consumer = KafkaConsumer::new(config);
     let topic = Topic {
         name: source_topic.to_string(),
@@ -105,4 +105,5 @@ fn main() {
     info!("Starting no-op consumer");
     stream_processor.subscribe(topic);
     stream_processor.run().unwrap();
+    Ok(())
 }
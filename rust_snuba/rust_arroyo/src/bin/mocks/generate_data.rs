@@ -80,7 +80,7 @@ fn main() {
         let payload = KafkaPayload {
             key: None,
             headers: None,
-            payload: Some(generate_metric().as_bytes().to_vec()),
+            payload: Some(generate_metric().as_bytes().into()),
         };
         producer.produce(&destination, &payload);
         producer.poll();
@@ -0,0 +1,342 @@
+use crate::codecs::{Codec, CodecError};
+use apache_avro::{from_avro_datum, from_value, to_avro_datum, to_value, Schema};
+use parking_lot::RwLock;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+// The first byte of a payload in the Confluent wire format, followed by the
+// schema id as a big endian u32 and the Avro datum.
+const MAGIC_BYTE: u8 = 0;
+const HEADER_LENGTH: usize = 5;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a schema that could not be fetched is not fetched again, doubled
+// after every failure.
+const MIN_FETCH_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_FETCH_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug)]
+pub enum AvroError {
+    #[error("Payload is not in the Confluent wire format")]
+    NotWireFormat,
+    #[error("Failed to fetch schema {0} from the registry: {1}")]
+    Registry(u32, String),
+    #[error("Invalid schema {0}: {1}")]
+    InvalidSchema(u32, String),
+    #[error("Failed to decode payload: {0}")]
+    Decode(#[from] apache_avro::Error),
+}
+
+impl From<AvroError> for CodecError {
+    fn from(error: AvroError) -> Self {
+        match error {
+            // Usually transient, the payload can be decoded once the
+            // registry is reachable again.
+            AvroError::Registry(..) => CodecError::Unavailable(error.to_string()),
+            _ => CodecError::Decode(error.to_string()),
+        }
+    }
+}
+
+/// Splits a Confluent wire format payload into its schema id and datum.
+pub fn parse_wire_format(payload: &[u8]) -> Result<(u32, &[u8]), AvroError> {
+    if payload.len() < HEADER_LENGTH || payload[0] != MAGIC_BYTE {
+        return Err(AvroError::NotWireFormat);
+    }
+    let schema_id = u32::from_be_bytes(payload[1..HEADER_LENGTH].try_into().unwrap());
+    Ok((schema_id, &payload[HEADER_LENGTH..]))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SchemaResponse {
+    schema: String,
+    // Absent for Avro schemas.
+    schema_type: Option<String>,
+}
+
+struct FailedFetch {
+    error: String,
+    retry_at: Instant,
+    backoff: Duration,
+}
+
+/// Fetches schemas by id from a Confluent Schema Registry. Schemas are
+/// immutable once registered, so every schema is fetched only once.
+///
+/// A schema that could not be fetched is not fetched again before a
+/// backoff, the error is returned right away in the meantime so that
+/// decoding does not wait for the registry on every message while it is
+/// down.
+pub struct SchemaRegistry {
+    url: String,
+    client: reqwest::blocking::Client,
+    schemas: RwLock<HashMap<u32, Arc<Schema>>>,
+    failures: RwLock<HashMap<u32, FailedFetch>>,
+}
+
+impl SchemaRegistry {
+    pub fn new(url: &str) -> Self {
+        SchemaRegistry {
+            url: url.trim_end_matches('/').to_string(),
+            client: reqwest::blocking::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .unwrap(),
+            schemas: RwLock::new(HashMap::new()),
+            failures: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Adds a schema to the cache, so it does not have to be fetched.
+    pub fn add_schema(&self, schema_id: u32, schema: Schema) {
+        self.schemas.write().insert(schema_id, Arc::new(schema));
+    }
+
+    pub fn get_schema(&self, schema_id: u32) -> Result<Arc<Schema>, AvroError> {
+        if let Some(schema) = self.schemas.read().get(&schema_id) {
+            return Ok(schema.clone());
+        }
+        if let Some(failure) = self.failures.read().get(&schema_id) {
+            if Instant::now() < failure.retry_at {
+                return Err(AvroError::Registry(schema_id, failure.error.clone()));
+            }
+        }
+
+        match self.fetch_schema(schema_id) {
+            Ok(schema) => {
+                let schema = Arc::new(schema);
+                self.failures.write().remove(&schema_id);
+                self.schemas.write().insert(schema_id, schema.clone());
+                Ok(schema)
+            }
+            Err(AvroError::Registry(_, error)) => {
+                let mut failures = self.failures.write();
+                let backoff = failures
+                    .get(&schema_id)
+                    .map_or(MIN_FETCH_BACKOFF, |failure| {
+                        (failure.backoff * 2).min(MAX_FETCH_BACKOFF)
+                    });
+                failures.insert(
+                    schema_id,
+                    FailedFetch {
+                        error: error.clone(),
+                        retry_at: Instant::now() + backoff,
+                        backoff,
+                    },
+                );
+                Err(AvroError::Registry(schema_id, error))
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    fn fetch_schema(&self, schema_id: u32) -> Result<Schema, AvroError> {
+        let registry_error =
+            |error: reqwest::Error| AvroError::Registry(schema_id, error.to_string());
+        let response: SchemaResponse = self
+            .client
+            .get(format!("{}/schemas/ids/{}", self.url, schema_id))
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.json())
+            .map_err(registry_error)?;
+
+        if let Some(schema_type) = response.schema_type.filter(|t| t != "AVRO") {
+            return Err(AvroError::InvalidSchema(
+                schema_id,
+                format!("unsupported schema type {}", schema_type),
+            ));
+        }
+        Schema::parse_str(&response.schema)
+            .map_err(|error| AvroError::InvalidSchema(schema_id, error.to_string()))
+    }
+}
+
+/// Decodes Avro payloads in the Confluent wire format, with the schema they
+/// were written with. Values can only be encoded once a writer schema is
+/// set, it has to be registered already.
+#[derive(Clone)]
+pub struct AvroCodec {
+    registry: Arc<SchemaRegistry>,
+    writer_schema: Option<(u32, Arc<Schema>)>,
+}
+
+impl AvroCodec {
+    pub fn new(registry: Arc<SchemaRegistry>) -> Self {
+        AvroCodec {
+            registry,
+            writer_schema: None,
+        }
+    }
+
+    pub fn with_writer_schema(mut self, schema_id: u32) -> Result<Self, AvroError> {
+        let schema = self.registry.get_schema(schema_id)?;
+        self.writer_schema = Some((schema_id, schema));
+        Ok(self)
+    }
+
+    fn decode_value<T: DeserializeOwned>(&self, payload: &[u8]) -> Result<T, AvroError> {
+        let (schema_id, mut datum) = parse_wire_format(payload)?;
+        let schema = self.registry.get_schema(schema_id)?;
+        let value = from_avro_datum(&schema, &mut datum, None)?;
+        Ok(from_value(&value)?)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for AvroCodec {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        let (schema_id, schema) = self
+            .writer_schema
+            .as_ref()
+            .ok_or_else(|| CodecError::Encode("No writer schema".to_string()))?;
+        let encode_error = |error: apache_avro::Error| CodecError::Encode(error.to_string());
+        let value = to_value(value).map_err(encode_error)?;
+        let datum = to_avro_datum(schema, value).map_err(encode_error)?;
+
+        let mut payload = Vec::with_capacity(HEADER_LENGTH + datum.len());
+        payload.push(MAGIC_BYTE);
+        payload.extend(schema_id.to_be_bytes());
+        payload.extend(datum);
+        Ok(payload)
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<T, CodecError> {
+        Ok(self.decode_value(payload)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_wire_format, AvroCodec, AvroError, SchemaRegistry};
+    use crate::codecs::{Codec, CodecError};
+    use apache_avro::types::Value;
+    use apache_avro::{to_avro_datum, Schema};
+    use serde::{Deserialize, Serialize};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    const SCHEMA: &str = r#"{
+        "type": "record",
+        "name": "Event",
+        "fields": [
+            {"name": "project_id", "type": "long"},
+            {"name": "message", "type": "string"}
+        ]
+    }"#;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Event {
+        project_id: i64,
+        message: String,
+    }
+
+    fn encode(schema_id: u32, schema: &Schema) -> Vec<u8> {
+        let value = Value::Record(vec![
+            ("project_id".to_string(), Value::Long(1)),
+            ("message".to_string(), Value::String("hello".to_string())),
+        ]);
+        let mut payload = vec![0];
+        payload.extend(schema_id.to_be_bytes());
+        payload.extend(to_avro_datum(schema, value).unwrap());
+        payload
+    }
+
+    #[test]
+    fn test_parse_wire_format() {
+        assert_eq!(
+            parse_wire_format(&[0, 0, 0, 1, 2, 42]).unwrap(),
+            (258, &[42][..])
+        );
+        assert!(matches!(
+            parse_wire_format(b"{\"json\": true}"),
+            Err(AvroError::NotWireFormat)
+        ));
+        assert!(matches!(
+            parse_wire_format(&[0, 0]),
+            Err(AvroError::NotWireFormat)
+        ));
+    }
+
+    #[test]
+    fn test_decode() {
+        let schema = Schema::parse_str(SCHEMA).unwrap();
+        let registry = Arc::new(SchemaRegistry::new("http://127.0.0.1:1"));
+        registry.add_schema(7, schema.clone());
+        let codec = AvroCodec::new(registry);
+        let event = Event {
+            project_id: 1,
+            message: "hello".to_string(),
+        };
+
+        let decoded: Event = codec.decode(&encode(7, &schema)).unwrap();
+        assert_eq!(decoded, event);
+
+        // The registry cannot be reached
+        let error = Codec::<Event>::decode(&codec, &encode(8, &schema)).unwrap_err();
+        assert!(error.is_retriable());
+
+        let error = Codec::<Event>::decode(&codec, &[0, 0, 0, 0, 7, 1]).unwrap_err();
+        assert!(matches!(error, CodecError::Decode(_)));
+        assert!(!error.is_retriable());
+
+        assert!(codec.encode(&event).is_err());
+        let codec = codec.with_writer_schema(7).unwrap();
+        assert_eq!(codec.encode(&event).unwrap(), encode(7, &schema));
+    }
+
+    #[test]
+    fn test_fetch_schema() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            // Only one request is served, the schema is cached afterwards.
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let length = stream.read(&mut request).unwrap();
+            assert!(String::from_utf8_lossy(&request[..length]).starts_with("GET /schemas/ids/3 "));
+            let body = serde_json::json!({ "schema": SCHEMA }).to_string();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+        });
+
+        let registry = SchemaRegistry::new(&url);
+        let schema = registry.get_schema(3).unwrap();
+        server.join().unwrap();
+        assert_eq!(*registry.get_schema(3).unwrap(), *schema);
+    }
+
+    #[test]
+    fn test_fetch_schema_backoff() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.read_exact(&mut [0; 16]).unwrap();
+            write!(
+                stream,
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n"
+            )
+            .unwrap();
+        });
+
+        let registry = SchemaRegistry::new(&url);
+        let error = registry.get_schema(3).unwrap_err().to_string();
+        assert!(error.contains("503"), "{}", error);
+        // The registry is gone now, the failure is returned until the
+        // backoff is over instead of fetching the schema again.
+        server.join().unwrap();
+        assert_eq!(registry.get_schema(3).unwrap_err().to_string(), error);
+    }
+}
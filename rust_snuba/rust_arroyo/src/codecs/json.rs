@@ -0,0 +1,35 @@
+use crate::codecs::{Codec, CodecError, PayloadFormat};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for JsonCodec {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        PayloadFormat::Json.encode(value)
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<T, CodecError> {
+        PayloadFormat::Json.decode(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JsonCodec;
+    use crate::codecs::{Codec, CodecError};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_json_codec() {
+        let value = HashMap::from([("project_id".to_string(), 1)]);
+        let payload = JsonCodec.encode(&value).unwrap();
+        assert_eq!(payload, b"{\"project_id\":1}");
+        let decoded: HashMap<String, u64> = JsonCodec.decode(&payload).unwrap();
+        assert_eq!(decoded, value);
+
+        let error = Codec::<HashMap<String, u64>>::decode(&JsonCodec, b"[").unwrap_err();
+        assert!(matches!(error, CodecError::Decode(_)));
+    }
+}
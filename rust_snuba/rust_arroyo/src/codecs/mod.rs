@@ -0,0 +1,135 @@
+use crate::backends::kafka::types::{Headers, KafkaPayload};
+use crate::codecs::json::JsonCodec;
+use crate::codecs::msgpack::MsgpackCodec;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use thiserror::Error;
+
+pub mod avro;
+pub mod json;
+pub mod msgpack;
+pub mod protobuf;
+
+#[derive(Error, Debug)]
+pub enum CodecError {
+    #[error("Failed to encode: {0}")]
+    Encode(String),
+    #[error("Failed to decode: {0}")]
+    Decode(String),
+    /// Decoding depends on a service that cannot be reached, for example
+    /// a schema registry. The same payload can be decoded later.
+    #[error("Unavailable: {0}")]
+    Unavailable(String),
+}
+
+impl CodecError {
+    pub fn is_retriable(&self) -> bool {
+        matches!(self, CodecError::Unavailable(_))
+    }
+}
+
+/// Converts values to and from the payloads of a topic.
+///
+/// Strategies and dead letter queue producers take a codec instead of
+/// assuming a format, so that the format can be picked for each topic.
+/// Message processors only decode, with a ``PayloadFormat``.
+pub trait Codec<T>: Send + Sync {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError>;
+
+    fn decode(&self, payload: &[u8]) -> Result<T, CodecError>;
+}
+
+/// The formats of the topics whose payloads can be decoded without a
+/// schema, picked with ``payload_format`` in the config of a topic.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadFormat {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+impl PayloadFormat {
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, CodecError> {
+        match self {
+            PayloadFormat::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            PayloadFormat::Msgpack => rmp_serde::to_vec(value).map_err(|e| e.to_string()),
+        }
+        .map_err(CodecError::Encode)
+    }
+
+    /// Unlike a ``Codec``, this does not require the value to be
+    /// serializable, as with the messages processors only read.
+    pub fn decode<T: DeserializeOwned>(self, payload: &[u8]) -> Result<T, CodecError> {
+        match self {
+            PayloadFormat::Json => serde_json::from_slice(payload).map_err(|e| e.to_string()),
+            PayloadFormat::Msgpack => rmp_serde::from_slice(payload).map_err(|e| e.to_string()),
+        }
+        .map_err(CodecError::Decode)
+    }
+
+    pub fn codec<T: Serialize + DeserializeOwned>(self) -> Arc<dyn Codec<T>> {
+        match self {
+            PayloadFormat::Json => Arc::new(JsonCodec),
+            PayloadFormat::Msgpack => Arc::new(MsgpackCodec),
+        }
+    }
+}
+
+/// A value decoded from the payload of a Kafka message, with the key and
+/// the headers of the message so that it can be produced again as it was,
+/// for example to a dead letter topic.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Decoded<T> {
+    pub key: Option<Arc<[u8]>>,
+    pub headers: Option<Headers>,
+    pub value: T,
+}
+
+impl<T> Decoded<T> {
+    /// Encodes the value with ``codec`` into the payload it was decoded from.
+    pub fn encode(&self, codec: &dyn Codec<T>) -> Result<KafkaPayload, CodecError> {
+        Ok(KafkaPayload {
+            key: self.key.clone(),
+            headers: self.headers.clone(),
+            payload: Some(codec.encode(&self.value)?.into()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PayloadFormat;
+    use crate::codecs::CodecError;
+    use serde::Deserialize;
+
+    // Only deserializable, like the messages of the processors.
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Outcome {
+        org_id: u64,
+    }
+
+    #[test]
+    fn test_payload_format() {
+        let value = serde_json::json!({"org_id": 1});
+        for format in [PayloadFormat::Json, PayloadFormat::Msgpack] {
+            let payload = format.encode(&value).unwrap();
+            let decoded: Outcome = format.decode(&payload).unwrap();
+            assert_eq!(decoded, Outcome { org_id: 1 });
+            let codec = format.codec::<serde_json::Value>();
+            assert_eq!(codec.decode(&payload).unwrap(), value);
+        }
+        assert_eq!(
+            PayloadFormat::Json.encode(&value).unwrap(),
+            b"{\"org_id\":1}"
+        );
+
+        let error = PayloadFormat::Msgpack.decode::<Outcome>(b"{}").unwrap_err();
+        assert!(matches!(error, CodecError::Decode(_)));
+        assert_eq!(
+            serde_json::from_str::<PayloadFormat>("\"msgpack\"").unwrap(),
+            PayloadFormat::Msgpack
+        );
+    }
+}
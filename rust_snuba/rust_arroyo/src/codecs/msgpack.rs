@@ -0,0 +1,45 @@
+use crate::codecs::{Codec, CodecError, PayloadFormat};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Still used by some of the older Snuba topics. Structs are encoded as
+/// arrays, like the Python ``msgpack`` package does for tuples.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MsgpackCodec;
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for MsgpackCodec {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        PayloadFormat::Msgpack.encode(value)
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<T, CodecError> {
+        PayloadFormat::Msgpack.decode(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MsgpackCodec;
+    use crate::codecs::{Codec, CodecError};
+    use serde::{Deserialize, Serialize};
+
+    // Replacements are encoded as ``[version, action_type, data]``.
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Replacement(u8, String, serde_json::Value);
+
+    #[test]
+    fn test_msgpack_codec() {
+        let replacement = Replacement(
+            2,
+            "end_delete_groups".to_string(),
+            serde_json::json!({"project_id": 1}),
+        );
+        let payload = MsgpackCodec.encode(&replacement).unwrap();
+        assert_eq!(payload[0], 0x93); // An array of 3 elements
+        let decoded: Replacement = MsgpackCodec.decode(&payload).unwrap();
+        assert_eq!(decoded, replacement);
+
+        let error = Codec::<Replacement>::decode(&MsgpackCodec, b"\xc1").unwrap_err();
+        assert!(matches!(error, CodecError::Decode(_)));
+    }
+}
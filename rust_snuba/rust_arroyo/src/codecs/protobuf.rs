@@ -0,0 +1,45 @@
+use crate::codecs::{Codec, CodecError};
+
+/// Encodes the message types generated by ``prost``.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProtobufCodec;
+
+impl<T: prost::Message + Default> Codec<T> for ProtobufCodec {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(value.encode_to_vec())
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<T, CodecError> {
+        T::decode(payload).map_err(|error| CodecError::Decode(error.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProtobufCodec;
+    use crate::codecs::{Codec, CodecError};
+
+    // What prost generates for ``message Span { string name = 1; uint64 duration_ms = 2; }``
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Span {
+        #[prost(string, tag = "1")]
+        name: String,
+        #[prost(uint64, tag = "2")]
+        duration_ms: u64,
+    }
+
+    #[test]
+    fn test_protobuf_codec() {
+        let span = Span {
+            name: "db.query".to_string(),
+            duration_ms: 12,
+        };
+        let payload = ProtobufCodec.encode(&span).unwrap();
+        let decoded: Span = ProtobufCodec.decode(&payload).unwrap();
+        assert_eq!(decoded, span);
+
+        // The length of the name is larger than the payload
+        let error = Codec::<Span>::decode(&ProtobufCodec, &[10, 20, b'd']).unwrap_err();
+        assert!(matches!(error, CodecError::Decode(_)));
+    }
+}
@@ -1,4 +1,5 @@
 pub mod backends;
+pub mod codecs;
 pub mod processing;
 pub mod types;
 pub mod utils;
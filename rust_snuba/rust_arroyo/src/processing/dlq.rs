@@ -0,0 +1,501 @@
+use crate::backends::kafka::types::KafkaPayload;
+use crate::backends::{Producer, ProducerError};
+use crate::codecs::{Codec, Decoded};
+use crate::processing::strategies::InvalidMessage;
+use crate::types::{BrokerMessage, Partition, Topic, TopicOrPartition};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+
+/// Writes invalid messages to a dead letter queue.
+pub trait DlqProducer<TPayload: Clone>: Send + Sync {
+    /// Produce the original message to the dead letter queue. This returns
+    /// once the message has been handed over to the underlying producer.
+    fn produce(&self, message: BrokerMessage<TPayload>) -> Result<(), ProducerError>;
+}
+
+/// Drops invalid messages. This is useful to skip invalid messages without
+/// having a dead letter topic to write them to.
+pub struct NoopDlqProducer;
+
+impl<TPayload: Clone> DlqProducer<TPayload> for NoopDlqProducer {
+    fn produce(&self, _message: BrokerMessage<TPayload>) -> Result<(), ProducerError> {
+        Ok(())
+    }
+}
+
+/// Produces invalid messages to a dead letter topic.
+pub struct KafkaDlqProducer<TPayload> {
+    producer: Arc<dyn Producer<TPayload>>,
+    destination: TopicOrPartition,
+}
+
+impl<TPayload> KafkaDlqProducer<TPayload> {
+    pub fn new(producer: Arc<dyn Producer<TPayload>>, topic: Topic) -> Self {
+        Self {
+            producer,
+            destination: TopicOrPartition::Topic(topic),
+        }
+    }
+}
+
+impl<TPayload: Clone + Send + Sync> DlqProducer<TPayload> for KafkaDlqProducer<TPayload> {
+    fn produce(&self, message: BrokerMessage<TPayload>) -> Result<(), ProducerError> {
+        self.producer.produce(&self.destination, &message.payload)
+    }
+}
+
+/// Produces invalid messages to a dead letter topic, encoding them with
+/// ``codec`` first. This is needed when the payloads the consumer returns
+/// are already decoded, the key and the headers of the original message
+/// are kept.
+pub struct EncodingDlqProducer<TPayload> {
+    producer: Arc<dyn Producer<KafkaPayload>>,
+    codec: Arc<dyn Codec<TPayload>>,
+    destination: TopicOrPartition,
+}
+
+impl<TPayload> EncodingDlqProducer<TPayload> {
+    pub fn new(
+        producer: Arc<dyn Producer<KafkaPayload>>,
+        codec: Arc<dyn Codec<TPayload>>,
+        topic: Topic,
+    ) -> Self {
+        Self {
+            producer,
+            codec,
+            destination: TopicOrPartition::Topic(topic),
+        }
+    }
+}
+
+impl<TPayload: Clone + Send + Sync> DlqProducer<Decoded<TPayload>>
+    for EncodingDlqProducer<TPayload>
+{
+    fn produce(&self, message: BrokerMessage<Decoded<TPayload>>) -> Result<(), ProducerError> {
+        let payload = message
+            .payload
+            .encode(self.codec.as_ref())
+            .map_err(|error| ProducerError::BrokerError(Box::new(error)))?;
+        self.producer.produce(&self.destination, &payload)
+    }
+}
+
+/// Defines an upper bound on the number of invalid messages the consumer
+/// accepts before it crashes. Either limit can be unset.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DlqLimit {
+    /// The ratio of invalid to total messages, computed per partition over
+    /// the last ``invalid_ratio_window`` messages.
+    pub max_invalid_ratio: Option<f64>,
+    /// The number of consecutive invalid messages.
+    pub max_consecutive_count: Option<u64>,
+    pub invalid_ratio_window: u64,
+    /// The ratio is only enforced once this many messages of the partition
+    /// were consumed, so that the first invalid message does not trip it.
+    pub min_sample_size: u64,
+}
+
+impl Default for DlqLimit {
+    fn default() -> Self {
+        DlqLimit {
+            max_invalid_ratio: None,
+            max_consecutive_count: None,
+            invalid_ratio_window: 1000,
+            min_sample_size: 100,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PartitionLimitState {
+    // The first offset accounted for.
+    first_offset: u64,
+    // The offset following the last message accounted for.
+    next_offset: u64,
+    // The offsets of the invalid messages within the window.
+    invalid: VecDeque<u64>,
+    consecutive_invalid: u64,
+}
+
+/// Keeps track of the invalid messages of each partition to enforce a
+/// ``DlqLimit``.
+///
+/// All the messages between two invalid messages are considered valid, so
+/// the state only needs to be told about the first message consumed on each
+/// partition and about the invalid ones.
+#[derive(Debug, Default)]
+pub struct DlqLimitState {
+    limit: DlqLimit,
+    partitions: BTreeMap<Partition, PartitionLimitState>,
+}
+
+impl DlqLimitState {
+    pub fn new(limit: DlqLimit) -> Self {
+        Self {
+            limit,
+            partitions: BTreeMap::new(),
+        }
+    }
+
+    /// Record a message consumed from the broker. Only the first message of
+    /// each partition matters, it is where counting valid messages starts.
+    pub fn record_consumed(&mut self, partition: &Partition, offset: u64) {
+        if !self.partitions.contains_key(partition) {
+            self.partitions.insert(
+                partition.clone(),
+                PartitionLimitState {
+                    first_offset: offset,
+                    next_offset: offset,
+                    ..Default::default()
+                },
+            );
+        }
+    }
+
+    /// Record an invalid message and return whether the limit still allows
+    /// it to be dead lettered.
+    pub fn record_invalid_message(&mut self, invalid: &InvalidMessage) -> bool {
+        let state = self
+            .partitions
+            .entry(invalid.partition.clone())
+            .or_insert_with(|| PartitionLimitState {
+                first_offset: invalid.offset,
+                next_offset: invalid.offset,
+                ..Default::default()
+            });
+
+        if invalid.offset > state.next_offset {
+            state.consecutive_invalid = 0;
+        }
+        state.consecutive_invalid += 1;
+        state.next_offset = invalid.offset + 1;
+
+        let window = self.limit.invalid_ratio_window.max(1);
+        let window_start = state.next_offset.saturating_sub(window);
+        state.invalid.retain(|offset| *offset >= window_start);
+        state.invalid.push_back(invalid.offset);
+
+        if let Some(max_invalid_ratio) = self.limit.max_invalid_ratio {
+            let consumed = (state.next_offset.saturating_sub(state.first_offset)).min(window);
+            let ratio = state.invalid.len() as f64 / consumed.max(1) as f64;
+            if consumed >= self.limit.min_sample_size && ratio > max_invalid_ratio {
+                return false;
+            }
+        }
+        if let Some(max_consecutive_count) = self.limit.max_consecutive_count {
+            if state.consecutive_invalid > max_consecutive_count {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Configures how the stream processor handles the ``InvalidMessage``
+/// errors raised by a strategy.
+pub struct DlqPolicy<TPayload: Clone> {
+    pub producer: Box<dyn DlqProducer<TPayload>>,
+    pub limit: DlqLimit,
+    /// The number of messages kept per partition so that the original
+    /// message can be found once it is declared invalid. ``None`` keeps
+    /// everything until the message is produced to the dead letter queue.
+    pub max_buffered_messages_per_partition: Option<usize>,
+}
+
+impl<TPayload: Clone> DlqPolicy<TPayload> {
+    pub fn new(producer: Box<dyn DlqProducer<TPayload>>, limit: DlqLimit) -> Self {
+        Self {
+            producer,
+            limit,
+            max_buffered_messages_per_partition: None,
+        }
+    }
+}
+
+/// Keeps the original messages returned by the consumer, keyed by partition
+/// and offset, so that they can be produced to the dead letter queue if a
+/// strategy raises ``InvalidMessage`` for them later on.
+///
+/// Strategies are free to transform or drop payloads, so the buffer is the
+/// only place the original bytes survive. Messages are retained until their
+/// offset is committed or their partition is revoked.
+pub struct BufferedMessages<TPayload: Clone> {
+    max_per_partition: Option<usize>,
+    buffered_messages: BTreeMap<Partition, VecDeque<BrokerMessage<TPayload>>>,
+}
+
+impl<TPayload: Clone> BufferedMessages<TPayload> {
+    pub fn new(max_per_partition: Option<usize>) -> Self {
+        Self {
+            max_per_partition,
+            buffered_messages: BTreeMap::new(),
+        }
+    }
+
+    /// Add a message to the buffer. If the partition is already holding
+    /// ``max_per_partition`` messages, the oldest one is dropped.
+    pub fn append(&mut self, message: BrokerMessage<TPayload>) {
+        let buffered = self
+            .buffered_messages
+            .entry(message.partition.clone())
+            .or_default();
+        if let Some(max) = self.max_per_partition {
+            if buffered.len() >= max {
+                log::warn!(
+                    "DLQ buffer exceeded, dropping message on partition {}",
+                    message.partition
+                );
+                buffered.pop_front();
+            }
+        }
+        buffered.push_back(message);
+    }
+
+    /// Return the message at the given offset, or ``None`` if it is not in
+    /// the buffer. Messages with a lower offset on the same partition are
+    /// dropped since they will never be asked for.
+    pub fn pop(&mut self, partition: &Partition, offset: u64) -> Option<BrokerMessage<TPayload>> {
+        let buffered = self.buffered_messages.get_mut(partition)?;
+        while let Some(message) = buffered.pop_front() {
+            if message.offset == offset {
+                return Some(message);
+            }
+            if message.offset > offset {
+                buffered.push_front(message);
+                break;
+            }
+        }
+        None
+    }
+
+    /// Drop all the messages of a partition.
+    pub fn remove(&mut self, partition: &Partition) {
+        self.buffered_messages.remove(partition);
+    }
+
+    /// The number of messages buffered across all partitions.
+    pub fn len(&self) -> usize {
+        self.buffered_messages.values().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BufferedMessages, DlqLimit, DlqLimitState, DlqProducer, EncodingDlqProducer};
+    use crate::backends::kafka::types::{Headers, KafkaPayload};
+    use crate::backends::local::broker::LocalBroker;
+    use crate::backends::local::LocalProducer;
+    use crate::backends::storages::memory::MemoryMessageStorage;
+    use crate::codecs::json::JsonCodec;
+    use crate::codecs::Decoded;
+    use crate::processing::strategies::InvalidMessage;
+    use crate::types::{BrokerMessage, Partition, Topic};
+    use crate::utils::clock::SystemClock;
+    use chrono::Utc;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_encoding_dlq_producer() {
+        let topic = Topic {
+            name: "dlq".to_string(),
+        };
+        let storage: MemoryMessageStorage<KafkaPayload> = Default::default();
+        let mut broker = LocalBroker::new(Box::new(storage), Box::new(SystemClock {}));
+        broker.create_topic(topic.clone(), 1).unwrap();
+        let broker = Arc::new(Mutex::new(broker));
+
+        let producer = EncodingDlqProducer::new(
+            Arc::new(LocalProducer::new(broker.clone())),
+            Arc::new(JsonCodec),
+            topic.clone(),
+        );
+        let partition = Partition {
+            topic: Topic {
+                name: "test".to_string(),
+            },
+            index: 0,
+        };
+        let mut headers = Headers::new();
+        headers.insert("received", b"1".to_vec());
+        let decoded = Decoded {
+            key: Some(b"key".to_vec().into()),
+            headers: Some(headers.clone()),
+            value: vec![1, 2],
+        };
+        producer
+            .produce(BrokerMessage::new(decoded, partition, 5, Utc::now()))
+            .unwrap();
+
+        let produced = broker
+            .lock()
+            .unwrap()
+            .consume(&Partition { topic, index: 0 }, 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(produced.payload.payload.as_deref(), Some(&b"[1,2]"[..]));
+        assert_eq!(produced.payload.key.as_deref(), Some(&b"key"[..]));
+        assert_eq!(produced.payload.headers, Some(headers));
+    }
+
+    #[test]
+    fn test_buffered_messages() {
+        let partition = Partition {
+            topic: Topic {
+                name: "test".to_string(),
+            },
+            index: 0,
+        };
+        let mut buffer = BufferedMessages::new(None);
+        for offset in 0..5 {
+            buffer.append(BrokerMessage::new(
+                offset,
+                partition.clone(),
+                offset,
+                Utc::now(),
+            ));
+        }
+
+        assert_eq!(buffer.pop(&partition, 2).unwrap().payload, 2);
+        // Older messages were dropped by the previous pop
+        assert!(buffer.pop(&partition, 1).is_none());
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.pop(&partition, 4).unwrap().payload, 4);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_remove_partition() {
+        let topic = Topic {
+            name: "test".to_string(),
+        };
+        let partitions: Vec<Partition> = (0..2)
+            .map(|index| Partition {
+                topic: topic.clone(),
+                index,
+            })
+            .collect();
+        let mut buffer = BufferedMessages::new(None);
+        for partition in &partitions {
+            buffer.append(BrokerMessage::new(0, partition.clone(), 0, Utc::now()));
+        }
+
+        buffer.remove(&partitions[0]);
+        assert_eq!(buffer.len(), 1);
+        assert!(buffer.pop(&partitions[0], 0).is_none());
+        assert!(buffer.pop(&partitions[1], 0).is_some());
+    }
+
+    #[test]
+    fn test_max_buffered_messages() {
+        let partition = Partition {
+            topic: Topic {
+                name: "test".to_string(),
+            },
+            index: 0,
+        };
+        let mut buffer = BufferedMessages::new(Some(2));
+        for offset in 0..3 {
+            buffer.append(BrokerMessage::new(
+                offset,
+                partition.clone(),
+                offset,
+                Utc::now(),
+            ));
+        }
+
+        assert!(buffer.pop(&partition, 0).is_none());
+        assert_eq!(buffer.pop(&partition, 1).unwrap().payload, 1);
+    }
+
+    #[test]
+    fn test_max_consecutive_count() {
+        let partition = Partition {
+            topic: Topic {
+                name: "test".to_string(),
+            },
+            index: 0,
+        };
+        let invalid = |offset| InvalidMessage {
+            partition: partition.clone(),
+            offset,
+        };
+        let mut state = DlqLimitState::new(DlqLimit {
+            max_consecutive_count: Some(2),
+            ..Default::default()
+        });
+        state.record_consumed(&partition, 0);
+
+        assert!(state.record_invalid_message(&invalid(0)));
+        assert!(state.record_invalid_message(&invalid(1)));
+        // A valid message resets the count
+        assert!(state.record_invalid_message(&invalid(3)));
+        assert!(state.record_invalid_message(&invalid(4)));
+        assert!(!state.record_invalid_message(&invalid(5)));
+    }
+
+    #[test]
+    fn test_max_invalid_ratio() {
+        let partition = Partition {
+            topic: Topic {
+                name: "test".to_string(),
+            },
+            index: 0,
+        };
+        let invalid = |offset| InvalidMessage {
+            partition: partition.clone(),
+            offset,
+        };
+        let mut state = DlqLimitState::new(DlqLimit {
+            max_invalid_ratio: Some(0.5),
+            min_sample_size: 0,
+            ..Default::default()
+        });
+        state.record_consumed(&partition, 10);
+        // Offsets consumed after the first one do not need to be recorded
+        state.record_consumed(&partition, 11);
+
+        // 1 invalid out of 4
+        assert!(state.record_invalid_message(&invalid(13)));
+        // 2 invalid out of 5
+        assert!(state.record_invalid_message(&invalid(14)));
+        // 3 invalid out of 6
+        assert!(state.record_invalid_message(&invalid(15)));
+        // 4 invalid out of 7
+        assert!(!state.record_invalid_message(&invalid(16)));
+    }
+
+    #[test]
+    fn test_invalid_ratio_window() {
+        let partition = Partition {
+            topic: Topic {
+                name: "test".to_string(),
+            },
+            index: 0,
+        };
+        let invalid = |offset| InvalidMessage {
+            partition: partition.clone(),
+            offset,
+        };
+        let mut state = DlqLimitState::new(DlqLimit {
+            max_invalid_ratio: Some(0.5),
+            invalid_ratio_window: 10,
+            min_sample_size: 4,
+            ..Default::default()
+        });
+        state.record_consumed(&partition, 0);
+
+        // Too few messages were consumed for the ratio to count.
+        assert!(state.record_invalid_message(&invalid(0)));
+        assert!(state.record_invalid_message(&invalid(1)));
+        // 3 invalid out of 4
+        assert!(!state.record_invalid_message(&invalid(3)));
+
+        // The invalid messages leave the window, 2 out of 10.
+        assert!(state.record_invalid_message(&invalid(12)));
+        assert!(state.record_invalid_message(&invalid(13)));
+    }
+}
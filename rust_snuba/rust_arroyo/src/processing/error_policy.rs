@@ -0,0 +1,163 @@
+use crate::backends::{ConsumerError, ProducerError};
+use crate::processing::strategies::retry::RetryPolicy;
+use crate::utils::metrics;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+/// How the processor handles the errors of the consumer and of the DLQ
+/// producer, see ``StreamProcessor::set_error_policy``.
+///
+/// Retriable errors, such as a broker that can not be reached, are retried
+/// with the backoff of ``RetryPolicy`` up to ``max_retries`` times in a row.
+/// Other errors, and errors that keep happening, stop the processor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ErrorPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for ErrorPolicy {
+    /// Retries for about a minute.
+    fn default() -> Self {
+        ErrorPolicy {
+            max_retries: 20,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl ErrorPolicy {
+    /// The delay before the given retry, starting at 1.
+    fn backoff(&self, retry: u32) -> Duration {
+        RetryPolicy {
+            max_attempts: self.max_retries.saturating_add(1),
+            initial_backoff: self.initial_backoff,
+            max_backoff: self.max_backoff,
+        }
+        .backoff(retry)
+    }
+}
+
+/// An error the processor may retry.
+pub(crate) trait ClassifiedError: fmt::Display {
+    fn is_retriable(&self) -> bool;
+    fn kind(&self) -> &'static str;
+}
+
+impl ClassifiedError for ConsumerError {
+    fn is_retriable(&self) -> bool {
+        ConsumerError::is_retriable(self)
+    }
+
+    fn kind(&self) -> &'static str {
+        ConsumerError::kind(self)
+    }
+}
+
+impl ClassifiedError for ProducerError {
+    fn is_retriable(&self) -> bool {
+        ProducerError::is_retriable(self)
+    }
+
+    fn kind(&self) -> &'static str {
+        ProducerError::kind(self)
+    }
+}
+
+/// Counts the errors in a row of every operation of the processor.
+#[derive(Default)]
+pub(crate) struct ErrorRetries {
+    policy: ErrorPolicy,
+    consecutive: HashMap<&'static str, u32>,
+}
+
+impl ErrorRetries {
+    pub fn new(policy: ErrorPolicy) -> Self {
+        ErrorRetries {
+            policy,
+            consecutive: HashMap::new(),
+        }
+    }
+
+    /// Returns how long to wait before retrying ``operation``, or ``None``
+    /// if the processor has to stop. Every error increments
+    /// ``arroyo.consumer.error.count``, tagged by operation, kind of error
+    /// and whether it is retried.
+    pub fn retry(
+        &mut self,
+        operation: &'static str,
+        error: &dyn ClassifiedError,
+    ) -> Option<Duration> {
+        let attempts = self.consecutive.entry(operation).or_default();
+        let retry = error.is_retriable() && *attempts < self.policy.max_retries;
+        metrics::increment(
+            "arroyo.consumer.error.count",
+            None,
+            Some(HashMap::from([
+                ("operation", operation),
+                ("kind", error.kind()),
+                ("action", if retry { "retry" } else { "crash" }),
+            ])),
+            None,
+        );
+        if !retry {
+            log::error!("{} error: {}", operation, error);
+            return None;
+        }
+
+        *attempts += 1;
+        let backoff = self.policy.backoff(*attempts);
+        log::warn!("{} error, retrying in {:?}: {}", operation, backoff, error);
+        Some(backoff)
+    }
+
+    /// Called when ``operation`` succeeds.
+    pub fn reset(&mut self, operation: &'static str) {
+        self.consecutive.remove(operation);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ErrorPolicy, ErrorRetries};
+    use crate::backends::{ConsumerError, ProducerError};
+    use std::time::Duration;
+
+    #[test]
+    fn test_error_retries() {
+        let mut retries = ErrorRetries::new(ErrorPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(250),
+        });
+        let transient = ConsumerError::BrokerTransportFailure {
+            source: "all brokers are down".into(),
+        };
+
+        // The backoffs double, reduced by up to half at random.
+        let mut retry = |operation| {
+            let backoff = retries.retry(operation, &transient)?;
+            Some(backoff.as_millis())
+        };
+        for max in [100, 200, 250] {
+            assert!(retry("poll").is_some_and(|backoff| backoff >= max / 2 && backoff <= max));
+        }
+        assert_eq!(retry("poll"), None);
+
+        // Operations are counted separately
+        assert!(retry("commit").is_some_and(|backoff| backoff <= 100));
+        retries.reset("poll");
+        assert!(retries
+            .retry("poll", &transient)
+            .is_some_and(|backoff| backoff <= Duration::from_millis(100)));
+
+        let fatal = ConsumerError::AuthFailure {
+            source: "SASL authentication failed".into(),
+        };
+        assert_eq!(retries.retry("poll", &fatal), None);
+        assert_eq!(retries.retry("dlq", &ProducerError::ProducerClosed), None);
+    }
+}
@@ -1,12 +1,38 @@
+pub mod dlq;
+pub mod error_policy;
+pub mod state;
 pub mod strategies;
+pub mod supervisor;
 
-use crate::backends::{AssignmentCallbacks, Consumer};
-use crate::types::{InnerMessage, Message, Partition, Topic};
-use std::collections::HashMap;
-use std::mem::replace;
+use crate::backends::{AssignmentCallbacks, Consumer, ConsumerError};
+use crate::types::{BrokerMessage, InnerMessage, Message, Partition, Position, Topic};
+use crate::utils::metrics;
+use crate::utils::timing::Deadline;
+use chrono::{DateTime, Utc};
+use dlq::{BufferedMessages, DlqLimitState, DlqPolicy};
+use error_policy::{ErrorPolicy, ErrorRetries};
+use signal_hook::consts::{SIGINT, SIGTERM, SIGUSR1};
+use signal_hook::flag;
+use signal_hook::iterator::{Handle, Signals};
+use signal_hook::SigId;
+use state::{PartitionState, ProcessorState, ProcessorStateHandle};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::thread::{self, sleep};
 use std::time::Duration;
-use strategies::{ProcessingStrategy, ProcessingStrategyFactory};
+use strategies::{
+    collect_invalid_on_join, InvalidMessage, MessageRejected, ProcessingStrategy,
+    ProcessingStrategyFactory, SubmitError,
+};
+
+// Bounds of the delay applied between attempts to submit a message that was
+// rejected by the strategy.
+const MIN_BACKPRESSURE_BACKOFF: Duration = Duration::from_millis(1);
+const MAX_BACKPRESSURE_BACKOFF: Duration = Duration::from_millis(100);
+const DEFAULT_JOIN_TIMEOUT: Duration = Duration::from_secs(5);
+const LAG_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+const STATE_PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Debug, Clone)]
 pub struct InvalidState;
@@ -22,44 +48,168 @@ pub enum RunError {
     InvalidState,
     PollError,
     PauseError,
+    SeekError,
+    InvalidMessage(InvalidMessage),
+    DlqProduceError,
+    DlqLimitExceeded,
+    OffsetGap,
+    CommitError,
+    /// The processor panicked, with the message of the panic.
+    Panic(String),
+}
+
+/// What the processor does when the offset of a message is higher than the
+/// offset that follows the previous message of its partition.
+///
+/// Compacted topics and topics written by transactional producers have gaps
+/// by design, ``Skip`` should be used for them.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OffsetGapPolicy {
+    /// Logs a warning and increments ``arroyo.consumer.offset_gap.count``.
+    #[default]
+    Log,
+    /// Stops the processor with ``RunError::OffsetGap``.
+    Crash,
+    /// Does not check offsets.
+    Skip,
 }
 
 struct Strategies<TPayload: Clone> {
     processing_factory: Box<dyn ProcessingStrategyFactory<TPayload>>,
     strategy: Option<Box<dyn ProcessingStrategy<TPayload>>>,
+    // Partitions revoked since the processor last checked, so that it can
+    // drop a carried over message it does not own anymore.
+    revoked_partitions: HashSet<Partition>,
+    // The whole assignment, which only changes incrementally with a
+    // cooperative assignor. Offsets are moved forward on every commit.
+    assigned_partitions: HashMap<Partition, u64>,
+    // Positions returned by a strategy that was replaced on assignment,
+    // which the processor commits on its next run.
+    pending_commit: HashMap<Partition, Position>,
+    // The messages reported invalid while a strategy was joined, with their
+    // copy taken out of the DLQ buffer, which the processor dead letters on
+    // its next run.
+    invalid_on_join: Vec<(InvalidMessage, Option<BrokerMessage<TPayload>>)>,
+}
+
+impl<TPayload: Clone> Strategies<TPayload> {
+    /// Closes the current strategy, if any, waits up to ``timeout`` for it to
+    /// complete and returns the positions it still had to commit.
+    fn close_strategy(
+        &mut self,
+        timeout: Option<Duration>,
+        buffered_messages: &Mutex<BufferedMessages<TPayload>>,
+    ) -> HashMap<Partition, Position> {
+        let mut strategy = match self.strategy.take() {
+            None => return HashMap::new(),
+            Some(strategy) => strategy,
+        };
+        strategy.close();
+        let span = tracing::info_span!("join");
+        let (request, mut invalid) =
+            span.in_scope(|| collect_invalid_on_join(|| strategy.join(timeout)));
+
+        // Popping a message drops those before it in its partition.
+        invalid.sort_by_key(|invalid| invalid.offset);
+        let mut buffered_messages = buffered_messages.lock().unwrap();
+        for invalid in invalid {
+            let message = buffered_messages.pop(&invalid.partition, invalid.offset);
+            self.invalid_on_join.push((invalid, message));
+        }
+        match request {
+            None => HashMap::new(),
+            Some(request) => request.positions,
+        }
+    }
+
+    /// Replaces the current strategy with one for the whole assignment, or
+    /// with nothing if there are no partitions left.
+    fn recreate_strategy(
+        &mut self,
+        buffered_messages: &Mutex<BufferedMessages<TPayload>>,
+    ) -> HashMap<Partition, Position> {
+        let positions = self.close_strategy(None, buffered_messages);
+        if !self.assigned_partitions.is_empty() {
+            self.strategy = Some(
+                self.processing_factory
+                    .create_with_partitions(self.assigned_partitions.clone()),
+            );
+        }
+        positions
+    }
 }
 
 struct Callbacks<TPayload: Clone> {
     strategies: Arc<Mutex<Strategies<TPayload>>>,
+    buffered_messages: Arc<Mutex<BufferedMessages<TPayload>>>,
 }
 
-impl<TPayload: 'static + Clone> AssignmentCallbacks for Callbacks<TPayload> {
+impl<TPayload: 'static + Clone + Send> AssignmentCallbacks for Callbacks<TPayload> {
     // TODO: Having the initialization of the strategy here
     // means that ProcessingStrategy and ProcessingStrategyFactory
     // have to be Send and Sync, which is really limiting and unnecessary.
     // Revisit this so that it is not the callback that perform the
     // initialization.  But we just provide a signal back to the
     // processor to do that.
-    fn on_assign(&mut self, _: HashMap<Partition, u64>) {
+    fn on_assign(&mut self, partitions: HashMap<Partition, u64>) {
+        metrics::increment(
+            "arroyo.consumer.partitions_assigned.count",
+            Some(partitions.len() as i64),
+            None,
+            None,
+        );
         let mut stg = self.strategies.lock().unwrap();
-        stg.strategy = Some(stg.processing_factory.create());
+        // Strategies hold per partition state, they are never carried over
+        // to a new assignment. With a cooperative assignor the previous
+        // strategy still had partitions, what it completed is committed by
+        // the processor.
+        stg.assigned_partitions.extend(partitions);
+        let positions = stg.recreate_strategy(&self.buffered_messages);
+        stg.pending_commit.extend(positions);
     }
-    fn on_revoke(&mut self, _: Vec<Partition>) {
+    fn on_revoke(&mut self, partitions: Vec<Partition>) -> HashMap<Partition, Position> {
+        metrics::increment(
+            "arroyo.consumer.partitions_revoked.count",
+            Some(partitions.len() as i64),
+            None,
+            None,
+        );
+        // The consumer commits whatever the strategy completed while
+        // shutting down before it lets go of the partitions. Revoking
+        // partitions the strategy does not own leaves it running.
         let mut stg = self.strategies.lock().unwrap();
-        match stg.strategy.as_mut() {
-            None => {}
-            Some(s) => {
-                s.close();
-                s.join(None);
-            }
+        let mut owned = false;
+        for partition in &partitions {
+            owned |= stg.assigned_partitions.remove(partition).is_some();
+        }
+        let positions = if owned {
+            stg.recreate_strategy(&self.buffered_messages)
+        } else {
+            HashMap::new()
+        };
+
+        // Whatever is still buffered for these partitions will be consumed
+        // again by whoever gets them next.
+        let mut buffered_messages = self.buffered_messages.lock().unwrap();
+        for partition in &partitions {
+            buffered_messages.remove(partition);
         }
-        stg.strategy = None;
+        drop(buffered_messages);
+
+        stg.revoked_partitions.extend(partitions);
+        positions
     }
 }
 
 impl<TPayload: Clone> Callbacks<TPayload> {
-    pub fn new(strategies: Arc<Mutex<Strategies<TPayload>>>) -> Self {
-        Self { strategies }
+    pub fn new(
+        strategies: Arc<Mutex<Strategies<TPayload>>>,
+        buffered_messages: Arc<Mutex<BufferedMessages<TPayload>>>,
+    ) -> Self {
+        Self {
+            strategies,
+            buffered_messages,
+        }
     }
 }
 
@@ -71,103 +221,903 @@ pub struct StreamProcessor<'a, TPayload: Clone> {
     consumer: Box<dyn Consumer<'a, TPayload> + 'a>,
     strategies: Arc<Mutex<Strategies<TPayload>>>,
     message: Option<Message<TPayload>>,
-    shutdown_requested: bool,
+    is_paused: bool,
+    backpressure_backoff: Option<Duration>,
+    dlq_policy: Option<DlqPolicy<TPayload>>,
+    // The messages returned by the consumer, kept around only when there
+    // is a DLQ policy so that invalid messages can be dead lettered.
+    buffered_messages: Arc<Mutex<BufferedMessages<TPayload>>>,
+    dlq_limit_state: DlqLimitState,
+    // Set by signal_shutdown and by the signal handlers installed by run.
+    shutdown_requested: Arc<AtomicBool>,
+    join_timeout: Option<Duration>,
+    start_position: Option<StartPosition>,
+    // Partitions that were already moved to the start position, they resume
+    // from their committed offsets when they are assigned again.
+    repositioned_partitions: HashSet<Partition>,
+    lag_report_deadline: Deadline,
+    commit_latency: CommitLatency,
+    offset_gap_policy: OffsetGapPolicy,
+    offset_gaps: OffsetGaps,
+    max_in_flight_messages: Option<InFlightLimit<'a>>,
+    in_flight: InFlightMessages,
+    // Whether the consumer is paused because of ``max_in_flight_messages``,
+    // independently of backpressure.
+    in_flight_paused: bool,
+    state: ProcessorStateHandle,
+    state_publish_deadline: Deadline,
+    error_retries: ErrorRetries,
+    on_commit: Option<CommitHook<'a>>,
+}
+
+/// Called with the positions the consumer committed, see
+/// ``StreamProcessor::set_on_commit``.
+pub type CommitHook<'a> = Box<dyn FnMut(&HashMap<Partition, Position>) + 'a>;
+
+/// Returns the current cap on the messages in flight, see
+/// ``StreamProcessor::set_max_in_flight_messages_with``.
+pub type InFlightLimit<'a> = Box<dyn Fn() -> Option<u64> + 'a>;
+
+/// Counts the messages submitted to the strategy whose offsets were not
+/// committed yet, from the difference between the positions of the last
+/// submitted and the last committed message of each partition.
+#[derive(Default)]
+struct InFlightMessages {
+    // The committed and the submitted positions.
+    positions: HashMap<Partition, (u64, u64)>,
+}
+
+impl InFlightMessages {
+    fn submitted(&mut self, partition: &Partition, offset: u64) {
+        let position = self
+            .positions
+            .entry(partition.clone())
+            .or_insert((offset, offset));
+        position.1 = position.1.max(offset + 1);
+    }
+
+    fn committed(&mut self, positions: &HashMap<Partition, Position>) {
+        for (partition, committed) in positions {
+            if let Some(position) = self.positions.get_mut(partition) {
+                position.0 = position.0.max(committed.offset);
+            }
+        }
+    }
+
+    fn forget<'p>(&mut self, partitions: impl IntoIterator<Item = &'p Partition>) {
+        for partition in partitions {
+            self.positions.remove(partition);
+        }
+    }
+
+    fn count(&self) -> u64 {
+        self.positions
+            .values()
+            .map(|(committed, submitted)| submitted.saturating_sub(*committed))
+            .sum()
+    }
+
+    fn count_partition(&self, partition: &Partition) -> u64 {
+        self.positions
+            .get(partition)
+            .map_or(0, |(committed, submitted)| {
+                submitted.saturating_sub(*committed)
+            })
+    }
+}
+
+/// Tracks the offset expected next on each partition. The first message
+/// consumed after an assignment or a seek only sets the expected offset.
+#[derive(Default)]
+struct OffsetGaps {
+    next_offsets: HashMap<Partition, u64>,
+}
+
+impl OffsetGaps {
+    /// Returns the number of offsets missing before this message, if any.
+    /// Going back, which happens when a message is consumed again, is not
+    /// a gap.
+    fn observe(&mut self, message: &BrokerMessage<impl Clone>) -> Option<u64> {
+        let expected = self
+            .next_offsets
+            .insert(message.partition.clone(), message.offset + 1)?;
+        (message.offset > expected).then(|| message.offset - expected)
+    }
+
+    fn forget<'p>(&mut self, partitions: impl IntoIterator<Item = &'p Partition>) {
+        for partition in partitions {
+            self.next_offsets.remove(partition);
+        }
+    }
+}
+
+/// Tracks the time between the Kafka timestamp of a message and the commit
+/// of its offset. A single message per partition is sampled at a time, the
+/// next one is sampled once its offset was committed.
+#[derive(Default)]
+struct CommitLatency {
+    samples: HashMap<Partition, (u64, DateTime<Utc>)>,
+}
+
+impl CommitLatency {
+    fn sample(&mut self, message: &BrokerMessage<impl Clone>) {
+        self.samples
+            .entry(message.partition.clone())
+            .or_insert((message.offset, message.timestamp));
+    }
+
+    fn forget(&mut self, partitions: &HashSet<Partition>) {
+        self.samples
+            .retain(|partition, _| !partitions.contains(partition));
+    }
+
+    /// Returns the latency of the sampled messages committed by
+    /// ``positions``.
+    fn committed(
+        &mut self,
+        positions: &HashMap<Partition, Position>,
+        now: DateTime<Utc>,
+    ) -> Vec<(Partition, Duration)> {
+        let mut latencies = Vec::new();
+        for (partition, position) in positions {
+            if let Some((offset, timestamp)) = self.samples.get(partition) {
+                if *offset < position.offset {
+                    let latency = (now - *timestamp).to_std().unwrap_or(Duration::ZERO);
+                    latencies.push((partition.clone(), latency));
+                    self.samples.remove(partition);
+                }
+            }
+        }
+        latencies
+    }
+
+    fn record(&mut self, positions: &HashMap<Partition, Position>) {
+        for (partition, latency) in self.committed(positions, Utc::now()) {
+            let index = partition.index.to_string();
+            metrics::time(
+                "arroyo.consumer.commit_latency",
+                latency.as_millis() as u64,
+                Some(HashMap::from([
+                    ("topic", partition.topic.name.as_str()),
+                    ("partition", index.as_str()),
+                ])),
+                None,
+            );
+        }
+    }
+}
+
+// The signal handlers installed by ``StreamProcessor::run``, removed when it
+// returns or panics.
+struct SignalHandlers {
+    ids: Vec<SigId>,
+    state_dump: Option<Handle>,
+}
+
+impl Drop for SignalHandlers {
+    fn drop(&mut self) {
+        for id in self.ids.drain(..) {
+            signal_hook::low_level::unregister(id);
+        }
+        if let Some(state_dump) = &self.state_dump {
+            state_dump.close();
+        }
+    }
+}
+
+/// Logs ``state`` as JSON.
+fn dump_state(state: &ProcessorState) {
+    match serde_json::to_string_pretty(state) {
+        Ok(state) => log::info!("State of the stream processor: {}", state),
+        Err(error) => log::warn!("Failed to dump the state: {}", error),
+    }
+}
+
+// Where partitions start the first time they are assigned, instead of their
+// committed offsets.
+enum StartPosition {
+    Timestamp(DateTime<Utc>),
+    Offsets(HashMap<Partition, u64>),
+}
+
+/// Commits ``positions`` and returns those the consumer committed. If
+/// staging or committing them fails and can be retried, they are put back
+/// into ``pending_commit`` to be committed by the next run.
+fn commit_positions<TPayload: Clone>(
+    consumer: &mut dyn Consumer<'_, TPayload>,
+    error_retries: &mut ErrorRetries,
+    pending_commit: &mut HashMap<Partition, Position>,
+    positions: HashMap<Partition, Position>,
+) -> Result<HashMap<Partition, Position>, RunError> {
+    let committed = consumer
+        .stage_offsets(positions.clone())
+        .and_then(|()| consumer.commit_offsets());
+    match committed {
+        Ok(committed) => {
+            error_retries.reset("commit");
+            metrics::increment("arroyo.consumer.commit.count", None, None, None);
+            Ok(committed)
+        }
+        Err(error) => {
+            let backoff = error_retries
+                .retry("commit", &error)
+                .ok_or(RunError::CommitError)?;
+            for (partition, position) in positions {
+                let pending = pending_commit.entry(partition).or_insert(position);
+                *pending = pending.max(position);
+            }
+            sleep(backoff);
+            Ok(HashMap::new())
+        }
+    }
+}
+
+// The span of a submit call carries the position of the message.
+fn submit_span<T: Clone>(message: &Message<T>) -> tracing::Span {
+    match &message.inner_message {
+        InnerMessage::BrokerMessage(message) => tracing::info_span!(
+            "submit",
+            partition = %message.partition,
+            offset = message.offset
+        ),
+        InnerMessage::AnyMessage(_) => tracing::info_span!("submit"),
+    }
+}
+
+/// Parses the offsets accepted by ``StreamProcessor::set_start_offsets``
+/// from a JSON object mapping topic names to objects mapping partition
+/// indexes to offsets, e.g. ``{"events": {"0": 100, "1": 250}}``.
+pub fn parse_offsets(json: &str) -> Result<HashMap<Partition, u64>, serde_json::Error> {
+    let topics: HashMap<String, HashMap<u16, u64>> = serde_json::from_str(json)?;
+    let mut offsets = HashMap::new();
+    for (topic, partitions) in topics {
+        for (index, offset) in partitions {
+            offsets.insert(
+                Partition {
+                    topic: Topic {
+                        name: topic.clone(),
+                    },
+                    index,
+                },
+                offset,
+            );
+        }
+    }
+    Ok(offsets)
 }
 
-impl<'a, TPayload: 'static + Clone> StreamProcessor<'a, TPayload> {
+impl<'a, TPayload: 'static + Clone + Send> StreamProcessor<'a, TPayload> {
     pub fn new(
         consumer: Box<dyn Consumer<'a, TPayload> + 'a>,
         processing_factory: Box<dyn ProcessingStrategyFactory<TPayload>>,
     ) -> Self {
+        Self::build(consumer, processing_factory, None)
+    }
+
+    /// Builds a processor that produces the messages the strategy raises
+    /// ``InvalidMessage`` for to a dead letter queue instead of crashing.
+    pub fn new_with_dlq_policy(
+        consumer: Box<dyn Consumer<'a, TPayload> + 'a>,
+        processing_factory: Box<dyn ProcessingStrategyFactory<TPayload>>,
+        dlq_policy: DlqPolicy<TPayload>,
+    ) -> Self {
+        Self::build(consumer, processing_factory, Some(dlq_policy))
+    }
+
+    fn build(
+        consumer: Box<dyn Consumer<'a, TPayload> + 'a>,
+        processing_factory: Box<dyn ProcessingStrategyFactory<TPayload>>,
+        dlq_policy: Option<DlqPolicy<TPayload>>,
+    ) -> Self {
+        let max_buffered_messages = dlq_policy
+            .as_ref()
+            .and_then(|policy| policy.max_buffered_messages_per_partition);
+        let dlq_limit = dlq_policy
+            .as_ref()
+            .map(|policy| policy.limit)
+            .unwrap_or_default();
         let strategies = Arc::new(Mutex::new(Strategies {
             processing_factory,
             strategy: None,
+            revoked_partitions: HashSet::new(),
+            assigned_partitions: HashMap::new(),
+            pending_commit: HashMap::new(),
+            invalid_on_join: Vec::new(),
         }));
+        let state = ProcessorStateHandle::default();
+        state.start();
 
         Self {
             consumer,
             strategies,
             message: None,
-            shutdown_requested: false,
+            is_paused: false,
+            backpressure_backoff: None,
+            dlq_policy,
+            buffered_messages: Arc::new(Mutex::new(BufferedMessages::new(max_buffered_messages))),
+            dlq_limit_state: DlqLimitState::new(dlq_limit),
+            shutdown_requested: Arc::new(AtomicBool::new(false)),
+            join_timeout: Some(DEFAULT_JOIN_TIMEOUT),
+            start_position: None,
+            repositioned_partitions: HashSet::new(),
+            lag_report_deadline: Deadline::new(LAG_REPORT_INTERVAL),
+            commit_latency: CommitLatency::default(),
+            offset_gap_policy: OffsetGapPolicy::default(),
+            offset_gaps: OffsetGaps::default(),
+            max_in_flight_messages: None,
+            in_flight: InFlightMessages::default(),
+            in_flight_paused: false,
+            state,
+            state_publish_deadline: Deadline::new(STATE_PUBLISH_INTERVAL),
+            error_retries: ErrorRetries::default(),
+            on_commit: None,
+        }
+    }
+
+    /// Returns the handle the processor publishes its state to, about every
+    /// second while it runs.
+    pub fn state(&self) -> ProcessorStateHandle {
+        self.state.clone()
+    }
+
+    pub(crate) fn set_state_handle(&mut self, state: ProcessorStateHandle) {
+        state.start();
+        self.state = state;
+    }
+
+    fn snapshot(&self) -> ProcessorState {
+        let positions = self.consumer.tell().unwrap_or_default();
+        let strategies = self.strategies.lock().unwrap();
+        let mut partitions: Vec<_> = strategies
+            .assigned_partitions
+            .iter()
+            .map(|(partition, committed)| PartitionState {
+                topic: partition.topic.name.clone(),
+                index: partition.index,
+                consumed: positions.get(partition).copied(),
+                committed: *committed,
+                staged: strategies
+                    .pending_commit
+                    .get(partition)
+                    .map(|position| position.offset),
+                in_flight_messages: self.in_flight.count_partition(partition),
+            })
+            .collect();
+        let strategy = strategies
+            .strategy
+            .as_ref()
+            .map(|strategy| strategy.describe());
+        drop(strategies);
+        partitions.sort_by(|a, b| (&a.topic, a.index).cmp(&(&b.topic, b.index)));
+        ProcessorState {
+            paused: self.is_paused || self.in_flight_paused,
+            message_carried_over: self.message.is_some(),
+            in_flight_messages: self.in_flight.count(),
+            strategy,
+            partitions,
+            ..Default::default()
+        }
+    }
+
+    fn publish_state(&self) {
+        self.state.set(self.snapshot());
+    }
+
+    /// Logs the state last published by the processor on every SIGUSR1,
+    /// from a thread of its own so that a processor that is stuck can still
+    /// be inspected. The thread exits once the returned handle is closed.
+    fn spawn_state_dump(&self) -> Option<Handle> {
+        let mut signals = match Signals::new([SIGUSR1]) {
+            Ok(signals) => signals,
+            Err(error) => {
+                log::warn!("Failed to register handler for SIGUSR1: {}", error);
+                return None;
+            }
+        };
+        let handle = signals.handle();
+        let state = self.state.clone();
+        let spawned = thread::Builder::new()
+            .name("state-dump".to_string())
+            .spawn(move || {
+                for _ in signals.forever() {
+                    dump_state(&state.get());
+                }
+            });
+        match spawned {
+            Ok(_) => Some(handle),
+            Err(error) => {
+                log::warn!("Failed to start the state dump thread: {}", error);
+                handle.close();
+                None
+            }
         }
     }
 
     pub fn subscribe(&mut self, topic: Topic) {
-        let callbacks: Box<dyn AssignmentCallbacks> =
-            Box::new(Callbacks::new(self.strategies.clone()));
-        self.consumer.subscribe(&[topic], callbacks).unwrap();
+        self.subscribe_to_topics(&[topic]);
+    }
+
+    /// Subscribes to several topics at once. Messages from all of them go
+    /// through the same strategy, which can tell them apart with
+    /// ``Message::topic``.
+    ///
+    /// Topics whose name starts with ``^`` are regular expressions, see
+    /// ``subscribe_to_pattern``.
+    pub fn subscribe_to_topics(&mut self, topics: &[Topic]) {
+        let callbacks: Box<dyn AssignmentCallbacks> = Box::new(Callbacks::new(
+            self.strategies.clone(),
+            self.buffered_messages.clone(),
+        ));
+        self.consumer.subscribe(topics, callbacks).unwrap();
+    }
+
+    /// Subscribes to all the topics matching a regular expression. The
+    /// consumer follows topics as they are created or deleted, each change
+    /// triggers a rebalance and the strategy is recreated for the new
+    /// assignment.
+    pub fn subscribe_to_pattern(&mut self, pattern: &str) {
+        let name = if pattern.starts_with('^') {
+            pattern.to_string()
+        } else {
+            format!("^{}", pattern)
+        };
+        self.subscribe_to_topics(&[Topic { name }]);
+    }
+
+    /// Stores a message returned by the consumer until it is submitted.
+    fn hold_message(&mut self, message: BrokerMessage<TPayload>) -> Result<(), RunError> {
+        self.check_offset_gap(&message)?;
+        self.commit_latency.sample(&message);
+        if self.dlq_policy.is_some() {
+            self.dlq_limit_state
+                .record_consumed(&message.partition, message.offset);
+            self.buffered_messages
+                .lock()
+                .unwrap()
+                .append(message.clone());
+        }
+        self.message = Some(Message {
+            inner_message: InnerMessage::BrokerMessage(message),
+        });
+        Ok(())
+    }
+
+    fn check_offset_gap(&mut self, message: &BrokerMessage<TPayload>) -> Result<(), RunError> {
+        if self.offset_gap_policy == OffsetGapPolicy::Skip {
+            return Ok(());
+        }
+        let Some(missing) = self.offset_gaps.observe(message) else {
+            return Ok(());
+        };
+        log::warn!(
+            "{} offsets missing on {} before offset {}",
+            missing,
+            message.partition,
+            message.offset
+        );
+        let index = message.partition.index.to_string();
+        metrics::increment(
+            "arroyo.consumer.offset_gap.count",
+            None,
+            Some(HashMap::from([
+                ("topic", message.partition.topic.name.as_str()),
+                ("partition", index.as_str()),
+            ])),
+            None,
+        );
+        match self.offset_gap_policy {
+            OffsetGapPolicy::Crash => Err(RunError::OffsetGap),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reconciles the carried over message and the paused state with the
+    /// assignment changes that happened since the last call.
+    ///
+    /// The carried over message is dropped if its partition was revoked, it
+    /// will be consumed again from the committed offsets by whoever owns its
+    /// partition. If a rebalance resumed the partition of a message consumed
+    /// from the broker, the consumer is moved back to that message so that
+    /// it is consumed again, and the partitions it was moved back on are
+    /// returned. Partitions that were newly assigned while the message is
+    /// held are paused as well. The consumer is resumed once there is
+    /// nothing carried over.
+    fn reconcile_assignment(&mut self) -> Result<HashSet<Partition>, RunError> {
+        let revoked = std::mem::take(&mut self.strategies.lock().unwrap().revoked_partitions);
+        self.commit_latency.forget(&revoked);
+        self.offset_gaps.forget(&revoked);
+        self.in_flight.forget(&revoked);
+        if self
+            .message
+            .as_ref()
+            .is_some_and(|message| message.committable().keys().any(|p| revoked.contains(p)))
+        {
+            log::warn!("Partition of the carried over message was revoked, dropping it");
+            self.message = None;
+        }
+
+        let mut sought = HashSet::new();
+        if !self.is_paused {
+            return Ok(sought);
+        }
+        let paused = self.consumer.paused().map_err(|_| RunError::PauseError)?;
+        let assigned: HashSet<Partition> = self.consumer.tell().unwrap().keys().cloned().collect();
+        if let Some(message) = self.message.as_ref() {
+            let resumed = !message.committable().keys().all(|p| paused.contains(p));
+            match &message.inner_message {
+                InnerMessage::BrokerMessage(broker_message) if resumed => {
+                    log::warn!(
+                        "Partition of the carried over message was resumed, consuming it again"
+                    );
+                    let partition = broker_message.partition.clone();
+                    self.consumer
+                        .seek(HashMap::from([(partition.clone(), broker_message.offset)]))
+                        .map_err(|_| RunError::SeekError)?;
+                    self.offset_gaps.forget([&partition]);
+                    sought.insert(partition);
+                    self.message = None;
+                }
+                // A message built out of several ones cannot be consumed
+                // again, it is held until it is accepted.
+                _ => {
+                    let unpaused: HashSet<Partition> =
+                        assigned.difference(&paused).cloned().collect();
+                    if !unpaused.is_empty() {
+                        self.consumer
+                            .pause(unpaused)
+                            .map_err(|_| RunError::PauseError)?;
+                    }
+                    return Ok(sought);
+                }
+            }
+        }
+
+        self.consumer
+            .resume(paused.intersection(&assigned).cloned().collect())
+            .map_err(|_| RunError::PauseError)?;
+        self.is_paused = false;
+        self.backpressure_backoff = None;
+        Ok(sought)
+    }
+
+    /// Moves the partitions assigned for the first time to the start
+    /// position, if there is one. The strategy is recreated with the new
+    /// offsets, and a message consumed before the seek is dropped since it
+    /// will be consumed again.
+    fn reposition_assigned_partitions(&mut self) -> Result<(), RunError> {
+        if self.start_position.is_none() {
+            return Ok(());
+        }
+        let mut stg = self.strategies.lock().unwrap();
+        let partitions: HashSet<Partition> = stg
+            .assigned_partitions
+            .keys()
+            .filter(|partition| !self.repositioned_partitions.contains(partition))
+            .cloned()
+            .collect();
+        if partitions.is_empty() {
+            return Ok(());
+        }
+
+        let offsets = match self.start_position.as_ref().unwrap() {
+            StartPosition::Timestamp(timestamp) => self
+                .consumer
+                .seek_to_timestamp(partitions.clone(), *timestamp)
+                .map_err(|e| {
+                    log::error!("Failed to seek to {}: {}", timestamp, e);
+                    RunError::SeekError
+                })?,
+            StartPosition::Offsets(start_offsets) => {
+                let offsets: HashMap<Partition, u64> = start_offsets
+                    .iter()
+                    .filter(|(partition, _)| partitions.contains(partition))
+                    .map(|(partition, offset)| (partition.clone(), *offset))
+                    .collect();
+                self.consumer.seek(offsets.clone()).map_err(|e| {
+                    log::error!("Failed to seek: {}", e);
+                    RunError::SeekError
+                })?;
+                offsets
+            }
+        };
+        self.repositioned_partitions.extend(partitions);
+        if offsets.is_empty() {
+            return Ok(());
+        }
+        log::info!("Moved partitions to their start position: {:?}", offsets);
+        self.offset_gaps.forget(offsets.keys());
+        self.in_flight.forget(offsets.keys());
+
+        if self.message.as_ref().is_some_and(|message| {
+            message
+                .committable()
+                .keys()
+                .any(|p| offsets.contains_key(p))
+        }) {
+            self.message = None;
+        }
+        stg.assigned_partitions.extend(offsets);
+        let pending = stg.recreate_strategy(&self.buffered_messages);
+        stg.pending_commit.extend(pending);
+        Ok(())
+    }
+
+    /// Pauses the consumer while ``max_in_flight_messages`` are waiting to
+    /// be committed, and resumes it once commits catch up unless it is
+    /// paused for backpressure. Partitions assigned in the meantime are
+    /// paused as well.
+    fn limit_in_flight_messages(&mut self) -> Result<(), RunError> {
+        let limited = match self
+            .max_in_flight_messages
+            .as_ref()
+            .and_then(|limit| limit())
+        {
+            Some(max_in_flight_messages) => self.in_flight.count() >= max_in_flight_messages,
+            None => false,
+        };
+        if !limited && !self.in_flight_paused {
+            return Ok(());
+        }
+        let assigned: HashSet<Partition> = self.consumer.tell().unwrap().keys().cloned().collect();
+        if limited {
+            let paused = self.consumer.paused().map_err(|_| RunError::PauseError)?;
+            let unpaused: HashSet<Partition> = assigned.difference(&paused).cloned().collect();
+            if !unpaused.is_empty() {
+                self.consumer
+                    .pause(unpaused)
+                    .map_err(|_| RunError::PauseError)?;
+            }
+            if !self.in_flight_paused {
+                metrics::increment("arroyo.consumer.in_flight_limit.pause", None, None, None);
+                self.in_flight_paused = true;
+            }
+        } else if self.in_flight_paused {
+            if !self.is_paused {
+                self.consumer
+                    .resume(assigned)
+                    .map_err(|_| RunError::PauseError)?;
+            }
+            self.in_flight_paused = false;
+        }
+        Ok(())
+    }
+
+    // Reports what the consumer committed without returning it from
+    // ``commit_offsets``, see ``Consumer::take_committed``.
+    fn report_committed(&mut self) {
+        let committed = self.consumer.take_committed();
+        self.record_committed(&committed);
+    }
+
+    // Accounts for the positions the consumer committed: the commit latency,
+    // the messages in flight and ``on_commit``.
+    fn record_committed(&mut self, committed: &HashMap<Partition, Position>) {
+        if committed.is_empty() {
+            return;
+        }
+        self.commit_latency.record(committed);
+        self.in_flight.committed(committed);
+        if let Some(on_commit) = self.on_commit.as_mut() {
+            on_commit(committed);
+        }
     }
 
     pub fn run_once(&mut self) -> Result<(), RunError> {
+        self.reconcile_assignment()?;
+        self.limit_in_flight_messages()?;
+
         let message_carried_over = self.message.is_some();
 
         if message_carried_over {
             // If a message was carried over from the previous run, the consumer
-            // should be paused and not returning any messages on ``poll``.
-            let res = self.consumer.poll(Some(Duration::ZERO)).unwrap();
-            match res {
-                None => {}
-                Some(_) => return Err(RunError::InvalidState),
+            // should be paused and not returning any messages on ``poll``. We
+            // still poll a paused consumer so that rebalance callbacks get
+            // triggered. If pausing failed we skip polling entirely so we do
+            // not pull a message we have no room for.
+            if self.is_paused {
+                let res = tracing::info_span!("poll")
+                    .in_scope(|| self.consumer.poll(Some(Duration::ZERO)));
+                if res.is_ok() {
+                    self.error_retries.reset("poll");
+                }
+                match res {
+                    Ok(None) | Err(ConsumerError::EndOfPartition) => {
+                        self.reconcile_assignment()?;
+                    }
+                    Ok(Some(inner)) => {
+                        // Only a rebalance can make a paused consumer return
+                        // a message, which is fine as long as it took away
+                        // the message we were holding. If the consumer was
+                        // moved back on its partition the message is
+                        // consumed again as well.
+                        let sought = self.reconcile_assignment()?;
+                        if self.message.is_some() {
+                            return Err(RunError::InvalidState);
+                        }
+                        if !sought.contains(&inner.partition) {
+                            self.hold_message(inner)?;
+                        }
+                    }
+                    Err(e) => {
+                        let backoff = self
+                            .error_retries
+                            .retry("poll", &e)
+                            .ok_or(RunError::PollError)?;
+                        sleep(backoff);
+                    }
+                }
             }
         } else {
             // Otherwise, we need to try fetch a new message from the consumer,
             // even if there is no active assignment and/or processing strategy.
-            let msg = self.consumer.poll(Some(Duration::ZERO));
-            //TODO: Support errors properly
+            let msg =
+                tracing::info_span!("poll").in_scope(|| self.consumer.poll(Some(Duration::ZERO)));
+            if msg.is_ok() {
+                self.error_retries.reset("poll");
+            }
             match msg {
-                Ok(None) => {
+                // Reaching the end of a partition is only an error if
+                // ``enable.partition.eof`` is set, there is no message.
+                Ok(None) | Err(ConsumerError::EndOfPartition) => {
                     self.message = None;
-                },
-                Ok(Some(inner)) => {
-                    self.message = Some(Message{inner_message: InnerMessage::BrokerMessage(inner)});
-                },
+                }
+                Ok(Some(inner)) => self.hold_message(inner)?,
                 Err(e) => {
-                    log::error!("poll error: {}", e);
-                    return Err(RunError::PollError)
-                },
+                    let backoff = self
+                        .error_retries
+                        .retry("poll", &e)
+                        .ok_or(RunError::PollError)?;
+                    sleep(backoff);
+                }
             }
         }
 
-        let mut trait_callbacks = self.strategies.lock().unwrap();
-        match trait_callbacks.strategy.as_mut() {
+        self.reposition_assigned_partitions()?;
+        self.dead_letter_invalid_on_join()?;
+        self.report_committed();
+
+        if self.lag_report_deadline.has_elapsed() {
+            self.report_lag();
+            self.lag_report_deadline = Deadline::new(LAG_REPORT_INTERVAL);
+        }
+        if self.state_publish_deadline.has_elapsed() {
+            self.publish_state();
+            self.state_publish_deadline = Deadline::new(STATE_PUBLISH_INTERVAL);
+        }
+
+        let strategies = self.strategies.clone();
+        let mut trait_callbacks = strategies.lock().unwrap();
+        if !trait_callbacks.pending_commit.is_empty() {
+            let positions = std::mem::take(&mut trait_callbacks.pending_commit);
+            let _span = tracing::info_span!("commit", partitions = positions.len()).entered();
+            let committed = commit_positions(
+                &mut *self.consumer,
+                &mut self.error_retries,
+                &mut trait_callbacks.pending_commit,
+                positions,
+            )?;
+            self.record_committed(&committed);
+        }
+
+        let stg = &mut *trait_callbacks;
+        match stg.strategy.as_mut() {
             None => match self.message.as_ref() {
                 None => {}
                 Some(_) => return Err(RunError::InvalidState),
             },
             Some(strategy) => {
-                let commit_request = strategy.poll();
+                let commit_request =
+                    tracing::info_span!("strategy_poll").in_scope(|| strategy.poll());
+                self.state.record_poll();
                 match commit_request {
-                    None => {}
-                    Some(request) => {
-                        self.consumer.stage_offsets(request.positions).unwrap();
-                        self.consumer.commit_offsets().unwrap();
+                    Ok(None) => {}
+                    Ok(Some(request)) => {
+                        let positions = request.positions;
+                        if self.dlq_policy.is_some() {
+                            // Committed messages can no longer be dead lettered.
+                            let mut buffered_messages = self.buffered_messages.lock().unwrap();
+                            for (partition, position) in &positions {
+                                if position.offset > 0 {
+                                    buffered_messages.pop(partition, position.offset - 1);
+                                }
+                            }
+                            metrics::gauge(
+                                "arroyo.consumer.dlq_buffer.len",
+                                buffered_messages.len() as u64,
+                                None,
+                                None,
+                            );
+                        }
+                        for (partition, position) in &positions {
+                            if let Some(assigned) = stg.assigned_partitions.get_mut(partition) {
+                                *assigned = position.offset;
+                            }
+                        }
+                        let _span =
+                            tracing::info_span!("commit", partitions = positions.len()).entered();
+                        let committed = commit_positions(
+                            &mut *self.consumer,
+                            &mut self.error_retries,
+                            &mut stg.pending_commit,
+                            positions,
+                        )?;
+                        self.record_committed(&committed);
+                    }
+                    Err(invalid) => {
+                        // The message, if any, is submitted on the next
+                        // call.
+                        drop(trait_callbacks);
+                        return self.handle_invalid_message(invalid);
                     }
                 };
 
-                let msg = replace(&mut self.message, None);
+                let msg = self.message.take();
                 if let Some(msg_s) = msg {
-                    let ret = strategy.submit(msg_s);
+                    let position = match &msg_s.inner_message {
+                        InnerMessage::BrokerMessage(message) => {
+                            Some((message.partition.clone(), message.offset))
+                        }
+                        InnerMessage::AnyMessage(_) => None,
+                    };
+                    let ret = submit_span(&msg_s).in_scope(|| strategy.submit(msg_s));
                     match ret {
-                        Ok(()) => {}
-                        Err(_) => {
-                            // If the processing strategy rejected our message, we need
-                            // to pause the consumer and hold the message until it is
-                            // accepted, at which point we can resume consuming.
-                            let partitions =
-                                self.consumer.tell().unwrap().keys().cloned().collect();
-                            if message_carried_over {
-                                let res = self.consumer.pause(partitions);
+                        Ok(()) => {
+                            self.state.record_submit();
+                            if let Some((partition, offset)) = position {
+                                self.in_flight.submitted(&partition, offset);
+                            }
+                            // The strategy accepted the message, so if we were
+                            // applying backpressure we can start consuming again.
+                            self.backpressure_backoff = None;
+                            if self.is_paused {
+                                let partitions =
+                                    self.consumer.tell().unwrap().keys().cloned().collect();
+                                let res = self.consumer.resume(partitions);
                                 match res {
                                     Ok(()) => {}
                                     Err(_) => return Err(RunError::PauseError),
                                 }
-                            } else {
-                                let res = self.consumer.resume(partitions);
+                                metrics::increment("arroyo.consumer.resume", None, None, None);
+                                self.is_paused = false;
+                            }
+                        }
+                        Err(SubmitError::MessageRejected(MessageRejected { message })) => {
+                            // If the processing strategy rejected our message, we need
+                            // to pause the consumer and hold the message until it is
+                            // accepted, at which point we can resume consuming.
+                            self.message = Some(message);
+                            if !self.is_paused {
+                                let partitions =
+                                    self.consumer.tell().unwrap().keys().cloned().collect();
+                                let res = self.consumer.pause(partitions);
                                 match res {
                                     Ok(()) => {}
                                     Err(_) => return Err(RunError::PauseError),
                                 }
+                                metrics::increment("arroyo.consumer.pause", None, None, None);
+                                self.is_paused = true;
                             }
+
+                            // Back off before retrying so that a strategy that
+                            // stays full does not turn the run loop into a busy
+                            // loop.
+                            let backoff = match self.backpressure_backoff {
+                                None => MIN_BACKPRESSURE_BACKOFF,
+                                Some(backoff) => (backoff * 2).min(MAX_BACKPRESSURE_BACKOFF),
+                            };
+                            self.backpressure_backoff = Some(backoff);
+                            drop(trait_callbacks);
+                            metrics::time(
+                                "arroyo.consumer.backpressure.time",
+                                backoff.as_millis() as u64,
+                                None,
+                                None,
+                            );
+                            sleep(backoff);
+                        }
+                        Err(SubmitError::InvalidMessage(invalid)) => {
+                            drop(trait_callbacks);
+                            self.handle_invalid_message(invalid)?;
                         }
                     }
                 }
@@ -176,56 +1126,309 @@ impl<'a, TPayload: 'static + Clone> StreamProcessor<'a, TPayload> {
         Ok(())
     }
 
-    /// The main run loop, see class docstring for more information.
-    pub fn run(&mut self) -> Result<(), RunError> {
-        while !self.shutdown_requested {
-            let ret = self.run_once();
-            match ret {
-                Ok(()) => {}
-                Err(e) => {
-                    let mut trait_callbacks = self.strategies.lock().unwrap();
+    /// Reports the position, the high watermark and the lag of every assigned
+    /// partition.
+    fn report_lag(&self) {
+        let (positions, watermarks) = match (self.consumer.tell(), self.consumer.high_watermarks())
+        {
+            (Ok(positions), Ok(watermarks)) => (positions, watermarks),
+            _ => return,
+        };
+        for (partition, position) in positions {
+            let index = partition.index.to_string();
+            let tags = HashMap::from([
+                ("topic", partition.topic.name.as_str()),
+                ("partition", index.as_str()),
+            ]);
+            metrics::gauge("arroyo.consumer.offset", position, Some(tags.clone()), None);
+            if let Some(watermark) = watermarks.get(&partition) {
+                metrics::gauge(
+                    "arroyo.consumer.high_watermark",
+                    *watermark,
+                    Some(tags.clone()),
+                    None,
+                );
+                metrics::gauge(
+                    "arroyo.consumer.lag",
+                    watermark.saturating_sub(position),
+                    Some(tags),
+                    None,
+                );
+            }
+        }
+    }
 
-                    match trait_callbacks.strategy.as_mut() {
-                        None => {}
-                        Some(strategy) => {
-                            strategy.terminate();
-                        }
+    /// Produces the original invalid message to the dead letter queue, or
+    /// fails if there is no DLQ policy.
+    fn handle_invalid_message(&mut self, invalid: InvalidMessage) -> Result<(), RunError> {
+        log::error!("{}", invalid);
+        metrics::increment("arroyo.consumer.invalid_message.count", None, None, None);
+        if self.dlq_policy.is_none() {
+            return Err(RunError::InvalidMessage(invalid));
+        }
+        let buffered = self
+            .buffered_messages
+            .lock()
+            .unwrap()
+            .pop(&invalid.partition, invalid.offset);
+        self.dead_letter(invalid, buffered)
+    }
+
+    // Dead letters the messages reported invalid while a strategy was
+    // joined. Without a DLQ policy they are skipped.
+    fn dead_letter_invalid_on_join(&mut self) -> Result<(), RunError> {
+        let invalid_on_join = std::mem::take(&mut self.strategies.lock().unwrap().invalid_on_join);
+        if self.dlq_policy.is_none() {
+            return Ok(());
+        }
+        for (invalid, message) in invalid_on_join {
+            self.dead_letter(invalid, message)?;
+        }
+        Ok(())
+    }
+
+    // Produces the copy of an invalid message from the DLQ buffer to the
+    // DLQ, within the limit of the policy.
+    fn dead_letter(
+        &mut self,
+        invalid: InvalidMessage,
+        buffered: Option<BrokerMessage<TPayload>>,
+    ) -> Result<(), RunError> {
+        let Some(policy) = self.dlq_policy.as_ref() else {
+            return Err(RunError::InvalidMessage(invalid));
+        };
+        if !self.dlq_limit_state.record_invalid_message(&invalid) {
+            log::error!("DLQ limit exceeded");
+            return Err(RunError::DlqLimitExceeded);
+        }
+
+        match buffered {
+            None => {
+                log::error!("Invalid message not found in the DLQ buffer");
+                Err(RunError::InvalidMessage(invalid))
+            }
+            Some(message) => loop {
+                match policy.producer.produce(message.clone()) {
+                    Ok(()) => {
+                        self.error_retries.reset("dlq");
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        let backoff = self
+                            .error_retries
+                            .retry("dlq", &e)
+                            .ok_or(RunError::DlqProduceError)?;
+                        sleep(backoff);
                     }
-                    self.consumer.close();
-                    return Err(e);
                 }
-            }
+            },
         }
-        self.shutdown();
-        Ok(())
     }
 
-    pub fn signal_shutdown(&mut self) {
-        self.shutdown_requested = true;
+    /// Sets what happens when offsets are missing from a partition, gaps
+    /// are logged by default.
+    pub fn set_offset_gap_policy(&mut self, policy: OffsetGapPolicy) {
+        self.offset_gap_policy = policy;
     }
 
-    pub fn shutdown(&mut self) {
-        self.consumer.close();
+    /// Caps the number of messages submitted to the strategy that were not
+    /// committed yet, whatever the strategy buffers. The consumer is paused
+    /// while the cap is reached.
+    pub fn set_max_in_flight_messages(&mut self, max_in_flight_messages: u64) {
+        self.set_max_in_flight_messages_with(move || Some(max_in_flight_messages));
     }
 
-    pub fn tell(self) -> HashMap<Partition, u64> {
-        self.consumer.tell().unwrap()
+    /// Same as ``set_max_in_flight_messages``, with a cap that is read on
+    /// every run so that it can change while the consumer runs. ``None``
+    /// lifts the cap.
+    pub fn set_max_in_flight_messages_with(
+        &mut self,
+        max_in_flight_messages: impl Fn() -> Option<u64> + 'a,
+    ) {
+        self.max_in_flight_messages = Some(Box::new(max_in_flight_messages));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::strategies::{
-        CommitRequest, MessageRejected, ProcessingStrategy, ProcessingStrategyFactory,
-    };
-    use super::StreamProcessor;
-    use crate::backends::local::broker::LocalBroker;
-    use crate::backends::local::LocalConsumer;
-    use crate::backends::storages::memory::MemoryMessageStorage;
-    use crate::types::{Message, Partition, Topic};
-    use crate::utils::clock::SystemClock;
-    use std::collections::HashMap;
-    use std::time::Duration;
+    /// Sets how errors of the consumer and of the DLQ producer are retried,
+    /// see ``ErrorPolicy``.
+    pub fn set_error_policy(&mut self, policy: ErrorPolicy) {
+        self.error_retries = ErrorRetries::new(policy);
+    }
+
+    /// Calls ``on_commit`` with the positions of every commit once the
+    /// broker acknowledged it. Asynchronous commits, and the positions the
+    /// consumer commits when partitions are revoked, are reported by the run
+    /// that follows.
+    pub fn set_on_commit(&mut self, on_commit: impl FnMut(&HashMap<Partition, Position>) + 'a) {
+        self.on_commit = Some(Box::new(on_commit));
+    }
+
+    /// Sets how long ``shutdown`` waits for the strategy to complete the
+    /// work it has in flight, ``None`` waits for as long as it takes.
+    pub fn set_join_timeout(&mut self, join_timeout: Option<Duration>) {
+        self.join_timeout = join_timeout;
+    }
+
+    /// Starts consuming every partition from the first message produced at
+    /// or after ``timestamp`` the first time it is assigned, instead of from
+    /// its committed offset. This is meant to replay a time range.
+    pub fn set_start_timestamp(&mut self, timestamp: DateTime<Utc>) {
+        self.start_position = Some(StartPosition::Timestamp(timestamp));
+    }
+
+    /// Starts consuming the given partitions from the given offsets the
+    /// first time they are assigned, instead of from their committed offset.
+    /// Other partitions are not affected. This is meant to replay a precise
+    /// range of messages, see ``parse_offsets`` to load the offsets from a
+    /// file.
+    pub fn set_start_offsets(&mut self, offsets: HashMap<Partition, u64>) {
+        self.start_position = Some(StartPosition::Offsets(offsets));
+    }
+
+    /// The main run loop, see class docstring for more information.
+    ///
+    /// SIGTERM and SIGINT request a graceful shutdown while it runs, a second
+    /// signal terminates the process right away. SIGUSR1 logs the state of
+    /// the processor and of its strategy.
+    pub fn run(&mut self) -> Result<(), RunError> {
+        let signals = SignalHandlers {
+            ids: self.register_signal_handlers(),
+            state_dump: self.spawn_state_dump(),
+        };
+        let mut ret = Ok(());
+        while !self.shutdown_requested.load(Ordering::Relaxed) {
+            ret = self.run_once();
+            if ret.is_err() {
+                break;
+            }
+        }
+        drop(signals);
+
+        if ret.is_err() {
+            let mut trait_callbacks = self.strategies.lock().unwrap();
+            if let Some(strategy) = trait_callbacks.strategy.as_mut() {
+                strategy.terminate();
+            }
+            drop(trait_callbacks);
+            self.consumer.close();
+            metrics::flush();
+            return ret;
+        }
+        let ret = self.shutdown();
+        metrics::flush();
+        ret
+    }
+
+    fn register_signal_handlers(&self) -> Vec<SigId> {
+        let mut ids = Vec::new();
+        for signal in [SIGTERM, SIGINT] {
+            // The conditional shutdown goes first so that it only fires if a
+            // previous signal already set the flag.
+            let registered =
+                flag::register_conditional_shutdown(signal, 1, self.shutdown_requested.clone())
+                    .and_then(|id| {
+                        ids.push(id);
+                        flag::register(signal, self.shutdown_requested.clone())
+                    });
+            match registered {
+                Ok(id) => ids.push(id),
+                Err(error) => {
+                    log::warn!(
+                        "Failed to register handler for signal {}: {}",
+                        signal,
+                        error
+                    )
+                }
+            }
+        }
+        ids
+    }
+
+    pub fn signal_shutdown(&mut self) {
+        self.shutdown_requested.store(true, Ordering::Relaxed);
+    }
+
+    /// Closes the strategy and waits up to the join timeout for it to
+    /// complete, commits the offsets it returns and closes the consumer. A
+    /// carried over message is dropped, it will be consumed again.
+    ///
+    /// The commit is retried like those of the run loop, a commit that keeps
+    /// failing returns ``RunError::CommitError`` once the consumer is closed.
+    pub fn shutdown(&mut self) -> Result<(), RunError> {
+        let mut trait_callbacks = self.strategies.lock().unwrap();
+        let mut positions = std::mem::take(&mut trait_callbacks.pending_commit);
+        positions
+            .extend(trait_callbacks.close_strategy(self.join_timeout, &self.buffered_messages));
+        drop(trait_callbacks);
+
+        // What the strategy reported invalid is dead lettered before the
+        // positions past it are committed.
+        let mut ret = self.dead_letter_invalid_on_join();
+        if ret.is_ok() && !positions.is_empty() {
+            let _span = tracing::info_span!("commit", partitions = positions.len()).entered();
+            ret = self.commit_on_shutdown(positions);
+        }
+        self.message = None;
+        self.consumer.close();
+        self.report_committed();
+        ret
+    }
+
+    // Commits synchronously, the partitions are released right after.
+    fn commit_on_shutdown(
+        &mut self,
+        positions: HashMap<Partition, Position>,
+    ) -> Result<(), RunError> {
+        loop {
+            let committed = self
+                .consumer
+                .stage_offsets(positions.clone())
+                .and_then(|()| self.consumer.commit_offsets_sync());
+            match committed {
+                Ok(committed) => {
+                    self.error_retries.reset("commit");
+                    self.record_committed(&committed);
+                    return Ok(());
+                }
+                Err(error) => {
+                    let backoff = self
+                        .error_retries
+                        .retry("commit", &error)
+                        .ok_or(RunError::CommitError)?;
+                    sleep(backoff);
+                }
+            }
+        }
+    }
+
+    pub fn tell(self) -> HashMap<Partition, u64> {
+        self.consumer.tell().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::dlq::{BufferedMessages, DlqLimit, DlqPolicy, DlqProducer};
+    use super::state::PartitionState;
+    use super::strategies::{
+        report_invalid_message_on_join, CommitRequest, MessageRejected, ProcessingStrategy,
+        ProcessingStrategyFactory, SubmitError,
+    };
+    use super::{
+        parse_offsets, Callbacks, CommitLatency, InvalidMessage, OffsetGaps, RunError, Strategies,
+        StreamProcessor,
+    };
+    use crate::backends::local::broker::LocalBroker;
+    use crate::backends::local::LocalConsumer;
+    use crate::backends::storages::memory::MemoryMessageStorage;
+    use crate::backends::AssignmentCallbacks;
+    use crate::backends::ProducerError;
+    use crate::types::{BrokerMessage, InnerMessage, Message, Partition, Position, Topic};
+    use crate::utils::clock::{Clock, SystemClock, TestingClock};
+    use chrono::{DateTime, Utc};
+    use std::collections::{HashMap, HashSet};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
     use uuid::Uuid;
 
     struct TestStrategy {
@@ -233,19 +1436,16 @@ mod tests {
     }
     impl ProcessingStrategy<String> for TestStrategy {
         #[allow(clippy::manual_map)]
-        fn poll(&mut self) -> Option<CommitRequest> {
+        fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
             match self.message.as_ref() {
-                None => None,
-                Some(message) => Some(CommitRequest {
-                    positions: HashMap::from([(
-                        message.partition.clone(),
-                        message.offset,
-                    )]),
-                }),
+                None => Ok(None),
+                Some(message) => Ok(Some(CommitRequest {
+                    positions: HashMap::from_iter(message.committable()),
+                })),
             }
         }
 
-        fn submit(&mut self, message: Message<String>) -> Result<(), MessageRejected> {
+        fn submit(&mut self, message: Message<String>) -> Result<(), SubmitError<String>> {
             self.message = Some(message);
             Ok(())
         }
@@ -266,7 +1466,7 @@ mod tests {
         }
     }
 
-    fn build_broker() -> LocalBroker<String> {
+    fn build_broker() -> Arc<Mutex<LocalBroker<String>>> {
         let storage: MemoryMessageStorage<String> = Default::default();
         let clock = SystemClock {};
         let mut broker = LocalBroker::new(Box::new(storage), Box::new(clock));
@@ -276,15 +1476,145 @@ mod tests {
         };
 
         let _ = broker.create_topic(topic1, 1);
-        broker
+        Arc::new(Mutex::new(broker))
+    }
+
+    #[test]
+    fn test_create_with_partitions() {
+        struct AssignmentFactory {
+            assignments: Arc<Mutex<Vec<HashMap<Partition, u64>>>>,
+        }
+        impl ProcessingStrategyFactory<String> for AssignmentFactory {
+            fn create(&self) -> Box<dyn ProcessingStrategy<String>> {
+                unreachable!("The processor creates strategies with their partitions")
+            }
+            fn create_with_partitions(
+                &self,
+                partitions: HashMap<Partition, u64>,
+            ) -> Box<dyn ProcessingStrategy<String>> {
+                self.assignments.lock().unwrap().push(partitions);
+                Box::new(TestStrategy { message: None })
+            }
+        }
+
+        let broker = build_broker();
+        let consumer = Box::new(LocalConsumer::new(
+            Uuid::nil(),
+            broker.clone(),
+            "test_group".to_string(),
+            false,
+        ));
+        let assignments = Arc::new(Mutex::new(Vec::new()));
+        let mut processor = StreamProcessor::new(
+            consumer,
+            Box::new(AssignmentFactory {
+                assignments: assignments.clone(),
+            }),
+        );
+        processor.subscribe(Topic {
+            name: "test1".to_string(),
+        });
+        assert!(processor.run_once().is_ok());
+
+        let partition = Partition {
+            topic: Topic {
+                name: "test1".to_string(),
+            },
+            index: 0,
+        };
+        assert_eq!(
+            *assignments.lock().unwrap(),
+            vec![HashMap::from([(partition, 0)])]
+        );
+    }
+
+    #[test]
+    fn test_incremental_assignment() {
+        struct AssignmentFactory {
+            assignments: Arc<Mutex<Vec<HashMap<Partition, u64>>>>,
+        }
+        impl ProcessingStrategyFactory<String> for AssignmentFactory {
+            fn create(&self) -> Box<dyn ProcessingStrategy<String>> {
+                unreachable!("The processor creates strategies with their partitions")
+            }
+            fn create_with_partitions(
+                &self,
+                partitions: HashMap<Partition, u64>,
+            ) -> Box<dyn ProcessingStrategy<String>> {
+                self.assignments.lock().unwrap().push(partitions.clone());
+                let now = Utc::now();
+                Box::new(JoinCommitStrategy {
+                    positions: partitions
+                        .into_iter()
+                        .map(|(partition, offset)| (partition, Position::new(offset, now)))
+                        .collect(),
+                })
+            }
+        }
+
+        let partition = |index| Partition {
+            topic: Topic {
+                name: "test1".to_string(),
+            },
+            index,
+        };
+        let assignments = Arc::new(Mutex::new(Vec::new()));
+        let strategies = Arc::new(Mutex::new(Strategies {
+            processing_factory: Box::new(AssignmentFactory {
+                assignments: assignments.clone(),
+            }),
+            strategy: None,
+            revoked_partitions: HashSet::new(),
+            assigned_partitions: HashMap::new(),
+            pending_commit: HashMap::new(),
+            invalid_on_join: Vec::new(),
+        }));
+        let mut callbacks = Callbacks::new(
+            strategies.clone(),
+            Arc::new(Mutex::new(BufferedMessages::new(None))),
+        );
+
+        // Cooperative rebalances only mention the partitions that move, the
+        // strategy is still recreated for the whole assignment.
+        callbacks.on_assign(HashMap::from([(partition(0), 1)]));
+        callbacks.on_assign(HashMap::from([(partition(1), 2)]));
+        let pending = strategies.lock().unwrap().pending_commit.clone();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[&partition(0)].offset, 1);
+
+        let positions = callbacks.on_revoke(vec![partition(0)]);
+        let offsets: HashMap<_, _> = positions
+            .into_iter()
+            .map(|(partition, position)| (partition, position.offset))
+            .collect();
+        assert_eq!(
+            offsets,
+            HashMap::from([(partition(0), 1), (partition(1), 2)])
+        );
+        assert_eq!(
+            *assignments.lock().unwrap(),
+            vec![
+                HashMap::from([(partition(0), 1)]),
+                HashMap::from([(partition(0), 1), (partition(1), 2)]),
+                HashMap::from([(partition(1), 2)]),
+            ]
+        );
+
+        // Revoking partitions that are not owned leaves the strategy alone.
+        assert!(callbacks.on_revoke(vec![partition(2)]).is_empty());
+        assert_eq!(assignments.lock().unwrap().len(), 3);
+        assert!(strategies.lock().unwrap().strategy.is_some());
+
+        callbacks.on_revoke(vec![partition(1)]);
+        assert!(strategies.lock().unwrap().strategy.is_none());
     }
 
     #[test]
     fn test_processor() {
-        let mut broker = build_broker();
+        let broker = build_broker();
         let consumer = Box::new(LocalConsumer::new(
             Uuid::nil(),
-            &mut broker,
+            broker.clone(),
             "test_group".to_string(),
             false,
         ));
@@ -297,9 +1627,37 @@ mod tests {
         assert!(res.is_ok())
     }
 
+    #[test]
+    fn test_end_of_partition() {
+        let broker = build_broker();
+        let partition = Partition {
+            topic: Topic {
+                name: "test1".to_string(),
+            },
+            index: 0,
+        };
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "message1".to_string());
+
+        // The consumer errors once the partition is consumed
+        let consumer = Box::new(LocalConsumer::new(
+            Uuid::nil(),
+            broker.clone(),
+            "test_group".to_string(),
+            true,
+        ));
+        let mut processor = StreamProcessor::new(consumer, Box::new(TestFactory {}));
+        processor.subscribe(partition.topic.clone());
+        for _ in 0..3 {
+            assert!(processor.run_once().is_ok());
+        }
+    }
+
     #[test]
     fn test_consume() {
-        let mut broker = build_broker();
+        let broker = build_broker();
         let topic1 = Topic {
             name: "test1".to_string(),
         };
@@ -307,12 +1665,18 @@ mod tests {
             topic: topic1,
             index: 0,
         };
-        let _ = broker.produce(&partition, "message1".to_string());
-        let _ = broker.produce(&partition, "message2".to_string());
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "message1".to_string());
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "message2".to_string());
 
         let consumer = Box::new(LocalConsumer::new(
             Uuid::nil(),
-            &mut broker,
+            broker.clone(),
             "test_group".to_string(),
             false,
         ));
@@ -330,4 +1694,1109 @@ mod tests {
 
         assert_eq!(processor.tell(), expected)
     }
+
+    #[test]
+    fn test_on_commit() {
+        let broker = build_broker();
+        let partition = Partition {
+            topic: Topic {
+                name: "test1".to_string(),
+            },
+            index: 0,
+        };
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "message1".to_string());
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "message2".to_string());
+
+        let consumer = Box::new(LocalConsumer::new(
+            Uuid::nil(),
+            broker.clone(),
+            "test_group".to_string(),
+            false,
+        ));
+        let commits = Arc::new(Mutex::new(Vec::new()));
+        let mut processor = StreamProcessor::new(consumer, Box::new(TestFactory {}));
+        let recorded = commits.clone();
+        processor.set_on_commit(move |positions| recorded.lock().unwrap().push(positions.clone()));
+        processor.subscribe(Topic {
+            name: "test1".to_string(),
+        });
+        for _ in 0..3 {
+            processor.run_once().unwrap();
+        }
+
+        // The positions carry the timestamps of the committed messages.
+        let broker = broker.lock().unwrap();
+        let expected: Vec<_> = (0..2)
+            .map(|offset| {
+                let message = broker.consume(&partition, offset).unwrap().unwrap();
+                HashMap::from([(
+                    partition.clone(),
+                    Position::new(offset + 1, message.timestamp),
+                )])
+            })
+            .collect();
+        assert_eq!(*commits.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_max_in_flight_messages() {
+        // Only commits once told to
+        struct SlowCommit {
+            commit: Arc<AtomicBool>,
+            message: Option<Message<String>>,
+        }
+        impl ProcessingStrategy<String> for SlowCommit {
+            fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+                if !self.commit.load(Ordering::Relaxed) {
+                    return Ok(None);
+                }
+                Ok(self.message.take().map(|message| CommitRequest {
+                    positions: HashMap::from_iter(message.committable()),
+                }))
+            }
+            fn submit(&mut self, message: Message<String>) -> Result<(), SubmitError<String>> {
+                self.message = Some(message);
+                Ok(())
+            }
+            fn close(&mut self) {}
+            fn terminate(&mut self) {}
+            fn join(&mut self, _: Option<Duration>) -> Option<CommitRequest> {
+                None
+            }
+        }
+        struct SlowCommitFactory {
+            commit: Arc<AtomicBool>,
+        }
+        impl ProcessingStrategyFactory<String> for SlowCommitFactory {
+            fn create(&self) -> Box<dyn ProcessingStrategy<String>> {
+                Box::new(SlowCommit {
+                    commit: self.commit.clone(),
+                    message: None,
+                })
+            }
+        }
+
+        let broker = build_broker();
+        let partition = Partition {
+            topic: Topic {
+                name: "test1".to_string(),
+            },
+            index: 0,
+        };
+        for i in 0..4 {
+            let _ = broker
+                .lock()
+                .unwrap()
+                .produce(&partition, format!("message{}", i));
+        }
+        let consumer = Box::new(LocalConsumer::new(
+            Uuid::nil(),
+            broker.clone(),
+            "test_group".to_string(),
+            false,
+        ));
+        let commit = Arc::new(AtomicBool::new(false));
+        let mut processor = StreamProcessor::new(
+            consumer,
+            Box::new(SlowCommitFactory {
+                commit: commit.clone(),
+            }),
+        );
+        processor.set_max_in_flight_messages(2);
+        processor.subscribe(Topic {
+            name: "test1".to_string(),
+        });
+
+        for _ in 0..5 {
+            processor.run_once().unwrap();
+        }
+        assert_eq!(processor.in_flight.count(), 2);
+        assert_eq!(
+            processor.consumer.paused().unwrap(),
+            HashSet::from([partition.clone()])
+        );
+
+        processor.publish_state();
+        let state = processor.state().get();
+        assert!(state.paused);
+        assert_eq!(state.in_flight_messages, 2);
+        assert_eq!(
+            state.partitions,
+            vec![PartitionState {
+                topic: "test1".to_string(),
+                index: 0,
+                consumed: Some(2),
+                committed: 0,
+                staged: None,
+                in_flight_messages: 2,
+            }]
+        );
+        assert!(state.last_submit_ms_ago.is_some());
+        assert!(state.strategy.is_some());
+
+        commit.store(true, Ordering::Relaxed);
+        for _ in 0..5 {
+            processor.run_once().unwrap();
+        }
+        assert!(processor.consumer.paused().unwrap().is_empty());
+
+        // The cap is read on every run.
+        let max_in_flight_messages = Arc::new(Mutex::new(Some(0)));
+        let limit = max_in_flight_messages.clone();
+        processor.set_max_in_flight_messages_with(move || *limit.lock().unwrap());
+        processor.run_once().unwrap();
+        assert_eq!(
+            processor.consumer.paused().unwrap(),
+            HashSet::from([partition.clone()])
+        );
+        *max_in_flight_messages.lock().unwrap() = None;
+        processor.run_once().unwrap();
+        assert!(processor.consumer.paused().unwrap().is_empty());
+        assert_eq!(processor.tell(), HashMap::from([(partition, 4)]));
+    }
+
+    #[test]
+    fn test_multiple_topics() {
+        struct TopicsStrategy {
+            topics: Arc<Mutex<Vec<Topic>>>,
+        }
+        impl ProcessingStrategy<String> for TopicsStrategy {
+            fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+                Ok(None)
+            }
+            fn submit(&mut self, message: Message<String>) -> Result<(), SubmitError<String>> {
+                self.topics
+                    .lock()
+                    .unwrap()
+                    .push(message.topic().unwrap().clone());
+                Ok(())
+            }
+            fn close(&mut self) {}
+            fn terminate(&mut self) {}
+            fn join(&mut self, _: Option<Duration>) -> Option<CommitRequest> {
+                None
+            }
+        }
+        struct TopicsFactory {
+            topics: Arc<Mutex<Vec<Topic>>>,
+        }
+        impl ProcessingStrategyFactory<String> for TopicsFactory {
+            fn create(&self) -> Box<dyn ProcessingStrategy<String>> {
+                Box::new(TopicsStrategy {
+                    topics: self.topics.clone(),
+                })
+            }
+        }
+
+        let broker = build_broker();
+        let topics = [
+            Topic {
+                name: "test1".to_string(),
+            },
+            Topic {
+                name: "test2".to_string(),
+            },
+        ];
+        let _ = broker.lock().unwrap().create_topic(topics[1].clone(), 1);
+        for topic in &topics {
+            let partition = Partition {
+                topic: topic.clone(),
+                index: 0,
+            };
+            let _ = broker
+                .lock()
+                .unwrap()
+                .produce(&partition, "message".to_string());
+        }
+
+        let consumer = Box::new(LocalConsumer::new(
+            Uuid::nil(),
+            broker.clone(),
+            "test_group".to_string(),
+            false,
+        ));
+        let consumed = Arc::new(Mutex::new(Vec::new()));
+        let mut processor = StreamProcessor::new(
+            consumer,
+            Box::new(TopicsFactory {
+                topics: consumed.clone(),
+            }),
+        );
+        processor.subscribe_to_topics(&topics);
+        for _ in 0..3 {
+            assert!(processor.run_once().is_ok());
+        }
+
+        let mut consumed = consumed.lock().unwrap().clone();
+        consumed.sort();
+        assert_eq!(consumed, topics);
+    }
+
+    #[test]
+    fn test_pattern_subscription() {
+        let broker = build_broker();
+        let consumer = Box::new(LocalConsumer::new(
+            Uuid::nil(),
+            broker.clone(),
+            "test_group".to_string(),
+            false,
+        ));
+        let mut processor = StreamProcessor::new(consumer, Box::new(TestFactory {}));
+        processor.subscribe_to_pattern("test.*");
+        assert!(processor.run_once().is_ok());
+        assert_eq!(
+            processor
+                .strategies
+                .lock()
+                .unwrap()
+                .assigned_partitions
+                .len(),
+            1
+        );
+
+        let _ = broker.lock().unwrap().create_topic(
+            Topic {
+                name: "test2".to_string(),
+            },
+            1,
+        );
+        assert!(processor.run_once().is_ok());
+        let strategies = processor.strategies.lock().unwrap();
+        assert_eq!(strategies.assigned_partitions.len(), 2);
+        assert!(strategies.strategy.is_some());
+    }
+
+    #[test]
+    fn test_start_timestamp() {
+        let storage: MemoryMessageStorage<String> = Default::default();
+        let start = SystemTime::UNIX_EPOCH;
+        let clock = TestingClock::new(start);
+        let mut broker = LocalBroker::new(Box::new(storage), Box::new(clock.clone()));
+        let topic = Topic {
+            name: "test1".to_string(),
+        };
+        let _ = broker.create_topic(topic.clone(), 1);
+        let partition = Partition {
+            topic: topic.clone(),
+            index: 0,
+        };
+        for payload in ["message1", "message2", "message3"] {
+            broker.produce(&partition, payload.to_string()).unwrap();
+            clock.sleep(Duration::from_secs(10));
+        }
+
+        let consumer = Box::new(LocalConsumer::new(
+            Uuid::nil(),
+            Arc::new(Mutex::new(broker)),
+            "test_group".to_string(),
+            false,
+        ));
+        let mut processor = StreamProcessor::new(consumer, Box::new(TestFactory {}));
+        processor.set_start_timestamp(DateTime::from(start + Duration::from_secs(15)));
+        processor.subscribe(topic);
+
+        // The first message is consumed on assignment, before the seek, and
+        // dropped.
+        assert!(processor.run_once().is_ok());
+        assert!(processor.message.is_none());
+        assert!(processor.run_once().is_ok());
+        assert_eq!(processor.tell(), HashMap::from([(partition, 3)]));
+    }
+
+    #[test]
+    fn test_start_offsets() {
+        let broker = build_broker();
+        let topic = Topic {
+            name: "test1".to_string(),
+        };
+        let partition = Partition {
+            topic: topic.clone(),
+            index: 0,
+        };
+        for payload in ["message1", "message2", "message3"] {
+            let _ = broker
+                .lock()
+                .unwrap()
+                .produce(&partition, payload.to_string());
+        }
+
+        let consumer = Box::new(LocalConsumer::new(
+            Uuid::nil(),
+            broker.clone(),
+            "test_group".to_string(),
+            false,
+        ));
+        let mut processor = StreamProcessor::new(consumer, Box::new(TestFactory {}));
+        processor.set_start_offsets(parse_offsets(r#"{"test1": {"0": 2}}"#).unwrap());
+        processor.subscribe(topic);
+
+        assert!(processor.run_once().is_ok());
+        assert!(processor.message.is_none());
+        assert!(processor.run_once().is_ok());
+        assert_eq!(processor.tell(), HashMap::from([(partition, 3)]));
+    }
+
+    // Rejects the first ``rejections`` messages it is given, then accepts
+    // everything.
+    struct RejectingStrategy {
+        rejections: Arc<Mutex<usize>>,
+        accepted: Arc<Mutex<Vec<String>>>,
+    }
+    impl ProcessingStrategy<String> for RejectingStrategy {
+        fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+            Ok(None)
+        }
+
+        fn submit(&mut self, message: Message<String>) -> Result<(), SubmitError<String>> {
+            let mut rejections = self.rejections.lock().unwrap();
+            if *rejections > 0 {
+                *rejections -= 1;
+                return Err(SubmitError::MessageRejected(MessageRejected { message }));
+            }
+            self.accepted.lock().unwrap().push(message.payload());
+            Ok(())
+        }
+
+        fn close(&mut self) {}
+
+        fn terminate(&mut self) {}
+
+        fn join(&mut self, _: Option<Duration>) -> Option<CommitRequest> {
+            None
+        }
+    }
+
+    struct RejectingFactory {
+        rejections: Arc<Mutex<usize>>,
+        accepted: Arc<Mutex<Vec<String>>>,
+    }
+    impl ProcessingStrategyFactory<String> for RejectingFactory {
+        fn create(&self) -> Box<dyn ProcessingStrategy<String>> {
+            Box::new(RejectingStrategy {
+                rejections: self.rejections.clone(),
+                accepted: self.accepted.clone(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_backpressure() {
+        let broker = build_broker();
+        let partition = Partition {
+            topic: Topic {
+                name: "test1".to_string(),
+            },
+            index: 0,
+        };
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "message1".to_string());
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "message2".to_string());
+
+        let consumer = Box::new(LocalConsumer::new(
+            Uuid::nil(),
+            broker.clone(),
+            "test_group".to_string(),
+            false,
+        ));
+
+        let accepted = Arc::new(Mutex::new(Vec::new()));
+        let mut processor = StreamProcessor::new(
+            consumer,
+            Box::new(RejectingFactory {
+                rejections: Arc::new(Mutex::new(2)),
+                accepted: accepted.clone(),
+            }),
+        );
+        processor.subscribe(Topic {
+            name: "test1".to_string(),
+        });
+
+        // The first message is rejected, carried over and the consumer paused.
+        assert!(processor.run_once().is_ok());
+        assert!(processor.message.is_some());
+        assert!(processor.is_paused);
+        assert_eq!(
+            processor.consumer.paused().unwrap(),
+            HashSet::from([partition.clone()])
+        );
+
+        // It is rejected again while the consumer stays paused.
+        assert!(processor.run_once().is_ok());
+        assert!(processor.is_paused);
+        assert!(accepted.lock().unwrap().is_empty());
+
+        // Once it is accepted the consumer is resumed.
+        assert!(processor.run_once().is_ok());
+        assert!(processor.message.is_none());
+        assert!(!processor.is_paused);
+        assert!(processor.consumer.paused().unwrap().is_empty());
+
+        assert!(processor.run_once().is_ok());
+        assert_eq!(*accepted.lock().unwrap(), vec!["message1", "message2"]);
+        assert_eq!(processor.tell(), HashMap::from([(partition, 2)]));
+    }
+
+    #[test]
+    fn test_resumed_by_rebalance() {
+        let broker = build_broker();
+        let partition = Partition {
+            topic: Topic {
+                name: "test1".to_string(),
+            },
+            index: 0,
+        };
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "message1".to_string());
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "message2".to_string());
+
+        let consumer = Box::new(LocalConsumer::new(
+            Uuid::nil(),
+            broker.clone(),
+            "test_group".to_string(),
+            false,
+        ));
+
+        let accepted = Arc::new(Mutex::new(Vec::new()));
+        let mut processor = StreamProcessor::new(
+            consumer,
+            Box::new(RejectingFactory {
+                rejections: Arc::new(Mutex::new(1)),
+                accepted: accepted.clone(),
+            }),
+        );
+        processor.subscribe(Topic {
+            name: "test1".to_string(),
+        });
+
+        assert!(processor.run_once().is_ok());
+        assert!(processor.is_paused);
+
+        // Simulate a reassignment resuming the partition behind the
+        // processor's back.
+        processor
+            .consumer
+            .resume(HashSet::from([partition.clone()]))
+            .unwrap();
+
+        // The carried over message is consumed again instead of being lost.
+        assert!(processor.run_once().is_ok());
+        assert!(!processor.is_paused);
+        assert!(processor.message.is_none());
+        assert!(processor.run_once().is_ok());
+        assert!(processor.run_once().is_ok());
+        assert_eq!(*accepted.lock().unwrap(), vec!["message1", "message2"]);
+        assert_eq!(processor.tell(), HashMap::from([(partition, 2)]));
+    }
+
+    #[test]
+    fn test_revoke_drops_carried_over_message() {
+        let broker = build_broker();
+        let partition = Partition {
+            topic: Topic {
+                name: "test1".to_string(),
+            },
+            index: 0,
+        };
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "message1".to_string());
+
+        let consumer = Box::new(LocalConsumer::new(
+            Uuid::nil(),
+            broker.clone(),
+            "test_group".to_string(),
+            false,
+        ));
+
+        let accepted = Arc::new(Mutex::new(Vec::new()));
+        let mut processor = StreamProcessor::new(
+            consumer,
+            Box::new(RejectingFactory {
+                rejections: Arc::new(Mutex::new(1)),
+                accepted: accepted.clone(),
+            }),
+        );
+        processor.subscribe(Topic {
+            name: "test1".to_string(),
+        });
+
+        assert!(processor.run_once().is_ok());
+        assert!(processor.is_paused);
+
+        // The revocation is delivered by the next poll, after which there is
+        // no strategy left to submit the message to.
+        processor.consumer.unsubscribe().unwrap();
+        assert!(processor.run_once().is_ok());
+        assert!(processor.message.is_none());
+        assert!(!processor.is_paused);
+        assert!(processor.strategies.lock().unwrap().strategy.is_none());
+        assert!(accepted.lock().unwrap().is_empty());
+    }
+
+    // Only hands out offsets to commit when it is joined.
+    struct JoinCommitStrategy {
+        positions: HashMap<Partition, Position>,
+    }
+    impl ProcessingStrategy<String> for JoinCommitStrategy {
+        fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+            Ok(None)
+        }
+
+        fn submit(&mut self, message: Message<String>) -> Result<(), SubmitError<String>> {
+            self.positions.extend(message.committable());
+            Ok(())
+        }
+
+        fn close(&mut self) {}
+
+        fn terminate(&mut self) {}
+
+        fn join(&mut self, _: Option<Duration>) -> Option<CommitRequest> {
+            Some(CommitRequest {
+                positions: std::mem::take(&mut self.positions),
+            })
+        }
+    }
+
+    struct JoinCommitFactory {}
+    impl ProcessingStrategyFactory<String> for JoinCommitFactory {
+        fn create(&self) -> Box<dyn ProcessingStrategy<String>> {
+            Box::new(JoinCommitStrategy {
+                positions: HashMap::new(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_commit_on_revoke() {
+        let broker = build_broker();
+        let topic = Topic {
+            name: "test1".to_string(),
+        };
+        let partition = Partition {
+            topic: topic.clone(),
+            index: 0,
+        };
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "message1".to_string());
+
+        let consumer = Box::new(LocalConsumer::new(
+            Uuid::nil(),
+            broker.clone(),
+            "test_group".to_string(),
+            false,
+        ));
+        let commits = Arc::new(Mutex::new(Vec::new()));
+        let mut processor = StreamProcessor::new(consumer, Box::new(JoinCommitFactory {}));
+        let recorded = commits.clone();
+        processor.set_on_commit(move |positions| recorded.lock().unwrap().push(positions.clone()));
+        processor.subscribe(topic.clone());
+        assert!(processor.run_once().is_ok());
+
+        processor.consumer.unsubscribe().unwrap();
+        assert!(processor.run_once().is_ok());
+
+        // The commit on revoke is reported too.
+        let commits = commits.lock().unwrap();
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[0][&partition].offset, 1);
+        drop(commits);
+
+        // The next member of the group starts after the message
+        let assignment = broker
+            .lock()
+            .unwrap()
+            .subscribe(Uuid::nil(), "test_group".to_string(), vec![topic])
+            .unwrap();
+        assert_eq!(assignment, HashMap::from([(partition, 1)]));
+    }
+
+    #[test]
+    fn test_shutdown() {
+        struct TimeoutStrategy {
+            positions: HashMap<Partition, Position>,
+            join_timeouts: Arc<Mutex<Vec<Option<Duration>>>>,
+        }
+        impl ProcessingStrategy<String> for TimeoutStrategy {
+            fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+                Ok(None)
+            }
+            fn submit(&mut self, message: Message<String>) -> Result<(), SubmitError<String>> {
+                self.positions.extend(message.committable());
+                Ok(())
+            }
+            fn close(&mut self) {}
+            fn terminate(&mut self) {}
+            fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+                self.join_timeouts.lock().unwrap().push(timeout);
+                Some(CommitRequest {
+                    positions: std::mem::take(&mut self.positions),
+                })
+            }
+        }
+        struct TimeoutFactory {
+            join_timeouts: Arc<Mutex<Vec<Option<Duration>>>>,
+        }
+        impl ProcessingStrategyFactory<String> for TimeoutFactory {
+            fn create(&self) -> Box<dyn ProcessingStrategy<String>> {
+                Box::new(TimeoutStrategy {
+                    positions: HashMap::new(),
+                    join_timeouts: self.join_timeouts.clone(),
+                })
+            }
+        }
+
+        let broker = build_broker();
+        let topic = Topic {
+            name: "test1".to_string(),
+        };
+        let partition = Partition {
+            topic: topic.clone(),
+            index: 0,
+        };
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "message1".to_string());
+
+        let consumer = Box::new(LocalConsumer::new(
+            Uuid::nil(),
+            broker.clone(),
+            "test_group".to_string(),
+            false,
+        ));
+        let join_timeouts = Arc::new(Mutex::new(Vec::new()));
+        let mut processor = StreamProcessor::new(
+            consumer,
+            Box::new(TimeoutFactory {
+                join_timeouts: join_timeouts.clone(),
+            }),
+        );
+        processor.set_join_timeout(Some(Duration::from_secs(1)));
+        processor.subscribe(topic.clone());
+        assert!(processor.run_once().is_ok());
+
+        processor.signal_shutdown();
+        assert!(processor.run().is_ok());
+        assert_eq!(
+            *join_timeouts.lock().unwrap(),
+            vec![Some(Duration::from_secs(1))]
+        );
+
+        let assignment = broker
+            .lock()
+            .unwrap()
+            .subscribe(Uuid::nil(), "test_group".to_string(), vec![topic])
+            .unwrap();
+        assert_eq!(assignment, HashMap::from([(partition, 1)]));
+    }
+
+    // Raises ``InvalidMessage`` for every message whose payload is
+    // "invalid".
+    struct ValidatingStrategy {}
+    impl ProcessingStrategy<String> for ValidatingStrategy {
+        fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+            Ok(None)
+        }
+
+        fn submit(&mut self, message: Message<String>) -> Result<(), SubmitError<String>> {
+            if message.payload() == "invalid" {
+                if let InnerMessage::BrokerMessage(inner) = &message.inner_message {
+                    return Err(SubmitError::InvalidMessage(InvalidMessage::from(inner)));
+                }
+            }
+            Ok(())
+        }
+
+        fn close(&mut self) {}
+
+        fn terminate(&mut self) {}
+
+        fn join(&mut self, _: Option<Duration>) -> Option<CommitRequest> {
+            None
+        }
+    }
+
+    struct ValidatingFactory {}
+    impl ProcessingStrategyFactory<String> for ValidatingFactory {
+        fn create(&self) -> Box<dyn ProcessingStrategy<String>> {
+            Box::new(ValidatingStrategy {})
+        }
+    }
+
+    struct RecordingDlqProducer {
+        produced: Arc<Mutex<Vec<BrokerMessage<String>>>>,
+    }
+    impl DlqProducer<String> for RecordingDlqProducer {
+        fn produce(&self, message: BrokerMessage<String>) -> Result<(), ProducerError> {
+            self.produced.lock().unwrap().push(message);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_invalid_message() {
+        let broker = build_broker();
+        let partition = Partition {
+            topic: Topic {
+                name: "test1".to_string(),
+            },
+            index: 0,
+        };
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "invalid".to_string());
+
+        let consumer = Box::new(LocalConsumer::new(
+            Uuid::nil(),
+            broker.clone(),
+            "test_group".to_string(),
+            false,
+        ));
+
+        // Without a DLQ policy the processor crashes.
+        let mut processor = StreamProcessor::new(consumer, Box::new(ValidatingFactory {}));
+        processor.subscribe(Topic {
+            name: "test1".to_string(),
+        });
+        match processor.run_once() {
+            Err(RunError::InvalidMessage(invalid)) => assert_eq!(invalid.offset, 0),
+            _ => panic!("Expected an InvalidMessage error"),
+        }
+    }
+
+    #[test]
+    fn test_dlq() {
+        let broker = build_broker();
+        let partition = Partition {
+            topic: Topic {
+                name: "test1".to_string(),
+            },
+            index: 0,
+        };
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "valid".to_string());
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "invalid".to_string());
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "valid".to_string());
+
+        let consumer = Box::new(LocalConsumer::new(
+            Uuid::nil(),
+            broker.clone(),
+            "test_group".to_string(),
+            false,
+        ));
+
+        let produced = Arc::new(Mutex::new(Vec::new()));
+        let mut processor = StreamProcessor::new_with_dlq_policy(
+            consumer,
+            Box::new(ValidatingFactory {}),
+            DlqPolicy::new(
+                Box::new(RecordingDlqProducer {
+                    produced: produced.clone(),
+                }),
+                DlqLimit::default(),
+            ),
+        );
+        processor.subscribe(Topic {
+            name: "test1".to_string(),
+        });
+
+        for _ in 0..3 {
+            assert!(processor.run_once().is_ok());
+        }
+
+        let produced = produced.lock().unwrap();
+        assert_eq!(produced.len(), 1);
+        assert_eq!(produced[0].payload, "invalid");
+        assert_eq!(produced[0].offset, 1);
+        assert_eq!(processor.tell(), HashMap::from([(partition, 3)]));
+    }
+
+    // Accepts every message and raises ``InvalidMessage`` from the next
+    // ``poll`` for those whose payload is "invalid".
+    struct DeferredValidatingStrategy {
+        invalid: Option<InvalidMessage>,
+    }
+    impl ProcessingStrategy<String> for DeferredValidatingStrategy {
+        fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+            match self.invalid.take() {
+                None => Ok(None),
+                Some(invalid) => Err(invalid),
+            }
+        }
+
+        fn submit(&mut self, message: Message<String>) -> Result<(), SubmitError<String>> {
+            if message.payload() == "invalid" {
+                if let InnerMessage::BrokerMessage(inner) = &message.inner_message {
+                    self.invalid = Some(InvalidMessage::from(inner));
+                }
+            }
+            Ok(())
+        }
+
+        fn close(&mut self) {}
+
+        fn terminate(&mut self) {}
+
+        fn join(&mut self, _: Option<Duration>) -> Option<CommitRequest> {
+            None
+        }
+    }
+
+    struct DeferredValidatingFactory {}
+    impl ProcessingStrategyFactory<String> for DeferredValidatingFactory {
+        fn create(&self) -> Box<dyn ProcessingStrategy<String>> {
+            Box::new(DeferredValidatingStrategy { invalid: None })
+        }
+    }
+
+    #[test]
+    fn test_dlq_from_poll() {
+        let broker = build_broker();
+        let partition = Partition {
+            topic: Topic {
+                name: "test1".to_string(),
+            },
+            index: 0,
+        };
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "invalid".to_string());
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "valid".to_string());
+
+        let consumer = Box::new(LocalConsumer::new(
+            Uuid::nil(),
+            broker.clone(),
+            "test_group".to_string(),
+            false,
+        ));
+
+        let produced = Arc::new(Mutex::new(Vec::new()));
+        let mut processor = StreamProcessor::new_with_dlq_policy(
+            consumer,
+            Box::new(DeferredValidatingFactory {}),
+            DlqPolicy::new(
+                Box::new(RecordingDlqProducer {
+                    produced: produced.clone(),
+                }),
+                DlqLimit::default(),
+            ),
+        );
+        processor.subscribe(Topic {
+            name: "test1".to_string(),
+        });
+
+        for _ in 0..3 {
+            assert!(processor.run_once().is_ok());
+        }
+
+        let produced = produced.lock().unwrap();
+        assert_eq!(produced.len(), 1);
+        assert_eq!(produced[0].offset, 0);
+    }
+
+    // Holds on to every message and only looks at them on join, reporting
+    // those whose payload is "invalid" and committing past all of them.
+    struct ValidatingOnJoinStrategy {
+        messages: Vec<Message<String>>,
+    }
+    impl ProcessingStrategy<String> for ValidatingOnJoinStrategy {
+        fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+            Ok(None)
+        }
+
+        fn submit(&mut self, message: Message<String>) -> Result<(), SubmitError<String>> {
+            self.messages.push(message);
+            Ok(())
+        }
+
+        fn close(&mut self) {}
+
+        fn terminate(&mut self) {}
+
+        fn join(&mut self, _: Option<Duration>) -> Option<CommitRequest> {
+            let mut positions = HashMap::new();
+            for message in self.messages.drain(..) {
+                if message.payload() == "invalid" {
+                    let invalid = InvalidMessage::for_message(&message).unwrap();
+                    report_invalid_message_on_join("ValidatingOnJoin", &invalid);
+                }
+                positions.extend(message.committable());
+            }
+            Some(CommitRequest { positions })
+        }
+    }
+
+    struct ValidatingOnJoinFactory {}
+    impl ProcessingStrategyFactory<String> for ValidatingOnJoinFactory {
+        fn create(&self) -> Box<dyn ProcessingStrategy<String>> {
+            Box::new(ValidatingOnJoinStrategy {
+                messages: Vec::new(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_dlq_on_join() {
+        let broker = build_broker();
+        let partition = Partition {
+            topic: Topic {
+                name: "test1".to_string(),
+            },
+            index: 0,
+        };
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "valid".to_string());
+        let _ = broker
+            .lock()
+            .unwrap()
+            .produce(&partition, "invalid".to_string());
+
+        let consumer = Box::new(LocalConsumer::new(
+            Uuid::nil(),
+            broker.clone(),
+            "test_group".to_string(),
+            false,
+        ));
+
+        let produced = Arc::new(Mutex::new(Vec::new()));
+        let mut processor = StreamProcessor::new_with_dlq_policy(
+            consumer,
+            Box::new(ValidatingOnJoinFactory {}),
+            DlqPolicy::new(
+                Box::new(RecordingDlqProducer {
+                    produced: produced.clone(),
+                }),
+                DlqLimit::default(),
+            ),
+        );
+        processor.subscribe(Topic {
+            name: "test1".to_string(),
+        });
+
+        for _ in 0..3 {
+            assert!(processor.run_once().is_ok());
+        }
+        assert!(processor.shutdown().is_ok());
+
+        // The message reported on join is dead lettered before the offsets
+        // past it are committed.
+        let produced = produced.lock().unwrap();
+        assert_eq!(produced.len(), 1);
+        assert_eq!(produced[0].offset, 1);
+        let assignment = broker
+            .lock()
+            .unwrap()
+            .subscribe(
+                Uuid::nil(),
+                "test_group".to_string(),
+                vec![partition.topic.clone()],
+            )
+            .unwrap();
+        assert_eq!(assignment, HashMap::from([(partition, 2)]));
+    }
+
+    #[test]
+    fn test_commit_latency() {
+        let partition = Partition {
+            topic: Topic {
+                name: "test".to_string(),
+            },
+            index: 0,
+        };
+        let start = DateTime::from(SystemTime::UNIX_EPOCH);
+        let mut latency = CommitLatency::default();
+        for (offset, seconds) in [(10, 0), (11, 5)] {
+            latency.sample(&BrokerMessage::new(
+                "payload".to_string(),
+                partition.clone(),
+                offset,
+                start + chrono::Duration::seconds(seconds),
+            ));
+        }
+
+        let now = start + chrono::Duration::seconds(30);
+        let positions = HashMap::from([(partition.clone(), Position::new(10, now))]);
+        assert!(latency.committed(&positions, now).is_empty());
+
+        // The first message is the sample
+        let positions = HashMap::from([(partition.clone(), Position::new(12, now))]);
+        assert_eq!(
+            latency.committed(&positions, now),
+            vec![(partition.clone(), Duration::from_secs(30))]
+        );
+        assert!(latency.committed(&positions, now).is_empty());
+
+        latency.sample(&BrokerMessage::new(
+            "payload".to_string(),
+            partition.clone(),
+            12,
+            start,
+        ));
+        latency.forget(&HashSet::from([partition.clone()]));
+        let positions = HashMap::from([(partition, Position::new(13, now))]);
+        assert!(latency.committed(&positions, now).is_empty());
+    }
+
+    #[test]
+    fn test_offset_gaps() {
+        let partition = Partition {
+            topic: Topic {
+                name: "test".to_string(),
+            },
+            index: 0,
+        };
+        let message = |offset| {
+            BrokerMessage::new(
+                "payload".to_string(),
+                partition.clone(),
+                offset,
+                DateTime::from(SystemTime::now()),
+            )
+        };
+        let mut gaps = OffsetGaps::default();
+        assert_eq!(gaps.observe(&message(10)), None);
+        assert_eq!(gaps.observe(&message(11)), None);
+        assert_eq!(gaps.observe(&message(15)), Some(3));
+        // Consuming again is not a gap
+        assert_eq!(gaps.observe(&message(12)), None);
+        assert_eq!(gaps.observe(&message(13)), None);
+
+        gaps.forget([&partition]);
+        assert_eq!(gaps.observe(&message(100)), None);
+    }
 }
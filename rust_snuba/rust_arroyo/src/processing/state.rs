@@ -0,0 +1,117 @@
+use crate::processing::strategies::StrategyDescription;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// A snapshot of what a ``StreamProcessor`` is doing, which it publishes
+/// regularly while it runs so that it can be inspected from other threads.
+/// The durations are computed when the state is read, so that they keep
+/// growing while the processor is stuck.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ProcessorState {
+    /// How long the processor has been running for.
+    pub uptime_secs: u64,
+    /// Whether the consumer is paused, because of backpressure or because
+    /// of the cap on messages in flight. All the partitions are paused
+    /// together.
+    pub paused: bool,
+    /// Whether a message rejected by the strategy waits to be submitted
+    /// again.
+    pub message_carried_over: bool,
+    /// The messages submitted to the strategy that were not committed yet.
+    pub in_flight_messages: u64,
+    /// How long ago the strategy was last polled and last accepted a
+    /// message, if ever.
+    pub last_poll_ms_ago: Option<u64>,
+    pub last_submit_ms_ago: Option<u64>,
+    /// The strategy of the current assignment, if any.
+    pub strategy: Option<StrategyDescription>,
+    /// The assigned partitions, in order.
+    pub partitions: Vec<PartitionState>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PartitionState {
+    pub topic: String,
+    pub index: u16,
+    /// The offset of the next message the consumer returns.
+    pub consumed: Option<u64>,
+    /// The last committed offset, or the offset the partition was assigned
+    /// with if nothing was committed since.
+    pub committed: u64,
+    /// An offset of a strategy replaced on assignment, committed on the
+    /// next run.
+    pub staged: Option<u64>,
+    pub in_flight_messages: u64,
+}
+
+#[derive(Debug, Default)]
+struct SharedState {
+    // Without the durations, they are computed from the instants.
+    state: ProcessorState,
+    started: Option<Instant>,
+    last_poll: Option<Instant>,
+    last_submit: Option<Instant>,
+}
+
+/// Shares the state a processor publishes, see ``StreamProcessor::state``.
+#[derive(Clone, Debug, Default)]
+pub struct ProcessorStateHandle {
+    shared: Arc<Mutex<SharedState>>,
+}
+
+impl ProcessorStateHandle {
+    /// Returns the state last published by the processor.
+    pub fn get(&self) -> ProcessorState {
+        let shared = self.shared.lock().unwrap();
+        let ms_ago = |instant: Option<Instant>| instant.map(|i| i.elapsed().as_millis() as u64);
+        ProcessorState {
+            uptime_secs: shared
+                .started
+                .map_or(0, |started| started.elapsed().as_secs()),
+            last_poll_ms_ago: ms_ago(shared.last_poll),
+            last_submit_ms_ago: ms_ago(shared.last_submit),
+            ..shared.state.clone()
+        }
+    }
+
+    pub(crate) fn set(&self, state: ProcessorState) {
+        self.shared.lock().unwrap().state = state;
+    }
+
+    /// Starts over for a new processor.
+    pub(crate) fn start(&self) {
+        *self.shared.lock().unwrap() = SharedState {
+            started: Some(Instant::now()),
+            ..Default::default()
+        };
+    }
+
+    pub(crate) fn record_poll(&self) {
+        self.shared.lock().unwrap().last_poll = Some(Instant::now());
+    }
+
+    pub(crate) fn record_submit(&self) {
+        self.shared.lock().unwrap().last_submit = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProcessorStateHandle;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[test]
+    fn test_durations_are_computed_on_read() {
+        let handle = ProcessorStateHandle::default();
+        assert_eq!(handle.get().last_poll_ms_ago, None);
+
+        handle.start();
+        handle.record_poll();
+        let first = handle.get().last_poll_ms_ago.unwrap();
+        sleep(Duration::from_millis(20));
+        assert!(handle.get().last_poll_ms_ago.unwrap() >= first + 20);
+        assert_eq!(handle.get().last_submit_ms_ago, None);
+    }
+}
@@ -1,25 +1,32 @@
-use crate::processing::strategies::{CommitRequest, MessageRejected, ProcessingStrategy};
-use crate::types::{Message, Partition};
-use log::info;
+use crate::processing::strategies::commit_policy::{CommitPolicy, Periodic};
+use crate::processing::strategies::{
+    CommitRequest, InvalidMessage, ProcessingStrategy, StrategyDescription, SubmitError,
+};
+use crate::types::{Message, Partition, Position};
+use crate::utils::clock::{Clock, SystemClock};
+use crate::utils::metrics;
+use log::{info, warn};
 use std::collections::HashMap;
 use std::time::{Duration, SystemTime};
 
 pub struct CommitOffsets {
-    partitions: HashMap<Partition, u64>,
+    partitions: HashMap<Partition, Position>,
+    // The highest position committed so far on each partition. Positions
+    // that are staged out of order never move a partition backwards.
+    committed: HashMap<Partition, Position>,
     last_commit_time: SystemTime,
-    commit_frequency: Duration,
+    commit_policy: Box<dyn CommitPolicy>,
+    uncommitted_count: u64,
+    clock: Box<dyn Clock>,
 }
-impl <T: Clone>ProcessingStrategy<T> for CommitOffsets {
-    fn poll(&mut self) -> Option<CommitRequest> {
-        self.commit(false)
+impl<T: Clone> ProcessingStrategy<T> for CommitOffsets {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        Ok(self.commit(false))
     }
 
-    fn submit(&mut self, message: Message<T>) -> Result<(), MessageRejected> {
-        for (partition, offset) in message.committable() {
-            self.partitions.insert(
-                partition,
-                offset
-            );
+    fn submit(&mut self, message: Message<T>) -> Result<(), SubmitError<T>> {
+        for (partition, position) in message.committable() {
+            self.stage(partition, position);
         }
         Ok(())
     }
@@ -31,24 +38,58 @@ impl <T: Clone>ProcessingStrategy<T> for CommitOffsets {
     fn join(&mut self, _: Option<Duration>) -> Option<CommitRequest> {
         self.commit(true)
     }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription::new("CommitOffsets")
+            .with_buffered_messages(self.uncommitted_count as usize)
+    }
 }
 
 impl CommitOffsets {
+    fn stage(&mut self, partition: Partition, position: Position) {
+        let highest = self
+            .partitions
+            .get(&partition)
+            .or_else(|| self.committed.get(&partition))
+            .copied();
+        if let Some(highest) = highest {
+            if position.offset < highest.offset {
+                warn!(
+                    "Ignoring offset {} on {}, offset {} was already staged or committed",
+                    position, partition, highest
+                );
+                metrics::increment(
+                    "arroyo.strategies.commit_offsets.offset_regression",
+                    None,
+                    None,
+                    None,
+                );
+                return;
+            }
+        }
+        self.partitions.insert(partition, position);
+        self.uncommitted_count += 1;
+    }
+
     fn commit(&mut self, force: bool) -> Option<CommitRequest> {
-        if SystemTime::now()
-            > self
-                .last_commit_time
-                .checked_add(self.commit_frequency)
-                .unwrap()
-            || force
+        let elapsed = self
+            .clock
+            .time()
+            .duration_since(self.last_commit_time)
+            .unwrap_or(Duration::ZERO);
+        if force
+            || self
+                .commit_policy
+                .should_commit(elapsed, self.uncommitted_count)
         {
             info!("Performing a commit");
             if !self.partitions.is_empty() {
                 let ret = Some(CommitRequest {
                     positions: self.partitions.clone(),
                 });
-                self.partitions.clear();
-                self.last_commit_time = SystemTime::now();
+                self.committed.extend(self.partitions.drain());
+                self.uncommitted_count = 0;
+                self.last_commit_time = self.clock.time();
                 ret
             } else {
                 None
@@ -60,20 +101,53 @@ impl CommitOffsets {
 }
 
 pub fn new(commit_frequency: Duration) -> CommitOffsets {
+    new_with_policy(Box::new(Periodic {
+        frequency: commit_frequency,
+        min_commit_count: None,
+    }))
+}
+
+/// Same as ``new`` but also commits once ``min_commit_count`` offsets have
+/// been staged, whichever happens first.
+pub fn new_with_min_commit_count(
+    commit_frequency: Duration,
+    min_commit_count: u64,
+) -> CommitOffsets {
+    new_with_policy(Box::new(Periodic {
+        frequency: commit_frequency,
+        min_commit_count: Some(min_commit_count),
+    }))
+}
+
+pub fn new_with_policy(commit_policy: Box<dyn CommitPolicy>) -> CommitOffsets {
+    new_with_clock(commit_policy, Box::new(SystemClock {}))
+}
+
+/// Same as ``new_with_policy`` but measures the time elapsed between
+/// commits with ``clock``.
+pub fn new_with_clock(
+    commit_policy: Box<dyn CommitPolicy>,
+    clock: Box<dyn Clock>,
+) -> CommitOffsets {
     CommitOffsets {
         partitions: Default::default(),
-        last_commit_time: SystemTime::now(),
-        commit_frequency,
+        committed: Default::default(),
+        last_commit_time: clock.time(),
+        commit_policy,
+        uncommitted_count: 0,
+        clock,
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::backends::kafka::types::KafkaPayload;
+    use crate::processing::strategies::commit_policy::{Immediate, Periodic};
     use crate::processing::strategies::{commit_offsets, CommitRequest, ProcessingStrategy};
-    use crate::types::{Message, Partition, Topic};
+    use crate::types::{Message, Partition, Position, Topic};
+    use crate::utils::clock::{Clock, TestingClock};
     use chrono::DateTime;
-    use std::thread::sleep;
+    use std::collections::HashMap;
     use std::time::{Duration, SystemTime};
 
     #[test]
@@ -92,51 +166,169 @@ mod tests {
             index: 1,
         };
         let timestamp = DateTime::from(SystemTime::now());
-        let m1 = Message {
-            partition: partition1.clone(),
-            offset: 1000,
-            payload: KafkaPayload {
+        let m1 = Message::new_broker_message(
+            KafkaPayload {
                 key: None,
                 headers: None,
                 payload: None,
             },
+            partition1.clone(),
+            1000,
             timestamp,
-        };
-        let m2 = Message {
-            partition: partition2.clone(),
-            offset: 2000,
-            payload: KafkaPayload {
+        );
+        let m2 = Message::new_broker_message(
+            KafkaPayload {
                 key: None,
                 headers: None,
                 payload: None,
             },
+            partition2.clone(),
+            2000,
             timestamp,
-        };
+        );
 
-        let mut noop = commit_offsets::new(Duration::from_secs(1));
+        let clock = TestingClock::new(SystemTime::now());
+        let mut noop: Box<dyn ProcessingStrategy<KafkaPayload>> =
+            Box::new(commit_offsets::new_with_clock(
+                Box::new(Periodic {
+                    frequency: Duration::from_secs(1),
+                    min_commit_count: None,
+                }),
+                Box::new(clock.clone()),
+            ));
 
         let mut commit_req1 = CommitRequest {
             positions: Default::default(),
         };
-        commit_req1.positions.insert(
-            partition1,
-            1001,
-        );
+        commit_req1
+            .positions
+            .insert(partition1, Position::new(1001, timestamp));
         noop.submit(m1).expect("Failed to submit");
-        assert_eq!(noop.poll(), None);
+        assert_eq!(noop.poll().unwrap(), None);
 
-        sleep(Duration::from_secs(2));
-        assert_eq!(noop.poll(), Some(commit_req1));
+        clock.sleep(Duration::from_secs(2));
+        assert_eq!(noop.poll().unwrap(), Some(commit_req1));
 
         let mut commit_req2 = CommitRequest {
             positions: Default::default(),
         };
-        commit_req2.positions.insert(
-            partition2,
-            2001,
-        );
+        commit_req2
+            .positions
+            .insert(partition2, Position::new(2001, timestamp));
         noop.submit(m2).expect("Failed to submit");
-        assert_eq!(noop.poll(), None);
+        assert_eq!(noop.poll().unwrap(), None);
         assert_eq!(noop.join(Some(Duration::from_secs(5))), Some(commit_req2))
     }
+
+    #[test]
+    fn test_min_commit_count() {
+        let partition = Partition {
+            topic: Topic {
+                name: "noop-commit".to_string(),
+            },
+            index: 0,
+        };
+        let timestamp = DateTime::from(SystemTime::now());
+        let build_message = |offset| {
+            Message::new_broker_message(
+                KafkaPayload {
+                    key: None,
+                    headers: None,
+                    payload: None,
+                },
+                partition.clone(),
+                offset,
+                timestamp,
+            )
+        };
+
+        let mut strategy: Box<dyn ProcessingStrategy<KafkaPayload>> = Box::new(
+            commit_offsets::new_with_min_commit_count(Duration::from_secs(60), 2),
+        );
+
+        strategy.submit(build_message(0)).expect("Failed to submit");
+        assert_eq!(strategy.poll().unwrap(), None);
+
+        strategy.submit(build_message(1)).expect("Failed to submit");
+        assert_eq!(
+            strategy.poll().unwrap(),
+            Some(CommitRequest {
+                positions: HashMap::from([(partition.clone(), Position::new(2, timestamp))]),
+            })
+        );
+
+        // The counter is reset after each commit
+        strategy.submit(build_message(2)).expect("Failed to submit");
+        assert_eq!(strategy.poll().unwrap(), None);
+    }
+
+    #[test]
+    fn test_immediate_policy() {
+        let partition = Partition {
+            topic: Topic {
+                name: "noop-commit".to_string(),
+            },
+            index: 0,
+        };
+        let mut strategy: Box<dyn ProcessingStrategy<String>> =
+            Box::new(commit_offsets::new_with_policy(Box::new(Immediate)));
+        let timestamp = DateTime::from(SystemTime::now());
+
+        assert_eq!(strategy.poll().unwrap(), None);
+        strategy
+            .submit(Message::new_broker_message(
+                "payload".to_string(),
+                partition.clone(),
+                5,
+                timestamp,
+            ))
+            .expect("Failed to submit");
+        assert_eq!(
+            strategy.poll().unwrap(),
+            Some(CommitRequest {
+                positions: HashMap::from([(partition, Position::new(6, timestamp))]),
+            })
+        );
+    }
+
+    #[test]
+    fn test_offsets_never_move_backwards() {
+        let partition = Partition {
+            topic: Topic {
+                name: "noop-commit".to_string(),
+            },
+            index: 0,
+        };
+        let timestamp = DateTime::from(SystemTime::now());
+        let build_message = |offset| {
+            Message::new_broker_message("payload".to_string(), partition.clone(), offset, timestamp)
+        };
+        let mut strategy: Box<dyn ProcessingStrategy<String>> =
+            Box::new(commit_offsets::new_with_policy(Box::new(Immediate)));
+
+        strategy
+            .submit(build_message(10))
+            .expect("Failed to submit");
+        strategy.submit(build_message(5)).expect("Failed to submit");
+        assert_eq!(
+            strategy.poll().unwrap(),
+            Some(CommitRequest {
+                positions: HashMap::from([(partition.clone(), Position::new(11, timestamp))]),
+            })
+        );
+
+        // Also compared against what was already committed
+        strategy.submit(build_message(7)).expect("Failed to submit");
+        assert_eq!(strategy.poll().unwrap(), None);
+
+        strategy
+            .submit(build_message(12))
+            .expect("Failed to submit");
+        assert_eq!(
+            strategy.poll().unwrap(),
+            Some(CommitRequest {
+                positions: HashMap::from([(partition, Position::new(13, timestamp))]),
+            })
+        );
+    }
 }
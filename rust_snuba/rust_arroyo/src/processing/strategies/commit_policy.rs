@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+/// Decides when the offsets staged by a strategy should be committed.
+///
+/// A policy is consulted on every ``poll`` with the time elapsed since the
+/// last commit and the number of offsets staged since then. Forced commits
+/// (for example during ``join``) bypass the policy entirely.
+pub trait CommitPolicy: Send + Sync {
+    fn should_commit(&self, elapsed: Duration, messages_since_last_commit: u64) -> bool;
+}
+
+/// Commit once ``frequency`` has elapsed, or as soon as ``min_commit_count``
+/// offsets have been staged, whichever happens first.
+pub struct Periodic {
+    pub frequency: Duration,
+    pub min_commit_count: Option<u64>,
+}
+
+impl CommitPolicy for Periodic {
+    fn should_commit(&self, elapsed: Duration, messages_since_last_commit: u64) -> bool {
+        let min_count_reached = match self.min_commit_count {
+            Some(min_commit_count) => messages_since_last_commit >= min_commit_count,
+            None => false,
+        };
+        elapsed >= self.frequency || min_count_reached
+    }
+}
+
+/// Commit at most once per second. This is the default used by Snuba
+/// consumers.
+pub struct OncePerSecond;
+
+impl CommitPolicy for OncePerSecond {
+    fn should_commit(&self, elapsed: Duration, _: u64) -> bool {
+        elapsed >= Duration::from_secs(1)
+    }
+}
+
+/// Commit every time ``n`` offsets have been staged, regardless of time.
+pub struct EveryNMessages(pub u64);
+
+impl CommitPolicy for EveryNMessages {
+    fn should_commit(&self, _: Duration, messages_since_last_commit: u64) -> bool {
+        messages_since_last_commit >= self.0
+    }
+}
+
+/// Commit on every poll. Mostly useful for tests and low volume topics.
+pub struct Immediate;
+
+impl CommitPolicy for Immediate {
+    fn should_commit(&self, _: Duration, _: u64) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CommitPolicy, EveryNMessages, Immediate, OncePerSecond, Periodic};
+    use std::time::Duration;
+
+    #[test]
+    fn test_periodic() {
+        let policy = Periodic {
+            frequency: Duration::from_secs(5),
+            min_commit_count: Some(10),
+        };
+        assert!(!policy.should_commit(Duration::from_secs(1), 1));
+        assert!(policy.should_commit(Duration::from_secs(5), 1));
+        assert!(policy.should_commit(Duration::from_secs(1), 10));
+
+        let policy = Periodic {
+            frequency: Duration::from_secs(5),
+            min_commit_count: None,
+        };
+        assert!(!policy.should_commit(Duration::from_secs(1), 1000));
+    }
+
+    #[test]
+    fn test_once_per_second() {
+        assert!(!OncePerSecond.should_commit(Duration::from_millis(999), 1000));
+        assert!(OncePerSecond.should_commit(Duration::from_secs(1), 0));
+    }
+
+    #[test]
+    fn test_every_n_messages() {
+        let policy = EveryNMessages(3);
+        assert!(!policy.should_commit(Duration::from_secs(60), 2));
+        assert!(policy.should_commit(Duration::ZERO, 3));
+    }
+
+    #[test]
+    fn test_immediate() {
+        assert!(Immediate.should_commit(Duration::ZERO, 0));
+    }
+}
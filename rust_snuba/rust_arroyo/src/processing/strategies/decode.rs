@@ -0,0 +1,228 @@
+use crate::backends::kafka::types::KafkaPayload;
+use crate::codecs::{Codec, Decoded};
+use crate::processing::strategies::{
+    raise_invalid_message, report_invalid_message_on_join, CommitRequest, InvalidMessage,
+    MessageRejected, ProcessingStrategy, StrategyDescription, SubmitError,
+};
+use crate::types::Message;
+use log::warn;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Decodes payloads with ``codec`` and submits the decoded value, with the
+/// key and the headers of the message, to the next step.
+///
+/// Payloads that cannot be decoded are raised as ``InvalidMessage``. When
+/// the codec cannot decode a payload for now, for example because a schema
+/// registry cannot be reached, the message is rejected instead so it is
+/// submitted again later.
+pub struct Decode<T: Clone + Send + Sync> {
+    codec: Arc<dyn Codec<T>>,
+    next_step: Box<dyn ProcessingStrategy<Decoded<T>>>,
+    message_carried_over: Option<Message<Decoded<T>>>,
+}
+
+impl<T: Clone + Send + Sync> Decode<T> {
+    pub fn new(
+        codec: Arc<dyn Codec<T>>,
+        next_step: Box<dyn ProcessingStrategy<Decoded<T>>>,
+    ) -> Self {
+        Self {
+            codec,
+            next_step,
+            message_carried_over: None,
+        }
+    }
+
+    fn submit_carried_over(&mut self) -> Result<(), InvalidMessage> {
+        if let Some(message) = self.message_carried_over.take() {
+            match self.next_step.submit(message) {
+                Ok(()) => {}
+                Err(SubmitError::MessageRejected(MessageRejected { message })) => {
+                    self.message_carried_over = Some(message);
+                }
+                Err(SubmitError::InvalidMessage(invalid)) => return Err(invalid),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: Clone + Send + Sync> ProcessingStrategy<KafkaPayload> for Decode<T> {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        self.submit_carried_over()?;
+        self.next_step.poll()
+    }
+
+    fn submit(&mut self, message: Message<KafkaPayload>) -> Result<(), SubmitError<KafkaPayload>> {
+        if self.message_carried_over.is_some() {
+            return Err(SubmitError::MessageRejected(MessageRejected { message }));
+        }
+
+        let payload = message.payload();
+        let value = match self
+            .codec
+            .decode(payload.payload.as_deref().unwrap_or_default())
+        {
+            Ok(value) => value,
+            Err(error) if error.is_retriable() => {
+                warn!("Failed to decode {}: {}", message, error);
+                return Err(SubmitError::MessageRejected(MessageRejected { message }));
+            }
+            Err(error) => {
+                warn!("Failed to decode {}: {}", message, error);
+                return raise_invalid_message(&message);
+            }
+        };
+        let decoded = Decoded {
+            key: payload.key,
+            headers: payload.headers,
+            value,
+        };
+
+        match self.next_step.submit(message.replace(decoded)) {
+            Ok(()) => Ok(()),
+            Err(SubmitError::MessageRejected(MessageRejected { message })) => {
+                self.message_carried_over = Some(message);
+                Ok(())
+            }
+            Err(SubmitError::InvalidMessage(invalid)) => Err(invalid.into()),
+        }
+    }
+
+    fn close(&mut self) {
+        self.next_step.close()
+    }
+
+    fn terminate(&mut self) {
+        self.next_step.terminate()
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        if let Err(invalid) = self.submit_carried_over() {
+            report_invalid_message_on_join("Decode", &invalid);
+        }
+        self.next_step.join(timeout)
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription::new("Decode")
+            .with_buffered_messages(self.message_carried_over.is_some() as usize)
+            .with_next_step(self.next_step.describe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Decode;
+    use crate::backends::kafka::types::{Headers, KafkaPayload};
+    use crate::codecs::avro::{AvroCodec, SchemaRegistry};
+    use crate::codecs::json::JsonCodec;
+    use crate::codecs::Decoded;
+    use crate::processing::strategies::testutils::{partition, Recorder};
+    use crate::processing::strategies::{ProcessingStrategy, SubmitError};
+    use crate::types::{Message, Position};
+    use apache_avro::types::Value;
+    use apache_avro::{to_avro_datum, Schema};
+    use chrono::Utc;
+    use std::collections::BTreeMap;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    fn message(payload: Vec<u8>, offset: u64) -> Message<KafkaPayload> {
+        let partition = partition("test", 0);
+        let payload = KafkaPayload {
+            key: None,
+            headers: None,
+            payload: Some(payload.into()),
+        };
+        Message::new_broker_message(payload, partition, offset, Utc::now())
+    }
+
+    #[test]
+    fn test_decode() {
+        let recorder = Recorder::<Decoded<String>>::default();
+        let submitted = recorder.submitted.clone();
+        let mut strategy = Decode::new(Arc::new(JsonCodec), Box::new(recorder));
+
+        let mut headers = Headers::new();
+        headers.insert("received", b"1".to_vec());
+        let payload = KafkaPayload {
+            key: Some(b"key".to_vec().into()),
+            headers: Some(headers.clone()),
+            payload: Some(b"\"hello\"".to_vec().into()),
+        };
+        strategy
+            .submit(Message::new_broker_message(
+                payload,
+                partition("test", 0),
+                0,
+                Utc::now(),
+            ))
+            .unwrap();
+        assert_eq!(
+            submitted.payloads(),
+            vec![Decoded {
+                key: Some(b"key".to_vec().into()),
+                headers: Some(headers),
+                value: "hello".to_string(),
+            }]
+        );
+
+        match strategy.submit(message(b"{".to_vec(), 1)) {
+            Err(SubmitError::InvalidMessage(invalid)) => assert_eq!(invalid.offset, 1),
+            _ => panic!("Expected an InvalidMessage error"),
+        }
+
+        // A batch is raised as the last message it commits.
+        let committable = BTreeMap::from([(partition("test", 0), Position::new(5, Utc::now()))]);
+        let payload = message(b"{".to_vec(), 4).payload();
+        match strategy.submit(Message::new_any_message(payload, committable)) {
+            Err(SubmitError::InvalidMessage(invalid)) => assert_eq!(invalid.offset, 4),
+            _ => panic!("Expected an InvalidMessage error"),
+        }
+    }
+
+    #[test]
+    fn test_decode_carries_over_rejected_messages() {
+        let recorder = Recorder::<Decoded<String>>::default();
+        recorder.reject.store(true, Ordering::Relaxed);
+        let mut strategy = Decode::new(Arc::new(JsonCodec), Box::new(recorder));
+        strategy.submit(message(b"\"hello\"".to_vec(), 0)).unwrap();
+        assert!(matches!(
+            strategy.submit(message(b"\"world\"".to_vec(), 1)),
+            Err(SubmitError::MessageRejected(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_avro() {
+        let schema = Schema::parse_str(r#"{"type": "string"}"#).unwrap();
+        let registry = Arc::new(SchemaRegistry::new("http://127.0.0.1:1"));
+        registry.add_schema(1, schema.clone());
+        let recorder = Recorder::<Decoded<String>>::default();
+        let submitted = recorder.submitted.clone();
+        let mut strategy = Decode::new(Arc::new(AvroCodec::new(registry)), Box::new(recorder));
+
+        let datum = to_avro_datum(&schema, Value::String("hello".to_string())).unwrap();
+        let payload = |schema_id: u8, datum: &[u8]| {
+            let mut payload = vec![0, 0, 0, 0, schema_id];
+            payload.extend(datum);
+            payload
+        };
+        strategy.submit(message(payload(1, &datum), 0)).unwrap();
+        assert_eq!(submitted.payloads()[0].value, "hello");
+
+        // Truncated datum
+        match strategy.submit(message(payload(1, &datum[..2]), 1)) {
+            Err(SubmitError::InvalidMessage(invalid)) => assert_eq!(invalid.offset, 1),
+            _ => panic!("Expected an InvalidMessage error"),
+        }
+
+        // The schema cannot be fetched
+        assert!(matches!(
+            strategy.submit(message(payload(2, &datum), 2)),
+            Err(SubmitError::MessageRejected(_))
+        ));
+    }
+}
@@ -0,0 +1,236 @@
+use crate::processing::strategies::filter::Filter;
+use crate::processing::strategies::{
+    CommitRequest, InvalidMessage, ProcessingStrategy, StrategyDescription, SubmitError,
+};
+use crate::types::Message;
+use crate::utils::clock::{Clock, SystemClock};
+use crate::utils::metrics;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Returns the bytes messages are deduplicated by, such as the key of the
+/// message or an id extracted from its payload.
+pub type DedupeKey<TPayload> = Arc<dyn Fn(&TPayload) -> Vec<u8> + Send + Sync>;
+
+// The most recently seen keys, up to ``capacity`` of them.
+struct SeenKeys {
+    capacity: usize,
+    window: Option<Duration>,
+    // The sequence number and time every key was last seen at.
+    keys: HashMap<Vec<u8>, (u64, SystemTime)>,
+    // The keys by sequence number, the least recently seen first.
+    by_recency: BTreeMap<u64, Vec<u8>>,
+    next_sequence: u64,
+}
+
+impl SeenKeys {
+    fn new(capacity: usize) -> Self {
+        SeenKeys {
+            capacity,
+            window: None,
+            keys: HashMap::new(),
+            by_recency: BTreeMap::new(),
+            next_sequence: 0,
+        }
+    }
+
+    fn contains(&mut self, key: &[u8], now: SystemTime) -> bool {
+        if let Some(window) = self.window {
+            while let Some((_, oldest)) = self.by_recency.first_key_value() {
+                let (_, seen_at) = self.keys[oldest];
+                if now.duration_since(seen_at).unwrap_or_default() < window {
+                    break;
+                }
+                let (_, oldest) = self.by_recency.pop_first().unwrap();
+                self.keys.remove(&oldest);
+            }
+        }
+        self.keys.contains_key(key)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, now: SystemTime) {
+        if let Some((sequence, _)) = self.keys.remove(&key) {
+            self.by_recency.remove(&sequence);
+        }
+        self.keys.insert(key.clone(), (self.next_sequence, now));
+        self.by_recency.insert(self.next_sequence, key);
+        self.next_sequence += 1;
+
+        while self.keys.len() > self.capacity {
+            let (_, oldest) = self.by_recency.pop_first().unwrap();
+            self.keys.remove(&oldest);
+        }
+    }
+}
+
+/// Drops the messages whose ``key`` is one of the ``capacity`` keys seen
+/// most recently, for the datasets that cannot store a row twice when a
+/// producer sends a message again. Dropped messages increment
+/// ``arroyo.strategies.dedupe.duplicates``.
+///
+/// With ``with_window`` keys are also forgotten once they were not seen for
+/// that long. A key only counts as seen once the next step accepted its
+/// message, so a message that was rejected is not dropped when it is
+/// submitted again. Keys are kept in memory, duplicates are not detected
+/// across consumers nor after a restart.
+pub struct Dedupe<TPayload: Clone> {
+    key: DedupeKey<TPayload>,
+    filter: Filter<TPayload>,
+    seen: SeenKeys,
+    clock: Box<dyn Clock>,
+}
+
+impl<TPayload: Clone + Send + Sync + 'static> Dedupe<TPayload> {
+    pub fn new(
+        key: DedupeKey<TPayload>,
+        capacity: usize,
+        next_step: Box<dyn ProcessingStrategy<TPayload>>,
+    ) -> Self {
+        assert!(capacity > 0, "the capacity must be positive");
+        Dedupe {
+            key,
+            filter: Filter::new(Arc::new(|_: &TPayload| true), next_step),
+            seen: SeenKeys::new(capacity),
+            clock: Box::new(SystemClock {}),
+        }
+    }
+
+    /// Forgets the keys that were not seen for ``window``.
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.seen.window = Some(window);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+}
+
+impl<TPayload: Clone + Send + Sync> ProcessingStrategy<TPayload> for Dedupe<TPayload> {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        self.filter.poll()
+    }
+
+    fn submit(&mut self, message: Message<TPayload>) -> Result<(), SubmitError<TPayload>> {
+        let key = (self.key)(&message.payload());
+        let now = self.clock.time();
+        if self.seen.contains(&key, now) {
+            metrics::increment("arroyo.strategies.dedupe.duplicates", None, None, None);
+            self.seen.insert(key, now);
+            self.filter.drop_message(message);
+            return Ok(());
+        }
+
+        self.filter.forward(message)?;
+        self.seen.insert(key, now);
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.filter.close();
+    }
+
+    fn terminate(&mut self) {
+        self.filter.terminate();
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        self.filter.join(timeout)
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription {
+            name: "Dedupe".to_string(),
+            ..self.filter.describe()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Dedupe;
+    use crate::processing::strategies::testutils::{partition, Recorder};
+    use crate::processing::strategies::{MessageRejected, ProcessingStrategy, SubmitError};
+    use crate::types::Message;
+    use crate::utils::clock::{Clock, TestingClock};
+    use chrono::Utc;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    // Submits the payloads at consecutive offsets, starting at ``offset``.
+    fn submit_all(strategy: &mut Dedupe<u64>, offset: u64, payloads: &[u64]) {
+        for (i, payload) in payloads.iter().enumerate() {
+            strategy
+                .submit(Message::new_broker_message(
+                    *payload,
+                    partition("test", 0),
+                    offset + i as u64,
+                    Utc::now(),
+                ))
+                .unwrap();
+        }
+    }
+
+    fn dedupe(capacity: usize, next_step: Recorder<u64>) -> Dedupe<u64> {
+        Dedupe::new(
+            Arc::new(|value: &u64| value.to_be_bytes().to_vec()),
+            capacity,
+            Box::new(next_step),
+        )
+    }
+
+    #[test]
+    fn test_dedupe() {
+        let recorder = Recorder::default();
+        let submitted = recorder.submitted.clone();
+        let mut strategy = dedupe(2, recorder);
+
+        submit_all(&mut strategy, 0, &[1, 2, 1, 2, 3]);
+        assert_eq!(submitted.payloads(), vec![1, 2, 3]);
+
+        // 2 and 3 are the most recently seen keys, 1 was evicted.
+        submit_all(&mut strategy, 5, &[1, 3]);
+        assert_eq!(submitted.payloads(), vec![1, 2, 3, 1]);
+    }
+
+    #[test]
+    fn test_dedupe_window() {
+        let clock = TestingClock::new(SystemTime::UNIX_EPOCH);
+        let recorder = Recorder::default();
+        let submitted = recorder.submitted.clone();
+        let mut strategy = dedupe(10, recorder)
+            .with_window(Duration::from_secs(60))
+            .with_clock(clock.clone());
+
+        submit_all(&mut strategy, 0, &[1, 2]);
+        clock.sleep(Duration::from_secs(40));
+        submit_all(&mut strategy, 2, &[2]);
+        // 1 was last seen a minute ago, seeing 2 again kept it.
+        clock.sleep(Duration::from_secs(20));
+        submit_all(&mut strategy, 3, &[1, 2]);
+        assert_eq!(submitted.payloads(), vec![1, 2, 1]);
+    }
+
+    #[test]
+    fn test_dedupe_rejected() {
+        let recorder = Recorder::default();
+        let (submitted, reject) = (recorder.submitted.clone(), recorder.reject.clone());
+        let mut strategy = dedupe(10, recorder);
+
+        reject.store(true, Ordering::Relaxed);
+        let message = Message::new_broker_message(1, partition("test", 0), 0, Utc::now());
+        let Err(SubmitError::MessageRejected(MessageRejected { message })) =
+            strategy.submit(message)
+        else {
+            panic!("the message should be rejected");
+        };
+
+        // The rejected message is not a duplicate of itself.
+        reject.store(false, Ordering::Relaxed);
+        strategy.submit(message).unwrap();
+        assert_eq!(submitted.payloads(), vec![1]);
+    }
+}
@@ -0,0 +1,135 @@
+use crate::processing::strategies::filter::Filter;
+use crate::processing::strategies::{
+    CommitRequest, InvalidMessage, ProcessingStrategy, StrategyDescription, SubmitError,
+};
+use crate::types::{InnerMessage, Message};
+use crate::utils::clock::{Clock, SystemClock};
+use crate::utils::metrics;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Drops the messages that were produced more than ``max_age`` ago, so that
+/// a consumer catching up on a large backlog skips the data that would be
+/// dropped by the TTL of the table anyway. Dropped messages increment
+/// ``arroyo.strategies.drop_stale.dropped``, tagged by topic and partition.
+///
+/// The age is measured against the timestamp of the broker message, messages
+/// built out of several ones have no timestamp and are always forwarded.
+pub struct DropStale<TPayload: Clone> {
+    max_age: Duration,
+    filter: Filter<TPayload>,
+    clock: Box<dyn Clock>,
+}
+
+impl<TPayload: Clone + Send + Sync + 'static> DropStale<TPayload> {
+    pub fn new(max_age: Duration, next_step: Box<dyn ProcessingStrategy<TPayload>>) -> Self {
+        DropStale {
+            max_age,
+            filter: Filter::new(Arc::new(|_: &TPayload| true), next_step),
+            clock: Box::new(SystemClock {}),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+}
+
+impl<TPayload: Clone + Send + Sync> ProcessingStrategy<TPayload> for DropStale<TPayload> {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        self.filter.poll()
+    }
+
+    fn submit(&mut self, message: Message<TPayload>) -> Result<(), SubmitError<TPayload>> {
+        let InnerMessage::BrokerMessage(broker_message) = &message.inner_message else {
+            return self.filter.forward(message);
+        };
+        let now = DateTime::<Utc>::from(self.clock.time());
+        let stale = (now - broker_message.timestamp)
+            .to_std()
+            .is_ok_and(|age| age > self.max_age);
+        if !stale {
+            return self.filter.forward(message);
+        }
+
+        let index = broker_message.partition.index.to_string();
+        metrics::increment(
+            "arroyo.strategies.drop_stale.dropped",
+            None,
+            Some(HashMap::from([
+                ("topic", broker_message.partition.topic.name.as_str()),
+                ("partition", index.as_str()),
+            ])),
+            None,
+        );
+        self.filter.drop_message(message);
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.filter.close();
+    }
+
+    fn terminate(&mut self) {
+        self.filter.terminate();
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        self.filter.join(timeout)
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription {
+            name: "DropStale".to_string(),
+            ..self.filter.describe()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DropStale;
+    use crate::processing::strategies::testutils::{partition, Recorder};
+    use crate::processing::strategies::ProcessingStrategy;
+    use crate::types::Message;
+    use crate::utils::clock::TestingClock;
+    use chrono::{DateTime, Utc};
+    use std::collections::BTreeMap;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_drop_stale() {
+        let partition = partition("test", 0);
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(86400);
+        let recorder = Recorder::committing();
+        let submitted = recorder.submitted.clone();
+        let mut strategy = DropStale::new(Duration::from_secs(3600), Box::new(recorder))
+            .with_clock(TestingClock::new(now));
+
+        let ages = [7200, 60, 3601, 0];
+        for (offset, age) in ages.into_iter().enumerate() {
+            let timestamp = DateTime::<Utc>::from(now - Duration::from_secs(age));
+            strategy
+                .submit(Message::new_broker_message(
+                    offset as u64,
+                    partition.clone(),
+                    offset as u64,
+                    timestamp,
+                ))
+                .unwrap();
+        }
+        assert_eq!(submitted.payloads(), vec![1, 3]);
+
+        // Messages without a timestamp are forwarded.
+        strategy
+            .submit(Message::new_any_message(4, BTreeMap::new()))
+            .unwrap();
+        assert_eq!(submitted.payloads(), vec![1, 3, 4]);
+
+        let request = strategy.poll().unwrap().unwrap();
+        assert_eq!(request.positions[&partition].offset, 4);
+    }
+}
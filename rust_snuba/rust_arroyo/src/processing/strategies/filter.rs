@@ -0,0 +1,226 @@
+use crate::processing::strategies::{
+    merge_commit_request, CommitRequest, InvalidMessage, ProcessingStrategy, StrategyDescription,
+    SubmitError,
+};
+use crate::types::{Message, Partition, Position};
+use crate::utils::metrics;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub type Predicate<TPayload> = Arc<dyn Fn(&TPayload) -> bool + Send + Sync>;
+
+/// Forwards the messages that satisfy ``predicate`` to the next step and
+/// drops the others.
+///
+/// The offsets of dropped messages still have to be committed. They are
+/// added to the commit requests returned by ``poll`` once every message
+/// forwarded before them on the same partition has been committed by the
+/// next step, so that dropping a message never commits past one that is
+/// still being processed. ``Sample``, ``Dedupe`` and ``DropStale`` are
+/// built on this one and commit the messages they drop the same way.
+///
+/// The messages ``predicate`` drops, including those of ``Sample``, are
+/// counted in ``arroyo.strategies.filter.dropped_messages``. ``Dedupe`` and
+/// ``DropStale`` count theirs in their own metric.
+pub struct Filter<TPayload: Clone> {
+    predicate: Predicate<TPayload>,
+    next_step: Box<dyn ProcessingStrategy<TPayload>>,
+    // Highest committable offset per partition of the messages forwarded to
+    // the next step and not committed yet.
+    forwarded: BTreeMap<Partition, u64>,
+    // Highest committable position per partition of the dropped messages
+    // that were not committed yet.
+    dropped: BTreeMap<Partition, Position>,
+}
+
+impl<TPayload: Clone + Send + Sync> Filter<TPayload> {
+    pub fn new(
+        predicate: Predicate<TPayload>,
+        next_step: Box<dyn ProcessingStrategy<TPayload>>,
+    ) -> Self {
+        Filter {
+            predicate,
+            next_step,
+            forwarded: BTreeMap::new(),
+            dropped: BTreeMap::new(),
+        }
+    }
+
+    /// Adds the offsets of dropped messages that can be safely committed to
+    /// the commit request of the next step.
+    fn merge_dropped(&mut self, request: Option<CommitRequest>) -> Option<CommitRequest> {
+        if let Some(request) = &request {
+            for (partition, position) in &request.positions {
+                if self
+                    .forwarded
+                    .get(partition)
+                    .is_some_and(|f| *f <= position.offset)
+                {
+                    self.forwarded.remove(partition);
+                }
+            }
+        }
+
+        let mut positions = HashMap::new();
+        let forwarded = &self.forwarded;
+        self.dropped.retain(|partition, position| {
+            if forwarded.contains_key(partition) {
+                return true;
+            }
+            positions.insert(partition.clone(), *position);
+            false
+        });
+
+        if positions.is_empty() {
+            request
+        } else {
+            merge_commit_request(request, Some(CommitRequest { positions }))
+        }
+    }
+
+    /// Drops the message without asking ``predicate``, for the strategies
+    /// built on top of this one that decide themselves what is dropped.
+    pub(crate) fn drop_message(&mut self, message: Message<TPayload>) {
+        for (partition, position) in message.committable() {
+            self.dropped.insert(partition, position);
+        }
+    }
+
+    /// Submits the message to the next step without asking ``predicate``.
+    pub(crate) fn forward(
+        &mut self,
+        message: Message<TPayload>,
+    ) -> Result<(), SubmitError<TPayload>> {
+        let committable = message.committable();
+        self.next_step.submit(message)?;
+        for (partition, position) in committable {
+            // Committing the forwarded message also commits any message
+            // dropped before it.
+            if self
+                .dropped
+                .get(&partition)
+                .is_some_and(|d| d.offset <= position.offset)
+            {
+                self.dropped.remove(&partition);
+            }
+            self.forwarded.insert(partition, position.offset);
+        }
+        Ok(())
+    }
+}
+
+impl<TPayload: Clone + Send + Sync> ProcessingStrategy<TPayload> for Filter<TPayload> {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        let request = self.next_step.poll()?;
+        Ok(self.merge_dropped(request))
+    }
+
+    fn submit(&mut self, message: Message<TPayload>) -> Result<(), SubmitError<TPayload>> {
+        if !(self.predicate)(&message.payload()) {
+            metrics::increment(
+                "arroyo.strategies.filter.dropped_messages",
+                None,
+                None,
+                None,
+            );
+            self.drop_message(message);
+            return Ok(());
+        }
+        self.forward(message)
+    }
+
+    fn close(&mut self) {
+        self.next_step.close();
+    }
+
+    fn terminate(&mut self) {
+        self.next_step.terminate();
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        let request = self.next_step.join(timeout);
+        self.merge_dropped(request)
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription::new("Filter").with_next_step(self.next_step.describe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Filter;
+    use crate::processing::strategies::testutils::{partition, Recorder};
+    use crate::processing::strategies::{CommitRequest, ProcessingStrategy};
+    use crate::types::{Message, Position};
+    use chrono::Utc;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_filter() {
+        let partition = partition("test", 0);
+        let now = Utc::now();
+        let recorder = Recorder::committing();
+        let submitted = recorder.submitted.clone();
+        let mut filter = Filter::new(
+            Arc::new(|value: &u64| ![1, 3].contains(value)),
+            Box::new(recorder),
+        );
+
+        for offset in 0..3 {
+            filter
+                .submit(Message::new_broker_message(
+                    offset,
+                    partition.clone(),
+                    offset,
+                    now,
+                ))
+                .unwrap();
+        }
+        assert_eq!(submitted.payloads(), vec![0, 2]);
+        assert_eq!(
+            filter.poll().unwrap(),
+            Some(CommitRequest {
+                positions: HashMap::from([(partition.clone(), Position::new(3, now))])
+            })
+        );
+
+        // A dropped message is committed once nothing forwarded before it
+        // is outstanding.
+        filter
+            .submit(Message::new_broker_message(3, partition.clone(), 3, now))
+            .unwrap();
+        assert_eq!(
+            filter.poll().unwrap(),
+            Some(CommitRequest {
+                positions: HashMap::from([(partition.clone(), Position::new(4, now))])
+            })
+        );
+        assert_eq!(filter.poll().unwrap(), None);
+    }
+
+    #[test]
+    fn test_dropped_waits_for_forwarded() {
+        let partition = partition("test", 0);
+        let mut filter = Filter::new(
+            Arc::new(|value: &u64| *value == 0),
+            Box::new(Recorder::default()),
+        );
+
+        for offset in 0..2 {
+            filter
+                .submit(Message::new_broker_message(
+                    offset,
+                    partition.clone(),
+                    offset,
+                    Utc::now(),
+                ))
+                .unwrap();
+        }
+        // Message 0 is still in flight so the dropped message 1 cannot be
+        // committed.
+        assert_eq!(filter.poll().unwrap(), None);
+    }
+}
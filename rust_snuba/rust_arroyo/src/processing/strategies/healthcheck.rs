@@ -0,0 +1,110 @@
+use crate::processing::strategies::{
+    CommitRequest, InvalidMessage, ProcessingStrategy, StrategyDescription, SubmitError,
+};
+use crate::types::Message;
+use log::warn;
+use std::fs::File;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+const TOUCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Touches ``path`` every time ``poll`` completes successfully, at most once
+/// per second, so that a liveness probe checking the modification time of
+/// the file can detect a consumer that stopped making progress.
+///
+/// The file is not touched if the next step fails, a consumer that keeps
+/// running into invalid messages is not considered healthy.
+pub struct Healthcheck<TPayload: Clone> {
+    path: PathBuf,
+    next_step: Box<dyn ProcessingStrategy<TPayload>>,
+    last_touch: Option<Instant>,
+}
+
+impl<TPayload: Clone> Healthcheck<TPayload> {
+    pub fn new(path: impl Into<PathBuf>, next_step: Box<dyn ProcessingStrategy<TPayload>>) -> Self {
+        Self {
+            path: path.into(),
+            next_step,
+            last_touch: None,
+        }
+    }
+
+    fn maybe_touch(&mut self) {
+        let now = Instant::now();
+        if self
+            .last_touch
+            .is_some_and(|last_touch| now.duration_since(last_touch) < TOUCH_INTERVAL)
+        {
+            return;
+        }
+
+        let touched = File::options()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|file| file.set_modified(SystemTime::now()));
+        match touched {
+            Ok(()) => self.last_touch = Some(now),
+            Err(error) => warn!(
+                "Failed to touch healthcheck file {}: {}",
+                self.path.display(),
+                error
+            ),
+        }
+    }
+}
+
+impl<TPayload: Clone> ProcessingStrategy<TPayload> for Healthcheck<TPayload> {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        let request = self.next_step.poll()?;
+        self.maybe_touch();
+        Ok(request)
+    }
+
+    fn submit(&mut self, message: Message<TPayload>) -> Result<(), SubmitError<TPayload>> {
+        self.next_step.submit(message)
+    }
+
+    fn close(&mut self) {
+        self.next_step.close();
+    }
+
+    fn terminate(&mut self) {
+        self.next_step.terminate();
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        self.next_step.join(timeout)
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription::new("Healthcheck").with_next_step(self.next_step.describe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Healthcheck;
+    use crate::processing::strategies::testutils::Recorder;
+    use crate::processing::strategies::ProcessingStrategy;
+    use std::fs;
+
+    #[test]
+    fn test_healthcheck() {
+        let path = std::env::temp_dir().join(format!("arroyo-healthcheck-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut strategy = Healthcheck::new(&path, Box::new(Recorder::<u64>::default()));
+        assert!(!path.exists());
+
+        strategy.poll().unwrap();
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+
+        // Polling again right away does not touch the file
+        strategy.poll().unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), modified);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,21 +1,220 @@
-use crate::types::{Message, Partition};
+use crate::types::{BrokerMessage, InnerMessage, Message, Partition, Position};
+use crate::utils::metrics;
+use serde::Serialize;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
+use thiserror::Error;
 
 pub mod commit_offsets;
-pub mod transform;
+pub mod commit_policy;
+pub mod decode;
+pub mod dedupe;
+pub mod drop_stale;
+pub mod filter;
+pub mod healthcheck;
 pub mod produce;
+pub mod rate_limit;
+pub mod reduce;
+pub mod retry;
+pub mod router;
+pub mod run_task;
+pub mod run_task_in_async_tasks;
+pub mod run_task_in_threads;
+pub mod sample;
+pub mod strategy_metrics;
+pub mod tee;
+#[cfg(any(test, feature = "testutils"))]
+pub mod testutils;
+pub mod throttle_commits;
+pub mod timeout;
+pub mod trace_context;
+pub mod transform;
 
+/// Returned by ``submit`` when a strategy cannot accept a message. The
+/// rejected message is handed back so the caller can hold on to it and
+/// submit it again later.
 #[derive(Debug, Clone)]
-pub struct MessageRejected;
+pub struct MessageRejected<T: Clone> {
+    pub message: Message<T>,
+}
+
+/// Identifies a message that cannot be processed, for example because its
+/// payload cannot be decoded.
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("Invalid message at offset {offset} of {partition}")]
+pub struct InvalidMessage {
+    pub partition: Partition,
+    pub offset: u64,
+}
+
+impl<T: Clone> From<&BrokerMessage<T>> for InvalidMessage {
+    fn from(message: &BrokerMessage<T>) -> Self {
+        InvalidMessage {
+            partition: message.partition.clone(),
+            offset: message.offset,
+        }
+    }
+}
+
+impl InvalidMessage {
+    /// Returns the invalid message to raise for ``message``. A message built
+    /// out of several ones, such as a batch, is raised as the last message
+    /// it commits of its first partition. It is ``None`` when the message
+    /// does not commit anything, there is then nothing to dead letter.
+    pub fn for_message<T: Clone>(message: &Message<T>) -> Option<Self> {
+        match &message.inner_message {
+            InnerMessage::BrokerMessage(broker_message) => Some(broker_message.into()),
+            InnerMessage::AnyMessage(any_message) => {
+                let (partition, position) = any_message.committable.iter().next()?;
+                Some(InvalidMessage {
+                    partition: partition.clone(),
+                    offset: position.offset.checked_sub(1)?,
+                })
+            }
+        }
+    }
+}
+
+/// Returns the error ``submit`` raises for an invalid ``message``, see
+/// ``InvalidMessage::for_message``. A message that does not commit any
+/// offset cannot be dead lettered, it is dropped with an error instead.
+pub fn raise_invalid_message<T: Clone, TMessage: Clone>(
+    message: &Message<TMessage>,
+) -> Result<(), SubmitError<T>> {
+    match InvalidMessage::for_message(message) {
+        Some(invalid) => Err(SubmitError::InvalidMessage(invalid)),
+        None => {
+            log::error!(
+                "Dropping the invalid {}, it does not commit any offset",
+                message
+            );
+            Ok(())
+        }
+    }
+}
 
+/// Returned by ``submit`` when a strategy does not process a message.
 #[derive(Debug, Clone)]
-pub struct InvalidMessage;
+pub enum SubmitError<T: Clone> {
+    MessageRejected(MessageRejected<T>),
+    InvalidMessage(InvalidMessage),
+}
+
+impl<T: Clone> From<MessageRejected<T>> for SubmitError<T> {
+    fn from(value: MessageRejected<T>) -> Self {
+        SubmitError::MessageRejected(value)
+    }
+}
+
+impl<T: Clone> From<InvalidMessage> for SubmitError<T> {
+    fn from(value: InvalidMessage) -> Self {
+        SubmitError::InvalidMessage(value)
+    }
+}
 
 /// Signals that we need to commit offsets
 #[derive(Debug, Clone, PartialEq)]
 pub struct CommitRequest {
-    pub positions: HashMap<Partition, u64>,
+    pub positions: HashMap<Partition, Position>,
+}
+
+impl CommitRequest {
+    /// The offsets to commit to the consumer.
+    pub fn offsets(&self) -> HashMap<Partition, u64> {
+        self.positions
+            .iter()
+            .map(|(partition, position)| (partition.clone(), position.offset))
+            .collect()
+    }
+}
+
+/// Combines two optional commit requests, keeping the furthest position of
+/// each partition.
+pub fn merge_commit_request(
+    value: Option<CommitRequest>,
+    other: Option<CommitRequest>,
+) -> Option<CommitRequest> {
+    match (value, other) {
+        (None, None) => None,
+        (Some(a), None) => Some(a),
+        (None, Some(b)) => Some(b),
+        (Some(mut a), Some(b)) => {
+            for (partition, position) in b.positions {
+                let entry = a.positions.entry(partition).or_insert(position);
+                *entry = entry.max(position);
+            }
+            Some(a)
+        }
+    }
+}
+
+thread_local! {
+    // The invalid messages reported while the stream processor joins a
+    // strategy, see ``collect_invalid_on_join``.
+    static INVALID_ON_JOIN: RefCell<Option<Vec<InvalidMessage>>> = const { RefCell::new(None) };
+}
+
+/// Reports a message a strategy found invalid while it was joined, which
+/// ``join`` cannot return. It is logged and counted, in
+/// ``arroyo.strategies.join.invalid_message`` tagged by strategy, and dead
+/// lettered by the stream processor once the strategy is joined if it has a
+/// DLQ policy. It is skipped otherwise.
+pub fn report_invalid_message_on_join(strategy: &str, invalid: &InvalidMessage) {
+    log::error!("{} raised during join of {}", invalid, strategy);
+    metrics::increment(
+        "arroyo.strategies.join.invalid_message",
+        None,
+        Some(HashMap::from([("strategy", strategy)])),
+        None,
+    );
+    INVALID_ON_JOIN.with(|reported| {
+        if let Some(reported) = reported.borrow_mut().as_mut() {
+            reported.push(invalid.clone());
+        }
+    });
+}
+
+/// Runs ``join`` and returns its result with the invalid messages that were
+/// reported meanwhile on this thread.
+pub(crate) fn collect_invalid_on_join<R>(join: impl FnOnce() -> R) -> (R, Vec<InvalidMessage>) {
+    let outer = INVALID_ON_JOIN.with(|reported| reported.replace(Some(Vec::new())));
+    let result = join();
+    let reported = INVALID_ON_JOIN.with(|reported| reported.replace(outer));
+    (result, reported.unwrap_or_default())
+}
+
+/// What a strategy reports about itself in the state of the stream
+/// processor, see ``ProcessingStrategy::describe``.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct StrategyDescription {
+    pub name: String,
+    /// The messages the strategy holds on to, for those that buffer any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buffered_messages: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub next_steps: Vec<StrategyDescription>,
+}
+
+impl StrategyDescription {
+    pub fn new(name: &str) -> Self {
+        StrategyDescription {
+            name: name.to_string(),
+            buffered_messages: None,
+            next_steps: Vec::new(),
+        }
+    }
+
+    pub fn with_buffered_messages(mut self, buffered_messages: usize) -> Self {
+        self.buffered_messages = Some(buffered_messages);
+        self
+    }
+
+    pub fn with_next_step(mut self, next_step: StrategyDescription) -> Self {
+        self.next_steps.push(next_step);
+        self
+    }
 }
 
 /// A processing strategy defines how a stream processor processes messages
@@ -35,8 +234,11 @@ pub trait ProcessingStrategy<TPayload: Clone>: Send + Sync {
     /// consumer poll interval timeout.
     ///
     /// This method may raise exceptions that were thrown by asynchronous
-    /// tasks since the previous call to ``poll``.
-    fn poll(&mut self) -> Option<CommitRequest>;
+    /// tasks since the previous call to ``poll``. An ``InvalidMessage``
+    /// error is returned if a message submitted earlier turned out to be
+    /// invalid, the stream processor then handles it like an error returned
+    /// by ``submit``.
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage>;
 
     /// Submit a message for processing.
     ///
@@ -46,9 +248,12 @@ pub trait ProcessingStrategy<TPayload: Clone>: Send + Sync {
     /// implies that the message was successfully processed.
     ///
     /// If the processing strategy is unable to accept a message (due to it
-    /// being at or over capacity, for example), this method will raise a
-    /// ``MessageRejected`` exception.
-    fn submit(&mut self, message: Message<TPayload>) -> Result<(), MessageRejected>;
+    /// being at or over capacity, for example), this method will return a
+    /// ``MessageRejected`` error carrying the message back to the caller.
+    ///
+    /// If the message cannot be processed at all, this method returns an
+    /// ``InvalidMessage`` error identifying it.
+    fn submit(&mut self, message: Message<TPayload>) -> Result<(), SubmitError<TPayload>>;
 
     /// Close this instance. No more messages should be accepted by the
     /// instance after this method has been called.
@@ -74,7 +279,18 @@ pub trait ProcessingStrategy<TPayload: Clone>: Send + Sync {
     /// until this function exits, allowing any work in progress to be
     /// completed and committed before the continuing the rebalancing
     /// process.
+    ///
+    /// Strategies that wrap a next step must only pass on the part of the
+    /// timeout they did not use themselves (see ``utils::timing::Deadline``)
+    /// so that the whole chain is bounded by the original timeout.
     fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest>;
+
+    /// Describes the strategy and the steps after it, for debugging a
+    /// consumer that stalls. Strategies that wrap a next step should include
+    /// its description, and those that buffer messages how many they hold.
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription::new(std::any::type_name::<Self>())
+    }
 }
 
 pub trait ProcessingStrategyFactory<TPayload: Clone>: Send + Sync {
@@ -83,4 +299,33 @@ pub trait ProcessingStrategyFactory<TPayload: Clone>: Send + Sync {
     /// :param commit: A function that accepts a mapping of ``Partition``
     /// instances to offset values that should be committed.
     fn create(&self) -> Box<dyn ProcessingStrategy<TPayload>>;
+
+    /// Instantiate a ``ProcessingStrategy`` for a new assignment. This is
+    /// what the stream processor calls every time partitions are assigned,
+    /// with the partitions now owned by the consumer and their starting
+    /// offsets. Factories that do not care about the assignment only need
+    /// to implement ``create``.
+    fn create_with_partitions(
+        &self,
+        _partitions: HashMap<Partition, u64>,
+    ) -> Box<dyn ProcessingStrategy<TPayload>> {
+        self.create()
+    }
+}
+
+/// A factory can be shared by several processors, such as the processors a
+/// ``Supervisor`` builds one after the other.
+impl<TPayload: Clone, F: ProcessingStrategyFactory<TPayload> + ?Sized>
+    ProcessingStrategyFactory<TPayload> for Arc<F>
+{
+    fn create(&self) -> Box<dyn ProcessingStrategy<TPayload>> {
+        (**self).create()
+    }
+
+    fn create_with_partitions(
+        &self,
+        partitions: HashMap<Partition, u64>,
+    ) -> Box<dyn ProcessingStrategy<TPayload>> {
+        (**self).create_with_partitions(partitions)
+    }
 }
@@ -1,98 +1,198 @@
-
-use futures::Future;
-use log::warn;
-use crate::backends::Producer;
-use crate::backends::kafka::producer::KafkaProducer;
-use crate::backends::kafka::types::KafkaPayload;
+use crate::backends::{ProduceFuture, Producer, ProducerError};
+use crate::processing::strategies::retry::RetryPolicy;
 use crate::processing::strategies::{
-    CommitRequest, MessageRejected, ProcessingStrategy,
+    report_invalid_message_on_join, CommitRequest, InvalidMessage, MessageRejected,
+    ProcessingStrategy, StrategyDescription, SubmitError,
 };
 use crate::types::{Message, TopicOrPartition};
-use std::collections::VecDeque;
-use std::pin::Pin;
+use crate::utils::metrics;
+use crate::utils::timing::Deadline;
+use futures::FutureExt;
+use log::warn;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
-use std::task::Context;
-use tokio::task::JoinHandle;
-use std::time::{Duration, Instant};
+use std::thread::sleep;
+use std::time::Duration;
 
-
-pub struct ProduceFuture {
-    pub producer: Arc<KafkaProducer>,
-    destination: Arc<TopicOrPartition>,
-    payload: KafkaPayload,
-    pub completed: bool,
+/// The delivery of a payload to a topic or partition. When the broker fails
+/// to deliver it with a retriable error, the payload is produced again once
+/// the backoff of ``RetryPolicy`` is over, until the policy gives up on it.
+pub struct Delivery<TPayload: Clone> {
+    payload: TPayload,
+    // ``None`` while waiting to produce the payload again.
+    future: Option<ProduceFuture<TPayload>>,
+    attempts: u32,
+    retry_at: Deadline,
 }
 
-impl Future for ProduceFuture {
-    type Output = ();
-    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>, ) -> std::task::Poll<()> {
-        self.producer.produce(&self.destination, &self.payload);
-        return std::task::Poll::Ready(())
+impl<TPayload: Clone + Send + Sync> Delivery<TPayload> {
+    pub fn produce(
+        producer: &dyn Producer<TPayload>,
+        destination: &TopicOrPartition,
+        payload: TPayload,
+    ) -> Self {
+        let future = producer.produce_async(destination, &payload);
+        Delivery {
+            payload,
+            future: Some(future),
+            attempts: 1,
+            retry_at: Deadline::new(Duration::ZERO),
+        }
+    }
+
+    /// Returns whether the payload was delivered, producing it again if its
+    /// backoff is over. The error is returned once it cannot be retried, or
+    /// after the last attempt of ``policy``. Retries are counted in
+    /// ``arroyo.strategies.produce.retry`` tagged by the kind of error.
+    pub fn poll(
+        &mut self,
+        producer: &dyn Producer<TPayload>,
+        destination: &TopicOrPartition,
+        policy: &RetryPolicy,
+    ) -> Result<bool, ProducerError> {
+        let Some(future) = self.future.as_mut() else {
+            if self.retry_at.has_elapsed() {
+                self.future = Some(producer.produce_async(destination, &self.payload));
+                self.attempts += 1;
+            }
+            return Ok(false);
+        };
+        let error = match future.now_or_never() {
+            None => return Ok(false),
+            Some(Ok(_)) => return Ok(true),
+            Some(Err(error)) => error,
+        };
+        self.future = None;
+        if !error.is_retriable() || self.attempts >= policy.max_attempts {
+            return Err(error);
+        }
+
+        let backoff = policy.backoff(self.attempts);
+        warn!(
+            "Failed to produce, retrying in {:?} ({}/{}): {}",
+            backoff, self.attempts, policy.max_attempts, error
+        );
+        metrics::increment(
+            "arroyo.strategies.produce.retry",
+            None,
+            Some(HashMap::from([("kind", error.kind())])),
+            None,
+        );
+        self.retry_at = Deadline::new(backoff);
+        Ok(false)
     }
 }
+
+/// Produces the payload of every submitted message to ``destination`` and
+/// forwards the message to the next step once the broker confirmed its
+/// delivery, so that its offset is never committed before that.
+///
+/// Messages are forwarded strictly in the order they were submitted, so a
+/// slow delivery holds back any message submitted after it. Failed
+/// deliveries are retried with ``RetryPolicy``, the message whose delivery
+/// still fails is raised as ``InvalidMessage`` from ``poll``.
 pub struct Produce<TPayload: Clone + Send + Sync> {
-    pub producer: Arc<KafkaProducer>,
+    pub producer: Arc<dyn Producer<TPayload>>,
     pub next_step: Box<dyn ProcessingStrategy<TPayload>>,
-    queue: VecDeque<(Message<TPayload>, JoinHandle<()>)>,
-    topic: Arc<TopicOrPartition>,
+    queue: VecDeque<(Message<TPayload>, Delivery<TPayload>)>,
+    destination: TopicOrPartition,
+    retry_policy: RetryPolicy,
+    // A message whose payload was produced but that was rejected by the
+    // next step.
+    message_carried_over: Option<Message<TPayload>>,
     closed: bool,
     max_queue_size: usize,
 }
 
-impl Produce<KafkaPayload> {
-    pub fn new(producer: KafkaProducer, next_step: Box<dyn ProcessingStrategy<KafkaPayload>>, topic:TopicOrPartition) -> Self {
+impl<TPayload: Clone + Send + Sync + 'static> Produce<TPayload> {
+    pub fn new(
+        producer: Arc<dyn Producer<TPayload>>,
+        next_step: Box<dyn ProcessingStrategy<TPayload>>,
+        destination: TopicOrPartition,
+    ) -> Self {
         Produce {
-            producer: Arc::new(producer),
+            producer,
             next_step,
             queue: VecDeque::new(),
-            topic: Arc::new(topic),
+            destination,
+            retry_policy: RetryPolicy::default(),
+            message_carried_over: None,
             closed: false,
             max_queue_size: 1000,
         }
     }
-}
 
-impl ProcessingStrategy<KafkaPayload>
-    for Produce< KafkaPayload>
-{
-    fn poll(&mut self) -> Option<CommitRequest> {
-        while !self.queue.is_empty(){
-            let (message, handle) = self.queue.pop_front().unwrap();
-
-            if handle.is_finished() {
-                let new_message = message.clone();
-                // block_on(async{
-                //     handle.await.unwrap();
-                // });
-                self.next_step.poll();
-                self.next_step.submit(new_message).unwrap()
-
-            }else{
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Forwards a message to the next step, holding on to it if the next
+    /// step rejects it. Returns whether the message was accepted.
+    fn forward(&mut self, message: Message<TPayload>) -> Result<bool, InvalidMessage> {
+        match self.next_step.submit(message) {
+            Ok(()) => Ok(true),
+            Err(SubmitError::MessageRejected(MessageRejected { message })) => {
+                self.message_carried_over = Some(message);
+                Ok(false)
+            }
+            Err(SubmitError::InvalidMessage(invalid)) => Err(invalid),
+        }
+    }
+
+    /// Forwards all the messages at the head of the queue whose delivery was
+    /// confirmed. Stops at the first message that is still in flight.
+    fn forward_completed(&mut self) -> Result<(), InvalidMessage> {
+        if let Some(message) = self.message_carried_over.take() {
+            if !self.forward(message)? {
+                return Ok(());
+            }
+        }
+
+        while let Some((_, delivery)) = self.queue.front_mut() {
+            let result = delivery.poll(
+                self.producer.as_ref(),
+                &self.destination,
+                &self.retry_policy,
+            );
+            if let Ok(false) = result {
+                break;
+            }
+            let (message, _) = self.queue.pop_front().unwrap();
+            if let Err(error) = result {
+                log::error!("Failed to produce {}: {}", message, error);
+                match InvalidMessage::for_message(&message) {
+                    Some(invalid) => return Err(invalid),
+                    None => continue,
+                }
+            }
+            if !self.forward(message)? {
                 break;
             }
         }
-        return None
+        Ok(())
+    }
+}
+
+impl<TPayload: Clone + Send + Sync + 'static> ProcessingStrategy<TPayload> for Produce<TPayload> {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        self.forward_completed()?;
+        self.next_step.poll()
     }
 
-    fn submit(&mut self, message: Message<KafkaPayload>) -> Result<(), MessageRejected> {
+    fn submit(&mut self, message: Message<TPayload>) -> Result<(), SubmitError<TPayload>> {
         if self.closed {
             panic!("Attempted to submit a message to a closed Produce strategy")
         }
         if self.queue.len() >= self.max_queue_size {
-            return Err(MessageRejected)
+            return Err(SubmitError::MessageRejected(MessageRejected { message }));
         }
 
-        let produce_fut = ProduceFuture {
-            producer: Arc::clone(&self.producer),
-            destination: Arc::clone(&self.topic),
-            payload: message.payload().clone(),
-            completed: false,
-        };
-        // spawn the future
-        let handle = tokio::spawn(produce_fut);
+        let delivery =
+            Delivery::produce(self.producer.as_ref(), &self.destination, message.payload());
 
-        self.queue.push_back((message, handle));
-        return Ok(());
+        self.queue.push_back((message, delivery));
+        Ok(())
     }
 
     fn close(&mut self) {
@@ -101,62 +201,82 @@ impl ProcessingStrategy<KafkaPayload>
 
     fn terminate(&mut self) {
         self.closed = true;
+        self.queue.clear();
         self.next_step.terminate()
     }
 
     fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
-        let start = Instant::now();
-        let mut remaining: Option<Duration> = None;
-
-        while !self.queue.is_empty(){
-            if let Some(timeout) = timeout {
-                remaining = Some(timeout - start.elapsed());
-                if remaining.unwrap() <= Duration::from_secs(0) {
-                    warn!("Timeout reached while waiting for the queue to be empty");
-                    break;
-                }
+        let deadline = Deadline::from_timeout(timeout);
+
+        loop {
+            if let Err(invalid) = self.forward_completed() {
+                report_invalid_message_on_join("Produce", &invalid);
             }
-            let (message, handle) = self.queue.pop_front().unwrap();
-            if handle.is_finished() {
-                let new_message = message.clone();
-                self.next_step.poll();
-                self.next_step.submit(new_message).unwrap()
-            }else{
+            if self.queue.is_empty() && self.message_carried_over.is_none() {
                 break;
             }
-
+            if deadline.has_elapsed() {
+                warn!("Timeout reached while waiting for the queue to be empty");
+                break;
+            }
+            sleep(Duration::from_millis(1));
         }
 
+        // Whatever is left of the timeout is handed to the next step.
         self.next_step.close();
-        self.next_step.join(remaining);
-        return None;
+        self.next_step.join(deadline.remaining())
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription::new("Produce")
+            .with_buffered_messages(self.queue.len() + self.message_carried_over.is_some() as usize)
+            .with_next_step(self.next_step.describe())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::Produce;
-    use crate::backends::kafka::config::KafkaConfig;
+    use crate::backends::kafka::config::{InitialOffset, KafkaConfig};
     use crate::backends::kafka::producer::KafkaProducer;
     use crate::backends::kafka::types::KafkaPayload;
+    use crate::processing::strategies::retry::RetryPolicy;
+    use crate::processing::strategies::testutils::{
+        partition, Recorder, RecordingProducer, Submitted,
+    };
     use crate::processing::strategies::{
-        CommitRequest, MessageRejected, ProcessingStrategy,
+        CommitRequest, InvalidMessage, ProcessingStrategy, SubmitError,
     };
     use crate::types::{Message, Partition, Topic, TopicOrPartition};
     use chrono::Utc;
-    use std::collections::VecDeque;
-    use std::sync::Arc;
+    use std::sync::{Arc, Mutex};
     use std::time::Duration;
 
+    struct Noop {}
+    impl<T: Clone> ProcessingStrategy<T> for Noop {
+        fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+            Ok(None)
+        }
+        fn submit(&mut self, _message: Message<T>) -> Result<(), SubmitError<T>> {
+            Ok(())
+        }
+        fn close(&mut self) {}
+        fn terminate(&mut self) {}
+        fn join(&mut self, _timeout: Option<Duration>) -> Option<CommitRequest> {
+            None
+        }
+    }
+
     #[tokio::test]
     async fn test_produce() {
         let config = KafkaConfig::new_consumer_config(
             vec!["localhost:9092".to_string()],
             "my_group".to_string(),
-            "latest".to_string(),
+            InitialOffset::Latest,
             false,
             None,
-        );
+        )
+        .unwrap();
 
         let partition = Partition {
             topic: Topic {
@@ -165,12 +285,40 @@ mod tests {
             index: 0,
         };
 
-        struct Noop {}
-        impl ProcessingStrategy<KafkaPayload> for Noop {
-            fn poll(&mut self) -> Option<CommitRequest> {
-                None
+        let producer: KafkaProducer = KafkaProducer::new(config);
+
+        let mut strategy: Produce<KafkaPayload> = Produce::new(
+            Arc::new(producer),
+            Box::new(Noop {}),
+            TopicOrPartition::Topic(partition.topic.clone()),
+        );
+
+        let payload_str = "hello world".as_bytes().into();
+        strategy
+            .submit(Message::new_broker_message(
+                KafkaPayload {
+                    key: None,
+                    headers: None,
+                    payload: Some(payload_str),
+                },
+                partition,
+                0,
+                Utc::now(),
+            ))
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_forward_after_produce() {
+        struct Recorder {
+            submitted: Arc<Mutex<Vec<String>>>,
+        }
+        impl ProcessingStrategy<String> for Recorder {
+            fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+                Ok(None)
             }
-            fn submit(&mut self, _message: Message<KafkaPayload>) -> Result<(), MessageRejected> {
+            fn submit(&mut self, message: Message<String>) -> Result<(), SubmitError<String>> {
+                self.submitted.lock().unwrap().push(message.payload());
                 Ok(())
             }
             fn close(&mut self) {}
@@ -180,25 +328,100 @@ mod tests {
             }
         }
 
-        let producer: KafkaProducer = KafkaProducer::new(config);
-
-        let mut strategy: Produce<KafkaPayload> = Produce {
-            next_step: Box::new(Noop {}),
-            producer: Arc::new(producer),
-            queue: VecDeque::new(),
-            topic: Arc::new(TopicOrPartition::Topic(partition.topic.clone())),
-            closed: false,
-            max_queue_size: 1000,
+        let partition = Partition {
+            topic: Topic {
+                name: "test".to_string(),
+            },
+            index: 0,
         };
+        let producer = Arc::new(RecordingProducer::default());
+        let submitted = Arc::new(Mutex::new(Vec::new()));
+        let mut strategy = Produce::new(
+            producer.clone(),
+            Box::new(Recorder {
+                submitted: submitted.clone(),
+            }),
+            TopicOrPartition::Topic(partition.topic.clone()),
+        );
+
+        for (offset, payload) in ["a", "b"].iter().enumerate() {
+            strategy
+                .submit(Message::new_broker_message(
+                    payload.to_string(),
+                    partition.clone(),
+                    offset as u64,
+                    Utc::now(),
+                ))
+                .unwrap();
+        }
+
+        let description = strategy.describe();
+        assert_eq!(description.name, "Produce");
+        assert_eq!(description.buffered_messages, Some(2));
+        assert!(description.next_steps[0].name.ends_with("::Recorder"));
+
+        strategy.close();
+        strategy.join(Some(Duration::from_secs(5)));
 
-        let payload_str = "hello world".to_string().as_bytes().to_vec();
+        assert_eq!(producer.produced(), vec!["a", "b"]);
+        assert_eq!(*submitted.lock().unwrap(), vec!["a", "b"]);
+    }
+
+    fn produce_failing(failures: u32) -> (Produce<String>, Submitted<String>) {
+        let next_step = Recorder::default();
+        let submitted = next_step.submitted.clone();
+        let strategy = Produce::new(
+            Arc::new(RecordingProducer::failing(failures)),
+            Box::new(next_step),
+            TopicOrPartition::Topic(partition("test", 0).topic),
+        )
+        .with_retry_policy(RetryPolicy {
+            max_attempts: 2,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        });
+        (strategy, submitted)
+    }
+
+    #[test]
+    fn test_produce_retries_failed_delivery() {
+        let (mut strategy, submitted) = produce_failing(1);
+        for (offset, payload) in ["a", "b"].iter().enumerate() {
+            strategy
+                .submit(Message::new_broker_message(
+                    payload.to_string(),
+                    partition("test", 0),
+                    offset as u64,
+                    Utc::now(),
+                ))
+                .unwrap();
+        }
+
+        // The failed delivery holds back the next message until it is
+        // produced again.
+        assert_eq!(strategy.poll(), Ok(None));
+        assert!(submitted.payloads().is_empty());
+        assert_eq!(strategy.poll(), Ok(None));
+        assert_eq!(strategy.poll(), Ok(None));
+        assert_eq!(submitted.payloads(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_produce_gives_up() {
+        let (mut strategy, submitted) = produce_failing(2);
         strategy
-            .submit(Message::new(
-                partition,
-                0,
-                KafkaPayload { key: None, headers: None, payload: Some(payload_str) },
+            .submit(Message::new_broker_message(
+                "a".to_string(),
+                partition("test", 0),
+                5,
                 Utc::now(),
             ))
             .unwrap();
+
+        assert_eq!(strategy.poll(), Ok(None));
+        assert_eq!(strategy.poll(), Ok(None));
+        assert_eq!(strategy.poll().unwrap_err().offset, 5);
+        assert!(submitted.payloads().is_empty());
+        assert_eq!(strategy.describe().buffered_messages, Some(0));
     }
 }
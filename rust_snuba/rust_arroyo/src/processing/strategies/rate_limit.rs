@@ -0,0 +1,162 @@
+use crate::processing::strategies::{
+    CommitRequest, InvalidMessage, MessageRejected, ProcessingStrategy, StrategyDescription,
+    SubmitError,
+};
+use crate::types::Message;
+use crate::utils::clock::{Clock, SystemClock};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+pub type Cost<TPayload> = Arc<dyn Fn(&TPayload) -> u64 + Send + Sync>;
+
+/// Forwards at most ``per_second`` messages per second to the next step, or
+/// ``per_second`` units of ``cost``, such as bytes, if one is set. Messages
+/// submitted above the rate are rejected with ``MessageRejected``, which
+/// makes the processor pause the consumer until they can be forwarded.
+///
+/// This is a token bucket that holds up to a second worth of tokens, so a
+/// consumer that was idle can forward a burst of ``per_second`` at once. A
+/// message is forwarded as long as the bucket is not empty even if it costs
+/// more than what is left, the next messages wait until the debt is repaid.
+pub struct RateLimit<TPayload: Clone> {
+    next_step: Box<dyn ProcessingStrategy<TPayload>>,
+    per_second: f64,
+    cost: Option<Cost<TPayload>>,
+    clock: Box<dyn Clock>,
+    tokens: f64,
+    last_refill: SystemTime,
+}
+
+impl<TPayload: Clone + Send + Sync> RateLimit<TPayload> {
+    pub fn new(per_second: u64, next_step: Box<dyn ProcessingStrategy<TPayload>>) -> Self {
+        assert!(per_second > 0, "the rate limit must be positive");
+        let clock = SystemClock {};
+        RateLimit {
+            next_step,
+            per_second: per_second as f64,
+            cost: None,
+            last_refill: clock.time(),
+            clock: Box::new(clock),
+            tokens: per_second as f64,
+        }
+    }
+
+    /// Counts ``cost`` tokens for every message instead of one.
+    pub fn with_cost(mut self, cost: Cost<TPayload>) -> Self {
+        self.cost = Some(cost);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.last_refill = clock.time();
+        self.clock = Box::new(clock);
+        self
+    }
+
+    fn refill(&mut self) {
+        let now = self.clock.time();
+        let elapsed = now.duration_since(self.last_refill).unwrap_or_default();
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.per_second).min(self.per_second);
+        self.last_refill = now;
+    }
+}
+
+impl<TPayload: Clone + Send + Sync> ProcessingStrategy<TPayload> for RateLimit<TPayload> {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        self.next_step.poll()
+    }
+
+    fn submit(&mut self, message: Message<TPayload>) -> Result<(), SubmitError<TPayload>> {
+        self.refill();
+        if self.tokens <= 0.0 {
+            return Err(SubmitError::MessageRejected(MessageRejected { message }));
+        }
+
+        let cost = match &self.cost {
+            Some(cost) => cost(&message.payload()),
+            None => 1,
+        };
+        self.next_step.submit(message)?;
+        self.tokens -= cost as f64;
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.next_step.close();
+    }
+
+    fn terminate(&mut self) {
+        self.next_step.terminate();
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        self.next_step.join(timeout)
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription::new("RateLimit").with_next_step(self.next_step.describe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimit;
+    use crate::processing::strategies::testutils::{partition, Recorder};
+    use crate::processing::strategies::{ProcessingStrategy, SubmitError};
+    use crate::types::Message;
+    use crate::utils::clock::{Clock, TestingClock};
+    use chrono::Utc;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    fn submit_all(strategy: &mut RateLimit<u64>, payloads: &[u64]) -> usize {
+        let partition = partition("test", 0);
+        payloads
+            .iter()
+            .take_while(|payload| {
+                let message =
+                    Message::new_broker_message(**payload, partition.clone(), 0, Utc::now());
+                match strategy.submit(message) {
+                    Ok(()) => true,
+                    Err(SubmitError::MessageRejected(_)) => false,
+                    Err(SubmitError::InvalidMessage(_)) => unreachable!(),
+                }
+            })
+            .count()
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let clock = TestingClock::new(SystemTime::UNIX_EPOCH);
+        let recorder = Recorder::default();
+        let submitted = recorder.submitted.clone();
+        let mut strategy = RateLimit::new(4, Box::new(recorder)).with_clock(clock.clone());
+
+        // A burst of a second worth of messages goes through.
+        assert_eq!(submit_all(&mut strategy, &[0, 1, 2, 3, 4, 5]), 4);
+        assert_eq!(submitted.payloads(), vec![0, 1, 2, 3]);
+
+        clock.sleep(Duration::from_millis(500));
+        assert_eq!(submit_all(&mut strategy, &[4, 5, 6]), 2);
+
+        // The bucket does not fill above a second worth of tokens.
+        clock.sleep(Duration::from_secs(10));
+        assert_eq!(submit_all(&mut strategy, &[6, 7, 8, 9, 10]), 4);
+    }
+
+    #[test]
+    fn test_rate_limit_cost() {
+        let clock = TestingClock::new(SystemTime::UNIX_EPOCH);
+        let mut strategy = RateLimit::new(100, Box::new(Recorder::default()))
+            .with_cost(Arc::new(|size: &u64| *size))
+            .with_clock(clock.clone());
+
+        // The message that empties the bucket goes through, the next ones
+        // wait until the debt is repaid.
+        assert_eq!(submit_all(&mut strategy, &[60, 60, 10]), 2);
+        clock.sleep(Duration::from_millis(100));
+        assert_eq!(submit_all(&mut strategy, &[10]), 0);
+        clock.sleep(Duration::from_millis(150));
+        assert_eq!(submit_all(&mut strategy, &[10, 10]), 1);
+    }
+}
@@ -0,0 +1,399 @@
+use crate::processing::strategies::{
+    report_invalid_message_on_join, CommitRequest, InvalidMessage, MessageRejected,
+    ProcessingStrategy, StrategyDescription, SubmitError,
+};
+use crate::types::{Message, Partition, Position};
+use crate::utils::metrics;
+use crate::utils::timing::Deadline;
+use log::warn;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+pub type Accumulator<TPayload, TResult> = Arc<dyn Fn(TResult, TPayload) -> TResult + Send + Sync>;
+
+struct BatchState<TResult> {
+    value: Option<TResult>,
+    positions: BTreeMap<Partition, Position>,
+    batch_start_time: Instant,
+    message_count: usize,
+}
+
+impl<TResult: Clone> BatchState<TResult> {
+    fn new(initial_value: TResult) -> Self {
+        BatchState {
+            value: Some(initial_value),
+            positions: BTreeMap::new(),
+            batch_start_time: Instant::now(),
+            message_count: 0,
+        }
+    }
+}
+
+/// Accumulates the payloads of the submitted messages into a batch using
+/// ``accumulator`` and forwards the batch to the next step once either
+/// ``max_batch_size`` messages were added to it or ``max_batch_time`` has
+/// passed since its first message was added.
+///
+/// The batch is forwarded as a message that carries the highest committable
+/// position of every partition that contributed to it. A batch the next
+/// step raises as invalid while a message is submitted is raised from the
+/// next ``poll``, the message is rejected meanwhile.
+pub struct Reduce<TPayload: Clone, TResult: Clone> {
+    next_step: Box<dyn ProcessingStrategy<TResult>>,
+    accumulator: Accumulator<TPayload, TResult>,
+    initial_value: TResult,
+    max_batch_size: usize,
+    max_batch_time: Duration,
+    batch_state: BatchState<TResult>,
+    message_carried_over: Option<Message<TResult>>,
+    // A batch the next step raised as invalid during ``submit``, raised from
+    // the next ``poll``.
+    invalid: Option<InvalidMessage>,
+    closed: bool,
+}
+
+impl<TPayload: Clone + Send + Sync, TResult: Clone + Send + Sync> Reduce<TPayload, TResult> {
+    pub fn new(
+        next_step: Box<dyn ProcessingStrategy<TResult>>,
+        accumulator: Accumulator<TPayload, TResult>,
+        initial_value: TResult,
+        max_batch_size: usize,
+        max_batch_time: Duration,
+    ) -> Self {
+        Reduce {
+            next_step,
+            accumulator,
+            batch_state: BatchState::new(initial_value.clone()),
+            initial_value,
+            max_batch_size,
+            max_batch_time,
+            message_carried_over: None,
+            invalid: None,
+            closed: false,
+        }
+    }
+
+    fn is_batch_complete(&self) -> bool {
+        let state = &self.batch_state;
+        state.message_count > 0
+            && (state.message_count >= self.max_batch_size
+                || state.batch_start_time.elapsed() >= self.max_batch_time)
+    }
+
+    /// Tries to submit the carried over batch, returns whether there is
+    /// nothing left to submit.
+    fn submit_carried_over(&mut self) -> Result<bool, InvalidMessage> {
+        if let Some(message) = self.message_carried_over.take() {
+            match self.next_step.submit(message) {
+                Ok(()) => {}
+                Err(SubmitError::MessageRejected(MessageRejected { message })) => {
+                    self.message_carried_over = Some(message);
+                    return Ok(false);
+                }
+                Err(SubmitError::InvalidMessage(invalid)) => return Err(invalid),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Forwards the current batch to the next step and starts a new one. The
+    /// batch is carried over if the next step rejects it.
+    fn flush(&mut self) -> Result<(), InvalidMessage> {
+        let batch_state = std::mem::replace(
+            &mut self.batch_state,
+            BatchState::new(self.initial_value.clone()),
+        );
+        metrics::time(
+            "arroyo.strategies.reduce.batch_time",
+            batch_state.batch_start_time.elapsed().as_millis() as u64,
+            None,
+            None,
+        );
+        metrics::gauge(
+            "arroyo.strategies.reduce.batch_size",
+            batch_state.message_count as u64,
+            None,
+            None,
+        );
+        let message = Message::new_any_message(batch_state.value.unwrap(), batch_state.positions);
+        match self.next_step.submit(message) {
+            Ok(()) => Ok(()),
+            Err(SubmitError::MessageRejected(MessageRejected { message })) => {
+                self.message_carried_over = Some(message);
+                Ok(())
+            }
+            Err(SubmitError::InvalidMessage(invalid)) => Err(invalid),
+        }
+    }
+}
+
+impl<TPayload: Clone + Send + Sync, TResult: Clone + Send + Sync> ProcessingStrategy<TPayload>
+    for Reduce<TPayload, TResult>
+{
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        if let Some(invalid) = self.invalid.take() {
+            return Err(invalid);
+        }
+        if self.submit_carried_over()? && self.is_batch_complete() {
+            self.flush()?;
+        }
+        self.next_step.poll()
+    }
+
+    fn submit(&mut self, message: Message<TPayload>) -> Result<(), SubmitError<TPayload>> {
+        if self.closed {
+            panic!("Attempted to submit a message to a closed Reduce strategy")
+        }
+        if self.invalid.is_some() {
+            return Err(SubmitError::MessageRejected(MessageRejected { message }));
+        }
+        let submitted = match self.submit_carried_over() {
+            Ok(true) if self.is_batch_complete() => self.flush().map(|()| true),
+            result => result,
+        };
+        match submitted {
+            Ok(true) if self.message_carried_over.is_none() => {}
+            Ok(_) => return Err(SubmitError::MessageRejected(MessageRejected { message })),
+            Err(invalid) => {
+                self.invalid = Some(invalid);
+                return Err(SubmitError::MessageRejected(MessageRejected { message }));
+            }
+        }
+
+        let state = &mut self.batch_state;
+        if state.message_count == 0 {
+            state.batch_start_time = Instant::now();
+        }
+        for (partition, position) in message.committable() {
+            state.positions.insert(partition, position);
+        }
+        let value = state.value.take().unwrap();
+        state.value = Some((self.accumulator)(value, message.payload()));
+        state.message_count += 1;
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        // The next step is closed on join, once the last batch is submitted.
+        self.closed = true;
+    }
+
+    fn terminate(&mut self) {
+        self.closed = true;
+        self.next_step.terminate();
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        let deadline = Deadline::from_timeout(timeout);
+        if let Some(invalid) = self.invalid.take() {
+            report_invalid_message_on_join("Reduce", &invalid);
+        }
+
+        loop {
+            match self.submit_carried_over() {
+                Ok(true) if self.batch_state.message_count == 0 => break,
+                Ok(true) => {
+                    if let Err(invalid) = self.flush() {
+                        report_invalid_message_on_join("Reduce", &invalid);
+                    }
+                    continue;
+                }
+                Ok(false) => {}
+                Err(invalid) => {
+                    report_invalid_message_on_join("Reduce", &invalid);
+                    continue;
+                }
+            }
+            if deadline.has_elapsed() {
+                warn!("Timeout reached while waiting for the batch to be submitted");
+                break;
+            }
+            sleep(Duration::from_millis(1));
+        }
+
+        self.next_step.close();
+        self.next_step.join(deadline.remaining())
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription::new("Reduce")
+            .with_buffered_messages(
+                self.batch_state.message_count + self.message_carried_over.is_some() as usize,
+            )
+            .with_next_step(self.next_step.describe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Reduce;
+    use crate::processing::strategies::testutils::{partition, Recorder, Submitted};
+    use crate::processing::strategies::{
+        CommitRequest, InvalidMessage, ProcessingStrategy, SubmitError,
+    };
+    use crate::types::{Message, Position};
+    use chrono::Utc;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    fn make_reduce(
+        max_batch_size: usize,
+        max_batch_time: Duration,
+    ) -> (Reduce<u64, Vec<u64>>, Submitted<Vec<u64>>) {
+        let recorder = Recorder::default();
+        let submitted = recorder.submitted.clone();
+        let reduce = Reduce::new(
+            Box::new(recorder),
+            Arc::new(|mut acc: Vec<u64>, value: u64| {
+                acc.push(value);
+                acc
+            }),
+            Vec::new(),
+            max_batch_size,
+            max_batch_time,
+        );
+        (reduce, submitted)
+    }
+
+    #[test]
+    fn test_reduce_by_size() {
+        let partition = partition("test", 0);
+        let (mut reduce, submitted) = make_reduce(2, Duration::from_secs(60));
+        let now = Utc::now();
+
+        for offset in 0..5 {
+            reduce
+                .submit(Message::new_broker_message(
+                    offset,
+                    partition.clone(),
+                    offset,
+                    now,
+                ))
+                .unwrap();
+            reduce.poll().unwrap();
+        }
+
+        {
+            let submitted = submitted.messages();
+            assert_eq!(submitted.len(), 2);
+            assert_eq!(submitted[0].payload(), vec![0, 1]);
+            assert_eq!(
+                submitted[0].committable(),
+                BTreeMap::from([(partition.clone(), Position::new(2, now))])
+            );
+            assert_eq!(submitted[1].payload(), vec![2, 3]);
+        }
+
+        // The last partial batch is flushed on join
+        reduce.close();
+        reduce.join(None);
+        let submitted = submitted.messages();
+        assert_eq!(submitted.len(), 3);
+        assert_eq!(submitted[2].payload(), vec![4]);
+        assert_eq!(
+            submitted[2].committable(),
+            BTreeMap::from([(partition, Position::new(5, now))])
+        );
+    }
+
+    #[test]
+    fn test_reduce_by_time() {
+        let partition = partition("test", 0);
+        let (mut reduce, submitted) = make_reduce(100, Duration::from_millis(20));
+
+        reduce
+            .submit(Message::new_broker_message(
+                1,
+                partition.clone(),
+                0,
+                Utc::now(),
+            ))
+            .unwrap();
+        reduce.poll().unwrap();
+        assert!(submitted.messages().is_empty());
+
+        sleep(Duration::from_millis(25));
+        reduce.poll().unwrap();
+        assert_eq!(submitted.messages().len(), 1);
+
+        // An empty batch is never flushed
+        sleep(Duration::from_millis(25));
+        reduce.poll().unwrap();
+        assert_eq!(submitted.messages().len(), 1);
+    }
+
+    // Raises ``InvalidMessage`` for every batch.
+    struct Invalid {
+        joined: Arc<Mutex<bool>>,
+    }
+    impl ProcessingStrategy<Vec<u64>> for Invalid {
+        fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+            Ok(None)
+        }
+        fn submit(&mut self, message: Message<Vec<u64>>) -> Result<(), SubmitError<Vec<u64>>> {
+            let (partition, _) = message.committable().into_iter().next().unwrap();
+            Err(SubmitError::InvalidMessage(InvalidMessage {
+                partition,
+                offset: 0,
+            }))
+        }
+        fn close(&mut self) {}
+        fn terminate(&mut self) {}
+        fn join(&mut self, _timeout: Option<Duration>) -> Option<CommitRequest> {
+            *self.joined.lock().unwrap() = true;
+            None
+        }
+    }
+
+    fn reduce_into_invalid(joined: Arc<Mutex<bool>>) -> Reduce<u64, Vec<u64>> {
+        Reduce::new(
+            Box::new(Invalid { joined }),
+            Arc::new(|mut acc: Vec<u64>, value: u64| {
+                acc.push(value);
+                acc
+            }),
+            Vec::new(),
+            1,
+            Duration::from_secs(60),
+        )
+    }
+
+    #[test]
+    fn test_join_skips_invalid_message() {
+        let joined = Arc::new(Mutex::new(false));
+        let mut reduce = reduce_into_invalid(joined.clone());
+        let partition = partition("test", 0);
+        reduce
+            .submit(Message::new_broker_message(1, partition, 0, Utc::now()))
+            .unwrap();
+
+        // The batch is skipped instead of crashing, the next step is joined.
+        reduce.close();
+        assert_eq!(reduce.join(None), None);
+        assert!(*joined.lock().unwrap());
+    }
+
+    #[test]
+    fn test_invalid_batch_on_submit() {
+        let partition = partition("test", 0);
+        let mut reduce = reduce_into_invalid(Arc::new(Mutex::new(false)));
+        let message =
+            |offset| Message::new_broker_message(offset, partition.clone(), offset, Utc::now());
+        reduce.submit(message(0)).unwrap();
+
+        // The message that flushes the invalid batch is handed back, the
+        // batch is raised from the next poll.
+        match reduce.submit(message(1)) {
+            Err(SubmitError::MessageRejected(rejected)) => {
+                assert_eq!(rejected.message.payload(), 1)
+            }
+            _ => panic!("Expected the message to be rejected"),
+        }
+        assert!(reduce.poll().is_err());
+        reduce.submit(message(1)).unwrap();
+    }
+}
@@ -0,0 +1,321 @@
+use crate::processing::strategies::{
+    report_invalid_message_on_join, CommitRequest, InvalidMessage, MessageRejected,
+    ProcessingStrategy, StrategyDescription, SubmitError,
+};
+use crate::types::Message;
+use crate::utils::metrics;
+use crate::utils::timing::Deadline;
+use rand::Rng;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// How many times an operation is attempted in total, such as ``Retry``
+/// submitting a message, and how long to wait between attempts. The delay
+/// doubles after every attempt, up to ``max_backoff``, and is then reduced
+/// by up to half at random so that consumers failing at the same time do
+/// not retry in lockstep.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay after the given failed attempt, starting at 1.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+struct PendingRetry<TPayload: Clone> {
+    message: Message<TPayload>,
+    attempts: u32,
+    retry_at: Deadline,
+}
+
+/// Submits a message to the next step again when it fails with
+/// ``InvalidMessage``, up to ``max_attempts`` times. The error of the last
+/// attempt is returned, from ``poll``, so that the message is dead lettered
+/// or crashes the consumer as usual. On ``join`` it is reported with
+/// ``report_invalid_message_on_join`` instead.
+///
+/// Retries happen on ``poll`` once the backoff is over, in the meantime new
+/// messages are rejected with ``MessageRejected``. Only the errors of
+/// ``submit`` are retried, the next step does not have the message anymore
+/// when it returns ``InvalidMessage`` from ``poll``.
+pub struct Retry<TPayload: Clone> {
+    next_step: Box<dyn ProcessingStrategy<TPayload>>,
+    policy: RetryPolicy,
+    pending: Option<PendingRetry<TPayload>>,
+    closed: bool,
+}
+
+impl<TPayload: Clone + Send + Sync> Retry<TPayload> {
+    pub fn new(next_step: Box<dyn ProcessingStrategy<TPayload>>) -> Self {
+        Retry {
+            next_step,
+            policy: RetryPolicy::default(),
+            pending: None,
+            closed: false,
+        }
+    }
+
+    pub fn with_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    fn attempt(
+        &mut self,
+        message: Message<TPayload>,
+        attempts: u32,
+    ) -> Result<(), SubmitError<TPayload>> {
+        let invalid = match self.next_step.submit(message.clone()) {
+            Err(SubmitError::InvalidMessage(invalid)) => invalid,
+            result => return result,
+        };
+        if attempts >= self.policy.max_attempts {
+            log::error!("Giving up on {} after {} attempts", message, attempts);
+            return Err(SubmitError::InvalidMessage(invalid));
+        }
+
+        let backoff = self.policy.backoff(attempts);
+        log::warn!(
+            "Failed to submit {}, retrying in {:?} ({}/{})",
+            message,
+            backoff,
+            attempts,
+            self.policy.max_attempts
+        );
+        metrics::increment("arroyo.strategies.retry.count", None, None, None);
+        self.pending = Some(PendingRetry {
+            message,
+            attempts: attempts + 1,
+            retry_at: Deadline::new(backoff),
+        });
+        Ok(())
+    }
+
+    fn retry_pending(&mut self) -> Result<(), InvalidMessage> {
+        if !self
+            .pending
+            .as_ref()
+            .is_some_and(|pending| pending.retry_at.has_elapsed())
+        {
+            return Ok(());
+        }
+        let PendingRetry {
+            message, attempts, ..
+        } = self.pending.take().unwrap();
+        match self.attempt(message, attempts) {
+            Ok(()) => Ok(()),
+            // Tried again on the next poll.
+            Err(SubmitError::MessageRejected(MessageRejected { message })) => {
+                self.pending = Some(PendingRetry {
+                    message,
+                    attempts,
+                    retry_at: Deadline::new(Duration::ZERO),
+                });
+                Ok(())
+            }
+            Err(SubmitError::InvalidMessage(invalid)) => Err(invalid),
+        }
+    }
+}
+
+impl<TPayload: Clone + Send + Sync> ProcessingStrategy<TPayload> for Retry<TPayload> {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        self.retry_pending()?;
+        self.next_step.poll()
+    }
+
+    fn submit(&mut self, message: Message<TPayload>) -> Result<(), SubmitError<TPayload>> {
+        if self.closed {
+            panic!("Attempted to submit a message to a closed Retry strategy")
+        }
+        if self.pending.is_some() {
+            return Err(SubmitError::MessageRejected(MessageRejected { message }));
+        }
+        self.attempt(message, 1)
+    }
+
+    fn close(&mut self) {
+        // The next step is closed on join, once the pending retry is done.
+        self.closed = true;
+    }
+
+    fn terminate(&mut self) {
+        self.closed = true;
+        self.pending = None;
+        self.next_step.terminate();
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        let deadline = Deadline::from_timeout(timeout);
+        while self.pending.is_some() && !deadline.has_elapsed() {
+            if let Err(invalid) = self.retry_pending() {
+                report_invalid_message_on_join("Retry", &invalid);
+                break;
+            }
+            sleep(Duration::from_millis(1));
+        }
+        self.pending = None;
+        self.next_step.close();
+        self.next_step.join(deadline.remaining())
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription::new("Retry")
+            .with_buffered_messages(self.pending.iter().count())
+            .with_next_step(self.next_step.describe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Retry, RetryPolicy};
+    use crate::processing::strategies::testutils::partition;
+    use crate::processing::strategies::{
+        collect_invalid_on_join, CommitRequest, InvalidMessage, ProcessingStrategy, SubmitError,
+    };
+    use crate::types::{InnerMessage, Message};
+    use chrono::Utc;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    // Fails the first ``failures`` submits, and panics on a submit after
+    // ``close``.
+    struct Flaky {
+        failures: u32,
+        submitted: Arc<Mutex<Vec<u64>>>,
+        closed: bool,
+    }
+    impl ProcessingStrategy<u64> for Flaky {
+        fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+            Ok(None)
+        }
+        fn submit(&mut self, message: Message<u64>) -> Result<(), SubmitError<u64>> {
+            assert!(!self.closed, "submitted after close");
+            if self.failures > 0 {
+                self.failures -= 1;
+                let InnerMessage::BrokerMessage(message) = message.inner_message else {
+                    unreachable!()
+                };
+                return Err(SubmitError::InvalidMessage(InvalidMessage::from(&message)));
+            }
+            self.submitted.lock().unwrap().push(message.payload());
+            Ok(())
+        }
+        fn close(&mut self) {
+            self.closed = true;
+        }
+        fn terminate(&mut self) {}
+        fn join(&mut self, _timeout: Option<Duration>) -> Option<CommitRequest> {
+            None
+        }
+    }
+
+    fn retry(failures: u32, submitted: Arc<Mutex<Vec<u64>>>) -> Retry<u64> {
+        Retry::new(Box::new(Flaky {
+            failures,
+            submitted,
+            closed: false,
+        }))
+        .with_policy(RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        })
+    }
+
+    fn message(offset: u64) -> Message<u64> {
+        Message::new_broker_message(offset, partition("test", 0), offset, Utc::now())
+    }
+
+    #[test]
+    fn test_retry() {
+        let submitted = Arc::new(Mutex::new(Vec::new()));
+        let mut strategy = retry(2, submitted.clone());
+
+        strategy.submit(message(0)).unwrap();
+        // The next message waits for the retries.
+        assert!(matches!(
+            strategy.submit(message(1)),
+            Err(SubmitError::MessageRejected(_))
+        ));
+        assert_eq!(strategy.poll(), Ok(None));
+        assert!(submitted.lock().unwrap().is_empty());
+        assert_eq!(strategy.poll(), Ok(None));
+        assert_eq!(*submitted.lock().unwrap(), vec![0]);
+
+        strategy.submit(message(1)).unwrap();
+        assert_eq!(*submitted.lock().unwrap(), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_retry_gives_up() {
+        let submitted = Arc::new(Mutex::new(Vec::new()));
+        let mut strategy = retry(3, submitted.clone());
+
+        strategy.submit(message(5)).unwrap();
+        assert_eq!(strategy.poll(), Ok(None));
+        let invalid = strategy.poll().unwrap_err();
+        assert_eq!(invalid.offset, 5);
+
+        // The failed message is not retried anymore.
+        assert_eq!(strategy.poll(), Ok(None));
+        strategy.submit(message(6)).unwrap();
+        assert_eq!(*submitted.lock().unwrap(), vec![6]);
+    }
+
+    #[test]
+    fn test_retry_on_join() {
+        // The pending retry is submitted before the next step is closed.
+        let submitted = Arc::new(Mutex::new(Vec::new()));
+        let mut strategy = retry(1, submitted.clone());
+        strategy.submit(message(0)).unwrap();
+        strategy.close();
+        let (_, invalid) = collect_invalid_on_join(|| strategy.join(None));
+        assert!(invalid.is_empty());
+        assert_eq!(*submitted.lock().unwrap(), vec![0]);
+
+        // The message that fails its last attempt is reported.
+        let submitted = Arc::new(Mutex::new(Vec::new()));
+        let mut strategy = retry(3, submitted.clone());
+        strategy.submit(message(5)).unwrap();
+        strategy.close();
+        let (_, invalid) = collect_invalid_on_join(|| strategy.join(None));
+        assert_eq!(invalid.len(), 1);
+        assert_eq!(invalid[0].offset, 5);
+        assert!(submitted.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+        };
+        for (attempt, max) in [(1, 100), (3, 400), (5, 1000), (100, 1000)] {
+            let backoff = policy.backoff(attempt);
+            assert!(backoff >= Duration::from_millis(max / 2));
+            assert!(backoff <= Duration::from_millis(max));
+        }
+    }
+}
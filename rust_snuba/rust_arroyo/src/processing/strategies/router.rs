@@ -0,0 +1,247 @@
+use crate::processing::strategies::{
+    raise_invalid_message, CommitRequest, InvalidMessage, ProcessingStrategy, StrategyDescription,
+    SubmitError,
+};
+use crate::types::{Message, Partition, Position};
+use crate::utils::offset_tracker::OffsetTracker;
+use crate::utils::timing::Deadline;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Returns the index of the branch a payload is submitted to.
+pub type RoutingFunction<TPayload> = Arc<dyn Fn(&TPayload) -> usize + Send + Sync>;
+
+/// Submits every message to one of ``branches``, the one ``route`` returns
+/// the index of. This is meant for topics that carry several kinds of
+/// messages that are processed differently.
+///
+/// Branches commit on their own schedule, a partition is only committed up
+/// to the first message that some branch did not commit yet. A message
+/// rejected by its branch is rejected by the router, which holds back the
+/// other branches too. A message routed to a branch that does not exist is
+/// raised as invalid.
+///
+/// On ``join`` every branch gets an even share of the time the branches
+/// before it left, so that a slow branch does not keep the others from
+/// being flushed.
+pub struct Router<TPayload: Clone> {
+    route: RoutingFunction<TPayload>,
+    branches: Vec<Box<dyn ProcessingStrategy<TPayload>>>,
+    // The committable offsets of the messages submitted to each branch that
+    // it did not commit yet, in order.
+    in_flight: Vec<HashMap<Partition, VecDeque<u64>>>,
+    offsets: OffsetTracker,
+    // The timestamps of the positions that were not committed yet.
+    timestamps: HashMap<Partition, BTreeMap<u64, DateTime<Utc>>>,
+}
+
+impl<TPayload: Clone + Send + Sync> Router<TPayload> {
+    pub fn new(
+        route: RoutingFunction<TPayload>,
+        branches: Vec<Box<dyn ProcessingStrategy<TPayload>>>,
+    ) -> Self {
+        Router {
+            route,
+            in_flight: vec![HashMap::new(); branches.len()],
+            branches,
+            offsets: OffsetTracker::new(),
+            timestamps: HashMap::new(),
+        }
+    }
+
+    fn record(&mut self, index: usize, request: Option<CommitRequest>) {
+        let Some(request) = request else {
+            return;
+        };
+        for (partition, position) in request.positions {
+            let Some(in_flight) = self.in_flight[index].get_mut(&partition) else {
+                continue;
+            };
+            while let Some(offset) = in_flight.front().filter(|o| **o <= position.offset) {
+                self.offsets.complete(&partition, offset.saturating_sub(1));
+                in_flight.pop_front();
+            }
+        }
+    }
+
+    fn take_committable(&mut self) -> Option<CommitRequest> {
+        let mut positions = HashMap::new();
+        for (partition, offset) in self.offsets.take_committable() {
+            let Some(timestamps) = self.timestamps.get_mut(&partition) else {
+                continue;
+            };
+            // There may be no message right before the position if there
+            // are gaps between the offsets.
+            let timestamp = timestamps.range(..=offset).next_back().map(|(_, t)| *t);
+            *timestamps = timestamps.split_off(&(offset + 1));
+            if let Some(timestamp) = timestamp {
+                positions.insert(partition, Position::new(offset, timestamp));
+            }
+        }
+        if positions.is_empty() {
+            return None;
+        }
+        Some(CommitRequest { positions })
+    }
+}
+
+impl<TPayload: Clone + Send + Sync> ProcessingStrategy<TPayload> for Router<TPayload> {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        for index in 0..self.branches.len() {
+            let request = self.branches[index].poll()?;
+            self.record(index, request);
+        }
+        Ok(self.take_committable())
+    }
+
+    fn submit(&mut self, message: Message<TPayload>) -> Result<(), SubmitError<TPayload>> {
+        let index = (self.route)(&message.payload());
+        if index >= self.branches.len() {
+            log::error!(
+                "{} routed to branch {} of {}",
+                message,
+                index,
+                self.branches.len()
+            );
+            return raise_invalid_message(&message);
+        }
+        let committable = message.committable();
+        self.branches[index].submit(message)?;
+        for (partition, position) in committable {
+            self.offsets
+                .add(&partition, position.offset.saturating_sub(1));
+            self.timestamps
+                .entry(partition.clone())
+                .or_default()
+                .insert(position.offset, position.timestamp);
+            self.in_flight[index]
+                .entry(partition)
+                .or_default()
+                .push_back(position.offset);
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        for branch in &mut self.branches {
+            branch.close();
+        }
+    }
+
+    fn terminate(&mut self) {
+        for branch in &mut self.branches {
+            branch.terminate();
+        }
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        let deadline = Deadline::from_timeout(timeout);
+        let count = self.branches.len();
+        for index in 0..count {
+            let request = self.branches[index].join(deadline.split((count - index) as u32));
+            self.record(index, request);
+        }
+        self.take_committable()
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        let mut description =
+            StrategyDescription::new("Router").with_buffered_messages(self.offsets.in_flight());
+        for branch in &self.branches {
+            description = description.with_next_step(branch.describe());
+        }
+        description
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Router;
+    use crate::processing::strategies::testutils::{partition, Recorder, SlowJoin};
+    use crate::processing::strategies::{CommitRequest, ProcessingStrategy, SubmitError};
+    use crate::types::{Message, Position};
+    use chrono::Utc;
+    use std::collections::HashMap;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_router() {
+        let partition = partition("test", 0);
+        let now = Utc::now();
+        let even = Recorder::committing();
+        let odd = Recorder::committing();
+        let (even_submitted, odd_submitted) = (even.submitted.clone(), odd.submitted.clone());
+        let hold_odd = odd.hold.clone();
+        hold_odd.store(true, Ordering::Relaxed);
+        let mut router = Router::new(
+            Arc::new(|value: &u64| (*value % 2) as usize),
+            vec![Box::new(even), Box::new(odd)],
+        );
+
+        for offset in 0..4 {
+            router
+                .submit(Message::new_broker_message(
+                    offset,
+                    partition.clone(),
+                    offset,
+                    now,
+                ))
+                .unwrap();
+        }
+        assert_eq!(even_submitted.payloads(), vec![0, 2]);
+        assert_eq!(odd_submitted.payloads(), vec![1, 3]);
+
+        // The odd branch holds back the partition after the first message.
+        assert_eq!(
+            router.poll().unwrap(),
+            Some(CommitRequest {
+                positions: HashMap::from([(partition.clone(), Position::new(1, now))])
+            })
+        );
+        assert_eq!(router.poll().unwrap(), None);
+
+        hold_odd.store(false, Ordering::Relaxed);
+        assert_eq!(
+            router.poll().unwrap(),
+            Some(CommitRequest {
+                positions: HashMap::from([(partition.clone(), Position::new(4, now))])
+            })
+        );
+        assert_eq!(router.join(None), None);
+    }
+
+    #[test]
+    fn test_unknown_branch() {
+        let partition = partition("test", 0);
+        let mut router = Router::new(
+            Arc::new(|value: &u64| *value as usize),
+            vec![Box::new(Recorder::committing())],
+        );
+
+        let message = Message::new_broker_message(1, partition.clone(), 7, Utc::now());
+        match router.submit(message) {
+            Err(SubmitError::InvalidMessage(invalid)) => assert_eq!(invalid.offset, 7),
+            _ => panic!("Expected an InvalidMessage error"),
+        }
+        assert_eq!(router.poll().unwrap(), None);
+    }
+
+    #[test]
+    fn test_join_splits_timeout() {
+        let (first, second) = (SlowJoin::default(), SlowJoin::default());
+        let (first_timeout, second_timeout) = (first.timeout.clone(), second.timeout.clone());
+        let mut router = Router::new(
+            Arc::new(|_: &u64| 0),
+            vec![Box::new(first), Box::new(second)],
+        );
+        router.close();
+        router.join(Some(Duration::from_millis(100)));
+
+        assert!(first_timeout.lock().unwrap().unwrap() <= Duration::from_millis(50));
+        assert!(second_timeout.lock().unwrap().unwrap() >= Duration::from_millis(40));
+    }
+}
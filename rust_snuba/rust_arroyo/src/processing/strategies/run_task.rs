@@ -0,0 +1,154 @@
+use crate::processing::strategies::{
+    report_invalid_message_on_join, CommitRequest, InvalidMessage, MessageRejected,
+    ProcessingStrategy, StrategyDescription, SubmitError,
+};
+use crate::types::Message;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub type TaskFunction<TPayload, TTransformed> =
+    Arc<dyn Fn(Message<TPayload>) -> Result<Message<TTransformed>, InvalidMessage> + Send + Sync>;
+
+/// Applies ``function`` to every submitted message and forwards the result
+/// to the next step.
+///
+/// If ``function`` fails the ``InvalidMessage`` it returned is raised from
+/// ``submit`` and nothing is forwarded.
+pub struct RunTask<TPayload: Clone + Send + Sync, TTransformed: Clone + Send + Sync> {
+    function: TaskFunction<TPayload, TTransformed>,
+    next_step: Box<dyn ProcessingStrategy<TTransformed>>,
+    message_carried_over: Option<Message<TTransformed>>,
+}
+
+impl<TPayload: Clone + Send + Sync, TTransformed: Clone + Send + Sync>
+    RunTask<TPayload, TTransformed>
+{
+    pub fn new(
+        function: TaskFunction<TPayload, TTransformed>,
+        next_step: Box<dyn ProcessingStrategy<TTransformed>>,
+    ) -> Self {
+        Self {
+            function,
+            next_step,
+            message_carried_over: None,
+        }
+    }
+
+    fn submit_carried_over(&mut self) -> Result<(), InvalidMessage> {
+        if let Some(message) = self.message_carried_over.take() {
+            match self.next_step.submit(message) {
+                Ok(()) => {}
+                Err(SubmitError::MessageRejected(MessageRejected { message })) => {
+                    self.message_carried_over = Some(message);
+                }
+                Err(SubmitError::InvalidMessage(invalid)) => return Err(invalid),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<TPayload: Clone + Send + Sync, TTransformed: Clone + Send + Sync> ProcessingStrategy<TPayload>
+    for RunTask<TPayload, TTransformed>
+{
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        self.submit_carried_over()?;
+        self.next_step.poll()
+    }
+
+    fn submit(&mut self, message: Message<TPayload>) -> Result<(), SubmitError<TPayload>> {
+        if self.message_carried_over.is_some() {
+            return Err(SubmitError::MessageRejected(MessageRejected { message }));
+        }
+
+        let transformed = (self.function)(message)?;
+
+        match self.next_step.submit(transformed) {
+            Ok(()) => Ok(()),
+            Err(SubmitError::MessageRejected(MessageRejected { message })) => {
+                self.message_carried_over = Some(message);
+                Ok(())
+            }
+            Err(SubmitError::InvalidMessage(invalid)) => Err(invalid.into()),
+        }
+    }
+
+    fn close(&mut self) {
+        self.next_step.close()
+    }
+
+    fn terminate(&mut self) {
+        self.next_step.terminate()
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        if let Err(invalid) = self.submit_carried_over() {
+            report_invalid_message_on_join("RunTask", &invalid);
+        }
+        self.next_step.join(timeout)
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription::new("RunTask")
+            .with_buffered_messages(self.message_carried_over.is_some() as usize)
+            .with_next_step(self.next_step.describe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RunTask;
+    use crate::processing::strategies::testutils::{partition, Recorder};
+    use crate::processing::strategies::{InvalidMessage, ProcessingStrategy, SubmitError};
+    use crate::types::{InnerMessage, Message};
+    use chrono::Utc;
+    use std::sync::Arc;
+
+    fn parse(message: Message<String>) -> Result<Message<u64>, InvalidMessage> {
+        match message.payload().parse() {
+            Ok(value) => Ok(message.replace(value)),
+            Err(_) => match &message.inner_message {
+                InnerMessage::BrokerMessage(inner) => Err(InvalidMessage::from(inner)),
+                InnerMessage::AnyMessage(_) => unreachable!(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_run_task() {
+        let partition = partition("test", 0);
+        let recorder = Recorder::default();
+        let submitted = recorder.submitted.clone();
+        let mut strategy = RunTask::new(Arc::new(parse), Box::new(recorder));
+
+        strategy
+            .submit(Message::new_broker_message(
+                "10".to_string(),
+                partition.clone(),
+                0,
+                Utc::now(),
+            ))
+            .unwrap();
+
+        let result = strategy.submit(Message::new_broker_message(
+            "not a number".to_string(),
+            partition.clone(),
+            1,
+            Utc::now(),
+        ));
+        match result {
+            Err(SubmitError::InvalidMessage(invalid)) => {
+                assert_eq!(
+                    invalid,
+                    InvalidMessage {
+                        partition,
+                        offset: 1
+                    }
+                );
+            }
+            _ => panic!("Expected an InvalidMessage error"),
+        }
+
+        assert_eq!(submitted.payloads(), vec![10]);
+    }
+}
@@ -0,0 +1,410 @@
+use crate::processing::strategies::timeout::{TaskStart, Timeout};
+use crate::processing::strategies::{
+    report_invalid_message_on_join, CommitRequest, InvalidMessage, MessageRejected,
+    ProcessingStrategy, StrategyDescription, SubmitError,
+};
+use crate::types::{Message, Partition};
+use crate::utils::timing::Deadline;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use log::warn;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+use tokio::runtime::{Builder, Handle, Runtime};
+use tokio::task::JoinHandle;
+
+pub type AsyncTaskFunction<TPayload, TTransformed> =
+    Arc<dyn Fn(TPayload) -> BoxFuture<'static, Result<TTransformed, InvalidMessage>> + Send + Sync>;
+
+type TaskHandle<TTransformed> = JoinHandle<Result<TTransformed, InvalidMessage>>;
+
+// An in flight message: its metadata, the partitions it commits, and the
+// handle of the task producing its new payload with when it started.
+struct Task<TTransformed> {
+    message: Message<()>,
+    partitions: Vec<Partition>,
+    handle: TaskHandle<TTransformed>,
+    start: TaskStart,
+}
+
+/// Runs the future returned by ``function`` for every submitted payload as a
+/// Tokio task. This is meant for IO bound work, such as HTTP requests, where
+/// many messages can be waited on at once without a thread each.
+///
+/// Results are forwarded in the order the messages were submitted within
+/// each partition, a slow message only holds back the messages of its own
+/// partition so offsets are never committed past it. A message that commits
+/// several partitions, such as a batch, holds back the later messages of
+/// all of them, and waits for the earlier ones.
+///
+/// At most ``max_pending_tasks`` tasks run at any time, once that limit is
+/// reached ``submit`` returns ``MessageRejected``.
+///
+/// If a task timeout is set, a task still running that long after it
+/// started is cancelled and treated as if it had failed, see ``Timeout``.
+pub struct RunTaskInAsyncTasks<TPayload: Clone + Send + Sync, TTransformed: Clone + Send + Sync> {
+    function: AsyncTaskFunction<TPayload, TTransformed>,
+    next_step: Box<dyn ProcessingStrategy<TTransformed>>,
+    // Only set if the strategy created its own runtime, which has to live
+    // as long as the tasks spawned on it.
+    _runtime: Option<Runtime>,
+    handle: Handle,
+    // In the order the messages were submitted.
+    tasks: VecDeque<Task<TTransformed>>,
+    message_carried_over: Option<Message<TTransformed>>,
+    max_pending_tasks: usize,
+    task_timeout: Option<Timeout>,
+    closed: bool,
+}
+
+impl<TPayload, TTransformed> RunTaskInAsyncTasks<TPayload, TTransformed>
+where
+    TPayload: Clone + Send + Sync + 'static,
+    TTransformed: Clone + Send + Sync + 'static,
+{
+    /// Runs the tasks on a runtime owned by the strategy, with a single
+    /// worker thread.
+    pub fn new(
+        function: AsyncTaskFunction<TPayload, TTransformed>,
+        next_step: Box<dyn ProcessingStrategy<TTransformed>>,
+        max_pending_tasks: usize,
+    ) -> Self {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("arroyo-async-task")
+            .enable_all()
+            .build()
+            .unwrap();
+        let handle = runtime.handle().clone();
+        Self::build(
+            function,
+            next_step,
+            Some(runtime),
+            handle,
+            max_pending_tasks,
+        )
+    }
+
+    /// Runs the tasks on an existing runtime.
+    pub fn new_with_handle(
+        function: AsyncTaskFunction<TPayload, TTransformed>,
+        next_step: Box<dyn ProcessingStrategy<TTransformed>>,
+        handle: Handle,
+        max_pending_tasks: usize,
+    ) -> Self {
+        Self::build(function, next_step, None, handle, max_pending_tasks)
+    }
+
+    fn build(
+        function: AsyncTaskFunction<TPayload, TTransformed>,
+        next_step: Box<dyn ProcessingStrategy<TTransformed>>,
+        runtime: Option<Runtime>,
+        handle: Handle,
+        max_pending_tasks: usize,
+    ) -> Self {
+        Self {
+            function,
+            next_step,
+            _runtime: runtime,
+            handle,
+            tasks: VecDeque::new(),
+            message_carried_over: None,
+            max_pending_tasks,
+            task_timeout: None,
+            closed: false,
+        }
+    }
+
+    pub fn with_task_timeout(mut self, task_timeout: Duration) -> Self {
+        self.task_timeout = Some(Timeout::new(task_timeout, "RunTaskInAsyncTasks"));
+        self
+    }
+
+    fn forward(&mut self, message: Message<TTransformed>) -> Result<bool, InvalidMessage> {
+        match self.next_step.submit(message) {
+            Ok(()) => Ok(true),
+            Err(SubmitError::MessageRejected(MessageRejected { message })) => {
+                self.message_carried_over = Some(message);
+                Ok(false)
+            }
+            Err(SubmitError::InvalidMessage(invalid)) => Err(invalid),
+        }
+    }
+
+    /// Forwards the result of every task that has completed and that no
+    /// earlier task still running shares a partition with, until the next
+    /// step rejects one.
+    fn forward_completed(&mut self) -> Result<(), InvalidMessage> {
+        if let Some(message) = self.message_carried_over.take() {
+            if !self.forward(message)? {
+                return Ok(());
+            }
+        }
+
+        // The partitions of the tasks that are still running so far.
+        let mut blocked: HashSet<Partition> = HashSet::new();
+        let mut index = 0;
+        while index < self.tasks.len() {
+            let task = &mut self.tasks[index];
+            if task.partitions.iter().any(|p| blocked.contains(p)) {
+                blocked.extend(task.partitions.iter().cloned());
+                index += 1;
+                continue;
+            }
+            let result = match (&mut task.handle).now_or_never() {
+                Some(result) => result,
+                None if self
+                    .task_timeout
+                    .is_some_and(|timeout| timeout.has_expired(&task.start)) =>
+                {
+                    task.handle.abort();
+                    let task = self.tasks.remove(index).unwrap();
+                    match self.task_timeout.unwrap().expire(&task.message) {
+                        Some(invalid) => return Err(invalid),
+                        None => continue,
+                    }
+                }
+                None => {
+                    blocked.extend(task.partitions.iter().cloned());
+                    index += 1;
+                    continue;
+                }
+            };
+            let Task { message, .. } = self.tasks.remove(index).unwrap();
+            let transformed = match result {
+                Ok(transformed) => transformed?,
+                Err(error) => panic!("Task for {} failed: {}", message, error),
+            };
+            if !self.forward(message.replace(transformed))? {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<TPayload, TTransformed> ProcessingStrategy<TPayload>
+    for RunTaskInAsyncTasks<TPayload, TTransformed>
+where
+    TPayload: Clone + Send + Sync + 'static,
+    TTransformed: Clone + Send + Sync + 'static,
+{
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        self.forward_completed()?;
+        self.next_step.poll()
+    }
+
+    fn submit(&mut self, message: Message<TPayload>) -> Result<(), SubmitError<TPayload>> {
+        if self.closed {
+            panic!("Attempted to submit a message to a closed RunTaskInAsyncTasks strategy")
+        }
+        if self.tasks.len() >= self.max_pending_tasks {
+            return Err(SubmitError::MessageRejected(MessageRejected { message }));
+        }
+
+        let (task, start) = Timeout::decorate((self.function)(message.payload()));
+        let handle = self.handle.spawn(task);
+        self.tasks.push_back(Task {
+            partitions: message.committable().into_keys().collect(),
+            message: message.replace(()),
+            handle,
+            start,
+        });
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+    }
+
+    fn terminate(&mut self) {
+        self.closed = true;
+        for task in self.tasks.drain(..) {
+            task.handle.abort();
+        }
+        self.next_step.terminate();
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        let deadline = Deadline::from_timeout(timeout);
+
+        loop {
+            if let Err(invalid) = self.forward_completed() {
+                report_invalid_message_on_join("RunTaskInAsyncTasks", &invalid);
+            }
+            if self.tasks.is_empty() && self.message_carried_over.is_none() {
+                break;
+            }
+            if deadline.has_elapsed() {
+                warn!("Timeout reached while waiting for tasks to finish");
+                break;
+            }
+            sleep(Duration::from_millis(1));
+        }
+
+        self.next_step.close();
+        self.next_step.join(deadline.remaining())
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription::new("RunTaskInAsyncTasks")
+            .with_buffered_messages(self.tasks.len() + self.message_carried_over.is_some() as usize)
+            .with_next_step(self.next_step.describe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RunTaskInAsyncTasks;
+    use crate::processing::strategies::testutils::{partition, Recorder};
+    use crate::processing::strategies::ProcessingStrategy;
+    use crate::types::{Message, Position};
+    use chrono::Utc;
+    use futures::FutureExt;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_run_task_in_async_tasks() {
+        let recorder = Recorder::default();
+        let submitted = recorder.submitted.clone();
+        let mut strategy = RunTaskInAsyncTasks::new(
+            Arc::new(|value: u64| {
+                async move {
+                    // The first message of partition 0 completes last.
+                    if value == 1 {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                    }
+                    Ok(value)
+                }
+                .boxed()
+            }),
+            Box::new(recorder),
+            3,
+        );
+
+        for (value, index) in [(1, 0), (2, 0), (3, 1)] {
+            strategy
+                .submit(Message::new_broker_message(
+                    value,
+                    partition("test", index),
+                    value,
+                    Utc::now(),
+                ))
+                .unwrap();
+        }
+        let rejected = strategy.submit(Message::new_broker_message(
+            4,
+            partition("test", 1),
+            4,
+            Utc::now(),
+        ));
+        assert!(rejected.is_err());
+
+        // Partition 1 is not held back by partition 0
+        while submitted.payloads().is_empty() {
+            strategy.poll().unwrap();
+        }
+        assert_eq!(submitted.payloads(), vec![3]);
+
+        strategy.close();
+        strategy.join(Some(Duration::from_secs(5)));
+        assert_eq!(submitted.payloads(), vec![3, 1, 2]);
+    }
+
+    #[test]
+    fn test_message_of_several_partitions() {
+        let recorder = Recorder::default();
+        let submitted = recorder.submitted.clone();
+        let mut strategy = RunTaskInAsyncTasks::new(
+            Arc::new(|value: u64| {
+                async move {
+                    // The batch completes last.
+                    if value == 1 {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                    }
+                    Ok(value)
+                }
+                .boxed()
+            }),
+            Box::new(recorder),
+            3,
+        );
+
+        let now = Utc::now();
+        let batch = Message::new_any_message(
+            1,
+            BTreeMap::from([
+                (partition("test", 0), Position::new(10, now)),
+                (partition("test", 1), Position::new(20, now)),
+            ]),
+        );
+        strategy.submit(batch).unwrap();
+        for (value, index) in [(2, 1), (3, 2)] {
+            strategy
+                .submit(Message::new_broker_message(
+                    value,
+                    partition("test", index),
+                    30,
+                    now,
+                ))
+                .unwrap();
+        }
+
+        // Partition 1 waits for the batch, partition 2 does not.
+        while submitted.payloads().is_empty() {
+            strategy.poll().unwrap();
+        }
+        assert_eq!(submitted.payloads(), vec![3]);
+        strategy.close();
+        strategy.join(Some(Duration::from_secs(5)));
+        assert_eq!(submitted.payloads(), vec![3, 1, 2]);
+    }
+
+    #[test]
+    fn test_task_timeout() {
+        let partition = partition("test", 0);
+        let recorder = Recorder::default();
+        let submitted = recorder.submitted.clone();
+        let mut strategy = RunTaskInAsyncTasks::new(
+            Arc::new(|value: u64| {
+                async move {
+                    if value == 1 {
+                        futures::future::pending::<()>().await;
+                    }
+                    Ok(value)
+                }
+                .boxed()
+            }),
+            Box::new(recorder),
+            2,
+        )
+        .with_task_timeout(Duration::from_millis(20));
+
+        for offset in 1..=2 {
+            strategy
+                .submit(Message::new_broker_message(
+                    offset,
+                    partition.clone(),
+                    offset,
+                    Utc::now(),
+                ))
+                .unwrap();
+        }
+
+        // The task that never completes is cancelled.
+        let invalid = loop {
+            if let Err(invalid) = strategy.poll() {
+                break invalid;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(invalid.offset, 1);
+        strategy.close();
+        strategy.join(Some(Duration::from_secs(5)));
+        assert_eq!(submitted.payloads(), vec![2]);
+    }
+}
@@ -0,0 +1,333 @@
+use crate::processing::strategies::timeout::{TaskStart, Timeout};
+use crate::processing::strategies::{
+    report_invalid_message_on_join, CommitRequest, InvalidMessage, MessageRejected,
+    ProcessingStrategy, StrategyDescription, SubmitError,
+};
+use crate::types::Message;
+use crate::utils::timing::Deadline;
+use futures::FutureExt;
+use log::warn;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+use tokio::runtime::{Builder, Runtime};
+use tokio::task::JoinHandle;
+
+pub type TaskFunction<TPayload, TTransformed> =
+    Arc<dyn Fn(TPayload) -> Result<TTransformed, InvalidMessage> + Send + Sync>;
+
+type TaskHandle<TTransformed> = JoinHandle<Result<TTransformed, InvalidMessage>>;
+
+/// Runs ``function`` on every submitted payload using a pool of
+/// ``concurrency`` threads and forwards the results to the next step in the
+/// same order the messages were submitted.
+///
+/// At most ``max_pending_tasks`` messages can be in flight at any time, once
+/// that limit is reached ``submit`` returns ``MessageRejected``. If
+/// ``function`` fails, its ``InvalidMessage`` error is returned by the
+/// ``poll`` call that picks up the result.
+///
+/// If a task timeout is set, a task still running that long after it
+/// started is treated as if ``function`` had failed, see ``Timeout``. A
+/// running function cannot be stopped, so its work is abandoned rather than
+/// cancelled: the thread stays busy until the function returns and its
+/// result is dropped. The pool has one thread less for the other tasks in
+/// the meantime, they are not timed out for it since their clock only
+/// starts when they run.
+pub struct RunTaskInThreads<TPayload: Clone + Send + Sync, TTransformed: Clone + Send + Sync> {
+    function: TaskFunction<TPayload, TTransformed>,
+    next_step: Box<dyn ProcessingStrategy<TTransformed>>,
+    runtime: Runtime,
+    // The metadata of each in flight message together with the handle of the
+    // task producing its new payload and when it started.
+    handles: VecDeque<(Message<()>, TaskHandle<TTransformed>, TaskStart)>,
+    message_carried_over: Option<Message<TTransformed>>,
+    max_pending_tasks: usize,
+    task_timeout: Option<Timeout>,
+    closed: bool,
+}
+
+impl<TPayload, TTransformed> RunTaskInThreads<TPayload, TTransformed>
+where
+    TPayload: Clone + Send + Sync + 'static,
+    TTransformed: Clone + Send + Sync + 'static,
+{
+    pub fn new(
+        function: TaskFunction<TPayload, TTransformed>,
+        next_step: Box<dyn ProcessingStrategy<TTransformed>>,
+        concurrency: usize,
+        max_pending_tasks: usize,
+    ) -> Self {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(concurrency)
+            .thread_name("arroyo-run-task")
+            .build()
+            .unwrap();
+
+        Self {
+            function,
+            next_step,
+            runtime,
+            handles: VecDeque::new(),
+            message_carried_over: None,
+            max_pending_tasks,
+            task_timeout: None,
+            closed: false,
+        }
+    }
+
+    pub fn with_task_timeout(mut self, task_timeout: Duration) -> Self {
+        self.task_timeout = Some(Timeout::new(task_timeout, "RunTaskInThreads"));
+        self
+    }
+
+    fn forward(&mut self, message: Message<TTransformed>) -> Result<bool, InvalidMessage> {
+        match self.next_step.submit(message) {
+            Ok(()) => Ok(true),
+            Err(SubmitError::MessageRejected(MessageRejected { message })) => {
+                self.message_carried_over = Some(message);
+                Ok(false)
+            }
+            Err(SubmitError::InvalidMessage(invalid)) => Err(invalid),
+        }
+    }
+
+    /// Forwards the result of every task at the head of the queue that has
+    /// completed, stopping at the first one still running.
+    fn forward_completed(&mut self) -> Result<(), InvalidMessage> {
+        if let Some(message) = self.message_carried_over.take() {
+            if !self.forward(message)? {
+                return Ok(());
+            }
+        }
+
+        while let Some((_, handle, start)) = self.handles.front_mut() {
+            let result = match handle.now_or_never() {
+                Some(result) => result,
+                None if self
+                    .task_timeout
+                    .is_some_and(|timeout| timeout.has_expired(start)) =>
+                {
+                    handle.abort();
+                    let (message, _, _) = self.handles.pop_front().unwrap();
+                    match self.task_timeout.unwrap().expire(&message) {
+                        Some(invalid) => return Err(invalid),
+                        None => continue,
+                    }
+                }
+                None => break,
+            };
+            let (message, _, _) = self.handles.pop_front().unwrap();
+            let transformed = match result {
+                Ok(transformed) => transformed?,
+                Err(error) => panic!("Task for {} failed: {}", message, error),
+            };
+            if !self.forward(message.replace(transformed))? {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<TPayload, TTransformed> ProcessingStrategy<TPayload>
+    for RunTaskInThreads<TPayload, TTransformed>
+where
+    TPayload: Clone + Send + Sync + 'static,
+    TTransformed: Clone + Send + Sync + 'static,
+{
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        self.forward_completed()?;
+        self.next_step.poll()
+    }
+
+    fn submit(&mut self, message: Message<TPayload>) -> Result<(), SubmitError<TPayload>> {
+        if self.closed {
+            panic!("Attempted to submit a message to a closed RunTaskInThreads strategy")
+        }
+        if self.handles.len() >= self.max_pending_tasks {
+            return Err(SubmitError::MessageRejected(MessageRejected { message }));
+        }
+
+        let function = self.function.clone();
+        let payload = message.payload();
+        let (task, start) = Timeout::decorate(async move { function(payload) });
+        let handle = self.runtime.spawn(task);
+        self.handles.push_back((message.replace(()), handle, start));
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+    }
+
+    fn terminate(&mut self) {
+        self.closed = true;
+        for (_, handle, _) in self.handles.drain(..) {
+            handle.abort();
+        }
+        self.next_step.terminate();
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        let deadline = Deadline::from_timeout(timeout);
+
+        loop {
+            if let Err(invalid) = self.forward_completed() {
+                report_invalid_message_on_join("RunTaskInThreads", &invalid);
+            }
+            if self.handles.is_empty() && self.message_carried_over.is_none() {
+                break;
+            }
+            if deadline.has_elapsed() {
+                warn!("Timeout reached while waiting for tasks to finish");
+                break;
+            }
+            sleep(Duration::from_millis(1));
+        }
+
+        self.next_step.close();
+        self.next_step.join(deadline.remaining())
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription::new("RunTaskInThreads")
+            .with_buffered_messages(
+                self.handles.len() + self.message_carried_over.is_some() as usize,
+            )
+            .with_next_step(self.next_step.describe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RunTaskInThreads;
+    use crate::processing::strategies::testutils::{partition, Recorder};
+    use crate::processing::strategies::{InvalidMessage, ProcessingStrategy};
+    use crate::types::Message;
+    use chrono::Utc;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_run_task_in_threads() {
+        let partition = partition("test", 0);
+        let recorder = Recorder::default();
+        let submitted = recorder.submitted.clone();
+        let mut strategy = RunTaskInThreads::new(
+            Arc::new(|value: u64| -> Result<u64, InvalidMessage> {
+                // Slow down the first task so that it completes last.
+                if value == 1 {
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                Ok(value * 2)
+            }),
+            Box::new(recorder),
+            2,
+            2,
+        );
+
+        for offset in 1..=2 {
+            strategy
+                .submit(Message::new_broker_message(
+                    offset,
+                    partition.clone(),
+                    offset,
+                    Utc::now(),
+                ))
+                .unwrap();
+        }
+
+        // The pool is full
+        let rejected = strategy.submit(Message::new_broker_message(
+            3,
+            partition.clone(),
+            3,
+            Utc::now(),
+        ));
+        assert!(rejected.is_err());
+
+        strategy.close();
+        strategy.join(Some(Duration::from_secs(5)));
+
+        // Results are forwarded in submission order
+        assert_eq!(submitted.payloads(), vec![2, 4]);
+    }
+
+    #[test]
+    fn test_task_timeout() {
+        let partition = partition("test", 0);
+        let recorder = Recorder::default();
+        let submitted = recorder.submitted.clone();
+        let mut strategy = RunTaskInThreads::new(
+            Arc::new(|value: u64| -> Result<u64, InvalidMessage> {
+                if value == 1 {
+                    std::thread::sleep(Duration::from_millis(500));
+                }
+                Ok(value)
+            }),
+            Box::new(recorder),
+            2,
+            2,
+        )
+        .with_task_timeout(Duration::from_millis(20));
+
+        for offset in 1..=2 {
+            strategy
+                .submit(Message::new_broker_message(
+                    offset,
+                    partition.clone(),
+                    offset,
+                    Utc::now(),
+                ))
+                .unwrap();
+        }
+
+        // The slow message is given up on, the next one is not held back.
+        let invalid = loop {
+            if let Err(invalid) = strategy.poll() {
+                break invalid;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(invalid.offset, 1);
+        strategy.poll().unwrap();
+        assert_eq!(submitted.payloads(), vec![2]);
+    }
+
+    #[test]
+    fn test_task_timeout_starts_with_task() {
+        let partition = partition("test", 0);
+        let recorder = Recorder::default();
+        let submitted = recorder.submitted.clone();
+        let mut strategy = RunTaskInThreads::new(
+            Arc::new(|value: u64| -> Result<u64, InvalidMessage> {
+                std::thread::sleep(Duration::from_millis(100));
+                Ok(value)
+            }),
+            Box::new(recorder),
+            1,
+            2,
+        )
+        .with_task_timeout(Duration::from_millis(150));
+
+        // The second task waits for the only thread, longer than the timeout
+        // once it is done, but not after it started.
+        for offset in 1..=2 {
+            strategy
+                .submit(Message::new_broker_message(
+                    offset,
+                    partition.clone(),
+                    offset,
+                    Utc::now(),
+                ))
+                .unwrap();
+        }
+        while submitted.payloads().len() < 2 {
+            strategy.poll().unwrap();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(submitted.payloads(), vec![1, 2]);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::processing::strategies::filter::Filter;
+use crate::processing::strategies::{
+    CommitRequest, InvalidMessage, ProcessingStrategy, StrategyDescription, SubmitError,
+};
+use crate::types::Message;
+use rand::Rng;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Returns the bytes a deterministic sample is taken by, such as the key of
+/// the message.
+pub type SampleKey<TPayload> = Arc<dyn Fn(&TPayload) -> Vec<u8> + Send + Sync>;
+
+// FNV-1a followed by the finalizer of MurmurHash3 to spread keys that only
+// differ by their last bytes. Unlike the hasher of the standard library it
+// is guaranteed to give the same hash in every version.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+// Maps the key to a number in [0, 1).
+fn sample_point(key: &[u8]) -> f64 {
+    (hash(key) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Forwards a fraction ``rate`` of the messages to the next step and drops
+/// the others, to run a canary pipeline or to load test a downstream system
+/// with part of the traffic.
+///
+/// Messages are picked at random, or by ``key`` so that all the messages
+/// with the same key are either forwarded or dropped, on every consumer.
+pub struct Sample<TPayload: Clone> {
+    filter: Filter<TPayload>,
+}
+
+impl<TPayload: Clone + Send + Sync + 'static> Sample<TPayload> {
+    pub fn new(rate: f64, next_step: Box<dyn ProcessingStrategy<TPayload>>) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&rate),
+            "the rate must be between 0 and 1"
+        );
+        Sample {
+            filter: Filter::new(
+                Arc::new(move |_: &TPayload| rand::thread_rng().gen_bool(rate)),
+                next_step,
+            ),
+        }
+    }
+
+    pub fn new_by_key(
+        rate: f64,
+        key: SampleKey<TPayload>,
+        next_step: Box<dyn ProcessingStrategy<TPayload>>,
+    ) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&rate),
+            "the rate must be between 0 and 1"
+        );
+        Sample {
+            filter: Filter::new(
+                Arc::new(move |payload: &TPayload| sample_point(&key(payload)) < rate),
+                next_step,
+            ),
+        }
+    }
+}
+
+impl<TPayload: Clone + Send + Sync> ProcessingStrategy<TPayload> for Sample<TPayload> {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        self.filter.poll()
+    }
+
+    fn submit(&mut self, message: Message<TPayload>) -> Result<(), SubmitError<TPayload>> {
+        self.filter.submit(message)
+    }
+
+    fn close(&mut self) {
+        self.filter.close();
+    }
+
+    fn terminate(&mut self) {
+        self.filter.terminate();
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        self.filter.join(timeout)
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription {
+            name: "Sample".to_string(),
+            ..self.filter.describe()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sample_point, Sample};
+    use crate::processing::strategies::testutils::{partition, Recorder};
+    use crate::processing::strategies::ProcessingStrategy;
+    use crate::types::Message;
+    use chrono::Utc;
+    use std::sync::Arc;
+
+    fn submit_all(strategy: &mut Sample<u64>, count: u64) {
+        let partition = partition("test", 0);
+        for offset in 0..count {
+            strategy
+                .submit(Message::new_broker_message(
+                    offset,
+                    partition.clone(),
+                    offset,
+                    Utc::now(),
+                ))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_sample() {
+        let recorder = Recorder::default();
+        let submitted = recorder.submitted.clone();
+        let mut strategy = Sample::new(0.1, Box::new(recorder));
+        submit_all(&mut strategy, 10_000);
+        let forwarded = submitted.payloads().len();
+        assert!((800..1200).contains(&forwarded), "{}", forwarded);
+    }
+
+    #[test]
+    fn test_sample_by_key() {
+        let sample = |rate| {
+            let recorder = Recorder::default();
+            let submitted = recorder.submitted.clone();
+            let mut strategy = Sample::new_by_key(
+                rate,
+                Arc::new(|value: &u64| (value % 100).to_be_bytes().to_vec()),
+                Box::new(recorder),
+            );
+            submit_all(&mut strategy, 1000);
+            drop(strategy);
+            submitted.payloads()
+        };
+
+        // The same keys are picked every time, and a higher rate picks the
+        // keys of a lower one.
+        let half = sample(0.5);
+        assert_eq!(sample(0.5), half);
+        assert!(half
+            .iter()
+            .all(|value| sample_point(&(value % 100).to_be_bytes()) < 0.5));
+        assert!(half.len() > 200 && half.len() < 800);
+        let more = sample(0.8);
+        assert!(half.iter().all(|value| more.contains(value)));
+        assert!(sample(0.0).is_empty());
+        assert_eq!(sample(1.0).len(), 1000);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::processing::strategies::{
+    CommitRequest, InvalidMessage, MessageRejected, ProcessingStrategy, StrategyDescription,
+    SubmitError,
+};
+use crate::types::Message;
+use crate::utils::metrics;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+const THROUGHPUT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Calls mostly take less than a millisecond, they are rounded instead of
+// being truncated to zero.
+fn millis(duration: Duration) -> u64 {
+    (duration.as_secs_f64() * 1000.0).round() as u64
+}
+
+/// Wraps a strategy and records how long its ``submit`` and ``poll`` calls
+/// take, how many messages it rejects and how many it accepts per second.
+/// All metrics are tagged with ``name``, so that the steps of a pipeline can
+/// be told apart.
+pub struct StrategyMetrics<TPayload: Clone> {
+    name: String,
+    next_step: Box<dyn ProcessingStrategy<TPayload>>,
+    window_start: Instant,
+    accepted: u64,
+}
+
+impl<TPayload: Clone> StrategyMetrics<TPayload> {
+    pub fn new(name: &str, next_step: Box<dyn ProcessingStrategy<TPayload>>) -> Self {
+        Self {
+            name: name.to_string(),
+            next_step,
+            window_start: Instant::now(),
+            accepted: 0,
+        }
+    }
+
+    fn tags(&self) -> Option<HashMap<&str, &str>> {
+        Some(HashMap::from([("strategy", self.name.as_str())]))
+    }
+
+    fn maybe_report_throughput(&mut self) {
+        let elapsed = self.window_start.elapsed();
+        if elapsed < THROUGHPUT_INTERVAL {
+            return;
+        }
+        let rate = self.accepted as f64 / elapsed.as_secs_f64();
+        metrics::gauge(
+            "arroyo.strategies.messages_per_second",
+            rate.round() as u64,
+            self.tags(),
+            None,
+        );
+        self.window_start = Instant::now();
+        self.accepted = 0;
+    }
+}
+
+impl<TPayload: Clone> ProcessingStrategy<TPayload> for StrategyMetrics<TPayload> {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        let start = Instant::now();
+        let request = self.next_step.poll();
+        metrics::time(
+            "arroyo.strategies.poll.time",
+            millis(start.elapsed()),
+            self.tags(),
+            None,
+        );
+        self.maybe_report_throughput();
+        request
+    }
+
+    fn submit(&mut self, message: Message<TPayload>) -> Result<(), SubmitError<TPayload>> {
+        let start = Instant::now();
+        let result = self.next_step.submit(message);
+        metrics::time(
+            "arroyo.strategies.submit.time",
+            millis(start.elapsed()),
+            self.tags(),
+            None,
+        );
+        match &result {
+            Ok(()) => self.accepted += 1,
+            Err(SubmitError::MessageRejected(MessageRejected { .. })) => {
+                metrics::increment("arroyo.strategies.rejected.count", None, self.tags(), None);
+            }
+            Err(SubmitError::InvalidMessage(_)) => {}
+        }
+        result
+    }
+
+    fn close(&mut self) {
+        self.next_step.close();
+    }
+
+    fn terminate(&mut self) {
+        self.next_step.terminate();
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        self.next_step.join(timeout)
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription::new("StrategyMetrics").with_next_step(self.next_step.describe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{millis, StrategyMetrics};
+    use crate::processing::strategies::{
+        CommitRequest, InvalidMessage, MessageRejected, ProcessingStrategy, SubmitError,
+    };
+    use crate::types::Message;
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    // Accepts every other message
+    struct Alternating {
+        accept: bool,
+    }
+    impl ProcessingStrategy<u64> for Alternating {
+        fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+            Ok(None)
+        }
+        fn submit(&mut self, message: Message<u64>) -> Result<(), SubmitError<u64>> {
+            self.accept = !self.accept;
+            if self.accept {
+                Ok(())
+            } else {
+                Err(SubmitError::MessageRejected(MessageRejected { message }))
+            }
+        }
+        fn close(&mut self) {}
+        fn terminate(&mut self) {}
+        fn join(&mut self, _timeout: Option<Duration>) -> Option<CommitRequest> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_strategy_metrics() {
+        let mut strategy =
+            StrategyMetrics::new("alternating", Box::new(Alternating { accept: false }));
+
+        for i in 0..4 {
+            let result = strategy.submit(Message::new_any_message(i, BTreeMap::new()));
+            assert_eq!(result.is_ok(), i % 2 == 0);
+        }
+        assert_eq!(strategy.accepted, 2);
+        assert!(strategy.poll().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_millis() {
+        assert_eq!(millis(Duration::from_micros(400)), 0);
+        assert_eq!(millis(Duration::from_micros(600)), 1);
+        assert_eq!(millis(Duration::from_millis(1500)), 1500);
+    }
+}
@@ -0,0 +1,234 @@
+use crate::processing::strategies::{
+    report_invalid_message_on_join, CommitRequest, InvalidMessage, MessageRejected,
+    ProcessingStrategy, StrategyDescription, SubmitError,
+};
+use crate::types::{Message, Partition, Position};
+use crate::utils::timing::Deadline;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Submits every message to each of ``branches``, which process it on their
+/// own, for example to write it to a database and produce it to another
+/// topic.
+///
+/// The offset of a message is only committed once all the branches have
+/// committed it, so no branch loses messages if the consumer restarts.
+/// Branches that reject a message get it again on the next ``poll``, the
+/// others are not submitted it twice. A message that is invalid for any
+/// branch is raised as invalid, even if other branches already accepted it.
+///
+/// On ``join`` every branch gets an even share of the time the branches
+/// before it left, so that a slow branch does not keep the others from
+/// being flushed.
+pub struct Tee<T: Clone> {
+    branches: Vec<Box<dyn ProcessingStrategy<T>>>,
+    commits: SharedCommits,
+    // A message and the branches that still have to accept it.
+    carried_over: Option<(Message<T>, Vec<usize>)>,
+}
+
+/// Tracks the offsets committed by several strategies that were all
+/// submitted the same messages, to only commit an offset once all of them
+/// committed it.
+pub struct SharedCommits {
+    // The positions each strategy committed.
+    committed: Vec<HashMap<Partition, Position>>,
+    // The offsets last committed by all strategies.
+    shared: HashMap<Partition, u64>,
+}
+
+impl SharedCommits {
+    pub fn new(strategies: usize) -> Self {
+        SharedCommits {
+            committed: vec![HashMap::new(); strategies],
+            shared: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, index: usize, commit_request: Option<CommitRequest>) {
+        if let Some(commit_request) = commit_request {
+            self.committed[index].extend(commit_request.positions);
+        }
+    }
+
+    /// Returns the offsets all strategies committed since the last call.
+    pub fn take(&mut self) -> Option<CommitRequest> {
+        let (first, others) = self.committed.split_first()?;
+        let mut positions = HashMap::new();
+        for (partition, position) in first {
+            let position = others
+                .iter()
+                .map(|committed| committed.get(partition).copied())
+                .try_fold(*position, |lowest, position| {
+                    let position = position?;
+                    Some(match position.offset < lowest.offset {
+                        true => position,
+                        false => lowest,
+                    })
+                });
+            if let Some(position) = position {
+                if self.shared.get(partition) != Some(&position.offset) {
+                    positions.insert(partition.clone(), position);
+                }
+            }
+        }
+        if positions.is_empty() {
+            return None;
+        }
+        self.shared.extend(
+            positions
+                .iter()
+                .map(|(partition, position)| (partition.clone(), position.offset)),
+        );
+        Some(CommitRequest { positions })
+    }
+}
+
+impl<T: Clone> Tee<T> {
+    pub fn new(branches: Vec<Box<dyn ProcessingStrategy<T>>>) -> Self {
+        Tee {
+            commits: SharedCommits::new(branches.len()),
+            branches,
+            carried_over: None,
+        }
+    }
+
+    // Returns the branches that rejected the message.
+    fn submit_to(
+        &mut self,
+        message: &Message<T>,
+        branches: Vec<usize>,
+    ) -> Result<Vec<usize>, InvalidMessage> {
+        let mut rejected = Vec::new();
+        for index in branches {
+            match self.branches[index].submit(message.clone()) {
+                Ok(()) => {}
+                Err(SubmitError::MessageRejected(_)) => rejected.push(index),
+                Err(SubmitError::InvalidMessage(invalid)) => return Err(invalid),
+            }
+        }
+        Ok(rejected)
+    }
+
+    fn submit_carried_over(&mut self) -> Result<(), InvalidMessage> {
+        if let Some((message, branches)) = self.carried_over.take() {
+            let rejected = self.submit_to(&message, branches)?;
+            if !rejected.is_empty() {
+                self.carried_over = Some((message, rejected));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: Clone + Send + Sync> ProcessingStrategy<T> for Tee<T> {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        self.submit_carried_over()?;
+        for index in 0..self.branches.len() {
+            let commit_request = self.branches[index].poll()?;
+            self.commits.record(index, commit_request);
+        }
+        Ok(self.commits.take())
+    }
+
+    fn submit(&mut self, message: Message<T>) -> Result<(), SubmitError<T>> {
+        if self.carried_over.is_some() {
+            return Err(SubmitError::MessageRejected(MessageRejected { message }));
+        }
+        let rejected = self.submit_to(&message, (0..self.branches.len()).collect())?;
+        if !rejected.is_empty() {
+            self.carried_over = Some((message, rejected));
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        for branch in &mut self.branches {
+            branch.close();
+        }
+    }
+
+    fn terminate(&mut self) {
+        for branch in &mut self.branches {
+            branch.terminate();
+        }
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        let deadline = Deadline::from_timeout(timeout);
+        if let Err(invalid) = self.submit_carried_over() {
+            report_invalid_message_on_join("Tee", &invalid);
+        }
+        let count = self.branches.len();
+        for index in 0..count {
+            let commit_request = self.branches[index].join(deadline.split((count - index) as u32));
+            self.commits.record(index, commit_request);
+        }
+        self.commits.take()
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        let mut description = StrategyDescription::new("Tee")
+            .with_buffered_messages(self.carried_over.is_some() as usize);
+        for branch in &self.branches {
+            description = description.with_next_step(branch.describe());
+        }
+        description
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tee;
+    use crate::processing::strategies::testutils::{partition, Recorder, SlowJoin};
+    use crate::processing::strategies::{CommitRequest, ProcessingStrategy};
+    use crate::types::{Message, Position};
+    use chrono::Utc;
+    use std::collections::HashMap;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    #[test]
+    fn test_tee() {
+        let partition = partition("test", 0);
+        let now = Utc::now();
+        let slow = Recorder::committing();
+        let hold = slow.hold.clone();
+        let mut tee = Tee::new(vec![Box::new(Recorder::committing()), Box::new(slow)]);
+
+        tee.submit(Message::new_broker_message(0, partition.clone(), 0, now))
+            .unwrap();
+        assert_eq!(
+            tee.poll().unwrap(),
+            Some(CommitRequest {
+                positions: HashMap::from([(partition.clone(), Position::new(1, now))])
+            })
+        );
+
+        // The message is only committed once the slow branch committed it.
+        hold.store(true, Ordering::Relaxed);
+        tee.submit(Message::new_broker_message(1, partition.clone(), 1, now))
+            .unwrap();
+        assert_eq!(tee.poll().unwrap(), None);
+        hold.store(false, Ordering::Relaxed);
+        assert_eq!(
+            tee.poll().unwrap(),
+            Some(CommitRequest {
+                positions: HashMap::from([(partition.clone(), Position::new(2, now))])
+            })
+        );
+        assert_eq!(tee.poll().unwrap(), None);
+    }
+
+    #[test]
+    fn test_join_splits_timeout() {
+        let (first, second) = (SlowJoin::default(), SlowJoin::default());
+        let (first_timeout, second_timeout) = (first.timeout.clone(), second.timeout.clone());
+        let mut tee: Tee<u64> = Tee::new(vec![Box::new(first), Box::new(second)]);
+        tee.close();
+        tee.join(Some(Duration::from_millis(100)));
+
+        assert!(first_timeout.lock().unwrap().unwrap() <= Duration::from_millis(50));
+        assert!(second_timeout.lock().unwrap().unwrap() >= Duration::from_millis(40));
+    }
+}
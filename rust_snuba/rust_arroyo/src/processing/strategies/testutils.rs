@@ -0,0 +1,213 @@
+use crate::backends::{ProduceFuture, Producer, ProducerError};
+use crate::processing::strategies::{
+    CommitRequest, InvalidMessage, MessageRejected, ProcessingStrategy, SubmitError,
+};
+use crate::types::{BrokerMessage, Message, Partition, Position, Topic, TopicOrPartition};
+use chrono::Utc;
+use futures::future;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+pub fn partition(topic: &str, index: u16) -> Partition {
+    Partition {
+        topic: Topic {
+            name: topic.to_string(),
+        },
+        index,
+    }
+}
+
+/// The messages a ``Recorder`` accepted, shared with the test.
+pub struct Submitted<T: Clone>(Arc<Mutex<Vec<Message<T>>>>);
+
+impl<T: Clone> Clone for Submitted<T> {
+    fn clone(&self) -> Self {
+        Submitted(self.0.clone())
+    }
+}
+
+impl<T: Clone> Submitted<T> {
+    pub fn messages(&self) -> MutexGuard<'_, Vec<Message<T>>> {
+        self.0.lock().unwrap()
+    }
+
+    pub fn payloads(&self) -> Vec<T> {
+        self.messages()
+            .iter()
+            .map(|message| message.payload())
+            .collect()
+    }
+}
+
+/// Stands for the next step of the strategy under test. It records the
+/// messages it accepts and rejects them while ``reject`` is set.
+///
+/// A ``committing`` recorder commits the messages it accepted on the next
+/// poll, unless ``hold`` is set, and on join. Submitting a message after
+/// ``close`` panics, as it does with the strategies that produce.
+pub struct Recorder<T: Clone> {
+    pub submitted: Submitted<T>,
+    pub reject: Arc<AtomicBool>,
+    pub hold: Arc<AtomicBool>,
+    commits: bool,
+    closed: bool,
+    pending: HashMap<Partition, Position>,
+}
+
+impl<T: Clone> Default for Recorder<T> {
+    fn default() -> Self {
+        Recorder {
+            submitted: Submitted(Arc::new(Mutex::new(Vec::new()))),
+            reject: Arc::new(AtomicBool::new(false)),
+            hold: Arc::new(AtomicBool::new(false)),
+            commits: false,
+            closed: false,
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Clone> Recorder<T> {
+    pub fn committing() -> Self {
+        Recorder {
+            commits: true,
+            ..Default::default()
+        }
+    }
+}
+
+impl<T: Clone + Send> ProcessingStrategy<T> for Recorder<T> {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        if self.hold.load(Ordering::Relaxed) || self.pending.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(CommitRequest {
+            positions: std::mem::take(&mut self.pending),
+        }))
+    }
+
+    fn submit(&mut self, message: Message<T>) -> Result<(), SubmitError<T>> {
+        assert!(!self.closed, "submitted after close");
+        if self.reject.load(Ordering::Relaxed) {
+            return Err(SubmitError::MessageRejected(MessageRejected { message }));
+        }
+        if self.commits {
+            self.pending.extend(message.committable());
+        }
+        self.submitted.messages().push(message);
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+    }
+
+    fn terminate(&mut self) {}
+
+    fn join(&mut self, _timeout: Option<Duration>) -> Option<CommitRequest> {
+        self.hold.store(false, Ordering::Relaxed);
+        self.poll().unwrap()
+    }
+}
+
+/// Stands for a next step that is slow to shut down: its ``join`` uses up
+/// the whole timeout it is given, which it records.
+#[derive(Default)]
+pub struct SlowJoin {
+    pub timeout: Arc<Mutex<Option<Duration>>>,
+}
+
+impl<T: Clone> ProcessingStrategy<T> for SlowJoin {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        Ok(None)
+    }
+
+    fn submit(&mut self, _message: Message<T>) -> Result<(), SubmitError<T>> {
+        Ok(())
+    }
+
+    fn close(&mut self) {}
+
+    fn terminate(&mut self) {}
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        *self.timeout.lock().unwrap() = timeout;
+        if let Some(timeout) = timeout {
+            std::thread::sleep(timeout);
+        }
+        None
+    }
+}
+
+/// A producer that records the payloads it is asked to produce and confirms
+/// their delivery right away. Messages produced to a topic land on its
+/// partition 0, at consecutive offsets.
+pub struct RecordingProducer<T> {
+    produced: Mutex<Vec<T>>,
+    failures: Mutex<u32>,
+}
+
+impl<T> Default for RecordingProducer<T> {
+    fn default() -> Self {
+        RecordingProducer {
+            produced: Mutex::new(Vec::new()),
+            failures: Mutex::new(0),
+        }
+    }
+}
+
+impl<T: Clone> RecordingProducer<T> {
+    /// A producer that fails the first ``failures`` payloads it is asked to
+    /// produce with a retriable error, without recording them.
+    pub fn failing(failures: u32) -> Self {
+        RecordingProducer {
+            produced: Mutex::new(Vec::new()),
+            failures: Mutex::new(failures),
+        }
+    }
+
+    pub fn produced(&self) -> Vec<T> {
+        self.produced.lock().unwrap().clone()
+    }
+
+    fn fail(&self) -> Option<ProducerError> {
+        let mut failures = self.failures.lock().unwrap();
+        if *failures == 0 {
+            return None;
+        }
+        *failures -= 1;
+        Some(ProducerError::BrokerError("Broker unavailable".into()))
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> Producer<T> for RecordingProducer<T> {
+    fn produce(&self, _destination: &TopicOrPartition, payload: &T) -> Result<(), ProducerError> {
+        if let Some(error) = self.fail() {
+            return Err(error);
+        }
+        self.produced.lock().unwrap().push(payload.clone());
+        Ok(())
+    }
+
+    fn produce_async(&self, destination: &TopicOrPartition, payload: &T) -> ProduceFuture<T> {
+        if let Some(error) = self.fail() {
+            return Box::pin(future::ready(Err(error)));
+        }
+        let mut produced = self.produced.lock().unwrap();
+        produced.push(payload.clone());
+        let partition = match destination {
+            TopicOrPartition::Topic(topic) => Partition {
+                topic: topic.clone(),
+                index: 0,
+            },
+            TopicOrPartition::Partition(partition) => partition.clone(),
+        };
+        let offset = produced.len() as u64 - 1;
+        let message = BrokerMessage::new(payload.clone(), partition, offset, Utc::now());
+        Box::pin(future::ready(Ok(message)))
+    }
+
+    fn close(&mut self) {}
+}
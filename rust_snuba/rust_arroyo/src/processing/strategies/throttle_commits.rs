@@ -0,0 +1,142 @@
+use crate::processing::strategies::commit_policy::CommitPolicy;
+use crate::processing::strategies::{
+    merge_commit_request, CommitRequest, InvalidMessage, ProcessingStrategy, StrategyDescription,
+    SubmitError,
+};
+use crate::types::Message;
+use crate::utils::clock::{Clock, SystemClock};
+use std::time::{Duration, SystemTime};
+
+/// Holds on to the commit requests of ``next_step`` until ``commit_policy``
+/// decides to commit, so that a next step which commits after every batch
+/// commits at the pace of the policy instead. The requests held in the
+/// meantime are merged, ``join`` returns them whatever the policy.
+pub struct ThrottleCommits<TPayload: Clone> {
+    next_step: Box<dyn ProcessingStrategy<TPayload>>,
+    commit_policy: Box<dyn CommitPolicy>,
+    pending: Option<CommitRequest>,
+    // The offsets merged into ``pending``.
+    staged_count: u64,
+    last_commit_time: SystemTime,
+    clock: Box<dyn Clock>,
+}
+
+impl<TPayload: Clone> ThrottleCommits<TPayload> {
+    pub fn new(
+        next_step: Box<dyn ProcessingStrategy<TPayload>>,
+        commit_policy: Box<dyn CommitPolicy>,
+    ) -> Self {
+        let clock = SystemClock {};
+        ThrottleCommits {
+            next_step,
+            commit_policy,
+            pending: None,
+            staged_count: 0,
+            last_commit_time: clock.time(),
+            clock: Box::new(clock),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.last_commit_time = clock.time();
+        self.clock = Box::new(clock);
+        self
+    }
+
+    fn stage(&mut self, request: Option<CommitRequest>) {
+        if let Some(request) = &request {
+            self.staged_count += request.positions.len() as u64;
+        }
+        self.pending = merge_commit_request(self.pending.take(), request);
+    }
+
+    fn take_pending(&mut self) -> Option<CommitRequest> {
+        self.staged_count = 0;
+        self.last_commit_time = self.clock.time();
+        self.pending.take()
+    }
+}
+
+impl<TPayload: Clone + Send + Sync> ProcessingStrategy<TPayload> for ThrottleCommits<TPayload> {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        let request = self.next_step.poll()?;
+        self.stage(request);
+        if self.pending.is_none() {
+            return Ok(None);
+        }
+        let elapsed = self
+            .clock
+            .time()
+            .duration_since(self.last_commit_time)
+            .unwrap_or(Duration::ZERO);
+        match self.commit_policy.should_commit(elapsed, self.staged_count) {
+            true => Ok(self.take_pending()),
+            false => Ok(None),
+        }
+    }
+
+    fn submit(&mut self, message: Message<TPayload>) -> Result<(), SubmitError<TPayload>> {
+        self.next_step.submit(message)
+    }
+
+    fn close(&mut self) {
+        self.next_step.close();
+    }
+
+    fn terminate(&mut self) {
+        self.next_step.terminate();
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        let request = self.next_step.join(timeout);
+        self.stage(request);
+        self.take_pending()
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription::new("ThrottleCommits").with_next_step(self.next_step.describe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ThrottleCommits;
+    use crate::processing::strategies::commit_policy::Periodic;
+    use crate::processing::strategies::testutils::{partition, Recorder};
+    use crate::processing::strategies::ProcessingStrategy;
+    use crate::types::Message;
+    use crate::utils::clock::{Clock, TestingClock};
+    use chrono::Utc;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_throttle_commits() {
+        let partition = partition("test", 0);
+        let clock = TestingClock::new(SystemTime::UNIX_EPOCH);
+        let policy = Periodic {
+            frequency: Duration::from_secs(1),
+            min_commit_count: None,
+        };
+        let mut strategy = ThrottleCommits::new(Box::new(Recorder::committing()), Box::new(policy))
+            .with_clock(clock.clone());
+
+        for offset in 0..3 {
+            let message =
+                Message::new_broker_message(offset, partition.clone(), offset, Utc::now());
+            strategy.submit(message).unwrap();
+            assert_eq!(strategy.poll().unwrap(), None);
+        }
+
+        clock.sleep(Duration::from_secs(1));
+        let request = strategy.poll().unwrap().unwrap();
+        assert_eq!(request.positions[&partition].offset, 3);
+        assert_eq!(strategy.poll().unwrap(), None);
+
+        // What is held is committed on join, whatever the policy.
+        let message = Message::new_broker_message(3, partition.clone(), 3, Utc::now());
+        strategy.submit(message).unwrap();
+        assert_eq!(strategy.poll().unwrap(), None);
+        let request = strategy.join(None).unwrap();
+        assert_eq!(request.positions[&partition].offset, 4);
+    }
+}
@@ -0,0 +1,105 @@
+use crate::processing::strategies::InvalidMessage;
+use crate::types::Message;
+use crate::utils::metrics;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Bounds how long the task a strategy runs for a message may take, such as
+/// those of ``RunTaskInThreads`` and ``RunTaskInAsyncTasks``, so that one
+/// message that never completes does not hold back its partition forever.
+///
+/// The tasks are decorated with ``decorate``, their clock starts when they
+/// start running rather than when they are spawned, so that a task queued
+/// behind busy workers is not given up on before it even ran. A task that
+/// runs for longer than the timeout is reported with ``expire``, and it is
+/// up to the strategy to cancel or abandon it.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeout {
+    timeout: Duration,
+    strategy: &'static str,
+}
+
+/// When a task started running, shared between the task and its strategy.
+#[derive(Debug, Clone, Default)]
+pub struct TaskStart(Arc<OnceLock<Instant>>);
+
+impl Timeout {
+    pub fn new(timeout: Duration, strategy: &'static str) -> Self {
+        Timeout { timeout, strategy }
+    }
+
+    /// Decorates the future of a task so that it records when it starts
+    /// running.
+    pub fn decorate<F: Future>(future: F) -> (impl Future<Output = F::Output>, TaskStart) {
+        let start = TaskStart::default();
+        let started = start.clone();
+        let future = async move {
+            started.0.get_or_init(Instant::now);
+            future.await
+        };
+        (future, start)
+    }
+
+    /// Whether the task has been running for longer than the timeout. A
+    /// task that did not start yet has not.
+    pub fn has_expired(&self, start: &TaskStart) -> bool {
+        start
+            .0
+            .get()
+            .is_some_and(|start| start.elapsed() >= self.timeout)
+    }
+
+    /// Reports that the task for ``message`` timed out, counted in
+    /// ``arroyo.strategies.task_timeout.count`` tagged by strategy, and
+    /// returns the error to raise for it. It is ``None`` when the message
+    /// does not commit any offset, it is then dropped with the error.
+    pub fn expire(&self, message: &Message<()>) -> Option<InvalidMessage> {
+        log::error!(
+            "Task for {} timed out after {:?} in {}",
+            message,
+            self.timeout,
+            self.strategy
+        );
+        metrics::increment(
+            "arroyo.strategies.task_timeout.count",
+            None,
+            Some(HashMap::from([("strategy", self.strategy)])),
+            None,
+        );
+        InvalidMessage::for_message(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Timeout;
+    use crate::processing::strategies::testutils::partition;
+    use crate::types::{Message, Position};
+    use chrono::Utc;
+    use futures::executor::block_on;
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    #[test]
+    fn test_timeout() {
+        let timeout = Timeout::new(Duration::ZERO, "Test");
+        let (task, start) = Timeout::decorate(async { 1 });
+
+        // The clock only starts with the task.
+        assert!(!timeout.has_expired(&start));
+        assert_eq!(block_on(task), 1);
+        assert!(timeout.has_expired(&start));
+
+        let message = Message::new_broker_message((), partition("test", 0), 5, Utc::now());
+        assert_eq!(timeout.expire(&message).unwrap().offset, 5);
+
+        // A message that does not commit anything has nothing to raise.
+        let message = Message::new_any_message((), BTreeMap::new());
+        assert_eq!(timeout.expire(&message), None);
+        let committable = BTreeMap::from([(partition("test", 0), Position::new(8, Utc::now()))]);
+        let message = Message::new_any_message((), committable);
+        assert_eq!(timeout.expire(&message).unwrap().offset, 7);
+    }
+}
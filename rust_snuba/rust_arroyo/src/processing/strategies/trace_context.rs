@@ -0,0 +1,135 @@
+use crate::backends::kafka::trace_context::TraceContext;
+use crate::backends::kafka::types::KafkaPayload;
+use crate::processing::strategies::{
+    CommitRequest, InvalidMessage, ProcessingStrategy, StrategyDescription, SubmitError,
+};
+use crate::types::Message;
+use std::time::Duration;
+
+/// Continues the trace of every submitted message that carries a
+/// ``sentry-trace`` or ``traceparent`` header. The next step is called
+/// within a ``process`` span tagged with the trace, and with the trace
+/// context set as current, so that the Kafka producer adds it to the
+/// messages produced from the same thread.
+pub struct PropagateTraceContext {
+    next_step: Box<dyn ProcessingStrategy<KafkaPayload>>,
+}
+
+impl PropagateTraceContext {
+    pub fn new(next_step: Box<dyn ProcessingStrategy<KafkaPayload>>) -> Self {
+        Self { next_step }
+    }
+}
+
+impl ProcessingStrategy<KafkaPayload> for PropagateTraceContext {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        self.next_step.poll()
+    }
+
+    fn submit(&mut self, message: Message<KafkaPayload>) -> Result<(), SubmitError<KafkaPayload>> {
+        let parent = message
+            .payload()
+            .headers
+            .as_ref()
+            .and_then(TraceContext::from_headers);
+        let Some(parent) = parent else {
+            return self.next_step.submit(message);
+        };
+
+        let context = parent.new_child();
+        let span = tracing::info_span!(
+            "process",
+            trace_id = %context.trace_id,
+            span_id = %context.span_id,
+            parent_span_id = %parent.span_id,
+        );
+        let previous = TraceContext::set_current(Some(context));
+        let entered = span.enter();
+        let result = self.next_step.submit(message);
+        drop(entered);
+        TraceContext::set_current(previous);
+        result
+    }
+
+    fn close(&mut self) {
+        self.next_step.close();
+    }
+
+    fn terminate(&mut self) {
+        self.next_step.terminate();
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        self.next_step.join(timeout)
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription::new("PropagateTraceContext").with_next_step(self.next_step.describe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PropagateTraceContext;
+    use crate::backends::kafka::trace_context::TraceContext;
+    use crate::backends::kafka::types::{Headers, KafkaPayload};
+    use crate::processing::strategies::{
+        CommitRequest, InvalidMessage, ProcessingStrategy, SubmitError,
+    };
+    use crate::types::Message;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    struct RecordContext {
+        contexts: Arc<Mutex<Vec<Option<TraceContext>>>>,
+    }
+    impl ProcessingStrategy<KafkaPayload> for RecordContext {
+        fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+            Ok(None)
+        }
+        fn submit(
+            &mut self,
+            _message: Message<KafkaPayload>,
+        ) -> Result<(), SubmitError<KafkaPayload>> {
+            self.contexts.lock().unwrap().push(TraceContext::current());
+            Ok(())
+        }
+        fn close(&mut self) {}
+        fn terminate(&mut self) {}
+        fn join(&mut self, _timeout: Option<Duration>) -> Option<CommitRequest> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_propagate_trace_context() {
+        let contexts = Arc::new(Mutex::new(Vec::new()));
+        let mut strategy = PropagateTraceContext::new(Box::new(RecordContext {
+            contexts: contexts.clone(),
+        }));
+
+        let mut headers = Headers::new();
+        headers.insert(
+            "sentry-trace",
+            "771a43a4192642f0b136d5159a501700-b7ad6b7169203331-1",
+        );
+        for headers in [Some(headers), None] {
+            let payload = KafkaPayload {
+                key: None,
+                headers,
+                payload: None,
+            };
+            strategy
+                .submit(Message::new_any_message(payload, BTreeMap::new()))
+                .unwrap();
+        }
+
+        let contexts = contexts.lock().unwrap();
+        let context = contexts[0].as_ref().unwrap();
+        assert_eq!(context.trace_id, "771a43a4192642f0b136d5159a501700");
+        assert_ne!(context.span_id, "b7ad6b7169203331");
+        assert!(contexts[1].is_none());
+        assert!(TraceContext::current().is_none());
+    }
+}
@@ -1,5 +1,6 @@
 use crate::processing::strategies::{
-    CommitRequest, InvalidMessage, MessageRejected, ProcessingStrategy,
+    report_invalid_message_on_join, CommitRequest, InvalidMessage, MessageRejected,
+    ProcessingStrategy, StrategyDescription, SubmitError,
 };
 use crate::types::Message;
 use std::time::Duration;
@@ -7,20 +8,63 @@ use std::time::Duration;
 pub struct Transform<TPayload: Clone + Send + Sync, TTransformed: Clone + Send + Sync> {
     pub function: fn(TPayload) -> Result<TTransformed, InvalidMessage>,
     pub next_step: Box<dyn ProcessingStrategy<TTransformed>>,
+    // A transformed message that was rejected by the next step. It is
+    // retried on every poll and no new messages are accepted until it
+    // goes through.
+    message_carried_over: Option<Message<TTransformed>>,
+}
+
+impl<TPayload: Clone + Send + Sync, TTransformed: Clone + Send + Sync>
+    Transform<TPayload, TTransformed>
+{
+    pub fn new(
+        function: fn(TPayload) -> Result<TTransformed, InvalidMessage>,
+        next_step: Box<dyn ProcessingStrategy<TTransformed>>,
+    ) -> Self {
+        Self {
+            function,
+            next_step,
+            message_carried_over: None,
+        }
+    }
+
+    fn submit_carried_over(&mut self) -> Result<(), InvalidMessage> {
+        if let Some(message) = self.message_carried_over.take() {
+            match self.next_step.submit(message) {
+                Ok(()) => {}
+                Err(SubmitError::MessageRejected(MessageRejected { message })) => {
+                    self.message_carried_over = Some(message);
+                }
+                Err(SubmitError::InvalidMessage(invalid)) => return Err(invalid),
+            }
+        }
+        Ok(())
+    }
 }
 
 impl<TPayload: Clone + Send + Sync, TTransformed: Clone + Send + Sync> ProcessingStrategy<TPayload>
     for Transform<TPayload, TTransformed>
 {
-    fn poll(&mut self) -> Option<CommitRequest> {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        self.submit_carried_over()?;
         self.next_step.poll()
     }
 
-    fn submit(&mut self, message: Message<TPayload>) -> Result<(), MessageRejected> {
-        // TODO: Handle InvalidMessage
-        let transformed = (self.function)(message.payload()).unwrap();
+    fn submit(&mut self, message: Message<TPayload>) -> Result<(), SubmitError<TPayload>> {
+        if self.message_carried_over.is_some() {
+            return Err(SubmitError::MessageRejected(MessageRejected { message }));
+        }
+
+        let transformed = message.try_map(self.function)?;
 
-        self.next_step.submit(message.replace(transformed))
+        match self.next_step.submit(transformed) {
+            Ok(()) => Ok(()),
+            Err(SubmitError::MessageRejected(MessageRejected { message })) => {
+                self.message_carried_over = Some(message);
+                Ok(())
+            }
+            Err(SubmitError::InvalidMessage(invalid)) => Err(invalid.into()),
+        }
     }
 
     fn close(&mut self) {
@@ -32,18 +76,28 @@ impl<TPayload: Clone + Send + Sync, TTransformed: Clone + Send + Sync> Processin
     }
 
     fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        if let Err(invalid) = self.submit_carried_over() {
+            report_invalid_message_on_join("Transform", &invalid);
+        }
         self.next_step.join(timeout)
     }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription::new("Transform")
+            .with_buffered_messages(self.message_carried_over.is_some() as usize)
+            .with_next_step(self.next_step.describe())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::Transform;
     use crate::processing::strategies::{
-        CommitRequest, InvalidMessage, MessageRejected, ProcessingStrategy,
+        CommitRequest, InvalidMessage, MessageRejected, ProcessingStrategy, SubmitError,
     };
     use crate::types::{Message, Partition, Topic};
     use chrono::Utc;
+    use std::sync::{Arc, Mutex};
     use std::time::Duration;
 
     #[test]
@@ -54,10 +108,10 @@ mod tests {
 
         struct Noop {}
         impl ProcessingStrategy<String> for Noop {
-            fn poll(&mut self) -> Option<CommitRequest> {
-                None
+            fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+                Ok(None)
             }
-            fn submit(&mut self, _message: Message<String>) -> Result<(), MessageRejected> {
+            fn submit(&mut self, _message: Message<String>) -> Result<(), SubmitError<String>> {
                 Ok(())
             }
             fn close(&mut self) {}
@@ -67,10 +121,7 @@ mod tests {
             }
         }
 
-        let mut strategy = Transform {
-            function: identity,
-            next_step: Box::new(Noop {}),
-        };
+        let mut strategy = Transform::new(identity, Box::new(Noop {}));
 
         let partition = Partition {
             topic: Topic {
@@ -80,12 +131,76 @@ mod tests {
         };
 
         strategy
-            .submit(Message::new(
+            .submit(Message::new_broker_message(
+                "Hello world".to_string(),
                 partition,
                 0,
-                "Hello world".to_string(),
                 Utc::now(),
             ))
             .unwrap();
     }
+
+    #[test]
+    fn test_transform_carries_over_rejected_message() {
+        fn identity(value: String) -> Result<String, InvalidMessage> {
+            Ok(value)
+        }
+
+        // Rejects every message until it is told to accept them.
+        struct Backpressure {
+            accept: Arc<Mutex<bool>>,
+            submitted: Arc<Mutex<Vec<String>>>,
+        }
+        impl ProcessingStrategy<String> for Backpressure {
+            fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+                Ok(None)
+            }
+            fn submit(&mut self, message: Message<String>) -> Result<(), SubmitError<String>> {
+                if !*self.accept.lock().unwrap() {
+                    return Err(SubmitError::MessageRejected(MessageRejected { message }));
+                }
+                self.submitted.lock().unwrap().push(message.payload());
+                Ok(())
+            }
+            fn close(&mut self) {}
+            fn terminate(&mut self) {}
+            fn join(&mut self, _timeout: Option<Duration>) -> Option<CommitRequest> {
+                None
+            }
+        }
+
+        let accept = Arc::new(Mutex::new(false));
+        let submitted = Arc::new(Mutex::new(Vec::new()));
+        let mut strategy = Transform::new(
+            identity,
+            Box::new(Backpressure {
+                accept: accept.clone(),
+                submitted: submitted.clone(),
+            }),
+        );
+
+        let partition = Partition {
+            topic: Topic {
+                name: "test".to_string(),
+            },
+            index: 0,
+        };
+        let build_message = |payload: &str, offset| {
+            Message::new_broker_message(payload.to_string(), partition.clone(), offset, Utc::now())
+        };
+
+        // The first message is held by the transform step, the second one is
+        // rejected back to the caller.
+        assert!(strategy.submit(build_message("first", 0)).is_ok());
+        let rejected = match strategy.submit(build_message("second", 1)) {
+            Err(SubmitError::MessageRejected(MessageRejected { message })) => message,
+            _ => panic!("Expected the message to be rejected"),
+        };
+        assert_eq!(rejected.payload(), "second");
+
+        *accept.lock().unwrap() = true;
+        strategy.poll().unwrap();
+        assert!(strategy.submit(rejected).is_ok());
+        assert_eq!(*submitted.lock().unwrap(), vec!["first", "second"]);
+    }
 }
@@ -0,0 +1,315 @@
+use crate::processing::state::ProcessorStateHandle;
+use crate::processing::strategies::retry::RetryPolicy;
+use crate::processing::{RunError, StreamProcessor};
+use crate::utils::metrics;
+use crate::utils::timing::Deadline;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// How often a supervisor waiting to restart checks for a shutdown signal.
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How a ``Supervisor`` restarts a processor that crashed.
+///
+/// The delay before a restart doubles from ``initial_backoff`` up to
+/// ``max_backoff``, with the jitter of ``RetryPolicy``. After ``max_restarts`` restarts the supervisor gives up
+/// and returns the error. A processor that ran for at least ``reset_after``
+/// before crashing is considered to have recovered, the budget and the
+/// backoff start over.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RestartPolicy {
+    pub max_restarts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub reset_after: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            max_restarts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            reset_after: Duration::from_secs(600),
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// The delay before the given restart, starting at 1.
+    fn backoff(&self, restart: u32) -> Duration {
+        RetryPolicy {
+            max_attempts: self.max_restarts.saturating_add(1),
+            initial_backoff: self.initial_backoff,
+            max_backoff: self.max_backoff,
+        }
+        .backoff(restart)
+    }
+}
+
+/// Runs a ``StreamProcessor`` and builds a new one, with a new consumer and
+/// a new strategy, when it stops with an error or panics. Crashes that only
+/// happen now and then, such as a rebalance that leaves the processor in a
+/// bad state, then do not take the whole process down.
+///
+/// ``build`` returns a processor that is already subscribed. A graceful
+/// shutdown of the processor stops the supervisor, and so does a shutdown
+/// signal received while it waits to restart.
+pub struct Supervisor<'a, TPayload: Clone> {
+    build: Box<dyn FnMut() -> StreamProcessor<'a, TPayload> + 'a>,
+    before_restart: Option<Box<dyn FnMut() + 'a>>,
+    policy: RestartPolicy,
+    state: ProcessorStateHandle,
+}
+
+// The message of a panic, which is a string unless the panic was raised
+// with another payload.
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "Box<dyn Any>".to_string(),
+        },
+    }
+}
+
+impl<'a, TPayload: 'static + Clone + Send> Supervisor<'a, TPayload> {
+    pub fn new(build: impl FnMut() -> StreamProcessor<'a, TPayload> + 'a) -> Self {
+        Supervisor {
+            build: Box::new(build),
+            before_restart: None,
+            policy: RestartPolicy::default(),
+            state: ProcessorStateHandle::default(),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: RestartPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Calls ``before_restart`` after a processor crashed, before the next
+    /// one is built. Resources the processors share, such as producers, can
+    /// be flushed or reset there.
+    pub fn with_before_restart(mut self, before_restart: impl FnMut() + 'a) -> Self {
+        self.before_restart = Some(Box::new(before_restart));
+        self
+    }
+
+    /// Returns the handle every processor the supervisor runs publishes its
+    /// state to, see ``StreamProcessor::state``.
+    pub fn state(&self) -> ProcessorStateHandle {
+        self.state.clone()
+    }
+
+    /// Runs processors until one shuts down gracefully, or until the restart
+    /// budget is exhausted.
+    pub fn run(&mut self) -> Result<(), RunError> {
+        let shutdown_requested = Arc::new(AtomicBool::new(false));
+        let signal_ids: Vec<_> = [SIGTERM, SIGINT]
+            .into_iter()
+            .filter_map(|signal| flag::register(signal, shutdown_requested.clone()).ok())
+            .collect();
+
+        let mut restarts = 0;
+        let result = loop {
+            let started = Instant::now();
+            // The processor is built and dropped within the attempt, a panic
+            // on the way leaves nothing of it behind.
+            let attempt = panic::catch_unwind(AssertUnwindSafe(|| {
+                let mut processor = (self.build)();
+                processor.set_state_handle(self.state.clone());
+                processor.run()
+            }));
+            let error = match attempt {
+                Ok(Ok(())) => break Ok(()),
+                Ok(Err(error)) => error,
+                Err(payload) => RunError::Panic(panic_message(payload)),
+            };
+
+            if started.elapsed() >= self.policy.reset_after {
+                restarts = 0;
+            }
+            if restarts >= self.policy.max_restarts {
+                break Err(error);
+            }
+            restarts += 1;
+            let backoff = self.policy.backoff(restarts);
+            log::error!(
+                "The processor crashed with {:?}, restarting in {:?} ({}/{})",
+                error,
+                backoff,
+                restarts,
+                self.policy.max_restarts
+            );
+            metrics::increment("arroyo.consumer.restart.count", None, None, None);
+
+            let deadline = Deadline::new(backoff);
+            while !deadline.has_elapsed() && !shutdown_requested.load(Ordering::Relaxed) {
+                sleep(backoff.min(SHUTDOWN_CHECK_INTERVAL));
+            }
+            if shutdown_requested.load(Ordering::Relaxed) {
+                break Err(error);
+            }
+            if let Some(before_restart) = self.before_restart.as_mut() {
+                before_restart();
+            }
+        };
+
+        for id in signal_ids {
+            signal_hook::low_level::unregister(id);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RestartPolicy, Supervisor};
+    use crate::backends::local::broker::LocalBroker;
+    use crate::backends::local::LocalConsumer;
+    use crate::backends::storages::memory::MemoryMessageStorage;
+    use crate::processing::strategies::{
+        CommitRequest, InvalidMessage, ProcessingStrategy, ProcessingStrategyFactory, SubmitError,
+    };
+    use crate::processing::{RunError, StreamProcessor};
+    use crate::types::{InnerMessage, Message, Partition, Topic};
+    use crate::utils::clock::SystemClock;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    struct InvalidStrategy;
+    impl ProcessingStrategy<String> for InvalidStrategy {
+        fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+            Ok(None)
+        }
+
+        fn submit(&mut self, message: Message<String>) -> Result<(), SubmitError<String>> {
+            let InnerMessage::BrokerMessage(message) = message.inner_message else {
+                unreachable!()
+            };
+            Err(SubmitError::InvalidMessage(InvalidMessage {
+                partition: message.partition,
+                offset: message.offset,
+            }))
+        }
+
+        fn close(&mut self) {}
+
+        fn terminate(&mut self) {}
+
+        fn join(&mut self, _: Option<Duration>) -> Option<CommitRequest> {
+            None
+        }
+    }
+
+    struct InvalidFactory;
+    impl ProcessingStrategyFactory<String> for InvalidFactory {
+        fn create(&self) -> Box<dyn ProcessingStrategy<String>> {
+            Box::new(InvalidStrategy)
+        }
+    }
+
+    struct PanickingFactory;
+    impl ProcessingStrategyFactory<String> for PanickingFactory {
+        fn create(&self) -> Box<dyn ProcessingStrategy<String>> {
+            panic!("Failed to create the strategy");
+        }
+    }
+
+    fn policy(max_restarts: u32) -> RestartPolicy {
+        RestartPolicy {
+            max_restarts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+            reset_after: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn test_supervisor() {
+        let topic = Topic {
+            name: "test1".to_string(),
+        };
+        let mut broker = LocalBroker::new(
+            Box::new(MemoryMessageStorage::default()),
+            Box::new(SystemClock {}),
+        );
+        broker.create_topic(topic.clone(), 1).unwrap();
+        let partition = Partition {
+            topic: topic.clone(),
+            index: 0,
+        };
+        broker.produce(&partition, "invalid".to_string()).unwrap();
+        let broker = Arc::new(Mutex::new(broker));
+
+        // Every processor crashes on the invalid message, the last error is
+        // returned once the budget is exhausted.
+        let builds = Arc::new(Mutex::new(0));
+        let build = |shutdown_after: Option<u32>| {
+            let broker = broker.clone();
+            let builds = builds.clone();
+            let topic = topic.clone();
+            move || {
+                let consumer = Box::new(LocalConsumer::new(
+                    Uuid::nil(),
+                    broker.clone(),
+                    "test_group".to_string(),
+                    false,
+                ));
+                let mut processor = StreamProcessor::new(consumer, Box::new(InvalidFactory));
+                processor.subscribe(topic.clone());
+                let mut builds = builds.lock().unwrap();
+                *builds += 1;
+                if shutdown_after == Some(*builds) {
+                    processor.signal_shutdown();
+                }
+                processor
+            }
+        };
+
+        let mut supervisor = Supervisor::new(build(None)).with_policy(policy(2));
+        match supervisor.run() {
+            Err(RunError::InvalidMessage(invalid)) => assert_eq!(invalid.offset, 0),
+            other => panic!("Expected an InvalidMessage error, got {:?}", other),
+        }
+        assert_eq!(*builds.lock().unwrap(), 3);
+
+        // A processor that shuts down gracefully stops the supervisor.
+        *builds.lock().unwrap() = 0;
+        let mut supervisor = Supervisor::new(build(Some(2))).with_policy(policy(5));
+        assert!(supervisor.run().is_ok());
+        assert_eq!(*builds.lock().unwrap(), 2);
+
+        // Panics are restarted like errors, ``before_restart`` runs between
+        // the attempts.
+        let restarts = Arc::new(Mutex::new(0));
+        let recorded = restarts.clone();
+        let mut supervisor = Supervisor::new(|| {
+            let consumer = Box::new(LocalConsumer::new(
+                Uuid::nil(),
+                broker.clone(),
+                "test_group".to_string(),
+                false,
+            ));
+            let mut processor = StreamProcessor::new(consumer, Box::new(PanickingFactory));
+            processor.subscribe(topic.clone());
+            processor
+        })
+        .with_policy(policy(2))
+        .with_before_restart(move || *recorded.lock().unwrap() += 1);
+        match supervisor.run() {
+            Err(RunError::Panic(message)) => assert_eq!(message, "Failed to create the strategy"),
+            other => panic!("Expected a panic, got {:?}", other),
+        }
+        assert_eq!(*restarts.lock().unwrap(), 2);
+    }
+}
@@ -1,15 +1,23 @@
 use chrono::{DateTime, Utc};
 use std::any::type_name;
 use std::cmp::Eq;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::hash::Hash;
-use std::collections::BTreeMap;
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq, PartialOrd, Ord)]
 pub struct Topic {
     pub name: String,
 }
 
+impl Topic {
+    /// Whether this is a pattern to subscribe with rather than a topic name.
+    /// As with librdkafka, names starting with ``^`` are regular expressions.
+    pub fn is_pattern(&self) -> bool {
+        self.name.starts_with('^')
+    }
+}
+
 impl fmt::Display for Topic {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "Topic({})", self.name)
@@ -29,12 +37,44 @@ impl fmt::Display for Partition {
     }
 }
 
+/// How far a partition can be committed: the offset that follows the last
+/// message processed, and the timestamp of that message. The timestamp is
+/// what the commit log records, and how far behind the consumer is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Position {
+    pub offset: u64,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Position {
+    pub fn new(offset: u64, timestamp: DateTime<Utc>) -> Self {
+        Self { offset, timestamp }
+    }
+
+    /// Returns the position that is the furthest in the partition.
+    pub fn max(self, other: Position) -> Position {
+        if other.offset > self.offset {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.offset)
+    }
+}
+
 #[derive(PartialEq)]
 pub enum TopicOrPartition {
     Topic(Topic),
     Partition(Partition),
 }
 
+/// A message as it was consumed from a partition. Committing it commits the
+/// offset that follows it.
 #[derive(Clone, Debug, PartialEq)]
 pub struct BrokerMessage<T: Clone> {
     pub payload: T,
@@ -43,7 +83,7 @@ pub struct BrokerMessage<T: Clone> {
     pub timestamp: DateTime<Utc>,
 }
 
-impl<T: Clone>BrokerMessage<T> {
+impl<T: Clone> BrokerMessage<T> {
     pub fn new(payload: T, partition: Partition, offset: u64, timestamp: DateTime<Utc>) -> Self {
         Self {
             payload,
@@ -53,6 +93,11 @@ impl<T: Clone>BrokerMessage<T> {
         }
     }
 
+    /// The offset committed once this message is processed.
+    pub fn next_offset(&self) -> u64 {
+        self.offset + 1
+    }
+
     pub fn replace<TReplaced: Clone>(self, replacement: TReplaced) -> BrokerMessage<TReplaced> {
         BrokerMessage {
             payload: replacement,
@@ -61,107 +106,208 @@ impl<T: Clone>BrokerMessage<T> {
             timestamp: self.timestamp,
         }
     }
+
+    pub fn map<TMapped: Clone>(self, f: impl FnOnce(T) -> TMapped) -> BrokerMessage<TMapped> {
+        BrokerMessage {
+            payload: f(self.payload),
+            partition: self.partition,
+            offset: self.offset,
+            timestamp: self.timestamp,
+        }
+    }
 }
 
+impl<T: Clone> fmt::Display for BrokerMessage<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Message<{}>(partition={}), offset={}",
+            type_name::<T>(),
+            &self.partition,
+            &self.offset
+        )
+    }
+}
+
+/// A value derived from one or more broker messages, for example a batch
+/// built by ``Reduce``. It does not have a position of its own, it carries
+/// the positions to commit once it is processed, for every partition that
+/// contributed to it.
 #[derive(Clone, Debug, PartialEq)]
 pub struct AnyMessage<T: Clone> {
     pub payload: T,
-    pub committable: BTreeMap<Partition, u64>
+    pub committable: BTreeMap<Partition, Position>,
 }
 
 impl<T: Clone> AnyMessage<T> {
-    pub fn new(payload: T, committable: BTreeMap<Partition, u64>) -> Self {
+    pub fn new(payload: T, committable: BTreeMap<Partition, Position>) -> Self {
         Self {
-            payload, committable
+            payload,
+            committable,
+        }
+    }
+
+    pub fn replace<TReplaced: Clone>(self, replacement: TReplaced) -> AnyMessage<TReplaced> {
+        AnyMessage {
+            payload: replacement,
+            committable: self.committable,
         }
     }
 
-    pub fn replace<TReplaced: Clone>(self, replacement: TReplaced) -> AnyMessage<TReplaced>{
-        AnyMessage{payload: replacement, committable: self.committable}
+    pub fn map<TMapped: Clone>(self, f: impl FnOnce(T) -> TMapped) -> AnyMessage<TMapped> {
+        AnyMessage {
+            payload: f(self.payload),
+            committable: self.committable,
+        }
     }
 }
 
-
-
 #[derive(Clone, Debug, PartialEq)]
 pub enum InnerMessage<T: Clone> {
     BrokerMessage(BrokerMessage<T>),
     AnyMessage(AnyMessage<T>),
 }
 
-
 #[derive(Clone, Debug, PartialEq)]
 pub struct Message<T: Clone> {
-    pub inner_message:  InnerMessage<T>,
+    pub inner_message: InnerMessage<T>,
 }
 
 impl<T: Clone> Message<T> {
+    pub fn new_broker_message(
+        payload: T,
+        partition: Partition,
+        offset: u64,
+        timestamp: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            inner_message: InnerMessage::BrokerMessage(BrokerMessage::new(
+                payload, partition, offset, timestamp,
+            )),
+        }
+    }
+
+    pub fn new_any_message(payload: T, committable: BTreeMap<Partition, Position>) -> Self {
+        Self {
+            inner_message: InnerMessage::AnyMessage(AnyMessage::new(payload, committable)),
+        }
+    }
+
     pub fn payload(&self) -> T {
         match &self.inner_message {
-            InnerMessage::BrokerMessage(BrokerMessage{payload, ..}) => payload.clone(),
-            InnerMessage::AnyMessage(AnyMessage{payload, ..}) => payload.clone(),
+            InnerMessage::BrokerMessage(BrokerMessage { payload, .. }) => payload.clone(),
+            InnerMessage::AnyMessage(AnyMessage { payload, .. }) => payload.clone(),
         }
     }
 
-    pub fn committable(&self) -> BTreeMap<Partition, u64> {
+    pub fn committable(&self) -> BTreeMap<Partition, Position> {
         match &self.inner_message {
-            InnerMessage::BrokerMessage(BrokerMessage{partition, offset, ..}) => {
+            InnerMessage::BrokerMessage(message) => {
                 let mut map = BTreeMap::new();
                 // TODO: Get rid of the clone
-                map.insert(partition.clone(), offset + 1);
+                map.insert(
+                    message.partition.clone(),
+                    Position::new(message.next_offset(), message.timestamp),
+                );
                 map
-            },
-            InnerMessage::AnyMessage(AnyMessage{committable, ..}) => {
-                committable.clone()
             }
+            InnerMessage::AnyMessage(AnyMessage { committable, .. }) => committable.clone(),
         }
+    }
 
+    /// The timestamp of the message, only broker messages have one.
+    pub fn timestamp(&self) -> Option<DateTime<Utc>> {
+        match &self.inner_message {
+            InnerMessage::BrokerMessage(BrokerMessage { timestamp, .. }) => Some(*timestamp),
+            InnerMessage::AnyMessage(_) => None,
+        }
+    }
+
+    /// The topic the message was consumed from. A message built out of
+    /// several messages only has a topic if they all come from the same one.
+    pub fn topic(&self) -> Option<&Topic> {
+        match &self.inner_message {
+            InnerMessage::BrokerMessage(BrokerMessage { partition, .. }) => Some(&partition.topic),
+            InnerMessage::AnyMessage(AnyMessage { committable, .. }) => {
+                let mut topics = committable.keys().map(|partition| &partition.topic);
+                let first = topics.next()?;
+                topics.all(|topic| topic == first).then_some(first)
+            }
+        }
     }
 
     pub fn replace<TReplaced: Clone>(self, replacement: TReplaced) -> Message<TReplaced> {
         match self.inner_message {
-            InnerMessage::BrokerMessage(inner) => {
-                Message{inner_message: InnerMessage::BrokerMessage(inner.replace(replacement))}
+            InnerMessage::BrokerMessage(inner) => Message {
+                inner_message: InnerMessage::BrokerMessage(inner.replace(replacement)),
             },
-            InnerMessage::AnyMessage(inner) => {
-                Message{inner_message: InnerMessage::AnyMessage(inner.replace(replacement))}
+            InnerMessage::AnyMessage(inner) => Message {
+                inner_message: InnerMessage::AnyMessage(inner.replace(replacement)),
             },
         }
     }
 
+    /// Transforms the payload, the position of the message is preserved.
+    pub fn map<TMapped: Clone>(self, f: impl FnOnce(T) -> TMapped) -> Message<TMapped> {
+        let inner_message = match self.inner_message {
+            InnerMessage::BrokerMessage(inner) => InnerMessage::BrokerMessage(inner.map(f)),
+            InnerMessage::AnyMessage(inner) => InnerMessage::AnyMessage(inner.map(f)),
+        };
+        Message { inner_message }
+    }
+
+    /// Like ``map``, for transformations that can fail.
+    pub fn try_map<TMapped: Clone, E>(
+        self,
+        f: impl FnOnce(T) -> Result<TMapped, E>,
+    ) -> Result<Message<TMapped>, E> {
+        let inner_message = match self.inner_message {
+            InnerMessage::BrokerMessage(inner) => {
+                let payload = f(inner.payload)?;
+                InnerMessage::BrokerMessage(BrokerMessage {
+                    payload,
+                    partition: inner.partition,
+                    offset: inner.offset,
+                    timestamp: inner.timestamp,
+                })
+            }
+            InnerMessage::AnyMessage(inner) => {
+                let payload = f(inner.payload)?;
+                InnerMessage::AnyMessage(AnyMessage {
+                    payload,
+                    committable: inner.committable,
+                })
+            }
+        };
+        Ok(Message { inner_message })
+    }
 }
 
 impl<T: Clone> fmt::Display for Message<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self.inner_message {
-            InnerMessage::BrokerMessage(BrokerMessage{partition, offset, ..}) => {
-                write!(
-                    f,
-                    "Message<{}>(partition={}), offset={}",
-                    type_name::<T>(),
-                    &partition,
-                    &offset
-                )
-            },
-            InnerMessage::AnyMessage(AnyMessage{committable, ..}) => {
+            InnerMessage::BrokerMessage(inner) => inner.fmt(f),
+            InnerMessage::AnyMessage(AnyMessage { committable, .. }) => {
                 write!(
                     f,
                     "Message<{}>(committable={})",
                     type_name::<T>(),
-                    &committable.iter().map(|(k, v)| format!("{}:{}", k, v)).collect::<Vec<_>>().join(",")
+                    &committable
+                        .iter()
+                        .map(|(k, v)| format!("{}:{}", k, v))
+                        .collect::<Vec<_>>()
+                        .join(",")
                 )
             }
         }
-
-
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Message, Partition, Topic};
+    use super::{InnerMessage, Message, Partition, Position, Topic};
     use chrono::Utc;
-    use std::collections::HashMap;
+    use std::collections::{BTreeMap, HashMap};
 
     #[test]
     fn message() {
@@ -170,14 +316,101 @@ mod tests {
             name: "test".to_string(),
         };
         let part = Partition { topic, index: 10 };
-        let message = Message::new(part, 10, "payload".to_string(), now);
+        let message = Message::new_broker_message("payload".to_string(), part.clone(), 10, now);
+
+        match &message.inner_message {
+            InnerMessage::BrokerMessage(inner) => {
+                assert_eq!(inner.partition.topic.name, "test");
+                assert_eq!(inner.partition.index, 10);
+                assert_eq!(inner.offset, 10);
+                assert_eq!(inner.timestamp, now);
+                assert_eq!(inner.next_offset(), 11);
+            }
+            InnerMessage::AnyMessage(_) => panic!("Expected a broker message"),
+        }
+        assert_eq!(message.payload(), "payload");
+        assert_eq!(message.timestamp(), Some(now));
+        assert_eq!(
+            message.committable(),
+            BTreeMap::from([(part, Position::new(11, now))])
+        )
+    }
 
-        assert_eq!(message.partition.topic.name, "test");
-        assert_eq!(message.partition.index, 10);
-        assert_eq!(message.offset, 10);
-        assert_eq!(message.payload, "payload");
-        assert_eq!(message.timestamp, now);
-        assert_eq!(message.next_offset(), 11)
+    #[test]
+    fn test_any_message() {
+        let now = Utc::now();
+        let topic = Topic {
+            name: "test".to_string(),
+        };
+        let committable = BTreeMap::from([
+            (
+                Partition {
+                    topic: topic.clone(),
+                    index: 0,
+                },
+                Position::new(5, now),
+            ),
+            (Partition { topic, index: 1 }, Position::new(8, now)),
+        ]);
+        let message = Message::new_any_message(vec![1, 2], committable.clone());
+        assert_eq!(message.timestamp(), None);
+        assert_eq!(message.committable(), committable);
+        assert_eq!(message.replace(()).committable(), committable);
+    }
+
+    #[test]
+    fn test_map() {
+        let now = Utc::now();
+        let partition = Partition {
+            topic: Topic {
+                name: "test".to_string(),
+            },
+            index: 0,
+        };
+        let message = Message::new_broker_message("10".to_string(), partition.clone(), 3, now);
+
+        let mapped = message.clone().map(|payload| payload.len());
+        assert_eq!(
+            mapped,
+            Message::new_broker_message(2, partition.clone(), 3, now)
+        );
+
+        let parsed = message.try_map(|payload| payload.parse::<u64>()).unwrap();
+        assert_eq!(parsed.payload(), 10);
+        assert_eq!(
+            parsed.committable(),
+            BTreeMap::from([(partition.clone(), Position::new(4, now))])
+        );
+
+        let invalid = Message::new_broker_message("a".to_string(), partition, 3, now);
+        assert!(invalid.try_map(|payload| payload.parse::<u64>()).is_err());
+    }
+
+    #[test]
+    fn test_topic() {
+        let topic = |name: &str| Topic {
+            name: name.to_string(),
+        };
+        let partition = |name: &str, index| Partition {
+            topic: topic(name),
+            index,
+        };
+
+        let message = Message::new_broker_message((), partition("a", 0), 10, Utc::now());
+        assert_eq!(message.topic(), Some(&topic("a")));
+
+        let position = Position::new(1, Utc::now());
+        let message = Message::new_any_message(
+            (),
+            BTreeMap::from([(partition("a", 0), position), (partition("a", 1), position)]),
+        );
+        assert_eq!(message.topic(), Some(&topic("a")));
+
+        let message = Message::new_any_message(
+            (),
+            BTreeMap::from([(partition("a", 0), position), (partition("b", 0), position)]),
+        );
+        assert_eq!(message.topic(), None);
     }
 
     #[test]
@@ -189,7 +422,7 @@ mod tests {
             },
             index: 10,
         };
-        let message = Message::new(part, 10, "payload".to_string(), now);
+        let message = Message::new_broker_message("payload".to_string(), part, 10, now);
 
         assert_eq!(
             message.to_string(),
@@ -242,7 +475,7 @@ mod tests {
         assert_ne!(&part as *const Partition, &part2 as *const Partition);
 
         let now = Utc::now();
-        let message = Message::new(part, 10, "payload".to_string(), now);
+        let message = Message::new_broker_message("payload".to_string(), part, 10, now);
         let message2 = message.clone();
 
         assert_eq!(message, message2);
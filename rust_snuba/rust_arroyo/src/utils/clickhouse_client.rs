@@ -29,8 +29,8 @@ impl ClickhouseClient {
     }
 
     pub async fn send(&self, body: String) -> Result<Response, Error> {
-
-        let res = self.client
+        let res = self
+            .client
             .post(self.url.clone())
             .headers(self.headers.clone())
             .body(body)
@@ -42,21 +42,19 @@ impl ClickhouseClient {
             .await;
 
         // println!("Response status {}", res.as_ref().unwrap().text());
-        return res
-
+        res
     }
 }
 
-
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[tokio::test]
-    async fn it_works() -> Result<(), reqwest::Error>{
-        let client: ClickhouseClient = ClickhouseClient::new("localhost",8123, "querylog_local");
+    async fn it_works() -> Result<(), reqwest::Error> {
+        let client: ClickhouseClient = ClickhouseClient::new("localhost", 8123, "querylog_local");
 
-        println!("{}", "running test");
+        println!("running test");
         let res = client.send("[]".to_string()).await;
         println!("Response status {}", res.unwrap().status());
         Ok(())
@@ -1,10 +1,11 @@
+use std::sync::{Arc, Mutex};
 use std::thread::sleep;
 use std::time::{Duration, SystemTime};
 
-pub trait Clock {
+pub trait Clock: Send + Sync {
     fn time(&self) -> SystemTime;
 
-    fn sleep(self, duration: Duration);
+    fn sleep(&self, duration: Duration);
 }
 
 pub struct SystemClock {}
@@ -13,7 +14,50 @@ impl Clock for SystemClock {
         SystemTime::now()
     }
 
-    fn sleep(self, duration: Duration) {
+    fn sleep(&self, duration: Duration) {
         sleep(duration)
     }
 }
+
+/// A clock that only moves forward when ``sleep`` is called, which lets
+/// tests exercise time based behavior without waiting. Clones share the
+/// same time.
+#[derive(Clone)]
+pub struct TestingClock {
+    time: Arc<Mutex<SystemTime>>,
+}
+
+impl TestingClock {
+    pub fn new(time: SystemTime) -> Self {
+        Self {
+            time: Arc::new(Mutex::new(time)),
+        }
+    }
+}
+
+impl Clock for TestingClock {
+    fn time(&self) -> SystemTime {
+        *self.time.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        *self.time.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, TestingClock};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_testing_clock() {
+        let start = SystemTime::UNIX_EPOCH;
+        let clock = TestingClock::new(start);
+        let other = clock.clone();
+
+        assert_eq!(clock.time(), start);
+        other.sleep(Duration::from_secs(5));
+        assert_eq!(clock.time(), start + Duration::from_secs(5));
+    }
+}
@@ -1,14 +1,19 @@
+use crate::utils::clock::{Clock, SystemClock};
+use cadence::ext::MetricBackend;
 use cadence::{
-    BufferedUdpMetricSink, Counted, Gauged, MetricBuilder, QueuingMetricSink, StatsdClient, Timed,
+    BufferedUdpMetricSink, Counted, Gauged, Metric, MetricBuilder, MetricSink, QueuingMetricSink,
+    StatsdClient, Timed,
 };
 use lazy_static::lazy_static;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use rand::Rng;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::net::{ToSocketAddrs, UdpSocket};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-pub trait Metrics {
+/// A metrics backend. Timings are in milliseconds.
+pub trait Metrics: Send + Sync {
     fn counter(
         &self,
         key: &str,
@@ -32,11 +37,52 @@ pub trait Metrics {
         tags: Option<HashMap<&str, &str>>,
         sample_rate: Option<f64>,
     );
+
+    /// Records a timing the caller already sampled at ``sample_rate``, it
+    /// stands for ``1 / sample_rate`` timings. This is how aggregated timers
+    /// are forwarded. Backends that cannot account for the rate record it
+    /// as a single timing.
+    fn sampled_time(
+        &self,
+        key: &str,
+        value: u64,
+        tags: Option<HashMap<&str, &str>>,
+        _sample_rate: f64,
+    ) {
+        self.time(key, value, tags, None);
+    }
+
+    /// Sends whatever the backend buffered.
+    fn flush(&self) {}
 }
 
+/// Discards every metric. This is the backend until another one is
+/// configured.
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {
+    fn counter(&self, _: &str, _: Option<i64>, _: Option<HashMap<&str, &str>>, _: Option<f64>) {}
+
+    fn gauge(&self, _: &str, _: u64, _: Option<HashMap<&str, &str>>, _: Option<f64>) {}
+
+    fn time(&self, _: &str, _: u64, _: Option<HashMap<&str, &str>>, _: Option<f64>) {}
+}
+
+/// Sends metrics to a StatsD server over UDP. Tags are sent in the DogStatsD
+/// format, the global tags are added to every metric.
 pub struct MetricsClient {
     statsd_client: StatsdClient,
     prefix: String,
+    global_tags: Vec<(String, String)>,
+}
+
+// A metric formatted by hand, for what cadence cannot express.
+struct RawMetric(String);
+
+impl Metric for RawMetric {
+    fn as_metric_str(&self) -> &str {
+        &self.0
+    }
 }
 
 impl Metrics for MetricsClient {
@@ -116,11 +162,78 @@ impl Metrics for MetricsClient {
             }
         }
     }
+
+    /// Sends the timing with its rate, so that the server scales the count
+    /// of the timer.
+    fn sampled_time(
+        &self,
+        key: &str,
+        value: u64,
+        tags: Option<HashMap<&str, &str>>,
+        sample_rate: f64,
+    ) {
+        let mut line = format!("{}.{}:{}|ms|@{}", self.prefix, key, value, sample_rate);
+        let tags: Vec<String> = self
+            .global_tags
+            .iter()
+            .map(|(k, v)| format!("{}:{}", k, v))
+            .chain(
+                tags.unwrap_or_default()
+                    .into_iter()
+                    .map(|(k, v)| format!("{}:{}", k, v)),
+            )
+            .collect();
+        if !tags.is_empty() {
+            line = format!("{}|#{}", line, tags.join(","));
+        }
+        if let Err(err) = self.statsd_client.send_metric(&RawMetric(line)) {
+            println!("Failed to send metric {}: {}", key, err)
+        }
+    }
 }
 
 impl MetricsClient {
+    pub fn new<A: ToSocketAddrs>(prefix: &str, host: A) -> Self {
+        Self::new_with_tags(prefix, host, HashMap::new())
+    }
+
+    pub fn new_with_tags<A: ToSocketAddrs>(
+        prefix: &str,
+        host: A,
+        global_tags: HashMap<String, String>,
+    ) -> Self {
+        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+
+        let buffered_udp_sink = BufferedUdpMetricSink::from(host, socket).unwrap();
+        let queuing_sink =
+            QueuingMetricSink::with_capacity(buffered_udp_sink, METRICS_MAX_QUEUE_SIZE);
+        // If metrics need to be sent out immediately without waiting then use the udp_sink below.
+        //let udp_sink = UdpMetricSink::from(host, socket).unwrap();
+        Self::from_sink(prefix, queuing_sink, global_tags)
+    }
+
+    fn from_sink<S: MetricSink + Sync + Send + std::panic::RefUnwindSafe + 'static>(
+        prefix: &str,
+        sink: S,
+        global_tags: HashMap<String, String>,
+    ) -> Self {
+        let mut builder = StatsdClient::builder(prefix, sink);
+        let mut global_tags: Vec<_> = global_tags.into_iter().collect();
+        global_tags.sort();
+        for (key, value) in &global_tags {
+            builder = builder.with_tag(key.clone(), value.clone());
+        }
+
+        Self {
+            statsd_client: builder.build(),
+            prefix: String::from(prefix),
+            global_tags,
+        }
+    }
+
     fn should_sample(&self, sample_rate: Option<f64>) -> bool {
-        rand::thread_rng().gen_range(0.0..=1.0) < sample_rate.unwrap_or(1.0)
+        should_sample(sample_rate)
     }
 
     fn send_with_tags<'t, T: cadence::Metric + From<String>>(
@@ -141,81 +254,639 @@ impl MetricsClient {
     }
 }
 
+type MetricKey = (String, BTreeMap<String, String>);
+
+#[derive(Default)]
+struct Timer {
+    samples: Vec<u64>,
+    count: u64,
+    // The number of timings the samples stand for, the sampled ones count
+    // for ``1 / sample_rate``.
+    weight: f64,
+}
+
+#[derive(Default)]
+struct Buffer {
+    counters: HashMap<MetricKey, i64>,
+    gauges: HashMap<MetricKey, u64>,
+    timers: HashMap<MetricKey, Timer>,
+}
+
+/// Aggregates metrics locally and forwards them to ``inner`` once every
+/// ``flush_interval``: counters are summed and gauges keep their last value.
+/// Timers keep a uniform sample of at most ``TIMER_SAMPLES`` values per
+/// interval, forwarded with the rate they were sampled at.
+///
+/// Buffered metrics are forwarded by the first call made after the interval
+/// has elapsed, or by ``flush``.
+pub struct AggregatingMetrics {
+    inner: Arc<dyn Metrics>,
+    clock: Box<dyn Clock>,
+    flush_interval: Duration,
+    last_flush: Mutex<SystemTime>,
+    buffer: Mutex<Buffer>,
+}
+
+const TIMER_SAMPLES: usize = 32;
+
+fn should_sample(sample_rate: Option<f64>) -> bool {
+    rand::thread_rng().gen_range(0.0..=1.0) < sample_rate.unwrap_or(1.0)
+}
+
+fn metric_key(key: &str, tags: Option<HashMap<&str, &str>>) -> MetricKey {
+    let tags = tags
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    (key.to_string(), tags)
+}
+
+fn borrow_tags(tags: &BTreeMap<String, String>) -> Option<HashMap<&str, &str>> {
+    if tags.is_empty() {
+        return None;
+    }
+    Some(tags.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect())
+}
+
+impl AggregatingMetrics {
+    pub fn new(inner: Arc<dyn Metrics>, clock: Box<dyn Clock>, flush_interval: Duration) -> Self {
+        let now = clock.time();
+        Self {
+            inner,
+            clock,
+            flush_interval,
+            last_flush: Mutex::new(now),
+            buffer: Mutex::new(Buffer::default()),
+        }
+    }
+
+    fn maybe_flush(&self) {
+        let now = self.clock.time();
+        {
+            let mut last_flush = self.last_flush.lock();
+            match now.duration_since(*last_flush) {
+                Ok(elapsed) if elapsed >= self.flush_interval => *last_flush = now,
+                _ => return,
+            }
+        }
+        self.flush();
+    }
+}
+
+impl Metrics for AggregatingMetrics {
+    fn counter(
+        &self,
+        key: &str,
+        value: Option<i64>,
+        tags: Option<HashMap<&str, &str>>,
+        sample_rate: Option<f64>,
+    ) {
+        if should_sample(sample_rate) {
+            // Aggregated counters are sent unsampled, so sampled increments
+            // are scaled up here.
+            let value = value.unwrap_or(1) as f64 / sample_rate.unwrap_or(1.0);
+            *self
+                .buffer
+                .lock()
+                .counters
+                .entry(metric_key(key, tags))
+                .or_default() += value.round() as i64;
+        }
+        self.maybe_flush();
+    }
+
+    fn gauge(
+        &self,
+        key: &str,
+        value: u64,
+        tags: Option<HashMap<&str, &str>>,
+        sample_rate: Option<f64>,
+    ) {
+        if should_sample(sample_rate) {
+            self.buffer
+                .lock()
+                .gauges
+                .insert(metric_key(key, tags), value);
+        }
+        self.maybe_flush();
+    }
+
+    fn time(
+        &self,
+        key: &str,
+        value: u64,
+        tags: Option<HashMap<&str, &str>>,
+        sample_rate: Option<f64>,
+    ) {
+        if should_sample(sample_rate) {
+            let mut buffer = self.buffer.lock();
+            let timer = buffer.timers.entry(metric_key(key, tags)).or_default();
+            timer.count += 1;
+            timer.weight += 1.0 / sample_rate.unwrap_or(1.0);
+            if timer.samples.len() < TIMER_SAMPLES {
+                timer.samples.push(value);
+            } else {
+                // Reservoir sampling keeps every value with the same
+                // probability.
+                let index = rand::thread_rng().gen_range(0..timer.count) as usize;
+                if index < TIMER_SAMPLES {
+                    timer.samples[index] = value;
+                }
+            }
+        }
+        self.maybe_flush();
+    }
+
+    fn flush(&self) {
+        let buffer = std::mem::take(&mut *self.buffer.lock());
+        for ((key, tags), value) in &buffer.counters {
+            self.inner
+                .counter(key, Some(*value), borrow_tags(tags), None);
+        }
+        for ((key, tags), value) in &buffer.gauges {
+            self.inner.gauge(key, *value, borrow_tags(tags), None);
+        }
+        for ((key, tags), timer) in &buffer.timers {
+            let sample_rate = timer.samples.len() as f64 / timer.weight;
+            for value in &timer.samples {
+                if sample_rate < 1.0 {
+                    self.inner
+                        .sampled_time(key, *value, borrow_tags(tags), sample_rate);
+                } else {
+                    self.inner.time(key, *value, borrow_tags(tags), None);
+                }
+            }
+        }
+        self.inner.flush();
+    }
+}
+
+/// The upper bounds of the buckets of the Prometheus histograms, in
+/// milliseconds.
+const HISTOGRAM_BUCKETS: [u64; 12] = [1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; HISTOGRAM_BUCKETS.len()],
+    sum: u64,
+    count: u64,
+}
+
+#[derive(Default)]
+struct PrometheusRegistry {
+    counters: BTreeMap<MetricKey, i64>,
+    gauges: BTreeMap<MetricKey, u64>,
+    histograms: BTreeMap<MetricKey, Histogram>,
+}
+
+/// Keeps the metrics in memory, to be scraped by Prometheus from ``render``
+/// instead of being sent. Counters are cumulative, gauges keep their last
+/// value and timers are histograms with ``HISTOGRAM_BUCKETS``.
+///
+/// Names are prefixed with ``prefix`` and dots replaced with underscores, the
+/// global tags are added as labels to every metric.
+pub struct PrometheusMetrics {
+    prefix: String,
+    global_tags: BTreeMap<String, String>,
+    registry: Mutex<PrometheusRegistry>,
+}
+
+fn prometheus_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl PrometheusMetrics {
+    pub fn new(prefix: &str, global_tags: HashMap<String, String>) -> Self {
+        Self {
+            prefix: prometheus_name(prefix),
+            global_tags: global_tags.into_iter().collect(),
+            registry: Mutex::new(PrometheusRegistry::default()),
+        }
+    }
+
+    fn name(&self, key: &str) -> String {
+        format!("{}_{}", self.prefix, prometheus_name(key))
+    }
+
+    fn labels(&self, tags: &BTreeMap<String, String>, extra: Option<(&str, &str)>) -> String {
+        let mut labels: Vec<_> = self
+            .global_tags
+            .iter()
+            .chain(tags.iter())
+            .map(|(k, v)| format!("{}=\"{}\"", prometheus_name(k), escape_label_value(v)))
+            .collect();
+        if let Some((k, v)) = extra {
+            labels.push(format!("{}=\"{}\"", k, v));
+        }
+        if labels.is_empty() {
+            return String::new();
+        }
+        format!("{{{}}}", labels.join(","))
+    }
+
+    // Adds a timing to its histogram ``count`` times.
+    fn observe(&self, key: &str, value: u64, tags: Option<HashMap<&str, &str>>, count: u64) {
+        let mut registry = self.registry.lock();
+        let histogram = registry
+            .histograms
+            .entry(metric_key(key, tags))
+            .or_default();
+        if let Some(bucket) = HISTOGRAM_BUCKETS.iter().position(|bound| value <= *bound) {
+            histogram.buckets[bucket] += count;
+        }
+        histogram.sum += value * count;
+        histogram.count += count;
+    }
+
+    /// Writes all the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let registry = self.registry.lock();
+        let mut output = String::new();
+        let mut last_name = None;
+        let mut write_type = |output: &mut String, name: &str, kind: &str| {
+            if last_name.as_deref() != Some(name) {
+                output.push_str(&format!("# TYPE {} {}\n", name, kind));
+                last_name = Some(name.to_string());
+            }
+        };
+
+        for ((key, tags), value) in &registry.counters {
+            let name = self.name(key);
+            write_type(&mut output, &name, "counter");
+            output.push_str(&format!("{}{} {}\n", name, self.labels(tags, None), value));
+        }
+        for ((key, tags), value) in &registry.gauges {
+            let name = self.name(key);
+            write_type(&mut output, &name, "gauge");
+            output.push_str(&format!("{}{} {}\n", name, self.labels(tags, None), value));
+        }
+        for ((key, tags), histogram) in &registry.histograms {
+            let name = self.name(key);
+            write_type(&mut output, &name, "histogram");
+            let mut cumulative = 0;
+            for (bound, count) in HISTOGRAM_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let labels = self.labels(tags, Some(("le", &bound.to_string())));
+                output.push_str(&format!("{}_bucket{} {}\n", name, labels, cumulative));
+            }
+            let labels = self.labels(tags, Some(("le", "+Inf")));
+            output.push_str(&format!("{}_bucket{} {}\n", name, labels, histogram.count));
+            let labels = self.labels(tags, None);
+            output.push_str(&format!("{}_sum{} {}\n", name, labels, histogram.sum));
+            output.push_str(&format!("{}_count{} {}\n", name, labels, histogram.count));
+        }
+        output
+    }
+}
+
+impl Metrics for PrometheusMetrics {
+    fn counter(
+        &self,
+        key: &str,
+        value: Option<i64>,
+        tags: Option<HashMap<&str, &str>>,
+        sample_rate: Option<f64>,
+    ) {
+        if !should_sample(sample_rate) {
+            return;
+        }
+        // Same as the aggregated counters, sampled increments are scaled up.
+        let value = value.unwrap_or(1) as f64 / sample_rate.unwrap_or(1.0);
+        *self
+            .registry
+            .lock()
+            .counters
+            .entry(metric_key(key, tags))
+            .or_default() += value.round() as i64;
+    }
+
+    fn gauge(
+        &self,
+        key: &str,
+        value: u64,
+        tags: Option<HashMap<&str, &str>>,
+        sample_rate: Option<f64>,
+    ) {
+        if should_sample(sample_rate) {
+            self.registry
+                .lock()
+                .gauges
+                .insert(metric_key(key, tags), value);
+        }
+    }
+
+    fn time(
+        &self,
+        key: &str,
+        value: u64,
+        tags: Option<HashMap<&str, &str>>,
+        sample_rate: Option<f64>,
+    ) {
+        if should_sample(sample_rate) {
+            self.observe(key, value, tags, 1);
+        }
+    }
+
+    fn sampled_time(
+        &self,
+        key: &str,
+        value: u64,
+        tags: Option<HashMap<&str, &str>>,
+        sample_rate: f64,
+    ) {
+        self.observe(key, value, tags, (1.0 / sample_rate).round() as u64);
+    }
+}
+
 lazy_static! {
-    static ref METRICS_CLIENT: RwLock<Option<Arc<MetricsClient>>> = RwLock::new(None);
+    static ref METRICS_CLIENT: RwLock<Arc<dyn Metrics>> = RwLock::new(Arc::new(NoopMetrics));
 }
 
 const METRICS_MAX_QUEUE_SIZE: usize = 1024;
+const METRICS_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Sets the backend that the stream processor, the strategies and the
+/// functions of this module report to.
+pub fn configure(metrics: Arc<dyn Metrics>) {
+    *METRICS_CLIENT.write() = metrics;
+}
 
+/// Sends metrics to a StatsD server.
 pub fn init<A: ToSocketAddrs>(prefix: &str, host: A) {
-    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-    socket.set_nonblocking(true).unwrap();
-
-    let buffered_udp_sink = BufferedUdpMetricSink::from(host, socket).unwrap();
-    let queuing_sink = QueuingMetricSink::with_capacity(buffered_udp_sink, METRICS_MAX_QUEUE_SIZE);
-    // If metrics need to be sent out immediately without waiting then use the udp_sink below.
-    //let udp_sink = UdpMetricSink::from(host, socket).unwrap();
-    let statsd_client = StatsdClient::from_sink(prefix, queuing_sink);
-
-    let metrics_client = MetricsClient {
-        statsd_client,
-        prefix: String::from(prefix),
-    };
+    init_with_tags(prefix, host, HashMap::new())
+}
+
+/// Sends metrics to a StatsD server, tagging all of them with
+/// ``global_tags``. Metrics are aggregated and sent once per second.
+pub fn init_with_tags<A: ToSocketAddrs>(
+    prefix: &str,
+    host: A,
+    global_tags: HashMap<String, String>,
+) {
+    let metrics_client = MetricsClient::new_with_tags(prefix, host, global_tags);
     println!("Emitting metrics with prefix {}", metrics_client.prefix);
-    *METRICS_CLIENT.write() = Some(Arc::new(metrics_client));
+    configure(Arc::new(AggregatingMetrics::new(
+        Arc::new(metrics_client),
+        Box::new(SystemClock {}),
+        METRICS_FLUSH_INTERVAL,
+    )));
+}
+
+/// Keeps the metrics in memory to be scraped by Prometheus, tagging all of
+/// them with ``global_tags``. The returned backend renders them.
+pub fn init_prometheus(
+    prefix: &str,
+    global_tags: HashMap<String, String>,
+) -> Arc<PrometheusMetrics> {
+    let metrics = Arc::new(PrometheusMetrics::new(prefix, global_tags));
+    configure(metrics.clone());
+    metrics
 }
 
 // TODO: Remove cloning METRICS_CLIENT each time this is called using thread local storage.
+// Metrics are silently discarded until a backend has been configured.
 pub fn increment(
     key: &str,
     value: Option<i64>,
     tags: Option<HashMap<&str, &str>>,
     sample_rate: Option<f64>,
 ) {
-    METRICS_CLIENT
-        .read()
-        .clone()
-        .unwrap()
-        .counter(key, value, tags, sample_rate);
+    let client = METRICS_CLIENT.read().clone();
+    client.counter(key, value, tags, sample_rate);
 }
 
 // TODO: Remove cloning METRICS_CLIENT each time this is called using thread local storage.
 pub fn gauge(key: &str, value: u64, tags: Option<HashMap<&str, &str>>, sample_rate: Option<f64>) {
-    METRICS_CLIENT
-        .read()
-        .clone()
-        .unwrap()
-        .gauge(key, value, tags, sample_rate);
+    let client = METRICS_CLIENT.read().clone();
+    client.gauge(key, value, tags, sample_rate);
 }
 
 // TODO: Remove cloning METRICS_CLIENT each time this is called using thread local storage.
 pub fn time(key: &str, value: u64, tags: Option<HashMap<&str, &str>>, sample_rate: Option<f64>) {
-    METRICS_CLIENT
-        .read()
-        .clone()
-        .unwrap()
-        .time(key, value, tags, sample_rate);
+    let client = METRICS_CLIENT.read().clone();
+    client.time(key, value, tags, sample_rate);
+}
+
+/// Sends whatever the configured backend buffered.
+pub fn flush() {
+    let client = METRICS_CLIENT.read().clone();
+    client.flush();
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::utils::metrics::{gauge, increment, init, time, METRICS_CLIENT};
-    use std::collections::HashMap;
+    use crate::utils::clock::{Clock, TestingClock};
+    use crate::utils::metrics::{
+        configure, gauge, increment, init, time, AggregatingMetrics, Metrics, MetricsClient,
+        NoopMetrics, PrometheusMetrics, TIMER_SAMPLES,
+    };
+    use cadence::SpyMetricSink;
+    use lazy_static::lazy_static;
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
+
+    lazy_static! {
+        // The backend is global, tests replacing it must not run concurrently.
+        static ref BACKEND: Mutex<()> = Mutex::new(());
+    }
+
+    /// Records every metric as ``key:value|type``.
+    #[derive(Default)]
+    struct RecordingMetrics {
+        metrics: Mutex<Vec<String>>,
+    }
+
+    impl RecordingMetrics {
+        fn record(&self, key: &str, value: String, kind: &str, tags: Option<HashMap<&str, &str>>) {
+            let mut line = format!("{}:{}|{}", key, value, kind);
+            if let Some(tags) = tags {
+                let tags: BTreeMap<_, _> = tags.into_iter().collect();
+                let tags: Vec<_> = tags.iter().map(|(k, v)| format!("{}:{}", k, v)).collect();
+                line = format!("{}|#{}", line, tags.join(","));
+            }
+            self.metrics.lock().unwrap().push(line);
+        }
+
+        fn take(&self) -> Vec<String> {
+            let mut metrics = std::mem::take(&mut *self.metrics.lock().unwrap());
+            metrics.sort();
+            metrics
+        }
+    }
+
+    impl Metrics for RecordingMetrics {
+        fn counter(
+            &self,
+            key: &str,
+            value: Option<i64>,
+            tags: Option<HashMap<&str, &str>>,
+            _: Option<f64>,
+        ) {
+            self.record(key, value.unwrap_or(1).to_string(), "c", tags);
+        }
+
+        fn gauge(&self, key: &str, value: u64, tags: Option<HashMap<&str, &str>>, _: Option<f64>) {
+            self.record(key, value.to_string(), "g", tags);
+        }
+
+        fn time(&self, key: &str, value: u64, tags: Option<HashMap<&str, &str>>, _: Option<f64>) {
+            self.record(key, value.to_string(), "ms", tags);
+        }
+
+        fn sampled_time(
+            &self,
+            key: &str,
+            value: u64,
+            tags: Option<HashMap<&str, &str>>,
+            sample_rate: f64,
+        ) {
+            self.record(
+                key,
+                value.to_string(),
+                &format!("ms|@{}", sample_rate),
+                tags,
+            );
+        }
+    }
+
+    #[test]
+    fn test_configure() {
+        let _guard = BACKEND.lock().unwrap();
+        let recording = Arc::new(RecordingMetrics::default());
+        configure(recording.clone());
+
+        increment("a", Some(2), None, None);
+        assert_eq!(recording.take(), vec!["a:2|c"]);
+        configure(Arc::new(NoopMetrics));
+    }
+
+    #[test]
+    fn test_aggregation() {
+        let recording = Arc::new(RecordingMetrics::default());
+        let clock = TestingClock::new(SystemTime::now());
+        let metrics = AggregatingMetrics::new(
+            recording.clone(),
+            Box::new(clock.clone()),
+            Duration::from_secs(1),
+        );
+
+        let tags = HashMap::from([("partition", "0")]);
+        metrics.counter("a", None, None, None);
+        metrics.counter("a", Some(2), None, None);
+        metrics.counter("a", Some(4), Some(tags.clone()), None);
+        metrics.gauge("b", 5, None, None);
+        metrics.gauge("b", 3, None, None);
+        metrics.time("c", 10, None, None);
+        metrics.time("c", 20, None, None);
+        assert!(recording.take().is_empty());
+
+        clock.sleep(Duration::from_secs(1));
+        metrics.counter("a", None, None, Some(0.0));
+        assert_eq!(
+            recording.take(),
+            vec!["a:3|c", "a:4|c|#partition:0", "b:3|g", "c:10|ms", "c:20|ms"]
+        );
+
+        // Timers are sampled, and forwarded with their rate
+        for i in 0..128 {
+            metrics.time("c", i, None, None);
+        }
+        metrics.flush();
+        let timings = recording.take();
+        assert_eq!(timings.len(), TIMER_SAMPLES);
+        assert!(timings.iter().all(|timing| timing.ends_with("|ms|@0.25")));
+    }
+
+    #[test]
+    fn test_statsd_format() {
+        let (rx, sink) = SpyMetricSink::new();
+        let client = MetricsClient::from_sink(
+            "snuba.consumer",
+            sink,
+            HashMap::from([("storage".to_string(), "errors".to_string())]),
+        );
+
+        client.counter("a", Some(2), None, None);
+        assert_eq!(
+            String::from_utf8(rx.try_recv().unwrap()).unwrap(),
+            "snuba.consumer.a:2|c|#storage:errors"
+        );
+
+        client.time("b", 30, Some(HashMap::from([("step", "reduce")])), None);
+        assert_eq!(
+            String::from_utf8(rx.try_recv().unwrap()).unwrap(),
+            "snuba.consumer.b:30|ms|#storage:errors,step:reduce"
+        );
+
+        client.sampled_time("c", 5, Some(HashMap::from([("step", "reduce")])), 0.25);
+        assert_eq!(
+            String::from_utf8(rx.try_recv().unwrap()).unwrap(),
+            "snuba.consumer.c:5|ms|@0.25|#storage:errors,step:reduce"
+        );
+    }
+
+    #[test]
+    fn test_prometheus_format() {
+        let metrics = PrometheusMetrics::new(
+            "snuba.consumer",
+            HashMap::from([("storage".to_string(), "errors".to_string())]),
+        );
+
+        metrics.counter("a", Some(2), None, None);
+        metrics.counter("a", None, None, None);
+        metrics.gauge("b", 5, Some(HashMap::from([("partition", "0")])), None);
+        metrics.time("c", 3, None, None);
+        metrics.time("c", 30_000, None, None);
+
+        let rendered = metrics.render();
+        let lines: Vec<_> = rendered.lines().collect();
+        assert_eq!(
+            lines[..5],
+            [
+                "# TYPE snuba_consumer_a counter",
+                "snuba_consumer_a{storage=\"errors\"} 3",
+                "# TYPE snuba_consumer_b gauge",
+                "snuba_consumer_b{storage=\"errors\",partition=\"0\"} 5",
+                "# TYPE snuba_consumer_c histogram",
+            ]
+        );
+        assert_eq!(
+            lines[5],
+            "snuba_consumer_c_bucket{storage=\"errors\",le=\"1\"} 0"
+        );
+        assert_eq!(
+            lines[6],
+            "snuba_consumer_c_bucket{storage=\"errors\",le=\"5\"} 1"
+        );
+        assert_eq!(
+            lines[17..],
+            [
+                "snuba_consumer_c_bucket{storage=\"errors\",le=\"+Inf\"} 2",
+                "snuba_consumer_c_sum{storage=\"errors\"} 30003",
+                "snuba_consumer_c_count{storage=\"errors\"} 2",
+            ]
+        );
+    }
 
     #[test]
     fn test_metrics() {
-        init("my_host", "0.0.0.0:8125");
+        let _guard = BACKEND.lock().unwrap();
+        let client = MetricsClient::new("my_host", "0.0.0.0:8125");
+        assert!(!client.should_sample(Some(0.0)));
+        assert!(client.should_sample(Some(1.0)));
 
-        assert!(!METRICS_CLIENT
-            .read()
-            .clone()
-            .unwrap()
-            .should_sample(Some(0.0)),);
-        assert!(METRICS_CLIENT
-            .read()
-            .clone()
-            .unwrap()
-            .should_sample(Some(1.0)),);
+        init("my_host", "0.0.0.0:8125");
 
         increment(
             "a",
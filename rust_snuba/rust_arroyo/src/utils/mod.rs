@@ -1,3 +1,5 @@
+pub mod clickhouse_client;
 pub mod clock;
 pub mod metrics;
-pub mod clickhouse_client;
+pub mod offset_tracker;
+pub mod timing;
@@ -0,0 +1,131 @@
+use crate::types::Partition;
+use std::collections::{BTreeSet, HashMap};
+
+#[derive(Debug)]
+struct PartitionOffsets {
+    in_flight: BTreeSet<u64>,
+    // The offset following the highest offset ever added.
+    next_offset: u64,
+    // The last position returned by ``take_committable``, or the first
+    // offset added. Nothing can be committed until the partition moves
+    // past it.
+    committed: u64,
+}
+
+/// Keeps track of the offsets in flight in a strategy that completes
+/// messages out of order, and computes how far each partition can be
+/// committed without committing past a message that was not completed.
+///
+/// Offsets are added in the order they are consumed. The position returned
+/// for a partition is the lowest offset still in flight, or the offset
+/// following the last one added if they all completed.
+#[derive(Debug, Default)]
+pub struct OffsetTracker {
+    partitions: HashMap<Partition, PartitionOffsets>,
+}
+
+impl OffsetTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a message that started processing.
+    pub fn add(&mut self, partition: &Partition, offset: u64) {
+        let offsets =
+            self.partitions
+                .entry(partition.clone())
+                .or_insert_with(|| PartitionOffsets {
+                    in_flight: BTreeSet::new(),
+                    next_offset: offset,
+                    committed: offset,
+                });
+        offsets.in_flight.insert(offset);
+        offsets.next_offset = offsets.next_offset.max(offset + 1);
+    }
+
+    /// Records a message that completed. Offsets that were never added are
+    /// ignored.
+    pub fn complete(&mut self, partition: &Partition, offset: u64) {
+        if let Some(offsets) = self.partitions.get_mut(partition) {
+            offsets.in_flight.remove(&offset);
+        }
+    }
+
+    /// The number of messages in flight on all partitions.
+    pub fn in_flight(&self) -> usize {
+        self.partitions
+            .values()
+            .map(|offsets| offsets.in_flight.len())
+            .sum()
+    }
+
+    /// Returns the positions of the partitions that moved forward since the
+    /// previous call.
+    pub fn take_committable(&mut self) -> HashMap<Partition, u64> {
+        let mut positions = HashMap::new();
+        for (partition, offsets) in self.partitions.iter_mut() {
+            let position = match offsets.in_flight.first() {
+                Some(lowest) => *lowest,
+                None => offsets.next_offset,
+            };
+            if position > offsets.committed {
+                offsets.committed = position;
+                positions.insert(partition.clone(), position);
+            }
+        }
+        positions
+    }
+
+    /// Stops tracking partitions, usually because they were revoked.
+    pub fn forget<'p>(&mut self, partitions: impl IntoIterator<Item = &'p Partition>) {
+        for partition in partitions {
+            self.partitions.remove(partition);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OffsetTracker;
+    use crate::types::{Partition, Topic};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_offset_tracker() {
+        let partition = Partition {
+            topic: Topic {
+                name: "test".to_string(),
+            },
+            index: 0,
+        };
+        let mut tracker = OffsetTracker::new();
+        for offset in 10..14 {
+            tracker.add(&partition, offset);
+        }
+        assert_eq!(tracker.in_flight(), 4);
+        assert!(tracker.take_committable().is_empty());
+
+        // Completing out of order does not commit past offset 10
+        tracker.complete(&partition, 12);
+        tracker.complete(&partition, 11);
+        assert!(tracker.take_committable().is_empty());
+
+        tracker.complete(&partition, 10);
+        assert_eq!(
+            tracker.take_committable(),
+            HashMap::from([(partition.clone(), 13)])
+        );
+        assert!(tracker.take_committable().is_empty());
+
+        tracker.complete(&partition, 13);
+        assert_eq!(
+            tracker.take_committable(),
+            HashMap::from([(partition.clone(), 14)])
+        );
+        assert_eq!(tracker.in_flight(), 0);
+
+        tracker.forget([&partition]);
+        tracker.add(&partition, 5);
+        assert!(tracker.take_committable().is_empty());
+    }
+}
@@ -0,0 +1,90 @@
+use std::time::{Duration, Instant};
+
+/// Represents a point in time by which some work has to be completed, for
+/// example a ``join`` call on a strategy.
+///
+/// Strategies that wrap other strategies should spend part of the time on
+/// their own work and hand ``remaining()`` down to the next step, so that the
+/// whole chain finishes within the timeout given to the outermost strategy.
+#[derive(Clone, Copy, Debug)]
+pub struct Deadline {
+    start: Instant,
+    duration: Option<Duration>,
+}
+
+impl Deadline {
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            start: Instant::now(),
+            duration: Some(duration),
+        }
+    }
+
+    /// Builds a deadline from the optional timeout passed to ``join``. A
+    /// ``None`` timeout never elapses.
+    pub fn from_timeout(timeout: Option<Duration>) -> Self {
+        Self {
+            start: Instant::now(),
+            duration: timeout,
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    pub fn has_elapsed(&self) -> bool {
+        match self.duration {
+            Some(duration) => self.elapsed() >= duration,
+            None => false,
+        }
+    }
+
+    /// The time left until the deadline, or ``None`` if there is no deadline.
+    /// This is the value to pass as timeout to downstream ``join`` calls.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.duration
+            .map(|duration| duration.saturating_sub(self.elapsed()))
+    }
+
+    /// Splits the remaining time evenly between ``parts`` children that are
+    /// joined one after the other.
+    pub fn split(&self, parts: u32) -> Option<Duration> {
+        self.remaining().map(|remaining| remaining / parts.max(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Deadline;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[test]
+    fn test_deadline() {
+        let deadline = Deadline::new(Duration::from_millis(20));
+        assert!(!deadline.has_elapsed());
+        assert!(deadline.remaining().unwrap() <= Duration::from_millis(20));
+
+        sleep(Duration::from_millis(25));
+        assert!(deadline.has_elapsed());
+        assert_eq!(deadline.remaining(), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_no_deadline() {
+        let deadline = Deadline::from_timeout(None);
+        assert!(!deadline.has_elapsed());
+        assert_eq!(deadline.remaining(), None);
+        assert_eq!(deadline.split(2), None);
+    }
+
+    #[test]
+    fn test_split() {
+        let deadline = Deadline::new(Duration::from_secs(10));
+        let part = deadline.split(4).unwrap();
+        assert!(part <= Duration::from_millis(2500));
+        assert!(part > Duration::from_millis(2000));
+        assert!(deadline.split(0).is_some());
+    }
+}
@@ -0,0 +1,151 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use rust_arroyo::processing::state::ProcessorStateHandle;
+use rust_arroyo::utils::metrics::PrometheusMetrics;
+
+/// Serves the state of the consumer as JSON on ``GET /state``, from a thread
+/// of its own, so that a consumer that is stuck can be inspected. It only
+/// speaks enough HTTP/1.1 for ``curl`` and probes, one request per
+/// connection.
+///
+/// The metrics are served to Prometheus on ``GET /metrics`` if ``metrics``
+/// is set.
+pub fn serve(
+    port: u16,
+    state: ProcessorStateHandle,
+    metrics: Option<Arc<PrometheusMetrics>>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    log::info!("Serving the consumer state on port {}", port);
+    thread::Builder::new()
+        .name("admin".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| handle(stream, &state, metrics.as_deref()));
+                if let Err(error) = result {
+                    log::warn!("Failed to serve an admin request: {}", error);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn handle(
+    mut stream: TcpStream,
+    state: &ProcessorStateHandle,
+    metrics: Option<&PrometheusMetrics>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers are read so that closing the connection does not reset
+    // it, none of them are used.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let (status, content_type, body) = respond(&request_line, state, metrics);
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(&body)
+}
+
+fn respond(
+    request_line: &str,
+    state: &ProcessorStateHandle,
+    metrics: Option<&PrometheusMetrics>,
+) -> (&'static str, &'static str, Vec<u8>) {
+    let mut parts = request_line.split_whitespace();
+    match (parts.next(), parts.next(), metrics) {
+        (Some("GET"), Some("/state"), _) => match serde_json::to_vec(&state.get()) {
+            Ok(body) => ("200 OK", "application/json", body),
+            Err(error) => (
+                "500 Internal Server Error",
+                "text/plain",
+                error.to_string().into_bytes(),
+            ),
+        },
+        (Some("GET"), Some("/metrics"), Some(metrics)) => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            metrics.render().into_bytes(),
+        ),
+        (Some("GET"), _, _) => ("404 Not Found", "text/plain", b"Not found\n".to_vec()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            b"Method not allowed\n".to_vec(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::respond;
+    use rust_arroyo::processing::state::ProcessorStateHandle;
+    use rust_arroyo::utils::metrics::{Metrics, PrometheusMetrics};
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_respond() {
+        let state = ProcessorStateHandle::default();
+        let (status, content_type, body) = respond("GET /state HTTP/1.1\r\n", &state, None);
+        assert_eq!((status, content_type), ("200 OK", "application/json"));
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "uptime_secs": 0,
+                "paused": false,
+                "message_carried_over": false,
+                "in_flight_messages": 0,
+                "last_poll_ms_ago": null,
+                "last_submit_ms_ago": null,
+                "strategy": null,
+                "partitions": [],
+            })
+        );
+
+        assert_eq!(
+            respond("GET / HTTP/1.1\r\n", &state, None).0,
+            "404 Not Found"
+        );
+        assert_eq!(
+            respond("POST /state HTTP/1.1\r\n", &state, None).0,
+            "405 Method Not Allowed"
+        );
+    }
+
+    #[test]
+    fn test_respond_metrics() {
+        let state = ProcessorStateHandle::default();
+        assert_eq!(
+            respond("GET /metrics HTTP/1.1\r\n", &state, None).0,
+            "404 Not Found"
+        );
+
+        let metrics = PrometheusMetrics::new("snuba.consumer", Default::default());
+        metrics.counter("batch.flushed", None, None, None);
+        let (status, content_type, body) =
+            respond("GET /metrics HTTP/1.1\r\n", &state, Some(&metrics));
+        assert_eq!(
+            (status, content_type),
+            ("200 OK", "text/plain; version=0.0.4")
+        );
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "# TYPE snuba_consumer_batch_flushed counter\nsnuba_consumer_batch_flushed 1\n"
+        );
+    }
+}
@@ -1,5 +1,10 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
 
+use anyhow::{bail, ensure, Context};
+use reqwest::header::HeaderValue;
+use rust_arroyo::codecs::PayloadFormat;
 use serde::Deserialize;
 
 #[derive(Deserialize)]
@@ -9,42 +14,316 @@ pub struct ConsumerConfig {
     pub raw_topic: TopicConfig,
     pub commit_log_topic: Option<TopicConfig>,
     pub replacements_topic: Option<TopicConfig>,
+    /// Shadow mode: the rows are produced to this topic instead of being
+    /// written to ClickHouse, to be compared with those of the Python
+    /// consumers.
+    #[serde(default)]
+    pub shadow_topic: Option<TopicConfig>,
+    /// Invalid messages are produced to this topic instead of crashing the
+    /// consumer.
+    #[serde(default)]
+    pub dlq_topic: Option<TopicConfig>,
+    /// The consumer stops once this ratio of the recent messages of a
+    /// partition were invalid, see ``DlqLimit``. Unlimited if not set.
+    #[serde(default)]
+    pub dlq_max_invalid_ratio: Option<f64>,
+    /// The consumer stops after this many consecutive invalid messages.
+    /// Unlimited if not set.
+    #[serde(default)]
+    pub dlq_max_consecutive_count: Option<u64>,
+    /// A YAML or JSON file polled for settings to change without restarting
+    /// the consumer, see ``RuntimeConfig``.
+    #[serde(default)]
+    pub runtime_config_file: Option<String>,
+    /// The port the state of the consumer is served on, see ``admin``.
+    #[serde(default)]
+    pub admin_port: Option<u16>,
+    /// Metrics are served to Prometheus on ``/metrics`` of the admin server
+    /// instead of being sent to StatsD.
+    #[serde(default)]
+    pub prometheus_metrics: bool,
+    /// How many times the consumer is restarted after a crash before giving
+    /// up, see ``Supervisor``.
+    #[serde(default)]
+    pub max_restarts: u32,
+    /// The messages are forwarded to the storages at most at this rate,
+    /// to spare ClickHouse while a backlog is consumed.
+    #[serde(default)]
+    pub max_messages_per_second: Option<u64>,
+    /// Same as ``max_messages_per_second``, counting the bytes of the
+    /// payloads.
+    #[serde(default)]
+    pub max_bytes_per_second: Option<u64>,
+    /// Messages produced longer ago than this are dropped instead of being
+    /// written, they would be removed by the TTL of the table anyway.
+    #[serde(default)]
+    pub max_message_age_secs: Option<u64>,
+    pub env: EnvConfig,
+    pub max_batch_size: usize,
+    pub max_batch_time_ms: u64,
+    #[serde(default)]
+    pub insert_compression: Compression,
+    /// Transient insert failures are retried up to this many attempts in
+    /// total, the default of the writer is used if not set.
+    #[serde(default)]
+    pub max_insert_attempts: Option<u32>,
+    /// Inserts stop being sent for ``circuit_breaker_cool_down_ms`` after
+    /// this many of them failed in a row, instead of being sent again after
+    /// a backoff.
+    #[serde(default)]
+    pub circuit_breaker_failures: Option<u32>,
+    #[serde(default)]
+    pub circuit_breaker_cool_down_ms: Option<u64>,
+    #[serde(default)]
+    pub kafka_backend: KafkaBackend,
+    /// Invalid messages are raised instead of only being logged.
+    #[serde(default)]
+    pub enforce_schema: bool,
+    /// The columns of the storages are not compared to the tables on
+    /// startup.
+    #[serde(default)]
+    pub skip_schema_check: bool,
+    /// Storages that have a Rust processor use it instead of their Python
+    /// processor.
+    #[serde(default)]
+    pub use_rust_processor: bool,
+    /// How many threads the Rust processors run on, one if not set.
+    #[serde(default)]
+    pub processor_concurrency: Option<usize>,
+}
+
+/// The librdkafka consumer the Rust consumer is built on.
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum KafkaBackend {
+    #[default]
+    Base,
+    Stream,
+}
+
+/// How the bodies of the inserts sent to ClickHouse are compressed. The
+/// default level of the algorithm is used if none is set.
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "algorithm", rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip {
+        level: Option<u32>,
+    },
+    Zstd {
+        level: Option<i32>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnvConfig {
+    pub dogstatsd_host: Option<String>,
+    pub dogstatsd_port: Option<u16>,
+    pub sentry_dsn: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct TopicConfig {
     pub physical_topic_name: String,
+    pub logical_topic_name: String,
     pub broker_config: BrokerConfig,
+    /// How the payloads of the topic are encoded, processors decode the
+    /// messages of ``raw_topic`` in this format.
+    #[serde(default)]
+    pub payload_format: PayloadFormat,
 }
 
 pub type BrokerConfig = HashMap<String, Option<String>>;
 
 impl ConsumerConfig {
+    /// Loads the JSON config the Python CLI resolved.
     pub fn load_from_str(payload: &str) -> Result<Self, anyhow::Error> {
         let d: Self = serde_json::from_str(payload)?;
+        d.validate()?;
         Ok(d)
     }
+
+    /// Loads a config written by hand, in YAML if the extension of the file
+    /// is ``.yaml`` or ``.yml`` and in JSON otherwise.
+    pub fn load_from_file(path: &Path) -> Result<Self, anyhow::Error> {
+        let payload = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml" | "yml") => {
+                let d: Self = serde_yaml::from_str(&payload)?;
+                d.validate()?;
+                Ok(d)
+            }
+            _ => Self::load_from_str(&payload),
+        }
+    }
+
+    /// Checks what deserializing can not, so that a wrong config fails on
+    /// startup with an error saying which setting is wrong.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        ensure!(
+            !self.storages.is_empty(),
+            "At least one storage is required"
+        );
+        let mut names = HashSet::new();
+        for storage in &self.storages {
+            ensure!(
+                names.insert(&storage.name),
+                "The storage {} is listed more than once",
+                storage.name
+            );
+            storage
+                .clickhouse_cluster
+                .validate()
+                .with_context(|| format!("Invalid ClickHouse cluster of {}", storage.name))?;
+            if let Some(slicing) = &storage.slicing {
+                slicing
+                    .validate()
+                    .with_context(|| format!("Invalid slicing of {}", storage.name))?;
+            }
+        }
+        ensure!(self.max_batch_size > 0, "max_batch_size must be positive");
+        ensure!(
+            self.max_batch_time_ms > 0,
+            "max_batch_time_ms must be positive"
+        );
+        ensure!(
+            self.max_insert_attempts != Some(0),
+            "max_insert_attempts must be positive"
+        );
+        if self.env.dogstatsd_host.is_some() != self.env.dogstatsd_port.is_some() {
+            bail!("dogstatsd_host and dogstatsd_port must be set together");
+        }
+        ensure!(
+            !self.prometheus_metrics || self.admin_port.is_some(),
+            "prometheus_metrics requires admin_port"
+        );
+        for (name, topic) in [
+            ("commit_log_topic", &self.commit_log_topic),
+            ("replacements_topic", &self.replacements_topic),
+            ("shadow_topic", &self.shadow_topic),
+            ("dlq_topic", &self.dlq_topic),
+        ] {
+            if let Some(topic) = topic {
+                ensure!(
+                    topic.physical_topic_name != self.raw_topic.physical_topic_name,
+                    "{} can not be the topic the consumer consumes, {}",
+                    name,
+                    topic.physical_topic_name
+                );
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
+#[allow(dead_code)]
 pub struct StoragesConfig {
     pub name: String,
     pub clickhouse_table_name: String,
     pub clickhouse_cluster: ClickhouseConfig,
     pub message_processor: MessageProcessorConfig,
+    #[serde(default)]
+    pub insert_format: InsertFormat,
+    /// The columns of the table rows are inserted into, not used by the
+    /// ``JSONEachRow`` format.
+    #[serde(default)]
+    pub columns: Vec<ColumnConfig>,
+    /// Rows are written to the cluster of their slice instead of
+    /// ``clickhouse_cluster``.
+    #[serde(default)]
+    pub slicing: Option<SlicingConfig>,
 }
 
-#[derive(Deserialize)]
+/// How the rows of a sliced storage are routed to the clusters of the
+/// slices, see ``snuba/datasets/slicing.py``.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SlicingConfig {
+    /// The column rows are sliced by, such as ``org_id``.
+    pub shard_column: String,
+    pub logical_partitions: u64,
+    /// The slice of every logical partition.
+    pub logical_partition_mapping: HashMap<u64, u32>,
+    pub clusters: HashMap<u32, ClickhouseConfig>,
+}
+
+impl SlicingConfig {
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        for logical_partition in 0..self.logical_partitions {
+            let Some(slice_id) = self.logical_partition_mapping.get(&logical_partition) else {
+                bail!("The logical partition {} has no slice", logical_partition);
+            };
+            ensure!(
+                self.clusters.contains_key(slice_id),
+                "The slice {} of the logical partition {} has no cluster",
+                slice_id,
+                logical_partition
+            );
+        }
+        for (slice_id, cluster) in &self.clusters {
+            cluster
+                .validate()
+                .with_context(|| format!("Invalid cluster of the slice {}", slice_id))?;
+        }
+        Ok(())
+    }
+}
+
+/// The format the rows of a storage are inserted in.
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+pub enum InsertFormat {
+    #[default]
+    #[serde(rename = "JSONEachRow")]
+    JsonEachRow,
+    RowBinary,
+    #[serde(rename = "CSVWithNames")]
+    CsvWithNames,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ColumnConfig {
+    pub name: String,
+    /// The ClickHouse type, such as ``Nullable(String)``.
+    pub r#type: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
 #[serde(deny_unknown_fields)]
+#[allow(dead_code)]
 pub struct ClickhouseConfig {
     pub host: String,
     pub port: u16,
+    pub http_port: u16,
     pub user: String,
     pub password: String,
     pub database: String,
 }
 
+impl ClickhouseConfig {
+    /// The credentials and the database are sent in HTTP headers.
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        for (name, value) in [
+            ("user", &self.user),
+            ("password", &self.password),
+            ("database", &self.database),
+        ] {
+            ensure!(
+                HeaderValue::from_str(value).is_ok(),
+                "The {} of {} can not be sent in an HTTP header",
+                name,
+                self.host
+            );
+        }
+        Ok(())
+    }
+}
+
 #[derive(Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct MessageProcessorConfig {
@@ -52,3 +331,120 @@ pub struct MessageProcessorConfig {
     pub python_module: String,
     // TODO: args support
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ConsumerConfig;
+    use rust_arroyo::codecs::PayloadFormat;
+    use serde_json::{json, Value};
+    use std::fs;
+
+    fn config() -> Value {
+        let topic = json!({
+            "physical_topic_name": "outcomes",
+            "logical_topic_name": "outcomes",
+            "broker_config": {"bootstrap.servers": "127.0.0.1:9092"},
+        });
+        json!({
+            "storages": [{
+                "name": "outcomes_raw",
+                "clickhouse_table_name": "outcomes_raw_local",
+                "clickhouse_cluster": {
+                    "host": "127.0.0.1",
+                    "port": 9000,
+                    "http_port": 8123,
+                    "user": "default",
+                    "password": "",
+                    "database": "default",
+                },
+                "message_processor": {
+                    "python_class_name": "OutcomesProcessor",
+                    "python_module": "snuba.datasets.processors.outcomes_processor",
+                },
+            }],
+            "raw_topic": topic,
+            "commit_log_topic": null,
+            "replacements_topic": null,
+            "env": {"dogstatsd_host": null, "dogstatsd_port": null, "sentry_dsn": null},
+            "max_batch_size": 1000,
+            "max_batch_time_ms": 1000,
+        })
+    }
+
+    fn error(config: Value) -> String {
+        let error = ConsumerConfig::load_from_str(&config.to_string())
+            .err()
+            .unwrap();
+        format!("{:#}", error)
+    }
+
+    #[test]
+    fn test_validate() {
+        let consumer_config = ConsumerConfig::load_from_str(&config().to_string()).unwrap();
+        assert_eq!(consumer_config.storages[0].name, "outcomes_raw");
+        assert!(consumer_config.dlq_topic.is_none());
+        assert_eq!(
+            consumer_config.raw_topic.payload_format,
+            PayloadFormat::Json
+        );
+
+        let mut msgpack = config();
+        msgpack["raw_topic"]["payload_format"] = json!("msgpack");
+        let consumer_config = ConsumerConfig::load_from_str(&msgpack.to_string()).unwrap();
+        assert_eq!(
+            consumer_config.raw_topic.payload_format,
+            PayloadFormat::Msgpack
+        );
+
+        let mut invalid = config();
+        invalid["max_batch_size"] = json!(0);
+        assert_eq!(error(invalid), "max_batch_size must be positive");
+
+        let mut invalid = config();
+        let storage = invalid["storages"][0].clone();
+        invalid["storages"].as_array_mut().unwrap().push(storage);
+        assert_eq!(
+            error(invalid),
+            "The storage outcomes_raw is listed more than once"
+        );
+
+        let mut invalid = config();
+        invalid["dlq_topic"] = invalid["raw_topic"].clone();
+        assert_eq!(
+            error(invalid),
+            "dlq_topic can not be the topic the consumer consumes, outcomes"
+        );
+
+        let mut invalid = config();
+        invalid["storages"][0]["slicing"] = json!({
+            "shard_column": "org_id",
+            "logical_partitions": 2,
+            "logical_partition_mapping": {"0": 0, "1": 1},
+            "clusters": {"0": invalid["storages"][0]["clickhouse_cluster"]},
+        });
+        assert_eq!(
+            error(invalid),
+            "Invalid slicing of outcomes_raw: The slice 1 of the logical partition 1 has no cluster"
+        );
+
+        let mut invalid = config();
+        invalid["storages"][0]["clickhouse_cluster"]["password"] = json!("secret\n");
+        assert_eq!(
+            error(invalid),
+            "Invalid ClickHouse cluster of outcomes_raw: The password of 127.0.0.1 can not be sent in an HTTP header"
+        );
+    }
+
+    #[test]
+    fn test_load_from_file() {
+        let path = std::env::temp_dir().join("snuba-test-consumer-config.yaml");
+        fs::write(&path, serde_yaml::to_string(&config()).unwrap()).unwrap();
+        let consumer_config = ConsumerConfig::load_from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(consumer_config.max_batch_size, 1000);
+        assert_eq!(
+            consumer_config.raw_topic.broker_config["bootstrap.servers"].as_deref(),
+            Some("127.0.0.1:9092")
+        );
+    }
+}
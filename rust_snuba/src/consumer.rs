@@ -1,130 +1,593 @@
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 
 use rust_arroyo::backends::kafka::config::KafkaConfig;
+use rust_arroyo::backends::kafka::producer::KafkaProducer;
+use rust_arroyo::backends::kafka::stream::KafkaStreamConsumer;
 use rust_arroyo::backends::kafka::types::KafkaPayload;
 use rust_arroyo::backends::kafka::KafkaConsumer;
-use rust_arroyo::processing::strategies::{
-    CommitRequest, MessageRejected, ProcessingStrategy, ProcessingStrategyFactory, commit_offsets
-};
+use rust_arroyo::backends::Consumer;
+use rust_arroyo::processing::dlq::{DlqLimit, DlqPolicy, KafkaDlqProducer};
+use rust_arroyo::processing::strategies::drop_stale::DropStale;
+use rust_arroyo::processing::strategies::healthcheck::Healthcheck;
+use rust_arroyo::processing::strategies::rate_limit::RateLimit;
+use rust_arroyo::processing::strategies::retry::RetryPolicy;
+use rust_arroyo::processing::strategies::tee::Tee;
+use rust_arroyo::processing::strategies::throttle_commits::ThrottleCommits;
+use rust_arroyo::processing::strategies::trace_context::PropagateTraceContext;
+use rust_arroyo::processing::strategies::{ProcessingStrategy, ProcessingStrategyFactory};
+use rust_arroyo::processing::supervisor::{RestartPolicy, Supervisor};
 use rust_arroyo::processing::StreamProcessor;
-use rust_arroyo::types::{Message, Topic};
+use rust_arroyo::types::{Topic, TopicOrPartition};
+use rust_arroyo::utils::metrics;
 
+use anyhow::{bail, Context};
+use pyo3::exceptions::PyRuntimeError;
 use pyo3::prelude::*;
 
+use crate::admin;
 use crate::config;
-use crate::strategies::python::PythonTransformStep;
+use crate::encoders::{build_encoder, RowsEncoder};
+use crate::processors::{get_processor, MessageProcessor};
+use crate::runtime_config::{RuntimeCommitPolicy, RuntimeConfigHandle};
+use crate::schema::check_schema;
+use crate::strategies::circuit_breaker::{CircuitBreaker, CircuitBreakerPolicy};
+use crate::strategies::clickhouse::{ClickhouseClient, ClickhouseWriter, INSERT_RETRY_POLICY};
+use crate::strategies::commit_log::CommitLog;
+use crate::strategies::processor::RustProcessor;
+use crate::strategies::python::{load_processor, PythonTransformStep};
+use crate::strategies::replacements::ProduceReplacements;
+use crate::strategies::sentry_context::SentryContext;
+use crate::strategies::shadow::ProduceRows;
+use crate::strategies::slicing::SlicedWriter;
+use crate::strategies::validate_schema::ValidateSchema;
 use crate::types::BytesInsertBatch;
 
-struct ClickhouseWriterStep {
-    next_step: Box<dyn ProcessingStrategy<()>>,
-}
-
-impl ClickhouseWriterStep {
-    fn new<N>(next_step: N) -> Self
-    where
-        N: ProcessingStrategy<()> + 'static,
-    {
-        ClickhouseWriterStep {
-            next_step: Box::new(next_step),
-        }
-    }
-}
-impl ProcessingStrategy<BytesInsertBatch> for ClickhouseWriterStep {
-    fn poll(&mut self) -> Option<CommitRequest> {
-        self.next_step.poll()
-    }
-
-    fn submit(&mut self, message: Message<BytesInsertBatch>) -> Result<(), MessageRejected> {
-        for row in message.payload().rows {
-            let decoded_row = String::from_utf8_lossy(&row);
-            log::debug!("insert: {:?}", decoded_row);
-        }
-
-        self.next_step.submit(message.replace(()))
-    }
-
-    fn close(&mut self) {
-        self.next_step.close();
-    }
-
-    fn terminate(&mut self) {
-        self.next_step.terminate();
-    }
-
-    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
-        self.next_step.join(timeout)
-    }
-}
+/// How often librdkafka reports the statistics of the consumer, which are
+/// sent as metrics.
+const KAFKA_STATISTICS_INTERVAL: Duration = Duration::from_secs(10);
 
+/// Runs the consumer until it is stopped, ``snuba rust-consumer`` calls it
+/// with the configuration it resolved. The GIL is released while the
+/// consumer runs, and errors are raised as a ``RuntimeError``.
 #[pyfunction]
 pub fn consumer(
     py: Python<'_>,
     consumer_group: &str,
     auto_offset_reset: &str,
     consumer_config_raw: &str,
-) {
-    py.allow_threads(|| consumer_impl(consumer_group, auto_offset_reset, consumer_config_raw));
+    health_check_file: Option<&str>,
+    group_instance_id: Option<&str>,
+) -> PyResult<()> {
+    py.allow_threads(|| {
+        let consumer_config = config::ConsumerConfig::load_from_str(consumer_config_raw)
+            .context("Invalid consumer config")?;
+        consumer_impl(
+            consumer_group,
+            auto_offset_reset,
+            consumer_config,
+            health_check_file,
+            group_instance_id,
+        )
+    })
+    .map_err(|error| PyRuntimeError::new_err(format!("{:#}", error)))
 }
 
-pub fn consumer_impl(consumer_group: &str, auto_offset_reset: &str, consumer_config_raw: &str) {
+/// Like ``consumer``, with the config of a YAML or JSON file instead of the
+/// one the CLI resolves from the settings of the storages.
+#[pyfunction]
+pub fn consumer_from_file(
+    py: Python<'_>,
+    consumer_group: &str,
+    auto_offset_reset: &str,
+    consumer_config_path: &str,
+    health_check_file: Option<&str>,
+    group_instance_id: Option<&str>,
+) -> PyResult<()> {
+    py.allow_threads(|| {
+        let consumer_config =
+            config::ConsumerConfig::load_from_file(Path::new(consumer_config_path))
+                .with_context(|| format!("Invalid consumer config {}", consumer_config_path))?;
+        consumer_impl(
+            consumer_group,
+            auto_offset_reset,
+            consumer_config,
+            health_check_file,
+            group_instance_id,
+        )
+    })
+    .map_err(|error| PyRuntimeError::new_err(format!("{:#}", error)))
+}
+
+pub fn consumer_impl(
+    consumer_group: &str,
+    auto_offset_reset: &str,
+    consumer_config: config::ConsumerConfig,
+    health_check_file: Option<&str>,
+    group_instance_id: Option<&str>,
+) -> anyhow::Result<()> {
+    enum StorageProcessor {
+        Rust(Arc<dyn MessageProcessor>),
+        // The function returned by ``load_processor``.
+        Python(Py<PyAny>),
+    }
+
+    struct StorageStrategyConfig {
+        name: String,
+        processor: StorageProcessor,
+        clickhouse_config: config::ClickhouseConfig,
+        clickhouse_table_name: String,
+        encoder: Arc<dyn RowsEncoder>,
+        slicing: Option<config::SlicingConfig>,
+    }
+
     struct ConsumerStrategyFactory {
-        processor_config: config::MessageProcessorConfig,
+        storages: Vec<StorageStrategyConfig>,
+        insert_compression: config::Compression,
+        retry_policy: RetryPolicy,
+        circuit_breaker: Option<CircuitBreakerPolicy>,
+        max_batch_size: usize,
+        max_batch_time: Duration,
+        health_check_file: Option<String>,
+        max_messages_per_second: Option<u64>,
+        max_bytes_per_second: Option<u64>,
+        max_message_age: Option<Duration>,
+        logical_topic_name: String,
+        enforce_schema: bool,
+        replacements: Option<(Arc<KafkaProducer>, Topic)>,
+        shadow: Option<(Arc<KafkaProducer>, Topic)>,
+        runtime_config: Option<RuntimeConfigHandle>,
+        processor_concurrency: usize,
+    }
+
+    impl ConsumerStrategyFactory {
+        fn create_storage(
+            &self,
+            storage: &StorageStrategyConfig,
+        ) -> Box<dyn ProcessingStrategy<KafkaPayload>> {
+            let writer = |cluster| {
+                let client = ClickhouseClient::new(cluster, &storage.clickhouse_table_name)
+                    .with_compression(self.insert_compression);
+                let writer =
+                    ClickhouseWriter::new(client, self.max_batch_size, self.max_batch_time)
+                        .with_retry_policy(self.retry_policy)
+                        .with_encoder(storage.encoder.clone());
+                let writer = match self.circuit_breaker {
+                    Some(policy) => writer.with_circuit_breaker(CircuitBreaker::new(policy)),
+                    None => writer,
+                };
+                match &self.runtime_config {
+                    Some(runtime_config) => writer.with_runtime_config(runtime_config.clone()),
+                    None => writer,
+                }
+            };
+            let writer: Box<dyn ProcessingStrategy<BytesInsertBatch>> =
+                match (&self.shadow, &storage.slicing) {
+                    (Some((producer, topic)), _) => Box::new(ProduceRows::new(
+                        producer.clone(),
+                        TopicOrPartition::Topic(topic.clone()),
+                        &storage.name,
+                    )),
+                    (None, None) => Box::new(writer(&storage.clickhouse_config)),
+                    (None, Some(slicing)) => {
+                        let writers = slicing
+                            .clusters
+                            .iter()
+                            .map(|(slice_id, cluster)| {
+                                let writer: Box<dyn ProcessingStrategy<BytesInsertBatch>> =
+                                    Box::new(writer(cluster));
+                                (*slice_id, writer)
+                            })
+                            .collect();
+                        Box::new(
+                            SlicedWriter::new(slicing.clone(), writers)
+                                .with_encoder(storage.encoder.clone()),
+                        )
+                    }
+                };
+            let writer = match &self.replacements {
+                Some((producer, topic)) => Box::new(ProduceReplacements::new(
+                    writer,
+                    producer.clone(),
+                    TopicOrPartition::Topic(topic.clone()),
+                )),
+                None => writer,
+            };
+            match &storage.processor {
+                StorageProcessor::Rust(processor) => Box::new(RustProcessor::new(
+                    processor.clone(),
+                    writer,
+                    self.processor_concurrency,
+                )),
+                StorageProcessor::Python(processor) => {
+                    let processor = Python::with_gil(|py| processor.clone_ref(py));
+                    Box::new(PythonTransformStep::new(processor, writer))
+                }
+            }
+        }
     }
 
     impl ProcessingStrategyFactory<KafkaPayload> for ConsumerStrategyFactory {
         fn create(&self) -> Box<dyn ProcessingStrategy<KafkaPayload>> {
-            let transform_step = PythonTransformStep::new(
-                self.processor_config.clone(),
-                ClickhouseWriterStep::new(commit_offsets::new(Duration::from_secs(1))),
-            )
-            .unwrap();
-            Box::new(transform_step)
+            let mut storages: Vec<_> = self
+                .storages
+                .iter()
+                .map(|storage| self.create_storage(storage))
+                .collect();
+            // Like the Python multistorage consumer, the pipeline of every
+            // storage processes and batches the messages on its own.
+            let transform_step = match storages.len() {
+                1 => storages.remove(0),
+                _ => Box::new(Tee::new(storages)),
+            };
+            let transform_step = match &self.runtime_config {
+                Some(runtime_config) => Box::new(ThrottleCommits::new(
+                    transform_step,
+                    Box::new(RuntimeCommitPolicy(runtime_config.clone())),
+                )),
+                None => transform_step,
+            };
+            let strategy: Box<dyn ProcessingStrategy<KafkaPayload>> = match &self.health_check_file
+            {
+                Some(path) => Box::new(Healthcheck::new(path, transform_step)),
+                None => transform_step,
+            };
+            let strategy = match self.max_bytes_per_second {
+                Some(limit) => {
+                    let size = |payload: &KafkaPayload| {
+                        payload
+                            .payload
+                            .as_ref()
+                            .map_or(0, |payload| payload.len() as u64)
+                    };
+                    Box::new(RateLimit::new(limit, strategy).with_cost(Arc::new(size)))
+                }
+                None => strategy,
+            };
+            let strategy = match self.max_messages_per_second {
+                Some(limit) => Box::new(RateLimit::new(limit, strategy)),
+                None => strategy,
+            };
+            let strategy = match self.max_message_age {
+                Some(max_age) => Box::new(DropStale::new(max_age, strategy)),
+                None => strategy,
+            };
+            let strategy = Box::new(ValidateSchema::new(
+                &self.logical_topic_name,
+                self.enforce_schema,
+                strategy,
+            ));
+            Box::new(SentryContext::new(Box::new(PropagateTraceContext::new(
+                strategy,
+            ))))
         }
     }
 
-    env_logger::init();
-    let consumer_config = config::ConsumerConfig::load_from_str(consumer_config_raw).unwrap();
-    // TODO: Support multiple storages
-    assert_eq!(consumer_config.storages.len(), 1);
-    assert!(consumer_config.replacements_topic.is_none());
-    assert!(consumer_config.commit_log_topic.is_none());
+    // Errors are reported to Sentry, lower levels are kept as breadcrumbs.
+    // The logger of an earlier start in the same process is kept.
+    let env_logger = env_logger::Builder::from_default_env().build();
+    let max_level = env_logger.filter();
+    if log::set_boxed_logger(Box::new(
+        sentry::integrations::log::SentryLogger::with_dest(env_logger),
+    ))
+    .is_ok()
+    {
+        log::set_max_level(max_level);
+    }
+
+    #[cfg(feature = "otlp")]
+    let otlp_runtime =
+        tokio::runtime::Runtime::new().context("Failed to build the OpenTelemetry runtime")?;
+    #[cfg(feature = "otlp")]
+    let _otlp_runtime_guard = otlp_runtime.enter();
+    #[cfg(feature = "otlp")]
+    crate::otlp::init("snuba-rust-consumer").context("Failed to set up OpenTelemetry")?;
+
+    // Panics are captured by the default integrations. The guard flushes
+    // pending events when the consumer exits.
+    let _sentry = consumer_config.env.sentry_dsn.as_deref().map(|dsn| {
+        sentry::init((
+            dsn,
+            sentry::ClientOptions {
+                release: std::env::var("SNUBA_RELEASE").ok().map(Into::into),
+                ..Default::default()
+            },
+        ))
+    });
+
     let first_storage = &consumer_config.storages[0];
+    // Same as the Python multistorage consumer.
+    let storage_tag = match consumer_config.storages.len() {
+        1 => first_storage.name.clone(),
+        _ => format!("{}_m", first_storage.name),
+    };
 
-    log::info!("Starting consumer for {:?}", first_storage.name,);
+    let storage_names: Vec<_> = consumer_config.storages.iter().map(|s| &s.name).collect();
+    log::info!("Starting consumer for {:?}", storage_names);
 
-    let broker_config: HashMap<_, _> = consumer_config
+    sentry::configure_scope(|scope| {
+        scope.set_tag("consumer_group", consumer_group);
+        scope.set_tag("storage", &storage_tag);
+    });
+
+    // Same prefix and tags as the Python consumers.
+    let metrics_tags = HashMap::from([
+        ("consumer_group".to_owned(), consumer_group.to_owned()),
+        ("storage".to_owned(), storage_tag),
+    ]);
+    let mut prometheus = None;
+    if consumer_config.prometheus_metrics {
+        prometheus = Some(metrics::init_prometheus("snuba.consumer", metrics_tags));
+    } else if let (Some(host), Some(port)) = (
+        consumer_config.env.dogstatsd_host.as_deref(),
+        consumer_config.env.dogstatsd_port,
+    ) {
+        metrics::init_with_tags("snuba.consumer", (host, port), metrics_tags);
+    }
+
+    let broker_config = |topic: &config::TopicConfig| -> HashMap<_, _> {
+        topic
+            .broker_config
+            .iter()
+            .filter_map(|(k, v)| {
+                let v = v.as_ref()?;
+                if v.is_empty() {
+                    return None;
+                }
+                Some((k.to_owned(), v.to_owned()))
+            })
+            .collect()
+    };
+
+    let mut config = KafkaConfig::new_consumer_config(
+        vec![],
+        consumer_group.to_owned(),
+        auto_offset_reset
+            .parse()
+            .context("Invalid auto offset reset")?,
+        false,
+        Some(broker_config(&consumer_config.raw_topic)),
+    )?;
+    if let Some(group_instance_id) = group_instance_id {
+        config = config.with_static_membership(group_instance_id.to_owned(), None);
+    }
+    // Unless the broker config of the topic sets another interval.
+    if !consumer_config
         .raw_topic
         .broker_config
+        .contains_key("statistics.interval.ms")
+    {
+        config = config.with_statistics_interval(KAFKA_STATISTICS_INTERVAL);
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to build the runtime of the schema check")?;
+    let mut storages = Vec::new();
+    for storage in &consumer_config.storages {
+        let clusters: Vec<_> = match &storage.slicing {
+            Some(slicing) => slicing.clusters.values().collect(),
+            None => vec![&storage.clickhouse_cluster],
+        };
+        for cluster in clusters {
+            if consumer_config.skip_schema_check {
+                break;
+            }
+            let client = ClickhouseClient::new(cluster, &storage.clickhouse_table_name);
+            if let Err(error) = runtime.block_on(check_schema(&client, &storage.columns)) {
+                bail!(
+                    "Schema check of {} on {} failed: {:#}",
+                    storage.clickhouse_table_name,
+                    cluster.host,
+                    error
+                );
+            }
+        }
+        let rust_processor = match consumer_config.use_rust_processor {
+            true => get_processor(&storage.name, consumer_config.raw_topic.payload_format)
+                .map(Arc::from),
+            false => None,
+        };
+        if consumer_config.use_rust_processor && rust_processor.is_none() {
+            log::warn!(
+                "{} has no Rust processor, using the Python processor",
+                storage.name
+            );
+        }
+        let processor = match rust_processor {
+            Some(processor) => StorageProcessor::Rust(processor),
+            None => {
+                StorageProcessor::Python(load_processor(&storage.message_processor).with_context(
+                    || format!("Failed to load the Python processor of {}", storage.name),
+                )?)
+            }
+        };
+        storages.push(StorageStrategyConfig {
+            name: storage.name.clone(),
+            processor,
+            clickhouse_config: storage.clickhouse_cluster.clone(),
+            clickhouse_table_name: storage.clickhouse_table_name.clone(),
+            encoder: build_encoder(storage.insert_format, &storage.columns)?,
+            slicing: storage.slicing.clone(),
+        });
+    }
+
+    let mut retry_policy = INSERT_RETRY_POLICY;
+    if let Some(max_attempts) = consumer_config.max_insert_attempts {
+        retry_policy.max_attempts = max_attempts;
+    }
+    let circuit_breaker = consumer_config
+        .circuit_breaker_failures
+        .map(|failure_threshold| {
+            let mut policy = CircuitBreakerPolicy {
+                failure_threshold,
+                ..Default::default()
+            };
+            if let Some(cool_down_ms) = consumer_config.circuit_breaker_cool_down_ms {
+                policy.cool_down = Duration::from_millis(cool_down_ms);
+            }
+            policy
+        });
+
+    let shadow = consumer_config.shadow_topic.as_ref().map(|topic| {
+        log::info!(
+            "Shadow mode, producing the rows to {} instead of writing them",
+            topic.physical_topic_name
+        );
+        let config = KafkaConfig::new_producer_config(vec![], Some(broker_config(topic)));
+        let topic = Topic {
+            name: topic.physical_topic_name.clone(),
+        };
+        (Arc::new(KafkaProducer::new(config)), topic)
+    });
+    // The subscriptions scheduler follows the consumer through the commit log.
+    // In shadow mode, the commit log and the replacements are left to the
+    // Python consumer.
+    let commit_log = consumer_config
+        .commit_log_topic
+        .as_ref()
+        .filter(|_| shadow.is_none())
+        .map(|topic| {
+            let config = KafkaConfig::new_producer_config(vec![], Some(broker_config(topic)));
+            let topic = Topic {
+                name: topic.physical_topic_name.clone(),
+            };
+            (Arc::new(KafkaProducer::new(config)), topic)
+        });
+    let replacements = consumer_config
+        .replacements_topic
+        .as_ref()
+        .filter(|_| shadow.is_none())
+        .map(|topic| {
+            let config = KafkaConfig::new_producer_config(vec![], Some(broker_config(topic)));
+            let topic = Topic {
+                name: topic.physical_topic_name.clone(),
+            };
+            (Arc::new(KafkaProducer::new(config)), topic)
+        });
+    // In shadow mode, the invalid messages are dead lettered by the Python
+    // consumer.
+    let dlq = consumer_config
+        .dlq_topic
+        .as_ref()
+        .filter(|_| shadow.is_none())
+        .map(|topic| {
+            let config = KafkaConfig::new_producer_config(vec![], Some(broker_config(topic)));
+            let topic = Topic {
+                name: topic.physical_topic_name.clone(),
+            };
+            (Arc::new(KafkaProducer::new(config)), topic)
+        });
+    let dlq_limit = DlqLimit {
+        max_invalid_ratio: consumer_config.dlq_max_invalid_ratio,
+        max_consecutive_count: consumer_config.dlq_max_consecutive_count,
+        ..Default::default()
+    };
+    let producers: Vec<_> = commit_log
         .iter()
-        .filter_map(|(k, v)| {
-            let v = v.as_ref()?;
-            if v.is_empty() {
-                return None;
+        .chain(&replacements)
+        .chain(&shadow)
+        .chain(&dlq)
+        .map(|(producer, _)| producer.clone())
+        .collect();
+    let commit_log = commit_log.map(|(producer, topic)| {
+        Arc::new(CommitLog::new(
+            producer,
+            TopicOrPartition::Topic(topic),
+            consumer_group,
+        ))
+    });
+
+    let factory = Arc::new(ConsumerStrategyFactory {
+        storages,
+        insert_compression: consumer_config.insert_compression,
+        retry_policy,
+        circuit_breaker,
+        max_batch_size: consumer_config.max_batch_size,
+        max_batch_time: Duration::from_millis(consumer_config.max_batch_time_ms),
+        health_check_file: health_check_file.map(str::to_owned),
+        max_messages_per_second: consumer_config.max_messages_per_second,
+        max_bytes_per_second: consumer_config.max_bytes_per_second,
+        max_message_age: consumer_config
+            .max_message_age_secs
+            .map(Duration::from_secs),
+        logical_topic_name: consumer_config.raw_topic.logical_topic_name.clone(),
+        enforce_schema: consumer_config.enforce_schema,
+        replacements,
+        shadow,
+        runtime_config: consumer_config
+            .runtime_config_file
+            .as_ref()
+            .map(|path| RuntimeConfigHandle::poll_file(path.into())),
+        processor_concurrency: consumer_config.processor_concurrency.unwrap_or(1).max(1),
+    });
+
+    // Every restart gets a new consumer and new strategies, the producers
+    // are shared.
+    let kafka_backend = consumer_config.kafka_backend;
+    let raw_topic = Topic {
+        name: consumer_config.raw_topic.physical_topic_name.to_owned(),
+    };
+    let build = move || {
+        let consumer: Box<dyn Consumer<KafkaPayload>> = match kafka_backend {
+            config::KafkaBackend::Base => Box::new(KafkaConsumer::new(config.clone())),
+            config::KafkaBackend::Stream => {
+                Box::new(KafkaStreamConsumer::new_stream(config.clone()))
             }
-            Some((k.to_owned(), v.to_owned()))
+        };
+        let runtime_config = factory.runtime_config.clone();
+        let factory = Box::new(factory.clone());
+        let mut processor = match &dlq {
+            Some((producer, topic)) => {
+                let dlq_producer = KafkaDlqProducer::new(producer.clone(), topic.clone());
+                let policy = DlqPolicy::new(Box::new(dlq_producer), dlq_limit);
+                StreamProcessor::new_with_dlq_policy(consumer, factory, policy)
+            }
+            None => StreamProcessor::new(consumer, factory),
+        };
+        if let Some(runtime_config) = runtime_config {
+            processor.set_max_in_flight_messages_with(move || {
+                runtime_config.get().max_in_flight_messages
+            });
+        }
+        if let Some(commit_log) = commit_log.clone() {
+            processor.set_on_commit(move |positions| commit_log.produce(positions));
+        }
+        processor.subscribe(raw_topic.clone());
+        processor
+    };
+    // What the crashed processor produced is delivered before the next one
+    // starts over from the committed offsets.
+    let restart_producers = producers.clone();
+    let mut supervisor = Supervisor::new(build)
+        .with_policy(RestartPolicy {
+            max_restarts: consumer_config.max_restarts,
+            ..Default::default()
         })
-        .collect();
+        .with_before_restart(move || {
+            for producer in &restart_producers {
+                producer.flush();
+            }
+        });
 
-    let config = KafkaConfig::new_consumer_config(
-        vec![],
-        consumer_group.to_owned(),
-        auto_offset_reset.to_owned(),
-        false,
-        Some(broker_config),
-    );
+    if let Some(port) = consumer_config.admin_port {
+        admin::serve(port, supervisor.state(), prometheus)
+            .context("Failed to start the admin server")?;
+    }
 
-    let processor_config = first_storage.message_processor.clone();
+    let result = supervisor
+        .run()
+        .map_err(|error| anyhow::anyhow!("The consumer stopped: {:?}", error));
 
-    let consumer = Box::new(KafkaConsumer::new(config));
-    let mut processor = StreamProcessor::new(
-        consumer,
-        Box::new(ConsumerStrategyFactory { processor_config }),
-    );
+    for producer in producers {
+        producer.flush();
+    }
 
-    processor.subscribe(Topic {
-        name: consumer_config.raw_topic.physical_topic_name.to_owned(),
-    });
+    #[cfg(feature = "otlp")]
+    crate::otlp::shutdown();
 
-    processor.run().unwrap();
+    result
 }
@@ -0,0 +1,141 @@
+use std::io::Write;
+
+use anyhow::bail;
+use serde_json::{Map, Value};
+
+use crate::config::ColumnConfig;
+use crate::encoders::RowsEncoder;
+
+/// Inserts the rows as CSV with a header line of the column names. It is
+/// slower than the other formats but easy to read, which makes it useful
+/// to debug inserts.
+///
+/// Missing values are written as ``\N``, ClickHouse inserts the default
+/// value of columns that are not nullable instead.
+pub struct CsvWithNamesEncoder {
+    names: Vec<String>,
+}
+
+impl CsvWithNamesEncoder {
+    pub fn new(columns: &[ColumnConfig]) -> Self {
+        CsvWithNamesEncoder {
+            names: columns.iter().map(|column| column.name.clone()).collect(),
+        }
+    }
+}
+
+fn write_quoted(value: &str, out: &mut Vec<u8>) {
+    out.push(b'"');
+    out.extend(value.replace('"', "\"\"").as_bytes());
+    out.push(b'"');
+}
+
+// Arrays are written as ClickHouse literals, such as ``['a','b']``.
+fn write_literal(value: &Value, out: &mut Vec<u8>) -> Result<(), anyhow::Error> {
+    match value {
+        Value::Null => out.extend(b"NULL"),
+        Value::Bool(value) => out.push(if *value { b'1' } else { b'0' }),
+        Value::Number(value) => write!(out, "{}", value)?,
+        Value::String(value) => {
+            out.push(b'\'');
+            out.extend(value.replace('\\', "\\\\").replace('\'', "\\'").as_bytes());
+            out.push(b'\'');
+        }
+        Value::Array(values) => {
+            out.push(b'[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                write_literal(value, out)?;
+            }
+            out.push(b']');
+        }
+        Value::Object(_) => bail!("Objects are not supported"),
+    }
+    Ok(())
+}
+
+fn write_value(value: Option<&Value>, out: &mut Vec<u8>) -> Result<(), anyhow::Error> {
+    match value {
+        None | Some(Value::Null) => out.extend(b"\\N"),
+        Some(Value::Bool(value)) => out.push(if *value { b'1' } else { b'0' }),
+        Some(Value::Number(value)) => write!(out, "{}", value)?,
+        Some(Value::String(value)) => write_quoted(value, out),
+        Some(value @ Value::Array(_)) => {
+            let mut literal = Vec::new();
+            write_literal(value, &mut literal)?;
+            write_quoted(&String::from_utf8_lossy(&literal), out);
+        }
+        Some(Value::Object(_)) => bail!("Objects are not supported"),
+    }
+    Ok(())
+}
+
+impl RowsEncoder for CsvWithNamesEncoder {
+    fn format(&self) -> &'static str {
+        "CSVWithNames"
+    }
+
+    fn columns(&self) -> &[String] {
+        &self.names
+    }
+
+    fn write_header(&self, out: &mut Vec<u8>) {
+        for (i, name) in self.names.iter().enumerate() {
+            if i > 0 {
+                out.push(b',');
+            }
+            write_quoted(name, out);
+        }
+        out.push(b'\n');
+    }
+
+    fn encode(&self, row: &[u8], out: &mut Vec<u8>) -> Result<(), anyhow::Error> {
+        let row: Map<String, Value> = serde_json::from_slice(row)?;
+        let length = out.len();
+        for (i, name) in self.names.iter().enumerate() {
+            if i > 0 {
+                out.push(b',');
+            }
+            if let Err(error) = write_value(row.get(name), out) {
+                out.truncate(length);
+                return Err(error.context(format!("Invalid value of column {}", name)));
+            }
+        }
+        out.push(b'\n');
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CsvWithNamesEncoder;
+    use crate::config::ColumnConfig;
+    use crate::encoders::RowsEncoder;
+
+    #[test]
+    fn test_encode() {
+        let columns: Vec<_> = ["project_id", "message", "tags.key", "release"]
+            .iter()
+            .map(|name| ColumnConfig {
+                name: name.to_string(),
+                r#type: "String".to_string(),
+            })
+            .collect();
+        let encoder = CsvWithNamesEncoder::new(&columns);
+
+        let mut out = Vec::new();
+        encoder.write_header(&mut out);
+        let row = br#"{"project_id": 1, "message": "say \"hi\"", "tags.key": ["a", "it's"]}"#;
+        encoder.encode(row, &mut out).unwrap();
+        assert!(encoder.encode(br#"{"message": {}}"#, &mut out).is_err());
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                "\"project_id\",\"message\",\"tags.key\",\"release\"\n",
+                "1,\"say \"\"hi\"\"\",\"['a','it\\'s']\",\\N\n",
+            )
+        );
+    }
+}
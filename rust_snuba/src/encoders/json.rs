@@ -0,0 +1,17 @@
+use crate::encoders::RowsEncoder;
+
+/// Inserts the rows as they are returned by the message processors, one
+/// JSON object per line.
+pub struct JsonEachRowEncoder;
+
+impl RowsEncoder for JsonEachRowEncoder {
+    fn format(&self) -> &'static str {
+        "JSONEachRow"
+    }
+
+    fn encode(&self, row: &[u8], out: &mut Vec<u8>) -> Result<(), anyhow::Error> {
+        out.extend(row);
+        out.push(b'\n');
+        Ok(())
+    }
+}
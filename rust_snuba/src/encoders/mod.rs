@@ -0,0 +1,41 @@
+pub mod csv;
+pub mod json;
+pub mod row_binary;
+
+use std::sync::Arc;
+
+use crate::config::{ColumnConfig, InsertFormat};
+
+/// Encodes the JSON rows returned by the message processors into the body
+/// of an insert in the format of the encoder. The batching of the rows is
+/// up to the ``ClickhouseWriter``.
+pub trait RowsEncoder: Send + Sync {
+    /// The name of the format in the insert query.
+    fn format(&self) -> &'static str;
+
+    /// The columns listed in the insert query, in the order their values
+    /// are encoded. Formats that name the columns of every row leave them
+    /// out.
+    fn columns(&self) -> &[String] {
+        &[]
+    }
+
+    /// Written once at the start of the body, before the rows.
+    fn write_header(&self, _out: &mut Vec<u8>) {}
+
+    /// Appends a row to ``out``. Nothing is appended if the row cannot be
+    /// encoded.
+    fn encode(&self, row: &[u8], out: &mut Vec<u8>) -> Result<(), anyhow::Error>;
+}
+
+pub fn build_encoder(
+    format: InsertFormat,
+    columns: &[ColumnConfig],
+) -> Result<Arc<dyn RowsEncoder>, anyhow::Error> {
+    let encoder: Arc<dyn RowsEncoder> = match format {
+        InsertFormat::JsonEachRow => Arc::new(json::JsonEachRowEncoder),
+        InsertFormat::RowBinary => Arc::new(row_binary::RowBinaryEncoder::new(columns)?),
+        InsertFormat::CsvWithNames => Arc::new(csv::CsvWithNamesEncoder::new(columns)),
+    };
+    Ok(encoder)
+}
@@ -0,0 +1,476 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use serde_json::{Map, Value};
+
+use crate::config::ColumnConfig;
+use crate::encoders::RowsEncoder;
+
+/// The type of a column as ClickHouse describes it, for example
+/// ``Array(Nullable(String))``. Only the types storages are defined with are
+/// supported.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnType {
+    UInt8,
+    UInt16,
+    UInt32,
+    UInt64,
+    Int8,
+    Int16,
+    Int32,
+    Int64,
+    Float32,
+    Float64,
+    Bool,
+    String,
+    FixedString(usize),
+    Uuid,
+    IPv4,
+    IPv6,
+    Date,
+    DateTime,
+    DateTime64(u32),
+    Enum8(HashMap<String, i8>),
+    Enum16(HashMap<String, i16>),
+    Nullable(Box<ColumnType>),
+    Array(Box<ColumnType>),
+    LowCardinality(Box<ColumnType>),
+}
+
+impl FromStr for ColumnType {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let name = name.trim();
+        let (base, args) = match name.split_once('(') {
+            Some((base, args)) => {
+                let args = args
+                    .strip_suffix(')')
+                    .ok_or_else(|| anyhow!("Unbalanced parentheses in {}", name))?;
+                (base.trim(), Some(args))
+            }
+            None => (name, None),
+        };
+        let inner = || -> Result<Box<ColumnType>, anyhow::Error> {
+            Ok(Box::new(args.unwrap_or_default().parse()?))
+        };
+
+        let column_type = match (base, args) {
+            ("UInt8", None) => ColumnType::UInt8,
+            ("UInt16", None) => ColumnType::UInt16,
+            ("UInt32", None) => ColumnType::UInt32,
+            ("UInt64", None) => ColumnType::UInt64,
+            ("Int8", None) => ColumnType::Int8,
+            ("Int16", None) => ColumnType::Int16,
+            ("Int32", None) => ColumnType::Int32,
+            ("Int64", None) => ColumnType::Int64,
+            ("Float32", None) => ColumnType::Float32,
+            ("Float64", None) => ColumnType::Float64,
+            ("Bool", None) => ColumnType::Bool,
+            ("String", None) => ColumnType::String,
+            ("FixedString", Some(length)) => ColumnType::FixedString(length.trim().parse()?),
+            ("UUID", None) => ColumnType::Uuid,
+            ("IPv4", None) => ColumnType::IPv4,
+            ("IPv6", None) => ColumnType::IPv6,
+            ("Date", None) => ColumnType::Date,
+            // The time zone does not change the encoding.
+            ("DateTime", _) => ColumnType::DateTime,
+            ("DateTime64", Some(args)) => {
+                let precision = args.split(',').next().unwrap_or_default();
+                ColumnType::DateTime64(precision.trim().parse()?)
+            }
+            ("Enum" | "Enum8" | "Enum16", Some(args)) => {
+                let values = parse_enum(args)?;
+                let fits_enum8 = values.values().all(|value| i8::try_from(*value).is_ok());
+                // Enum without a size is an Enum8 if all the values fit.
+                if base == "Enum8" || (base == "Enum" && fits_enum8) {
+                    let values = values
+                        .into_iter()
+                        .map(|(name, value)| Ok((name, i8::try_from(value)?)))
+                        .collect::<Result<_, anyhow::Error>>()?;
+                    ColumnType::Enum8(values)
+                } else {
+                    ColumnType::Enum16(values)
+                }
+            }
+            ("Nullable", Some(_)) => ColumnType::Nullable(inner()?),
+            ("Array", Some(_)) => ColumnType::Array(inner()?),
+            ("LowCardinality", Some(_)) => ColumnType::LowCardinality(inner()?),
+            _ => bail!("Unsupported column type {}", name),
+        };
+        Ok(column_type)
+    }
+}
+
+// Parses the values of ``Enum('a' = 1, 'b' = 2)``.
+fn parse_enum(args: &str) -> Result<HashMap<String, i16>, anyhow::Error> {
+    let mut values = HashMap::new();
+    let mut chars = args.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+        match chars.next() {
+            None => break,
+            Some('\'') => {}
+            Some(c) => bail!("Unexpected {:?} in enum {}", c, args),
+        }
+        let mut name = String::new();
+        loop {
+            match chars.next() {
+                Some('\\') => name.extend(chars.next()),
+                Some('\'') => break,
+                Some(c) => name.push(c),
+                None => bail!("Unterminated name in enum {}", args),
+            }
+        }
+        let value: String = chars
+            .by_ref()
+            .skip_while(|c| c.is_whitespace() || *c == '=')
+            .take_while(|c| *c != ',')
+            .collect();
+        values.insert(name, value.trim().parse()?);
+    }
+    Ok(values)
+}
+
+fn write_varint(mut value: usize, out: &mut Vec<u8>) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn parse_datetime(value: &str) -> Result<DateTime<Utc>, anyhow::Error> {
+    // The format of ``JSONRowEncoder``, and ISO 8601 as sent by some
+    // processors.
+    [
+        "%Y-%m-%d %H:%M:%S%.f",
+        "%Y-%m-%dT%H:%M:%S%.f",
+        "%Y-%m-%dT%H:%M:%S%.fZ",
+    ]
+    .iter()
+    .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+    .map(|datetime| datetime.and_utc())
+    .ok_or_else(|| anyhow!("Invalid datetime {:?}", value))
+}
+
+impl ColumnType {
+    fn write(&self, value: &Value, out: &mut Vec<u8>) -> Result<(), anyhow::Error> {
+        if value.is_null() {
+            return self.write_default(out);
+        }
+        let invalid = || anyhow!("Invalid value {} for {:?}", value, self);
+        let as_u64 = || -> Result<u64, anyhow::Error> {
+            match value {
+                Value::Bool(value) => Ok(*value as u64),
+                Value::String(value) => Ok(value.parse()?),
+                _ => value.as_u64().ok_or_else(invalid),
+            }
+        };
+        let as_i64 = || -> Result<i64, anyhow::Error> {
+            match value {
+                Value::Bool(value) => Ok(*value as i64),
+                Value::String(value) => Ok(value.parse()?),
+                _ => value.as_i64().ok_or_else(invalid),
+            }
+        };
+        let as_f64 = || -> Result<f64, anyhow::Error> {
+            match value {
+                Value::String(value) => Ok(value.parse()?),
+                _ => value.as_f64().ok_or_else(invalid),
+            }
+        };
+        let as_str = || value.as_str().ok_or_else(invalid);
+
+        match self {
+            ColumnType::UInt8 => out.push(u8::try_from(as_u64()?)?),
+            ColumnType::UInt16 => out.extend(u16::try_from(as_u64()?)?.to_le_bytes()),
+            ColumnType::UInt32 => out.extend(u32::try_from(as_u64()?)?.to_le_bytes()),
+            ColumnType::UInt64 => out.extend(as_u64()?.to_le_bytes()),
+            ColumnType::Int8 => out.extend(i8::try_from(as_i64()?)?.to_le_bytes()),
+            ColumnType::Int16 => out.extend(i16::try_from(as_i64()?)?.to_le_bytes()),
+            ColumnType::Int32 => out.extend(i32::try_from(as_i64()?)?.to_le_bytes()),
+            ColumnType::Int64 => out.extend(as_i64()?.to_le_bytes()),
+            ColumnType::Float32 => out.extend((as_f64()? as f32).to_le_bytes()),
+            ColumnType::Float64 => out.extend(as_f64()?.to_le_bytes()),
+            ColumnType::Bool => out.push(value.as_bool().ok_or_else(invalid)? as u8),
+            ColumnType::String => {
+                let value = as_str()?;
+                write_varint(value.len(), out);
+                out.extend(value.as_bytes());
+            }
+            ColumnType::FixedString(length) => {
+                let value = as_str()?.as_bytes();
+                if value.len() > *length {
+                    return Err(invalid());
+                }
+                out.extend(value);
+                out.resize(out.len() + length - value.len(), 0);
+            }
+            ColumnType::Uuid => {
+                let hex: String = as_str()?.chars().filter(|c| *c != '-').collect();
+                if hex.len() != 32 {
+                    return Err(invalid());
+                }
+                // Two little endian halves
+                let uuid = u128::from_str_radix(&hex, 16)?;
+                out.extend(((uuid >> 64) as u64).to_le_bytes());
+                out.extend((uuid as u64).to_le_bytes());
+            }
+            ColumnType::IPv4 => {
+                let address = match value {
+                    Value::String(value) => u32::from(value.parse::<Ipv4Addr>()?),
+                    _ => u32::try_from(as_u64()?)?,
+                };
+                out.extend(address.to_le_bytes());
+            }
+            ColumnType::IPv6 => {
+                let address = match as_str()?.parse::<IpAddr>()? {
+                    IpAddr::V4(address) => address.to_ipv6_mapped(),
+                    IpAddr::V6(address) => address,
+                };
+                out.extend(address.octets());
+            }
+            ColumnType::Date => {
+                let days = match value {
+                    Value::String(value) => {
+                        let date = value.get(..10).ok_or_else(invalid)?;
+                        let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")?;
+                        (date - NaiveDate::default()).num_days()
+                    }
+                    _ => as_i64()?,
+                };
+                out.extend(u16::try_from(days)?.to_le_bytes());
+            }
+            ColumnType::DateTime => {
+                let timestamp = match value {
+                    Value::String(value) => parse_datetime(value)?.timestamp(),
+                    _ => as_i64()?,
+                };
+                out.extend(u32::try_from(timestamp)?.to_le_bytes());
+            }
+            ColumnType::DateTime64(precision) => {
+                let scale = 10i64.pow(*precision);
+                let ticks = match value {
+                    Value::String(value) => {
+                        let datetime = parse_datetime(value)?;
+                        let nanos = datetime.timestamp_subsec_nanos() as i64;
+                        datetime.timestamp() * scale + nanos * scale / 1_000_000_000
+                    }
+                    _ => (as_f64()? * scale as f64) as i64,
+                };
+                out.extend(ticks.to_le_bytes());
+            }
+            ColumnType::Enum8(values) => {
+                out.extend(values.get(as_str()?).ok_or_else(invalid)?.to_le_bytes())
+            }
+            ColumnType::Enum16(values) => {
+                out.extend(values.get(as_str()?).ok_or_else(invalid)?.to_le_bytes())
+            }
+            ColumnType::Nullable(inner) => {
+                out.push(0);
+                inner.write(value, out)?;
+            }
+            ColumnType::Array(inner) => {
+                let values = value.as_array().ok_or_else(invalid)?;
+                write_varint(values.len(), out);
+                for value in values {
+                    inner.write(value, out)?;
+                }
+            }
+            ColumnType::LowCardinality(inner) => inner.write(value, out)?,
+        }
+        Ok(())
+    }
+
+    /// Writes the value ClickHouse fills in for missing and null values of
+    /// columns that are not nullable.
+    fn write_default(&self, out: &mut Vec<u8>) -> Result<(), anyhow::Error> {
+        let zeros = match self {
+            ColumnType::UInt8 | ColumnType::Int8 | ColumnType::Bool => 1,
+            ColumnType::UInt16 | ColumnType::Int16 | ColumnType::Date => 2,
+            ColumnType::UInt32
+            | ColumnType::Int32
+            | ColumnType::Float32
+            | ColumnType::IPv4
+            | ColumnType::DateTime => 4,
+            ColumnType::UInt64
+            | ColumnType::Int64
+            | ColumnType::Float64
+            | ColumnType::DateTime64(_) => 8,
+            ColumnType::Uuid | ColumnType::IPv6 => 16,
+            ColumnType::FixedString(length) => *length,
+            // Empty strings and arrays have a length of 0.
+            ColumnType::String | ColumnType::Array(_) => 1,
+            ColumnType::Nullable(_) => {
+                out.push(1);
+                return Ok(());
+            }
+            ColumnType::Enum8(values) => {
+                let value = values.values().min().context("Empty enum")?;
+                out.extend(value.to_le_bytes());
+                return Ok(());
+            }
+            ColumnType::Enum16(values) => {
+                let value = values.values().min().context("Empty enum")?;
+                out.extend(value.to_le_bytes());
+                return Ok(());
+            }
+            ColumnType::LowCardinality(inner) => return inner.write_default(out),
+        };
+        out.resize(out.len() + zeros, 0);
+        Ok(())
+    }
+}
+
+/// Encodes the JSON rows returned by the message processors in the
+/// ``RowBinary`` format, which is cheaper for ClickHouse to parse and
+/// smaller on the wire than ``JSONEachRow``.
+///
+/// ``RowBinary`` has no way to leave a value out, so every column of the
+/// storage is written. Missing and null values are replaced by the default
+/// value of the type, or null for nullable columns, like ClickHouse does
+/// for ``JSONEachRow``. Columns with a ``DEFAULT`` expression should not be
+/// listed.
+pub struct RowBinaryEncoder {
+    names: Vec<String>,
+    types: Vec<ColumnType>,
+}
+
+impl RowBinaryEncoder {
+    pub fn new(columns: &[ColumnConfig]) -> Result<Self, anyhow::Error> {
+        let types = columns
+            .iter()
+            .map(|column| {
+                column
+                    .r#type
+                    .parse()
+                    .with_context(|| format!("Invalid type of column {}", column.name))
+            })
+            .collect::<Result<_, anyhow::Error>>()?;
+        Ok(RowBinaryEncoder {
+            names: columns.iter().map(|column| column.name.clone()).collect(),
+            types,
+        })
+    }
+}
+
+impl RowsEncoder for RowBinaryEncoder {
+    fn format(&self) -> &'static str {
+        "RowBinary"
+    }
+
+    fn columns(&self) -> &[String] {
+        &self.names
+    }
+
+    fn encode(&self, row: &[u8], out: &mut Vec<u8>) -> Result<(), anyhow::Error> {
+        let row: Map<String, Value> = serde_json::from_slice(row)?;
+        let length = out.len();
+        for (name, column_type) in self.names.iter().zip(&self.types) {
+            let result = match row.get(name) {
+                Some(value) => column_type.write(value, out),
+                None => column_type.write_default(out),
+            };
+            if let Err(error) = result {
+                out.truncate(length);
+                return Err(error.context(format!("Invalid value of column {}", name)));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ColumnType, RowBinaryEncoder};
+    use crate::config::ColumnConfig;
+    use crate::encoders::RowsEncoder;
+    use std::collections::HashMap;
+
+    fn encoder(columns: &[(&str, &str)]) -> RowBinaryEncoder {
+        let columns: Vec<_> = columns
+            .iter()
+            .map(|(name, r#type)| ColumnConfig {
+                name: name.to_string(),
+                r#type: r#type.to_string(),
+            })
+            .collect();
+        RowBinaryEncoder::new(&columns).unwrap()
+    }
+
+    #[test]
+    fn test_parse_column_type() {
+        assert_eq!(
+            "Array(Nullable(String))".parse::<ColumnType>().unwrap(),
+            ColumnType::Array(Box::new(ColumnType::Nullable(Box::new(ColumnType::String))))
+        );
+        assert_eq!(
+            "Enum('a' = 1, 'it\\'s' = 2)".parse::<ColumnType>().unwrap(),
+            ColumnType::Enum8(HashMap::from([
+                ("a".to_string(), 1),
+                ("it's".to_string(), 2)
+            ]))
+        );
+        assert_eq!(
+            "Enum('a' = 1000)".parse::<ColumnType>().unwrap(),
+            ColumnType::Enum16(HashMap::from([("a".to_string(), 1000)]))
+        );
+        assert_eq!(
+            "DateTime64(3, 'UTC')".parse::<ColumnType>().unwrap(),
+            ColumnType::DateTime64(3)
+        );
+        assert!("AggregateFunction(uniq, UInt64)"
+            .parse::<ColumnType>()
+            .is_err());
+        assert!("Nullable(String".parse::<ColumnType>().is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let encoder = encoder(&[
+            ("project_id", "UInt64"),
+            ("event_id", "UUID"),
+            ("timestamp", "DateTime"),
+            ("retention_days", "UInt16"),
+            ("message", "String"),
+            ("release", "Nullable(String)"),
+            ("tags.key", "Array(String)"),
+            ("level", "Enum('error' = 1, 'info' = 2)"),
+            ("ip_address_v4", "Nullable(IPv4)"),
+        ]);
+        let mut out = Vec::new();
+        let row = br#"{
+            "project_id": 1,
+            "event_id": "6b5bd2d6-8d3f-4f2f-9f8b-a1c8e5c1e0a0",
+            "timestamp": "2023-01-02 03:04:05",
+            "message": "hi",
+            "tags.key": ["a", "bc"],
+            "level": "info",
+            "ip_address_v4": "127.0.0.1"
+        }"#;
+        encoder.encode(row, &mut out).unwrap();
+
+        let mut expected = vec![1, 0, 0, 0, 0, 0, 0, 0];
+        expected.extend([0x2f, 0x4f, 0x3f, 0x8d, 0xd6, 0xd2, 0x5b, 0x6b]);
+        expected.extend([0xa0, 0xe0, 0xc1, 0xe5, 0xc8, 0xa1, 0x8b, 0x9f]);
+        expected.extend(1672628645u32.to_le_bytes());
+        // Missing values are replaced by defaults
+        expected.extend([0, 0]);
+        expected.extend([2, b'h', b'i']);
+        expected.push(1);
+        expected.extend([2, 1, b'a', 2, b'b', b'c']);
+        expected.push(2);
+        expected.extend([0, 1, 0, 0, 127]);
+        assert_eq!(out, expected);
+
+        // Nothing is written for invalid rows
+        let row = br#"{"project_id": -1}"#;
+        assert!(encoder.encode(row, &mut out).is_err());
+        assert!(encoder.encode(b"not json", &mut out).is_err());
+        assert_eq!(out, expected);
+    }
+}
@@ -1,5 +1,12 @@
+mod admin;
 mod config;
 mod consumer;
+mod encoders;
+#[cfg(feature = "otlp")]
+mod otlp;
+mod processors;
+mod runtime_config;
+mod schema;
 mod strategies;
 mod types;
 
@@ -8,5 +15,6 @@ use pyo3::prelude::*;
 #[pymodule]
 fn rust_snuba(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(consumer::consumer, m)?)?;
+    m.add_function(wrap_pyfunction!(consumer::consumer_from_file, m)?)?;
     Ok(())
 }
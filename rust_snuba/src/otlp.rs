@@ -0,0 +1,38 @@
+use opentelemetry::runtime::Tokio;
+use opentelemetry::sdk::trace::Tracer;
+use opentelemetry::trace::TraceError;
+use tracing_subscriber::layer::SubscriberExt;
+
+/// Exports the tracing spans to the OpenTelemetry collector configured
+/// through the standard ``OTEL_EXPORTER_OTLP_*`` environment variables.
+/// Nothing is exported if ``OTEL_EXPORTER_OTLP_ENDPOINT`` is not set.
+///
+/// Spans are exported in batches from a Tokio runtime, which has to be
+/// entered when this is called and kept alive while the consumer runs.
+pub fn init(service_name: &str) -> Result<Option<Tracer>, TraceError> {
+    if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_none() {
+        return Ok(None);
+    }
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+        .with_trace_config(opentelemetry::sdk::trace::config().with_resource(
+            opentelemetry::sdk::Resource::new(vec![opentelemetry::KeyValue::new(
+                "service.name",
+                service_name.to_owned(),
+            )]),
+        ))
+        .install_batch(Tokio)?;
+
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer.clone()));
+    tracing::subscriber::set_global_default(subscriber)
+        .map_err(|e| TraceError::Other(Box::new(e)))?;
+    Ok(Some(tracer))
+}
+
+/// Exports the spans that were not exported yet.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
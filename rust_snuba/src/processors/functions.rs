@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::Context;
+use chrono::DateTime;
+use rust_arroyo::backends::kafka::types::KafkaPayload;
+use rust_arroyo::codecs::PayloadFormat;
+use rust_arroyo::processing::strategies::InvalidMessage;
+use rust_arroyo::utils::metrics;
+use serde::de::{MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Number;
+use uuid::Uuid;
+
+use crate::processors::utils::DATETIME_FORMAT;
+use crate::processors::{KafkaMessageMetadata, MessageProcessor};
+use crate::types::InsertBatch;
+
+const MAX_DEPTH: u32 = 1024;
+
+/// The Rust port of ``FunctionsMessageProcessor``, which writes a row per
+/// function of the call trees of a profile.
+pub struct FunctionsProcessor {
+    format: PayloadFormat,
+}
+
+impl FunctionsProcessor {
+    pub fn new(format: PayloadFormat) -> Self {
+        FunctionsProcessor { format }
+    }
+}
+
+#[derive(Deserialize)]
+struct Profile {
+    profile_id: Uuid,
+    project_id: u64,
+    transaction_name: String,
+    timestamp: f64,
+    platform: String,
+    #[serde(default)]
+    environment: Option<String>,
+    #[serde(default)]
+    release: Option<String>,
+    os_name: String,
+    os_version: String,
+    retention_days: u16,
+    #[serde(default)]
+    received: Option<f64>,
+    #[serde(deserialize_with = "call_trees_in_order")]
+    call_trees: Vec<Vec<Frame>>,
+}
+
+/// The call trees of every thread, in the order of the message since the
+/// first call of a function is the one that is kept.
+fn call_trees_in_order<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Vec<Frame>>, D::Error> {
+    struct CallTrees;
+
+    impl<'de> Visitor<'de> for CallTrees {
+        type Value = Vec<Vec<Frame>>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("call trees by thread")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut call_trees = Vec::new();
+            while let Some((_, root_frames)) = map.next_entry::<String, Vec<Frame>>()? {
+                call_trees.push(root_frames);
+            }
+            Ok(call_trees)
+        }
+    }
+
+    deserializer.deserialize_map(CallTrees)
+}
+
+#[derive(Deserialize)]
+struct Frame {
+    fingerprint: u64,
+    name: String,
+    package: String,
+    #[serde(default)]
+    path: Option<String>,
+    #[serde(default)]
+    is_application: Option<bool>,
+    // Written as it was sent, an integer or a float, like Python does.
+    duration_ns: Number,
+    #[serde(default)]
+    children: Vec<Frame>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct FunctionRow<'a> {
+    project_id: u64,
+    transaction_name: &'a str,
+    timestamp: &'a str,
+    depth: u32,
+    parent_fingerprint: u64,
+    fingerprint: u64,
+    name: &'a str,
+    package: &'a str,
+    path: &'a str,
+    is_application: u8,
+    platform: &'a str,
+    environment: Option<&'a str>,
+    release: Option<&'a str>,
+    os_name: &'a str,
+    os_version: &'a str,
+    retention_days: u16,
+    durations: Vec<Number>,
+    profile_id: String,
+    materialization_version: u8,
+}
+
+/// Walks the call trees depth first, the durations of a function that is
+/// called several times are aggregated in the row of its first call.
+fn process<'a>(profile: &'a Profile, timestamp: &'a str) -> Vec<FunctionRow<'a>> {
+    let mut rows: Vec<FunctionRow> = Vec::new();
+    let mut row_by_fingerprint: HashMap<u64, usize> = HashMap::new();
+    let mut max_depth_reached = false;
+    let profile_id = profile.profile_id.to_string();
+
+    for root_frames in &profile.call_trees {
+        for root_frame in root_frames {
+            let mut stack = vec![(root_frame, 0, 0)];
+            while let Some((frame, depth, parent_fingerprint)) = stack.pop() {
+                match row_by_fingerprint.get(&frame.fingerprint) {
+                    Some(&index) => rows[index].durations.push(frame.duration_ns.clone()),
+                    None => {
+                        row_by_fingerprint.insert(frame.fingerprint, rows.len());
+                        rows.push(FunctionRow {
+                            project_id: profile.project_id,
+                            transaction_name: &profile.transaction_name,
+                            timestamp,
+                            depth,
+                            parent_fingerprint,
+                            fingerprint: frame.fingerprint,
+                            name: &frame.name,
+                            package: &frame.package,
+                            path: frame.path.as_deref().unwrap_or_default(),
+                            is_application: frame.is_application.unwrap_or(true).into(),
+                            platform: &profile.platform,
+                            environment: profile.environment.as_deref(),
+                            release: profile.release.as_deref(),
+                            os_name: &profile.os_name,
+                            os_version: &profile.os_version,
+                            retention_days: profile.retention_days,
+                            durations: vec![frame.duration_ns.clone()],
+                            profile_id: profile_id.clone(),
+                            materialization_version: 0,
+                        });
+                    }
+                }
+
+                if depth < MAX_DEPTH {
+                    stack.extend(
+                        frame
+                            .children
+                            .iter()
+                            .map(|child| (child, depth + 1, frame.fingerprint)),
+                    );
+                } else if !frame.children.is_empty() {
+                    max_depth_reached = true;
+                }
+            }
+        }
+    }
+
+    if max_depth_reached {
+        metrics::increment("functions.processor.max_depth_reached", None, None, None);
+    }
+    rows
+}
+
+impl MessageProcessor for FunctionsProcessor {
+    fn process_message(
+        &self,
+        payload: KafkaPayload,
+        metadata: KafkaMessageMetadata,
+    ) -> Result<InsertBatch, InvalidMessage> {
+        let payload = payload.payload.unwrap_or_default();
+        let profile: Profile = self
+            .format
+            .decode(&payload)
+            .map_err(|error| metadata.invalid(error))?;
+        let timestamp = DateTime::from_timestamp_millis((profile.timestamp * 1000.0) as i64)
+            .context("Invalid timestamp")
+            .map_err(|error| metadata.invalid(error))?
+            .format(DATETIME_FORMAT)
+            .to_string();
+        let received = profile
+            .received
+            .and_then(|received| DateTime::from_timestamp_millis((received * 1000.0) as i64));
+        InsertBatch::from_rows(process(&profile, &timestamp))
+            .map(|batch| batch.with_origin_timestamp(received))
+            .map_err(|error| metadata.invalid(error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{process, Profile};
+    use serde_json::json;
+
+    #[test]
+    fn test_process() {
+        let profile: Profile = serde_json::from_value(json!({
+            "profile_id": "a1b2c3d4e5f60718293a4b5c6d7e8f90",
+            "project_id": 1,
+            "transaction_name": "vroom-vroom",
+            "timestamp": 1_696_163_445,
+            "platform": "python",
+            "os_name": "iOS",
+            "os_version": "16.0",
+            "retention_days": 30,
+            "call_trees": {
+                "259": [{
+                    "fingerprint": 123,
+                    "name": "foo",
+                    "package": "",
+                    "duration_ns": 10,
+                    "children": [
+                        {"fingerprint": 456, "name": "bar", "package": "", "duration_ns": 5},
+                        {
+                            "fingerprint": 789,
+                            "name": "baz",
+                            "package": "",
+                            "is_application": false,
+                            "duration_ns": 3,
+                            "children": [
+                                {"fingerprint": 456, "name": "bar", "package": "", "duration_ns": 2},
+                            ],
+                        },
+                    ],
+                }],
+            },
+        }))
+        .unwrap();
+        let rows = process(&profile, "2023-10-01 12:30:45");
+
+        let functions: Vec<_> = rows
+            .iter()
+            .map(|row| {
+                (
+                    row.fingerprint,
+                    row.parent_fingerprint,
+                    row.depth,
+                    row.durations.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            functions,
+            vec![
+                (123, 0, 0, vec![10.into()]),
+                (789, 123, 1, vec![3.into()]),
+                (456, 789, 2, vec![2.into(), 5.into()]),
+            ]
+        );
+        assert_eq!(rows[1].is_application, 0);
+        assert_eq!(rows[0].profile_id, "a1b2c3d4-e5f6-0718-293a-4b5c6d7e8f90");
+    }
+}
@@ -0,0 +1,337 @@
+use anyhow::{bail, ensure, Context};
+use chrono::DateTime;
+use rust_arroyo::backends::kafka::types::KafkaPayload;
+use rust_arroyo::codecs::PayloadFormat;
+use rust_arroyo::processing::strategies::InvalidMessage;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::processors::utils::{enforce_retention, ensure_valid_date, DATETIME_FORMAT};
+use crate::processors::{KafkaMessageMetadata, MessageProcessor};
+use crate::types::InsertBatch;
+
+// The hardcoded values of the materialized views.
+const GRANULARITY_ONE_MINUTE: u8 = 1;
+const GRANULARITY_ONE_HOUR: u8 = 2;
+const GRANULARITY_ONE_DAY: u8 = 3;
+
+/// The kind of metric a bucket storage holds, the other metrics of the
+/// topic are skipped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetricType {
+    Counter,
+    Set,
+    Distribution,
+}
+
+impl MetricType {
+    // The ``type`` of the messages.
+    fn input_type(self) -> &'static str {
+        match self {
+            MetricType::Counter => "c",
+            MetricType::Set => "s",
+            MetricType::Distribution => "d",
+        }
+    }
+
+    // The ``metric_type`` of the rows.
+    fn output_type(self) -> &'static str {
+        match self {
+            MetricType::Counter => "counter",
+            MetricType::Set => "set",
+            MetricType::Distribution => "distribution",
+        }
+    }
+}
+
+/// The Rust port of the ``GenericMetricsBucketProcessor`` of every kind of
+/// metric.
+pub struct GenericMetricsProcessor {
+    metric_type: MetricType,
+    format: PayloadFormat,
+}
+
+impl GenericMetricsProcessor {
+    pub fn new(metric_type: MetricType, format: PayloadFormat) -> Self {
+        GenericMetricsProcessor {
+            metric_type,
+            format,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GenericMetric {
+    use_case_id: String,
+    org_id: u64,
+    project_id: u64,
+    metric_id: u64,
+    r#type: Option<String>,
+    timestamp: f64,
+    tags: Map<String, Value>,
+    value: Value,
+    #[serde(default)]
+    retention_days: Option<Value>,
+    mapping_meta: Map<String, Value>,
+    #[serde(default = "first_version")]
+    version: u64,
+    /// When Relay received the metric, in seconds.
+    #[serde(default)]
+    sentry_received_timestamp: Option<f64>,
+}
+
+fn first_version() -> u64 {
+    1
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct GenericMetricRow {
+    use_case_id: String,
+    org_id: u64,
+    project_id: u64,
+    metric_id: u64,
+    timestamp: String,
+    #[serde(rename = "tags.key")]
+    tag_keys: Vec<u64>,
+    #[serde(rename = "tags.raw_value")]
+    tag_raw_values: Vec<String>,
+    #[serde(rename = "tags.indexed_value")]
+    tag_indexed_values: Vec<u64>,
+    metric_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    set_values: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    distribution_values: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count_value: Option<Value>,
+    materialization_version: u8,
+    retention_days: u16,
+    timeseries_id: u32,
+    granularities: Vec<u8>,
+}
+
+// Same as ``zlib.adler32``.
+fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    (b << 16) | a
+}
+
+/// A hash of the organization, project, metric and tags, so that the rows
+/// of a timeseries are written to the same shard.
+fn timeseries_id(message: &GenericMetric, sorted_tags: &[(&String, &Value)]) -> u32 {
+    let mut token = Vec::new();
+    for field in [message.org_id, message.project_id, message.metric_id] {
+        token.extend(field.to_le_bytes());
+    }
+    for (key, value) in sorted_tags {
+        token.extend(key.as_bytes());
+        match value {
+            Value::Number(value) => token.extend(value.as_u64().unwrap_or_default().to_le_bytes()),
+            Value::String(value) => token.extend(value.as_bytes()),
+            _ => {}
+        }
+    }
+    adler32(&token)
+}
+
+fn is_number(value: &Value) -> bool {
+    value.is_number()
+}
+
+fn is_integer(value: &Value) -> bool {
+    value.is_i64() || value.is_u64()
+}
+
+fn values(
+    value: &Value,
+    valid: fn(&Value) -> bool,
+    kind: &str,
+) -> Result<Vec<Value>, anyhow::Error> {
+    let values = value
+        .as_array()
+        .with_context(|| format!("expected iterable of values for {}", kind))?;
+    if let Some(value) = values.iter().find(|value| !valid(value)) {
+        bail!("Illegal value in {}: {}", kind, value);
+    }
+    Ok(values.clone())
+}
+
+impl GenericMetricsProcessor {
+    /// Returns ``None`` for metrics that are skipped.
+    fn process(&self, message: GenericMetric) -> Result<Option<GenericMetricRow>, anyhow::Error> {
+        if message.r#type.as_deref() != Some(self.metric_type.input_type()) {
+            return Ok(None);
+        }
+
+        let timestamp = DateTime::from_timestamp_millis((message.timestamp * 1000.0) as i64)
+            .map(|timestamp| timestamp.naive_utc())
+            .and_then(ensure_valid_date)
+            .context("Invalid timestamp")?;
+        let Ok(retention_days) = enforce_retention(message.retention_days.as_ref(), timestamp)
+        else {
+            return Ok(None);
+        };
+
+        let mut raw_values_index = Map::new();
+        for values in message.mapping_meta.values() {
+            let values = values.as_object().context("Invalid mapping metadata")?;
+            raw_values_index.extend(values.clone());
+        }
+
+        let mut sorted_tags: Vec<_> = message.tags.iter().collect();
+        sorted_tags.sort_by_key(|(key, _)| *key);
+        let mut tag_keys = Vec::new();
+        let mut tag_raw_values = Vec::new();
+        let mut tag_indexed_values = Vec::new();
+        for (key, value) in &sorted_tags {
+            ensure!(key.bytes().all(|c| c.is_ascii_digit()), "Tag key invalid");
+            tag_keys.push(key.parse()?);
+            match message.version {
+                1 => {
+                    let value = value.as_u64().context("Tag value invalid")?;
+                    tag_indexed_values.push(value);
+                    let raw_value = raw_values_index.get(&value.to_string());
+                    tag_raw_values.push(
+                        raw_value
+                            .and_then(Value::as_str)
+                            .unwrap_or_default()
+                            .to_string(),
+                    );
+                }
+                2 => {
+                    let value = value.as_str().context("Tag value invalid")?;
+                    tag_indexed_values.push(0);
+                    tag_raw_values.push(value.to_string());
+                }
+                _ => {}
+            }
+        }
+
+        let mut row = GenericMetricRow {
+            use_case_id: message.use_case_id.clone(),
+            org_id: message.org_id,
+            project_id: message.project_id,
+            metric_id: message.metric_id,
+            timestamp: timestamp.format(DATETIME_FORMAT).to_string(),
+            tag_keys,
+            tag_raw_values,
+            tag_indexed_values,
+            metric_type: self.metric_type.output_type(),
+            set_values: None,
+            distribution_values: None,
+            count_value: None,
+            materialization_version: 1,
+            retention_days,
+            timeseries_id: timeseries_id(&message, &sorted_tags),
+            granularities: vec![
+                GRANULARITY_ONE_MINUTE,
+                GRANULARITY_ONE_HOUR,
+                GRANULARITY_ONE_DAY,
+            ],
+        };
+        match self.metric_type {
+            MetricType::Set => row.set_values = Some(values(&message.value, is_integer, "set")?),
+            MetricType::Distribution => {
+                row.distribution_values = Some(values(&message.value, is_number, "distribution")?)
+            }
+            MetricType::Counter => {
+                ensure!(
+                    message.value.is_number(),
+                    "Illegal value in counter: {}",
+                    message.value
+                );
+                row.count_value = Some(message.value.clone());
+            }
+        }
+        Ok(Some(row))
+    }
+}
+
+impl MessageProcessor for GenericMetricsProcessor {
+    fn process_message(
+        &self,
+        payload: KafkaPayload,
+        metadata: KafkaMessageMetadata,
+    ) -> Result<InsertBatch, InvalidMessage> {
+        let payload = payload.payload.unwrap_or_default();
+        let message: GenericMetric = self
+            .format
+            .decode(&payload)
+            .map_err(|error| metadata.invalid(error))?;
+        let sentry_received_timestamp = message
+            .sentry_received_timestamp
+            .and_then(|timestamp| DateTime::from_timestamp_millis((timestamp * 1000.0) as i64));
+        let row = self
+            .process(message)
+            .map_err(|error| metadata.invalid(error))?;
+        InsertBatch::from_rows(row)
+            .map(|batch| batch.with_sentry_received_timestamp(sentry_received_timestamp))
+            .map_err(|error| metadata.invalid(error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{adler32, GenericMetric, GenericMetricsProcessor, MetricType};
+    use chrono::Utc;
+    use rust_arroyo::codecs::PayloadFormat;
+    use serde_json::{json, Value};
+
+    fn message(r#type: &str, value: Value) -> GenericMetric {
+        serde_json::from_value(json!({
+            "use_case_id": "performance",
+            "org_id": 1,
+            "project_id": 2,
+            "metric_id": 3,
+            "type": r#type,
+            "timestamp": Utc::now().timestamp(),
+            "tags": {"10": 11, "9": 12},
+            "value": value,
+            "retention_days": 30,
+            "mapping_meta": {"c": {"11": "production"}, "h": {"12": "release"}},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_adler32() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+    }
+
+    #[test]
+    fn test_process() {
+        let processor = GenericMetricsProcessor::new(MetricType::Set, PayloadFormat::Json);
+        let row = processor
+            .process(message("s", json!([1, 2])))
+            .unwrap()
+            .unwrap();
+        assert_eq!(row.tag_keys, vec![10, 9]);
+        assert_eq!(row.tag_raw_values, vec!["production", "release"]);
+        assert_eq!(row.tag_indexed_values, vec![11, 12]);
+        assert_eq!(row.set_values, Some(vec![json!(1), json!(2)]));
+        assert_eq!(row.retention_days, 30);
+
+        // Other kinds of metrics are skipped
+        assert_eq!(processor.process(message("c", json!(1))).unwrap(), None);
+        // Sets only have integers
+        assert!(processor.process(message("s", json!([1.5]))).is_err());
+
+        let processor = GenericMetricsProcessor::new(MetricType::Counter, PayloadFormat::Json);
+        let row = processor
+            .process(message("c", json!(1.5)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(row.count_value, Some(json!(1.5)));
+        assert_eq!(row.metric_type, "counter");
+    }
+}
@@ -0,0 +1,191 @@
+mod functions;
+mod generic_metrics;
+mod outcomes;
+mod profiles;
+mod querylog;
+mod replays;
+mod spans;
+mod utils;
+
+use std::fmt::Display;
+
+use chrono::{DateTime, Utc};
+use rust_arroyo::backends::kafka::types::KafkaPayload;
+use rust_arroyo::codecs::PayloadFormat;
+use rust_arroyo::processing::strategies::InvalidMessage;
+use rust_arroyo::types::Partition;
+
+use crate::types::InsertBatch;
+
+/// Where a message was consumed from, like the ``KafkaMessageMetadata`` the
+/// Python processors get.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KafkaMessageMetadata {
+    pub partition: Partition,
+    pub offset: u64,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl KafkaMessageMetadata {
+    /// Logs why the message could not be processed and returns it as
+    /// invalid.
+    pub fn invalid(&self, error: impl Display) -> InvalidMessage {
+        log::error!(
+            "Failed to process the message at offset {} of {}: {:#}",
+            self.offset,
+            self.partition,
+            error
+        );
+        InvalidMessage {
+            partition: self.partition.clone(),
+            offset: self.offset,
+        }
+    }
+}
+
+/// Turns the messages of a topic into the rows of a storage, like the
+/// ``DatasetMessageProcessor`` of the storage in Python. Messages that do
+/// not produce any row return an empty batch.
+pub trait MessageProcessor: Send + Sync {
+    fn process_message(
+        &self,
+        payload: KafkaPayload,
+        metadata: KafkaMessageMetadata,
+    ) -> Result<InsertBatch, InvalidMessage>;
+}
+
+type ProcessorFactory = fn(PayloadFormat) -> Box<dyn MessageProcessor>;
+
+/// The storages that have a Rust processor, by the name of the storage.
+const PROCESSORS: &[(&str, ProcessorFactory)] = &[
+    ("functions_raw", |format| {
+        Box::new(functions::FunctionsProcessor::new(format))
+    }),
+    ("generic_metrics_counters_raw", |format| {
+        Box::new(generic_metrics::GenericMetricsProcessor::new(
+            generic_metrics::MetricType::Counter,
+            format,
+        ))
+    }),
+    ("generic_metrics_distributions_raw", |format| {
+        Box::new(generic_metrics::GenericMetricsProcessor::new(
+            generic_metrics::MetricType::Distribution,
+            format,
+        ))
+    }),
+    ("generic_metrics_sets_raw", |format| {
+        Box::new(generic_metrics::GenericMetricsProcessor::new(
+            generic_metrics::MetricType::Set,
+            format,
+        ))
+    }),
+    ("outcomes_raw", |format| {
+        Box::new(outcomes::OutcomesProcessor::new(format))
+    }),
+    ("profiles", |format| {
+        Box::new(profiles::ProfilesProcessor::new(format))
+    }),
+    ("querylog", |format| {
+        Box::new(querylog::QuerylogProcessor::new(format))
+    }),
+    ("replays", |format| {
+        Box::new(replays::ReplaysProcessor::new(format))
+    }),
+    ("spans", |format| {
+        Box::new(spans::SpansProcessor::new(format))
+    }),
+];
+
+/// Returns the Rust processor of a storage if it was ported, decoding the
+/// messages in the ``format`` of the topic they are consumed from.
+pub fn get_processor(
+    storage_name: &str,
+    format: PayloadFormat,
+) -> Option<Box<dyn MessageProcessor>> {
+    PROCESSORS
+        .iter()
+        .find(|(name, _)| *name == storage_name)
+        .map(|(_, factory)| factory(format))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_processor, KafkaMessageMetadata, PROCESSORS};
+    use chrono::{TimeZone, Utc};
+    use glob::glob;
+    use rust_arroyo::backends::kafka::types::KafkaPayload;
+    use rust_arroyo::codecs::PayloadFormat;
+    use rust_arroyo::types::{Partition, Topic};
+    use serde::Deserialize;
+    use serde_json::Value;
+    use std::collections::HashSet;
+    use std::fs;
+
+    // The processors of the storages that have no Python processor in this
+    // repository to generate snapshots from.
+    const WITHOUT_SNAPSHOTS: &[&str] = &["spans"];
+
+    // The rows a Python processor returned for a message, written by
+    // ``scripts/generate-rust-processor-snapshots.py``.
+    #[derive(Deserialize)]
+    struct Snapshot {
+        message: Value,
+        offset: u64,
+        rows: Vec<String>,
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let pattern = format!(
+            "{}/src/processors/snapshots/*/*.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let mut checked = 0;
+        let mut storages = HashSet::new();
+        for path in glob(&pattern).unwrap() {
+            let path = path.unwrap();
+            let storage = path.parent().unwrap().file_name().unwrap();
+            let storage = storage.to_str().unwrap();
+            storages.insert(storage.to_owned());
+            let processor = get_processor(storage, PayloadFormat::Json)
+                .unwrap_or_else(|| panic!("{} does not have a Rust processor", storage));
+            let snapshot: Snapshot = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+
+            let payload = KafkaPayload {
+                key: None,
+                headers: None,
+                payload: Some(serde_json::to_vec(&snapshot.message).unwrap().into()),
+            };
+            // Same as the metadata of the script.
+            let metadata = KafkaMessageMetadata {
+                partition: Partition {
+                    topic: Topic {
+                        name: storage.to_string(),
+                    },
+                    index: 0,
+                },
+                offset: snapshot.offset,
+                timestamp: Utc.with_ymd_and_hms(2023, 10, 1, 12, 30, 45).unwrap(),
+            };
+            let batch = processor.process_message(payload, metadata).unwrap();
+            let rows: Vec<_> = batch
+                .rows
+                .into_iter()
+                .map(|row| String::from_utf8(row).unwrap())
+                .collect();
+            assert_eq!(rows, snapshot.rows, "{}", path.display());
+            checked += 1;
+        }
+        assert!(checked > 0, "No snapshot matches {}", pattern);
+
+        // Every processor that can be checked against Python is.
+        for (name, _) in PROCESSORS {
+            assert_eq!(
+                storages.contains(*name),
+                !WITHOUT_SNAPSHOTS.contains(name),
+                "{} must have snapshots unless it is in WITHOUT_SNAPSHOTS",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use chrono::{NaiveDateTime, Utc};
+use rust_arroyo::backends::kafka::types::KafkaPayload;
+use rust_arroyo::codecs::PayloadFormat;
+use rust_arroyo::processing::strategies::InvalidMessage;
+use rust_arroyo::utils::metrics;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+use crate::processors::utils::{ensure_valid_date, unicodify, DATETIME_FORMAT};
+use crate::processors::{KafkaMessageMetadata, MessageProcessor};
+use crate::types::InsertBatch;
+
+const OUTCOME_ABUSE: u8 = 4;
+const OUTCOME_CLIENT_DISCARD: u8 = 5;
+
+const CLIENT_DISCARD_REASONS: &[&str] = &[
+    "queue_overflow",
+    "cache_overflow",
+    "ratelimit_backoff",
+    "network_error",
+    "before_send",
+    "event_processor",
+    "sample_rate",
+    "send_error",
+    "internal_sdk_error",
+];
+
+/// ``DataCategory.ERROR`` of Relay.
+const DATA_CATEGORY_ERROR: u8 = 1;
+
+// Same as ``PAYLOAD_DATETIME_FORMAT`` in the settings.
+const PAYLOAD_DATETIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.fZ";
+
+/// The Rust port of ``OutcomesProcessor``.
+pub struct OutcomesProcessor {
+    format: PayloadFormat,
+}
+
+impl OutcomesProcessor {
+    pub fn new(format: PayloadFormat) -> Self {
+        OutcomesProcessor { format }
+    }
+}
+
+// Fields that are set to null keep their null, only missing fields get the
+// defaults, like ``dict.get`` in the Python processor.
+#[derive(Deserialize)]
+struct Outcome {
+    #[serde(default = "zero")]
+    org_id: Option<u64>,
+    #[serde(default = "zero")]
+    project_id: Option<u64>,
+    #[serde(default)]
+    key_id: Option<u64>,
+    timestamp: Value,
+    outcome: u8,
+    #[serde(default = "error_category")]
+    category: Option<u8>,
+    #[serde(default = "one")]
+    quantity: Option<u64>,
+    #[serde(default)]
+    reason: Option<Value>,
+    #[serde(default)]
+    event_id: Option<String>,
+}
+
+fn zero() -> Option<u64> {
+    Some(0)
+}
+
+fn one() -> Option<u64> {
+    Some(1)
+}
+
+fn error_category() -> Option<u8> {
+    Some(DATA_CATEGORY_ERROR)
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct OutcomeRow {
+    org_id: Option<u64>,
+    project_id: Option<u64>,
+    key_id: Option<u64>,
+    timestamp: Option<String>,
+    outcome: u8,
+    category: Option<u8>,
+    quantity: Option<u64>,
+    reason: Option<String>,
+    event_id: Option<String>,
+}
+
+fn parse_timestamp(timestamp: &Value) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(timestamp.as_str()?, PAYLOAD_DATETIME_FORMAT).ok()
+}
+
+/// Returns ``None`` for outcomes that are dropped.
+fn process(message: Map<String, Value>) -> Result<Option<OutcomeRow>, anyhow::Error> {
+    let has_category = message.contains_key("category");
+    let has_quantity = message.contains_key("quantity");
+    let outcome: Outcome = serde_json::from_value(Value::Object(message))?;
+
+    // Relays let any reason through, undesired reasons are only dropped
+    // here so that new ones can be added without updating every Relay.
+    let mut reason = outcome.reason.as_ref().and_then(unicodify);
+    if outcome.outcome == OUTCOME_CLIENT_DISCARD {
+        reason = reason.filter(|reason| CLIENT_DISCARD_REASONS.contains(&reason.as_str()));
+    }
+
+    if outcome.outcome != OUTCOME_ABUSE {
+        if !has_category {
+            metrics::increment("outcomes.processor.missing_category", None, None, None);
+        }
+        if !has_quantity {
+            metrics::increment("outcomes.processor.missing_quantity", None, None, None);
+        }
+    }
+
+    let timestamp = match parse_timestamp(&outcome.timestamp) {
+        Some(timestamp) => timestamp,
+        None => {
+            metrics::increment("outcomes.processor.bad_outcome_timestamp", None, None, None);
+            Utc::now().naive_utc()
+        }
+    };
+
+    let event_id = match outcome.event_id.as_deref().map(Uuid::parse_str).transpose() {
+        Ok(event_id) => event_id.map(|event_id| event_id.to_string()),
+        Err(_) => {
+            metrics::increment("outcomes.processor.bad_outcome", None, None, None);
+            return Ok(None);
+        }
+    };
+
+    Ok(Some(OutcomeRow {
+        org_id: outcome.org_id,
+        project_id: outcome.project_id,
+        key_id: outcome.key_id,
+        timestamp: ensure_valid_date(timestamp)
+            .map(|timestamp| timestamp.format(DATETIME_FORMAT).to_string()),
+        outcome: outcome.outcome,
+        category: outcome.category,
+        quantity: outcome.quantity,
+        reason,
+        event_id,
+    }))
+}
+
+impl MessageProcessor for OutcomesProcessor {
+    fn process_message(
+        &self,
+        payload: KafkaPayload,
+        metadata: KafkaMessageMetadata,
+    ) -> Result<InsertBatch, InvalidMessage> {
+        let payload = payload.payload.unwrap_or_default();
+        let message: Map<String, Value> = self
+            .format
+            .decode(&payload)
+            .map_err(|error| metadata.invalid(error))?;
+        let row = process(message).map_err(|error| metadata.invalid(error))?;
+        InsertBatch::from_rows(row).map_err(|error| metadata.invalid(error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{process, OutcomeRow};
+    use serde_json::{json, Value};
+
+    fn outcome(message: Value) -> Option<OutcomeRow> {
+        match message {
+            Value::Object(message) => process(message).unwrap(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_process() {
+        let row = outcome(json!({
+            "org_id": 1,
+            "project_id": 2,
+            "key_id": null,
+            "timestamp": "2023-10-01T12:30:45.123456Z",
+            "outcome": 5,
+            "reason": "sample_rate",
+            "event_id": "a".repeat(32),
+            "quantity": 3,
+        }));
+        assert_eq!(
+            row,
+            Some(OutcomeRow {
+                org_id: Some(1),
+                project_id: Some(2),
+                key_id: None,
+                timestamp: Some("2023-10-01 12:30:45".to_string()),
+                outcome: 5,
+                category: Some(1),
+                quantity: Some(3),
+                reason: Some("sample_rate".to_string()),
+                event_id: Some("aaaaaaaa-aaaa-aaaa-aaaa-aaaaaaaaaaaa".to_string()),
+            })
+        );
+
+        // Unknown client discard reasons are dropped
+        let row = outcome(json!({
+            "timestamp": "2023-10-01T12:30:45.000000Z",
+            "outcome": 5,
+            "reason": "unknown",
+            "category": null,
+        }))
+        .unwrap();
+        assert_eq!(row.reason, None);
+        assert_eq!(row.org_id, Some(0));
+        assert_eq!(row.category, None);
+
+        // But kept for other outcomes
+        let row = outcome(json!({
+            "timestamp": "2023-10-01T12:30:45.000000Z",
+            "outcome": 2,
+            "reason": 42,
+        }))
+        .unwrap();
+        assert_eq!(row.reason.as_deref(), Some("42"));
+
+        // Outcomes with an invalid event id are dropped
+        let row = outcome(json!({
+            "timestamp": "2023-10-01T12:30:45.000000Z",
+            "outcome": 0,
+            "event_id": "invalid",
+        }));
+        assert_eq!(row, None);
+    }
+}
@@ -0,0 +1,370 @@
+use chrono::{DateTime, NaiveDateTime};
+use rust_arroyo::backends::kafka::types::KafkaPayload;
+use rust_arroyo::codecs::PayloadFormat;
+use rust_arroyo::processing::strategies::InvalidMessage;
+use rust_arroyo::utils::metrics;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+use crate::processors::utils::{enforce_retention, DATETIME_FORMAT};
+use crate::processors::{KafkaMessageMetadata, MessageProcessor};
+use crate::types::InsertBatch;
+
+/// The Rust port of ``ProfilesMessageProcessor``, profiles in the legacy
+/// format and in the sample format are both written to the same columns.
+pub struct ProfilesProcessor {
+    format: PayloadFormat,
+}
+
+impl ProfilesProcessor {
+    pub fn new(format: PayloadFormat) -> Self {
+        ProfilesProcessor { format }
+    }
+}
+
+#[derive(Deserialize)]
+struct LegacyProfile {
+    #[serde(default)]
+    android_api_level: Option<u32>,
+    #[serde(default = "unknown_architecture")]
+    architecture: String,
+    #[serde(default)]
+    device_classification: String,
+    device_locale: String,
+    device_manufacturer: String,
+    device_model: String,
+    #[serde(default)]
+    device_os_build_number: Option<String>,
+    device_os_name: String,
+    device_os_version: String,
+    duration_ns: u64,
+    #[serde(default)]
+    environment: Option<String>,
+    organization_id: u64,
+    platform: String,
+    profile_id: String,
+    project_id: u64,
+    trace_id: String,
+    transaction_id: String,
+    transaction_name: String,
+    version_code: String,
+    version_name: String,
+}
+
+#[derive(Deserialize)]
+struct SampleProfile {
+    #[serde(default)]
+    android_api_level: Option<u32>,
+    device: Device,
+    os: Os,
+    transactions: Vec<Transaction>,
+    #[serde(default)]
+    environment: Option<String>,
+    organization_id: u64,
+    platform: String,
+    event_id: String,
+    project_id: u64,
+    #[serde(default)]
+    version_code: String,
+    release: String,
+}
+
+#[derive(Deserialize)]
+struct Device {
+    #[serde(default = "unknown_architecture")]
+    architecture: String,
+    #[serde(default)]
+    classification: String,
+    #[serde(default)]
+    locale: String,
+    #[serde(default)]
+    manufacturer: String,
+    #[serde(default)]
+    model: String,
+}
+
+#[derive(Deserialize)]
+struct Os {
+    #[serde(default)]
+    build_number: Option<String>,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    version: String,
+}
+
+#[derive(Deserialize)]
+struct Transaction {
+    id: String,
+    name: String,
+    trace_id: String,
+    relative_start_ns: u64,
+    relative_end_ns: u64,
+}
+
+fn unknown_architecture() -> String {
+    "unknown".to_string()
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct ProfileRow {
+    android_api_level: Option<u32>,
+    architecture: String,
+    device_classification: String,
+    device_locale: String,
+    device_manufacturer: String,
+    device_model: String,
+    device_os_build_number: Option<String>,
+    device_os_name: String,
+    device_os_version: String,
+    duration_ns: u64,
+    environment: Option<String>,
+    offset: u64,
+    organization_id: u64,
+    partition: u16,
+    platform: String,
+    profile_id: String,
+    project_id: u64,
+    received: String,
+    retention_days: u16,
+    trace_id: String,
+    transaction_id: String,
+    transaction_name: String,
+    version_code: String,
+    version_name: String,
+}
+
+/// Why a profile is dropped, every reason has its own metric.
+#[derive(Debug, PartialEq)]
+enum Skip {
+    EventTooOld,
+    InvalidTransaction,
+    InvalidUuid,
+    MissingField,
+}
+
+impl Skip {
+    fn metric(&self) -> &'static str {
+        match self {
+            Skip::EventTooOld => "profiles.processor.event_too_old",
+            Skip::InvalidTransaction => "profiles.processor.invalid_transaction",
+            Skip::InvalidUuid => "profiles.processor.invalid_uuid",
+            Skip::MissingField => "profiles.processor.missing_field",
+        }
+    }
+}
+
+fn uuid(value: &str) -> Result<String, Skip> {
+    Uuid::parse_str(value)
+        .map(|uuid| uuid.to_string())
+        .map_err(|_| Skip::InvalidUuid)
+}
+
+fn parse<T: for<'de> Deserialize<'de>>(message: Map<String, Value>) -> Result<T, Skip> {
+    serde_json::from_value(Value::Object(message)).map_err(|_| Skip::MissingField)
+}
+
+/// Returns the row with when the profile was received.
+fn process(
+    mut message: Map<String, Value>,
+    metadata: &KafkaMessageMetadata,
+) -> Result<(ProfileRow, NaiveDateTime), Skip> {
+    let received = message
+        .get("received")
+        .and_then(Value::as_f64)
+        .and_then(|received| DateTime::from_timestamp_millis((received * 1000.0) as i64))
+        .map(|received| received.naive_utc())
+        .ok_or(Skip::MissingField)?;
+    let retention_days = message.remove("retention_days").ok_or(Skip::MissingField)?;
+    let retention_days =
+        enforce_retention(Some(&retention_days), received).map_err(|_| Skip::EventTooOld)?;
+
+    let row = if message.contains_key("version") {
+        normalize_sample_format(parse(message)?, metadata, retention_days, received)?
+    } else {
+        normalize_legacy_format(parse(message)?, metadata, retention_days, received)?
+    };
+    Ok((row, received))
+}
+
+fn normalize_legacy_format(
+    profile: LegacyProfile,
+    metadata: &KafkaMessageMetadata,
+    retention_days: u16,
+    received: NaiveDateTime,
+) -> Result<ProfileRow, Skip> {
+    Ok(ProfileRow {
+        android_api_level: profile.android_api_level,
+        architecture: profile.architecture,
+        device_classification: profile.device_classification,
+        device_locale: profile.device_locale,
+        device_manufacturer: profile.device_manufacturer,
+        device_model: profile.device_model,
+        device_os_build_number: profile.device_os_build_number,
+        device_os_name: profile.device_os_name,
+        device_os_version: profile.device_os_version,
+        duration_ns: profile.duration_ns,
+        environment: profile.environment,
+        offset: metadata.offset,
+        organization_id: profile.organization_id,
+        partition: metadata.partition.index,
+        platform: profile.platform,
+        profile_id: uuid(&profile.profile_id)?,
+        project_id: profile.project_id,
+        received: received.format(DATETIME_FORMAT).to_string(),
+        retention_days,
+        trace_id: uuid(&profile.trace_id)?,
+        transaction_id: uuid(&profile.transaction_id)?,
+        transaction_name: profile.transaction_name,
+        version_code: profile.version_code,
+        version_name: profile.version_name,
+    })
+}
+
+fn normalize_sample_format(
+    profile: SampleProfile,
+    metadata: &KafkaMessageMetadata,
+    retention_days: u16,
+    received: NaiveDateTime,
+) -> Result<ProfileRow, Skip> {
+    let transaction = profile
+        .transactions
+        .into_iter()
+        .next()
+        .ok_or(Skip::InvalidTransaction)?;
+    Ok(ProfileRow {
+        android_api_level: profile.android_api_level,
+        architecture: profile.device.architecture,
+        device_classification: profile.device.classification,
+        device_locale: profile.device.locale,
+        device_manufacturer: profile.device.manufacturer,
+        device_model: profile.device.model,
+        device_os_build_number: profile.os.build_number,
+        device_os_name: profile.os.name,
+        device_os_version: profile.os.version,
+        duration_ns: transaction
+            .relative_end_ns
+            .saturating_sub(transaction.relative_start_ns),
+        environment: profile.environment,
+        offset: metadata.offset,
+        organization_id: profile.organization_id,
+        partition: metadata.partition.index,
+        platform: profile.platform,
+        profile_id: uuid(&profile.event_id)?,
+        project_id: profile.project_id,
+        received: received.format(DATETIME_FORMAT).to_string(),
+        retention_days,
+        trace_id: uuid(&transaction.trace_id)?,
+        transaction_id: uuid(&transaction.id)?,
+        transaction_name: transaction.name,
+        version_code: profile.version_code,
+        version_name: profile.release,
+    })
+}
+
+impl MessageProcessor for ProfilesProcessor {
+    fn process_message(
+        &self,
+        payload: KafkaPayload,
+        metadata: KafkaMessageMetadata,
+    ) -> Result<InsertBatch, InvalidMessage> {
+        let payload = payload.payload.unwrap_or_default();
+        let message: Map<String, Value> = self
+            .format
+            .decode(&payload)
+            .map_err(|error| metadata.invalid(error))?;
+        match process(message, &metadata) {
+            Ok((row, received)) => InsertBatch::from_rows([row])
+                .map(|batch| batch.with_origin_timestamp(Some(received.and_utc())))
+                .map_err(|error| metadata.invalid(error)),
+            Err(skip) => {
+                metrics::increment(skip.metric(), None, None, None);
+                Ok(InsertBatch::skip())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{process, Skip};
+    use crate::processors::KafkaMessageMetadata;
+    use chrono::Utc;
+    use rust_arroyo::types::{Partition, Topic};
+    use serde_json::{json, Map, Value};
+
+    fn metadata() -> KafkaMessageMetadata {
+        KafkaMessageMetadata {
+            partition: Partition {
+                topic: Topic {
+                    name: "processed-profiles".to_string(),
+                },
+                index: 1,
+            },
+            offset: 42,
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn message(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(message) => message,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_process() {
+        let received = Utc::now().timestamp();
+        let row = process(
+            message(json!({
+                "version": "1",
+                "received": received,
+                "retention_days": 30,
+                "organization_id": 1,
+                "project_id": 2,
+                "platform": "cocoa",
+                "event_id": "a1b2c3d4e5f60718293a4b5c6d7e8f90",
+                "release": "1.0 (1)",
+                "device": {"architecture": "arm64e", "model": "iPhone14,2"},
+                "os": {"name": "iOS", "version": "16.0"},
+                "transactions": [{
+                    "id": "b1b2c3d4e5f60718293a4b5c6d7e8f90",
+                    "name": "vroom",
+                    "trace_id": "c1b2c3d4e5f60718293a4b5c6d7e8f90",
+                    "relative_start_ns": 100,
+                    "relative_end_ns": 350,
+                }],
+            })),
+            &metadata(),
+        )
+        .unwrap()
+        .0;
+        assert_eq!(row.duration_ns, 250);
+        assert_eq!(row.architecture, "arm64e");
+        assert_eq!(row.device_locale, "");
+        assert_eq!(row.profile_id, "a1b2c3d4-e5f6-0718-293a-4b5c6d7e8f90");
+        assert_eq!(row.version_name, "1.0 (1)");
+        assert_eq!((row.partition, row.offset), (1, 42));
+
+        let skip = process(
+            message(json!({
+                "received": received,
+                "retention_days": 30,
+                "device_locale": "en_US",
+            })),
+            &metadata(),
+        );
+        assert_eq!(skip.unwrap_err(), Skip::MissingField);
+
+        let skip = process(
+            message(json!({
+                "received": received - 100 * 24 * 3600,
+                "retention_days": 30,
+            })),
+            &metadata(),
+        );
+        assert_eq!(skip.unwrap_err(), Skip::EventTooOld);
+    }
+}
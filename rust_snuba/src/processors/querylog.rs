@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+
+use rust_arroyo::backends::kafka::types::KafkaPayload;
+use rust_arroyo::codecs::PayloadFormat;
+use rust_arroyo::processing::strategies::InvalidMessage;
+use rust_arroyo::utils::metrics;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+use crate::processors::utils::python_json_dumps;
+use crate::processors::{KafkaMessageMetadata, MessageProcessor};
+use crate::types::InsertBatch;
+
+/// The Rust port of ``QuerylogProcessor``, which writes a row per Snuba
+/// request with the ClickHouse queries it ran flattened into arrays.
+pub struct QuerylogProcessor {
+    format: PayloadFormat,
+}
+
+impl QuerylogProcessor {
+    pub fn new(format: PayloadFormat) -> Self {
+        QuerylogProcessor { format }
+    }
+}
+
+#[derive(Deserialize)]
+struct Querylog {
+    request: Request,
+    dataset: String,
+    #[serde(default)]
+    projects: Option<Vec<Value>>,
+    #[serde(default)]
+    organization: Option<u64>,
+    query_list: Vec<QueryMetadata>,
+    #[serde(default)]
+    timing: Option<Timing>,
+    #[serde(default)]
+    status: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Request {
+    id: String,
+    body: Map<String, Value>,
+    referrer: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct Timing {
+    #[serde(default)]
+    timestamp: Option<u64>,
+    #[serde(default)]
+    duration_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct QueryMetadata {
+    sql: String,
+    status: String,
+    trace_id: String,
+    stats: Map<String, Value>,
+    #[serde(default)]
+    profile: Option<Profile>,
+    #[serde(default)]
+    result_profile: Option<ResultProfile>,
+}
+
+#[derive(Deserialize, Default)]
+struct Profile {
+    time_range: Option<i64>,
+    #[serde(default)]
+    all_columns: Option<Vec<String>>,
+    multi_level_condition: bool,
+    where_profile: WhereProfile,
+    groupby_cols: Vec<String>,
+    array_join_cols: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct WhereProfile {
+    columns: Vec<String>,
+    mapping_cols: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct ResultProfile {
+    #[serde(default)]
+    bytes: u64,
+    #[serde(default)]
+    elapsed: f64,
+}
+
+#[derive(Serialize, Default, Debug, PartialEq)]
+struct QuerylogRow {
+    request_id: String,
+    request_body: String,
+    referrer: String,
+    dataset: String,
+    projects: Vec<Value>,
+    organization: Option<u64>,
+    #[serde(rename = "clickhouse_queries.sql")]
+    sql: Vec<String>,
+    #[serde(rename = "clickhouse_queries.status")]
+    query_status: Vec<String>,
+    #[serde(rename = "clickhouse_queries.trace_id")]
+    trace_id: Vec<String>,
+    #[serde(rename = "clickhouse_queries.duration_ms")]
+    query_duration_ms: Vec<i64>,
+    #[serde(rename = "clickhouse_queries.stats")]
+    stats: Vec<String>,
+    #[serde(rename = "clickhouse_queries.final")]
+    r#final: Vec<i64>,
+    #[serde(rename = "clickhouse_queries.cache_hit")]
+    cache_hit: Vec<i64>,
+    #[serde(rename = "clickhouse_queries.sample")]
+    sample: Vec<f64>,
+    #[serde(rename = "clickhouse_queries.max_threads")]
+    max_threads: Vec<i64>,
+    #[serde(rename = "clickhouse_queries.num_days")]
+    num_days: Vec<i64>,
+    #[serde(rename = "clickhouse_queries.clickhouse_table")]
+    clickhouse_table: Vec<String>,
+    #[serde(rename = "clickhouse_queries.query_id")]
+    query_id: Vec<String>,
+    #[serde(rename = "clickhouse_queries.is_duplicate")]
+    is_duplicate: Vec<i64>,
+    #[serde(rename = "clickhouse_queries.consistent")]
+    consistent: Vec<i64>,
+    #[serde(rename = "clickhouse_queries.all_columns")]
+    all_columns: Vec<Vec<String>>,
+    #[serde(rename = "clickhouse_queries.or_conditions")]
+    or_conditions: Vec<bool>,
+    #[serde(rename = "clickhouse_queries.where_columns")]
+    where_columns: Vec<Vec<String>>,
+    #[serde(rename = "clickhouse_queries.where_mapping_columns")]
+    where_mapping_columns: Vec<Vec<String>>,
+    #[serde(rename = "clickhouse_queries.groupby_columns")]
+    groupby_columns: Vec<Vec<String>>,
+    #[serde(rename = "clickhouse_queries.array_join_columns")]
+    array_join_columns: Vec<Vec<String>>,
+    #[serde(rename = "clickhouse_queries.bytes_scanned")]
+    bytes_scanned: Vec<u64>,
+    // Set after the queries by the Python processor, which the order of the
+    // keys follows.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<String>,
+}
+
+// Only the keys at the top are sorted, like the Python processor.
+fn to_json_string(map: &Map<String, Value>) -> String {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_by_key(|(key, _)| *key);
+    let sorted: Map<String, Value> = entries
+        .into_iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    python_json_dumps(&sorted)
+}
+
+// Like ``int(stats.get(key) or 0)``.
+fn stat_int(stats: &Map<String, Value>, key: &str) -> i64 {
+    match stats.get(key) {
+        Some(Value::Bool(value)) => *value as i64,
+        Some(Value::Number(value)) => value
+            .as_i64()
+            .unwrap_or_else(|| value.as_f64().unwrap_or_default() as i64),
+        _ => 0,
+    }
+}
+
+fn stat_string(stats: &Map<String, Value>, key: &str) -> String {
+    match stats.get(key) {
+        Some(Value::String(value)) => value.clone(),
+        _ => String::new(),
+    }
+}
+
+// Project ids that are not positive integers are dropped.
+fn is_valid_project(project: &Value) -> bool {
+    let id = match project {
+        Value::Number(id) => id.as_i64(),
+        Value::String(id) => id.trim().parse().ok(),
+        _ => None,
+    };
+    id.is_some_and(|id| id > 0)
+}
+
+fn process(message: Querylog) -> Result<QuerylogRow, anyhow::Error> {
+    let timing = message.timing.unwrap_or_default();
+    let projects = message.projects.unwrap_or_default();
+    let (projects, invalid): (Vec<_>, Vec<_>) = projects.into_iter().partition(is_valid_project);
+    if !invalid.is_empty() {
+        log::error!("Invalid project ids {:?}", invalid);
+    }
+
+    let mut row = QuerylogRow {
+        request_id: Uuid::parse_str(&message.request.id)?.to_string(),
+        request_body: to_json_string(&message.request.body),
+        referrer: message.request.referrer.unwrap_or_default(),
+        dataset: message.dataset,
+        projects,
+        organization: message.organization,
+        timestamp: timing.timestamp,
+        duration_ms: timing.duration_ms,
+        status: message.status,
+        ..Default::default()
+    };
+
+    for query in message.query_list {
+        let stats = &query.stats;
+        row.sql.push(query.sql);
+        row.query_status.push(query.status);
+        row.trace_id
+            .push(Uuid::parse_str(&query.trace_id)?.to_string());
+        row.stats.push(to_json_string(stats));
+        row.r#final.push(stat_int(stats, "final"));
+        row.cache_hit.push(stat_int(stats, "cache_hit"));
+        row.sample.push(match stats.get("sample") {
+            Some(Value::Number(sample)) => sample.as_f64().unwrap_or_default(),
+            _ => 0.0,
+        });
+        row.max_threads.push(stat_int(stats, "max_threads"));
+        row.clickhouse_table
+            .push(stat_string(stats, "clickhouse_table"));
+        row.query_id.push(stat_string(stats, "query_id"));
+        row.is_duplicate.push(stat_int(stats, "is_duplicate"));
+        row.consistent.push(stat_int(stats, "consistent"));
+
+        let profile = query.profile.unwrap_or_default();
+        let result_profile = query.result_profile.unwrap_or_default();
+        row.num_days
+            .push(profile.time_range.unwrap_or_default().max(0));
+        row.all_columns
+            .push(profile.all_columns.unwrap_or_default());
+        row.or_conditions.push(profile.multi_level_condition);
+        row.where_columns.push(profile.where_profile.columns);
+        row.where_mapping_columns
+            .push(profile.where_profile.mapping_cols);
+        row.groupby_columns.push(profile.groupby_cols);
+        row.array_join_columns.push(profile.array_join_cols);
+        row.bytes_scanned.push(result_profile.bytes);
+        row.query_duration_ms
+            .push((result_profile.elapsed * 1000.0) as i64);
+    }
+
+    let missing: Vec<_> = [
+        ("duration_ms", row.duration_ms.is_none()),
+        ("status", row.status.is_none()),
+        ("timestamp", row.timestamp.is_none()),
+    ]
+    .into_iter()
+    .filter_map(|(field, missing)| missing.then_some(field))
+    .collect();
+    if !missing.is_empty() {
+        let fields = missing.join(",");
+        metrics::increment(
+            "querylog.process.missing_fields",
+            None,
+            Some(HashMap::from([("fields", fields.as_str())])),
+            None,
+        );
+    }
+    Ok(row)
+}
+
+impl MessageProcessor for QuerylogProcessor {
+    fn process_message(
+        &self,
+        payload: KafkaPayload,
+        metadata: KafkaMessageMetadata,
+    ) -> Result<InsertBatch, InvalidMessage> {
+        let payload = payload.payload.unwrap_or_default();
+        let message: Querylog = self
+            .format
+            .decode(&payload)
+            .map_err(|error| metadata.invalid(error))?;
+        let row = process(message).map_err(|error| metadata.invalid(error))?;
+        InsertBatch::from_rows([row]).map_err(|error| metadata.invalid(error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{process, Querylog};
+    use serde_json::json;
+
+    #[test]
+    fn test_process() {
+        let message: Querylog = serde_json::from_value(json!({
+            "request": {
+                "id": "a".repeat(32),
+                "body": {"selected_columns": ["event_id"], "limit": 100, "sample": 0.1},
+                "referrer": "search",
+            },
+            "dataset": "events",
+            "projects": [2, -1],
+            "organization": null,
+            "timing": {"timestamp": 1700000000, "duration_ms": 10},
+            "status": "success",
+            "query_list": [{
+                "sql": "select event_id from sentry_dist",
+                "status": "success",
+                "trace_id": "b".repeat(32),
+                "stats": {"sample": 10, "error_code": 386, "final": true},
+                "profile": {
+                    "time_range": 10,
+                    "all_columns": ["tags", "timestamp"],
+                    "multi_level_condition": false,
+                    "where_profile": {"columns": ["timestamp"], "mapping_cols": ["tags"]},
+                    "groupby_cols": [],
+                    "array_join_cols": [],
+                },
+                "result_profile": {"bytes": 1337, "elapsed": 0.042},
+            }],
+        }))
+        .unwrap();
+
+        let row = serde_json::to_value(process(message).unwrap()).unwrap();
+        assert_eq!(
+            row,
+            json!({
+                "request_id": "aaaaaaaa-aaaa-aaaa-aaaa-aaaaaaaaaaaa",
+                "request_body": r#"{"limit": 100, "sample": 0.1, "selected_columns": ["event_id"]}"#,
+                "referrer": "search",
+                "dataset": "events",
+                "projects": [2],
+                "organization": null,
+                "timestamp": 1700000000,
+                "duration_ms": 10,
+                "status": "success",
+                "clickhouse_queries.sql": ["select event_id from sentry_dist"],
+                "clickhouse_queries.status": ["success"],
+                "clickhouse_queries.trace_id": ["bbbbbbbb-bbbb-bbbb-bbbb-bbbbbbbbbbbb"],
+                "clickhouse_queries.duration_ms": [42],
+                "clickhouse_queries.stats": [
+                    r#"{"error_code": 386, "final": true, "sample": 10}"#
+                ],
+                "clickhouse_queries.final": [1],
+                "clickhouse_queries.cache_hit": [0],
+                "clickhouse_queries.sample": [10.0],
+                "clickhouse_queries.max_threads": [0],
+                "clickhouse_queries.num_days": [10],
+                "clickhouse_queries.clickhouse_table": [""],
+                "clickhouse_queries.query_id": [""],
+                "clickhouse_queries.is_duplicate": [0],
+                "clickhouse_queries.consistent": [0],
+                "clickhouse_queries.all_columns": [["tags", "timestamp"]],
+                "clickhouse_queries.or_conditions": [false],
+                "clickhouse_queries.where_columns": [["timestamp"]],
+                "clickhouse_queries.where_mapping_columns": [["tags"]],
+                "clickhouse_queries.groupby_columns": [[]],
+                "clickhouse_queries.array_join_columns": [[]],
+                "clickhouse_queries.bytes_scanned": [1337],
+            })
+        );
+    }
+}
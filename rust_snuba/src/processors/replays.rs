@@ -0,0 +1,698 @@
+use std::net::IpAddr;
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, Utc};
+use rust_arroyo::backends::kafka::types::KafkaPayload;
+use rust_arroyo::codecs::PayloadFormat;
+use rust_arroyo::processing::strategies::InvalidMessage;
+use rust_arroyo::utils::metrics;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+use crate::processors::utils::{enforce_retention, unicodify, DATETIME_FORMAT};
+use crate::processors::{KafkaMessageMetadata, MessageProcessor};
+use crate::types::InsertBatch;
+
+// Limit for error_ids / trace_ids / urls array elements
+const LIST_ELEMENT_LIMIT: usize = 1000;
+const MAX_CLICK_EVENTS: usize = 20;
+
+/// The Rust port of ``ReplaysProcessor``, which writes a row per segment of
+/// a replay and a row per click of the replay actions.
+pub struct ReplaysProcessor {
+    format: PayloadFormat,
+}
+
+impl ReplaysProcessor {
+    pub fn new(format: PayloadFormat) -> Self {
+        ReplaysProcessor { format }
+    }
+}
+
+#[derive(Deserialize)]
+struct ReplayMessage {
+    payload: Vec<u8>,
+    retention_days: Value,
+    start_time: f64,
+    project_id: u64,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+struct ReplayRow {
+    // The keys are in the order the Python processor sets them.
+    retention_days: u16,
+    project_id: u64,
+    replay_id: String,
+    segment_id: Option<u16>,
+    timestamp: String,
+    replay_start_timestamp: Option<String>,
+    urls: Vec<String>,
+    trace_ids: Vec<String>,
+    error_ids: Vec<String>,
+    release: Option<String>,
+    environment: Option<String>,
+    dist: Option<String>,
+    platform: Option<String>,
+    replay_type: Option<&'static str>,
+    is_archived: Option<u8>,
+    title: String,
+    #[serde(rename = "tags.key")]
+    tag_keys: Vec<String>,
+    #[serde(rename = "tags.value")]
+    tag_values: Vec<String>,
+    sdk_name: Option<String>,
+    sdk_version: Option<String>,
+    partition: u16,
+    offset: u64,
+    user_name: Option<String>,
+    user_id: Option<String>,
+    user_email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip_address_v4: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip_address_v6: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    event_hash: String,
+    /// Only set if the event has contexts.
+    #[serde(flatten)]
+    contexts: Option<ContextColumns>,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+struct ContextColumns {
+    os_name: Option<String>,
+    os_version: Option<String>,
+    browser_name: Option<String>,
+    browser_version: Option<String>,
+    device_name: Option<String>,
+    device_brand: Option<String>,
+    device_family: Option<String>,
+    device_model: Option<String>,
+    error_sample_rate: Option<f64>,
+    session_sample_rate: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct Click {
+    timestamp: Value,
+    event_hash: String,
+    node_id: Value,
+    tag: Value,
+    id: Value,
+    class: Value,
+    text: Value,
+    role: Value,
+    alt: Value,
+    testid: Value,
+    aria_label: Value,
+    title: Value,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct ReplayActionRow {
+    project_id: u64,
+    timestamp: String,
+    replay_id: String,
+    segment_id: Option<u16>,
+    event_hash: String,
+    // Default values for non-nullable columns.
+    trace_ids: Vec<String>,
+    error_ids: Vec<String>,
+    urls: Vec<String>,
+    platform: &'static str,
+    user: Option<String>,
+    sdk_name: Option<String>,
+    sdk_version: Option<String>,
+    retention_days: u16,
+    partition: u16,
+    offset: u64,
+    click_node_id: u32,
+    click_tag: String,
+    click_id: String,
+    click_class: Vec<String>,
+    click_text: String,
+    click_role: String,
+    click_alt: String,
+    click_testid: String,
+    click_aria_label: String,
+    click_title: String,
+}
+
+/// Like ``to_string``: containers and booleans are written as JSON, like
+/// ``rapidjson.dumps`` does, and null is an empty string.
+fn to_string(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Bool(_) | Value::Array(_) | Value::Object(_) => {
+            // Only strings can have characters outside of ASCII, which
+            // ``rapidjson`` escapes.
+            let mut dumps = String::new();
+            for c in value.to_string().chars() {
+                if c.is_ascii() {
+                    dumps.push(c);
+                } else {
+                    let mut units = [0; 2];
+                    for unit in c.encode_utf16(&mut units) {
+                        dumps.push_str(&format!("\\u{:04X}", unit));
+                    }
+                }
+            }
+            dumps
+        }
+        Value::Number(_) | Value::String(_) => unicodify(value).unwrap_or_default(),
+    }
+}
+
+fn maybe_string(value: Option<&Value>) -> Option<String> {
+    value.filter(|value| !value.is_null()).map(to_string)
+}
+
+fn capped_string(value: &Value, capacity: usize) -> String {
+    to_string(value).chars().take(capacity).collect()
+}
+
+/// Like ``int`` in Python, which also parses strings and truncates floats.
+fn to_int(value: &Value) -> Result<i64, anyhow::Error> {
+    match value {
+        Value::Bool(value) => Ok((*value).into()),
+        Value::Number(number) => number
+            .as_i64()
+            .or_else(|| number.as_f64().map(|float| float.trunc() as i64))
+            .with_context(|| format!("Integer \"{}\" overflowed.", number)),
+        Value::String(value) => Ok(value.trim().parse()?),
+        _ => bail!("Invalid integer {}", value),
+    }
+}
+
+fn to_float(value: &Value) -> Result<f64, anyhow::Error> {
+    match value {
+        Value::Bool(value) => Ok(u8::from(*value).into()),
+        Value::Number(number) => number.as_f64().context("Invalid float"),
+        Value::String(value) => Ok(value.trim().parse()?),
+        _ => bail!("Invalid float {}", value),
+    }
+}
+
+fn collapse<T: TryFrom<i64>>(value: i64) -> Result<T, anyhow::Error> {
+    T::try_from(value).map_err(|_| anyhow::anyhow!("Integer \"{}\" overflowed.", value))
+}
+
+/// Datetimes for the replays schema standardize on 32 bit dates.
+fn to_datetime(value: &Value) -> Result<String, anyhow::Error> {
+    let timestamp: u32 = collapse(to_int(value)?)?;
+    let datetime = DateTime::from_timestamp(timestamp.into(), 0).context("Invalid timestamp")?;
+    Ok(datetime.format(DATETIME_FORMAT).to_string())
+}
+
+fn maybe<T>(
+    value: Option<&Value>,
+    into: impl Fn(&Value) -> Result<T, anyhow::Error>,
+) -> Result<Option<T>, anyhow::Error> {
+    value.filter(|value| !value.is_null()).map(into).transpose()
+}
+
+fn to_uuid(value: &Value) -> Result<String, anyhow::Error> {
+    Ok(Uuid::parse_str(&to_string(value))?.to_string())
+}
+
+fn capped_list<T>(metric_name: &str, mut values: Vec<T>) -> Vec<T> {
+    if values.len() > LIST_ELEMENT_LIMIT {
+        metrics::increment(
+            &format!(
+                "replays.processor.\"{}\" exceeded maximum length.",
+                metric_name
+            ),
+            None,
+            None,
+            None,
+        );
+        values.truncate(LIST_ELEMENT_LIMIT);
+    }
+    values
+}
+
+/// The values of a list that are not null, capped to the maximum length.
+fn to_typed_list<T>(
+    metric_name: &str,
+    value: Option<&Value>,
+    into: impl Fn(&Value) -> Result<T, anyhow::Error>,
+) -> Result<Vec<T>, anyhow::Error> {
+    let values = match value {
+        None | Some(Value::Null) => return Ok(Vec::new()),
+        Some(Value::Array(values)) => values,
+        Some(value) => bail!("Invalid type specified. Expected list; received {}", value),
+    };
+    let values = values.iter().take(LIST_ELEMENT_LIMIT + 1).collect();
+    capped_list(metric_name, values)
+        .into_iter()
+        .filter(|value| !value.is_null())
+        .map(into)
+        .collect()
+}
+
+struct Tags {
+    keys: Vec<String>,
+    values: Vec<String>,
+    transaction: Option<String>,
+}
+
+/// Tags are either an object or a list of pairs.
+fn process_tags(value: Option<&Value>) -> Result<Tags, anyhow::Error> {
+    let mut tags = Tags {
+        keys: Vec::new(),
+        values: Vec::new(),
+        transaction: None,
+    };
+    let pairs: Vec<(Value, &Value)> = match value {
+        None | Some(Value::Null) => return Ok(tags),
+        Some(Value::Object(object)) => object
+            .iter()
+            .map(|(key, value)| (Value::String(key.clone()), value))
+            .collect(),
+        Some(Value::Array(items)) => items
+            .iter()
+            .filter_map(|item| match item.as_array()?.as_slice() {
+                [key, value] => Some((key.clone(), value)),
+                _ => None,
+            })
+            .collect(),
+        Some(value) => bail!("Invalid tags type specified: {}", value),
+    };
+
+    for (key, value) in capped_list("tags", pairs) {
+        let value = maybe_string(Some(value));
+        if key.as_str() == Some("transaction") {
+            tags.transaction = value;
+        } else if let Some(value) = value {
+            tags.keys.push(to_string(&key));
+            tags.values.push(value);
+        }
+    }
+    Ok(tags)
+}
+
+fn get_object<'a>(object: &'a Map<String, Value>, key: &str) -> Option<&'a Map<String, Value>> {
+    object.get(key).and_then(Value::as_object)
+}
+
+/// ``_ensure_valid_ip``, addresses can be invalid after PII stripping.
+fn ensure_valid_ip(value: Option<&Value>) -> Option<IpAddr> {
+    unicodify(value?)?.parse().ok()
+}
+
+fn segment_id_to_event_hash(segment_id: &Value) -> Result<String, anyhow::Error> {
+    if segment_id.is_null() {
+        // Rows with null segment_id fields are considered "out of band" meaning they do not
+        // originate from the SDK and do not relate to a specific segment.
+        //
+        // For example: archive requests.
+        return Ok(Uuid::new_v4().simple().to_string());
+    }
+    let digest = md5(to_string(segment_id).as_bytes());
+    Ok(Uuid::from_bytes(digest).to_string())
+}
+
+fn process_replay_event(
+    replay_event: &Map<String, Value>,
+    mut row: ReplayRow,
+) -> Result<ReplayRow, anyhow::Error> {
+    row.replay_id = to_uuid(replay_event.get("replay_id").context("Missing replay_id")?)?;
+    row.segment_id = maybe(replay_event.get("segment_id"), |value| {
+        collapse(to_int(value)?)
+    })?;
+    row.timestamp = match maybe(replay_event.get("timestamp"), to_datetime)? {
+        Some(timestamp) => timestamp,
+        None => Utc::now().format(DATETIME_FORMAT).to_string(),
+    };
+    row.replay_start_timestamp = maybe(replay_event.get("replay_start_timestamp"), to_datetime)?;
+    row.urls = to_typed_list("urls", replay_event.get("urls"), |value| {
+        Ok(to_string(value))
+    })?;
+    row.trace_ids = to_typed_list("trace_ids", replay_event.get("trace_ids"), to_uuid)?;
+    row.error_ids = to_typed_list("error_ids", replay_event.get("error_ids"), to_uuid)?;
+    row.release = maybe_string(replay_event.get("release"));
+    row.environment = maybe_string(replay_event.get("environment"));
+    row.dist = maybe_string(replay_event.get("dist"));
+    row.platform = maybe_string(Some(
+        replay_event.get("platform").context("Missing platform")?,
+    ));
+    row.replay_type = match replay_event.get("replay_type").and_then(Value::as_str) {
+        Some("session") => Some("session"),
+        Some("error") => Some("error"),
+        _ => None,
+    };
+    // Archived can only be 1 or null.
+    row.is_archived = match replay_event.get("is_archived") {
+        Some(Value::Bool(true)) => Some(1),
+        _ => None,
+    };
+
+    let tags = process_tags(replay_event.get("tags"))?;
+    // we have to set title to empty string as it is non-nullable,
+    // and on clickhouse 20 this throws an error.
+    row.title = tags.transaction.unwrap_or_default();
+    row.tag_keys = tags.keys;
+    row.tag_values = tags.values;
+
+    if let Some(sdk) = get_object(replay_event, "sdk") {
+        row.sdk_name = maybe_string(sdk.get("name"));
+        row.sdk_version = maybe_string(sdk.get("version"));
+    }
+
+    let empty = Map::new();
+    let user = get_object(replay_event, "user").unwrap_or(&empty);
+    row.user_id = user.get("id").and_then(unicodify);
+    row.user_name = user.get("username").and_then(unicodify);
+    row.user_email = user.get("email").and_then(unicodify);
+    let ip_address = ensure_valid_ip(user.get("ip_address"));
+    match ip_address {
+        Some(IpAddr::V4(ip_address)) => row.ip_address_v4 = Some(ip_address.to_string()),
+        Some(IpAddr::V6(ip_address)) => row.ip_address_v6 = Some(ip_address.to_string()),
+        None => {}
+    }
+    row.user = [
+        row.user_id.clone(),
+        row.user_name.clone(),
+        row.user_email.clone(),
+        ip_address.map(|ip_address| ip_address.to_string()),
+    ]
+    .into_iter()
+    .flatten()
+    .find(|user| !user.is_empty());
+
+    row.event_hash = match replay_event
+        .get("event_hash")
+        .filter(|value| !value.is_null())
+    {
+        Some(event_hash) => to_string(event_hash),
+        None => segment_id_to_event_hash(
+            replay_event
+                .get("segment_id")
+                .context("Missing segment_id")?,
+        )?,
+    };
+
+    let contexts = get_object(replay_event, "contexts").filter(|contexts| !contexts.is_empty());
+    if let Some(contexts) = contexts {
+        let context = |name| get_object(contexts, name).unwrap_or(&empty);
+        let (os, browser, device) = (context("os"), context("browser"), context("device"));
+        let replay = context("replay");
+        row.contexts = Some(ContextColumns {
+            os_name: maybe_string(os.get("name")),
+            os_version: maybe_string(os.get("version")),
+            browser_name: maybe_string(browser.get("name")),
+            browser_version: maybe_string(browser.get("version")),
+            device_name: maybe_string(device.get("name")),
+            device_brand: maybe_string(device.get("brand")),
+            device_family: maybe_string(device.get("family")),
+            device_model: maybe_string(device.get("model")),
+            error_sample_rate: maybe(replay.get("error_sample_rate"), to_float)?,
+            session_sample_rate: maybe(replay.get("session_sample_rate"), to_float)?,
+        });
+    }
+    Ok(row)
+}
+
+fn process_replay_actions(
+    replay_event: &Map<String, Value>,
+    message: &ReplayMessage,
+    retention_days: u16,
+    metadata: &KafkaMessageMetadata,
+) -> Result<Vec<ReplayActionRow>, anyhow::Error> {
+    let replay_id = to_uuid(replay_event.get("replay_id").context("Missing replay_id")?)?;
+    let clicks = replay_event
+        .get("clicks")
+        .and_then(Value::as_array)
+        .context("Missing clicks")?;
+    clicks
+        .iter()
+        .take(MAX_CLICK_EVENTS)
+        .map(|click| {
+            let click = Click::deserialize(click)?;
+            let classes = match &click.class {
+                Value::Array(classes) => classes.as_slice(),
+                _ => bail!("Invalid click class {}", click.class),
+            };
+            Ok(ReplayActionRow {
+                project_id: message.project_id,
+                timestamp: maybe(Some(&click.timestamp), to_datetime)?
+                    .context("Missing data for required field: timestamp")?,
+                replay_id: replay_id.clone(),
+                segment_id: None,
+                event_hash: click.event_hash,
+                trace_ids: Vec::new(),
+                error_ids: Vec::new(),
+                urls: Vec::new(),
+                platform: "javascript",
+                user: None,
+                sdk_name: None,
+                sdk_version: None,
+                retention_days,
+                partition: metadata.partition.index,
+                offset: metadata.offset,
+                click_node_id: collapse(to_int(&click.node_id)?)?,
+                click_tag: capped_string(&click.tag, 32),
+                click_id: capped_string(&click.id, 64),
+                click_class: classes
+                    .iter()
+                    .take(10)
+                    .filter(|class| !class.is_null())
+                    .map(|class| capped_string(class, 64))
+                    .collect(),
+                click_text: capped_string(&click.text, 1024),
+                click_role: capped_string(&click.role, 32),
+                click_alt: capped_string(&click.alt, 64),
+                click_testid: capped_string(&click.testid, 64),
+                click_aria_label: capped_string(&click.aria_label, 64),
+                click_title: capped_string(&click.title, 64),
+            })
+        })
+        .collect()
+}
+
+/// Returns the rows of the message, none if the replay is older than its
+/// retention.
+fn process(
+    message: ReplayMessage,
+    metadata: &KafkaMessageMetadata,
+) -> Result<InsertBatch, anyhow::Error> {
+    let replay_event: Map<String, Value> = serde_json::from_slice(&message.payload)?;
+    let start_time = DateTime::from_timestamp_millis((message.start_time * 1000.0) as i64)
+        .context("Invalid start_time")?
+        .naive_utc();
+    let Ok(retention_days) = enforce_retention(Some(&message.retention_days), start_time) else {
+        return Ok(InsertBatch::skip());
+    };
+
+    if replay_event.get("type").and_then(Value::as_str) == Some("replay_actions") {
+        let rows = process_replay_actions(&replay_event, &message, retention_days, metadata)?;
+        return Ok(InsertBatch::from_rows(rows)?);
+    }
+
+    let row = ReplayRow {
+        project_id: message.project_id,
+        retention_days,
+        partition: metadata.partition.index,
+        offset: metadata.offset,
+        ..Default::default()
+    };
+    let row = process_replay_event(&replay_event, row)?;
+    Ok(InsertBatch::from_rows([row])?)
+}
+
+impl MessageProcessor for ReplaysProcessor {
+    fn process_message(
+        &self,
+        payload: KafkaPayload,
+        metadata: KafkaMessageMetadata,
+    ) -> Result<InsertBatch, InvalidMessage> {
+        let payload = payload.payload.unwrap_or_default();
+        self.format
+            .decode(&payload)
+            .map_err(anyhow::Error::from)
+            .and_then(|message| process(message, &metadata))
+            .map_err(|error| {
+                metrics::increment("replays.processor.consumer_error", None, None, None);
+                metadata.invalid(error)
+            })
+    }
+}
+
+/// The MD5 digest of some data, to hash the segment ids like ``hashlib.md5``.
+fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 64] = [
+        7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5,
+        9, 14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10,
+        15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+    ];
+    let constants: Vec<u32> = (0..64)
+        .map(|i: i32| (f64::from(i + 1).sin().abs() * 4294967296.0) as u32)
+        .collect();
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend(((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for chunk in message.chunks(64) {
+        let words: Vec<u32> = chunk
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f
+                .wrapping_add(a)
+                .wrapping_add(constants[i])
+                .wrapping_add(words[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(SHIFTS[i]));
+        }
+        for (value, new) in state.iter_mut().zip([a, b, c, d]) {
+            *value = value.wrapping_add(new);
+        }
+    }
+
+    let mut digest = [0; 16];
+    for (bytes, value) in digest.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_le_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{md5, process, to_string, ReplayMessage};
+    use crate::processors::KafkaMessageMetadata;
+    use chrono::Utc;
+    use rust_arroyo::types::{Partition, Topic};
+    use serde_json::{json, Value};
+
+    fn metadata() -> KafkaMessageMetadata {
+        KafkaMessageMetadata {
+            partition: Partition {
+                topic: Topic {
+                    name: "ingest-replay-events".to_string(),
+                },
+                index: 0,
+            },
+            offset: 7,
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn rows(replay_event: Value) -> Vec<Value> {
+        let message = ReplayMessage {
+            payload: serde_json::to_vec(&replay_event).unwrap(),
+            retention_days: json!(30),
+            start_time: Utc::now().timestamp() as f64,
+            project_id: 1,
+        };
+        process(message, &metadata())
+            .unwrap()
+            .rows
+            .iter()
+            .map(|row| serde_json::from_slice(row).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_md5() {
+        let hex = |digest: [u8; 16]| {
+            digest
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>()
+        };
+        assert_eq!(hex(md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(md5(b"0")), "cfcd208495d565ef66e7dff9f98764da");
+    }
+
+    #[test]
+    fn test_to_string() {
+        assert_eq!(to_string(&json!(null)), "");
+        assert_eq!(to_string(&json!(true)), "true");
+        assert_eq!(to_string(&json!(1.5)), "1.5");
+        assert_eq!(to_string(&json!({"a": ["\u{e9}"]})), r#"{"a":["\u00E9"]}"#);
+    }
+
+    #[test]
+    fn test_replay_event() {
+        let rows = rows(json!({
+            "type": "replay_event",
+            "replay_id": "e5e062bf2e1d4afd96fd2f90b6770431",
+            "segment_id": 0,
+            "timestamp": 1_696_163_445,
+            "urls": ["http://localhost:3000", null],
+            "error_ids": ["df11e6d952da470386a64340f13151c4"],
+            "trace_ids": [],
+            "platform": "javascript",
+            "replay_type": "unknown",
+            "tags": [["transaction", "/"], ["isSdk", true], ["skipped", null]],
+            "user": {"username": "me", "ip_address": "127.0.0.1"},
+            "contexts": {"os": {"name": "macOS"}, "replay": {"error_sample_rate": "0.5"}},
+        }));
+        assert_eq!(rows.len(), 1);
+        let row = &rows[0];
+        assert_eq!(row["replay_id"], "e5e062bf-2e1d-4afd-96fd-2f90b6770431");
+        assert_eq!(row["timestamp"], "2023-10-01 12:30:45");
+        assert_eq!(row["urls"], json!(["http://localhost:3000"]));
+        assert_eq!(
+            row["error_ids"],
+            json!(["df11e6d9-52da-4703-86a6-4340f13151c4"])
+        );
+        assert_eq!(row["replay_type"], Value::Null);
+        assert_eq!(row["title"], "/");
+        assert_eq!(row["tags.key"], json!(["isSdk"]));
+        assert_eq!(row["tags.value"], json!(["true"]));
+        assert_eq!(row["user"], "me");
+        assert_eq!(row["ip_address_v4"], "127.0.0.1");
+        assert_eq!(row["os_name"], "macOS");
+        assert_eq!(row["error_sample_rate"], 0.5);
+        // The md5 of the segment id
+        assert_eq!(row["event_hash"], "cfcd2084-95d5-65ef-66e7-dff9f98764da");
+        assert_eq!(row["offset"], 7);
+    }
+
+    #[test]
+    fn test_replay_actions() {
+        let click = json!({
+            "timestamp": 1_696_163_445,
+            "event_hash": "df3c3aa2daae465e89f1169e49139827",
+            "node_id": 59,
+            "tag": "div",
+            "id": "id",
+            "class": ["class1", "class2"],
+            "text": "text".repeat(300),
+            "role": "button",
+            "alt": "",
+            "testid": "",
+            "aria_label": null,
+            "title": "title",
+        });
+        let rows = rows(json!({
+            "type": "replay_actions",
+            "replay_id": "e5e062bf2e1d4afd96fd2f90b6770431",
+            "clicks": vec![click; 25],
+        }));
+        assert_eq!(rows.len(), 20);
+        assert_eq!(rows[0]["click_node_id"], 59);
+        assert_eq!(rows[0]["click_class"], json!(["class1", "class2"]));
+        assert_eq!(rows[0]["click_text"].as_str().unwrap().len(), 1024);
+        assert_eq!(rows[0]["click_aria_label"], "");
+        assert_eq!(rows[0]["platform"], "javascript");
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use chrono::{DateTime, NaiveDateTime};
+use rust_arroyo::backends::kafka::types::KafkaPayload;
+use rust_arroyo::codecs::PayloadFormat;
+use rust_arroyo::processing::strategies::InvalidMessage;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::processors::utils::{enforce_retention, ensure_valid_date, unicodify, DATETIME_FORMAT};
+use crate::processors::{KafkaMessageMetadata, MessageProcessor};
+use crate::types::InsertBatch;
+
+/// ``SPAN_STATUS_NAME_TO_CODE`` of Relay.
+const SPAN_STATUS_NAME_TO_CODE: &[(&str, u8)] = &[
+    ("ok", 0),
+    ("cancelled", 1),
+    ("unknown", 2),
+    ("unknown_error", 2),
+    ("invalid_argument", 3),
+    ("deadline_exceeded", 4),
+    ("not_found", 5),
+    ("already_exists", 6),
+    ("permission_denied", 7),
+    ("resource_exhausted", 8),
+    ("failed_precondition", 9),
+    ("aborted", 10),
+    ("out_of_range", 11),
+    ("unimplemented", 12),
+    ("internal_error", 13),
+    ("unavailable", 14),
+    ("data_loss", 15),
+    ("unauthenticated", 16),
+];
+
+const UNKNOWN_SPAN_STATUS: u8 = 2;
+
+/// Writes the spans Relay extracts from transactions and produces to the
+/// spans topic, a row per span.
+pub struct SpansProcessor {
+    format: PayloadFormat,
+}
+
+impl SpansProcessor {
+    pub fn new(format: PayloadFormat) -> Self {
+        SpansProcessor { format }
+    }
+}
+
+#[derive(Deserialize)]
+struct FromSpanMessage {
+    #[serde(default)]
+    description: Option<String>,
+    duration_ms: u32,
+    #[serde(default)]
+    event_id: Option<Uuid>,
+    exclusive_time_ms: f64,
+    is_segment: bool,
+    #[serde(default)]
+    parent_span_id: Option<String>,
+    project_id: u64,
+    #[serde(default)]
+    retention_days: Option<Value>,
+    #[serde(default)]
+    segment_id: Option<String>,
+    #[serde(default)]
+    sentry_tags: BTreeMap<String, Value>,
+    span_id: String,
+    start_timestamp_ms: u64,
+    #[serde(default)]
+    tags: Option<BTreeMap<String, Value>>,
+    trace_id: Uuid,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct SpanRow {
+    project_id: u64,
+    transaction_id: Option<String>,
+    trace_id: String,
+    span_id: u64,
+    parent_span_id: u64,
+    segment_id: u64,
+    is_segment: u8,
+    segment_name: String,
+    start_timestamp: String,
+    start_ms: u16,
+    end_timestamp: String,
+    end_ms: u16,
+    duration: u32,
+    exclusive_time: f64,
+    op: String,
+    group: u64,
+    span_status: u8,
+    span_kind: String,
+    description: String,
+    status: Option<u32>,
+    module: String,
+    action: String,
+    domain: String,
+    platform: String,
+    user: String,
+    transaction_op: Option<String>,
+    #[serde(rename = "tags.key")]
+    tag_keys: Vec<String>,
+    #[serde(rename = "tags.value")]
+    tag_values: Vec<String>,
+    #[serde(rename = "sentry_tags.key")]
+    sentry_tag_keys: Vec<String>,
+    #[serde(rename = "sentry_tags.value")]
+    sentry_tag_values: Vec<String>,
+    retention_days: u16,
+    partition: u16,
+    offset: u64,
+    deleted: u8,
+}
+
+/// Ids are sent as hex strings and stored as integers.
+fn hex_to_u64(value: &str) -> Result<u64, anyhow::Error> {
+    u64::from_str_radix(value, 16).with_context(|| format!("Invalid hex id {:?}", value))
+}
+
+fn optional_hex_to_u64(value: Option<&str>) -> Result<u64, anyhow::Error> {
+    value.map_or(Ok(0), hex_to_u64)
+}
+
+fn split_timestamp(timestamp_ms: u64) -> Result<(NaiveDateTime, u16), anyhow::Error> {
+    let timestamp = i64::try_from(timestamp_ms)
+        .ok()
+        .and_then(DateTime::from_timestamp_millis)
+        .map(|timestamp| timestamp.naive_utc())
+        .and_then(ensure_valid_date)
+        .context("Invalid timestamp")?;
+    Ok((timestamp, (timestamp_ms % 1000) as u16))
+}
+
+fn keys_and_values(tags: &BTreeMap<String, Value>) -> (Vec<String>, Vec<String>) {
+    tags.iter()
+        .filter_map(|(key, value)| Some((key.clone(), unicodify(value)?)))
+        .unzip()
+}
+
+/// Returns ``None`` for spans that are older than their retention.
+fn process(
+    message: FromSpanMessage,
+    metadata: &KafkaMessageMetadata,
+) -> Result<Option<SpanRow>, anyhow::Error> {
+    let (start_timestamp, start_ms) = split_timestamp(message.start_timestamp_ms)?;
+    let (end_timestamp, end_ms) =
+        split_timestamp(message.start_timestamp_ms + u64::from(message.duration_ms))?;
+    let Ok(retention_days) = enforce_retention(message.retention_days.as_ref(), start_timestamp)
+    else {
+        return Ok(None);
+    };
+
+    // The tags Relay extracts from the span are promoted to their own columns.
+    let sentry_tag = |key: &str| {
+        message
+            .sentry_tags
+            .get(key)
+            .and_then(unicodify)
+            .unwrap_or_default()
+    };
+    let span_status = message
+        .sentry_tags
+        .get("status")
+        .and_then(Value::as_str)
+        .and_then(|status| {
+            SPAN_STATUS_NAME_TO_CODE
+                .iter()
+                .find(|(name, _)| *name == status)
+        })
+        .map_or(UNKNOWN_SPAN_STATUS, |(_, code)| *code);
+    let group = match message.sentry_tags.get("group").and_then(Value::as_str) {
+        Some(group) => hex_to_u64(group)?,
+        None => 0,
+    };
+    let transaction_op = message
+        .sentry_tags
+        .get("transaction.op")
+        .and_then(unicodify);
+    let (sentry_tag_keys, sentry_tag_values) = keys_and_values(&message.sentry_tags);
+    let (tag_keys, tag_values) = keys_and_values(&message.tags.unwrap_or_default());
+
+    Ok(Some(SpanRow {
+        project_id: message.project_id,
+        transaction_id: message.event_id.map(|event_id| event_id.to_string()),
+        trace_id: message.trace_id.to_string(),
+        span_id: hex_to_u64(&message.span_id)?,
+        parent_span_id: optional_hex_to_u64(message.parent_span_id.as_deref())?,
+        segment_id: optional_hex_to_u64(message.segment_id.as_deref())?,
+        is_segment: message.is_segment.into(),
+        segment_name: sentry_tag("transaction"),
+        start_timestamp: start_timestamp.format(DATETIME_FORMAT).to_string(),
+        start_ms,
+        end_timestamp: end_timestamp.format(DATETIME_FORMAT).to_string(),
+        end_ms,
+        duration: message.duration_ms,
+        exclusive_time: message.exclusive_time_ms,
+        op: sentry_tag("op"),
+        group,
+        span_status,
+        span_kind: String::new(),
+        description: message.description.unwrap_or_default(),
+        status: sentry_tag("status_code").parse().ok(),
+        module: sentry_tag("module"),
+        action: sentry_tag("action"),
+        domain: sentry_tag("domain"),
+        platform: sentry_tag("system"),
+        user: sentry_tag("user"),
+        transaction_op,
+        tag_keys,
+        tag_values,
+        sentry_tag_keys,
+        sentry_tag_values,
+        retention_days,
+        partition: metadata.partition.index,
+        offset: metadata.offset,
+        deleted: 0,
+    }))
+}
+
+impl MessageProcessor for SpansProcessor {
+    fn process_message(
+        &self,
+        payload: KafkaPayload,
+        metadata: KafkaMessageMetadata,
+    ) -> Result<InsertBatch, InvalidMessage> {
+        let payload = payload.payload.unwrap_or_default();
+        let message: FromSpanMessage = self
+            .format
+            .decode(&payload)
+            .map_err(|error| metadata.invalid(error))?;
+        let row = process(message, &metadata).map_err(|error| metadata.invalid(error))?;
+        InsertBatch::from_rows(row).map_err(|error| metadata.invalid(error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{process, FromSpanMessage};
+    use crate::processors::KafkaMessageMetadata;
+    use chrono::Utc;
+    use rust_arroyo::types::{Partition, Topic};
+    use serde_json::{json, Value};
+
+    fn message(fields: Value) -> FromSpanMessage {
+        let mut message = json!({
+            "duration_ms": 1500,
+            "event_id": "dcc403b73ef548648188bbfa6012e9dc",
+            "exclusive_time_ms": 0.228,
+            "is_segment": false,
+            "parent_span_id": "deadbeefdeadbeef",
+            "project_id": 1,
+            "retention_days": 90,
+            "segment_id": "deadbeefdeadbeef",
+            "sentry_tags": {
+                "group": "ff",
+                "op": "http.client",
+                "status": "not_found",
+                "status_code": "404",
+                "system": "python",
+                "transaction": "/api/0/relays/projectconfigs/",
+            },
+            "span_id": "bbbbbbbbbbbbbbbb",
+            "start_timestamp_ms": Utc::now().timestamp_millis() / 1000 * 1000 + 250,
+            "tags": {"tag1": "value1", "tag2": 123},
+            "trace_id": "6f2a27f8-9ad7-4b8b-9d57-e02e1bb1a5ee",
+        });
+        message
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        serde_json::from_value(message).unwrap()
+    }
+
+    fn metadata() -> KafkaMessageMetadata {
+        KafkaMessageMetadata {
+            partition: Partition {
+                topic: Topic {
+                    name: "snuba-spans".to_string(),
+                },
+                index: 2,
+            },
+            offset: 10,
+            timestamp: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_process() {
+        let row = process(message(json!({})), &metadata()).unwrap().unwrap();
+        assert_eq!(row.span_id, 0xbbbbbbbbbbbbbbbb);
+        assert_eq!(row.parent_span_id, 0xdeadbeefdeadbeef);
+        assert_eq!(row.group, 255);
+        assert_eq!(row.segment_name, "/api/0/relays/projectconfigs/");
+        assert_eq!(row.span_status, 5);
+        assert_eq!(row.status, Some(404));
+        assert_eq!(row.platform, "python");
+        assert_eq!((row.start_ms, row.end_ms), (250, 750));
+        assert_eq!(row.duration, 1500);
+        assert_eq!(row.tag_keys, vec!["tag1", "tag2"]);
+        assert_eq!(row.tag_values, vec!["value1", "123"]);
+        assert_eq!(
+            row.transaction_id.as_deref(),
+            Some("dcc403b7-3ef5-4864-8188-bbfa6012e9dc")
+        );
+        assert_eq!((row.partition, row.offset), (2, 10));
+
+        // Segments have no parent
+        let row = process(
+            message(json!({"parent_span_id": null, "sentry_tags": {}})),
+            &metadata(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(row.parent_span_id, 0);
+        assert_eq!(row.span_status, 2);
+
+        assert!(process(message(json!({"span_id": "invalid"})), &metadata()).is_err());
+    }
+}
@@ -0,0 +1,244 @@
+use std::io;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use serde::Serialize;
+use serde_json::ser::{CharEscape, CompactFormatter, Formatter, Serializer};
+use serde_json::Value;
+
+/// How datetimes are written in rows, like ``DATETIME_FORMAT`` of
+/// ``snuba.clickhouse``.
+pub const DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Writes JSON like ``json.dumps`` does with its default arguments: with a
+/// space after separators, every character outside of printable ASCII
+/// escaped, and floats written like ``repr``.
+struct PythonFormatter;
+
+impl Formatter for PythonFormatter {
+    fn begin_array_value<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> io::Result<()> {
+        if first {
+            Ok(())
+        } else {
+            writer.write_all(b", ")
+        }
+    }
+
+    fn begin_object_key<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> io::Result<()> {
+        if first {
+            Ok(())
+        } else {
+            writer.write_all(b", ")
+        }
+    }
+
+    fn begin_object_value<W: ?Sized + io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(b": ")
+    }
+
+    fn write_string_fragment<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        fragment: &str,
+    ) -> io::Result<()> {
+        for c in fragment.chars() {
+            if c.is_ascii() && c != '\x7f' {
+                writer.write_all(&[c as u8])?;
+            } else {
+                let mut units = [0; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    write!(writer, "\\u{:04x}", unit)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_char_escape<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        char_escape: CharEscape,
+    ) -> io::Result<()> {
+        CompactFormatter.write_char_escape(writer, char_escape)
+    }
+
+    fn write_f64<W: ?Sized + io::Write>(&mut self, writer: &mut W, value: f64) -> io::Result<()> {
+        writer.write_all(python_float_repr(value).as_bytes())
+    }
+}
+
+/// Formats a float like ``repr`` does in Python: the shortest digits that
+/// round trip, in scientific notation below ``1e-4`` and from ``1e16``.
+fn python_float_repr(value: f64) -> String {
+    if value == 0.0 {
+        return if value.is_sign_negative() {
+            "-0.0"
+        } else {
+            "0.0"
+        }
+        .to_string();
+    }
+    // Such as ``-1.2345e3``.
+    let scientific = format!("{:e}", value);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    let (sign, mantissa) = match mantissa.strip_prefix('-') {
+        Some(mantissa) => ("-", mantissa),
+        None => ("", mantissa),
+    };
+    let digits = mantissa.replace('.', "");
+
+    if !(-4..16).contains(&exponent) {
+        let mantissa = match digits.split_at(1) {
+            (first, "") => first.to_string(),
+            (first, rest) => format!("{}.{}", first, rest),
+        };
+        let exponent_sign = if exponent < 0 { '-' } else { '+' };
+        return format!(
+            "{}{}e{}{:02}",
+            sign,
+            mantissa,
+            exponent_sign,
+            exponent.abs()
+        );
+    }
+
+    let point = exponent + 1;
+    if point <= 0 {
+        let zeros = "0".repeat(-point as usize);
+        format!("{}0.{}{}", sign, zeros, digits)
+    } else if point as usize >= digits.len() {
+        let zeros = "0".repeat(point as usize - digits.len());
+        format!("{}{}{}.0", sign, digits, zeros)
+    } else {
+        let (integer, fraction) = digits.split_at(point as usize);
+        format!("{}{}.{}", sign, integer, fraction)
+    }
+}
+
+/// Serializes a value to the same string as ``json.dumps`` in Python, as
+/// long as maps keep the order of their keys.
+pub fn python_json_dumps<T: Serialize + ?Sized>(value: &T) -> String {
+    let mut out = Vec::new();
+    let mut serializer = Serializer::with_formatter(&mut out, PythonFormatter);
+    value.serialize(&mut serializer).unwrap();
+    // Only ASCII is written
+    String::from_utf8(out).unwrap()
+}
+
+/// Like ``_ensure_valid_date``, dates that do not fit in a ``DateTime``
+/// column are dropped.
+pub fn ensure_valid_date(date: NaiveDateTime) -> Option<NaiveDateTime> {
+    let seconds = date.and_utc().timestamp();
+    u32::try_from(seconds).ok().map(|_| date)
+}
+
+// The retention settings of the default configuration.
+const ENFORCE_RETENTION: bool = false;
+const LOWER_RETENTION_DAYS: u16 = 30;
+const DEFAULT_RETENTION_DAYS: u16 = 90;
+const VALID_RETENTION_DAYS: &[u16] = &[30, 90];
+const DISCARD_OLD_EVENTS: bool = true;
+
+/// The event is older than its retention and should not be written.
+#[derive(Debug, PartialEq)]
+pub struct EventTooOld;
+
+/// Like ``enforce_retention`` of ``snuba.datasets.events_format``, returns
+/// the retention of the event, the default if it is not an integer.
+pub fn enforce_retention(
+    retention_days: Option<&Value>,
+    timestamp: NaiveDateTime,
+) -> Result<u16, EventTooOld> {
+    let mut retention_days = retention_days
+        .and_then(Value::as_u64)
+        .map_or(DEFAULT_RETENTION_DAYS, |days| {
+            days.try_into().unwrap_or(u16::MAX)
+        });
+    if ENFORCE_RETENTION && !VALID_RETENTION_DAYS.contains(&retention_days) {
+        retention_days = match retention_days <= LOWER_RETENTION_DAYS {
+            true => LOWER_RETENTION_DAYS,
+            false => DEFAULT_RETENTION_DAYS,
+        };
+    }
+
+    let now = Utc::now().naive_utc();
+    let timestamp = ensure_valid_date(timestamp).unwrap_or(now);
+    if DISCARD_OLD_EVENTS && timestamp < now - Duration::days(retention_days.into()) {
+        return Err(EventTooOld);
+    }
+    Ok(retention_days)
+}
+
+/// Like ``_unicodify``, turns any value into a string.
+pub fn unicodify(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(value) => Some(value.clone()),
+        Value::Bool(true) => Some("True".to_string()),
+        Value::Bool(false) => Some("False".to_string()),
+        Value::Number(value) => Some(match value.as_f64() {
+            Some(float) if value.is_f64() => python_float_repr(float),
+            _ => value.to_string(),
+        }),
+        Value::Array(_) | Value::Object(_) => Some(python_json_dumps(value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{enforce_retention, python_float_repr, python_json_dumps, unicodify, EventTooOld};
+    use chrono::{Duration, Utc};
+    use serde_json::json;
+
+    #[test]
+    fn test_python_json_dumps() {
+        let value = json!({
+            "b": [1, 2.5, null, true],
+            "a": {"emoji": "h\u{e9}\u{1f600}\n", "empty": {}},
+        });
+        assert_eq!(
+            python_json_dumps(&value),
+            r#"{"b": [1, 2.5, null, true], "a": {"emoji": "h\u00e9\ud83d\ude00\n", "empty": {}}}"#
+        );
+
+        for (value, repr) in [
+            (1.0, "1.0"),
+            (0.1, "0.1"),
+            (-123.456, "-123.456"),
+            (0.0001, "0.0001"),
+            (0.00001, "1e-05"),
+            (1e15, "1000000000000000.0"),
+            (1e16, "1e+16"),
+            (1.5e300, "1.5e+300"),
+        ] {
+            assert_eq!(python_float_repr(value), repr);
+        }
+    }
+
+    #[test]
+    fn test_enforce_retention() {
+        let now = Utc::now().naive_utc();
+        assert_eq!(enforce_retention(Some(&json!(30)), now), Ok(30));
+        assert_eq!(enforce_retention(Some(&json!("30")), now), Ok(90));
+        assert_eq!(
+            enforce_retention(None, now - Duration::days(100)),
+            Err(EventTooOld)
+        );
+    }
+
+    #[test]
+    fn test_unicodify() {
+        assert_eq!(unicodify(&json!(null)), None);
+        assert_eq!(unicodify(&json!(true)).as_deref(), Some("True"));
+        assert_eq!(unicodify(&json!(1.0)).as_deref(), Some("1.0"));
+        assert_eq!(unicodify(&json!(["a", 1])).as_deref(), Some(r#"["a", 1]"#));
+    }
+}
@@ -0,0 +1,133 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use rust_arroyo::processing::strategies::commit_policy::CommitPolicy;
+use serde::Deserialize;
+
+/// How often the runtime config file is read.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The settings that can be changed on a running consumer, by editing the
+/// runtime config file. Settings that are not set, or a file that does not
+/// exist, fall back to the consumer config.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RuntimeConfig {
+    pub max_batch_size: Option<usize>,
+    pub max_batch_time_ms: Option<u64>,
+    /// How often offsets are committed. They are committed after every
+    /// insert if not set.
+    pub commit_frequency_ms: Option<u64>,
+    /// The consumer is paused while this many consumed messages are not
+    /// committed yet. Not limited if not set.
+    pub max_in_flight_messages: Option<u64>,
+}
+
+impl RuntimeConfig {
+    /// Reads the file, in YAML or JSON.
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        match fs::read_to_string(path) {
+            Ok(payload) if payload.trim().is_empty() => Ok(RuntimeConfig::default()),
+            Ok(payload) => Ok(serde_yaml::from_str(&payload)?),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(RuntimeConfig::default()),
+            Err(error) => Err(error.into()),
+        }
+    }
+}
+
+/// The latest runtime config, shared by the poller and the strategies which
+/// read it on every poll.
+#[derive(Clone, Debug, Default)]
+pub struct RuntimeConfigHandle {
+    current: Arc<RwLock<RuntimeConfig>>,
+}
+
+impl RuntimeConfigHandle {
+    pub fn get(&self) -> RuntimeConfig {
+        self.current.read().unwrap().clone()
+    }
+
+    fn reload(&self, path: &Path) {
+        let config = match RuntimeConfig::load(path) {
+            Ok(config) => config,
+            Err(error) => {
+                log::warn!("Invalid runtime config {}: {:#}", path.display(), error);
+                return;
+            }
+        };
+        let mut current = self.current.write().unwrap();
+        if *current != config {
+            log::info!("Runtime config changed to {:?}", config);
+            *current = config;
+        }
+    }
+
+    /// Reads ``path`` now and then every ``POLL_INTERVAL`` from a thread of
+    /// its own. A file that can not be read or parsed is logged and the
+    /// previous config kept.
+    pub fn poll_file(path: PathBuf) -> Self {
+        let handle = RuntimeConfigHandle::default();
+        handle.reload(&path);
+
+        let polled = handle.clone();
+        thread::Builder::new()
+            .name("runtime-config".to_string())
+            .spawn(move || loop {
+                thread::sleep(POLL_INTERVAL);
+                polled.reload(&path);
+            })
+            .unwrap();
+        handle
+    }
+}
+
+/// Commits at the ``commit_frequency_ms`` of the runtime config, as soon as
+/// there is something to commit while it is not set.
+pub struct RuntimeCommitPolicy(pub RuntimeConfigHandle);
+
+impl CommitPolicy for RuntimeCommitPolicy {
+    fn should_commit(&self, elapsed: Duration, _: u64) -> bool {
+        match self.0.get().commit_frequency_ms {
+            Some(commit_frequency_ms) => elapsed >= Duration::from_millis(commit_frequency_ms),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RuntimeCommitPolicy, RuntimeConfig, RuntimeConfigHandle};
+    use rust_arroyo::processing::strategies::commit_policy::CommitPolicy;
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn test_runtime_config() {
+        let path = std::env::temp_dir().join("snuba-test-runtime-config.yaml");
+        let _ = fs::remove_file(&path);
+        assert_eq!(
+            RuntimeConfig::load(&path).unwrap(),
+            RuntimeConfig::default()
+        );
+
+        fs::write(&path, "max_batch_size: 10\n").unwrap();
+        let handle = RuntimeConfigHandle::poll_file(path.clone());
+        assert_eq!(handle.get().max_batch_size, Some(10));
+        assert_eq!(handle.get().max_batch_time_ms, None);
+
+        let policy = RuntimeCommitPolicy(handle.clone());
+        assert!(policy.should_commit(Duration::ZERO, 1));
+        fs::write(&path, "commit_frequency_ms: 1000\n").unwrap();
+        handle.reload(&path);
+        assert!(!policy.should_commit(Duration::from_millis(500), 1));
+        assert!(policy.should_commit(Duration::from_millis(1000), 1));
+
+        fs::write(&path, "max_batch_sizes: 10\n").unwrap();
+        assert!(RuntimeConfig::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
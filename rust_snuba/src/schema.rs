@@ -0,0 +1,154 @@
+use serde::Deserialize;
+
+use crate::config::ColumnConfig;
+use crate::encoders::row_binary::ColumnType;
+use crate::strategies::clickhouse::ClickhouseClient;
+
+/// A column of a table as listed in ``system.columns``.
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct TableColumn {
+    pub name: String,
+    pub r#type: String,
+    /// ``DEFAULT``, ``MATERIALIZED`` or ``ALIAS``, empty if the column has
+    /// no default expression.
+    pub default_kind: String,
+}
+
+fn strip_low_cardinality(column_type: ColumnType) -> ColumnType {
+    match column_type {
+        ColumnType::LowCardinality(inner) => strip_low_cardinality(*inner),
+        ColumnType::Nullable(inner) => {
+            ColumnType::Nullable(Box::new(strip_low_cardinality(*inner)))
+        }
+        ColumnType::Array(inner) => ColumnType::Array(Box::new(strip_low_cardinality(*inner))),
+        column_type => column_type,
+    }
+}
+
+// Like the Python schemas, ``LowCardinality`` is only a storage detail and
+// does not make types differ.
+fn same_type(declared: &str, actual: &str) -> bool {
+    match (declared.parse(), actual.parse()) {
+        (Ok(declared), Ok(actual)) => {
+            strip_low_cardinality(declared) == strip_low_cardinality(actual)
+        }
+        _ => {
+            let normalize = |name: &str| name.replace(char::is_whitespace, "");
+            normalize(declared) == normalize(actual)
+        }
+    }
+}
+
+/// Returns the differences between the columns the storage declares and
+/// the columns of the table, one line per difference.
+///
+/// Columns of the table without a default expression have to be declared,
+/// otherwise their values would be silently replaced by defaults.
+pub fn diff_columns(declared: &[ColumnConfig], table: &[TableColumn]) -> Vec<String> {
+    let mut errors = Vec::new();
+    for column in declared {
+        match table.iter().find(|actual| actual.name == column.name) {
+            None => errors.push(format!(
+                "Column {} is declared but does not exist in the table",
+                column.name
+            )),
+            Some(actual) if !same_type(&column.r#type, &actual.r#type) => errors.push(format!(
+                "Column {} is declared as {} but is {} in the table",
+                column.name, column.r#type, actual.r#type
+            )),
+            Some(actual) if matches!(actual.default_kind.as_str(), "MATERIALIZED" | "ALIAS") => {
+                errors.push(format!(
+                    "Column {} is declared but is {} in the table",
+                    column.name, actual.default_kind
+                ))
+            }
+            Some(_) => {}
+        }
+    }
+    for actual in table {
+        if actual.default_kind.is_empty() && !declared.iter().any(|c| c.name == actual.name) {
+            errors.push(format!(
+                "Column {} ({}) of the table is not declared",
+                actual.name, actual.r#type
+            ));
+        }
+    }
+    errors
+}
+
+/// Checks that the declared columns match the table before anything is
+/// inserted. Nothing is checked if no columns are declared.
+pub async fn check_schema(
+    client: &ClickhouseClient,
+    declared: &[ColumnConfig],
+) -> Result<(), anyhow::Error> {
+    if declared.is_empty() {
+        return Ok(());
+    }
+    let table = client.describe_table().await?;
+    if table.is_empty() {
+        anyhow::bail!("The table does not exist");
+    }
+    let errors = diff_columns(declared, &table);
+    if !errors.is_empty() {
+        anyhow::bail!(
+            "The declared columns do not match the table:\n{}",
+            errors.join("\n")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff_columns, TableColumn};
+    use crate::config::ColumnConfig;
+
+    fn declared(name: &str, r#type: &str) -> ColumnConfig {
+        ColumnConfig {
+            name: name.to_string(),
+            r#type: r#type.to_string(),
+        }
+    }
+
+    fn actual(name: &str, r#type: &str, default_kind: &str) -> TableColumn {
+        TableColumn {
+            name: name.to_string(),
+            r#type: r#type.to_string(),
+            default_kind: default_kind.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_diff_columns() {
+        let table = vec![
+            actual("project_id", "UInt64", ""),
+            actual("tags.key", "Array(LowCardinality(String))", ""),
+            actual("level", "Enum8('error' = 1, 'info' = 2)", ""),
+            actual("deleted", "UInt8", "DEFAULT"),
+            actual("day", "Date", "MATERIALIZED"),
+        ];
+        let columns = vec![
+            declared("project_id", "UInt64"),
+            declared("tags.key", "Array(String)"),
+            declared("level", "Enum('error' = 1, 'info' = 2)"),
+        ];
+        assert!(diff_columns(&columns, &table).is_empty());
+
+        let columns = vec![
+            declared("project_id", "UInt32"),
+            declared("tags.key", "Array(String)"),
+            declared("day", "Date"),
+            declared("message", "String"),
+        ];
+        assert_eq!(
+            diff_columns(&columns, &table),
+            vec![
+                "Column project_id is declared as UInt32 but is UInt64 in the table",
+                "Column day is declared but is MATERIALIZED in the table",
+                "Column message is declared but does not exist in the table",
+                "Column level (Enum8('error' = 1, 'info' = 2)) of the table is not declared",
+            ]
+        );
+    }
+}
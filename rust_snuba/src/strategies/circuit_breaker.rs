@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use rust_arroyo::utils::metrics;
+
+/// After how many failed inserts in a row the circuit opens, and how long
+/// it stays open before an insert is tried again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CircuitBreakerPolicy {
+    pub failure_threshold: u32,
+    pub cool_down: Duration,
+}
+
+impl Default for CircuitBreakerPolicy {
+    fn default() -> Self {
+        CircuitBreakerPolicy {
+            failure_threshold: 5,
+            cool_down: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CircuitState {
+    Closed,
+    Open { since: Instant },
+    HalfOpen,
+}
+
+impl CircuitState {
+    fn name(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open { .. } => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+/// Stops sending inserts to a ClickHouse cluster that keeps failing, see
+/// ``ClickhouseWriter::with_circuit_breaker``.
+///
+/// The circuit opens after ``failure_threshold`` failures in a row. Once the
+/// cool-down is over it is half open, a single insert is let through which
+/// closes the circuit if it succeeds and opens it again otherwise. Every
+/// change of state increments ``insertions.circuit_breaker.state_change``,
+/// tagged by the new state.
+pub struct CircuitBreaker {
+    policy: CircuitBreakerPolicy,
+    state: CircuitState,
+    consecutive_failures: u32,
+    // Whether the insert let through by the half open circuit is still
+    // waiting for its result.
+    trial_in_flight: bool,
+}
+
+impl CircuitBreaker {
+    pub fn new(policy: CircuitBreakerPolicy) -> Self {
+        CircuitBreaker {
+            policy,
+            state: CircuitState::Closed,
+            consecutive_failures: 0,
+            trial_in_flight: false,
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state
+    }
+
+    /// Whether an insert can be sent. An open circuit whose cool-down is
+    /// over becomes half open, it lets a single insert through until the
+    /// result of that one is recorded.
+    pub fn allows_request(&mut self) -> bool {
+        match self.state {
+            CircuitState::Closed => true,
+            CircuitState::HalfOpen => !std::mem::replace(&mut self.trial_in_flight, true),
+            CircuitState::Open { since } if since.elapsed() >= self.policy.cool_down => {
+                self.transition(CircuitState::HalfOpen);
+                self.trial_in_flight = true;
+                true
+            }
+            CircuitState::Open { .. } => false,
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        self.trial_in_flight = false;
+        self.consecutive_failures = 0;
+        if self.state != CircuitState::Closed {
+            self.transition(CircuitState::Closed);
+        }
+    }
+
+    pub fn record_failure(&mut self) {
+        self.trial_in_flight = false;
+        self.consecutive_failures += 1;
+        let open = match self.state {
+            CircuitState::Closed => self.consecutive_failures >= self.policy.failure_threshold,
+            CircuitState::HalfOpen => true,
+            CircuitState::Open { .. } => false,
+        };
+        if open {
+            self.transition(CircuitState::Open {
+                since: Instant::now(),
+            });
+        }
+    }
+
+    fn transition(&mut self, state: CircuitState) {
+        match state {
+            CircuitState::Open { .. } => log::error!(
+                "{} inserts failed in a row, not inserting for {:?}",
+                self.consecutive_failures,
+                self.policy.cool_down
+            ),
+            _ => log::info!("The insert circuit breaker is {}", state.name()),
+        }
+        metrics::increment(
+            "insertions.circuit_breaker.state_change",
+            None,
+            Some(HashMap::from([("state", state.name())])),
+            None,
+        );
+        self.state = state;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CircuitBreaker, CircuitBreakerPolicy, CircuitState};
+    use std::time::Duration;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let mut breaker = CircuitBreaker::new(CircuitBreakerPolicy {
+            failure_threshold: 3,
+            cool_down: Duration::from_secs(60),
+        });
+
+        // Only failures in a row open the circuit.
+        breaker.record_failure();
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.allows_request());
+
+        breaker.record_failure();
+        assert!(matches!(breaker.state(), CircuitState::Open { .. }));
+        assert!(!breaker.allows_request());
+    }
+
+    #[test]
+    fn test_circuit_breaker_half_open() {
+        let mut breaker = CircuitBreaker::new(CircuitBreakerPolicy {
+            failure_threshold: 1,
+            cool_down: Duration::ZERO,
+        });
+
+        breaker.record_failure();
+        assert!(matches!(breaker.state(), CircuitState::Open { .. }));
+        // The cool-down is over, a single insert is let through and its
+        // failure opens the circuit again.
+        assert!(breaker.allows_request());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(!breaker.allows_request());
+        breaker.record_failure();
+        assert!(matches!(breaker.state(), CircuitState::Open { .. }));
+
+        assert!(breaker.allows_request());
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+}
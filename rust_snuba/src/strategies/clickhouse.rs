@@ -0,0 +1,1018 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::mem;
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use flate2::write::GzEncoder;
+use log::warn;
+use reqwest::header::{HeaderMap, HeaderValue, CONNECTION, CONTENT_ENCODING};
+use reqwest::StatusCode;
+use rust_arroyo::processing::strategies::retry::RetryPolicy;
+use rust_arroyo::processing::strategies::{
+    merge_commit_request, raise_invalid_message, CommitRequest, InvalidMessage, MessageRejected,
+    ProcessingStrategy, StrategyDescription, SubmitError,
+};
+use rust_arroyo::types::{Message, Partition, Position};
+use rust_arroyo::utils::metrics;
+use rust_arroyo::utils::timing::Deadline;
+use thiserror::Error;
+use tokio::runtime::{Builder, Runtime};
+use tokio::task::JoinHandle;
+
+use crate::config::{ClickhouseConfig, Compression};
+use crate::encoders::json::JsonEachRowEncoder;
+use crate::encoders::RowsEncoder;
+use crate::runtime_config::RuntimeConfigHandle;
+use crate::schema::TableColumn;
+use crate::strategies::circuit_breaker::{CircuitBreaker, CircuitState};
+use crate::types::BytesInsertBatch;
+
+// ClickHouse error codes of failures that usually go away on their own,
+// from ``src/Common/ErrorCodes.cpp``.
+const TRANSIENT_ERROR_CODES: [u32; 7] = [
+    159, // TIMEOUT_EXCEEDED
+    164, // READONLY
+    202, // TOO_MANY_SIMULTANEOUS_QUERIES
+    209, // SOCKET_TIMEOUT
+    210, // NETWORK_ERROR
+    252, // TOO_MANY_PARTS
+    999, // KEEPER_EXCEPTION
+];
+
+#[derive(Error, Debug)]
+pub enum InsertError {
+    #[error(transparent)]
+    Transport(#[from] reqwest::Error),
+    #[error("ClickHouse returned {status} (code {code:?}): {message}")]
+    Response {
+        status: StatusCode,
+        code: Option<u32>,
+        message: String,
+    },
+    #[error("Failed to compress the insert: {0}")]
+    Compression(#[from] std::io::Error),
+}
+
+impl InsertError {
+    /// Whether the same insert can succeed if it is sent again. Errors
+    /// caused by the rows, such as parse errors or columns that do not
+    /// exist, fail every time.
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            InsertError::Transport(error) => {
+                error.is_timeout() || error.is_connect() || error.is_request()
+            }
+            InsertError::Response { status, code, .. } => {
+                matches!(
+                    *status,
+                    StatusCode::TOO_MANY_REQUESTS
+                        | StatusCode::BAD_GATEWAY
+                        | StatusCode::SERVICE_UNAVAILABLE
+                        | StatusCode::GATEWAY_TIMEOUT
+                ) || code.is_some_and(|code| TRANSIENT_ERROR_CODES.contains(&code))
+            }
+            InsertError::Compression(_) => false,
+        }
+    }
+}
+
+/// How many times an insert is attempted before its batch is set aside to be
+/// sent again, and how long to wait between attempts.
+pub const INSERT_RETRY_POLICY: RetryPolicy = RetryPolicy {
+    max_attempts: 5,
+    initial_backoff: Duration::from_millis(100),
+    max_backoff: Duration::from_secs(10),
+};
+
+/// Inserts rows into a table through the HTTP interface of ClickHouse.
+pub struct ClickhouseClient {
+    client: reqwest::Client,
+    url: String,
+    headers: HeaderMap<HeaderValue>,
+    table: String,
+    compression: Compression,
+}
+
+/// Compresses an insert body, ClickHouse decompresses it according to the
+/// ``Content-Encoding`` header.
+fn compress(compression: Compression, body: Vec<u8>) -> Result<Vec<u8>, std::io::Error> {
+    match compression {
+        Compression::None => Ok(body),
+        Compression::Gzip { level } => {
+            let level = level.map_or(flate2::Compression::default(), flate2::Compression::new);
+            let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 4), level);
+            encoder.write_all(&body)?;
+            encoder.finish()
+        }
+        Compression::Zstd { level } => zstd::encode_all(body.as_slice(), level.unwrap_or(0)),
+    }
+}
+
+impl ClickhouseClient {
+    /// The credentials and the database of ``config`` are expected to have
+    /// been validated with the consumer config.
+    pub fn new(config: &ClickhouseConfig, table: &str) -> Self {
+        let header =
+            |value: &str| HeaderValue::from_str(value).expect("Validated with the consumer config");
+        let mut headers = HeaderMap::new();
+        headers.insert(CONNECTION, HeaderValue::from_static("keep-alive"));
+        headers.insert("X-ClickHouse-User", header(&config.user));
+        headers.insert("X-ClickHouse-Key", header(&config.password));
+        headers.insert("X-ClickHouse-Database", header(&config.database));
+
+        ClickhouseClient {
+            client: reqwest::Client::new(),
+            url: format!("http://{}:{}", config.host, config.http_port),
+            headers,
+            table: table.to_owned(),
+            compression: Compression::None,
+        }
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        let encoding = match compression {
+            Compression::None => None,
+            Compression::Gzip { .. } => Some("gzip"),
+            Compression::Zstd { .. } => Some("zstd"),
+        };
+        match encoding {
+            Some(encoding) => self
+                .headers
+                .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding)),
+            None => self.headers.remove(CONTENT_ENCODING),
+        };
+        self.compression = compression;
+        self
+    }
+
+    /// Sends rows in the format of the ``query``. Retriable errors are
+    /// retried according to ``retry_policy``.
+    pub async fn send(
+        &self,
+        query: &str,
+        body: Vec<u8>,
+        retry_policy: &RetryPolicy,
+    ) -> Result<(), InsertError> {
+        let body = compress(self.compression, body)?;
+        let mut attempt = 1;
+        loop {
+            let error = match self.post(query, body.clone()).await {
+                Ok(()) => {
+                    record_attempt("success");
+                    return Ok(());
+                }
+                Err(error) => error,
+            };
+            if !error.is_retriable() {
+                record_attempt("failure");
+                return Err(error);
+            }
+            if attempt >= retry_policy.max_attempts {
+                record_attempt("exhausted");
+                return Err(error);
+            }
+
+            let backoff = retry_policy.backoff(attempt);
+            warn!(
+                "Insert attempt {} failed, retrying in {:?}: {}",
+                attempt, backoff, error
+            );
+            record_attempt("retry");
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    /// Returns the columns of the table from ``system.columns``.
+    pub async fn describe_table(&self) -> Result<Vec<TableColumn>, anyhow::Error> {
+        let mut headers = self.headers.clone();
+        headers.remove(CONTENT_ENCODING);
+        let response = self
+            .client
+            .get(&self.url)
+            .headers(headers)
+            .query(&[("query", DESCRIBE_QUERY), ("param_table", &self.table)])
+            .send()
+            .await?;
+
+        let status = response.status();
+        let body = response.text().await?;
+        if !status.is_success() {
+            anyhow::bail!("ClickHouse returned {}: {}", status, body);
+        }
+        body.lines()
+            .map(|line| Ok(serde_json::from_str(line)?))
+            .collect()
+    }
+
+    async fn post(&self, query: &str, body: Vec<u8>) -> Result<(), InsertError> {
+        let response = self
+            .client
+            .post(&self.url)
+            .headers(self.headers.clone())
+            .query(&[("query", query)])
+            .body(body)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let code = response
+                .headers()
+                .get("X-ClickHouse-Exception-Code")
+                .and_then(|code| code.to_str().ok()?.parse().ok());
+            let message = response.text().await.unwrap_or_default();
+            return Err(InsertError::Response {
+                status,
+                code,
+                message,
+            });
+        }
+        Ok(())
+    }
+}
+
+const DESCRIBE_QUERY: &str = "SELECT name, type, default_kind FROM system.columns \
+    WHERE database = currentDatabase() AND table = {table:String} FORMAT JSONEachRow";
+
+fn insert_query(table: &str, encoder: &dyn RowsEncoder) -> String {
+    let columns = encoder.columns();
+    if columns.is_empty() {
+        return format!("INSERT INTO {} FORMAT {}", table, encoder.format());
+    }
+    let columns: Vec<_> = columns.iter().map(|name| format!("`{}`", name)).collect();
+    format!(
+        "INSERT INTO {} ({}) FORMAT {}",
+        table,
+        columns.join(", "),
+        encoder.format()
+    )
+}
+
+fn record_attempt(outcome: &str) {
+    metrics::increment(
+        "insertions.attempt",
+        None,
+        Some(HashMap::from([("outcome", outcome)])),
+        None,
+    );
+}
+
+// When the message was produced and when its event happened and was
+// received by Sentry.
+struct MessageTimestamps {
+    produced: Option<DateTime<Utc>>,
+    origin: Option<DateTime<Utc>>,
+    sentry_received: Option<DateTime<Utc>>,
+}
+
+type TimestampGetter = fn(&MessageTimestamps) -> Option<DateTime<Utc>>;
+
+/// Records the max and mean latency of the messages of an insert, like
+/// the ``InsertBatchWriter`` of the Python consumers.
+fn record_latencies(timestamps: &[MessageTimestamps], inserted: DateTime<Utc>) {
+    let latencies: [(&str, &str, TimestampGetter); 3] = [
+        ("max_latency_ms", "latency_ms", |t| t.produced),
+        ("max_end_to_end_latency_ms", "end_to_end_latency_ms", |t| {
+            t.origin
+        }),
+        (
+            "max_sentry_received_latency_ms",
+            "sentry_received_latency_ms",
+            |t| t.sentry_received,
+        ),
+    ];
+    for (max_key, mean_key, timestamp) in latencies {
+        let latencies: Vec<u64> = timestamps
+            .iter()
+            .filter_map(timestamp)
+            .map(|timestamp| (inserted - timestamp).num_milliseconds().max(0) as u64)
+            .collect();
+        if let Some(max) = latencies.iter().max() {
+            let mean = latencies.iter().sum::<u64>() / latencies.len() as u64;
+            metrics::time(max_key, *max, None, None);
+            metrics::time(mean_key, mean, None, None);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Batch {
+    body: Vec<u8>,
+    rows: usize,
+    // The positions to commit once the batch is inserted.
+    positions: HashMap<Partition, Position>,
+    timestamps: Vec<MessageTimestamps>,
+    created: Option<Instant>,
+}
+
+struct Insert {
+    handle: JoinHandle<Result<(), InsertError>>,
+    // Kept with its body, to be sent again if the insert fails.
+    batch: Batch,
+    started: Instant,
+}
+
+/// Accumulates the rows of the submitted batches and inserts them into
+/// ClickHouse once ``max_batch_size`` rows were accumulated or the oldest
+/// ones have waited for ``max_batch_time``.
+///
+/// This is the last step of the pipeline. The offsets of the messages are
+/// only committed after their rows were inserted. One insert runs at a time
+/// while the next batch accumulates, once that batch is full too ``submit``
+/// returns ``MessageRejected``.
+///
+/// When an insert still fails with a retriable error after the retries, the
+/// batch is sent again before any other, after the backoff of the retry
+/// policy for the number of consecutive failures. In the meantime the next
+/// batch fills up and ``submit`` returns ``MessageRejected``. With a circuit
+/// breaker, the batch is sent again as long as the circuit is closed. While
+/// it is open ``submit`` returns ``MessageRejected``, which pauses the
+/// consumer for the cool-down. Errors that are not retriable crash the
+/// consumer, the batch is consumed again on restart.
+///
+/// Rows are inserted as ``JSONEachRow`` unless another encoder is set. The
+/// batch limits of a runtime config, if one is set, take precedence over
+/// those the writer was created with as of the next poll.
+pub struct ClickhouseWriter {
+    client: Arc<ClickhouseClient>,
+    query: String,
+    encoder: Arc<dyn RowsEncoder>,
+    runtime: Runtime,
+    batch: Batch,
+    insert: Option<Insert>,
+    // A batch whose insert failed, to send before the next one once
+    // ``resend_at`` elapsed, and the number of consecutive failures.
+    failed: Option<Batch>,
+    resend_at: Deadline,
+    failures: u32,
+    max_batch_size: usize,
+    max_batch_time: Duration,
+    // The limits the writer was created with, the runtime config overrides
+    // them.
+    configured_max_batch_size: usize,
+    configured_max_batch_time: Duration,
+    runtime_config: Option<RuntimeConfigHandle>,
+    retry_policy: RetryPolicy,
+    circuit_breaker: Option<CircuitBreaker>,
+}
+
+impl ClickhouseWriter {
+    pub fn new(client: ClickhouseClient, max_batch_size: usize, max_batch_time: Duration) -> Self {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("clickhouse-writer")
+            .enable_all()
+            .build()
+            .unwrap();
+        ClickhouseWriter {
+            query: insert_query(&client.table, &JsonEachRowEncoder),
+            encoder: Arc::new(JsonEachRowEncoder),
+            client: Arc::new(client),
+            runtime,
+            batch: Batch::default(),
+            insert: None,
+            failed: None,
+            resend_at: Deadline::new(Duration::ZERO),
+            failures: 0,
+            max_batch_size,
+            max_batch_time,
+            configured_max_batch_size: max_batch_size,
+            configured_max_batch_time: max_batch_time,
+            runtime_config: None,
+            retry_policy: INSERT_RETRY_POLICY,
+            circuit_breaker: None,
+        }
+    }
+
+    pub fn with_runtime_config(mut self, runtime_config: RuntimeConfigHandle) -> Self {
+        self.runtime_config = Some(runtime_config);
+        self
+    }
+
+    fn apply_runtime_config(&mut self) {
+        let Some(runtime_config) = &self.runtime_config else {
+            return;
+        };
+        let config = runtime_config.get();
+        self.max_batch_size = config
+            .max_batch_size
+            .unwrap_or(self.configured_max_batch_size);
+        self.max_batch_time = config
+            .max_batch_time_ms
+            .map(Duration::from_millis)
+            .unwrap_or(self.configured_max_batch_time);
+    }
+
+    pub fn with_encoder(mut self, encoder: Arc<dyn RowsEncoder>) -> Self {
+        self.query = insert_query(&self.client.table, encoder.as_ref());
+        self.encoder = encoder;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_circuit_breaker(mut self, circuit_breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    /// Returns the positions of the insert in flight if it completed. The
+    /// batch of a retriable failure is kept to be sent again, other failures
+    /// crash the consumer.
+    fn check_insert(&mut self) -> Option<CommitRequest> {
+        if !self.insert.as_ref()?.handle.is_finished() {
+            return None;
+        }
+        let insert = self.insert.take().unwrap();
+        let result = self
+            .runtime
+            .block_on(insert.handle)
+            .unwrap_or_else(|error| panic!("Insert task failed: {}", error));
+        let batch = insert.batch;
+        match result {
+            Ok(()) => {
+                self.failures = 0;
+                if let Some(circuit_breaker) = &mut self.circuit_breaker {
+                    circuit_breaker.record_success();
+                }
+            }
+            Err(error) if error.is_retriable() => {
+                self.failures += 1;
+                let backoff = match &mut self.circuit_breaker {
+                    Some(circuit_breaker) => {
+                        circuit_breaker.record_failure();
+                        Duration::ZERO
+                    }
+                    None => self.retry_policy.backoff(self.failures),
+                };
+                log::error!(
+                    "Failed to insert {} rows, sending them again in {:?}: {}",
+                    batch.rows,
+                    backoff,
+                    error
+                );
+                self.failed = Some(batch);
+                self.resend_at = Deadline::new(backoff);
+                return None;
+            }
+            Err(error) => panic!("Failed to insert {} rows: {}", batch.rows, error),
+        }
+
+        metrics::time(
+            "insertions.batch_write_ms",
+            insert.started.elapsed().as_millis() as u64,
+            None,
+            None,
+        );
+        metrics::increment(
+            "insertions.batch_write_msgs",
+            Some(batch.rows as i64),
+            None,
+            None,
+        );
+        record_latencies(&batch.timestamps, Utc::now());
+        Some(CommitRequest {
+            positions: batch.positions,
+        })
+    }
+
+    fn batch_ready(&self) -> bool {
+        self.batch.rows >= self.max_batch_size
+            || self
+                .batch
+                .created
+                .is_some_and(|created| created.elapsed() >= self.max_batch_time)
+    }
+
+    fn maybe_flush(&mut self, force: bool) {
+        if self.insert.is_some() {
+            return;
+        }
+        if let Some(batch) = self.failed.take() {
+            let allowed = match self.circuit_breaker.as_mut() {
+                Some(circuit_breaker) => circuit_breaker.allows_request(),
+                None => self.resend_at.has_elapsed(),
+            };
+            if allowed {
+                self.spawn_insert(batch);
+            } else {
+                self.failed = Some(batch);
+            }
+            return;
+        }
+        if self.batch.created.is_none() || (!force && !self.batch_ready()) {
+            return;
+        }
+
+        let batch = mem::take(&mut self.batch);
+        self.spawn_insert(batch);
+    }
+
+    fn spawn_insert(&mut self, batch: Batch) {
+        let client = self.client.clone();
+        let query = self.query.clone();
+        let body = batch.body.clone();
+        let retry_policy = self.retry_policy;
+        self.insert = Some(Insert {
+            handle: self
+                .runtime
+                .spawn(async move { client.send(&query, body, &retry_policy).await }),
+            batch,
+            started: Instant::now(),
+        });
+    }
+
+    fn circuit_open(&self) -> bool {
+        self.circuit_breaker
+            .as_ref()
+            .is_some_and(|circuit_breaker| {
+                matches!(circuit_breaker.state(), CircuitState::Open { .. })
+            })
+    }
+}
+
+impl ProcessingStrategy<BytesInsertBatch> for ClickhouseWriter {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        self.apply_runtime_config();
+        let commit_request = self.check_insert();
+        self.maybe_flush(false);
+        Ok(commit_request)
+    }
+
+    fn submit(
+        &mut self,
+        message: Message<BytesInsertBatch>,
+    ) -> Result<(), SubmitError<BytesInsertBatch>> {
+        if self.batch.rows >= self.max_batch_size || self.circuit_open() {
+            return Err(SubmitError::MessageRejected(MessageRejected { message }));
+        }
+
+        let BytesInsertBatch {
+            rows,
+            replacements,
+            origin_timestamp,
+            sentry_received_timestamp,
+        } = message.payload();
+        // Same as the Python consumers, replacements have to be produced
+        // before the rows reach the writer. They are dropped when the
+        // consumer has no replacements topic, the rows are still written.
+        if replacements.is_some() {
+            log::error!(
+                "Dropping the replacements of {}, no replacements topic is set",
+                message
+            );
+            metrics::increment("insertions.dropped_replacements", None, None, None);
+        }
+        // Rows are only added once all of them could be encoded.
+        let mut body = Vec::new();
+        if self.batch.created.is_none() {
+            self.encoder.write_header(&mut body);
+        }
+        for row in &rows {
+            if let Err(error) = self.encoder.encode(row, &mut body) {
+                log::error!("Failed to encode a row of {}: {:#}", message, error);
+                return raise_invalid_message(&message);
+            }
+        }
+        self.batch.body.extend(body);
+        self.batch.rows += rows.len();
+        for (partition, position) in message.committable() {
+            self.batch.positions.insert(partition, position);
+        }
+        self.batch.timestamps.push(MessageTimestamps {
+            produced: message.timestamp(),
+            origin: origin_timestamp,
+            sentry_received: sentry_received_timestamp,
+        });
+        self.batch.created.get_or_insert_with(Instant::now);
+        self.maybe_flush(false);
+        Ok(())
+    }
+
+    fn close(&mut self) {}
+
+    fn terminate(&mut self) {
+        if let Some(insert) = self.insert.take() {
+            insert.handle.abort();
+        }
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        let deadline = Deadline::from_timeout(timeout);
+        let mut commit_request = None;
+
+        loop {
+            commit_request = merge_commit_request(commit_request, self.check_insert());
+            self.maybe_flush(true);
+            if self.insert.is_none() {
+                break;
+            }
+            if deadline.has_elapsed() {
+                log::warn!("Timeout reached while waiting for the insert to finish");
+                break;
+            }
+            sleep(Duration::from_millis(1));
+        }
+        commit_request
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        let inserting = self
+            .insert
+            .as_ref()
+            .map_or(0, |insert| insert.batch.timestamps.len());
+        let failed = self
+            .failed
+            .as_ref()
+            .map_or(0, |batch| batch.timestamps.len());
+        StrategyDescription::new("ClickhouseWriter")
+            .with_buffered_messages(self.batch.timestamps.len() + inserting + failed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        compress, ClickhouseClient, ClickhouseWriter, InsertError, RetryPolicy, INSERT_RETRY_POLICY,
+    };
+    use crate::config::{ClickhouseConfig, ColumnConfig, Compression};
+    use crate::encoders::csv::CsvWithNamesEncoder;
+    use crate::schema::TableColumn;
+    use crate::strategies::circuit_breaker::{CircuitBreaker, CircuitBreakerPolicy};
+    use crate::types::{BytesInsertBatch, ReplacementBatch};
+    use chrono::Utc;
+    use reqwest::StatusCode;
+    use rust_arroyo::processing::strategies::testutils::partition;
+    use rust_arroyo::processing::strategies::{ProcessingStrategy, SubmitError};
+    use rust_arroyo::types::Message;
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    // Answers an insert with every response, one after the other, and
+    // returns the request lines and the bodies of the inserts.
+    fn run_server(
+        listener: TcpListener,
+        responses: &'static [&'static str],
+    ) -> thread::JoinHandle<Vec<(String, Vec<u8>)>> {
+        thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                let (head, body_length) = loop {
+                    let length = stream.read(&mut buffer).unwrap();
+                    request.extend(&buffer[..length]);
+                    let request = String::from_utf8_lossy(&request);
+                    if let Some((head, _)) = request.split_once("\r\n\r\n") {
+                        let body_length = head
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length: "))
+                            .map_or(0, |length| length.parse().unwrap());
+                        break (head.to_string(), body_length);
+                    }
+                };
+                while request.len() < head.len() + 4 + body_length {
+                    let length = stream.read(&mut buffer).unwrap();
+                    request.extend(&buffer[..length]);
+                }
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+                    response
+                )
+                .unwrap();
+                let request_line = head.lines().next().unwrap().to_string();
+                requests.push((request_line, request[head.len() + 4..].to_vec()));
+            }
+            requests
+        })
+    }
+
+    fn config(listener: &TcpListener) -> ClickhouseConfig {
+        ClickhouseConfig {
+            host: "127.0.0.1".to_string(),
+            port: 9000,
+            http_port: listener.local_addr().unwrap().port(),
+            user: "default".to_string(),
+            password: "".to_string(),
+            database: "default".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_compress() {
+        let body = b"{\"offset\":0}\n".repeat(100);
+        assert_eq!(compress(Compression::None, body.clone()).unwrap(), body);
+
+        let gzipped = compress(Compression::Gzip { level: Some(9) }, body.clone()).unwrap();
+        assert!(gzipped.len() < body.len());
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(gzipped.as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+
+        let compressed = compress(Compression::Zstd { level: None }, body.clone()).unwrap();
+        assert!(compressed.len() < body.len());
+        assert_eq!(zstd::decode_all(compressed.as_slice()).unwrap(), body);
+    }
+
+    #[test]
+    fn test_clickhouse_writer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = ClickhouseClient::new(&config(&listener), "querylog_local");
+        let server = run_server(listener, &["503 Service Unavailable", "200 OK"]);
+
+        let mut writer = ClickhouseWriter::new(client, 2, Duration::from_secs(60))
+            .with_retry_policy(RetryPolicy {
+                max_attempts: 2,
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(1),
+            });
+        let partition = partition("test", 0);
+        for offset in 0..2 {
+            // Replacements without a replacements topic are dropped, the
+            // rows are still written.
+            let batch = BytesInsertBatch {
+                rows: vec![format!("{{\"offset\":{}}}", offset).into_bytes()],
+                replacements: (offset == 1).then(|| ReplacementBatch {
+                    key: b"1".to_vec(),
+                    values: vec![b"[2]".to_vec()],
+                }),
+                ..Default::default()
+            };
+            writer
+                .submit(Message::new_broker_message(
+                    batch,
+                    partition.clone(),
+                    offset,
+                    Utc::now(),
+                ))
+                .unwrap();
+        }
+
+        let commit_request = loop {
+            if let Some(commit_request) = writer.poll().unwrap() {
+                break commit_request;
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(commit_request.offsets(), HashMap::from([(partition, 2)]));
+        // The insert was retried after the first failure
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        for (request_line, body) in requests {
+            assert!(request_line.contains("FORMAT+JSONEachRow"));
+            assert_eq!(body, b"{\"offset\":0}\n{\"offset\":1}\n");
+        }
+        assert!(writer.join(Some(Duration::from_secs(1))).is_none());
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = ClickhouseClient::new(&config(&listener), "querylog_local");
+        let server = run_server(
+            listener,
+            &[
+                "503 Service Unavailable",
+                "503 Service Unavailable",
+                "200 OK",
+            ],
+        );
+
+        let mut writer = ClickhouseWriter::new(client, 1, Duration::from_secs(60))
+            .with_retry_policy(RetryPolicy {
+                max_attempts: 1,
+                ..Default::default()
+            })
+            .with_circuit_breaker(CircuitBreaker::new(CircuitBreakerPolicy {
+                failure_threshold: 1,
+                cool_down: Duration::from_millis(50),
+            }));
+        let partition = partition("test", 0);
+        let message = |offset| {
+            let batch = BytesInsertBatch {
+                rows: vec![b"{}".to_vec()],
+                ..Default::default()
+            };
+            Message::new_broker_message(batch, partition.clone(), offset, Utc::now())
+        };
+        writer.submit(message(0)).unwrap();
+
+        // The consumer is paused while the circuit is open.
+        while !writer.circuit_open() {
+            assert!(writer.poll().unwrap().is_none());
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(matches!(
+            writer.submit(message(1)),
+            Err(SubmitError::MessageRejected(_))
+        ));
+
+        // The batch is sent again after every cool-down until it is inserted.
+        let commit_request = loop {
+            if let Some(commit_request) = writer.poll().unwrap() {
+                break commit_request;
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(
+            commit_request.offsets(),
+            HashMap::from([(partition.clone(), 1)])
+        );
+        assert_eq!(server.join().unwrap().len(), 3);
+        assert!(writer.submit(message(1)).is_ok());
+    }
+
+    #[test]
+    fn test_resend_failed_batch() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = ClickhouseClient::new(&config(&listener), "querylog_local");
+        let server = run_server(
+            listener,
+            &[
+                "503 Service Unavailable",
+                "503 Service Unavailable",
+                "200 OK",
+            ],
+        );
+
+        let mut writer = ClickhouseWriter::new(client, 1, Duration::from_secs(60))
+            .with_retry_policy(RetryPolicy {
+                max_attempts: 1,
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(1),
+            });
+        let batch = BytesInsertBatch {
+            rows: vec![b"{}".to_vec()],
+            ..Default::default()
+        };
+        writer
+            .submit(Message::new_broker_message(
+                batch,
+                partition("test", 0),
+                0,
+                Utc::now(),
+            ))
+            .unwrap();
+
+        // Without a circuit breaker the batch is sent again until it is
+        // inserted, instead of crashing the consumer.
+        let commit_request = loop {
+            if let Some(commit_request) = writer.poll().unwrap() {
+                break commit_request;
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(
+            commit_request.offsets(),
+            HashMap::from([(partition("test", 0), 1)])
+        );
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 3);
+        for (_, body) in requests {
+            assert_eq!(body, b"{}\n");
+        }
+    }
+
+    #[test]
+    fn test_retriable_errors() {
+        let error = |status, code| InsertError::Response {
+            status,
+            code,
+            message: "".to_string(),
+        };
+        assert!(error(StatusCode::SERVICE_UNAVAILABLE, None).is_retriable());
+        assert!(error(StatusCode::INTERNAL_SERVER_ERROR, Some(252)).is_retriable());
+        // CANNOT_PARSE_INPUT_ASSERTION_FAILED
+        assert!(!error(StatusCode::BAD_REQUEST, Some(27)).is_retriable());
+        assert!(!error(StatusCode::INTERNAL_SERVER_ERROR, None).is_retriable());
+    }
+
+    #[test]
+    fn test_permanent_errors_are_not_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = ClickhouseClient::new(&config(&listener), "querylog_local");
+        let server = run_server(listener, &["400 Bad Request"]);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let result = runtime.block_on(client.send(
+            "INSERT INTO querylog_local FORMAT JSONEachRow",
+            b"{}\n".to_vec(),
+            &INSERT_RETRY_POLICY,
+        ));
+        assert!(!result.unwrap_err().is_retriable());
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_encoder() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = ClickhouseClient::new(&config(&listener), "querylog_local");
+        let server = run_server(listener, &["200 OK"]);
+
+        let encoder = CsvWithNamesEncoder::new(&[ColumnConfig {
+            name: "tags.key".to_string(),
+            r#type: "Array(String)".to_string(),
+        }]);
+        let mut writer = ClickhouseWriter::new(client, 1, Duration::from_secs(60))
+            .with_encoder(Arc::new(encoder));
+
+        // Rows that cannot be encoded are invalid messages
+        let batch = BytesInsertBatch {
+            rows: vec![b"{\"tags.key\":{}}".to_vec()],
+            ..Default::default()
+        };
+        let result = writer.submit(Message::new_broker_message(
+            batch,
+            partition("test", 0),
+            0,
+            Utc::now(),
+        ));
+        assert!(matches!(result, Err(SubmitError::InvalidMessage(_))));
+
+        let batch = BytesInsertBatch {
+            rows: vec![b"{\"tags.key\":[\"a\"]}".to_vec()],
+            ..Default::default()
+        };
+        writer
+            .submit(Message::new_broker_message(
+                batch,
+                partition("test", 0),
+                1,
+                Utc::now(),
+            ))
+            .unwrap();
+        let commit_request = writer.join(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(
+            commit_request.offsets(),
+            HashMap::from([(partition("test", 0), 2)])
+        );
+
+        let (request_line, body) = server.join().unwrap().remove(0);
+        assert!(request_line.contains("%28%60tags.key%60%29+FORMAT+CSVWithNames"));
+        assert_eq!(body, b"\"tags.key\"\n\"['a']\"\n");
+    }
+
+    #[test]
+    fn test_describe_table() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = ClickhouseClient::new(&config(&listener), "querylog_local")
+            .with_compression(Compression::Gzip { level: None });
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let length = stream.read(&mut request).unwrap();
+            let request = String::from_utf8_lossy(&request[..length]).to_string();
+            let body = concat!(
+                "{\"name\":\"project_id\",\"type\":\"UInt64\",\"default_kind\":\"\"}\n",
+                "{\"name\":\"offset\",\"type\":\"UInt64\",\"default_kind\":\"DEFAULT\"}\n",
+            );
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            request
+        });
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let columns = runtime.block_on(client.describe_table()).unwrap();
+        assert_eq!(
+            columns,
+            vec![
+                TableColumn {
+                    name: "project_id".to_string(),
+                    r#type: "UInt64".to_string(),
+                    default_kind: "".to_string(),
+                },
+                TableColumn {
+                    name: "offset".to_string(),
+                    r#type: "UInt64".to_string(),
+                    default_kind: "DEFAULT".to_string(),
+                },
+            ]
+        );
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("GET "));
+        assert!(request.contains("param_table=querylog_local"));
+        // The request has no body to decompress
+        assert!(!request.to_lowercase().contains("content-encoding"));
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use rust_arroyo::backends::kafka::types::KafkaPayload;
+use rust_arroyo::backends::Producer;
+use rust_arroyo::types::{Partition, Position, TopicOrPartition};
+use rust_arroyo::utils::metrics;
+
+/// An entry of the commit log, which the subscriptions scheduler reads to
+/// know how far the consumer of a partition got.
+#[derive(Debug, PartialEq)]
+pub struct Commit {
+    pub partition: Partition,
+    pub group: String,
+    /// The offset of the last message that was written, not the offset
+    /// that is committed to Kafka.
+    pub offset: u64,
+    pub orig_message_ts: DateTime<Utc>,
+}
+
+impl Commit {
+    /// Encodes the entry like the ``CommitCodec`` of the Python Arroyo.
+    pub fn encode(&self) -> KafkaPayload {
+        let key = format!(
+            "{}:{}:{}",
+            self.partition.topic.name, self.partition.index, self.group
+        );
+        let payload = serde_json::json!({
+            "offset": self.offset,
+            "orig_message_ts": self.orig_message_ts.timestamp_micros() as f64 / 1_000_000.0,
+        });
+        KafkaPayload {
+            key: Some(key.into_bytes().into()),
+            headers: None,
+            payload: Some(payload.to_string().into_bytes().into()),
+        }
+    }
+}
+
+/// Produces an entry to the commit log for every partition whose offsets
+/// were committed, like the ``ProcessedMessageBatchWriter`` of the Python
+/// consumers. The entry has the offset and the timestamp of the last
+/// committed message, from its position. It is meant to be called from
+/// ``StreamProcessor::set_on_commit``, once the broker acknowledged the
+/// commit.
+///
+/// An entry that fails to be produced is kept and produced again with the
+/// next commit, unless a later entry of its partition replaced it. Failures
+/// are counted in ``commit_log.produce_failed``. Delivery failures are only
+/// logged by the producer.
+pub struct CommitLog {
+    producer: Arc<dyn Producer<KafkaPayload>>,
+    destination: TopicOrPartition,
+    group: String,
+    pending: Mutex<HashMap<Partition, Commit>>,
+}
+
+impl CommitLog {
+    pub fn new(
+        producer: Arc<dyn Producer<KafkaPayload>>,
+        destination: TopicOrPartition,
+        group: &str,
+    ) -> Self {
+        CommitLog {
+            producer,
+            destination,
+            group: group.to_owned(),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn produce(&self, positions: &HashMap<Partition, Position>) {
+        let mut pending = self.pending.lock().unwrap();
+        for (partition, position) in positions {
+            let Some(offset) = position.offset.checked_sub(1) else {
+                continue;
+            };
+            let commit = Commit {
+                partition: partition.clone(),
+                group: self.group.clone(),
+                offset,
+                orig_message_ts: position.timestamp,
+            };
+            pending.insert(partition.clone(), commit);
+        }
+
+        pending.retain(|_, commit| {
+            match self.producer.produce(&self.destination, &commit.encode()) {
+                Ok(()) => false,
+                Err(error) => {
+                    log::warn!(
+                        "Failed to produce {:?} to the commit log, retrying with the next commit: {}",
+                        commit,
+                        error
+                    );
+                    metrics::increment("commit_log.produce_failed", None, None, None);
+                    true
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Commit, CommitLog};
+    use chrono::{TimeZone, Utc};
+    use rust_arroyo::backends::kafka::types::KafkaPayload;
+    use rust_arroyo::processing::strategies::testutils::{partition, RecordingProducer};
+    use rust_arroyo::types::{Position, Topic, TopicOrPartition};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_encode() {
+        let commit = Commit {
+            partition: partition("events", 3),
+            group: "snuba-consumers".to_string(),
+            offset: 42,
+            orig_message_ts: Utc.timestamp_opt(1700000000, 250_000_000).unwrap(),
+        };
+        let payload = commit.encode();
+        assert_eq!(
+            payload.key.as_deref(),
+            Some(&b"events:3:snuba-consumers"[..])
+        );
+        assert_eq!(
+            payload.payload.as_deref(),
+            Some(&br#"{"offset":42,"orig_message_ts":1700000000.25}"#[..])
+        );
+    }
+
+    #[test]
+    fn test_produce_commit_log() {
+        let producer = Arc::new(RecordingProducer::<KafkaPayload>::default());
+        let commit_log = CommitLog::new(
+            producer.clone(),
+            TopicOrPartition::Topic(Topic {
+                name: "snuba-commit-log".to_string(),
+            }),
+            "group",
+        );
+
+        commit_log.produce(&HashMap::from([(
+            partition("events", 0),
+            Position::new(2, Utc.timestamp_opt(1, 0).unwrap()),
+        )]));
+        // Nothing was consumed from partition 1 yet.
+        commit_log.produce(&HashMap::from([
+            (
+                partition("events", 0),
+                Position::new(3, Utc.timestamp_opt(2, 0).unwrap()),
+            ),
+            (
+                partition("events", 1),
+                Position::new(0, Utc.timestamp_opt(2, 0).unwrap()),
+            ),
+        ]));
+
+        // Every entry is for the last committed message
+        let produced: Vec<_> = producer
+            .produced()
+            .iter()
+            .map(|payload| String::from_utf8(payload.payload.as_deref().unwrap().to_vec()))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            produced,
+            vec![
+                r#"{"offset":1,"orig_message_ts":1.0}"#,
+                r#"{"offset":2,"orig_message_ts":2.0}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_retry_with_next_commit() {
+        let producer = Arc::new(RecordingProducer::<KafkaPayload>::failing(2));
+        let commit_log = CommitLog::new(
+            producer.clone(),
+            TopicOrPartition::Topic(Topic {
+                name: "snuba-commit-log".to_string(),
+            }),
+            "group",
+        );
+
+        commit_log.produce(&HashMap::from([(
+            partition("events", 0),
+            Position::new(2, Utc.timestamp_opt(1, 0).unwrap()),
+        )]));
+        // The entry of partition 0 is replaced by the later one.
+        commit_log.produce(&HashMap::from([(
+            partition("events", 0),
+            Position::new(3, Utc.timestamp_opt(2, 0).unwrap()),
+        )]));
+        assert!(producer.produced().is_empty());
+        commit_log.produce(&HashMap::from([(
+            partition("events", 1),
+            Position::new(5, Utc.timestamp_opt(3, 0).unwrap()),
+        )]));
+
+        let mut produced: Vec<_> = producer
+            .produced()
+            .iter()
+            .map(|payload| String::from_utf8(payload.key.as_deref().unwrap().to_vec()).unwrap())
+            .collect();
+        produced.sort();
+        assert_eq!(produced, vec!["events:0:group", "events:1:group"]);
+    }
+}
@@ -1 +1,10 @@
+pub mod circuit_breaker;
+pub mod clickhouse;
+pub mod commit_log;
+pub mod processor;
 pub mod python;
+pub mod replacements;
+pub mod sentry_context;
+pub mod shadow;
+pub mod slicing;
+pub mod validate_schema;
@@ -0,0 +1,178 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use rust_arroyo::backends::kafka::types::KafkaPayload;
+use rust_arroyo::processing::strategies::run_task_in_threads::RunTaskInThreads;
+use rust_arroyo::processing::strategies::{
+    raise_invalid_message, CommitRequest, InvalidMessage, MessageRejected, ProcessingStrategy,
+    StrategyDescription, SubmitError,
+};
+use rust_arroyo::types::{InnerMessage, Message};
+
+use crate::processors::{KafkaMessageMetadata, MessageProcessor};
+use crate::types::BytesInsertBatch;
+
+// Enough messages are queued for every thread to pick up the next one as
+// soon as it is done with the previous one.
+const MAX_PENDING_TASKS_PER_THREAD: usize = 4;
+
+/// Processes every message with a Rust processor, the counterpart of
+/// ``PythonTransformStep`` for the storages that were ported. Messages are
+/// processed by a pool of ``concurrency`` threads, the batches are
+/// forwarded in the order of the messages.
+///
+/// The processors need the partition and offset of the message, a message
+/// built out of several ones is raised as invalid.
+pub struct RustProcessor {
+    inner: RunTaskInThreads<(KafkaPayload, KafkaMessageMetadata), BytesInsertBatch>,
+}
+
+impl RustProcessor {
+    pub fn new(
+        processor: Arc<dyn MessageProcessor>,
+        next_step: Box<dyn ProcessingStrategy<BytesInsertBatch>>,
+        concurrency: usize,
+    ) -> Self {
+        let function = move |(payload, metadata): (KafkaPayload, KafkaMessageMetadata)| {
+            let batch = processor.process_message(payload, metadata)?;
+            Ok(BytesInsertBatch::from(batch))
+        };
+        RustProcessor {
+            inner: RunTaskInThreads::new(
+                Arc::new(function),
+                next_step,
+                concurrency,
+                concurrency * MAX_PENDING_TASKS_PER_THREAD,
+            ),
+        }
+    }
+}
+
+impl ProcessingStrategy<KafkaPayload> for RustProcessor {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        self.inner.poll()
+    }
+
+    fn submit(&mut self, message: Message<KafkaPayload>) -> Result<(), SubmitError<KafkaPayload>> {
+        let InnerMessage::BrokerMessage(broker_message) = &message.inner_message else {
+            return raise_invalid_message(&message);
+        };
+        let metadata = KafkaMessageMetadata {
+            partition: broker_message.partition.clone(),
+            offset: broker_message.offset,
+            timestamp: broker_message.timestamp,
+        };
+        let payload = message.payload();
+        match self.inner.submit(message.replace((payload, metadata))) {
+            Ok(()) => Ok(()),
+            Err(SubmitError::MessageRejected(MessageRejected { message })) => {
+                let (payload, _) = message.payload();
+                Err(SubmitError::MessageRejected(MessageRejected {
+                    message: message.replace(payload),
+                }))
+            }
+            Err(SubmitError::InvalidMessage(invalid)) => Err(invalid.into()),
+        }
+    }
+
+    fn close(&mut self) {
+        self.inner.close();
+    }
+
+    fn terminate(&mut self) {
+        self.inner.terminate();
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        self.inner.join(timeout)
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription {
+            name: "RustProcessor".to_string(),
+            ..self.inner.describe()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RustProcessor;
+    use crate::processors::{KafkaMessageMetadata, MessageProcessor};
+    use crate::types::InsertBatch;
+    use chrono::Utc;
+    use rust_arroyo::backends::kafka::types::KafkaPayload;
+    use rust_arroyo::processing::strategies::testutils::{partition, Recorder};
+    use rust_arroyo::processing::strategies::{InvalidMessage, ProcessingStrategy, SubmitError};
+    use rust_arroyo::types::{Message, Position};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    // Every message is a row, empty messages are invalid.
+    struct Rows;
+
+    impl MessageProcessor for Rows {
+        fn process_message(
+            &self,
+            payload: KafkaPayload,
+            metadata: KafkaMessageMetadata,
+        ) -> Result<InsertBatch, InvalidMessage> {
+            let row = payload
+                .payload
+                .filter(|payload| !payload.is_empty())
+                .ok_or(InvalidMessage {
+                    partition: metadata.partition,
+                    offset: metadata.offset,
+                })?;
+            Ok(InsertBatch {
+                rows: vec![row.to_vec()],
+                origin_timestamp: Some(metadata.timestamp),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[test]
+    fn test_rust_processor() {
+        let next_step = Recorder::default();
+        let submitted = next_step.submitted.clone();
+        let mut strategy = RustProcessor::new(Arc::new(Rows), Box::new(next_step), 2);
+        let partition = partition("snuba-queries", 0);
+        let payload = |payload: &[u8]| KafkaPayload {
+            key: None,
+            headers: None,
+            payload: Some(payload.into()),
+        };
+        let message = |offset, content: &[u8]| {
+            Message::new_broker_message(payload(content), partition.clone(), offset, Utc::now())
+        };
+
+        strategy.submit(message(0, b"{}")).unwrap();
+        strategy.submit(message(1, b"")).unwrap();
+        let invalid = loop {
+            match strategy.poll() {
+                Ok(_) => sleep(Duration::from_millis(1)),
+                Err(invalid) => break invalid,
+            }
+        };
+        assert_eq!(invalid.offset, 1);
+        assert_eq!(submitted.payloads()[0].rows, vec![b"{}".to_vec()]);
+
+        // A message without a partition and offset of its own is raised as
+        // the last message it commits.
+        let message = Message::new_any_message(
+            payload(b"{}"),
+            BTreeMap::from([(partition, Position::new(5, Utc::now()))]),
+        );
+        let result = strategy.submit(message);
+        assert!(matches!(
+            result,
+            Err(SubmitError::InvalidMessage(InvalidMessage {
+                offset: 4,
+                ..
+            }))
+        ));
+    }
+}
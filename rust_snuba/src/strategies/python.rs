@@ -1,32 +1,41 @@
 use std::time::Duration;
 
 use rust_arroyo::backends::kafka::types::KafkaPayload;
-use rust_arroyo::processing::strategies::{CommitRequest, MessageRejected, ProcessingStrategy};
+use rust_arroyo::processing::strategies::{
+    raise_invalid_message, report_invalid_message_on_join, CommitRequest, InvalidMessage,
+    MessageRejected, ProcessingStrategy, StrategyDescription, SubmitError,
+};
 use rust_arroyo::types::{BrokerMessage, InnerMessage, Message};
 
 use anyhow::Error;
+use chrono::DateTime;
 
 use pyo3::prelude::*;
+use pyo3::types::PyBytes;
 
-use crate::types::BytesInsertBatch;
+use crate::types::{BytesInsertBatch, ReplacementBatch};
 
 use crate::config::MessageProcessorConfig;
 
+// The rows, their origin timestamp in seconds and the replacements, as a
+// key and values, returned by ``_wrapped``.
+type ProcessedMessage = (Vec<Vec<u8>>, Option<f64>, Option<(Vec<u8>, Vec<Vec<u8>>)>);
+
 pub struct PythonTransformStep {
     next_step: Box<dyn ProcessingStrategy<BytesInsertBatch>>,
     py_process_message: Py<PyAny>,
+    message_carried_over: Option<Message<BytesInsertBatch>>,
 }
 
-impl PythonTransformStep {
-    pub fn new<N>(processor_config: MessageProcessorConfig, next_step: N) -> Result<Self, Error>
-    where
-        N: ProcessingStrategy<BytesInsertBatch> + 'static,
-    {
-        let next_step = Box::new(next_step);
-        let python_module = &processor_config.python_module;
-        let python_class_name = &processor_config.python_class_name;
-        let code = format!(
-            r#"
+/// Imports the Python processor of ``processor_config`` and returns the
+/// function a ``PythonTransformStep`` calls on each message. It is loaded once
+/// when the consumer starts, so that a processor that cannot be imported stops
+/// the consumer there instead of in the strategy factory.
+pub fn load_processor(processor_config: &MessageProcessorConfig) -> Result<Py<PyAny>, Error> {
+    let python_module = &processor_config.python_module;
+    let python_class_name = &processor_config.python_class_name;
+    let code = format!(
+        r#"
 import rapidjson
 from snuba.datasets.processors import DatasetMessageProcessor
 
@@ -35,11 +44,20 @@ from {python_module} import {python_class_name} as Processor
 processor = Processor.from_kwargs()
 
 from snuba.consumers.types import KafkaMessageMetadata
-from snuba.processor import InsertBatch
-from datetime import datetime
+from snuba.processor import InsertBatch, ReplacementBatch
+from datetime import datetime, timezone
 from snuba.consumers.consumer import json_row_encoder
 
 
+def _timestamp(value):
+    if value is None:
+        return None
+    # Processors return naive datetimes in UTC
+    if value.tzinfo is None:
+        value = value.replace(tzinfo=timezone.utc)
+    return value.timestamp()
+
+
 def _wrapped(message, offset, partition, timestamp):
     rv = processor.process_message(
         message=rapidjson.loads(bytearray(message)),
@@ -51,65 +69,117 @@ def _wrapped(message, offset, partition, timestamp):
     )
 
     if rv is None:
-        return rv
+        return [], None, None
 
-    assert isinstance(rv, InsertBatch), "this consumer does not support replacements"
+    if isinstance(rv, ReplacementBatch):
+        values = [rapidjson.dumps(value).encode("utf-8") for value in rv.values]
+        return [], None, (rv.key.encode("utf-8"), values)
 
-    return [json_row_encoder.encode(row) for row in rv.rows]
-"#
-        );
+    assert isinstance(rv, InsertBatch)
 
-        let py_process_message = Python::with_gil(|py| -> PyResult<Py<PyAny>> {
-            let fun: Py<PyAny> = PyModule::from_code(py, &code, "", "")?
-                .getattr("_wrapped")?
-                .into();
-            Ok(fun)
-        })?;
+    rows = [json_row_encoder.encode(row) for row in rv.rows]
+    return rows, _timestamp(rv.origin_timestamp), None
+"#
+    );
+
+    let py_process_message = Python::with_gil(|py| -> PyResult<Py<PyAny>> {
+        let fun: Py<PyAny> = PyModule::from_code(py, &code, "", "")?
+            .getattr("_wrapped")?
+            .into();
+        Ok(fun)
+    })?;
+    Ok(py_process_message)
+}
 
-        Ok(PythonTransformStep {
+impl PythonTransformStep {
+    /// ``py_process_message`` is the function returned by ``load_processor``.
+    pub fn new(
+        py_process_message: Py<PyAny>,
+        next_step: Box<dyn ProcessingStrategy<BytesInsertBatch>>,
+    ) -> Self {
+        PythonTransformStep {
             next_step,
             py_process_message,
-        })
+            message_carried_over: None,
+        }
+    }
+
+    fn submit_carried_over(&mut self) -> Result<(), InvalidMessage> {
+        if let Some(message) = self.message_carried_over.take() {
+            match self.next_step.submit(message) {
+                Ok(()) => {}
+                Err(SubmitError::MessageRejected(MessageRejected { message })) => {
+                    self.message_carried_over = Some(message);
+                }
+                Err(SubmitError::InvalidMessage(invalid)) => return Err(invalid),
+            }
+        }
+        Ok(())
     }
 }
 
 impl ProcessingStrategy<KafkaPayload> for PythonTransformStep {
-    fn poll(&mut self) -> Option<CommitRequest> {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        self.submit_carried_over()?;
         self.next_step.poll()
     }
 
-    fn submit(&mut self, message: Message<KafkaPayload>) -> Result<(), MessageRejected> {
+    fn submit(&mut self, message: Message<KafkaPayload>) -> Result<(), SubmitError<KafkaPayload>> {
+        if self.message_carried_over.is_some() {
+            return Err(SubmitError::MessageRejected(MessageRejected { message }));
+        }
+
         // TODO: add procspawn/parallelism
         log::debug!("processing message,  message={}", message);
 
+        // The processor needs the partition and offset of the message, a
+        // message built out of several ones is raised as invalid.
+        let InnerMessage::BrokerMessage(BrokerMessage {
+            payload,
+            offset,
+            partition,
+            timestamp,
+        }) = &message.inner_message
+        else {
+            return raise_invalid_message(&message);
+        };
+
         let result = Python::with_gil(|py| -> PyResult<BytesInsertBatch> {
-            match &message.inner_message {
-                InnerMessage::AnyMessage(..) => {
-                    panic!("AnyMessage cannot be processed");
-                }
-                InnerMessage::BrokerMessage(BrokerMessage {
-                    payload,
-                    offset,
-                    partition,
-                    timestamp,
-                }) => {
-                    let args = (
-                        payload.payload.clone(),
-                        *offset,
-                        partition.index,
-                        *timestamp,
-                    );
-                    let result = self.py_process_message.call1(py, args)?;
-                    let result_decoded: Vec<Vec<u8>> = result.extract(py)?;
-                    Ok(BytesInsertBatch {
-                        rows: result_decoded,
-                    })
-                }
+            let args = (
+                payload.payload.as_deref().map(|p| PyBytes::new(py, p)),
+                *offset,
+                partition.index,
+                *timestamp,
+            );
+            let result = self.py_process_message.call1(py, args)?;
+            let (rows, origin_timestamp, replacements): ProcessedMessage = result.extract(py)?;
+            Ok(BytesInsertBatch {
+                rows,
+                replacements: replacements.map(|(key, values)| ReplacementBatch { key, values }),
+                origin_timestamp: origin_timestamp.and_then(|timestamp| {
+                    DateTime::from_timestamp_millis((timestamp * 1000.0) as i64)
+                }),
+                sentry_received_timestamp: None,
+            })
+        });
+        let result = match result {
+            Ok(result) => result,
+            // The processor raised, for instance on a payload it cannot
+            // decode, the message is dead lettered or crashes the consumer.
+            Err(error) => {
+                log::error!("Python processor failed on {}: {}", message, error);
+                return raise_invalid_message(&message);
             }
-        })
-        .unwrap();
+        };
 
-        self.next_step.submit(message.replace(result))
+        match self.next_step.submit(message.replace(result)) {
+            Ok(()) => Ok(()),
+            Err(SubmitError::MessageRejected(MessageRejected { message })) => {
+                self.message_carried_over = Some(message);
+                Ok(())
+            }
+            Err(SubmitError::InvalidMessage(invalid)) => Err(invalid.into()),
+        }
     }
 
     fn close(&mut self) {
@@ -123,6 +193,15 @@ impl ProcessingStrategy<KafkaPayload> for PythonTransformStep {
     fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
         // TODO: we need to shut down the python module properly in order to avoid dataloss in
         // sentry sdk or similar things that run in python's atexit
+        if let Err(invalid) = self.submit_carried_over() {
+            report_invalid_message_on_join("PythonTransformStep", &invalid);
+        }
         self.next_step.join(timeout)
     }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription::new("PythonTransformStep")
+            .with_buffered_messages(self.message_carried_over.is_some() as usize)
+            .with_next_step(self.next_step.describe())
+    }
 }
@@ -0,0 +1,299 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+
+use rust_arroyo::backends::kafka::types::KafkaPayload;
+use rust_arroyo::backends::Producer;
+use rust_arroyo::processing::strategies::produce::Delivery;
+use rust_arroyo::processing::strategies::retry::RetryPolicy;
+use rust_arroyo::processing::strategies::{
+    report_invalid_message_on_join, CommitRequest, InvalidMessage, MessageRejected,
+    ProcessingStrategy, StrategyDescription, SubmitError,
+};
+use rust_arroyo::types::{Message, TopicOrPartition};
+use rust_arroyo::utils::timing::Deadline;
+
+use crate::types::BytesInsertBatch;
+
+/// Produces the replacements of every message to the replacements topic,
+/// one Kafka message per value like the ``ReplacementBatchWriter`` of the
+/// Python consumers, and forwards the message without them to the next
+/// step once all of them were delivered.
+///
+/// Messages are forwarded in the order they were submitted, so a message
+/// without replacements waits for the replacements submitted before it.
+/// Failed deliveries are retried with ``RetryPolicy``, the message whose
+/// replacements still fail is raised as ``InvalidMessage``.
+pub struct ProduceReplacements {
+    next_step: Box<dyn ProcessingStrategy<BytesInsertBatch>>,
+    producer: Arc<dyn Producer<KafkaPayload>>,
+    destination: TopicOrPartition,
+    retry_policy: RetryPolicy,
+    // The messages and the deliveries of their replacements that are still
+    // in flight.
+    queue: VecDeque<(Message<BytesInsertBatch>, Vec<Delivery<KafkaPayload>>)>,
+    message_carried_over: Option<Message<BytesInsertBatch>>,
+    max_queue_size: usize,
+}
+
+impl ProduceReplacements {
+    pub fn new(
+        next_step: Box<dyn ProcessingStrategy<BytesInsertBatch>>,
+        producer: Arc<dyn Producer<KafkaPayload>>,
+        destination: TopicOrPartition,
+    ) -> Self {
+        ProduceReplacements {
+            next_step,
+            producer,
+            destination,
+            retry_policy: RetryPolicy::default(),
+            queue: VecDeque::new(),
+            message_carried_over: None,
+            max_queue_size: 1000,
+        }
+    }
+
+    /// Returns whether the next step accepted the message.
+    fn forward(&mut self, message: Message<BytesInsertBatch>) -> Result<bool, InvalidMessage> {
+        match self.next_step.submit(message) {
+            Ok(()) => Ok(true),
+            Err(SubmitError::MessageRejected(MessageRejected { message })) => {
+                self.message_carried_over = Some(message);
+                Ok(false)
+            }
+            Err(SubmitError::InvalidMessage(invalid)) => Err(invalid),
+        }
+    }
+
+    fn forward_completed(&mut self) -> Result<(), InvalidMessage> {
+        if let Some(message) = self.message_carried_over.take() {
+            if !self.forward(message)? {
+                return Ok(());
+            }
+        }
+
+        while let Some((_, deliveries)) = self.queue.front_mut() {
+            let mut failed = None;
+            deliveries.retain_mut(|delivery| {
+                match delivery.poll(
+                    self.producer.as_ref(),
+                    &self.destination,
+                    &self.retry_policy,
+                ) {
+                    Ok(delivered) => !delivered,
+                    Err(error) => {
+                        failed.get_or_insert(error);
+                        false
+                    }
+                }
+            });
+            if let Some(error) = failed {
+                let (message, _) = self.queue.pop_front().unwrap();
+                log::error!("Failed to produce a replacement of {}: {}", message, error);
+                match InvalidMessage::for_message(&message) {
+                    Some(invalid) => return Err(invalid),
+                    None => continue,
+                }
+            }
+            if !deliveries.is_empty() {
+                break;
+            }
+            let (message, _) = self.queue.pop_front().unwrap();
+            if !self.forward(message)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ProcessingStrategy<BytesInsertBatch> for ProduceReplacements {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        self.forward_completed()?;
+        self.next_step.poll()
+    }
+
+    fn submit(
+        &mut self,
+        message: Message<BytesInsertBatch>,
+    ) -> Result<(), SubmitError<BytesInsertBatch>> {
+        if self.queue.len() >= self.max_queue_size {
+            return Err(SubmitError::MessageRejected(MessageRejected { message }));
+        }
+
+        let mut batch = message.payload();
+        let deliveries = match batch.replacements.take() {
+            Some(replacements) => replacements
+                .values
+                .into_iter()
+                .map(|value| {
+                    let payload = KafkaPayload {
+                        key: Some(replacements.key.clone().into()),
+                        headers: None,
+                        payload: Some(value.into()),
+                    };
+                    Delivery::produce(self.producer.as_ref(), &self.destination, payload)
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        self.queue.push_back((message.replace(batch), deliveries));
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.next_step.close();
+    }
+
+    fn terminate(&mut self) {
+        self.queue.clear();
+        self.next_step.terminate();
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        let deadline = Deadline::from_timeout(timeout);
+        loop {
+            if let Err(invalid) = self.forward_completed() {
+                report_invalid_message_on_join("ProduceReplacements", &invalid);
+            }
+            if self.queue.is_empty() && self.message_carried_over.is_none() {
+                break;
+            }
+            if deadline.has_elapsed() {
+                log::warn!("Timeout reached while waiting for the replacements to be produced");
+                break;
+            }
+            sleep(Duration::from_millis(1));
+        }
+        self.next_step.join(deadline.remaining())
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription::new("ProduceReplacements")
+            .with_buffered_messages(self.queue.len() + self.message_carried_over.is_some() as usize)
+            .with_next_step(self.next_step.describe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProduceReplacements;
+    use crate::types::{BytesInsertBatch, ReplacementBatch};
+    use chrono::Utc;
+    use rust_arroyo::backends::kafka::types::KafkaPayload;
+    use rust_arroyo::processing::strategies::retry::RetryPolicy;
+    use rust_arroyo::processing::strategies::testutils::{partition, Recorder, RecordingProducer};
+    use rust_arroyo::processing::strategies::ProcessingStrategy;
+    use rust_arroyo::types::{Message, Topic, TopicOrPartition};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_produce_replacements() {
+        let producer = Arc::new(RecordingProducer::<KafkaPayload>::default());
+        let next_step = Recorder::default();
+        let submitted = next_step.submitted.clone();
+        let mut strategy = ProduceReplacements::new(
+            Box::new(next_step),
+            producer.clone(),
+            TopicOrPartition::Topic(Topic {
+                name: "event-replacements".to_string(),
+            }),
+        );
+
+        let partition = partition("events", 0);
+        let batches = [
+            BytesInsertBatch {
+                rows: vec![b"{}".to_vec()],
+                ..Default::default()
+            },
+            BytesInsertBatch {
+                rows: vec![],
+                replacements: Some(ReplacementBatch {
+                    key: b"1".to_vec(),
+                    values: vec![b"[2,\"end_delete_groups\"]".to_vec(), b"[2]".to_vec()],
+                }),
+                ..Default::default()
+            },
+        ];
+        for (offset, batch) in batches.into_iter().enumerate() {
+            strategy
+                .submit(Message::new_broker_message(
+                    batch,
+                    partition.clone(),
+                    offset as u64,
+                    Utc::now(),
+                ))
+                .unwrap();
+        }
+        strategy.poll().unwrap();
+
+        let produced: Vec<_> = producer
+            .produced()
+            .iter()
+            .map(|payload| {
+                (
+                    payload.key.as_deref().unwrap().to_vec(),
+                    payload.payload.as_deref().unwrap().to_vec(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            produced,
+            vec![
+                (b"1".to_vec(), b"[2,\"end_delete_groups\"]".to_vec()),
+                (b"1".to_vec(), b"[2]".to_vec()),
+            ]
+        );
+
+        // The replacements are not forwarded to the writer
+        let submitted = submitted.payloads();
+        assert_eq!(submitted.len(), 2);
+        assert_eq!(submitted[0].rows, vec![b"{}".to_vec()]);
+        assert!(submitted[1].replacements.is_none());
+    }
+
+    #[test]
+    fn test_retry_failed_replacement() {
+        let producer = Arc::new(RecordingProducer::<KafkaPayload>::failing(1));
+        let next_step = Recorder::default();
+        let submitted = next_step.submitted.clone();
+        let mut strategy = ProduceReplacements::new(
+            Box::new(next_step),
+            producer.clone(),
+            TopicOrPartition::Topic(Topic {
+                name: "event-replacements".to_string(),
+            }),
+        );
+        strategy.retry_policy = RetryPolicy {
+            max_attempts: 2,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        };
+
+        let batch = BytesInsertBatch {
+            replacements: Some(ReplacementBatch {
+                key: b"1".to_vec(),
+                values: vec![b"[2]".to_vec()],
+            }),
+            ..Default::default()
+        };
+        strategy
+            .submit(Message::new_broker_message(
+                batch,
+                partition("events", 0),
+                0,
+                Utc::now(),
+            ))
+            .unwrap();
+
+        // The message waits for its replacement to be produced again.
+        strategy.poll().unwrap();
+        strategy.poll().unwrap();
+        assert!(submitted.payloads().is_empty());
+        strategy.poll().unwrap();
+        assert_eq!(producer.produced().len(), 1);
+        assert_eq!(submitted.payloads().len(), 1);
+    }
+}
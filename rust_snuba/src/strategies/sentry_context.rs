@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use rust_arroyo::processing::strategies::{
+    CommitRequest, InvalidMessage, ProcessingStrategy, StrategyDescription, SubmitError,
+};
+use rust_arroyo::types::{InnerMessage, Message};
+
+/// Tags the Sentry scope with the partition and offset of the message being
+/// submitted, so that a panic or an error raised while processing it can be
+/// traced back to the message. The tags are removed once ``submit`` returns.
+pub struct SentryContext<TPayload: Clone> {
+    next_step: Box<dyn ProcessingStrategy<TPayload>>,
+}
+
+impl<TPayload: Clone> SentryContext<TPayload> {
+    pub fn new(next_step: Box<dyn ProcessingStrategy<TPayload>>) -> Self {
+        SentryContext { next_step }
+    }
+}
+
+impl<TPayload: Clone> ProcessingStrategy<TPayload> for SentryContext<TPayload> {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        self.next_step.poll()
+    }
+
+    fn submit(&mut self, message: Message<TPayload>) -> Result<(), SubmitError<TPayload>> {
+        let InnerMessage::BrokerMessage(broker_message) = &message.inner_message else {
+            return self.next_step.submit(message);
+        };
+        let (partition, offset) = (broker_message.partition.to_string(), broker_message.offset);
+        // The tags only apply while the message is submitted, so that what is
+        // reported afterwards is not attributed to it.
+        sentry::with_scope(
+            |scope| {
+                scope.set_tag("partition", partition);
+                scope.set_tag("offset", offset);
+            },
+            || self.next_step.submit(message),
+        )
+    }
+
+    fn close(&mut self) {
+        self.next_step.close();
+    }
+
+    fn terminate(&mut self) {
+        self.next_step.terminate();
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        self.next_step.join(timeout)
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription::new("SentryContext").with_next_step(self.next_step.describe())
+    }
+}
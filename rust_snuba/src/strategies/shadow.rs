@@ -0,0 +1,267 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+
+use rust_arroyo::backends::kafka::types::KafkaPayload;
+use rust_arroyo::backends::Producer;
+use rust_arroyo::processing::strategies::produce::Delivery;
+use rust_arroyo::processing::strategies::retry::RetryPolicy;
+use rust_arroyo::processing::strategies::{
+    report_invalid_message_on_join, CommitRequest, InvalidMessage, MessageRejected,
+    ProcessingStrategy, StrategyDescription, SubmitError,
+};
+use rust_arroyo::types::{Message, TopicOrPartition};
+use rust_arroyo::utils::metrics;
+use rust_arroyo::utils::timing::Deadline;
+
+use crate::types::BytesInsertBatch;
+
+/// Replaces the ClickHouse writer in shadow mode: every row is produced to
+/// ``destination``, keyed by the name of the storage, instead of being
+/// inserted. The rows can then be compared to those the Python consumer of
+/// the storage writes, before switching to the Rust consumer.
+///
+/// Offsets are committed once all the rows of the messages before them were
+/// delivered. Failed deliveries are retried with ``RetryPolicy``, the
+/// message whose rows still fail is raised as ``InvalidMessage``.
+pub struct ProduceRows {
+    producer: Arc<dyn Producer<KafkaPayload>>,
+    destination: TopicOrPartition,
+    key: Arc<[u8]>,
+    retry_policy: RetryPolicy,
+    // The messages whose offsets are committed once their rows were
+    // delivered.
+    queue: VecDeque<(Message<()>, Vec<Delivery<KafkaPayload>>)>,
+    // Raised on the next poll, after the offsets delivered before it.
+    invalid_message: Option<InvalidMessage>,
+    max_queue_size: usize,
+}
+
+impl ProduceRows {
+    pub fn new(
+        producer: Arc<dyn Producer<KafkaPayload>>,
+        destination: TopicOrPartition,
+        storage: &str,
+    ) -> Self {
+        ProduceRows {
+            producer,
+            destination,
+            key: storage.as_bytes().into(),
+            retry_policy: RetryPolicy::default(),
+            queue: VecDeque::new(),
+            invalid_message: None,
+            max_queue_size: 1000,
+        }
+    }
+
+    fn commit_delivered(&mut self) -> Option<CommitRequest> {
+        let mut positions = HashMap::new();
+        while let Some((_, deliveries)) = self.queue.front_mut() {
+            let mut failed = None;
+            deliveries.retain_mut(|delivery| {
+                match delivery.poll(
+                    self.producer.as_ref(),
+                    &self.destination,
+                    &self.retry_policy,
+                ) {
+                    Ok(delivered) => !delivered,
+                    Err(error) => {
+                        failed.get_or_insert(error);
+                        false
+                    }
+                }
+            });
+            if let Some(error) = failed {
+                let (message, _) = self.queue.pop_front().unwrap();
+                log::error!(
+                    "Failed to produce the shadow rows of {}: {}",
+                    message,
+                    error
+                );
+                self.invalid_message = InvalidMessage::for_message(&message);
+                break;
+            }
+            if !deliveries.is_empty() {
+                break;
+            }
+            let (message, _) = self.queue.pop_front().unwrap();
+            positions.extend(message.committable());
+        }
+        if positions.is_empty() {
+            return None;
+        }
+        Some(CommitRequest { positions })
+    }
+}
+
+impl ProcessingStrategy<BytesInsertBatch> for ProduceRows {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        if let Some(invalid) = self.invalid_message.take() {
+            return Err(invalid);
+        }
+        Ok(self.commit_delivered())
+    }
+
+    fn submit(
+        &mut self,
+        message: Message<BytesInsertBatch>,
+    ) -> Result<(), SubmitError<BytesInsertBatch>> {
+        if self.queue.len() >= self.max_queue_size {
+            return Err(SubmitError::MessageRejected(MessageRejected { message }));
+        }
+
+        let batch = message.payload();
+        metrics::increment(
+            "shadow.rows_produced",
+            Some(batch.rows.len() as i64),
+            None,
+            None,
+        );
+        let deliveries = batch
+            .rows
+            .into_iter()
+            .map(|row| {
+                let payload = KafkaPayload {
+                    key: Some(self.key.clone()),
+                    headers: None,
+                    payload: Some(row.into()),
+                };
+                Delivery::produce(self.producer.as_ref(), &self.destination, payload)
+            })
+            .collect();
+        self.queue.push_back((message.replace(()), deliveries));
+        Ok(())
+    }
+
+    fn close(&mut self) {}
+
+    fn terminate(&mut self) {
+        self.queue.clear();
+        self.invalid_message = None;
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        let deadline = Deadline::from_timeout(timeout);
+        let mut positions = HashMap::new();
+        loop {
+            if let Some(commit_request) = self.commit_delivered() {
+                positions.extend(commit_request.positions);
+            }
+            if let Some(invalid) = self.invalid_message.take() {
+                report_invalid_message_on_join("ProduceRows", &invalid);
+            }
+            if self.queue.is_empty() {
+                break;
+            }
+            if deadline.has_elapsed() {
+                log::warn!("Timeout reached while waiting for the shadow rows to be produced");
+                break;
+            }
+            sleep(Duration::from_millis(1));
+        }
+        if positions.is_empty() {
+            return None;
+        }
+        Some(CommitRequest { positions })
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription::new("ProduceRows").with_buffered_messages(self.queue.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProduceRows;
+    use crate::types::BytesInsertBatch;
+    use chrono::Utc;
+    use rust_arroyo::backends::kafka::types::KafkaPayload;
+    use rust_arroyo::processing::strategies::retry::RetryPolicy;
+    use rust_arroyo::processing::strategies::testutils::{partition, RecordingProducer};
+    use rust_arroyo::processing::strategies::ProcessingStrategy;
+    use rust_arroyo::types::{Message, Topic, TopicOrPartition};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_produce_rows() {
+        let producer = Arc::new(RecordingProducer::<KafkaPayload>::default());
+        let mut strategy = ProduceRows::new(
+            producer.clone(),
+            TopicOrPartition::Topic(Topic {
+                name: "snuba-shadow-rows".to_string(),
+            }),
+            "outcomes_raw",
+        );
+
+        let partition = partition("outcomes", 0);
+        for (offset, rows) in [vec![b"{\"a\":1}".to_vec(), b"{\"a\":2}".to_vec()], vec![]]
+            .into_iter()
+            .enumerate()
+        {
+            let batch = BytesInsertBatch {
+                rows,
+                ..Default::default()
+            };
+            strategy
+                .submit(Message::new_broker_message(
+                    batch,
+                    partition.clone(),
+                    offset as u64,
+                    Utc::now(),
+                ))
+                .unwrap();
+        }
+
+        let commit_request = strategy.poll().unwrap().unwrap();
+        assert_eq!(commit_request.offsets(), HashMap::from([(partition, 2)]));
+
+        let produced = producer.produced();
+        assert_eq!(produced.len(), 2);
+        assert_eq!(produced[0].key.as_deref(), Some(&b"outcomes_raw"[..]));
+        assert_eq!(produced[1].payload.as_deref(), Some(&b"{\"a\":2}"[..]));
+    }
+
+    #[test]
+    fn test_raise_failed_rows() {
+        let producer = Arc::new(RecordingProducer::<KafkaPayload>::failing(1));
+        let mut strategy = ProduceRows::new(
+            producer.clone(),
+            TopicOrPartition::Topic(Topic {
+                name: "snuba-shadow-rows".to_string(),
+            }),
+            "outcomes_raw",
+        );
+        strategy.retry_policy = RetryPolicy {
+            max_attempts: 1,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        };
+
+        for offset in 0..2 {
+            let batch = BytesInsertBatch {
+                rows: vec![b"{}".to_vec()],
+                ..Default::default()
+            };
+            strategy
+                .submit(Message::new_broker_message(
+                    batch,
+                    partition("outcomes", 0),
+                    offset,
+                    Utc::now(),
+                ))
+                .unwrap();
+        }
+
+        // The message is raised before the offsets after it are committed.
+        assert_eq!(strategy.poll(), Ok(None));
+        assert_eq!(strategy.poll().unwrap_err().offset, 0);
+        let commit_request = strategy.poll().unwrap().unwrap();
+        assert_eq!(
+            commit_request.offsets(),
+            HashMap::from([(partition("outcomes", 0), 2)])
+        );
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use rust_arroyo::processing::strategies::tee::SharedCommits;
+use rust_arroyo::processing::strategies::{
+    raise_invalid_message, report_invalid_message_on_join, CommitRequest, InvalidMessage,
+    MessageRejected, ProcessingStrategy, StrategyDescription, SubmitError,
+};
+use rust_arroyo::types::Message;
+use rust_arroyo::utils::timing::Deadline;
+use serde_json::{Map, Value};
+
+use crate::config::SlicingConfig;
+use crate::encoders::json::JsonEachRowEncoder;
+use crate::encoders::RowsEncoder;
+use crate::types::BytesInsertBatch;
+
+/// Routes every row to the writer of its slice, like the sliced storage
+/// sets of Snuba. The slice of a row is the slice of the logical partition
+/// of its shard key, usually the organization id.
+///
+/// Every writer is submitted every message, with only the rows of its
+/// slice, so that offsets are only committed once all the slices inserted
+/// their rows. The rows are checked with the ``encoder`` of the writers
+/// first, a message with a row that would be invalid for any writer is not
+/// submitted to any of them.
+pub struct SlicedWriter {
+    config: SlicingConfig,
+    encoder: Arc<dyn RowsEncoder>,
+    // The writer of every slice and its index in ``writers``.
+    slices: HashMap<u32, usize>,
+    writers: Vec<Box<dyn ProcessingStrategy<BytesInsertBatch>>>,
+    commits: SharedCommits,
+    // The messages the writers rejected.
+    carried_over: Vec<(usize, Message<BytesInsertBatch>)>,
+}
+
+impl SlicedWriter {
+    pub fn new(
+        config: SlicingConfig,
+        writers: Vec<(u32, Box<dyn ProcessingStrategy<BytesInsertBatch>>)>,
+    ) -> Self {
+        let slices = writers
+            .iter()
+            .enumerate()
+            .map(|(index, (slice_id, _))| (*slice_id, index))
+            .collect();
+        let writers: Vec<_> = writers.into_iter().map(|(_, writer)| writer).collect();
+        SlicedWriter {
+            config,
+            encoder: Arc::new(JsonEachRowEncoder),
+            slices,
+            commits: SharedCommits::new(writers.len()),
+            writers,
+            carried_over: Vec::new(),
+        }
+    }
+
+    pub fn with_encoder(mut self, encoder: Arc<dyn RowsEncoder>) -> Self {
+        self.encoder = encoder;
+        self
+    }
+
+    /// Returns the index of the writer of the row.
+    fn route(&self, row: &[u8]) -> Result<usize, anyhow::Error> {
+        let row: Map<String, Value> = serde_json::from_slice(row)?;
+        let shard_key = row
+            .get(self.config.shard_column.as_str())
+            .and_then(Value::as_u64)
+            .with_context(|| format!("No {} in the row", self.config.shard_column))?;
+        let logical_partition = shard_key % self.config.logical_partitions;
+        let slice_id = self
+            .config
+            .logical_partition_mapping
+            .get(&logical_partition)
+            .with_context(|| format!("No slice for logical partition {}", logical_partition))?;
+        self.slices
+            .get(slice_id)
+            .copied()
+            .with_context(|| format!("No cluster for slice {}", slice_id))
+    }
+
+    fn submit_carried_over(&mut self) -> Result<(), InvalidMessage> {
+        let mut rejected = Vec::new();
+        for (index, message) in std::mem::take(&mut self.carried_over) {
+            match self.writers[index].submit(message) {
+                Ok(()) => {}
+                Err(SubmitError::MessageRejected(MessageRejected { message })) => {
+                    rejected.push((index, message))
+                }
+                Err(SubmitError::InvalidMessage(invalid)) => return Err(invalid),
+            }
+        }
+        self.carried_over = rejected;
+        Ok(())
+    }
+}
+
+impl ProcessingStrategy<BytesInsertBatch> for SlicedWriter {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        self.submit_carried_over()?;
+        for index in 0..self.writers.len() {
+            let commit_request = self.writers[index].poll()?;
+            self.commits.record(index, commit_request);
+        }
+        Ok(self.commits.take())
+    }
+
+    fn submit(
+        &mut self,
+        message: Message<BytesInsertBatch>,
+    ) -> Result<(), SubmitError<BytesInsertBatch>> {
+        if !self.carried_over.is_empty() {
+            return Err(SubmitError::MessageRejected(MessageRejected { message }));
+        }
+
+        let mut batch = message.payload();
+        let message_rows = mem::take(&mut batch.rows);
+        let mut rows = vec![Vec::new(); self.writers.len()];
+        let mut encoded = Vec::new();
+        for row in message_rows {
+            let routed = self.route(&row).and_then(|index| {
+                encoded.clear();
+                self.encoder.encode(&row, &mut encoded)?;
+                Ok(index)
+            });
+            match routed {
+                Ok(index) => rows[index].push(row),
+                Err(error) => {
+                    log::error!(
+                        "Failed to route or encode a row of {}: {:#}",
+                        message,
+                        error
+                    );
+                    return raise_invalid_message(&message);
+                }
+            }
+        }
+
+        for (index, rows) in rows.into_iter().enumerate() {
+            let message = message.clone().replace(BytesInsertBatch {
+                rows,
+                ..batch.clone()
+            });
+            match self.writers[index].submit(message) {
+                Ok(()) => {}
+                Err(SubmitError::MessageRejected(MessageRejected { message })) => {
+                    self.carried_over.push((index, message))
+                }
+                Err(SubmitError::InvalidMessage(invalid)) => return Err(invalid.into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        for writer in &mut self.writers {
+            writer.close();
+        }
+    }
+
+    fn terminate(&mut self) {
+        for writer in &mut self.writers {
+            writer.terminate();
+        }
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        let deadline = Deadline::from_timeout(timeout);
+        if let Err(invalid) = self.submit_carried_over() {
+            report_invalid_message_on_join("SlicedWriter", &invalid);
+        }
+        for index in 0..self.writers.len() {
+            let commit_request = self.writers[index].join(deadline.remaining());
+            self.commits.record(index, commit_request);
+        }
+        self.commits.take()
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        let mut description = StrategyDescription::new("SlicedWriter")
+            .with_buffered_messages(self.carried_over.len());
+        for writer in &self.writers {
+            description = description.with_next_step(writer.describe());
+        }
+        description
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SlicedWriter;
+    use crate::config::{ColumnConfig, SlicingConfig};
+    use crate::encoders::csv::CsvWithNamesEncoder;
+    use crate::types::BytesInsertBatch;
+    use chrono::Utc;
+    use rust_arroyo::processing::strategies::testutils::{partition, Recorder};
+    use rust_arroyo::processing::strategies::{CommitRequest, ProcessingStrategy, SubmitError};
+    use rust_arroyo::types::{Message, Position};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_sliced_writer() {
+        let first = Recorder::committing();
+        let first_batches = first.submitted.clone();
+        let second = Recorder::committing();
+        let second_batches = second.submitted.clone();
+        let rows = |batches: Vec<BytesInsertBatch>| -> Vec<Vec<u8>> {
+            batches.into_iter().flat_map(|batch| batch.rows).collect()
+        };
+        let config = SlicingConfig {
+            shard_column: "org_id".to_string(),
+            logical_partitions: 4,
+            logical_partition_mapping: HashMap::from([(0, 0), (1, 0), (2, 1), (3, 1)]),
+            clusters: HashMap::new(),
+        };
+        let mut writer =
+            SlicedWriter::new(config, vec![(0, Box::new(first)), (1, Box::new(second))]);
+
+        let partition = partition("metrics", 0);
+        let batch = BytesInsertBatch {
+            rows: vec![
+                b"{\"org_id\":1}".to_vec(),
+                b"{\"org_id\":6}".to_vec(),
+                b"{\"org_id\":4}".to_vec(),
+            ],
+            ..Default::default()
+        };
+        let now = Utc::now();
+        writer
+            .submit(Message::new_broker_message(
+                batch,
+                partition.clone(),
+                0,
+                now,
+            ))
+            .unwrap();
+        assert_eq!(
+            writer.poll().unwrap(),
+            Some(CommitRequest {
+                positions: HashMap::from([(partition.clone(), Position::new(1, now))]),
+            })
+        );
+        assert_eq!(
+            rows(first_batches.payloads()),
+            vec![b"{\"org_id\":1}".to_vec(), b"{\"org_id\":4}".to_vec()]
+        );
+        assert_eq!(
+            rows(second_batches.payloads()),
+            vec![b"{\"org_id\":6}".to_vec()]
+        );
+
+        // Rows without a shard key cannot be routed
+        let batch = BytesInsertBatch {
+            rows: vec![b"{\"project_id\":1}".to_vec()],
+            ..Default::default()
+        };
+        let result = writer.submit(Message::new_broker_message(
+            batch,
+            partition.clone(),
+            1,
+            Utc::now(),
+        ));
+        assert!(matches!(result, Err(SubmitError::InvalidMessage(_))));
+
+        // None of the rows are written if one of them cannot be encoded
+        let columns = ["org_id", "tags.key"].map(|name| ColumnConfig {
+            name: name.to_string(),
+            r#type: "String".to_string(),
+        });
+        writer = writer.with_encoder(Arc::new(CsvWithNamesEncoder::new(&columns)));
+        let batch = BytesInsertBatch {
+            rows: vec![
+                b"{\"org_id\":1}".to_vec(),
+                b"{\"org_id\":6,\"tags.key\":{}}".to_vec(),
+            ],
+            ..Default::default()
+        };
+        let result = writer.submit(Message::new_broker_message(batch, partition, 2, Utc::now()));
+        assert!(matches!(result, Err(SubmitError::InvalidMessage(_))));
+        assert_eq!(first_batches.messages().len(), 1);
+        assert_eq!(second_batches.messages().len(), 1);
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use rust_arroyo::backends::kafka::types::KafkaPayload;
+use rust_arroyo::processing::strategies::{
+    CommitRequest, InvalidMessage, ProcessingStrategy, StrategyDescription, SubmitError,
+};
+use rust_arroyo::types::{InnerMessage, Message};
+use rust_arroyo::utils::metrics;
+use sentry_kafka_schemas::{Schema, SchemaError};
+
+/// Validates the payload of every message against the JSON schema of the
+/// topic from ``sentry-kafka-schemas``.
+///
+/// Invalid messages are logged and forwarded, unless ``enforce`` is set. In
+/// that case they are raised as ``InvalidMessage``, which sends them to the
+/// dead letter queue if the stream processor has one and crashes the
+/// consumer otherwise. Topics without a schema are not validated.
+pub struct ValidateSchema {
+    topic: String,
+    schema: Option<Schema>,
+    enforce: bool,
+    next_step: Box<dyn ProcessingStrategy<KafkaPayload>>,
+}
+
+impl ValidateSchema {
+    /// ``topic`` is the logical name of the topic, such as ``events``, not
+    /// the physical name it can be overridden with.
+    pub fn new(
+        topic: &str,
+        enforce: bool,
+        next_step: Box<dyn ProcessingStrategy<KafkaPayload>>,
+    ) -> Self {
+        let schema = match sentry_kafka_schemas::get_schema(topic, None) {
+            Ok(schema) => Some(schema),
+            Err(SchemaError::TopicNotFound) => None,
+            Err(error) => {
+                log::error!("Failed to load the schema of {}: {}", topic, error);
+                None
+            }
+        };
+        ValidateSchema {
+            topic: topic.to_owned(),
+            schema,
+            enforce,
+            next_step,
+        }
+    }
+
+    fn validate(&self, payload: &KafkaPayload) -> Result<(), String> {
+        let schema = match &self.schema {
+            Some(schema) => schema,
+            None => return Ok(()),
+        };
+        let payload = payload.payload.as_deref().ok_or("Empty payload")?;
+        schema
+            .validate_json(payload)
+            .map(|_| ())
+            .map_err(|error| error.to_string())
+    }
+}
+
+impl ProcessingStrategy<KafkaPayload> for ValidateSchema {
+    fn poll(&mut self) -> Result<Option<CommitRequest>, InvalidMessage> {
+        self.next_step.poll()
+    }
+
+    fn submit(&mut self, message: Message<KafkaPayload>) -> Result<(), SubmitError<KafkaPayload>> {
+        if let InnerMessage::BrokerMessage(broker_message) = &message.inner_message {
+            if let Err(error) = self.validate(&broker_message.payload) {
+                // Same tag as the Python consumers.
+                sentry::configure_scope(|scope| {
+                    scope.set_tag("invalid_message_schema", "true");
+                });
+                log::warn!(
+                    "Invalid message for {} at {}: {}",
+                    self.topic,
+                    broker_message,
+                    error
+                );
+                metrics::increment(
+                    "schema_validation.invalid",
+                    None,
+                    Some(HashMap::from([("topic", self.topic.as_str())])),
+                    None,
+                );
+                if self.enforce {
+                    return Err(SubmitError::InvalidMessage(InvalidMessage::from(
+                        broker_message,
+                    )));
+                }
+            }
+        }
+        self.next_step.submit(message)
+    }
+
+    fn close(&mut self) {
+        self.next_step.close();
+    }
+
+    fn terminate(&mut self) {
+        self.next_step.terminate();
+    }
+
+    fn join(&mut self, timeout: Option<Duration>) -> Option<CommitRequest> {
+        self.next_step.join(timeout)
+    }
+
+    fn describe(&self) -> StrategyDescription {
+        StrategyDescription::new("ValidateSchema").with_next_step(self.next_step.describe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ValidateSchema;
+    use chrono::Utc;
+    use rust_arroyo::backends::kafka::types::KafkaPayload;
+    use rust_arroyo::processing::strategies::testutils::{partition, Recorder};
+    use rust_arroyo::processing::strategies::{ProcessingStrategy, SubmitError};
+    use rust_arroyo::types::Message;
+
+    fn message(payload: &[u8], offset: u64) -> Message<KafkaPayload> {
+        let payload = KafkaPayload {
+            key: None,
+            headers: None,
+            payload: Some(payload.into()),
+        };
+        Message::new_broker_message(
+            payload,
+            partition("snuba-generic-metrics", 0),
+            offset,
+            Utc::now(),
+        )
+    }
+
+    #[test]
+    fn test_validate_schema() {
+        let schema = sentry_kafka_schemas::get_schema("snuba-generic-metrics", None).unwrap();
+        let valid = schema.examples()[0].payload().to_vec();
+
+        for enforce in [false, true] {
+            let next_step = Recorder::default();
+            let submitted = next_step.submitted.clone();
+            let mut strategy =
+                ValidateSchema::new("snuba-generic-metrics", enforce, Box::new(next_step));
+            strategy.submit(message(&valid, 0)).unwrap();
+
+            let result = strategy.submit(message(b"{\"not\": \"a metric\"}", 1));
+            match result {
+                Err(SubmitError::InvalidMessage(invalid)) => {
+                    assert!(enforce);
+                    assert_eq!(invalid.offset, 1);
+                }
+                Err(_) => panic!("Expected an InvalidMessage error"),
+                Ok(()) => assert!(!enforce),
+            }
+            assert_eq!(submitted.messages().len(), if enforce { 1 } else { 2 });
+        }
+
+        // Topics without a schema are not validated
+        let next_step = Recorder::default();
+        let submitted = next_step.submitted.clone();
+        let mut strategy = ValidateSchema::new("no-such-topic", true, Box::new(next_step));
+        strategy.submit(message(b"not json", 0)).unwrap();
+        assert_eq!(submitted.messages().len(), 1);
+    }
+}
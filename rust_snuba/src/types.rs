@@ -1,6 +1,125 @@
-use serde::Deserialize;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize)]
 pub struct BytesInsertBatch {
     pub rows: Vec<Vec<u8>>,
+    /// The replacements the processor returned instead of rows, which are
+    /// produced to the replacements topic before the rows are written.
+    #[serde(default)]
+    pub replacements: Option<ReplacementBatch>,
+    #[serde(skip)]
+    pub origin_timestamp: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    pub sentry_received_timestamp: Option<DateTime<Utc>>,
+}
+
+impl From<InsertBatch> for BytesInsertBatch {
+    fn from(batch: InsertBatch) -> Self {
+        BytesInsertBatch {
+            rows: batch.rows,
+            replacements: batch.replacements,
+            origin_timestamp: batch.origin_timestamp,
+            sentry_received_timestamp: batch.sentry_received_timestamp,
+        }
+    }
+}
+
+/// Like the ``ReplacementBatch`` of the Python processors, with every
+/// value already encoded to JSON.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ReplacementBatch {
+    pub key: Vec<u8>,
+    pub values: Vec<Vec<u8>>,
+}
+
+/// What a Rust processor returns for a message: any number of rows, none
+/// if the message is skipped, or replacements, encoded to JSON. The timestamps are those of
+/// the event the message is about, which the latency of the insert is
+/// measured from.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InsertBatch {
+    pub rows: Vec<Vec<u8>>,
+    /// Like the ``ReplacementBatch`` a Python processor returns instead of
+    /// an ``InsertBatch``.
+    pub replacements: Option<ReplacementBatch>,
+    /// When the event happened, like the ``origin_timestamp`` of the
+    /// ``InsertBatch`` of the Python processors.
+    pub origin_timestamp: Option<DateTime<Utc>>,
+    /// When the event was received by Sentry.
+    pub sentry_received_timestamp: Option<DateTime<Utc>>,
+}
+
+impl InsertBatch {
+    /// A batch without rows, for the messages that are skipped.
+    pub fn skip() -> Self {
+        InsertBatch::default()
+    }
+
+    pub fn from_rows<T: Serialize>(
+        rows: impl IntoIterator<Item = T>,
+    ) -> Result<Self, serde_json::Error> {
+        let rows = rows
+            .into_iter()
+            .map(|row| serde_json::to_vec(&row))
+            .collect::<Result<_, _>>()?;
+        Ok(InsertBatch {
+            rows,
+            ..Default::default()
+        })
+    }
+
+    pub fn with_origin_timestamp(mut self, origin_timestamp: Option<DateTime<Utc>>) -> Self {
+        self.origin_timestamp = origin_timestamp;
+        self
+    }
+
+    pub fn with_sentry_received_timestamp(
+        mut self,
+        sentry_received_timestamp: Option<DateTime<Utc>>,
+    ) -> Self {
+        self.sentry_received_timestamp = sentry_received_timestamp;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BytesInsertBatch, InsertBatch, ReplacementBatch};
+    use chrono::Utc;
+    use serde_json::json;
+
+    #[test]
+    fn test_insert_batch() {
+        let now = Utc::now();
+        let batch = InsertBatch::from_rows([json!({"a": 1}), json!({"a": 2})])
+            .unwrap()
+            .with_origin_timestamp(Some(now));
+        assert_eq!(
+            batch.rows,
+            vec![br#"{"a":1}"#.to_vec(), br#"{"a":2}"#.to_vec()]
+        );
+
+        let batch = BytesInsertBatch::from(batch);
+        assert_eq!(batch.rows.len(), 2);
+        assert_eq!(batch.origin_timestamp, Some(now));
+        assert_eq!(batch.sentry_received_timestamp, None);
+        assert!(batch.replacements.is_none());
+
+        assert!(InsertBatch::from_rows(None::<u8>).unwrap().rows.is_empty());
+    }
+
+    #[test]
+    fn test_replacements() {
+        let replacements = ReplacementBatch {
+            key: b"1".to_vec(),
+            values: vec![br#"[2,"end_delete_groups"]"#.to_vec()],
+        };
+        let batch = BytesInsertBatch::from(InsertBatch {
+            replacements: Some(replacements.clone()),
+            ..Default::default()
+        });
+        assert!(batch.rows.is_empty());
+        assert_eq!(batch.replacements, Some(replacements));
+    }
 }
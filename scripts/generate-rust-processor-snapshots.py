@@ -0,0 +1,80 @@
+#!/usr/bin/env python3
+"""
+Writes the snapshots the Rust processors are tested against. Every message
+of the input file, a JSON document per line, is processed by the Python
+processor of the storage and saved with the rows it returned, encoded like
+the consumers send them to ClickHouse.
+
+The snapshots are written to rust_snuba/src/processors/snapshots/<storage>
+and checked by the ``test_snapshots`` test of rust_snuba.
+
+The processors that enforce a retention skip the messages that are older than
+it. For the snapshots not to expire, their messages should have a retention
+long enough to cover their timestamps, such as 36500 days.
+"""
+
+import argparse
+import json
+import os
+from datetime import datetime
+
+import rapidjson
+
+from snuba.clickhouse.http import JSONRowEncoder
+from snuba.consumers.types import KafkaMessageMetadata
+from snuba.datasets.storages.factory import get_writable_storage
+from snuba.datasets.storages.storage_key import StorageKey
+from snuba.processor import InsertBatch
+
+SNAPSHOTS_DIR = os.path.join(
+    os.path.dirname(__file__), "..", "rust_snuba", "src", "processors", "snapshots"
+)
+
+# Same as the metadata the Rust test gives to the processors.
+PARTITION = 0
+TIMESTAMP = datetime(2023, 10, 1, 12, 30, 45)
+
+
+def main() -> None:
+    parser = argparse.ArgumentParser(description=__doc__)
+    parser.add_argument("storage", help="The name of the storage")
+    parser.add_argument("messages", help="A file with a message per line")
+    parser.add_argument(
+        "--name", default="snapshot", help="The prefix of the snapshot files"
+    )
+    args = parser.parse_args()
+
+    storage = get_writable_storage(StorageKey(args.storage))
+    processor = storage.get_table_writer().get_stream_loader().get_processor()
+    encoder = JSONRowEncoder()
+
+    directory = os.path.join(SNAPSHOTS_DIR, args.storage)
+    os.makedirs(directory, exist_ok=True)
+
+    with open(args.messages) as messages:
+        for offset, line in enumerate(messages):
+            message = rapidjson.loads(line)
+            result = processor.process_message(
+                message,
+                KafkaMessageMetadata(
+                    offset=offset, partition=PARTITION, timestamp=TIMESTAMP
+                ),
+            )
+            rows = []
+            if result is not None:
+                assert isinstance(result, InsertBatch), "Replacements are not supported"
+                rows = [encoder.encode(row).decode("utf-8") for row in result.rows]
+
+            path = os.path.join(directory, f"{args.name}-{offset}.json")
+            with open(path, "w") as snapshot:
+                json.dump(
+                    {"message": message, "offset": offset, "rows": rows},
+                    snapshot,
+                    indent=2,
+                )
+                snapshot.write("\n")
+            print(f"Wrote {path}")
+
+
+if __name__ == "__main__":
+    main()
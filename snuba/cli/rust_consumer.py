@@ -4,7 +4,11 @@ from typing import Any, Mapping, Optional, Sequence
 
 import click
 
+from snuba import settings
+from snuba.clickhouse.columns import ColumnType, SchemaModifiers
+from snuba.clusters.cluster import ClickhouseCluster
 from snuba.datasets.schemas.tables import TableSchema
+from snuba.datasets.slicing import SENTRY_LOGICAL_PARTITIONS, is_storage_set_sliced
 from snuba.datasets.storage import WritableTableStorage
 from snuba.datasets.storages.factory import (
     get_writable_storage,
@@ -38,6 +42,48 @@ from snuba.utils.streams.configuration_builder import _get_default_topic_configu
     help="Kafka consumer auto offset reset.",
 )
 @click.option("--raw-events-topic", help="Topic to consume raw events from.")
+@click.option(
+    "--max-batch-size",
+    default=settings.DEFAULT_MAX_BATCH_SIZE,
+    type=int,
+    help="Max number of rows to batch in memory before writing to ClickHouse.",
+)
+@click.option(
+    "--max-batch-time-ms",
+    default=settings.DEFAULT_MAX_BATCH_TIME_MS,
+    type=int,
+    help="Max length of time to buffer rows in memory before writing to ClickHouse.",
+)
+@click.option(
+    "--insert-compression",
+    default="none",
+    type=click.Choice(["none", "gzip", "zstd"]),
+    help="Compression of the bodies of the inserts sent to ClickHouse.",
+)
+@click.option(
+    "--insert-compression-level",
+    default=None,
+    type=int,
+    help="Compression level, the default level of the algorithm is used if not provided.",
+)
+@click.option(
+    "--max-insert-attempts",
+    default=None,
+    type=int,
+    help="How many times an insert is attempted when it fails with a transient error, such as a timeout, before the batch is sent again after a backoff.",
+)
+@click.option(
+    "--circuit-breaker-failures",
+    default=None,
+    type=int,
+    help="Stop inserting for a cool-down period after this many inserts failed in a row, instead of sending them again after a backoff.",
+)
+@click.option(
+    "--circuit-breaker-cool-down-ms",
+    default=None,
+    type=int,
+    help="How long inserts are not sent once the circuit breaker opened.",
+)
 @click.option(
     "--commit-log-topic",
     help="Topic for committed offsets to be written to, triggering post-processing task(s)",
@@ -77,12 +123,127 @@ from snuba.utils.streams.configuration_builder import _get_default_topic_configu
     help="Logging level to use.",
     default="info",
 )
+@click.option(
+    "--health-check-file",
+    default=None,
+    type=str,
+    help="Arroyo will touch this file at intervals to indicate health. If not provided, no health check is performed.",
+)
+@click.option(
+    "--group-instance-id",
+    default=None,
+    type=str,
+    help="Kafka group instance id. Makes the consumer a static group member, which can restart without triggering a rebalance.",
+)
+@click.option(
+    "--kafka-backend",
+    default="base",
+    type=click.Choice(["base", "stream"]),
+    help="The librdkafka consumer to use. The stream consumer commits offsets asynchronously.",
+)
+@click.option(
+    "--enforce-schema",
+    default=False,
+    is_flag=True,
+    help="Messages that do not match the schema of the topic are sent to the DLQ, or crash the consumer if there is none, instead of only being logged.",
+)
+@click.option(
+    "--skip-schema-check",
+    default=False,
+    is_flag=True,
+    help="Do not check on startup that the columns of the storage match the ClickHouse table.",
+)
+@click.option(
+    "--use-rust-processor",
+    default=False,
+    is_flag=True,
+    help="Process messages with the Rust processor of the storage if there is one, instead of the Python processor.",
+)
+@click.option(
+    "--processor-concurrency",
+    default=None,
+    type=int,
+    help="How many threads the Rust processors run on.",
+)
+@click.option(
+    "--max-dlq-invalid-ratio",
+    default=None,
+    type=float,
+    help="Stop the consumer once this ratio of the recent messages of a partition were dead lettered.",
+)
+@click.option(
+    "--max-dlq-consecutive-count",
+    default=None,
+    type=int,
+    help="Stop the consumer after this many consecutive invalid messages.",
+)
+@click.option(
+    "--shadow-topic",
+    default=None,
+    type=str,
+    help="Shadow mode: produce the rows to this topic, on the brokers of the raw topic, instead of writing them to ClickHouse, to compare them with those of the Python consumer. Use a consumer group of its own.",
+)
+@click.option(
+    "--consumer-config-file",
+    default=None,
+    type=click.Path(exists=True, dir_okay=False),
+    help="Run with the consumer config of this YAML or JSON file instead of the one resolved from the settings of the storage.",
+)
+@click.option(
+    "--runtime-config-file",
+    default=None,
+    type=str,
+    help="A YAML or JSON file the consumer polls for max_batch_size and max_batch_time_ms overrides, which apply without a restart.",
+)
+@click.option(
+    "--admin-port",
+    default=None,
+    type=int,
+    help="Serve the state of the consumer, such as its assignment and offsets, as JSON on GET /state on this port.",
+)
+@click.option(
+    "--prometheus-metrics",
+    default=False,
+    is_flag=True,
+    help="Serve the metrics to Prometheus on GET /metrics of the admin port instead of sending them to StatsD.",
+)
+@click.option(
+    "--max-restarts",
+    default=0,
+    type=int,
+    help="Restart the consumer with a new Kafka consumer up to this many times after it crashed, with an exponential backoff.",
+)
+@click.option(
+    "--max-messages-per-second",
+    default=None,
+    type=int,
+    help="Forward at most this many messages per second to the storages, to spare ClickHouse while a backlog is consumed.",
+)
+@click.option(
+    "--max-bytes-per-second",
+    default=None,
+    type=int,
+    help="Forward at most this many bytes of payload per second to the storages.",
+)
+@click.option(
+    "--max-message-age-secs",
+    default=None,
+    type=int,
+    help="Drop the messages produced longer ago than this, to skip data that would be removed by the TTL of the tables anyway.",
+)
 def rust_consumer(
     *,
     storage_names: Sequence[str],
     consumer_group: str,
     auto_offset_reset: str,
     raw_events_topic: Optional[str],
+    max_batch_size: int,
+    max_batch_time_ms: int,
+    insert_compression: str,
+    insert_compression_level: Optional[int],
+    max_insert_attempts: Optional[int],
+    circuit_breaker_failures: Optional[int],
+    circuit_breaker_cool_down_ms: Optional[int],
     commit_log_topic: Optional[str],
     replacements_topic: Optional[str],
     bootstrap_servers: Sequence[str],
@@ -90,11 +251,45 @@ def rust_consumer(
     replacement_bootstrap_servers: Sequence[str],
     slice_id: Optional[int],
     log_level: str,
+    health_check_file: Optional[str],
+    group_instance_id: Optional[str],
+    kafka_backend: str,
+    enforce_schema: bool,
+    skip_schema_check: bool,
+    use_rust_processor: bool,
+    processor_concurrency: Optional[int],
+    max_dlq_invalid_ratio: Optional[float],
+    max_dlq_consecutive_count: Optional[int],
+    shadow_topic: Optional[str],
+    consumer_config_file: Optional[str],
+    runtime_config_file: Optional[str],
+    admin_port: Optional[int],
+    prometheus_metrics: bool,
+    max_restarts: int,
+    max_messages_per_second: Optional[int],
+    max_bytes_per_second: Optional[int],
+    max_message_age_secs: Optional[int],
 ) -> None:
     """
     Experimental alternative to`snuba consumer`
     """
 
+    import os
+
+    import rust_snuba
+
+    os.environ["RUST_LOG"] = log_level
+
+    if consumer_config_file is not None:
+        rust_snuba.consumer_from_file(  # type: ignore
+            consumer_group,
+            auto_offset_reset,
+            consumer_config_file,
+            health_check_file,
+            group_instance_id,
+        )
+        return
+
     consumer_config = resolve_consumer_config(
         storage_names=storage_names,
         raw_topic=raw_events_topic,
@@ -104,20 +299,39 @@ def rust_consumer(
         commit_log_bootstrap_servers=commit_log_bootstrap_servers,
         replacement_bootstrap_servers=replacement_bootstrap_servers,
         slice_id=slice_id,
+        max_batch_size=max_batch_size,
+        max_batch_time_ms=max_batch_time_ms,
+        insert_compression=InsertCompressionConfig(
+            algorithm=insert_compression, level=insert_compression_level
+        ),
+        max_insert_attempts=max_insert_attempts,
+        circuit_breaker_failures=circuit_breaker_failures,
+        circuit_breaker_cool_down_ms=circuit_breaker_cool_down_ms,
+        kafka_backend=kafka_backend,
+        enforce_schema=enforce_schema,
+        skip_schema_check=skip_schema_check,
+        use_rust_processor=use_rust_processor,
+        processor_concurrency=processor_concurrency,
+        max_dlq_invalid_ratio=max_dlq_invalid_ratio,
+        max_dlq_consecutive_count=max_dlq_consecutive_count,
+        shadow_topic=shadow_topic,
+        runtime_config_file=runtime_config_file,
+        admin_port=admin_port,
+        prometheus_metrics=prometheus_metrics,
+        max_restarts=max_restarts,
+        max_messages_per_second=max_messages_per_second,
+        max_bytes_per_second=max_bytes_per_second,
+        max_message_age_secs=max_message_age_secs,
     )
 
     consumer_config_raw = json.dumps(asdict(consumer_config))
 
-    import os
-
-    import rust_snuba
-
-    os.environ["RUST_LOG"] = log_level
-
     rust_snuba.consumer(  # type: ignore
         consumer_group,
         auto_offset_reset,
         consumer_config_raw,
+        health_check_file,
+        group_instance_id,
     )
 
 
@@ -125,6 +339,7 @@ def rust_consumer(
 class ClickhouseClusterConfig:
     host: str
     port: int
+    http_port: int
     user: str
     password: str
     database: str
@@ -136,18 +351,49 @@ class MessageProcessorConfig:
     python_module: str
 
 
+@dataclass(frozen=True)
+class ColumnConfig:
+    name: str
+    type: str
+
+
+@dataclass(frozen=True)
+class SlicingConfig:
+    shard_column: str
+    logical_partitions: int
+    logical_partition_mapping: Mapping[int, int]
+    clusters: Mapping[int, ClickhouseClusterConfig]
+
+
 @dataclass(frozen=True)
 class StorageConfig:
     name: str
     clickhouse_table_name: str
     clickhouse_cluster: ClickhouseClusterConfig
     message_processor: MessageProcessorConfig
+    insert_format: str
+    columns: Sequence[ColumnConfig]
+    slicing: Optional[SlicingConfig]
 
 
 @dataclass(frozen=True)
 class TopicConfig:
     broker_config: Mapping[str, Any]
     physical_topic_name: str
+    logical_topic_name: str
+
+
+@dataclass(frozen=True)
+class InsertCompressionConfig:
+    algorithm: str
+    level: Optional[int] = None
+
+
+@dataclass(frozen=True)
+class EnvConfig:
+    dogstatsd_host: Optional[str]
+    dogstatsd_port: Optional[int]
+    sentry_dsn: Optional[str]
 
 
 @dataclass(frozen=True)
@@ -160,6 +406,29 @@ class RustConsumerConfig:
     raw_topic: TopicConfig
     commit_log_topic: Optional[TopicConfig]
     replacements_topic: Optional[TopicConfig]
+    shadow_topic: Optional[TopicConfig]
+    dlq_topic: Optional[TopicConfig]
+    dlq_max_invalid_ratio: Optional[float]
+    dlq_max_consecutive_count: Optional[int]
+    runtime_config_file: Optional[str]
+    admin_port: Optional[int]
+    prometheus_metrics: bool
+    max_restarts: int
+    max_messages_per_second: Optional[int]
+    max_bytes_per_second: Optional[int]
+    max_message_age_secs: Optional[int]
+    env: EnvConfig
+    max_batch_size: int
+    max_batch_time_ms: int
+    insert_compression: InsertCompressionConfig
+    max_insert_attempts: Optional[int]
+    circuit_breaker_failures: Optional[int]
+    circuit_breaker_cool_down_ms: Optional[int]
+    kafka_backend: str
+    enforce_schema: bool
+    skip_schema_check: bool
+    use_rust_processor: bool
+    processor_concurrency: Optional[int]
 
 
 def _resolve_topic_config(
@@ -178,7 +447,11 @@ def _resolve_topic_config(
         physical_topic_name = topic_spec.get_physical_topic_name(slice_id)
 
     broker = _get_default_topic_configuration(topic_spec.topic, slice_id)
-    return TopicConfig(broker_config=broker, physical_topic_name=physical_topic_name)
+    return TopicConfig(
+        broker_config=broker,
+        physical_topic_name=physical_topic_name,
+        logical_topic_name=topic_spec.topic.value,
+    )
 
 
 def resolve_consumer_config(
@@ -191,6 +464,27 @@ def resolve_consumer_config(
     commit_log_bootstrap_servers: Sequence[str],
     replacement_bootstrap_servers: Sequence[str],
     slice_id: Optional[int],
+    max_batch_size: int = settings.DEFAULT_MAX_BATCH_SIZE,
+    max_batch_time_ms: int = settings.DEFAULT_MAX_BATCH_TIME_MS,
+    insert_compression: InsertCompressionConfig = InsertCompressionConfig("none"),
+    max_insert_attempts: Optional[int] = None,
+    circuit_breaker_failures: Optional[int] = None,
+    circuit_breaker_cool_down_ms: Optional[int] = None,
+    kafka_backend: str = "base",
+    enforce_schema: bool = False,
+    skip_schema_check: bool = False,
+    use_rust_processor: bool = False,
+    processor_concurrency: Optional[int] = None,
+    max_dlq_invalid_ratio: Optional[float] = None,
+    max_dlq_consecutive_count: Optional[int] = None,
+    shadow_topic: Optional[str] = None,
+    runtime_config_file: Optional[str] = None,
+    admin_port: Optional[int] = None,
+    prometheus_metrics: bool = False,
+    max_restarts: int = 0,
+    max_messages_per_second: Optional[int] = None,
+    max_bytes_per_second: Optional[int] = None,
+    max_message_age_secs: Optional[int] = None,
 ) -> RustConsumerConfig:
     """
     Resolves the ClickHouse cluster and Kafka brokers, and the physical topic name
@@ -223,30 +517,96 @@ def resolve_consumer_config(
         "replacements topic", replacements_topic_spec, replacements_topic, slice_id
     )
 
+    dlq_config = stream_loader.get_dlq_config()
+    resolved_dlq_topic = _resolve_topic_config(
+        "dlq topic",
+        KafkaTopicSpec(dlq_config.topic) if dlq_config is not None else None,
+        None,
+        slice_id,
+    )
+
+    resolved_shadow_topic = None
+    if shadow_topic is not None:
+        resolved_shadow_topic = TopicConfig(
+            broker_config=resolved_raw_topic.broker_config,
+            physical_topic_name=shadow_topic,
+            logical_topic_name=shadow_topic,
+        )
+
     return RustConsumerConfig(
         storages=[
-            resolve_storage_config(storage_name, storage)
+            resolve_storage_config(storage_name, storage, slice_id)
             for (storage_name, storage) in storages.items()
         ],
         raw_topic=resolved_raw_topic,
         commit_log_topic=resolved_commit_log_topic,
         replacements_topic=resolved_replacements_topic,
+        shadow_topic=resolved_shadow_topic,
+        dlq_topic=resolved_dlq_topic,
+        dlq_max_invalid_ratio=max_dlq_invalid_ratio,
+        dlq_max_consecutive_count=max_dlq_consecutive_count,
+        runtime_config_file=runtime_config_file,
+        admin_port=admin_port,
+        prometheus_metrics=prometheus_metrics,
+        max_restarts=max_restarts,
+        max_messages_per_second=max_messages_per_second,
+        max_bytes_per_second=max_bytes_per_second,
+        max_message_age_secs=max_message_age_secs,
+        env=EnvConfig(
+            dogstatsd_host=settings.DOGSTATSD_HOST,
+            dogstatsd_port=settings.DOGSTATSD_PORT,
+            sentry_dsn=settings.SENTRY_DSN,
+        ),
+        max_batch_size=max_batch_size,
+        max_batch_time_ms=max_batch_time_ms,
+        insert_compression=insert_compression,
+        max_insert_attempts=max_insert_attempts,
+        circuit_breaker_failures=circuit_breaker_failures,
+        circuit_breaker_cool_down_ms=circuit_breaker_cool_down_ms,
+        kafka_backend=kafka_backend,
+        enforce_schema=enforce_schema,
+        skip_schema_check=skip_schema_check,
+        use_rust_processor=use_rust_processor,
+        processor_concurrency=processor_concurrency,
     )
 
 
-def resolve_storage_config(
-    storage_name: str, storage: WritableTableStorage
-) -> StorageConfig:
-    cluster = storage.get_cluster()
+def _resolve_cluster_config(cluster: ClickhouseCluster) -> ClickhouseClusterConfig:
     user, password = cluster.get_credentials()
-    clickhouse_cluster = ClickhouseClusterConfig(
+    return ClickhouseClusterConfig(
         host=cluster.get_host(),
         port=cluster.get_port(),
+        http_port=cluster.get_http_port(),
         user=user,
         password=password,
         database=cluster.get_database(),
     )
 
+
+def resolve_storage_config(
+    storage_name: str, storage: WritableTableStorage, slice_id: Optional[int]
+) -> StorageConfig:
+    storage_set = storage.get_storage_set_key()
+    slicing = None
+    if slice_id is None and is_storage_set_sliced(storage_set):
+        # Without a slice, the consumer writes the rows of every slice to its
+        # own cluster, like the Python consumers of each slice would.
+        clusters = {
+            slice: _resolve_cluster_config(storage.get_cluster(slice))
+            for slice in range(settings.SLICED_STORAGE_SETS[storage_set.value])
+        }
+        slicing = SlicingConfig(
+            shard_column="org_id",
+            logical_partitions=SENTRY_LOGICAL_PARTITIONS,
+            logical_partition_mapping=settings.LOGICAL_PARTITION_MAPPING[
+                storage_set.value
+            ],
+            clusters=clusters,
+        )
+        clickhouse_cluster = clusters[0]
+    else:
+        clickhouse_cluster = _resolve_cluster_config(storage.get_cluster(slice_id))
+
     processor = storage.get_table_writer().get_stream_loader().get_processor()
 
     table_schema = storage.get_schema()
@@ -259,9 +619,23 @@ def resolve_storage_config(
             python_class_name=processor.__class__.__name__,
             python_module=processor.__class__.__module__,
         ),
+        insert_format=settings.RUST_CONSUMER_INSERT_FORMATS.get(
+            storage_name, "JSONEachRow"
+        ),
+        columns=[
+            ColumnConfig(name=column.flattened, type=column.type.for_schema())
+            for column in table_schema.get_columns()
+            if not _is_readonly(column.type)
+        ],
+        slicing=slicing,
     )
 
 
+def _is_readonly(column_type: ColumnType[SchemaModifiers]) -> bool:
+    modifiers = column_type.get_modifiers()
+    return modifiers is not None and modifiers.readonly
+
+
 def validate_storages(storages: Sequence[WritableTableStorage]) -> None:
     """
     Validates that storage combination is valid based on topic definitions
@@ -204,6 +204,10 @@ KAFKA_TOPIC_MAP: Mapping[str, str] = {}
 # Mapping of default Kafka topic name to broker config
 KAFKA_BROKER_CONFIG: Mapping[str, Mapping[str, Any]] = {}
 
+# Mapping of storage name to the format the Rust consumer inserts its rows
+# in: JSONEachRow (the default), RowBinary or CSVWithNames
+RUST_CONSUMER_INSERT_FORMATS: Mapping[str, str] = {}
+
 DEFAULT_MAX_BATCH_SIZE = 50000
 DEFAULT_MAX_BATCH_TIME_MS = 2 * 1000
 DEFAULT_QUEUED_MAX_MESSAGE_KBYTES = 10000